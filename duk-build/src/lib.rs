@@ -0,0 +1,91 @@
+//! Helpers for the build scripts of crates that embed `duk`.
+//!
+//! Hosts often have a runtime layer written in Javascript, which sets up the globals that their
+//! plugins use.  Instead of evaluating it whenever a context starts, a build script can evaluate
+//! it once and save a snapshot of the result (see `duk::snapshot`), which the host then embeds
+//! with `duk::include_snapshot!` and restores with `duk::Context::from_snapshot`.
+//!
+//! Snapshots only restore in contexts with the same Duktape version and configuration, so the
+//! build dependency on `duk` must enable the same Duktape-related features as the normal one.
+//!
+//! # Examples
+//!
+//! In `build.rs`, with `duk-build` as a build dependency:
+//!
+//! ```no_run
+//! extern crate duk_build;
+//!
+//! fn main() {
+//!     duk_build::snapshot("runtime.js", "runtime.snap").unwrap();
+//! }
+//! ```
+//!
+//! And in the host:
+//!
+//! ```ignore
+//! let ctx = duk::Context::from_snapshot(duk::include_snapshot!("runtime.snap")).unwrap();
+//! ```
+
+extern crate duk;
+
+use std::env;
+use std::fs;
+use std::path;
+
+/// Evaluates a script in a context with the defaults of `duk::Context::new`, and writes a
+/// snapshot of the globals that it defined to `out_path`, see `snapshot_with`.
+pub fn snapshot<P, Q>(script: P, out_path: Q) -> duk::Result<()>
+    where P: AsRef<path::Path>,
+          Q: AsRef<path::Path>
+{
+    snapshot_with(duk::Context::builder(), script, out_path)
+}
+
+/// Evaluates a script in a context from the builder, and writes a snapshot of the globals that
+/// it defined to `out_path`.  Use this when the script needs host functions while it runs; the
+/// host then restores the snapshot with `duk::ContextBuilder::build_from_snapshot`.
+///
+/// A relative `out_path` is taken to be relative to `OUT_DIR`, where `include_snapshot!` looks
+/// for it.  Cargo is told to run the build script again when the script changes.
+pub fn snapshot_with<P, Q>(builder: duk::ContextBuilder, script: P, out_path: Q) -> duk::Result<()>
+    where P: AsRef<path::Path>,
+          Q: AsRef<path::Path>
+{
+    let script = script.as_ref();
+    println!("cargo:rerun-if-changed={}", script.display());
+    let ctx = builder.try_build()?;
+    ctx.eval_file(script)?;
+    let out_path = match env::var_os("OUT_DIR") {
+        Some(out_dir) => path::Path::new(&out_dir).join(out_path),
+        None => out_path.as_ref().to_owned(),
+    };
+    fs::write(out_path, ctx.snapshot())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn snapshot_runtime() {
+        let _ = env_logger::init();
+        let dir = duk::testing::ScratchDir::new("build");
+        let script = dir.join("runtime.js");
+        fs::write(&script, "var runtime = {version: 2};\n\
+                            function describe() { return 'runtime ' + runtime.version; }\n")
+            .unwrap();
+        // Absolute paths are used as they are, even when `OUT_DIR` is set
+        let out_path = dir.join("runtime.snap");
+        snapshot(&script, &out_path).unwrap();
+
+        let ctx = duk::Context::from_snapshot(&fs::read(&out_path).unwrap()).unwrap();
+        assert_eq!(duk::Value::String("runtime 2".to_owned()),
+                   ctx.call("describe", ()).unwrap().to_value());
+        assert!(snapshot(dir.join("missing.js"), &out_path).is_err());
+    }
+}
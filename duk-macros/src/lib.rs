@@ -0,0 +1,135 @@
+//! Procedural macros for exposing Rust functions to scripts running in `duk`.
+//!
+//! The `#[duk_function]` attribute turns a plain Rust function into a native function (see
+//! `duk::native`), by generating the code that converts the arguments from `duk::Value`s with
+//! `duk::FromValue`, and the result into a `duk::Value`.  The function itself stays as it is, so
+//! Rust code can still call it.  Next to it, the attribute defines a type of the same name that
+//! implements `duk::native::NativeFunction`, which `duk::Context::register_native` registers.
+//!
+//! The parameters can have any type that implements `duk::FromValue`, like `f64`, `String`,
+//! `Vec<T>` or `Option<T>` for optional parameters.  An argument that doesn't convert throws a
+//! `TypeError` that names the parameter.  The function can return nothing, anything that
+//! converts into a `duk::Value`, or a `duk::Result` of those, whose errors are thrown into the
+//! script.
+//!
+//! By default, the function has the same name in Javascript as in Rust;
+//! `#[duk_function(name = "addNumbers")]` gives it another one.
+//!
+//! # Examples
+//!
+//! ```
+//! extern crate duk;
+//! extern crate duk_macros;
+//!
+//! use duk_macros::duk_function;
+//!
+//! #[duk_function]
+//! fn add(a: f64, b: f64) -> f64 {
+//!     a + b
+//! }
+//!
+//! #[duk_function(name = "readConfig")]
+//! fn read_config(key: String, fallback: Option<String>) -> duk::Result<String> {
+//!     match fallback {
+//!         Some(fallback) => Ok(fallback),
+//!         None => Err(duk::JsError::new(duk::JsErrorKind::Error, &key).into()),
+//!     }
+//! }
+//!
+//! fn main() {
+//!     let ctx = duk::Context::new();
+//!     ctx.register_native::<add>();
+//!     ctx.register_native::<read_config>();
+//!     assert_eq!(duk::Value::Number(3.0), ctx.eval_string("add(1, 2)").unwrap().to_value());
+//!     assert_eq!(duk::Value::String("dark".to_owned()),
+//!                ctx.eval_string("readConfig('theme', 'dark')").unwrap().to_value());
+//!     assert_eq!(3.0, add(1.0, 2.0));
+//! }
+//! ```
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro2::TokenStream;
+use syn::spanned::Spanned;
+
+/// Generates a `duk::native::NativeFunction` for a function, see the crate documentation.
+#[proc_macro_attribute]
+pub fn duk_function(attr: proc_macro::TokenStream,
+                    item: proc_macro::TokenStream)
+                    -> proc_macro::TokenStream {
+    let function = syn::parse_macro_input!(item as syn::ItemFn);
+    let mut name = None;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("name") {
+            name = Some(meta.value()?.parse::<syn::LitStr>()?.value());
+            Ok(())
+        } else {
+            Err(meta.error("expected `name = \"...\"`"))
+        }
+    });
+    syn::parse_macro_input!(attr with parser);
+    match expand(&function, name) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+fn expand(function: &syn::ItemFn, name: Option<String>) -> syn::Result<TokenStream> {
+    let signature = &function.sig;
+    if !signature.generics.params.is_empty() {
+        return Err(syn::Error::new(signature.generics.span(),
+                                   "native functions can't be generic"));
+    }
+    if let Some(asyncness) = signature.asyncness {
+        return Err(syn::Error::new(asyncness.span(), "native functions can't be async"));
+    }
+
+    let ident = &signature.ident;
+    let js_name = name.unwrap_or_else(|| ident.to_string());
+    let mut names = Vec::new();
+    let mut conversions = Vec::new();
+    for (index, input) in signature.inputs.iter().enumerate() {
+        let input = match *input {
+            syn::FnArg::Typed(ref input) => input,
+            syn::FnArg::Receiver(ref receiver) => {
+                return Err(syn::Error::new(receiver.span(),
+                                           "native functions can't take `self`"));
+            }
+        };
+        let param = match *input.pat {
+            syn::Pat::Ident(ref pat) => pat.ident.to_string(),
+            _ => format!("argument {}", index + 1),
+        };
+        let arg = format_ident!("arg{}", index);
+        let ty = &input.ty;
+        conversions.push(quote! {
+            let #arg = ::duk::native::argument::<#ty>(args, #index, #param)?;
+        });
+        names.push(arg);
+    }
+    let nargs = names.len();
+    let vis = &function.vis;
+    let doc = format!("The native function `{}`, see `duk::Context::register_native`.", js_name);
+
+    Ok(quote! {
+        #function
+
+        #[doc = #doc]
+        #[allow(non_camel_case_types)]
+        #vis struct #ident {}
+
+        impl ::duk::native::NativeFunction for #ident {
+            const NAME: &'static str = #js_name;
+            const NARGS: usize = #nargs;
+
+            fn call(args: &[::duk::Value]) -> ::duk::Result<::duk::Value> {
+                #(#conversions)*
+                ::duk::native::NativeResult::into_result(#ident(#(#names),*))
+            }
+        }
+    })
+}
@@ -0,0 +1,60 @@
+extern crate duk;
+extern crate duk_macros;
+extern crate env_logger;
+
+use duk::{Context, JsError, JsErrorKind, Value};
+use duk_macros::duk_function;
+
+#[duk_function]
+fn add(a: f64, b: f64) -> f64 {
+    a + b
+}
+
+#[duk_function(name = "joinPath")]
+fn join_path(parts: Vec<String>, separator: Option<String>) -> String {
+    parts.join(&separator.unwrap_or_else(|| "/".to_owned()))
+}
+
+#[duk_function]
+fn check(value: i32) -> duk::Result<()> {
+    if value < 0 {
+        Err(JsError::new(JsErrorKind::Range, "negative").into())
+    } else {
+        Ok(())
+    }
+}
+
+#[test]
+fn marshalling() {
+    let _ = env_logger::init();
+    let ctx = Context::new();
+    ctx.register_native::<add>();
+    ctx.register_native::<join_path>();
+    ctx.register_native::<check>();
+    ctx.register_module("math", |m| {
+        m.native::<add>();
+    });
+    let result = ctx.eval_string(r"
+      function error(f) { try { f(); } catch (e) { return e.name + ': ' + e.message; } }
+      [add(1, 2), math.add(3, 4), add.length, joinPath(['a', 'b']), joinPath(['a', 'b'], '\\'),
+       typeof check(1), error(function () { check(-1); }),
+       error(function () { add(1, 'two'); }), error(function () { add(1); })]
+    ")
+        .unwrap();
+    assert_eq!(Value::Array(vec![Value::Number(3.0),
+                                 Value::Number(7.0),
+                                 Value::Number(2.0),
+                                 Value::String("a/b".to_owned()),
+                                 Value::String("a\\b".to_owned()),
+                                 Value::String("undefined".to_owned()),
+                                 Value::String("RangeError: negative".to_owned()),
+                                 Value::String("TypeError: argument 2 (b): expected a number, got \
+                                                a string"
+                                     .to_owned()),
+                                 Value::String("TypeError: argument 2 (b): expected a number, got \
+                                                undefined"
+                                     .to_owned())]),
+               result.to_value());
+    // The functions can still be called from Rust
+    assert_eq!(3.0, add(1.0, 2.0));
+}
@@ -1,36 +1,154 @@
 extern crate gcc;
 
+use std::env;
+
 fn main() {
     let mut config = gcc::Config::new();
 
+    let target_arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap_or_default();
+    let target_os = env::var("CARGO_CFG_TARGET_OS").unwrap_or_default();
+    if target_arch == "wasm32" {
+        if target_os != "wasi" {
+            // Duktape needs a C library (for memory allocation, setjmp/longjmp and dates), which
+            // only WASI targets have.
+            panic!("duktape-sys can't be built for wasm32-{}; build for wasm32-wasip1 instead \
+                    (with the wasi-sdk as the C compiler)",
+                   target_os);
+        }
+        // Duktape unwinds errors with setjmp/longjmp, which WASI supports through the exception
+        // handling proposal and the setjmp library of the wasi-sdk.
+        config.flag("-mllvm");
+        config.flag("-wasm-enable-sjlj");
+        println!("cargo:rustc-link-lib=setjmp");
+    }
+
+    let mut options = Vec::new();
+
     let wire_debug = if cfg!(feature = "spam") {
-        config.define("DUK_OPT_DEBUG_LEVEL", Some("DUK_LEVEL_DDDEBUG"));
+        define(&mut options, "DUK_OPT_DEBUG_LEVEL", Some("DUK_LEVEL_DDDEBUG"));
         true
     } else if cfg!(feature = "trace") {
-        config.define("DUK_OPT_DEBUG_LEVEL", Some("DUK_LEVEL_DDEBUG"));
+        define(&mut options, "DUK_OPT_DEBUG_LEVEL", Some("DUK_LEVEL_DDEBUG"));
         true
     } else if cfg!(feature = "debug") {
-        config.define("DUK_OPT_DEBUG_LEVEL", Some("DUK_LEVEL_DEBUG"));
+        define(&mut options, "DUK_OPT_DEBUG_LEVEL", Some("DUK_LEVEL_DEBUG"));
         true
     } else {
         false
     };
 
     if wire_debug {
-        config.define("DUK_OPT_DEBUG", None);
-        config.define("DUK_OPT_DEBUG_WRITE", Some("__duktape_sys_debug_write"));
+        define(&mut options, "DUK_OPT_DEBUG", None);
+        define(&mut options, "DUK_OPT_DEBUG_WRITE", Some("__duktape_sys_debug_write"));
     }
 
     config.define("DUK_LOGGING_FLUSH", None);
 
+    if cfg!(feature = "debugger") {
+        define(&mut options, "DUK_OPT_DEBUGGER_SUPPORT", None);
+        define(&mut options, "DUK_OPT_DEBUGGER_PAUSE_UNCAUGHT", None);
+        define(&mut options, "DUK_OPT_INTERRUPT_COUNTER", None);
+    }
+
+    if cfg!(feature = "profiler") || cfg!(feature = "timeout") {
+        define(&mut options, "DUK_OPT_INTERRUPT_COUNTER", None);
+    }
+
+    if cfg!(feature = "profiler") || cfg!(feature = "debugger") || cfg!(feature = "timeout") {
+        define(&mut options,
+               "DUK_OPT_EXEC_TIMEOUT_CHECK",
+               Some("__duktape_sys_exec_timeout_check"));
+    }
+
+    if cfg!(feature = "date-provider") {
+        define(&mut options, "DUK_OPT_DATE_GET_NOW", Some("__duktape_sys_date_get_now"));
+    }
+
+    if cfg!(feature = "low-memory") {
+        // Trade some performance and limits (like at most 64k properties per object) for a
+        // considerably smaller per-heap footprint.  Built-in functions become lightfuncs, which
+        // have no names of their own.  DUK_OPT_NO_HSTRING_CLEN is left out: it breaks built-ins
+        // like `String.prototype.replace` in this version of Duktape, and saves next to nothing.
+        define(&mut options, "DUK_OPT_LIGHTFUNC_BUILTINS", None);
+        define(&mut options, "DUK_OPT_OBJSIZES16", None);
+        define(&mut options, "DUK_OPT_STRHASH16", None);
+        define(&mut options, "DUK_OPT_STRTAB_CHAIN", None);
+        define(&mut options, "DUK_OPT_STRTAB_CHAIN_SIZE", Some("128"));
+    }
+
+    if cfg!(feature = "fastint") {
+        define(&mut options, "DUK_OPT_FASTINT", None);
+    }
+
+    if cfg!(feature = "no-es6-proxy") {
+        define(&mut options, "DUK_OPT_NO_ES6_PROXY", None);
+    }
+
+    if cfg!(feature = "cesu8") {
+        // String.fromCharCode turns code points outside the BMP into surrogate pairs, like other
+        // engines do, instead of into single non-standard characters.
+        define(&mut options, "DUK_OPT_NO_NONSTD_STRING_FROMCHARCODE_32BIT", None);
+    }
+
+    // Any other option of duk_config.h, like `DUKTAPE_SYS_OPTIONS="NO_ES6_PROXY REFCOUNT16"` or
+    // `DUKTAPE_SYS_OPTIONS=DUK_OPT_STRTAB_CHAIN_SIZE=64`.
+    println!("cargo:rerun-if-env-changed=DUKTAPE_SYS_OPTIONS");
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=duktape");
+    println!("cargo:rerun-if-changed=src/wrapper.c");
+    println!("cargo:rerun-if-changed=src/internals.c");
+    println!("cargo:rerun-if-changed=src/internals.h");
+    if let Ok(extra) = env::var("DUKTAPE_SYS_OPTIONS") {
+        let separator = |c: char| c.is_whitespace() || c == ',';
+        for option in extra.split(separator).filter(|o| !o.is_empty()) {
+            let (name, value) = match option.find('=') {
+                Some(i) => (&option[..i], Some(&option[i + 1..])),
+                None => (option, None),
+            };
+            let valid = |c: char| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_';
+            if name.is_empty() || !name.chars().all(valid) {
+                panic!("invalid Duktape option {:?} in DUKTAPE_SYS_OPTIONS", option);
+            }
+            if name.trim_start_matches("DUK_OPT_").starts_with("ROM_") {
+                panic!("Duktape option {:?} needs ROM built-ins, which the bundled sources were \
+                        not prepared with (see the low-memory feature)",
+                       option);
+            }
+            if name.starts_with("DUK_OPT_") {
+                define(&mut options, name, value);
+            } else {
+                define(&mut options, &format!("DUK_OPT_{}", name), value);
+            }
+        }
+    }
+
+    for &(ref name, ref value) in &options {
+        config.define(name, value.as_ref().map(String::as_str));
+    }
+    // Reported by `duktape_sys::CONFIG_OPTIONS`
+    let summary = options.iter()
+        .map(|&(ref name, ref value)| match *value {
+            Some(ref value) => format!("{}={}", name, value),
+            None => name.clone(),
+        })
+        .collect::<Vec<_>>();
+    println!("cargo:rustc-env=DUKTAPE_SYS_CONFIG_OPTIONS={}", summary.join(" "));
+
     config.include("duktape/src");
     config.include("duktape/extras/logging");
     config.include("duktape/extras/module-node");
     config.flag("-std=c99");
-    config.file("duktape/src/duktape.c");
+    // Includes duktape.c, for helpers that need the internals of Duktape
+    config.file("src/internals.c");
     config.file("duktape/extras/logging/duk_logging.c");
     config.file("duktape/extras/module-node/duk_module_node.c");
     config.file("src/wrapper.c");
 
     config.compile("libduktape.a");
 }
+
+/// Adds a `DUK_OPT_*` define, replacing an earlier one with the same name.
+fn define(options: &mut Vec<(String, Option<String>)>, name: &str, value: Option<&str>) {
+    options.retain(|option| option.0 != name);
+    options.push((name.to_owned(), value.map(str::to_owned)));
+}
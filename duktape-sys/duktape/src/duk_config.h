@@ -3387,6 +3387,15 @@ typedef struct duk_hthread duk_context;
 
 /* __OVERRIDE_DEFINES__ */
 
+/* duktape-sys: an external provider for the current time, see the
+ * date-provider feature.
+ */
+#if defined(DUK_OPT_DATE_GET_NOW)
+extern double DUK_OPT_DATE_GET_NOW(void *ctx);
+#undef DUK_USE_DATE_GET_NOW
+#define DUK_USE_DATE_GET_NOW(ctx)  DUK_OPT_DATE_GET_NOW((void *) (ctx))
+#endif
+
 /*
  *  Date provider selection
  *
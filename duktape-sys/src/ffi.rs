@@ -330,6 +330,7 @@ extern "C" {
     pub static DUK_LOG_WARN: duk_int_t;
     pub static DUK_LOG_ERROR: duk_int_t;
     pub static DUK_LOG_FATAL: duk_int_t;
+    pub static DUK_BUFOBJ_UINT8ARRAY: duk_uint_t;
 }
 extern "C" {
     pub fn duk_create_heap(alloc_func: duk_alloc_function,
@@ -0,0 +1,120 @@
+/*
+ *  Helpers that need the internals of Duktape, like reading the call stack without calling into
+ *  Duktape, for the executor interrupt hook (see DUK_USE_EXEC_TIMEOUT_CHECK), which must not use
+ *  the Duktape API.  The internals are only visible within the translation unit of Duktape, so this
+ *  file includes the amalgamated sources and is compiled instead of them.
+ */
+
+#include "duktape.c"
+#include "internals.h"
+
+/* Points the output at the string value of an own data property, or at nothing. */
+static void duktape_sys__get_string(duk_hthread *thr,
+                                    duk_hobject *obj,
+                                    duk_hstring *key,
+                                    const char **out,
+                                    duk_size_t *out_len) {
+	duk_tval *tv = duk_hobject_find_existing_entry_tval_ptr(thr->heap, obj, key);
+
+	if (tv != NULL && DUK_TVAL_IS_STRING(tv)) {
+		duk_hstring *h = DUK_TVAL_GET_STRING(tv);
+		*out = (const char *) DUK_HSTRING_GET_DATA(h);
+		*out_len = (duk_size_t) DUK_HSTRING_GET_BYTELEN(h);
+	} else {
+		*out = NULL;
+		*out_len = 0;
+	}
+}
+
+duk_size_t duktape_sys_capture_callstack(duk_context *ctx,
+                                         duktape_sys_frame *frames,
+                                         duk_size_t max_frames) {
+	duk_hthread *thr = ((duk_hthread *) ctx)->heap->curr_thread;
+	duk_size_t depth;
+	duk_size_t i;
+
+	if (thr == NULL) {
+		return 0;
+	}
+
+	depth = (duk_size_t) thr->callstack_top;
+	for (i = 0; i < depth && i < max_frames; i++) {
+		/* Innermost activation first */
+		duk_activation *act = thr->callstack + thr->callstack_top - 1 - i;
+		duk_hobject *func = DUK_ACT_GET_FUNC(act);
+		duktape_sys_frame *frame = frames + i;
+
+		frame->function_name = NULL;
+		frame->function_name_len = 0;
+		frame->file_name = NULL;
+		frame->file_name_len = 0;
+		frame->line = 0;
+		if (func == NULL) {
+			/* A lightfunc, which has no properties of its own */
+			continue;
+		}
+
+		duktape_sys__get_string(thr,
+		                        func,
+		                        DUK_HTHREAD_STRING_NAME(thr),
+		                        &frame->function_name,
+		                        &frame->function_name_len);
+		duktape_sys__get_string(thr,
+		                        func,
+		                        DUK_HTHREAD_STRING_FILE_NAME(thr),
+		                        &frame->file_name,
+		                        &frame->file_name_len);
+#if defined(DUK_USE_PC2LINE)
+		if (DUK_HOBJECT_IS_COMPFUNC(func)) {
+			duk_tval *tv = duk_hobject_find_existing_entry_tval_ptr(thr->heap,
+			                                                         func,
+			                                                         DUK_HTHREAD_STRING_INT_PC2LINE(thr));
+			if (tv != NULL && DUK_TVAL_IS_BUFFER(tv)) {
+				duk_hbuffer_fixed *pc2line = (duk_hbuffer_fixed *) DUK_TVAL_GET_BUFFER(tv);
+				duk_uint_fast32_t pc = duk_hthread_get_act_prev_pc(thr, act);
+				frame->line = (duk_uint_t) duk__hobject_pc2line_query_raw(thr, pc2line, pc);
+			}
+		}
+#endif
+	}
+
+	return depth;
+}
+
+duk_int_t duktape_sys_find_global(duk_context *ctx, duk_idx_t key_idx) {
+	duk_hthread *thr = (duk_hthread *) ctx;
+	duk_hobject *global = thr->builtins[DUK_BIDX_GLOBAL];
+	duk_hstring *key = duk_get_hstring(ctx, key_idx);
+	duk_int_t e_idx;
+	duk_int_t h_idx;
+
+	if (key == NULL) {
+		return -1;
+	}
+	duk_hobject_find_existing_entry(thr->heap, global, key, &e_idx, &h_idx);
+	if (e_idx < 0 || DUK_HOBJECT_E_SLOT_IS_ACCESSOR(thr->heap, global, e_idx)) {
+		return -1;
+	}
+	return e_idx;
+}
+
+duk_bool_t duktape_sys_global_is(duk_context *ctx, duk_int_t entry, void *key, void *value) {
+	duk_hthread *thr = (duk_hthread *) ctx;
+	duk_hobject *global = thr->builtins[DUK_BIDX_GLOBAL];
+	duk_tval *tv;
+
+	/* Deleting the property clears the key of its entry, and compacting the global object moves
+	 * entries around, so the key tells whether the entry still belongs to the property.
+	 */
+	if (entry < 0 || (duk_uint_fast32_t) entry >= (duk_uint_fast32_t) DUK_HOBJECT_GET_ENEXT(global)) {
+		return 0;
+	}
+	if (DUK_HOBJECT_E_GET_KEY(thr->heap, global, entry) != (duk_hstring *) key ||
+	    DUK_HOBJECT_E_SLOT_IS_ACCESSOR(thr->heap, global, entry)) {
+		return 0;
+	}
+	tv = DUK_HOBJECT_E_GET_VALUE_TVAL_PTR(thr->heap, global, entry);
+	return DUK_TVAL_IS_OBJECT(tv) &&
+	       DUK_TVAL_GET_OBJECT(tv) == (duk_hobject *) value &&
+	       DUK_HOBJECT_IS_CALLABLE((duk_hobject *) value);
+}
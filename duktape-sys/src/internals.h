@@ -0,0 +1,33 @@
+#pragma once
+#include "duktape.h"
+
+/* A function on the call stack.  The names point into the Duktape heap, aren't NUL-terminated,
+ * and are NULL if the function doesn't have them.
+ */
+typedef struct {
+	const char *function_name;
+	duk_size_t function_name_len;
+	const char *file_name;
+	duk_size_t file_name_len;
+	duk_uint_t line;
+} duktape_sys_frame;
+
+/* Stores the innermost functions on the call stack of the running thread of the heap into
+ * frames, innermost first, and returns the depth of the call stack, which may be larger than
+ * max_frames.  Doesn't call into Duktape, so it is safe within the executor interrupt hook.
+ */
+duk_size_t duktape_sys_capture_callstack(duk_context *ctx,
+                                         duktape_sys_frame *frames,
+                                         duk_size_t max_frames);
+
+/* Returns the index of the entry of the own data property of the global object with the key at
+ * key_idx, or -1 if there is none.  Entries keep their index until the property is deleted or the
+ * global object is resized.
+ */
+duk_int_t duktape_sys_find_global(duk_context *ctx, duk_idx_t key_idx);
+
+/* Checks whether the entry of the global object still has the key (a heap pointer to a string) and
+ * holds the value (a heap pointer to a callable object, which may since have been freed) as a data
+ * property.  Doesn't call into Duktape.
+ */
+duk_bool_t duktape_sys_global_is(duk_context *ctx, duk_int_t entry, void *key, void *value);
@@ -13,6 +13,11 @@ extern crate log;
 
 mod ffi;
 
+#[cfg(any(feature = "profiler", feature = "debugger", feature = "timeout"))]
+use std::mem;
+#[cfg(any(feature = "profiler", feature = "debugger", feature = "timeout"))]
+use std::sync::atomic;
+
 pub use ffi::*;
 
 #[cfg(any(feature = "debug", feature = "trace", feature = "spam"))]
@@ -34,3 +39,95 @@ pub unsafe extern "C" fn __duktape_sys_debug_write(
         }
     }
 }
+
+/// The `DUK_OPT_*` options that Duktape was compiled with, separated by spaces, like
+/// `DUK_OPT_FASTINT DUK_OPT_STRTAB_CHAIN_SIZE=128`.  Includes the options set by cargo features
+/// and the ones from the `DUKTAPE_SYS_OPTIONS` environment variable at build time.
+pub const CONFIG_OPTIONS: &str = env!("DUKTAPE_SYS_CONFIG_OPTIONS");
+
+/// A hook that is called with the heap udata every time the bytecode executor is interrupted
+/// (roughly every 256k executed instructions).  Returning a non-zero value aborts execution with
+/// a `RangeError`.  The hook must not call the Duktape API, except for
+/// `duktape_sys_capture_callstack`.
+pub type ExecTimeoutCheck = unsafe fn(*mut libc::c_void) -> duk_bool_t;
+
+#[cfg(any(feature = "profiler", feature = "debugger", feature = "timeout"))]
+static EXEC_TIMEOUT_CHECK: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+/// Installs the hook that is called when the bytecode executor is interrupted.  There is only one
+/// hook for all heaps of the process, which tells them apart by their udata, so the first hook
+/// stays installed.  Returns whether `check` is the installed hook.
+#[cfg(any(feature = "profiler", feature = "debugger", feature = "timeout"))]
+pub fn set_exec_timeout_check(check: ExecTimeoutCheck) -> bool {
+    use std::sync::atomic::Ordering::SeqCst;
+
+    let check = check as usize;
+    match EXEC_TIMEOUT_CHECK.compare_exchange(0, check, SeqCst, SeqCst) {
+        Ok(_) => true,
+        Err(installed) => installed == check,
+    }
+}
+
+#[cfg(any(feature = "profiler", feature = "debugger", feature = "timeout"))]
+#[no_mangle]
+pub unsafe extern "C" fn __duktape_sys_exec_timeout_check(udata: *mut libc::c_void) -> duk_bool_t {
+    match EXEC_TIMEOUT_CHECK.load(atomic::Ordering::SeqCst) {
+        0 => 0,
+        check => mem::transmute::<usize, ExecTimeoutCheck>(check)(udata),
+    }
+}
+
+/// A function on the call stack, see `duktape_sys_capture_callstack`.  The names point into the
+/// Duktape heap, aren't NUL-terminated, and are null if the function doesn't have them.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct duktape_sys_frame {
+    pub function_name: *const libc::c_char,
+    pub function_name_len: duk_size_t,
+    pub file_name: *const libc::c_char,
+    pub file_name_len: duk_size_t,
+    pub line: duk_uint_t,
+}
+
+extern "C" {
+    /// Stores the innermost functions on the call stack of the running thread of the heap into
+    /// `frames`, innermost first, and returns the depth of the call stack, which may be larger
+    /// than `max_frames`.  Doesn't call into Duktape, so it is safe within the executor interrupt
+    /// hook.
+    pub fn duktape_sys_capture_callstack(ctx: *mut duk_context,
+                                         frames: *mut duktape_sys_frame,
+                                         max_frames: duk_size_t)
+                                         -> duk_size_t;
+
+    /// Returns the index of the entry of the own data property of the global object with the
+    /// key at `key_idx`, or -1 if there is none.  Entries keep their index until the property is
+    /// deleted or the global object is resized.
+    pub fn duktape_sys_find_global(ctx: *mut duk_context, key_idx: duk_idx_t) -> duk_int_t;
+
+    /// Checks whether the entry of the global object still has the key (a heap pointer to a
+    /// string) and holds the value (a heap pointer to a callable object, which may since have been
+    /// freed) as a data property.  Doesn't call into Duktape.
+    pub fn duktape_sys_global_is(ctx: *mut duk_context,
+                                 entry: duk_int_t,
+                                 key: *mut libc::c_void,
+                                 value: *mut libc::c_void)
+                                 -> duk_bool_t;
+}
+
+/// A hook that provides the current time for `Date.now()` and `new Date()`, in milliseconds since
+/// the epoch, with the `date-provider` feature.  It is called with the context that asks.  Without
+/// a hook, the system time is used.
+#[cfg(feature = "date-provider")]
+pub static mut DATE_GET_NOW: Option<unsafe fn(*mut duk_context) -> f64> = None;
+
+#[cfg(feature = "date-provider")]
+#[no_mangle]
+pub unsafe extern "C" fn __duktape_sys_date_get_now(ctx: *mut libc::c_void) -> f64 {
+    match DATE_GET_NOW {
+        Some(now) => now(ctx as *mut duk_context),
+        None => {
+            let now = ::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH);
+            now.map_or(0.0, |d| d.as_secs() as f64 * 1000.0 + d.subsec_millis() as f64)
+        }
+    }
+}
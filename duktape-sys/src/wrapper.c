@@ -546,6 +546,12 @@ const duk_int_t DUK_LOG_FATAL =
 #pragma pop_macro("DUK_LOG_FATAL")
   DUK_LOG_FATAL;
 
+#pragma push_macro("DUK_BUFOBJ_UINT8ARRAY")
+#undef DUK_BUFOBJ_UINT8ARRAY
+const duk_uint_t DUK_BUFOBJ_UINT8ARRAY =
+#pragma pop_macro("DUK_BUFOBJ_UINT8ARRAY")
+  DUK_BUFOBJ_UINT8ARRAY;
+
 #pragma push_macro("duk_create_heap_default")
 #undef duk_create_heap_default
 duk_context * duk_create_heap_default() {
@@ -0,0 +1,49 @@
+//! Compares the different ways of calling a global function from Rust.
+//!
+//! Run with `cargo run --release --example call_bench`.
+
+extern crate duk;
+
+use std::time;
+
+const ITERATIONS: u32 = 100_000;
+
+fn bench<F>(name: &str, mut f: F)
+    where F: FnMut()
+{
+    let start = time::Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let elapsed = start.elapsed();
+    let nanos = elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+    println!("{:<28} {:>8} ns/call", name, nanos / ITERATIONS as u64);
+}
+
+fn main() {
+    let ctx = duk::Context::new();
+    ctx.eval_string("function tick(n) { return n + 1; }").unwrap();
+    let arg = duk::Value::Number(1.0);
+
+    bench("global_object().call_method", || {
+        ctx.global_object().call_method("tick", &[&arg]).unwrap();
+    });
+
+    bench("call_global", || {
+        ctx.call_global("tick", &[&arg]).unwrap();
+    });
+
+    bench("call_global_void", || {
+        ctx.call_global_void("tick", &[&arg]).unwrap();
+    });
+
+    let tick = ctx.global_function("tick").unwrap();
+    bench("FunctionRef::call", || {
+        tick.call(&[&arg]).unwrap();
+    });
+
+    let args = ctx.args_builder().arg(&arg);
+    bench("FunctionRef::call_args", || {
+        tick.call_args(&args).unwrap();
+    });
+}
@@ -0,0 +1,29 @@
+//! Compares creating, using and dropping short-lived contexts with the system allocator and with
+//! the pool allocator.
+//!
+//! Run with `cargo run --release --example pool_bench`.
+
+extern crate duk;
+
+use std::time;
+
+const ITERATIONS: u32 = 1_000;
+
+fn bench<F>(name: &str, build: F)
+    where F: Fn() -> duk::Context
+{
+    let start = time::Instant::now();
+    for _ in 0..ITERATIONS {
+        let ctx = build();
+        ctx.eval_string("var a = []; for (var i = 0; i < 1000; i++) { a.push({s: 'x' + i}); }")
+            .unwrap();
+    }
+    let elapsed = start.elapsed();
+    let micros = elapsed.as_secs() * 1_000_000 + elapsed.subsec_micros() as u64;
+    println!("{:<24} {:>8} µs/context", name, micros / ITERATIONS as u64);
+}
+
+fn main() {
+    bench("system allocator", || duk::Context::builder().build());
+    bench("pool allocator", || duk::Context::builder().with_pool_allocator().build());
+}
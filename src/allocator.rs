@@ -0,0 +1,257 @@
+//! Custom memory allocators for the Duktape heap, see `ContextBuilder::with_allocator`.
+//!
+//! Devices without a general-purpose `malloc`, or with memory regions that scripts must stay
+//! within, can serve every allocation of a context from their own allocator.  Each context owns
+//! its allocator, and the allocator outlives the heap, so it may hand out memory from a fixed
+//! arena that it owns.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::alloc;
+//!
+//! /// Counts the bytes requested from the system allocator.
+//! struct Counting {
+//!     requested: usize,
+//! }
+//!
+//! fn layout(size: usize) -> alloc::Layout {
+//!     alloc::Layout::from_size_align(size + 16, 16).unwrap()
+//! }
+//!
+//! impl duk::allocator::Allocator for Counting {
+//!     unsafe fn alloc(&mut self, size: usize) -> *mut u8 {
+//!         self.requested += size;
+//!         let block = alloc::alloc(layout(size));
+//!         if block.is_null() {
+//!             return block;
+//!         }
+//!         *(block as *mut usize) = size;
+//!         block.add(16)
+//!     }
+//!
+//!     unsafe fn realloc(&mut self, data: *mut u8, size: usize) -> *mut u8 {
+//!         let old_size = if data.is_null() { 0 } else { *(data.sub(16) as *mut usize) };
+//!         duk::allocator::realloc_by_copy(self, data, size, old_size)
+//!     }
+//!
+//!     unsafe fn free(&mut self, data: *mut u8) {
+//!         if !data.is_null() {
+//!             let block = data.sub(16);
+//!             alloc::dealloc(block, layout(*(block as *mut usize)));
+//!         }
+//!     }
+//! }
+//!
+//! let ctx = duk::Context::builder().with_allocator(Box::new(Counting { requested: 0 })).build();
+//! ctx.eval_string("var list = [1, 2, 3].map(String);").unwrap();
+//! ```
+
+use std::alloc;
+use std::cmp;
+use std::os;
+use std::ptr;
+
+use duktape_sys;
+
+use HeapData;
+
+/// Serves the memory of a Duktape heap.
+///
+/// The methods follow the contract of `malloc`, `realloc` and `free`: returned memory must be
+/// aligned for any type (8 bytes is enough on most platforms), and null means that the
+/// allocation failed, which Duktape turns into an out of memory error.
+pub trait Allocator {
+    /// Allocates `size` bytes, or returns null.  Duktape never asks for zero bytes.
+    ///
+    /// # Safety
+    ///
+    /// Only Duktape calls this, from the thread that runs the context.
+    unsafe fn alloc(&mut self, size: usize) -> *mut u8;
+
+    /// Resizes an allocation, keeping its contents up to the smaller of the old and new sizes, or
+    /// returns null and leaves the allocation as it was.  `data` may be null, in which case this
+    /// allocates, and `size` may be zero, in which case this frees `data` and returns null.
+    ///
+    /// # Safety
+    ///
+    /// `data` is null or was returned by this allocator and not freed since.
+    unsafe fn realloc(&mut self, data: *mut u8, size: usize) -> *mut u8;
+
+    /// Frees an allocation.  `data` may be null, in which case this does nothing.
+    ///
+    /// # Safety
+    ///
+    /// `data` is null or was returned by this allocator and not freed since.
+    unsafe fn free(&mut self, data: *mut u8);
+}
+
+/// Implements `Allocator::realloc` for allocators that can't resize in place, by allocating,
+/// copying and freeing, given the size of the old allocation.
+///
+/// # Safety
+///
+/// `data` is null or was returned by `allocator` and not freed since, and `old_size` is its size.
+pub unsafe fn realloc_by_copy<A>(allocator: &mut A,
+                                 data: *mut u8,
+                                 size: usize,
+                                 old_size: usize)
+                                 -> *mut u8
+    where A: Allocator + ?Sized
+{
+    if size == 0 {
+        allocator.free(data);
+        return ptr::null_mut();
+    }
+    if data.is_null() {
+        return allocator.alloc(size);
+    }
+    let new_data = allocator.alloc(size);
+    if !new_data.is_null() {
+        ptr::copy_nonoverlapping(data, new_data, cmp::min(old_size, size));
+        allocator.free(data);
+    }
+    new_data
+}
+
+/// Serves allocations from the system allocator, up to a number of bytes in total, which caps the
+/// memory of contexts that run untrusted scripts.
+pub(crate) struct Budget {
+    used: usize,
+    limit: usize,
+}
+
+impl Budget {
+    pub(crate) fn new(limit: usize) -> Budget {
+        Budget { used: 0, limit }
+    }
+}
+
+fn budget_layout(size: usize) -> alloc::Layout {
+    alloc::Layout::from_size_align(size + 16, 16).unwrap()
+}
+
+impl Allocator for Budget {
+    unsafe fn alloc(&mut self, size: usize) -> *mut u8 {
+        if self.used + size > self.limit {
+            return ptr::null_mut();
+        }
+        // The size is kept in a header before the data, for `realloc` and `free`
+        let block = alloc::alloc(budget_layout(size));
+        if block.is_null() {
+            return block;
+        }
+        self.used += size;
+        *(block as *mut usize) = size;
+        block.add(16)
+    }
+
+    unsafe fn realloc(&mut self, data: *mut u8, size: usize) -> *mut u8 {
+        let old_size = if data.is_null() { 0 } else { *(data.sub(16) as *mut usize) };
+        realloc_by_copy(self, data, size, old_size)
+    }
+
+    unsafe fn free(&mut self, data: *mut u8) {
+        if !data.is_null() {
+            let block = data.sub(16);
+            let size = *(block as *mut usize);
+            self.used -= size;
+            alloc::dealloc(block, budget_layout(size));
+        }
+    }
+}
+
+/// Returns the allocator of the heap with the specified udata.
+unsafe fn heap_allocator<'a>(udata: *mut os::raw::c_void) -> &'a mut dyn Allocator {
+    &mut **(*(udata as *mut HeapData)).allocator.as_mut().unwrap()
+}
+
+/// Remembers a refused allocation for the hooks of the heap with the specified udata.
+unsafe fn checked(udata: *mut os::raw::c_void, size: usize, data: *mut u8) -> *mut os::raw::c_void {
+    if data.is_null() && size > 0 {
+        (*(udata as *mut HeapData)).allocation_failed = true;
+    }
+    data as *mut os::raw::c_void
+}
+
+pub(crate) unsafe extern "C" fn alloc_function(udata: *mut os::raw::c_void,
+                                               size: duktape_sys::duk_size_t)
+                                               -> *mut os::raw::c_void {
+    checked(udata, size, heap_allocator(udata).alloc(size))
+}
+
+pub(crate) unsafe extern "C" fn realloc_function(udata: *mut os::raw::c_void,
+                                                 data: *mut os::raw::c_void,
+                                                 size: duktape_sys::duk_size_t)
+                                                 -> *mut os::raw::c_void {
+    checked(udata, size, heap_allocator(udata).realloc(data as *mut u8, size))
+}
+
+pub(crate) unsafe extern "C" fn free_function(udata: *mut os::raw::c_void,
+                                              data: *mut os::raw::c_void) {
+    heap_allocator(udata).free(data as *mut u8)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use super::*;
+    use {Context, Value};
+
+    #[test]
+    fn custom_allocator() {
+        let _ = env_logger::init();
+        let ctx = Context::builder().with_allocator(Box::new(Budget::new(4 * 1024 * 1024))).build();
+        assert_eq!(Value::Number(6.0),
+                   ctx.eval_string("[1, 2, 3].reduce(function (a, b) { return a + b; })")
+                       .unwrap()
+                       .to_value());
+        // Exceeding the budget is an ordinary error
+        assert!(ctx.eval_string("var a = []; while (true) { a.push(String(a.length)); }")
+            .is_err());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn low_memory_heap_size() {
+        use std::cell;
+        use std::rc;
+
+        /// Budget that shares how much of it is used.
+        struct Shared(Budget, rc::Rc<cell::Cell<usize>>);
+
+        impl Allocator for Shared {
+            unsafe fn alloc(&mut self, size: usize) -> *mut u8 {
+                let data = self.0.alloc(size);
+                self.1.set(self.0.used);
+                data
+            }
+
+            unsafe fn realloc(&mut self, data: *mut u8, size: usize) -> *mut u8 {
+                let data = self.0.realloc(data, size);
+                self.1.set(self.0.used);
+                data
+            }
+
+            unsafe fn free(&mut self, data: *mut u8) {
+                self.0.free(data);
+                self.1.set(self.0.used);
+            }
+        }
+
+        let _ = env_logger::init();
+        let used = rc::Rc::new(cell::Cell::new(0));
+        let ctx = Context::builder()
+            .with_allocator(Box::new(Shared(Budget::new(usize::MAX / 2), used.clone())))
+            .build();
+        ctx.gc();
+        // A fresh context takes about 110 KB, or about 70 KB with the low-memory profile
+        if cfg!(feature = "low-memory") {
+            assert!(used.get() < 90 * 1000, "{} bytes", used.get());
+        } else {
+            assert!(used.get() > 90 * 1000, "{} bytes", used.get());
+        }
+        ctx.assert_clean();
+    }
+}
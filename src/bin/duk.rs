@@ -0,0 +1,203 @@
+//! Runs scripts with the same runtime that hosts embed, with the `cli` feature.
+//!
+//! Usage: `duk [-e SOURCE]... [FILE]...` or `duk bundle ENTRY [-o OUTPUT]`
+//!
+//! The sources given with `-e` and then the files are evaluated in order, after which the event
+//! loop runs until no timers are left.  Files with the `.dukb` extension are bundles, which are
+//! built by the `bundle` subcommand (see `duk::bundle`).  Without sources or files, an interactive
+//! loop reads from the standard input.  Scripts get the `console` (printing to the standard
+//! output, and warnings and errors to the standard error), the timer globals, and `require` for
+//! modules that are resolved relative to the requiring module (or the working directory) and read
+//! from disk.
+//!
+//! Install with `cargo install duk --features cli`.
+
+extern crate duk;
+
+use std::env;
+use std::error;
+use std::fs;
+use std::io;
+use std::path;
+use std::process;
+
+/// The number of timers that scripts can have scheduled at a time.
+const MAX_TIMERS: usize = 1024;
+
+const USAGE: &str = "usage: duk [-e SOURCE]... [FILE]...
+       duk bundle ENTRY [-o OUTPUT]
+
+Evaluates the sources and then the files in order, and runs the event loop until no timers are
+left.  Starts an interactive loop if there is nothing to evaluate.  Files ending in .dukb are run
+as bundles.
+
+The bundle subcommand compiles ENTRY and the modules it requires into a bundle, which is written
+to OUTPUT, or next to ENTRY with the .dukb extension.
+
+options:
+  -e, --eval SOURCE      evaluate SOURCE
+  -o, --output OUTPUT    write the bundle to OUTPUT
+  -h, --help             print this help";
+
+/// The extension of bundle files.
+const BUNDLE_EXTENSION: &str = "dukb";
+
+enum Command {
+    Run(Vec<Script>),
+    Bundle { entry: path::PathBuf, output: path::PathBuf },
+    Help,
+}
+
+enum Script {
+    Source(String),
+    File(path::PathBuf),
+}
+
+fn parse_args<I>(mut args: I) -> Result<Command, String>
+    where I: Iterator<Item = String>
+{
+    let mut args = args.by_ref().peekable();
+    if args.peek().is_some_and(|a| a == "bundle") {
+        args.next();
+        return parse_bundle_args(args);
+    }
+    let mut scripts = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-e" | "--eval" => {
+                let source = args.next().ok_or_else(|| format!("{} needs an argument", arg))?;
+                scripts.push(Script::Source(source));
+            }
+            "-h" | "--help" => return Ok(Command::Help),
+            "--" => scripts.extend(args.by_ref().map(|a| Script::File(a.into()))),
+            _ if arg.starts_with('-') => {
+                return Err(format!("unknown option {}", arg));
+            }
+            _ => scripts.push(Script::File(arg.into())),
+        }
+    }
+    Ok(Command::Run(scripts))
+}
+
+fn parse_bundle_args<I>(mut args: I) -> Result<Command, String>
+    where I: Iterator<Item = String>
+{
+    let mut entry = None;
+    let mut output = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => {
+                let path = args.next().ok_or_else(|| format!("{} needs an argument", arg))?;
+                output = Some(path::PathBuf::from(path));
+            }
+            "-h" | "--help" => return Ok(Command::Help),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ if entry.is_some() => return Err("bundle takes a single entry script".to_owned()),
+            _ => entry = Some(path::PathBuf::from(arg)),
+        }
+    }
+    let entry = entry.ok_or_else(|| "bundle needs an entry script".to_owned())?;
+    let output = output.unwrap_or_else(|| entry.with_extension(BUNDLE_EXTENSION));
+    Ok(Command::Bundle { entry, output })
+}
+
+/// Resolves a module id to a path: relative ids (starting with `./` or `../`) relative to the
+/// directory of the requiring module, others relative to the working directory.  A `.js`
+/// extension is added unless the id names an existing file.
+fn resolve(id: String, parent: String) -> String {
+    let base = if id.starts_with("./") || id.starts_with("../") {
+        path::Path::new(&parent).parent().map(path::Path::to_owned).unwrap_or_default()
+    } else {
+        path::PathBuf::new()
+    };
+    let mut resolved = base.join(&id);
+    if !resolved.is_file() && resolved.extension().is_none() {
+        resolved.set_extension("js");
+    }
+    resolved.to_string_lossy().into_owned()
+}
+
+fn print_console(level: duk::console::Level, message: &str) {
+    match level {
+        duk::console::Level::Warn | duk::console::Level::Error => eprintln!("{}", message),
+        _ => println!("{}", message),
+    }
+}
+
+/// Describes an error with the stack trace of a thrown error, and the path for a file that
+/// couldn't be read.
+fn describe(error: &duk::Error, path: Option<&path::Path>) -> String {
+    match (error.kind(), path) {
+        (&duk::ErrorKind::Js(duk::JsError { stack: Some(ref stack), .. }), _) => stack.clone(),
+        (&duk::ErrorKind::Io(_), Some(path)) => format!("duk: {}: {}", path.display(), error),
+        _ => format!("duk: {}", error),
+    }
+}
+
+fn run(scripts: &[Script]) -> Result<(), String> {
+    let ctx = duk::Context::builder()
+        .with_console_sink(Box::new(print_console))
+        .with_timers(MAX_TIMERS)
+        .with_module_resolver(Box::new(resolve))
+        .with_module_loader(Box::new(|id| fs::read(id).ok().map(|s| duk::sources::decode(&s))))
+        .build();
+
+    if scripts.is_empty() {
+        let stdin = io::stdin();
+        duk::repl::Repl::new(&ctx)
+            .run(stdin.lock(), io::stdout())
+            .map_err(|e| format!("duk: {}", e))?;
+    }
+    for script in scripts {
+        let result = match *script {
+            Script::Source(ref source) => ctx.eval_string_with_filename("eval", source),
+            Script::File(ref path) if path.extension().is_some_and(|e| e == BUNDLE_EXTENSION) => {
+                ctx.load_bundle(path)
+            }
+            Script::File(ref path) => ctx.eval_file(path),
+        };
+        if let Err(error) = result {
+            let path = match *script {
+                Script::File(ref path) => Some(path.as_path()),
+                Script::Source(_) => None,
+            };
+            return Err(describe(&error, path));
+        }
+    }
+    ctx.run_event_loop().map_err(|e| describe(&e, None))
+}
+
+fn bundle(entry: &path::Path, output: &path::Path) -> Result<(), String> {
+    let bundle = duk::bundle::Bundle::build(entry).map_err(|e| describe_chain(&e))?;
+    bundle.save(output).map_err(|e| describe(&e, Some(output)))
+}
+
+/// Describes an error and its causes, like a `Load` error for a module that didn't compile.
+fn describe_chain(error: &duk::Error) -> String {
+    let mut message = format!("duk: {}", error);
+    let mut cause = (error.1).0.as_ref().map(|c| &**c as &dyn error::Error);
+    while let Some(error) = cause {
+        message.push_str(&format!(": {}", error));
+        cause = error.source();
+    }
+    message
+}
+
+fn main() {
+    let result = match parse_args(env::args().skip(1)) {
+        Ok(Command::Run(scripts)) => run(&scripts),
+        Ok(Command::Bundle { entry, output }) => bundle(&entry, &output),
+        Ok(Command::Help) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(message) => {
+            eprintln!("duk: {}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(message) = result {
+        eprintln!("{}", message);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,283 @@
+//! Rust values exposed to scripts as Javascript objects, see `Context::register_object`.
+//!
+//! A bound object keeps a reference to a shared Rust value, like a service of the host, and its
+//! methods are native functions that call Rust methods of that value.  The value is the userdata
+//! of the object (see `ObjectRef::set_userdata`), as a `RefCell<T>`, and methods find it through
+//! `this`, so the receiver is threaded through like for any Javascript method:
+//! `service.status()` works, and so does `service.status.call(otherService)` with another object
+//! bound to a value of the same type.  Calling a method on anything else throws a `TypeError`.
+//!
+//! Methods registered with `method` borrow the value immutably, so they may call each other
+//! through the script, while methods registered with `method_mut` borrow it mutably, and throw
+//! if the value is already borrowed, like when the host holds a `RefMut` of it during the call.
+//!
+//! The object keeps the value alive until the object is garbage collected, or the context is
+//! dropped.  The host can keep its own `Rc` to share the value with the rest of the program.
+//!
+//! Scripts can also create Rust values themselves, through classes that `Context::register_class`
+//! defines: `new Database('app.db')` calls a Rust constructor with the arguments, binds the value
+//! that it returns to the new object, and the object inherits the methods of the class from its
+//! prototype.  Calling the class without `new` throws a `TypeError`, and so does a constructor
+//! that fails with a conversion error, like a wrong type of argument.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::cell;
+//! use std::rc;
+//!
+//! use duk::Value;
+//!
+//! struct Downloads {
+//!     fetched: Vec<String>,
+//! }
+//!
+//! let downloads = rc::Rc::new(cell::RefCell::new(Downloads { fetched: Vec::new() }));
+//! let ctx = duk::Context::new();
+//! ctx.register_object("downloads", downloads.clone(), |object| {
+//!     object.method_mut("fetch", 1, |downloads, args| {
+//!             downloads.fetched.push(format!("{:?}", args[0]));
+//!             Ok(Value::Undefined)
+//!         })
+//!         .method("status", 0, |downloads, _| {
+//!             Ok(Value::String(format!("{} fetched", downloads.fetched.len())))
+//!         });
+//! });
+//! let status = ctx.eval_string("downloads.fetch('a.txt'); downloads.status()").unwrap();
+//! assert_eq!(Value::String("1 fetched".to_owned()), status.to_value());
+//! assert_eq!(1, downloads.borrow().fetched.len());
+//! ```
+
+use std::cell;
+use std::marker;
+use std::rc;
+
+use duktape_sys;
+
+use native;
+use nul_str;
+use strings;
+use userdata;
+use {JsError, JsErrorKind, Result, Value};
+
+/// Adds methods to a bound object or the prototype of a class, see `Context::register_object` and
+/// `Context::register_class`.
+pub struct Methods<'a, T> {
+    ctx: *mut duktape_sys::duk_context,
+    value: marker::PhantomData<&'a T>,
+}
+
+impl<'a, T> Methods<'a, T>
+    where T: 'static
+{
+    /// Adds a method that gets `nargs` arguments and borrows the value immutably.
+    pub fn method<F>(&mut self, name: &str, nargs: usize, method: F) -> &mut Self
+        where F: Fn(&T, &[Value]) -> Result<Value> + 'static
+    {
+        self.add(name, nargs, move |value: &rc::Rc<cell::RefCell<T>>, args: &[Value]| {
+            match value.try_borrow() {
+                Ok(value) => method(&value, args),
+                Err(_) => Err(borrowed()),
+            }
+        })
+    }
+
+    /// Adds a method that gets `nargs` arguments and borrows the value mutably.
+    pub fn method_mut<F>(&mut self, name: &str, nargs: usize, method: F) -> &mut Self
+        where F: Fn(&mut T, &[Value]) -> Result<Value> + 'static
+    {
+        self.add(name, nargs, move |value: &rc::Rc<cell::RefCell<T>>, args: &[Value]| {
+            match value.try_borrow_mut() {
+                Ok(mut value) => method(&mut value, args),
+                Err(_) => Err(borrowed()),
+            }
+        })
+    }
+
+    fn add<F>(&mut self, name: &str, nargs: usize, method: F) -> &mut Self
+        where F: Fn(&rc::Rc<cell::RefCell<T>>, &[Value]) -> Result<Value> + 'static
+    {
+        let function = move |args: &native::Arguments| {
+            // Holding on to the value keeps it alive, even if the method drops its object
+            let value = args.this_userdata::<cell::RefCell<T>>()
+                .ok_or_else(|| JsError::new(JsErrorKind::Type, "method called on a wrong object"))?;
+            method(&value, args.values())
+        };
+        unsafe {
+            strings::push(self.ctx, name);
+            native::push(self.ctx, nargs as duktape_sys::duk_idx_t, Box::new(function));
+            duktape_sys::duk_put_prop(self.ctx, -3);
+        }
+        self
+    }
+}
+
+/// Binds the value to the object on top of the stack, and adds its methods.
+pub(crate) unsafe fn bind<T, F>(ctx: *mut duktape_sys::duk_context,
+                                value: rc::Rc<cell::RefCell<T>>,
+                                build: F)
+    where T: 'static,
+          F: FnOnce(&mut Methods<T>)
+{
+    let bound = userdata::set(ctx, -1, value);
+    // The object is a new one, which can always have a finalizer
+    debug_assert!(bound, "object can't have userdata");
+    build(&mut Methods {
+        ctx,
+        value: marker::PhantomData,
+    });
+}
+
+/// Pushes a class, whose constructor binds the value from `constructor` to new objects, and whose
+/// prototype has the methods that `build` adds.
+pub(crate) unsafe fn push_class<T, C, F>(ctx: *mut duktape_sys::duk_context,
+                                         name: &str,
+                                         nargs: usize,
+                                         constructor: C,
+                                         build: F)
+    where T: 'static,
+          C: Fn(&[Value]) -> Result<T> + 'static,
+          F: FnOnce(&mut Methods<T>)
+{
+    use duktape_sys::*;
+
+    let class = name.to_owned();
+    let function = move |args: &native::Arguments| {
+        if duk_is_constructor_call(args.ctx) == 0 {
+            let message = format!("class constructor {} cannot be invoked without 'new'", class);
+            return Err(JsError::new(JsErrorKind::Type, &message).into());
+        }
+        let value = constructor(args.values())?;
+        duk_push_this(args.ctx);
+        let bound = userdata::set(args.ctx, -1, rc::Rc::new(cell::RefCell::new(value)));
+        duk_pop(args.ctx);
+        if bound {
+            Ok(Value::Undefined)
+        } else {
+            Err(JsError::new(JsErrorKind::Type, "object can't have a bound value").into())
+        }
+    };
+    native::push(ctx, nargs as duk_idx_t, Box::new(function));
+    strings::push(ctx, "name");
+    strings::push(ctx, name);
+    duk_def_prop(ctx,
+                 -3,
+                 DUK_DEFPROP_HAVE_VALUE | DUK_DEFPROP_HAVE_WRITABLE | DUK_DEFPROP_HAVE_ENUMERABLE |
+                 DUK_DEFPROP_HAVE_CONFIGURABLE | DUK_DEFPROP_CONFIGURABLE);
+
+    duk_push_object(ctx);
+    build(&mut Methods {
+        ctx,
+        value: marker::PhantomData,
+    });
+    duk_dup(ctx, -2);
+    duk_put_prop_string(ctx, -2, nul_str(b"constructor\0"));
+    duk_put_prop_string(ctx, -2, nul_str(b"prototype\0"));
+}
+
+fn borrowed() -> ::Error {
+    JsError::new(JsErrorKind::Error, "bound value is already borrowed").into()
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use super::*;
+    use Context;
+
+    struct Counter {
+        count: u32,
+    }
+
+    #[test]
+    fn receivers() {
+        let _ = env_logger::init();
+        let first = rc::Rc::new(cell::RefCell::new(Counter { count: 0 }));
+        let second = rc::Rc::new(cell::RefCell::new(Counter { count: 10 }));
+        let ctx = Context::new();
+        for &(name, ref counter) in &[("first", &first), ("second", &second)] {
+            ctx.register_object(name, (*counter).clone(), |object| {
+                object.method_mut("increment", 1, |counter, args| {
+                        counter.count += match args[0] {
+                            Value::Number(n) => n as u32,
+                            _ => 1,
+                        };
+                        Ok(Value::Number(f64::from(counter.count)))
+                    })
+                    .method("count", 0, |counter, _| Ok(Value::Number(f64::from(counter.count))));
+            });
+        }
+        let result = ctx.eval_string(r"
+          function error(f) { try { f(); } catch (e) { return e.name; } }
+          first.increment();
+          second.increment(5);
+          [first.count(), second.count(), first.count.call(second),
+           error(function () { first.count.call({}); }),
+           error(function () { var count = first.count; count(); })]
+        ")
+            .unwrap()
+            .to_value();
+        assert_eq!(Value::Array(vec![Value::Number(1.0),
+                                     Value::Number(15.0),
+                                     Value::Number(15.0),
+                                     Value::String("TypeError".to_owned()),
+                                     Value::String("TypeError".to_owned())]),
+                   result);
+        assert_eq!(15, second.borrow().count);
+
+        // Mutable methods can't run while the host borrows the value
+        let borrowed = first.borrow();
+        assert!(ctx.eval_string("first.increment()").is_err());
+        ctx.eval_string("first.count()").unwrap();
+        drop(borrowed);
+
+        // The context drops its references when it is dropped
+        ctx.assert_clean();
+        drop(ctx);
+        assert_eq!(1, rc::Rc::strong_count(&first));
+        assert_eq!(1, rc::Rc::strong_count(&second));
+    }
+
+    #[test]
+    fn classes() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.register_class("Counter",
+                           1,
+                           |args| Ok(Counter { count: native::argument(args, 0, "start")? }),
+                           |class| {
+            class.method_mut("increment", 0, |counter, _| {
+                counter.count += 1;
+                Ok(Value::Number(f64::from(counter.count)))
+            });
+        });
+        let result = ctx.eval_string(r"
+          function error(f) { try { f(); } catch (e) { return e.name + ': ' + e.message; } }
+          var a = new Counter(1), b = new Counter(10);
+          a.increment();
+          [a.increment(), b.increment(), a instanceof Counter, a.constructor === Counter,
+           Counter.name, Object.keys(a).length, error(function () { Counter(1); }),
+           error(function () { new Counter('one'); }),
+           error(function () { Counter.prototype.increment(); })]
+        ")
+            .unwrap()
+            .to_value();
+        assert_eq!(Value::Array(vec![Value::Number(3.0),
+                                     Value::Number(11.0),
+                                     Value::Boolean(true),
+                                     Value::Boolean(true),
+                                     Value::String("Counter".to_owned()),
+                                     Value::Number(0.0),
+                                     Value::String("TypeError: class constructor Counter cannot \
+                                                    be invoked without 'new'"
+                                         .to_owned()),
+                                     Value::String("TypeError: argument 1 (start): expected a \
+                                                    number, got a string"
+                                         .to_owned()),
+                                     Value::String("TypeError: method called on a wrong object"
+                                         .to_owned())]),
+                   result);
+        ctx.assert_clean();
+    }
+}
@@ -0,0 +1,324 @@
+//! Buffer helpers that work directly on the memory of Duktape buffers, for scripts (as
+//! `host.buffer`) and for Rust (see `Context::concat_buffers` and friends).
+//!
+//! Every helper accepts plain buffers, `ArrayBuffer`s and typed array views alike:
+//!
+//!   * `concat(list, totalLength)` joins the buffers of the list into a new `Uint8Array`, which is
+//!     truncated or zero-filled to `totalLength` if that is given.
+//!   * `slice(buffer, start, end)` copies a range into a new `Uint8Array`, where negative indices
+//!     count from the end like `Array.prototype.slice`.
+//!   * `compare(a, b)` orders two buffers by their bytes, and returns -1, 0 or 1.
+//!   * `copy(source, target, targetStart, sourceStart, sourceEnd)` copies bytes into the target in
+//!     place, as many as fit, and returns how many were copied.
+//!
+//! The helpers throw a `TypeError` if they get something that isn't a buffer.
+
+use std::cmp;
+use std::os;
+use std::ptr;
+use std::slice;
+
+use duktape_sys;
+
+use nul_str;
+use push_host_object;
+
+/// Builds `host.buffer` from the natives, which return `undefined` for invalid arguments.
+const SETUP: &[u8] = br#"(function (concat, slice, compare, copy) {
+  function check(name, native, expected) {
+    return function () {
+      var result = native.apply(undefined, arguments);
+      if (result === undefined) {
+        throw new TypeError('host.buffer.' + name + ': ' + expected);
+      }
+      return result;
+    };
+  }
+  return {
+    concat: check('concat', concat, 'expected an array of buffers'),
+    slice: check('slice', slice, 'expected a buffer'),
+    compare: check('compare', compare, 'expected two buffers'),
+    copy: check('copy', copy, 'expected two buffers')
+  };
+})"#;
+
+/// Defines `host.buffer`.
+pub(crate) unsafe fn setup(ctx: *mut duktape_sys::duk_context) {
+    use duktape_sys::*;
+
+    let ret = duk_peval_lstring(ctx, SETUP.as_ptr() as *const os::raw::c_char, SETUP.len());
+    assert_eq!(0, ret, "failed to compile host.buffer");
+    duk_push_c_function(ctx, Some(concat_native), 2);
+    duk_push_c_function(ctx, Some(slice_native), 3);
+    duk_push_c_function(ctx, Some(compare_native), 2);
+    duk_push_c_function(ctx, Some(copy_native), 5);
+    let ret = duk_pcall(ctx, 4);
+    assert_eq!(0, ret, "failed to set up host.buffer");
+
+    push_host_object(ctx);
+    duk_swap_top(ctx, -2);
+    duk_put_prop_string(ctx, -2, nul_str(b"buffer\0"));
+    duk_pop(ctx);
+}
+
+/// Returns the data pointer and length of the buffer at the specified index, or `None` if it
+/// isn't a buffer.  The pointer is only valid while the buffer is neither resized nor collected.
+unsafe fn buffer_data(ctx: *mut duktape_sys::duk_context,
+                      index: duktape_sys::duk_idx_t)
+                      -> Option<(*mut u8, usize)> {
+    let mut len = 0;
+    let data = duktape_sys::duk_get_buffer_data(ctx, index, &mut len) as *mut u8;
+    if !data.is_null() {
+        Some((data, len))
+    } else if 1 == duktape_sys::duk_is_buffer(ctx, index) {
+        Some((ptr::null_mut(), 0))
+    } else {
+        None
+    }
+}
+
+/// Converts the optional index argument at the specified stack index into a position within a
+/// buffer of the specified length, counting negative indices from the end.
+unsafe fn position(ctx: *mut duktape_sys::duk_context,
+                   index: duktape_sys::duk_idx_t,
+                   len: usize,
+                   default: usize)
+                   -> usize {
+    if 1 == duktape_sys::duk_is_undefined(ctx, index) {
+        return default;
+    }
+    let n = duktape_sys::duk_to_number(ctx, index);
+    if n.is_nan() {
+        0
+    } else if n < 0.0 {
+        (len as f64 + n.ceil()).max(0.0) as usize
+    } else {
+        n.min(len as f64) as usize
+    }
+}
+
+/// Pushes a `Uint8Array` over the plain buffer on top of the stack in its place.
+unsafe fn wrap_in_view(ctx: *mut duktape_sys::duk_context) {
+    use duktape_sys::*;
+
+    let mut len = 0;
+    duk_get_buffer(ctx, -1, &mut len);
+    duk_push_buffer_object(ctx, -1, 0, len, DUK_BUFOBJ_UINT8ARRAY);
+    duk_remove(ctx, -2);
+}
+
+/// Pushes a new plain buffer with the bytes of all buffers in the array at the specified index,
+/// truncated or zero-filled to `total` bytes if that is given.  Returns false and pushes nothing if
+/// the value isn't an array of buffers.
+pub(crate) unsafe fn concat(ctx: *mut duktape_sys::duk_context,
+                            index: duktape_sys::duk_idx_t,
+                            total: Option<usize>)
+                            -> bool {
+    use duktape_sys::*;
+
+    if 0 == duk_is_array(ctx, index) {
+        return false;
+    }
+    let index = duk_normalize_index(ctx, index);
+    let count = duk_get_length(ctx, index);
+    let mut len = 0;
+    for i in 0..count {
+        duk_get_prop_index(ctx, index, i as duk_uarridx_t);
+        let part = buffer_data(ctx, -1);
+        duk_pop(ctx);
+        match part {
+            Some((_, part_len)) => len += part_len,
+            None => return false,
+        }
+    }
+
+    let len = total.unwrap_or(len);
+    let out = duk_push_fixed_buffer(ctx, len) as *mut u8;
+    let mut offset = 0;
+    for i in 0..count {
+        if offset == len {
+            break;
+        }
+        duk_get_prop_index(ctx, index, i as duk_uarridx_t);
+        if let Some((data, part_len)) = buffer_data(ctx, -1) {
+            let n = cmp::min(part_len, len - offset);
+            if n > 0 {
+                ptr::copy_nonoverlapping(data, out.add(offset), n);
+            }
+            offset += n;
+        }
+        duk_pop(ctx);
+    }
+    true
+}
+
+/// Orders the buffers at the specified indices by their bytes, or returns `None` if either isn't a
+/// buffer.
+pub(crate) unsafe fn compare(ctx: *mut duktape_sys::duk_context,
+                             a: duktape_sys::duk_idx_t,
+                             b: duktape_sys::duk_idx_t)
+                             -> Option<cmp::Ordering> {
+    match (buffer_data(ctx, a), buffer_data(ctx, b)) {
+        (Some(a), Some(b)) => Some(bytes(a).cmp(bytes(b))),
+        _ => None,
+    }
+}
+
+/// Copies bytes from the buffer at `source` (starting at `source_start`, up to `source_end`) into
+/// the buffer at `target` (starting at `target_start`), and returns how many were copied, or `None`
+/// if either isn't a buffer.  The buffers may overlap.
+pub(crate) unsafe fn copy(ctx: *mut duktape_sys::duk_context,
+                          source: duktape_sys::duk_idx_t,
+                          target: duktape_sys::duk_idx_t,
+                          target_start: usize,
+                          source_start: usize,
+                          source_end: usize)
+                          -> Option<usize> {
+    let (source, source_len) = buffer_data(ctx, source)?;
+    let (target, target_len) = buffer_data(ctx, target)?;
+    let source_end = cmp::min(source_end, source_len);
+    let target_start = cmp::min(target_start, target_len);
+    let n = cmp::min(source_end.saturating_sub(source_start), target_len - target_start);
+    if n > 0 {
+        ptr::copy(source.add(source_start), target.add(target_start), n);
+    }
+    Some(n)
+}
+
+unsafe fn bytes<'a>((data, len): (*mut u8, usize)) -> &'a [u8] {
+    if len == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(data, len)
+    }
+}
+
+/// `concat(list, totalLength)`
+unsafe extern "C" fn concat_native(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    let total = if 1 == duktape_sys::duk_is_undefined(ctx, 1) {
+        None
+    } else {
+        Some(duktape_sys::duk_to_number(ctx, 1).max(0.0) as usize)
+    };
+    if concat(ctx, 0, total) {
+        wrap_in_view(ctx);
+        1
+    } else {
+        0
+    }
+}
+
+/// `slice(buffer, start, end)`
+unsafe extern "C" fn slice_native(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    let len = match buffer_data(ctx, 0) {
+        Some((_, len)) => len,
+        None => return 0,
+    };
+    let start = position(ctx, 1, len, 0);
+    let end = cmp::max(start, position(ctx, 2, len, len));
+    duktape_sys::duk_push_fixed_buffer(ctx, end - start);
+    // Converting the positions may have run script code that resized the buffer
+    copy(ctx, 0, -1, 0, start, end);
+    wrap_in_view(ctx);
+    1
+}
+
+/// `compare(a, b)`
+unsafe extern "C" fn compare_native(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    match compare(ctx, 0, 1) {
+        Some(ordering) => {
+            duktape_sys::duk_push_int(ctx, ordering as duktape_sys::duk_int_t);
+            1
+        }
+        None => 0,
+    }
+}
+
+/// `copy(source, target, targetStart, sourceStart, sourceEnd)`
+unsafe extern "C" fn copy_native(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    let (source_len, target_len) = match (buffer_data(ctx, 0), buffer_data(ctx, 1)) {
+        (Some((_, source_len)), Some((_, target_len))) => (source_len, target_len),
+        _ => return 0,
+    };
+    let target_start = position(ctx, 2, target_len, 0);
+    let source_start = position(ctx, 3, source_len, 0);
+    let source_end = position(ctx, 4, source_len, source_len);
+    match copy(ctx, 0, 1, target_start, source_start, source_end) {
+        Some(n) => {
+            duktape_sys::duk_push_uint(ctx, n as duktape_sys::duk_uint_t);
+            1
+        }
+        None => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::cmp;
+
+    use {Context, Value};
+
+    #[test]
+    fn host_buffer() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.eval_string(r"
+          function bytes(b) {
+            var out = [];
+            for (var i = 0; i < b.length; i++) { out.push(b[i]); }
+            return out.join();
+          }
+          var a = new Uint8Array([1, 2, 3]), b = Duktape.dec('hex', '0405');
+        ")
+            .unwrap();
+
+        let check = |script: &str, expected: &str| {
+            assert_eq!(Value::String(expected.to_owned()),
+                       ctx.eval_string(script).unwrap().to_value(),
+                       "{}",
+                       script);
+        };
+        check("bytes(host.buffer.concat([a, b, new ArrayBuffer(1)]))", "1,2,3,4,5,0");
+        check("bytes(host.buffer.concat([a, b], 4)) + ';' + bytes(host.buffer.concat([b], 3))",
+              "1,2,3,4;4,5,0");
+        check("bytes(host.buffer.slice(a, 1)) + ';' + bytes(host.buffer.slice(a, -2, -1))",
+              "2,3;2");
+        check("bytes(host.buffer.slice(a, 2, 1))", "");
+        check("[host.buffer.compare(a, b), host.buffer.compare(b, a), \
+                host.buffer.compare(a, a.subarray(0))].join()",
+              "-1,1,0");
+        check("host.buffer.copy(b, a, 1) + ';' + bytes(a)", "2;1,4,5");
+        check("host.buffer.copy(a, a, 0, 1) + ';' + bytes(a)", "2;4,5,5");
+
+        let error = ctx.eval_string("host.buffer.concat([a, 'b'])").unwrap_err();
+        assert!(error.to_string().contains("expected an array of buffers"), "{}", error);
+        assert!(ctx.eval_string("host.buffer.compare(a)").is_err());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn rust_buffers() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let a = ctx.eval_string("new Uint8Array([1, 2])").unwrap();
+        let b = Value::Bytes(vec![3]);
+
+        let joined = ctx.concat_buffers(&[&a, &b, &a]).unwrap();
+        assert_eq!(Value::Bytes(vec![1, 2, 3, 1, 2]), joined.to_value());
+        assert_eq!(cmp::Ordering::Greater, ctx.compare_buffers(&joined, &a).unwrap());
+        assert_eq!(cmp::Ordering::Less, ctx.compare_buffers(&a, &b).unwrap());
+
+        assert_eq!(2, ctx.copy_buffer(&a, &joined, 3).unwrap());
+        assert_eq!(0, ctx.copy_buffer(&a, &joined, 5).unwrap());
+        assert_eq!(Value::Bytes(vec![1, 2, 3, 1, 2]), joined.to_value());
+        assert_eq!(1, ctx.copy_buffer(&b, &joined, 1).unwrap());
+        assert_eq!(Value::Bytes(vec![1, 3, 3, 1, 2]), joined.to_value());
+
+        assert!(ctx.concat_buffers(&[&a, &Value::Number(1.0)]).is_err());
+        assert!(ctx.compare_buffers(&a, &Value::Null).is_err());
+        drop((a, joined));
+        ctx.assert_clean();
+    }
+}
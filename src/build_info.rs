@@ -0,0 +1,196 @@
+//! The version and configuration of the embedded Duktape, see `duk::version` and
+//! `Context::build_info`.
+//!
+//! Hosts can use this to refuse plugins that need something the build lacks, like a newer Duktape
+//! or the `Proxy` built-in, with a clear message instead of a confusing script error.
+//!
+//! # Examples
+//!
+//! ```
+//! let ctx = duk::Context::new();
+//! let info = ctx.build_info();
+//! println!("Duktape {} ({})", info.version, info.env);
+//! if !info.has_feature("buffer-objects") {
+//!     println!("plugins that use typed arrays won't work");
+//! }
+//! ```
+
+use std::ffi;
+use std::fmt;
+
+use duktape_sys;
+
+use nul_str;
+use strings;
+
+/// The cargo features of this crate that affect what scripts can do, with whether they are
+/// enabled.
+const FEATURES: &[(&str, bool)] = &[("chrono", cfg!(feature = "chrono")),
+                                    ("clock", cfg!(feature = "clock")),
+                                    ("console", cfg!(feature = "console")),
+                                    ("crypto", cfg!(feature = "crypto")),
+                                    ("debugger", cfg!(feature = "debugger")),
+                                    ("encoding", cfg!(feature = "encoding")),
+                                    ("exec", cfg!(feature = "exec")),
+                                    ("fetch", cfg!(feature = "fetch")),
+                                    ("intl", cfg!(feature = "intl")),
+                                    ("logging", cfg!(feature = "logging")),
+                                    ("low-memory", cfg!(feature = "low-memory")),
+                                    ("profiler", cfg!(feature = "profiler")),
+                                    ("serde", cfg!(feature = "serde")),
+                                    ("timeout", cfg!(feature = "timeout")),
+                                    ("tracing", cfg!(feature = "tracing")),
+                                    ("url", cfg!(feature = "url")),
+                                    ("ws", cfg!(feature = "ws"))];
+
+/// A Duktape version.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Version {
+    /// The version as a single number, `major * 10000 + minor * 100 + patch`, which is convenient
+    /// for comparisons.  Pre-releases are numbered just below the release, like `19999` for 2.0.0.
+    pub number: u32,
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+    /// The output of `git describe` for the Duktape sources, like `v1.5.0-339-g091eb02`.
+    pub git_describe: &'static str,
+}
+
+/// How the embedded Duktape was configured.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BuildInfo {
+    pub version: Version,
+    /// The configuration summary of Duktape, as in `Duktape.env`, like
+    /// `ll u pn p1 a1 x64 linux gcc`.
+    pub env: String,
+    /// Whether numbers that are integers are stored as such, which speeds up integer arithmetic.
+    pub fastint: bool,
+    /// Whether the ES2015 `Proxy` built-in is available.
+    pub es6_proxy: bool,
+    /// Whether typed arrays, `ArrayBuffer` and `DataView` are available.
+    pub buffer_objects: bool,
+    /// Whether `String.fromCharCode` turns code points outside the BMP into surrogate pairs (CESU-8
+    /// internally), like other engines do, instead of into single non-standard characters.
+    pub cesu8: bool,
+    /// The `DUK_OPT_*` options that Duktape was compiled with, like `DUK_OPT_FASTINT` or
+    /// `DUK_OPT_STRTAB_CHAIN_SIZE=128`.  These come from the cargo features of `duktape-sys` and
+    /// from the `DUKTAPE_SYS_OPTIONS` environment variable at build time, like
+    /// `DUKTAPE_SYS_OPTIONS="NO_ES6_PROXY REFCOUNT16"`.
+    pub options: Vec<&'static str>,
+    /// The enabled cargo features of this crate that affect what scripts can do, like `console`
+    /// or `debugger`.
+    pub features: Vec<&'static str>,
+}
+
+/// Returns the version of the embedded Duktape.
+pub fn version() -> Version {
+    let number: u32 = unsafe { duktape_sys::DUK_VERSION };
+    let git_describe = unsafe { ffi::CStr::from_ptr(duktape_sys::DUK_GIT_DESCRIBE) };
+    Version {
+        number,
+        major: number / 10000,
+        minor: number / 100 % 100,
+        patch: number % 100,
+        git_describe: git_describe.to_str().unwrap_or(""),
+    }
+}
+
+impl BuildInfo {
+    /// Checks for a feature by name: the names of the boolean fields, with dashes instead of
+    /// underscores (like `es6-proxy`), and the names in `features`.
+    pub fn has_feature(&self, name: &str) -> bool {
+        match name {
+            "fastint" => self.fastint,
+            "es6-proxy" => self.es6_proxy,
+            "buffer-objects" => self.buffer_objects,
+            "cesu8" => self.cesu8,
+            name => self.features.contains(&name),
+        }
+    }
+
+    /// Checks whether Duktape was compiled with an option, by name with or without the
+    /// `DUK_OPT_` prefix and without its value, like `FASTINT` or `STRTAB_CHAIN_SIZE`.
+    pub fn has_option(&self, name: &str) -> bool {
+        let name = name.strip_prefix("DUK_OPT_").unwrap_or(name);
+        self.options.iter().any(|option| {
+            let option = option.split('=').next().unwrap_or(option);
+            option.strip_prefix("DUK_OPT_") == Some(name)
+        })
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Inspects the built-ins of a context to find out how Duktape was configured.
+pub(crate) unsafe fn probe(ctx: *mut duktape_sys::duk_context) -> BuildInfo {
+    use duktape_sys::*;
+
+    duk_get_global_string(ctx, nul_str(b"Duktape\0"));
+    duk_get_prop_string(ctx, -1, nul_str(b"env\0"));
+    let env = strings::get(ctx, -1);
+    duk_pop_2(ctx);
+    let fastint = env.split(' ').nth(1).is_some_and(|tval| tval.contains('f'));
+    let options = duktape_sys::CONFIG_OPTIONS.split(' ').filter(|o| !o.is_empty()).collect();
+
+    BuildInfo {
+        version: version(),
+        env,
+        fastint,
+        // The built-in exists either way, but its constructor throws when Proxy support is disabled
+        es6_proxy: check(ctx, b"typeof new Proxy({}, {}) === 'object'\0"),
+        buffer_objects: is_global_function(ctx, b"Uint8Array\0"),
+        cesu8: check(ctx, b"String.fromCharCode(0x10041).length === 2\0"),
+        options,
+        features: FEATURES.iter().filter(|f| f.1).map(|f| f.0).collect(),
+    }
+}
+
+/// Evaluates an expression that checks for a feature, which is missing if the expression throws.
+unsafe fn check(ctx: *mut duktape_sys::duk_context, expression: &[u8]) -> bool {
+    let result = duktape_sys::duk_peval_string(ctx, nul_str(expression)) == 0 &&
+                 duktape_sys::duk_get_boolean(ctx, -1) != 0;
+    duktape_sys::duk_pop(ctx);
+    result
+}
+
+unsafe fn is_global_function(ctx: *mut duktape_sys::duk_context, name: &[u8]) -> bool {
+    duktape_sys::duk_get_global_string(ctx, nul_str(name));
+    let result = duktape_sys::duk_is_function(ctx, -1) != 0;
+    duktape_sys::duk_pop(ctx);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use super::*;
+    use Context;
+
+    #[test]
+    fn build_info() {
+        let _ = env_logger::init();
+        let version = version();
+        assert_eq!(version.number, version.major * 10000 + version.minor * 100 + version.patch);
+        assert!(version.git_describe.starts_with('v'));
+
+        let ctx = Context::new();
+        let info = ctx.build_info();
+        assert_eq!(version, info.version);
+        assert_eq!(8, info.env.split(' ').count());
+        assert_eq!(!cfg!(feature = "no-es6-proxy"), info.has_feature("es6-proxy"));
+        assert!(info.has_feature("buffer-objects"));
+        assert_eq!(cfg!(feature = "logging"), info.has_feature("logging"));
+        assert!(!info.has_feature("teleportation"));
+        assert_eq!(cfg!(feature = "cesu8"), info.has_feature("cesu8"));
+        assert_eq!(cfg!(feature = "fastint"), info.fastint);
+        assert_eq!(cfg!(feature = "fastint"), info.has_option("FASTINT"));
+        assert_eq!(cfg!(feature = "debugger"), info.has_option("DUK_OPT_DEBUGGER_SUPPORT"));
+        assert!(!info.has_option("TELEPORTATION"));
+        ctx.assert_clean();
+    }
+}
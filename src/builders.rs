@@ -0,0 +1,197 @@
+//! Fluent builders for nested `Value`s, see `Value::object` and `Value::array`.
+//!
+//! The builders accept anything that converts into a `Value`, including other builders, so nested
+//! arguments can be written without spelling out `BTreeMap`s and `Vec`s.  Besides the chaining
+//! methods, `insert` and `push` add to a builder in place, for data whose shape is only known at
+//! runtime.
+//!
+//! # Examples
+//!
+//! ```
+//! use duk::Value;
+//!
+//! let ctx = duk::Context::new();
+//! ctx.eval_string("function describe(o) { return o.name + ': ' + o.tags.join(); }").unwrap();
+//! let mut tags = Value::array();
+//! for tag in &["a", "b"] {
+//!     tags.push(*tag);
+//! }
+//! let arg = Value::object()
+//!     .field("name", "x")
+//!     .field("tags", tags)
+//!     .field("size", Value::object().field("w", 2).field("h", 3))
+//!     .build();
+//! let value = ctx.call_global("describe", &[&arg]).unwrap().to_value();
+//! assert_eq!(Value::String("x: a,b".to_owned()), value);
+//! ```
+
+use std::collections;
+
+use Value;
+
+/// Builds a `Value::Object`, see `Value::object`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ObjectBuilder {
+    properties: collections::BTreeMap<String, Value>,
+}
+
+/// Builds a `Value::Array`, see `Value::array`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ArrayBuilder {
+    elements: Vec<Value>,
+}
+
+impl Value {
+    /// Starts building an object, which is empty until properties are added.
+    pub fn object() -> ObjectBuilder {
+        ObjectBuilder::default()
+    }
+
+    /// Starts building an array, which is empty until elements are added.
+    pub fn array() -> ArrayBuilder {
+        ArrayBuilder::default()
+    }
+}
+
+impl ObjectBuilder {
+    /// Adds a property, replacing an earlier property with the same name.
+    pub fn field<K, V>(mut self, name: K, value: V) -> ObjectBuilder
+        where K: Into<String>,
+              V: Into<Value>
+    {
+        self.insert(name, value);
+        self
+    }
+
+    /// Adds a property in place, replacing an earlier property with the same name.
+    pub fn insert<K, V>(&mut self, name: K, value: V) -> &mut ObjectBuilder
+        where K: Into<String>,
+              V: Into<Value>
+    {
+        self.properties.insert(name.into(), value.into());
+        self
+    }
+
+    /// The number of properties so far.
+    pub fn len(&self) -> usize {
+        self.properties.len()
+    }
+
+    /// Whether there are no properties yet.
+    pub fn is_empty(&self) -> bool {
+        self.properties.is_empty()
+    }
+
+    /// Finishes the object.
+    pub fn build(self) -> Value {
+        Value::Object(self.properties)
+    }
+}
+
+impl ArrayBuilder {
+    /// Appends an element.
+    pub fn element<V>(mut self, value: V) -> ArrayBuilder
+        where V: Into<Value>
+    {
+        self.push(value);
+        self
+    }
+
+    /// Appends an element in place.
+    pub fn push<V>(&mut self, value: V) -> &mut ArrayBuilder
+        where V: Into<Value>
+    {
+        self.elements.push(value.into());
+        self
+    }
+
+    /// The number of elements so far.
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    /// Whether there are no elements yet.
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    /// Finishes the array.
+    pub fn build(self) -> Value {
+        Value::Array(self.elements)
+    }
+}
+
+impl From<ObjectBuilder> for Value {
+    fn from(builder: ObjectBuilder) -> Value {
+        builder.build()
+    }
+}
+
+impl From<ArrayBuilder> for Value {
+    fn from(builder: ArrayBuilder) -> Value {
+        builder.build()
+    }
+}
+
+impl<V> Extend<V> for ArrayBuilder
+    where V: Into<Value>
+{
+    fn extend<I>(&mut self, iter: I)
+        where I: IntoIterator<Item = V>
+    {
+        self.elements.extend(iter.into_iter().map(Into::into));
+    }
+}
+
+impl<K, V> Extend<(K, V)> for ObjectBuilder
+    where K: Into<String>,
+          V: Into<Value>
+{
+    fn extend<I>(&mut self, iter: I)
+        where I: IntoIterator<Item = (K, V)>
+    {
+        self.properties.extend(iter.into_iter().map(|(k, v)| (k.into(), v.into())));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::collections;
+
+    use {Context, Value};
+
+    #[test]
+    fn builders() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let mut counts = Value::object();
+        counts.extend(vec![("b", 2), ("a", 1)]);
+        counts.insert("a", 3);
+        let mut list = Value::array().element(true).element(Value::array());
+        list.extend(vec!["x", "y"]);
+        let value = Value::object()
+            .field("counts", counts)
+            .field("list", list)
+            .field(String::from("empty"), Value::object())
+            .build();
+
+        let mut expected = collections::BTreeMap::new();
+        let mut counts = collections::BTreeMap::new();
+        counts.insert("a".to_owned(), Value::Number(3.0));
+        counts.insert("b".to_owned(), Value::Number(2.0));
+        expected.insert("counts".to_owned(), Value::Object(counts));
+        expected.insert("list".to_owned(),
+                        Value::Array(vec![Value::Boolean(true),
+                                          Value::Array(vec![]),
+                                          Value::String("x".to_owned()),
+                                          Value::String("y".to_owned())]));
+        expected.insert("empty".to_owned(), Value::Object(collections::BTreeMap::new()));
+        assert_eq!(Value::Object(expected), value);
+
+        ctx.eval_string("function identity(v) { return v; }").unwrap();
+        assert_eq!(value, ctx.call_global("identity", &[&value]).unwrap().to_value());
+        ctx.assert_clean();
+    }
+}
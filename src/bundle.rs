@@ -0,0 +1,489 @@
+//! Bytecode bundles of a script and the modules it requires, see `Bundle`.
+//!
+//! Building a bundle follows the `require` calls of the entry script, compiles every module it
+//! finds to Duktape bytecode, and records how each `require` call resolves.  Loading a bundle with
+//! `Context::load_bundle` then skips reading, parsing and compiling sources altogether, and runs
+//! the entry module with a `require` that serves the bundled modules.
+//!
+//! Modules are resolved like the `duk` binary does: ids starting with `./` or `../` relative to
+//! the requiring module, other ids relative to the directory of the entry script, and `.js` is
+//! appended unless the id names an existing file.  Only calls with a single string literal, like
+//! `require('./util')`, are found; other ids, including `host:` modules, are passed on to the
+//! `require` of the context at runtime.  Module ids are the paths relative to the directory of
+//! the entry script, and are used as the file names in stack traces.
+//!
+//! The package starts with a format version and records the version and configuration of the
+//! Duktape that compiled it, and loading checks that they match the context.  Bytecode is not
+//! validated otherwise, and Duktape may crash on bytecode that was tampered with, so only load
+//! bundles from trusted sources.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! let bundle = duk::bundle::Bundle::build("plugin/main.js").unwrap();
+//! bundle.save("plugin.dukb").unwrap();
+//!
+//! let ctx = duk::Context::new();
+//! let exports = ctx.load_bundle("plugin.dukb").unwrap();
+//! println!("{:?}", exports.to_value());
+//! ```
+
+use std::fs;
+use std::io;
+use std::os;
+use std::path;
+use std::ptr;
+use std::slice;
+
+use duktape_sys;
+
+use build_info;
+use sources;
+use strings;
+use {ChainErr, Context, ErrorKind, Result};
+
+/// The first bytes of every bundle.
+const MAGIC: &[u8; 8] = b"DUKBNDL\0";
+
+/// The version of the package format, which changes whenever the layout does.
+const FORMAT_VERSION: u32 = 1;
+
+/// Creates the function that loads a module by id, given the module functions and how the
+/// `require` calls of each module resolve.  Modules are cached by id, so that each runs once.
+const LOADER: &[u8] = b"(function (modules, links) {
+  var cache = {};
+  var fallback = typeof require === 'function' ? require : null;
+  function load(id) {
+    if (cache.hasOwnProperty(id)) {
+      return cache[id].exports;
+    }
+    var module = {id: id, exports: {}};
+    cache[id] = module;
+    var dirname = id.lastIndexOf('/') < 0 ? '' : id.substring(0, id.lastIndexOf('/'));
+    modules[id].call(module.exports, module.exports, requireFrom(id), module, id, dirname);
+    return module.exports;
+  }
+  function requireFrom(parent) {
+    return function require(id) {
+      if (links[parent].hasOwnProperty(id)) {
+        return load(links[parent][id]);
+      }
+      if (fallback === null) {
+        throw new Error('cannot find module: ' + id);
+      }
+      return fallback(id);
+    };
+  }
+  return load;
+})";
+
+/// A script and the modules it requires, compiled to bytecode.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Bundle {
+    duktape_version: u32,
+    duktape_env: String,
+    entry: String,
+    modules: Vec<Module>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Module {
+    id: String,
+    /// The ids passed to `require` by this module, and the ids of the modules they resolve to.
+    links: Vec<(String, String)>,
+    bytecode: Vec<u8>,
+}
+
+impl Bundle {
+    /// Builds a bundle of the specified entry script and every module it requires, directly or
+    /// indirectly.
+    ///
+    /// Fails with `ErrorKind::Load` for a module that can't be read or compiled, with the original
+    /// error as the cause.
+    pub fn build<P>(entry: P) -> Result<Bundle>
+        where P: AsRef<path::Path>
+    {
+        let entry = entry.as_ref();
+        let root = entry.parent().unwrap_or_else(|| path::Path::new(""));
+        let entry_id = entry.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| ErrorKind::Load(entry.display().to_string()))?;
+
+        let ctx = Context::try_new()?;
+        let info = ctx.build_info();
+        let mut modules: Vec<Module> = Vec::new();
+        let mut pending = vec![entry_id.clone()];
+        while let Some(id) = pending.pop() {
+            if modules.iter().any(|m| m.id == id) {
+                continue;
+            }
+            let path = root.join(&id);
+            let source = fs::read(&path)
+                .map(|bytes| sources::decode(&bytes))
+                .chain_err(|| ErrorKind::Load(path.display().to_string()))?;
+            let bytecode = unsafe { compile(&ctx, &id, &source) }
+                .chain_err(|| ErrorKind::Load(path.display().to_string()))?;
+            let links = required_ids(&source)
+                .into_iter()
+                .map(|request| {
+                    let resolved = resolve(root, &id, &request);
+                    (request, resolved)
+                })
+                .collect::<Vec<_>>();
+            pending.extend(links.iter().rev().map(|link| link.1.clone()));
+            modules.push(Module { id, links, bytecode });
+        }
+
+        Ok(Bundle {
+            duktape_version: info.version.number,
+            duktape_env: info.env,
+            entry: entry_id,
+            modules,
+        })
+    }
+
+    /// The id of the entry module.
+    pub fn entry(&self) -> &str {
+        &self.entry
+    }
+
+    /// The ids of the bundled modules, starting with the entry module.
+    pub fn module_ids(&self) -> Vec<&str> {
+        self.modules.iter().map(|m| m.id.as_str()).collect()
+    }
+
+    /// Reads a bundle from a file, see `read`.
+    pub fn open<P>(path: P) -> Result<Bundle>
+        where P: AsRef<path::Path>
+    {
+        Bundle::read(fs::File::open(path)?)
+    }
+
+    /// Writes the bundle to a file, see `write`.
+    pub fn save<P>(&self, path: P) -> Result<()>
+        where P: AsRef<path::Path>
+    {
+        self.write(io::BufWriter::new(fs::File::create(path)?))
+    }
+
+    /// Reads a bundle in the package format, or fails with `ErrorKind::InvalidBundle` if the data
+    /// isn't a bundle of a supported format version.
+    pub fn read<R>(mut reader: R) -> Result<Bundle>
+        where R: io::Read
+    {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let mut input = Input(&data);
+        if input.take(MAGIC.len())? != MAGIC {
+            return Err(ErrorKind::InvalidBundle("not a bundle".to_owned()).into());
+        }
+        let format_version = input.u32()?;
+        if format_version != FORMAT_VERSION {
+            let message = format!("unsupported format version {}", format_version);
+            return Err(ErrorKind::InvalidBundle(message).into());
+        }
+        let duktape_version = input.u32()?;
+        let duktape_env = input.string()?;
+        let entry = input.string()?;
+        let mut modules = Vec::new();
+        for _ in 0..input.u32()? {
+            let id = input.string()?;
+            let mut links = Vec::new();
+            for _ in 0..input.u32()? {
+                links.push((input.string()?, input.string()?));
+            }
+            let len = input.u32()? as usize;
+            let bytecode = input.take(len)?.to_vec();
+            modules.push(Module { id, links, bytecode });
+        }
+        if !input.0.is_empty() {
+            return Err(ErrorKind::InvalidBundle("trailing data".to_owned()).into());
+        }
+        Ok(Bundle { duktape_version, duktape_env, entry, modules })
+    }
+
+    /// Writes the bundle in the package format: the magic bytes `DUKBNDL\0`, then the format
+    /// version, the Duktape version and configuration, the entry id and the modules, with
+    /// little-endian 32-bit numbers and lengths.
+    pub fn write<W>(&self, mut writer: W) -> Result<()>
+        where W: io::Write
+    {
+        writer.write_all(MAGIC)?;
+        write_u32(&mut writer, FORMAT_VERSION)?;
+        write_u32(&mut writer, self.duktape_version)?;
+        write_bytes(&mut writer, self.duktape_env.as_bytes())?;
+        write_bytes(&mut writer, self.entry.as_bytes())?;
+        write_u32(&mut writer, self.modules.len() as u32)?;
+        for module in &self.modules {
+            write_bytes(&mut writer, module.id.as_bytes())?;
+            write_u32(&mut writer, module.links.len() as u32)?;
+            for (request, resolved) in &module.links {
+                write_bytes(&mut writer, request.as_bytes())?;
+                write_bytes(&mut writer, resolved.as_bytes())?;
+            }
+            write_bytes(&mut writer, &module.bytecode)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Checks that the bundle was compiled by the same Duktape as the context uses.
+pub(crate) unsafe fn check_compatible(ctx: *mut duktape_sys::duk_context,
+                                      bundle: &Bundle)
+                                      -> Result<()> {
+    let info = build_info::probe(ctx);
+    if bundle.duktape_version != info.version.number || bundle.duktape_env != info.env {
+        let message = format!("compiled for Duktape {} ({}), but the context has {} ({})",
+                              bundle.duktape_version,
+                              bundle.duktape_env,
+                              info.version.number,
+                              info.env);
+        return Err(ErrorKind::InvalidBundle(message).into());
+    }
+    Ok(())
+}
+
+/// Pushes the function that runs the entry module of the bundle, and its argument, for a call
+/// with 1 argument.
+pub(crate) unsafe fn push_run(ctx: *mut duktape_sys::duk_context, bundle: &Bundle) {
+    use duktape_sys::*;
+
+    duk_push_object(ctx);
+    for module in &bundle.modules {
+        let buf = duk_push_fixed_buffer(ctx, module.bytecode.len());
+        ptr::copy_nonoverlapping(module.bytecode.as_ptr(), buf as *mut u8, module.bytecode.len());
+        duk_load_function(ctx);
+        strings::push(ctx, &module.id);
+        duk_swap_top(ctx, -2);
+        duk_put_prop(ctx, -3);
+    }
+    duk_push_object(ctx);
+    for module in &bundle.modules {
+        strings::push(ctx, &module.id);
+        duk_push_object(ctx);
+        for (request, resolved) in &module.links {
+            strings::push(ctx, request);
+            strings::push(ctx, resolved);
+            duk_put_prop(ctx, -3);
+        }
+        duk_put_prop(ctx, -3);
+    }
+    push_loader(ctx);
+    strings::push(ctx, &bundle.entry);
+}
+
+/// Replaces the objects of module functions and of links by id on top of the stack with the
+/// function that loads a module by id, and returns its exports.
+pub(crate) unsafe fn push_loader(ctx: *mut duktape_sys::duk_context) {
+    use duktape_sys::*;
+
+    let ret = duk_peval_lstring(ctx, LOADER.as_ptr() as *const os::raw::c_char, LOADER.len());
+    assert_eq!(0, ret, "failed to compile the module loader");
+    duk_insert(ctx, -3);
+    let ret = duk_pcall(ctx, 2);
+    assert_eq!(0, ret, "failed to create the module loader");
+}
+
+/// Compiles the source of a module into the bytecode of a function that takes the module
+/// arguments, see `push_module`.
+unsafe fn compile(ctx: &Context, id: &str, source: &str) -> Result<Vec<u8>> {
+    use duktape_sys::*;
+
+    push_module(ctx, id, source)?;
+    duk_dump_function(ctx.raw);
+    let mut len = 0;
+    let data = duk_get_buffer(ctx.raw, -1, &mut len);
+    let bytecode = slice::from_raw_parts(data as *const u8, len).to_vec();
+    duk_pop(ctx.raw);
+    Ok(bytecode)
+}
+
+/// Compiles the source of a module into a function that takes the module arguments, and pushes
+/// it.  The function header is on the first line, so line numbers stay the same.
+pub(crate) unsafe fn push_module(ctx: &Context, id: &str, source: &str) -> Result<()> {
+    use duktape_sys::*;
+
+    let wrapped = format!("function (exports, require, module, __filename, __dirname) {{{}\n}}",
+                          source);
+    strings::push(ctx.raw, id);
+    let ret = duk_pcompile_lstring_filename(ctx.raw,
+                                            DUK_COMPILE_FUNCTION,
+                                            wrapped.as_ptr() as *const os::raw::c_char,
+                                            wrapped.len());
+    if ret != 0 {
+        return Err(ctx.pop_error());
+    }
+    Ok(())
+}
+
+/// Finds the ids of the `require` calls with a single string literal.
+pub(crate) fn required_ids(source: &str) -> Vec<String> {
+    let mut ids = Vec::new();
+    let mut rest = source;
+    while let Some(i) = rest.find("require") {
+        let before = source.len() - rest.len() + i;
+        let is_word = source[..before]
+            .chars()
+            .next_back()
+            .is_none_or(|c| !(c.is_alphanumeric() || c == '_' || c == '$' || c == '.'));
+        rest = &rest[i + "require".len()..];
+        if !is_word {
+            continue;
+        }
+        let call = rest.trim_start();
+        if !call.starts_with('(') {
+            continue;
+        }
+        let arg = call[1..].trim_start();
+        let quote = match arg.chars().next() {
+            Some(quote @ '\'') | Some(quote @ '"') => quote,
+            _ => continue,
+        };
+        if let Some(end) = arg[1..].find([quote, '\\', '\n']) {
+            let after = arg[1 + end..].chars().next();
+            if after == Some(quote) && arg[2 + end..].trim_start().starts_with(')') {
+                ids.push(arg[1..1 + end].to_owned());
+            }
+        }
+    }
+    ids
+}
+
+/// Resolves a required id to the id of the bundled module.
+pub(crate) fn resolve(root: &path::Path, parent: &str, request: &str) -> String {
+    let mut segments = Vec::new();
+    if request.starts_with("./") || request.starts_with("../") {
+        segments.extend(parent.split('/'));
+        segments.pop();
+    }
+    for segment in request.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." if segments.last().is_some_and(|&s| s != "..") => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let mut id = segments.join("/");
+    let path = root.join(&id);
+    if !path.is_file() && path.extension().is_none() {
+        id.push_str(".js");
+    }
+    id
+}
+
+/// The unread rest of a bundle.
+struct Input<'a>(&'a [u8]);
+
+impl<'a> Input<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(ErrorKind::InvalidBundle("truncated".to_owned()).into());
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| ErrorKind::InvalidBundle("invalid UTF-8".to_owned()).into())
+    }
+}
+
+fn write_u32<W>(writer: &mut W, n: u32) -> io::Result<()>
+    where W: io::Write
+{
+    writer.write_all(&n.to_le_bytes())
+}
+
+fn write_bytes<W>(writer: &mut W, bytes: &[u8]) -> io::Result<()>
+    where W: io::Write
+{
+    write_u32(writer, bytes.len() as u32)?;
+    writer.write_all(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::fs;
+
+    use super::*;
+    use testing::ScratchDir;
+    use {Context, ErrorKind, Value};
+
+    #[test]
+    fn required_ids_and_resolution() {
+        assert_eq!(vec!["./a", "b/c", "./d.js"],
+                   required_ids("var a = require('./a'), c = require ( \"b/c\" );\n\
+                                 x.require('no'); required('no'); require(name);\n\
+                                 require('./d.js'); require('e' + f); require('g\\'');"));
+        let root = path::Path::new("/nonexistent");
+        assert_eq!("lib/util.js", resolve(root, "main.js", "./lib/util"));
+        assert_eq!("util.js", resolve(root, "lib/a.js", "../util"));
+        assert_eq!("lib/b.js", resolve(root, "lib/a.js", "./b.js"));
+        assert_eq!("../shared.js", resolve(root, "main.js", "../shared"));
+        assert_eq!("lib/util.js", resolve(root, "lib/deep/a.js", "lib/util"));
+    }
+
+    #[test]
+    #[cfg_attr(target_os = "wasi", ignore = "needs a temporary directory")]
+    fn bundle_round_trip() {
+        let _ = env_logger::init();
+        let dir = ScratchDir::new("bundle");
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("main.js"),
+                  "var a = require('./lib/a');\nvar counter = require('./lib/counter');\n\
+                   exports.result = a.value + counter.count;\n\
+                   exports.fail = function () { throw new Error('failed in ' + __filename); };")
+            .unwrap();
+        fs::write(dir.join("lib/a.js"),
+                  "exports.value = require('./counter').count * 10;").unwrap();
+        fs::write(dir.join("lib/counter.js"),
+                  "runs = (typeof runs === 'number' ? runs : 0) + 1; exports.count = 2;")
+            .unwrap();
+
+        let bundle = Bundle::build(dir.join("main.js")).unwrap();
+        assert_eq!("main.js", bundle.entry());
+        assert_eq!(vec!["main.js", "lib/a.js", "lib/counter.js"], bundle.module_ids());
+        let mut data = Vec::new();
+        bundle.write(&mut data).unwrap();
+        let bundle = Bundle::read(&data[..]).unwrap();
+
+        let ctx = Context::new();
+        let exports = ctx.eval_bundle(&bundle).unwrap();
+        assert_eq!(Value::Number(22.0), exports.get("result").unwrap().to_value());
+        assert_eq!(Value::Number(1.0), ctx.eval_string("runs").unwrap().to_value());
+        let error = exports.call_method("fail", &[]).unwrap_err();
+        match *error.kind() {
+            ErrorKind::Js(ref error) => assert_eq!("failed in main.js", error.message),
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+
+        bundle.save(dir.join("main.dukb")).unwrap();
+        let exports = ctx.load_bundle(dir.join("main.dukb")).unwrap();
+        assert_eq!(Value::Number(22.0), exports.get("result").unwrap().to_value());
+
+        data[8] = 99;
+        assert_eq!("invalid bundle: unsupported format version 99",
+                   Bundle::read(&data[..]).unwrap_err().to_string());
+        assert_eq!("invalid bundle: not a bundle",
+                   Bundle::read(&b"function"[..]).unwrap_err().to_string());
+
+        fs::write(dir.join("lib/counter.js"), "exports.count = ;").unwrap();
+        let error = Bundle::build(dir.join("main.js")).unwrap_err();
+        assert_eq!(format!("failed to load {}", dir.join("lib/counter.js").display()),
+                   error.to_string());
+        ctx.assert_clean();
+    }
+}
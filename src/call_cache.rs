@@ -0,0 +1,173 @@
+//! A cache of the global functions that `Context::call_global` and its variants call, so that
+//! calling the same global function over and over doesn't look its name up every time.
+//!
+//! An entry remembers the function, the key of its name and where the global object keeps the
+//! property, and holds on to the key in the heap stash.  Before every use, the entry is checked
+//! against the global object without calling into Duktape, so assigning to, deleting or redefining
+//! the global invalidates it.  The cache doesn't hold on to the function, which is only used while
+//! the global object still does.
+
+use std::cell;
+use std::collections;
+use std::os;
+
+use duktape_sys;
+
+use nul_str;
+
+/// The key of the heap stash entry with the keys of the cache.
+const STASH_KEY: &[u8] = b"callCache\0";
+
+/// The number of names that the cache holds at most; once it is full, it starts over.
+const CAPACITY: usize = 64;
+
+struct Entry {
+    /// The index of the key in the array in the heap stash.
+    slot: u32,
+    /// The index of the entry of the property in the global object.
+    index: duktape_sys::duk_int_t,
+    key: *mut os::raw::c_void,
+    function: *mut os::raw::c_void,
+}
+
+/// The cached global functions of a context, by name.
+#[derive(Default)]
+pub(crate) struct CallCache {
+    entries: cell::RefCell<collections::HashMap<String, Entry>>,
+}
+
+impl CallCache {
+    /// Pushes the global function with the name if the cache holds it and the global object still
+    /// does too, and returns whether it did.
+    pub(crate) unsafe fn push(&self, ctx: *mut duktape_sys::duk_context, name: &str) -> bool {
+        use duktape_sys::*;
+
+        let entries = self.entries.borrow();
+        let entry = match entries.get(name) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let current = duktape_sys_global_is(ctx, entry.index, entry.key, entry.function) != 0;
+        if current {
+            duk_push_heapptr(ctx, entry.function);
+        }
+        current
+    }
+
+    /// Caches the global function with the name, whose key is at the index, if it is a callable
+    /// data property of the global object.
+    pub(crate) unsafe fn insert(&self,
+                                ctx: *mut duktape_sys::duk_context,
+                                name: &str,
+                                key_index: duktape_sys::duk_idx_t) {
+        use duktape_sys::*;
+
+        let key_index = duk_normalize_index(ctx, key_index);
+        let index = duktape_sys_find_global(ctx, key_index);
+        if index < 0 {
+            return;
+        }
+        // An own data property, so reading it doesn't run any script
+        duk_push_global_object(ctx);
+        duk_dup(ctx, key_index);
+        duk_get_prop(ctx, -2);
+        if duk_is_callable(ctx, -1) == 0 {
+            duk_pop_2(ctx);
+            return;
+        }
+
+        let mut entries = self.entries.borrow_mut();
+        let full = entries.len() >= CAPACITY && !entries.contains_key(name);
+        if full {
+            entries.clear();
+        }
+        let slot = entries.get(name).map_or(entries.len() as u32, |entry| entry.slot);
+        push_stash(ctx, full);
+        duk_dup(ctx, key_index);
+        duk_put_prop_index(ctx, -2, slot);
+        entries.insert(name.to_owned(),
+                       Entry {
+                           slot,
+                           index,
+                           key: duk_get_heapptr(ctx, key_index),
+                           function: duk_get_heapptr(ctx, -2),
+                       });
+        duk_pop_3(ctx);
+    }
+}
+
+/// Pushes the array with the keys of the cache, which is a new one if `clear` is set.
+unsafe fn push_stash(ctx: *mut duktape_sys::duk_context, clear: bool) {
+    use duktape_sys::*;
+
+    duk_push_heap_stash(ctx);
+    if clear || duk_get_prop_string(ctx, -1, nul_str(STASH_KEY)) == 0 {
+        if !clear {
+            duk_pop(ctx);
+        }
+        duk_push_array(ctx);
+        duk_dup_top(ctx);
+        duk_put_prop_string(ctx, -3, nul_str(STASH_KEY));
+    }
+    duk_remove(ctx, -2);
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use {Context, Value};
+
+    #[test]
+    fn invalidation() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.eval_string("function tick(n) { return n + 1; }").unwrap();
+        for i in 0..3 {
+            assert_eq!(Value::Number(i as f64 + 1.0),
+                       ctx.call_global("tick", &[&(i as f64)]).unwrap().to_value());
+        }
+
+        // Assigning to the global replaces the cached function, even with one that isn't callable
+        ctx.eval_string("tick = function (n) { return n - 1; };").unwrap();
+        assert_eq!(Value::Number(1.0), ctx.call_global("tick", &[&2]).unwrap().to_value());
+        ctx.eval_string("var saved = tick; tick = {};").unwrap();
+        assert!(ctx.call_global("tick", &[&2]).is_err());
+        ctx.eval_string("tick = saved;").unwrap();
+        assert_eq!(Value::Number(1.0), ctx.call_global("tick", &[&2]).unwrap().to_value());
+
+        // Growing the global object moves its properties around
+        ctx.eval_string("for (var i = 0; i < 1000; i++) { this['g' + i] = i; }").unwrap();
+        assert_eq!(Value::Number(1.0), ctx.call_global("tick", &[&2]).unwrap().to_value());
+        ctx.eval_string("tick = function (n) { return n * 10; };").unwrap();
+        assert_eq!(Value::Number(20.0), ctx.call_global("tick", &[&2]).unwrap().to_value());
+
+        // So do deleting and redefining it, even as an accessor
+        ctx.eval_string("delete tick;").unwrap();
+        assert!(ctx.call_global("tick", &[&2]).is_err());
+        ctx.eval_string("Object.defineProperty(this, 'tick', { get: function () { \
+                           return function (n) { return -n; }; }, configurable: true });")
+            .unwrap();
+        assert_eq!(Value::Number(-2.0), ctx.call_global("tick", &[&2]).unwrap().to_value());
+        ctx.eval_string("delete tick; var tick = function () { return 'again'; };").unwrap();
+        assert_eq!(Value::String("again".to_owned()),
+                   ctx.call_global("tick", &[]).unwrap().to_value());
+
+        // Functions that scripts replace while they run
+        ctx.eval_string("function swap() { swap = function () { return 2; }; return 1; }").unwrap();
+        assert_eq!(Value::Number(1.0), ctx.call_global("swap", &[]).unwrap().to_value());
+        assert_eq!(Value::Number(2.0), ctx.call_global("swap", &[]).unwrap().to_value());
+
+        // More names than the cache holds
+        let code = "for (var i = 0; i < 200; i++) { this['f' + i] = Function('return ' + i); }";
+        ctx.eval_string(code).unwrap();
+        for _ in 0..2 {
+            for i in 0..200 {
+                let name = format!("f{}", i);
+                let result = ctx.call_global(&name, &[]).unwrap();
+                assert_eq!(Value::Number(i as f64), result.to_value());
+            }
+        }
+        ctx.assert_clean();
+    }
+}
@@ -0,0 +1,342 @@
+//! A census of the values on the Javascript heap, see `Context::heap_census`.
+//!
+//! The census walks the heap starting from the global object, the stashes and the value stack of
+//! the context, following all own properties (including non-enumerable and internal ones, like
+//! the variables captured by closures) and prototypes.  Accessors are never invoked, so taking a
+//! census doesn't run any user code.
+//!
+//! Sizes are the ones reported by `Duktape.info()`: the header and property table of objects, and
+//! the header and data of strings and buffers.  Values that are only reachable from local
+//! variables of functions that are currently running, and the backing buffers of buffer objects,
+//! are not counted.
+
+use std::collections;
+use std::os;
+use std::slice;
+use std::str;
+
+use duktape_sys;
+
+use nul_str;
+
+/// The number and approximate total size of a group of heap values.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Usage {
+    /// The number of values in the group.
+    pub count: usize,
+    /// The approximate number of bytes used by the values in the group.
+    pub bytes: usize,
+}
+
+/// The values that are reachable on the Javascript heap of a context.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct HeapCensus {
+    /// Objects that are not functions, including arrays and buffer objects.
+    pub objects: Usage,
+    /// Javascript and native functions.
+    pub functions: Usage,
+    /// Strings, including property names.
+    pub strings: Usage,
+    /// Plain buffers.
+    pub buffers: Usage,
+    /// Objects and functions grouped by the name of the constructor of their prototype (like
+    /// `Array` or a user-defined `Foo`), or by their internal class name if the constructor
+    /// doesn't have a name.
+    pub by_class: collections::BTreeMap<String, Usage>,
+}
+
+impl Usage {
+    fn add(&mut self, bytes: usize) {
+        self.count += 1;
+        self.bytes += bytes;
+    }
+}
+
+impl HeapCensus {
+    /// The combined usage of all objects, functions, strings and buffers.
+    pub fn total(&self) -> Usage {
+        Usage {
+            count: self.objects.count + self.functions.count + self.strings.count +
+                   self.buffers.count,
+            bytes: self.objects.bytes + self.functions.bytes + self.strings.bytes +
+                   self.buffers.bytes,
+        }
+    }
+}
+
+/// Returns the own property values of an object (or the getter and setter of an accessor) without
+/// invoking any accessors.
+const PROPERTY_VALUES: &[u8] = b"(function (o, k) {
+  var d = Object.getOwnPropertyDescriptor(o, k);
+  return !d ? [] : 'value' in d ? [d.value] : [d.get, d.set];
+})";
+
+/// Returns the name that an object is grouped by.
+const CLASS_NAME: &[u8] = b"(function (o) {
+  var p = Object.getPrototypeOf(o);
+  var d = p && Object.getOwnPropertyDescriptor(p, 'constructor');
+  var c = d && d.value;
+  if (typeof c === 'function' && c.prototype === p && typeof c.name === 'string' && c.name) {
+    return c.name;
+  }
+  return Object.prototype.toString.call(o).slice(8, -1);
+})";
+
+struct Walk {
+    census: HeapCensus,
+    visited: collections::HashSet<*mut os::raw::c_void>,
+    pending: Vec<*mut os::raw::c_void>,
+    info_idx: duktape_sys::duk_idx_t,
+    values_idx: duktape_sys::duk_idx_t,
+    class_idx: duktape_sys::duk_idx_t,
+}
+
+/// Takes a census of the heap of the specified context, leaving its value stack untouched.
+pub(crate) unsafe fn take(ctx: *mut duktape_sys::duk_context) -> HeapCensus {
+    use duktape_sys::*;
+
+    let top = duk_get_top(ctx);
+    duk_require_stack(ctx, 16);
+
+    duk_get_global_string(ctx, nul_str(b"Duktape\0"));
+    duk_get_prop_string(ctx, -1, nul_str(b"info\0"));
+    duk_remove(ctx, -2);
+    push_helper(ctx, PROPERTY_VALUES);
+    push_helper(ctx, CLASS_NAME);
+
+    let mut walk = Walk {
+        census: HeapCensus::default(),
+        visited: collections::HashSet::new(),
+        pending: Vec::new(),
+        info_idx: top,
+        values_idx: top + 1,
+        class_idx: top + 2,
+    };
+
+    for i in 0..top {
+        duk_dup(ctx, i);
+        walk.visit(ctx);
+    }
+    duk_push_global_object(ctx);
+    walk.visit(ctx);
+    duk_push_heap_stash(ctx);
+    walk.visit(ctx);
+    duk_push_global_stash(ctx);
+    walk.visit(ctx);
+
+    while let Some(ptr) = walk.pending.pop() {
+        duk_push_heapptr(ctx, ptr);
+        walk.scan(ctx);
+        duk_pop(ctx);
+    }
+
+    duk_set_top(ctx, top);
+    walk.census
+}
+
+unsafe fn push_helper(ctx: *mut duktape_sys::duk_context, source: &[u8]) {
+    let ret = duktape_sys::duk_peval_lstring(ctx, source.as_ptr() as *const os::raw::c_char,
+                                             source.len());
+    assert_eq!(0, ret, "failed to compile census helper");
+}
+
+impl Walk {
+    /// Counts the value on top of the stack if it hasn't been seen yet, and pops it.
+    unsafe fn visit(&mut self, ctx: *mut duktape_sys::duk_context) {
+        use duktape_sys::*;
+
+        let t = duk_get_type(ctx, -1);
+        if t == DUK_TYPE_STRING || t == DUK_TYPE_OBJECT || t == DUK_TYPE_BUFFER {
+            let ptr = duk_get_heapptr(ctx, -1);
+            if !ptr.is_null() && self.visited.insert(ptr) {
+                if t == DUK_TYPE_STRING {
+                    let bytes = self.size(ctx, false);
+                    self.census.strings.add(bytes);
+                } else if t == DUK_TYPE_BUFFER {
+                    let bytes = self.size(ctx, false);
+                    self.census.buffers.add(bytes);
+                } else {
+                    let bytes = self.size(ctx, true);
+                    if duk_is_function(ctx, -1) == 1 {
+                        self.census.functions.add(bytes);
+                    } else {
+                        self.census.objects.add(bytes);
+                    }
+                    let class = self.class_name(ctx);
+                    self.census.by_class.entry(class).or_default().add(bytes);
+                    self.pending.push(ptr);
+                }
+            }
+        }
+        duk_pop(ctx);
+    }
+
+    /// Visits the prototype and all own property keys and values of the object on top of the
+    /// stack.
+    unsafe fn scan(&mut self, ctx: *mut duktape_sys::duk_context) {
+        use duktape_sys::*;
+
+        let obj_idx = duk_get_top(ctx) - 1;
+        duk_get_prototype(ctx, obj_idx);
+        self.visit(ctx);
+
+        // Enumerators keep their own state in these internal properties, so enumerating an
+        // object that has them (like a bound function) including internal properties fails
+        let mut flags = DUK_ENUM_OWN_PROPERTIES_ONLY | DUK_ENUM_INCLUDE_NONENUMERABLE |
+                        DUK_ENUM_NO_PROXY_BEHAVIOR;
+        let mut clashes = false;
+        for key in &[&b"\xffTarget"[..], &b"\xffNext"[..]] {
+            duk_push_lstring(ctx, key.as_ptr() as *const os::raw::c_char, key.len());
+            clashes |= self.visit_property(ctx, obj_idx);
+            duk_pop(ctx);
+        }
+        if !clashes {
+            flags |= DUK_ENUM_INCLUDE_INTERNAL;
+        }
+
+        duk_enum(ctx, obj_idx, flags);
+        let enum_idx = obj_idx + 1;
+        while duk_next(ctx, enum_idx, 0) != 0 {
+            // Array indices are not stored as strings, the enumerator creates them on the fly
+            if !is_array_index(ctx) {
+                duk_dup(ctx, -1);
+                self.visit(ctx);
+            }
+            self.visit_property(ctx, obj_idx);
+            duk_pop(ctx);
+        }
+        duk_pop(ctx);
+    }
+
+    /// Visits the value (or getter and setter) of the own property of the object at the specified
+    /// index whose key is on top of the stack, and returns whether the property exists.
+    unsafe fn visit_property(&mut self,
+                             ctx: *mut duktape_sys::duk_context,
+                             obj_idx: duktape_sys::duk_idx_t)
+                             -> bool {
+        use duktape_sys::*;
+
+        duk_dup(ctx, self.values_idx);
+        duk_dup(ctx, obj_idx);
+        duk_dup(ctx, -3);
+        // Stack: [ ... key values obj key ]
+        let mut exists = false;
+        if duk_pcall(ctx, 2) == 0 && duk_is_array(ctx, -1) == 1 {
+            let len = duk_get_length(ctx, -1);
+            exists = len > 0;
+            for i in 0..len {
+                duk_get_prop_index(ctx, -1, i as duk_uarridx_t);
+                self.visit(ctx);
+            }
+        }
+        duk_pop(ctx);
+        exists
+    }
+
+    /// Returns the size in bytes of the value on top of the stack, according to `Duktape.info()`.
+    unsafe fn size(&self, ctx: *mut duktape_sys::duk_context, object: bool) -> usize {
+        use duktape_sys::*;
+
+        duk_dup(ctx, self.info_idx);
+        duk_dup(ctx, -2);
+        if duk_pcall(ctx, 1) != 0 {
+            duk_pop(ctx);
+            return 0;
+        }
+
+        // The info array is [type, address, refcount, sizes...].  Objects have their header and
+        // property table sizes at 3 and 4, followed by table dimensions and, for compiled
+        // functions, the size of the bytecode at 9.  Strings and buffers only have sizes.
+        let indices: &[duk_uarridx_t] = if object { &[3, 4, 9] } else { &[3, 4] };
+        let mut bytes = 0;
+        for &i in indices {
+            duk_get_prop_index(ctx, -1, i);
+            bytes += duk_get_uint(ctx, -1) as usize;
+            duk_pop(ctx);
+        }
+        duk_pop(ctx);
+        bytes
+    }
+
+    /// Returns the name of the group of the object on top of the stack.
+    unsafe fn class_name(&self, ctx: *mut duktape_sys::duk_context) -> String {
+        use duktape_sys::*;
+
+        duk_dup(ctx, self.class_idx);
+        duk_dup(ctx, -2);
+        let name = if duk_pcall(ctx, 1) == 0 && duk_is_string(ctx, -1) == 1 {
+            let mut len = 0;
+            let data = duk_get_lstring(ctx, -1, &mut len);
+            String::from_utf8_lossy(slice::from_raw_parts(data as *const u8, len)).into_owned()
+        } else {
+            "Object".to_owned()
+        };
+        duk_pop(ctx);
+        name
+    }
+}
+
+/// Checks whether the property key on top of the stack is an array index.
+unsafe fn is_array_index(ctx: *mut duktape_sys::duk_context) -> bool {
+    let mut len = 0;
+    let data = duktape_sys::duk_get_lstring(ctx, -1, &mut len);
+    if data.is_null() {
+        return false;
+    }
+    let key = slice::from_raw_parts(data as *const u8, len);
+    match str::from_utf8(key).ok().and_then(|k| k.parse::<u32>().ok()) {
+        Some(index) => index != u32::MAX && index.to_string().as_bytes() == key,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use Context;
+
+    #[test]
+    fn heap_census() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let before = ctx.heap_census();
+
+        ctx.eval_string(r"
+          function Widget(name) { this.name = name; }
+          var widgets = [];
+          for (var i = 0; i < 100; i++) {
+            widgets.push(new Widget('widget number ' + i));
+          }
+        ")
+            .unwrap();
+        let after = ctx.heap_census();
+
+        assert_eq!(None, before.by_class.get("Widget"));
+        let widgets = after.by_class["Widget"];
+        assert_eq!(100, widgets.count);
+        assert!(widgets.bytes > 0);
+        assert!(after.objects.count >= before.objects.count + 100);
+        assert!(after.strings.count >= before.strings.count + 100);
+        assert!(after.functions.count > before.functions.count);
+        assert!(after.total().bytes > before.total().bytes);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn heap_census_closures() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.eval_string(r"
+          var handlers = (function () {
+            var cache = [new Date(0), new Date(1)];
+            return { get: function () { return cache; } };
+          })();
+        ")
+            .unwrap();
+
+        let census = ctx.heap_census();
+        assert_eq!(2, census.by_class["Date"].count);
+        ctx.assert_clean();
+    }
+}
@@ -0,0 +1,80 @@
+//! Custom time sources for `Date`, see `ContextBuilder::with_clock`, with the `clock` feature.
+//!
+//! Without a clock, `Date.now()` and `new Date()` read the system time.  Devices without a
+//! real-time clock, or with one that is only reachable through their own drivers, can provide the
+//! time instead, and tests can pin it to get reproducible dates.
+//!
+//! # Examples
+//!
+//! ```
+//! let ctx = duk::Context::builder().with_clock(Box::new(|| 1_500_000_000_000.0)).build();
+//! let year = ctx.eval_string("new Date().getUTCFullYear()").unwrap().to_value();
+//! assert_eq!(duk::Value::Number(2017.0), year);
+//! ```
+
+use std::time;
+
+use duktape_sys;
+
+use HeapData;
+
+/// Provides the current time.
+pub trait Clock {
+    /// The number of milliseconds since the Unix epoch, which may have a fractional part.
+    fn now(&self) -> f64;
+}
+
+impl<F> Clock for F
+    where F: Fn() -> f64
+{
+    fn now(&self) -> f64 {
+        self()
+    }
+}
+
+/// The system time, which is what contexts without a clock use.
+pub fn system_now() -> f64 {
+    match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as f64 * 1000.0 + since.subsec_nanos() as f64 / 1e6,
+        Err(_) => 0.0,
+    }
+}
+
+/// The `DUK_USE_DATE_GET_NOW` hook of Duktape, which reads the clock of the heap.
+pub(crate) unsafe fn date_get_now(ctx: *mut duktape_sys::duk_context) -> f64 {
+    let mut funcs = duktape_sys::duk_memory_functions::default();
+    duktape_sys::duk_get_memory_functions(ctx, &mut funcs);
+    match (*(funcs.udata as *mut HeapData)).clock {
+        Some(ref clock) => clock.now(),
+        None => system_now(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::cell;
+    use std::rc;
+
+    use {Context, Value};
+
+    #[test]
+    fn custom_clock() {
+        let _ = env_logger::init();
+        let now = rc::Rc::new(cell::Cell::new(86_400_000.0));
+        let clock = now.clone();
+        let ctx = Context::builder().with_clock(Box::new(move || clock.get())).build();
+        assert_eq!(Value::Number(86_400_000.0), ctx.eval_string("Date.now()").unwrap().to_value());
+        now.set(now.get() + 1500.0);
+        assert_eq!(Value::String("1970-01-02T00:00:01.500Z".to_owned()),
+                   ctx.eval_string("new Date().toISOString()").unwrap().to_value());
+
+        // Other contexts keep using the system time
+        let other = Context::new();
+        assert_eq!(Value::Boolean(true),
+                   other.eval_string("new Date().getUTCFullYear() > 2000").unwrap().to_value());
+        ctx.assert_clean();
+        other.assert_clean();
+    }
+}
@@ -0,0 +1,197 @@
+//! Base64 and hex encoding with Duktape's built-in codecs, for Rust (see
+//! `Context::encode_base64` and friends) and for scripts.
+//!
+//! Scripts get the global `btoa` and `atob` functions, which convert between binary strings (where
+//! every character is a byte) and base64.  Buffers can be encoded with `Duktape.enc('base64',
+//! ...)` and `Duktape.enc('hex', ...)`, and decoded with `Duktape.dec`.
+
+use std::ffi;
+use std::os;
+use std::ptr;
+use std::slice;
+use std::str;
+
+use duktape_sys;
+
+use nul_str;
+
+/// Defines `btoa` and `atob`, given the codec functions and the native conversions between binary
+/// strings and buffers.
+const SETUP: &[u8] = b"(function (enc, dec, toBytes, toBinary) {
+  return [
+    function btoa(data) {
+      var bytes = toBytes(String(data));
+      if (bytes === undefined) {
+        throw new TypeError('btoa: the string contains characters outside of latin1');
+      }
+      return enc('base64', bytes);
+    },
+    function atob(data) {
+      var s = String(data).replace(/[\\t\\n\\f\\r ]/g, '');
+      if (s.length % 4 === 2 || s.length % 4 === 3) {
+        s += s.length % 4 === 2 ? '==' : '=';
+      }
+      try {
+        return toBinary(dec('base64', s));
+      } catch (e) {
+        throw new TypeError('atob: the string is not valid base64');
+      }
+    }
+  ];
+})";
+
+/// Defines the global `btoa` and `atob` functions.
+pub unsafe fn setup(ctx: *mut duktape_sys::duk_context) {
+    use duktape_sys::*;
+
+    let ret = duk_peval_lstring(ctx, SETUP.as_ptr() as *const os::raw::c_char, SETUP.len());
+    assert_eq!(0, ret, "failed to compile btoa and atob");
+    duk_get_global_string(ctx, nul_str(b"Duktape\0"));
+    duk_get_prop_string(ctx, -1, nul_str(b"enc\0"));
+    duk_get_prop_string(ctx, -2, nul_str(b"dec\0"));
+    duk_remove(ctx, -3);
+    duk_push_c_function(ctx, Some(binary_to_bytes), 1);
+    duk_push_c_function(ctx, Some(bytes_to_binary), 1);
+    let ret = duk_pcall(ctx, 4);
+    assert_eq!(0, ret, "failed to set up btoa and atob");
+
+    duk_get_prop_index(ctx, -1, 0);
+    duk_put_global_string(ctx, nul_str(b"btoa\0"));
+    duk_get_prop_index(ctx, -1, 1);
+    duk_put_global_string(ctx, nul_str(b"atob\0"));
+    duk_pop(ctx);
+}
+
+/// Converts a binary string into a buffer, or returns `undefined` if it has characters above
+/// U+00FF.
+unsafe extern "C" fn binary_to_bytes(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    use duktape_sys::*;
+
+    let mut len = 0;
+    let data = duk_require_lstring(ctx, 0, &mut len);
+    // Characters outside of the BMP are stored as surrogates, which are not valid UTF-8, but
+    // they are above U+00FF anyway
+    let bytes = match str::from_utf8(slice::from_raw_parts(data as *const u8, len)) {
+        Ok(string) if string.chars().all(|c| (c as u32) < 0x100) => {
+            string.chars().map(|c| c as u8).collect::<Vec<_>>()
+        }
+        _ => return 0,
+    };
+    push_bytes(ctx, &bytes);
+    1
+}
+
+/// Converts buffer data into a binary string.
+unsafe extern "C" fn bytes_to_binary(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    use duktape_sys::*;
+
+    let mut len = 0;
+    let data = duk_get_buffer_data(ctx, 0, &mut len);
+    let binary = if data.is_null() {
+        String::new()
+    } else {
+        slice::from_raw_parts(data as *const u8, len).iter().map(|&b| b as char).collect()
+    };
+    duk_push_lstring(ctx, binary.as_ptr() as *const os::raw::c_char, binary.len());
+    1
+}
+
+/// Encodes bytes as base64.
+pub(crate) unsafe fn encode_base64(ctx: *mut duktape_sys::duk_context, data: &[u8]) -> String {
+    push_bytes(ctx, data);
+    let encoded = duktape_sys::duk_base64_encode(ctx, -1);
+    let encoded = ffi::CStr::from_ptr(encoded).to_string_lossy().into_owned();
+    duktape_sys::duk_pop(ctx);
+    encoded
+}
+
+/// Encodes bytes as lowercase hex.
+pub(crate) unsafe fn encode_hex(ctx: *mut duktape_sys::duk_context, data: &[u8]) -> String {
+    push_bytes(ctx, data);
+    let encoded = duktape_sys::duk_hex_encode(ctx, -1);
+    let encoded = ffi::CStr::from_ptr(encoded).to_string_lossy().into_owned();
+    duktape_sys::duk_pop(ctx);
+    encoded
+}
+
+/// Decodes base64 (if `hex` is false) or hex into a buffer on top of the stack, or leaves an error
+/// there, and returns the status of the call.
+pub(crate) unsafe fn decode(ctx: *mut duktape_sys::duk_context,
+                            encoded: &str,
+                            hex: bool)
+                            -> duktape_sys::duk_int_t {
+    use duktape_sys::*;
+
+    duk_push_c_function(ctx, Some(decode_native), 2);
+    duk_push_lstring(ctx, encoded.as_ptr() as *const os::raw::c_char, encoded.len());
+    duk_push_boolean(ctx, if hex { 1 } else { 0 });
+    duk_pcall(ctx, 2)
+}
+
+/// Copies the buffer on top of the stack.
+pub(crate) unsafe fn buffer_bytes(ctx: *mut duktape_sys::duk_context) -> Vec<u8> {
+    let mut len = 0;
+    let data = duktape_sys::duk_get_buffer(ctx, -1, &mut len);
+    if data.is_null() {
+        Vec::new()
+    } else {
+        slice::from_raw_parts(data as *const u8, len).to_vec()
+    }
+}
+
+unsafe extern "C" fn decode_native(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    if duktape_sys::duk_get_boolean(ctx, 1) != 0 {
+        duktape_sys::duk_hex_decode(ctx, 0);
+    } else {
+        duktape_sys::duk_base64_decode(ctx, 0);
+    }
+    duktape_sys::duk_pop(ctx);
+    1
+}
+
+unsafe fn push_bytes(ctx: *mut duktape_sys::duk_context, data: &[u8]) {
+    let buf = duktape_sys::duk_push_fixed_buffer(ctx, data.len());
+    ptr::copy_nonoverlapping(data.as_ptr(), buf as *mut u8, data.len());
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use {Context, ErrorKind, JsErrorKind, Value};
+
+    #[test]
+    fn btoa_atob() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        assert_eq!(Value::String("aGVsbG8g/w==".to_owned()),
+                   ctx.eval_string("btoa('hello \\xff')").unwrap().to_value());
+        assert_eq!(Value::String("hello \u{ff}".to_owned()),
+                   ctx.eval_string("atob('aGVsbG8g/w==')").unwrap().to_value());
+        assert_eq!(Value::String("ab".to_owned()),
+                   ctx.eval_string("atob(' YW\\nI ')").unwrap().to_value());
+        assert!(ctx.eval_string("btoa('\\u263a')").is_err());
+        assert!(ctx.eval_string("atob('YW*I')").is_err());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn rust_codecs() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let data = [0u8, 1, 0xfe, 0xff, b'a'];
+        assert_eq!("AAH+/2E=", ctx.encode_base64(&data));
+        assert_eq!(data.to_vec(), ctx.decode_base64("AAH+\n/2E=").unwrap());
+        assert_eq!("0001feff61", ctx.encode_hex(&data));
+        assert_eq!(data.to_vec(), ctx.decode_hex("0001FEff61").unwrap());
+        assert_eq!("", ctx.encode_base64(&[]));
+        assert_eq!(Vec::<u8>::new(), ctx.decode_hex("").unwrap());
+
+        match ctx.decode_hex("abc").unwrap_err().0 {
+            ErrorKind::Js(ref e) => assert_eq!(JsErrorKind::Type, e.kind),
+            ref other => panic!("unexpected error {:?}", other),
+        }
+        assert!(ctx.decode_base64("YW*I").is_err());
+        ctx.assert_clean();
+    }
+}
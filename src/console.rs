@@ -0,0 +1,503 @@
+//! A `console` object for scripts that formats its arguments and routes the messages to a host
+//! sink, or into the `log` crate by default.
+//!
+//! The methods are `log`, `info`, `debug`, `warn`, `error`, `trace` (which appends a stack
+//! trace), `assert`, `group`, `groupCollapsed`, `groupEnd`, `time`, `timeLog`, `timeEnd` and
+//! `table`.  Arguments are formatted like in Node.js: a leading string can contain `%s`, `%d`,
+//! `%i`, `%f`, `%j`, `%o`, `%O` and `%c` placeholders, strings are printed as is, and other values
+//! are inspected up to a depth of two levels, with strings quoted, cycles shown as `[Circular]`
+//! and functions shown by name.  Messages inside a group are indented by two spaces per level.
+//!
+//! Without a sink (see `ContextBuilder::with_console_sink`), messages are logged with the
+//! `duk::console` target, at the level of the `Level` of the method.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::cell::RefCell;
+//! use std::rc::Rc;
+//!
+//! let lines = Rc::new(RefCell::new(Vec::new()));
+//! let sink = lines.clone();
+//! let ctx = duk::Context::builder()
+//!     .with_console_sink(Box::new(move |_: duk::console::Level, msg: &str| {
+//!         sink.borrow_mut().push(msg.to_owned())
+//!     }))
+//!     .build();
+//! ctx.eval_string("console.log('%s has %d items', 'cart', 2, {total: 9.5})").unwrap();
+//! assert_eq!(vec!["cart has 2 items { total: 9.5 }"], *lines.borrow());
+//! ```
+
+use std::os;
+
+use duktape_sys;
+use log;
+
+use nul_str;
+use strings;
+use HeapData;
+
+const TARGET: &str = "duk::console";
+
+/// Builds the `console` object on top of the native sink functions.
+const SETUP: &[u8] = br#"(function (enabled, write) {
+  var TRACE = 0, DEBUG = 1, INFO = 2, WARN = 3, ERROR = 4;
+  var MAX_DEPTH = 2, MAX_ITEMS = 100, LINE_WIDTH = 72;
+  var indent = '';
+  var timers = {};
+
+  function quote(s) {
+    return "'" + s.replace(/\\/g, '\\\\').replace(/'/g, "\\'").replace(/\n/g, '\\n') + "'";
+  }
+
+  function isView(v) {
+    return ArrayBuffer.isView(v) && !(v instanceof DataView);
+  }
+
+  function wrap(open, items, close) {
+    if (items.length === 0) {
+      return open + close;
+    }
+    var oneLine = open + ' ' + items.join(', ') + ' ' + close;
+    if (oneLine.length <= LINE_WIDTH && oneLine.indexOf('\n') < 0) {
+      return oneLine;
+    }
+    return open + '\n' + items.map(function (item) {
+      return '  ' + item.replace(/\n/g, '\n  ');
+    }).join(',\n') + '\n' + close;
+  }
+
+  function inspect(v, depth, seen) {
+    switch (typeof v) {
+    case 'string':
+      return quote(v);
+    case 'function':
+      return '[Function' + (v.name ? ': ' + v.name : '') + ']';
+    case 'buffer':
+      var hex = Duktape.enc('hex', v).replace(/(..)(?!$)/g, '$1 ');
+      return '<Buffer' + (hex ? ' ' + hex : '') + '>';
+    case 'object':
+      break;
+    default:
+      return String(v);
+    }
+    if (v === null) {
+      return 'null';
+    }
+    if (seen.indexOf(v) >= 0) {
+      return '[Circular]';
+    }
+    if (v instanceof Error) {
+      return String(v.stack || v);
+    }
+    if (v instanceof Date) {
+      return isNaN(v.getTime()) ? 'Invalid Date' : v.toISOString();
+    }
+    if (v instanceof RegExp) {
+      return String(v);
+    }
+
+    var array = Array.isArray(v) || isView(v);
+    var name = v.constructor && v.constructor.name;
+    var prefix = array ? (name && name !== 'Array' ? name + ' ' : '') :
+                         (name && name !== 'Object' ? name + ' ' : '');
+    if (depth > MAX_DEPTH) {
+      return '[' + (array ? name || 'Array' : name || 'Object') + ']';
+    }
+
+    seen.push(v);
+    var items = [];
+    if (array) {
+      for (var i = 0; i < v.length && i < MAX_ITEMS; i++) {
+        items.push(inspect(v[i], depth + 1, seen));
+      }
+      if (v.length > MAX_ITEMS) {
+        items.push('... ' + (v.length - MAX_ITEMS) + ' more items');
+      }
+    } else {
+      Object.keys(v).forEach(function (key) {
+        var label = /^[A-Za-z_$][\w$]*$/.test(key) ? key : quote(key);
+        items.push(label + ': ' + inspect(v[key], depth + 1, seen));
+      });
+    }
+    seen.pop();
+    return prefix + (array ? wrap('[', items, ']') : wrap('{', items, '}'));
+  }
+
+  function show(v) {
+    return typeof v === 'string' ? v : inspect(v, 0, []);
+  }
+
+  function format(args) {
+    var rest = 0;
+    var first = '';
+    if (typeof args[0] === 'string') {
+      rest = 1;
+      first = args[0].replace(/%([sdifjoOc%])/g, function (match, spec) {
+        if (spec === '%') {
+          return '%';
+        }
+        if (rest >= args.length) {
+          return match;
+        }
+        var arg = args[rest++];
+        switch (spec) {
+        case 's': return typeof arg === 'object' && arg !== null ? inspect(arg, 1, []) : String(arg);
+        case 'd': return String(typeof arg === 'object' ? NaN : Number(arg));
+        case 'i': return String(parseInt(arg, 10));
+        case 'f': return String(parseFloat(arg));
+        case 'j':
+          try {
+            return JSON.stringify(arg);
+          } catch (e) {
+            return '[Circular]';
+          }
+        case 'c': return '';
+        default: return inspect(arg, 0, []);
+        }
+      });
+    }
+    var parts = rest ? [first] : [];
+    for (var i = rest; i < args.length; i++) {
+      parts.push(show(args[i]));
+    }
+    return parts.join(' ');
+  }
+
+  function emit(level, message) {
+    if (enabled(level)) {
+      write(level, indent ? indent + message.replace(/\n/g, '\n' + indent) : message);
+    }
+  }
+
+  function logger(level) {
+    return function () {
+      if (enabled(level)) {
+        emit(level, format(arguments));
+      }
+    };
+  }
+
+  function table(data, columns) {
+    if (typeof data !== 'object' || data === null) {
+      return [format([data])];
+    }
+    var header = ['(index)'];
+    var rows = [];
+    var hasValues = false;
+    Object.keys(data).forEach(function (index) {
+      var row = data[index];
+      var cells = {};
+      if (typeof row === 'object' && row !== null && !(row instanceof Date)) {
+        Object.keys(row).forEach(function (key) {
+          if (columns && columns.indexOf(key) < 0) {
+            return;
+          }
+          if (header.indexOf(key) < 0) {
+            header.push(key);
+          }
+          cells[key] = inspect(row[key], 1, []);
+        });
+      } else {
+        hasValues = true;
+        cells.Values = inspect(row, 1, []);
+      }
+      rows.push([index, cells]);
+    });
+    if (hasValues) {
+      header.push('Values');
+    }
+
+    var lines = rows.map(function (row) {
+      return header.map(function (key, i) {
+        return i === 0 ? row[0] : (row[1].hasOwnProperty(key) ? row[1][key] : '');
+      });
+    });
+    var widths = header.map(function (key, i) {
+      return lines.reduce(function (width, line) {
+        return Math.max(width, line[i].length);
+      }, key.length);
+    });
+    function line(cells) {
+      return '| ' + cells.map(function (cell, i) {
+        return cell + new Array(widths[i] - cell.length + 1).join(' ');
+      }).join(' | ') + ' |';
+    }
+    var rule = '+' + widths.map(function (width) {
+      return new Array(width + 3).join('-');
+    }).join('+') + '+';
+    return [rule, line(header), rule].concat(lines.map(line), [rule]);
+  }
+
+  function label(value) {
+    return value === undefined ? 'default' : String(value);
+  }
+
+  function elapsed(name) {
+    return name + ': ' + (Date.now() - timers[name]) + 'ms';
+  }
+
+  return {
+    log: logger(INFO),
+    info: logger(INFO),
+    debug: logger(DEBUG),
+    warn: logger(WARN),
+    error: logger(ERROR),
+    trace: function () {
+      if (enabled(TRACE)) {
+        var stack = String(new Error().stack).split('\n').slice(2).join('\n');
+        emit(TRACE, 'Trace' + (arguments.length ? ': ' + format(arguments) : '') + '\n' + stack);
+      }
+    },
+    assert: function (condition) {
+      if (!condition) {
+        var args = Array.prototype.slice.call(arguments, 1);
+        emit(ERROR, 'Assertion failed' + (args.length ? ': ' + format(args) : ''));
+      }
+    },
+    group: function () {
+      if (arguments.length) {
+        emit(INFO, format(arguments));
+      }
+      indent += '  ';
+    },
+    groupCollapsed: function () {
+      this.group.apply(this, arguments);
+    },
+    groupEnd: function () {
+      indent = indent.slice(2);
+    },
+    time: function (name) {
+      name = label(name);
+      if (timers.hasOwnProperty(name)) {
+        emit(WARN, "Timer '" + name + "' already exists");
+        return;
+      }
+      timers[name] = Date.now();
+    },
+    timeLog: function (name) {
+      name = label(name);
+      if (!timers.hasOwnProperty(name)) {
+        emit(WARN, "Timer '" + name + "' does not exist");
+        return;
+      }
+      var args = Array.prototype.slice.call(arguments, 1);
+      emit(INFO, elapsed(name) + (args.length ? ' ' + format(args) : ''));
+    },
+    timeEnd: function (name) {
+      name = label(name);
+      if (!timers.hasOwnProperty(name)) {
+        emit(WARN, "Timer '" + name + "' does not exist");
+        return;
+      }
+      emit(INFO, elapsed(name));
+      delete timers[name];
+    },
+    table: function (data, columns) {
+      if (enabled(INFO)) {
+        emit(INFO, table(data, columns).join('\n'));
+      }
+    }
+  };
+})"#;
+
+/// The severity of a console message.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Level {
+    /// `console.trace`
+    Trace,
+    /// `console.debug`
+    Debug,
+    /// `console.log`, `console.info`, groups, timers and tables.
+    Info,
+    /// `console.warn`, and misused timers.
+    Warn,
+    /// `console.error` and failed `console.assert` calls.
+    Error,
+}
+
+/// Receives the formatted messages of the console, see `ContextBuilder::with_console_sink`.
+///
+/// Closures taking a `Level` and a `&str` are sinks too.
+pub trait ConsoleSink {
+    /// Handles a message, which may span multiple lines.
+    fn write(&self, level: Level, message: &str);
+
+    /// Checks whether messages of the level are wanted at all, so that formatting can be skipped
+    /// for the others.
+    fn enabled(&self, _level: Level) -> bool {
+        true
+    }
+}
+
+impl<F> ConsoleSink for F
+    where F: Fn(Level, &str)
+{
+    fn write(&self, level: Level, message: &str) {
+        self(level, message)
+    }
+}
+
+impl Level {
+    fn from_index(index: duktape_sys::duk_int_t) -> Level {
+        match index {
+            0 => Level::Trace,
+            1 => Level::Debug,
+            2 => Level::Info,
+            3 => Level::Warn,
+            _ => Level::Error,
+        }
+    }
+
+    fn log_level(self) -> log::LogLevel {
+        match self {
+            Level::Trace => log::LogLevel::Trace,
+            Level::Debug => log::LogLevel::Debug,
+            Level::Info => log::LogLevel::Info,
+            Level::Warn => log::LogLevel::Warn,
+            Level::Error => log::LogLevel::Error,
+        }
+    }
+}
+
+/// Defines the global `console` object.
+pub(crate) unsafe fn setup(ctx: *mut duktape_sys::duk_context) {
+    use duktape_sys::*;
+
+    let ret = duk_peval_lstring(ctx, SETUP.as_ptr() as *const os::raw::c_char, SETUP.len());
+    assert_eq!(0, ret, "failed to compile the console");
+    duk_push_c_function(ctx, Some(enabled), 1);
+    duk_push_c_function(ctx, Some(write), 2);
+    let ret = duk_pcall(ctx, 2);
+    assert_eq!(0, ret, "failed to set up the console");
+    duk_put_global_string(ctx, nul_str(b"console\0"));
+}
+
+unsafe fn sink<'a>(ctx: *mut duktape_sys::duk_context) -> Option<&'a dyn ConsoleSink> {
+    let mut funcs = duktape_sys::duk_memory_functions::default();
+    duktape_sys::duk_get_memory_functions(ctx, &mut funcs);
+    (*(funcs.udata as *mut HeapData)).console_sink.as_deref()
+}
+
+/// `enabled(level)`
+unsafe extern "C" fn enabled(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    let level = Level::from_index(duktape_sys::duk_get_int(ctx, 0));
+    let enabled = match sink(ctx) {
+        Some(sink) => sink.enabled(level),
+        None => log_enabled!(target: TARGET, level.log_level()),
+    };
+    duktape_sys::duk_push_boolean(ctx, enabled as duktape_sys::duk_bool_t);
+    1
+}
+
+/// `write(level, message)`
+unsafe extern "C" fn write(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    let level = Level::from_index(duktape_sys::duk_get_int(ctx, 0));
+    let message = strings::get(ctx, 1);
+    match sink(ctx) {
+        Some(sink) => sink.write(level, &message),
+        None => log!(target: TARGET, level.log_level(), "{}", message),
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::cell;
+    use std::rc;
+
+    use super::*;
+
+    use Context;
+
+    type Messages = rc::Rc<cell::RefCell<Vec<(Level, String)>>>;
+
+    fn capture() -> (Context, Messages) {
+        let messages = rc::Rc::new(cell::RefCell::new(Vec::new()));
+        let sink = messages.clone();
+        let ctx = Context::builder()
+            .with_console_sink(Box::new(move |level, msg: &str| {
+                sink.borrow_mut().push((level, msg.to_owned()))
+            }))
+            .build();
+        (ctx, messages)
+    }
+
+    #[test]
+    fn console_levels() {
+        let _ = env_logger::init();
+        let (ctx, messages) = capture();
+        ctx.eval_string(r"
+          console.debug('debug', 1);
+          console.log('log', {a: [1, 'b']});
+          console.info('info', null, undefined);
+          console.warn('warn', true);
+          console.error(new TypeError('bad'));
+          console.assert(true, 'not logged');
+          console.assert(1 > 2, 'math');
+          function traced() { console.trace('here'); }
+          traced();
+        ")
+            .unwrap();
+
+        let logged = messages.borrow();
+        assert_eq!(vec![(Level::Debug, "debug 1".to_owned()),
+                        (Level::Info, "log { a: [ 1, 'b' ] }".to_owned()),
+                        (Level::Info, "info null undefined".to_owned()),
+                        (Level::Warn, "warn true".to_owned())],
+                   logged[..4].to_vec());
+        // Errors are shown with their stack
+        assert!(logged[4].1.starts_with("TypeError: bad\n    at eval (eval:6)"), "{}", logged[4].1);
+        assert_eq!((Level::Error, "Assertion failed: math".to_owned()), logged[5]);
+        let (level, ref trace) = logged[6];
+        assert_eq!(Level::Trace, level);
+        assert!(trace.starts_with("Trace: here\n    at traced "), "{}", trace);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn console_formatting() {
+        let _ = env_logger::init();
+        let (ctx, messages) = capture();
+        ctx.eval_string(r"
+          var cyclic = {name: 'loop', deep: {a: {b: {c: 1}}}};
+          cyclic.self = cyclic;
+          console.log(cyclic, [function named() {}, 'it\'s']);
+          console.log('%s=%d (%i%%) %j %o%c', 'x', '42', 7.9, {k: [1]}, [], 'color: red', 'rest');
+          console.group('outer');
+          console.info({list: new Array(30).join('x').split(''), text: 'a long enough line'});
+          console.groupEnd();
+          console.table([{a: 1, b: 'x'}, {a: 2}, 3]);
+          console.time('t');
+          console.timeEnd('t');
+          console.timeEnd('t');
+        ")
+            .unwrap();
+
+        let logged = messages.borrow();
+        assert_eq!("{ name: 'loop', deep: { a: { b: [Object] } }, self: [Circular] } [ \
+                    [Function: named], 'it\\'s' ]",
+                   logged[0].1);
+        assert_eq!("x=42 (7%) {\"k\":[1]} [] rest", logged[1].1);
+        assert_eq!((Level::Info, "outer".to_owned()), logged[2]);
+        assert!(logged[3].1.starts_with("  {\n    list: [\n      'x',\n"), "{}", logged[3].1);
+        assert!(logged[3].1.ends_with("'x'\n    ],\n    text: 'a long enough line'\n  }"),
+                "{}",
+                logged[3].1);
+        assert_eq!("+---------+---+-----+--------+\n\
+                    | (index) | a | b   | Values |\n\
+                    +---------+---+-----+--------+\n\
+                    | 0       | 1 | 'x' |        |\n\
+                    | 1       | 2 |     |        |\n\
+                    | 2       |   |     | 3      |\n\
+                    +---------+---+-----+--------+",
+                   logged[4].1);
+        assert!(logged[5].1.starts_with("t: ") && logged[5].1.ends_with("ms"),
+                "{}",
+                logged[5].1);
+        assert_eq!((Level::Warn, "Timer 't' does not exist".to_owned()), logged[6]);
+        ctx.assert_clean();
+    }
+}
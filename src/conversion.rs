@@ -0,0 +1,165 @@
+//! Options for converting Javascript values into `Value`s, see `Context::set_conversion_options`.
+//!
+//! The options of a context apply to every conversion it does, like `Reference::to_value`, the
+//! results of `eval_as` and `call_global_as`, and the values of `host.events` events.  By default,
+//! nesting is unlimited and `Date` objects convert like other objects, into an empty
+//! `Value::Object`.
+//!
+//! There are no options for the order of object properties or for integers: objects always
+//! become `BTreeMap`s sorted by key, and numbers are always `f64`s.
+//!
+//! # Examples
+//!
+//! ```
+//! use duk::conversion::{ConversionOptions, DateConversion};
+//!
+//! let ctx = duk::Context::new();
+//! ctx.set_conversion_options(ConversionOptions::new()
+//!     .with_max_depth(2)
+//!     .with_dates(DateConversion::IsoString));
+//! let value = ctx.eval_string("[new Date(0), [[1]]]").unwrap().to_value();
+//! assert_eq!(duk::Value::Array(vec![duk::Value::String("1970-01-01T00:00:00.000Z".to_owned()),
+//!                                   duk::Value::Array(vec![duk::Value::Foreign("too deep")])]),
+//!            value);
+//! ```
+
+use duktape_sys;
+
+use nul_str;
+
+/// How `Date` objects are converted.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DateConversion {
+    /// Like other objects, into an object with the own enumerable properties, which is empty.
+    Object,
+    /// Into the number of milliseconds since the epoch, or `NaN` for invalid dates.
+    Millis,
+    /// Into an ISO 8601 string like `Date.prototype.toISOString` returns, or `null` for invalid
+    /// dates.
+    IsoString,
+}
+
+/// Options for converting Javascript values into `Value`s.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ConversionOptions {
+    max_depth: Option<usize>,
+    dates: DateConversion,
+}
+
+impl ConversionOptions {
+    /// Returns the default options.
+    pub fn new() -> ConversionOptions {
+        ConversionOptions {
+            max_depth: None,
+            dates: DateConversion::Object,
+        }
+    }
+
+    /// Limits how deeply arrays and objects are nested, which also protects against cyclic
+    /// structures.  Arrays and objects below the limit become `Value::Foreign("too deep")`, so
+    /// with a limit of 1, the elements of the top-level array are converted but arrays within it
+    /// are not.
+    pub fn with_max_depth(mut self, max_depth: usize) -> ConversionOptions {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Chooses how `Date` objects are converted.
+    pub fn with_dates(mut self, dates: DateConversion) -> ConversionOptions {
+        self.dates = dates;
+        self
+    }
+
+    /// Returns the nesting limit, if any.
+    pub fn max_depth(&self) -> Option<usize> {
+        self.max_depth
+    }
+
+    /// Returns how `Date` objects are converted.
+    pub fn dates(&self) -> DateConversion {
+        self.dates
+    }
+
+    /// Whether arrays and objects at the specified depth (starting at 0) are too deep to convert.
+    pub(crate) fn too_deep(&self, depth: usize) -> bool {
+        self.max_depth.is_some_and(|max| depth >= max)
+    }
+}
+
+impl Default for ConversionOptions {
+    fn default() -> ConversionOptions {
+        ConversionOptions::new()
+    }
+}
+
+/// Pushes the result of calling the specified method of the `Date` object at the (normalized)
+/// index, and returns `true`, or returns `false` without pushing anything if it isn't a `Date`.
+/// Pushes `undefined` if the method throws.
+pub(crate) unsafe fn push_date_method(ctx: *mut duktape_sys::duk_context,
+                                      index: duktape_sys::duk_idx_t,
+                                      method: &[u8])
+                                      -> bool {
+    use duktape_sys::*;
+
+    duk_get_global_string(ctx, nul_str(b"Date\0"));
+    let is_date = duk_is_function(ctx, -1) != 0 && duk_instanceof(ctx, index, -1) != 0;
+    duk_pop(ctx);
+    if !is_date {
+        return false;
+    }
+    duk_get_prop_string(ctx, index, nul_str(method));
+    duk_dup(ctx, index);
+    if duk_pcall_method(ctx, 0) != 0 {
+        duk_pop(ctx);
+        duk_push_undefined(ctx);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use super::*;
+    use {Context, Value};
+
+    #[test]
+    fn conversion_options() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        assert_eq!(ConversionOptions::new(), ctx.conversion_options());
+        ctx.eval_string("var cyclic = {dates: [new Date(1000), new Date(NaN)], n: 1}; \
+                         cyclic.self = cyclic;")
+            .unwrap();
+        let object = |properties: Vec<(&str, Value)>| {
+            Value::Object(properties.into_iter().map(|(k, v)| (k.to_owned(), v)).collect())
+        };
+
+        ctx.set_conversion_options(ConversionOptions::new()
+            .with_max_depth(2)
+            .with_dates(DateConversion::Millis));
+        let dates = Value::Array(vec![Value::Number(1000.0), Value::Number(2000.0)]);
+        assert_eq!(object(vec![("dates", dates),
+                               ("n", Value::Number(1.0)),
+                               ("self",
+                                object(vec![("dates", Value::Foreign("too deep")),
+                                            ("n", Value::Number(1.0)),
+                                            ("self", Value::Foreign("too deep"))]))]),
+                   ctx.eval_string("cyclic.dates[1] = new Date(2000); cyclic").unwrap().to_value());
+
+        ctx.set_conversion_options(ConversionOptions::new().with_dates(DateConversion::IsoString));
+        assert_eq!(Value::Array(vec![Value::String("1970-01-01T00:00:01.000Z".to_owned()),
+                                     Value::Null]),
+                   ctx.eval_string("[cyclic.dates[0], new Date(NaN)]").unwrap().to_value());
+
+        let ctx = Context::builder()
+            .with_conversion_options(ConversionOptions::new()
+                .with_max_depth(0)
+                .with_dates(DateConversion::IsoString))
+            .build();
+        assert_eq!(Value::Foreign("too deep"), ctx.eval_string("[]").unwrap().to_value());
+        assert_eq!(Value::String("1970-01-01T00:00:00.000Z".to_owned()),
+                   ctx.eval_string("new Date(0)").unwrap().to_value());
+        ctx.assert_clean();
+    }
+}
@@ -0,0 +1,111 @@
+//! Line and function coverage of Javascript code, see `Context::start_coverage`.
+//!
+//! Coverage is collected by single-stepping through all executed code with the in-process
+//! debugger, so it slows execution down considerably.  Duktape only reports line transitions, so
+//! a line that is executed several times in a row (for example a loop written on a single line)
+//! is only counted once, while a line that calls a function is counted again when the call
+//! returns.
+
+use std::collections;
+use std::io;
+
+use debugger;
+
+/// The coverage of all files that have been executed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CoverageReport {
+    files: collections::BTreeMap<String, FileCoverage>,
+}
+
+/// The coverage of a single file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileCoverage {
+    /// The number of times each executed line was entered, by line number.
+    pub lines: collections::BTreeMap<u32, u64>,
+    /// The executed functions, in the order in which they were first called.
+    pub functions: Vec<FunctionCoverage>,
+}
+
+/// The coverage of a single function.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FunctionCoverage {
+    /// The name of the function, or `(anonymous)`.
+    pub name: String,
+    /// The first line of the function that was executed.
+    pub line_number: u32,
+    /// The number of times the function was called.
+    pub calls: u64,
+}
+
+impl CoverageReport {
+    /// The covered files, by file name (or original file name, if a source map is registered).
+    pub fn files(&self) -> &collections::BTreeMap<String, FileCoverage> {
+        &self.files
+    }
+
+    /// The coverage of the specified file, if any of its code was executed.
+    pub fn file(&self, file_name: &str) -> Option<&FileCoverage> {
+        self.files.get(file_name)
+    }
+
+    /// Writes the report in the LCOV tracefile format understood by `genhtml` and most coverage
+    /// services.
+    ///
+    /// Only executed lines and functions are known, so the report doesn't contain any lines or
+    /// functions with zero hits.
+    pub fn write_lcov<W>(&self, writer: &mut W) -> io::Result<()>
+        where W: io::Write
+    {
+        for (file_name, file) in &self.files {
+            writeln!(writer, "SF:{}", file_name)?;
+            for function in &file.functions {
+                writeln!(writer, "FN:{},{}", function.line_number, function.name)?;
+            }
+            for function in &file.functions {
+                writeln!(writer, "FNDA:{},{}", function.calls, function.name)?;
+            }
+            writeln!(writer, "FNF:{}", file.functions.len())?;
+            writeln!(writer, "FNH:{}", file.functions.len())?;
+            for (line, hits) in &file.lines {
+                writeln!(writer, "DA:{},{}", line, hits)?;
+            }
+            writeln!(writer, "LF:{}", file.lines.len())?;
+            writeln!(writer, "LH:{}", file.lines.len())?;
+            writeln!(writer, "end_of_record")?;
+        }
+        Ok(())
+    }
+
+    /// Records a pause at a new line, given the call stack with the innermost frame first.
+    pub(crate) fn record(&mut self, call_stack: &[debugger::StackFrame]) {
+        let frame = match call_stack.first() {
+            Some(frame) if !frame.file_name.is_empty() => frame,
+            _ => return,
+        };
+
+        let file = self.files.entry(frame.file_name.clone()).or_default();
+        *file.lines.entry(frame.line_number).or_insert(0) += 1;
+
+        // Duktape pauses before the first instruction of every called function
+        if frame.pc == 0 {
+            let name = if frame.function_name.is_empty() {
+                "(anonymous)"
+            } else {
+                &frame.function_name
+            };
+            let existing = file.functions
+                .iter_mut()
+                .find(|f| f.name == name && f.line_number == frame.line_number);
+            match existing {
+                Some(function) => function.calls += 1,
+                None => {
+                    file.functions.push(FunctionCoverage {
+                        name: name.to_owned(),
+                        line_number: frame.line_number,
+                        calls: 1,
+                    })
+                }
+            }
+        }
+    }
+}
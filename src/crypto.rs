@@ -0,0 +1,229 @@
+//! A secure random source and hashing for scripts, so that plugins don't roll their own.
+//!
+//! Scripts get the global `crypto.getRandomValues(array)`, which fills an integer typed array
+//! with random bytes from the operating system (at most 65536 bytes at a time, like in browsers),
+//! and the `host:crypto` module:
+//!
+//! * `sha256(data, encoding)` hashes a string (as UTF-8) or buffer data.
+//! * `hmac('sha256', key, data, encoding)` computes an HMAC, where the key is a string or buffer
+//!   data too.
+//!
+//! Digests are returned as lowercase hex by default; the encoding can also be `'base64'` or
+//! `'buffer'` (a `Uint8Array`).  The hashes come from the `sha2` and `hmac` crates and the random
+//! bytes from the `getrandom` crate, so `getRandomValues` throws on platforms without a random
+//! source.
+
+use std::os;
+use std::ptr;
+use std::slice;
+
+use duktape_sys;
+use getrandom;
+use hmac;
+use hmac::Mac;
+use sha2;
+use sha2::Digest;
+
+use host_modules;
+use nul_str;
+use strings;
+
+/// Builds the global `crypto` object and the exports of `host:crypto` on top of the native
+/// functions.
+const SETUP: &[u8] = b"(function (fill, sha256, hmacSha256, enc) {
+  function isBytes(data) {
+    return typeof data === 'buffer' || data instanceof ArrayBuffer || ArrayBuffer.isView(data);
+  }
+  function input(data, name) {
+    if (isBytes(data)) {
+      return data;
+    }
+    if (typeof data !== 'string') {
+      throw new TypeError(name + ' must be a string or buffer data');
+    }
+    return data;
+  }
+  function output(digest, encoding) {
+    if (encoding === undefined || encoding === 'hex') {
+      return enc('hex', digest);
+    }
+    if (encoding === 'base64') {
+      return enc('base64', digest);
+    }
+    if (encoding === 'buffer') {
+      return new Uint8Array(digest);
+    }
+    throw new TypeError('unsupported encoding: ' + encoding);
+  }
+
+  var crypto = {
+    getRandomValues: function (array) {
+      if (!ArrayBuffer.isView(array) || array instanceof DataView ||
+          array instanceof Float32Array || array instanceof Float64Array) {
+        throw new TypeError('getRandomValues needs an integer typed array');
+      }
+      if (array.byteLength > 65536) {
+        throw new RangeError('getRandomValues can fill at most 65536 bytes');
+      }
+      if (!fill(array)) {
+        throw new Error('no secure random source is available');
+      }
+      return array;
+    }
+  };
+  var exports = {
+    sha256: function (data, encoding) {
+      return output(sha256(input(data, 'data')), encoding);
+    },
+    hmac: function (algorithm, key, data, encoding) {
+      if (String(algorithm).toLowerCase() !== 'sha256') {
+        throw new TypeError('unsupported algorithm: ' + algorithm);
+      }
+      return output(hmacSha256(input(key, 'key'), input(data, 'data')), encoding);
+    }
+  };
+  return [crypto, exports];
+})";
+
+/// Computes the SHA-256 digest of the data.
+pub(crate) fn sha256(data: &[u8]) -> [u8; 32] {
+    sha2::Sha256::digest(data).into()
+}
+
+/// Computes the HMAC-SHA-256 of the data (RFC 2104).
+pub(crate) fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(key)
+        .expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// Fills the buffer with random bytes from the operating system.
+fn fill_random(buf: &mut [u8]) -> Result<(), getrandom::Error> {
+    getrandom::getrandom(buf)
+}
+
+/// Defines the global `crypto` object and registers the `host:crypto` module.
+pub(crate) unsafe fn setup(ctx: *mut duktape_sys::duk_context) {
+    use duktape_sys::*;
+
+    let ret = duk_peval_lstring(ctx, SETUP.as_ptr() as *const os::raw::c_char, SETUP.len());
+    assert_eq!(0, ret, "failed to compile the crypto functions");
+    duk_push_c_function(ctx, Some(fill), 1);
+    duk_push_c_function(ctx, Some(sha256_native), 1);
+    duk_push_c_function(ctx, Some(hmac_sha256_native), 2);
+    duk_get_global_string(ctx, nul_str(b"Duktape\0"));
+    duk_get_prop_string(ctx, -1, nul_str(b"enc\0"));
+    duk_remove(ctx, -2);
+    let ret = duk_pcall(ctx, 4);
+    assert_eq!(0, ret, "failed to set up the crypto functions");
+
+    duk_get_prop_index(ctx, -1, 0);
+    duk_put_global_string(ctx, nul_str(b"crypto\0"));
+    duk_get_prop_index(ctx, -1, 1);
+    host_modules::register(ctx, b"host:crypto\0");
+    duk_pop(ctx);
+}
+
+/// Reads a string as UTF-8, or buffer data as is.
+unsafe fn get_bytes(ctx: *mut duktape_sys::duk_context, index: duktape_sys::duk_idx_t) -> Vec<u8> {
+    let mut len = 0;
+    let data = duktape_sys::duk_get_buffer_data(ctx, index, &mut len);
+    if !data.is_null() {
+        slice::from_raw_parts(data as *const u8, len).to_vec()
+    } else if duktape_sys::duk_is_buffer(ctx, index) != 0 {
+        Vec::new()
+    } else {
+        strings::get(ctx, index).into_bytes()
+    }
+}
+
+/// Pushes a digest as a plain buffer, which the codecs of `Duktape.enc` accept.
+unsafe fn push_digest(ctx: *mut duktape_sys::duk_context, digest: &[u8]) {
+    let buf = duktape_sys::duk_push_fixed_buffer(ctx, digest.len());
+    ptr::copy_nonoverlapping(digest.as_ptr(), buf as *mut u8, digest.len());
+}
+
+/// `fill(array)`, returns whether the array could be filled.
+unsafe extern "C" fn fill(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    let mut len = 0;
+    let data = duktape_sys::duk_get_buffer_data(ctx, 0, &mut len);
+    let filled = data.is_null() ||
+                 fill_random(slice::from_raw_parts_mut(data as *mut u8, len)).is_ok();
+    duktape_sys::duk_push_boolean(ctx, filled as duktape_sys::duk_bool_t);
+    1
+}
+
+/// `sha256(data)`
+unsafe extern "C" fn sha256_native(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    let digest = sha256(&get_bytes(ctx, 0));
+    push_digest(ctx, &digest);
+    1
+}
+
+/// `hmacSha256(key, data)`
+unsafe extern "C" fn hmac_sha256_native(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    let digest = hmac_sha256(&get_bytes(ctx, 0), &get_bytes(ctx, 1));
+    push_digest(ctx, &digest);
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use super::*;
+    use {Context, Value};
+
+    #[test]
+    fn digests() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let result = ctx.eval_string(r"
+          var crypto = require('host:crypto');
+          [crypto.sha256(''), crypto.sha256('abc'),
+           crypto.sha256(Duktape.dec('hex', '616263'), 'base64'),
+           crypto.hmac('sha256', 'key', 'The quick brown fox jumps over the lazy dog'),
+           crypto.hmac('SHA256', Duktape.dec('hex', '0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b'),
+                       'Hi There', 'buffer').length]
+        ")
+            .unwrap();
+        let expected = ["e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+                        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+                        "ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=",
+                        "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"];
+        let mut expected = expected.iter().map(|s| Value::String(s.to_string())).collect::<Vec<_>>();
+        expected.push(Value::Number(32.0));
+        assert_eq!(Value::Array(expected), result.to_value());
+
+        // A key longer than a block is hashed first (RFC 4231, test case 6)
+        let key = [0xaa; 131];
+        assert_eq!("60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+                   ctx.encode_hex(&hmac_sha256(&key,
+                                               b"Test Using Larger Than Block-Size Key - Hash \
+                                                 Key First")));
+        assert_eq!("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+                   ctx.encode_hex(&sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")));
+        assert!(ctx.eval_string("require('host:crypto').hmac('md5', 'k', 'd')").is_err());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn get_random_values() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let result = ctx.eval_string(r"
+          var a = new Uint32Array(8), b = new Uint32Array(8);
+          var same = crypto.getRandomValues(a) === a;
+          crypto.getRandomValues(b);
+          [same, Array.prototype.join.call(a) !== Array.prototype.join.call(b)]
+        ")
+            .unwrap();
+        assert_eq!(Value::Array(vec![Value::Boolean(true), Value::Boolean(true)]),
+                   result.to_value());
+        assert!(ctx.eval_string("crypto.getRandomValues(new Float64Array(2))").is_err());
+        assert!(ctx.eval_string("crypto.getRandomValues(new Uint8Array(65537))").is_err());
+        assert!(ctx.eval_string("crypto.getRandomValues([1, 2])").is_err());
+        ctx.assert_clean();
+    }
+}
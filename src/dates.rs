@@ -0,0 +1,147 @@
+//! Conversions between Javascript dates and `chrono::DateTime<Utc>`, with the `chrono` feature.
+//!
+//! A `DateTime<Utc>` is an `Argument` that scripts receive as a real `Date` object.  In the other
+//! direction, `Reference::to_date_time` reads `Date` objects, and `DateTime<Utc>` is a `FromValue`
+//! that accepts both numbers of milliseconds since the epoch and ISO 8601 strings like the ones of
+//! `Date.prototype.toISOString` (which `JSON.stringify` uses too).  Javascript dates have a
+//! resolution of a millisecond, so sub-millisecond precision is truncated on the way in.
+//!
+//! # Examples
+//!
+//! ```
+//! # extern crate chrono;
+//! # extern crate duk;
+//! use chrono::{TimeZone, Utc};
+//!
+//! # fn main() {
+//! let ctx = duk::Context::new();
+//! ctx.eval_string("function nextDay(date) { return new Date(date.getTime() + 86400000); }")
+//!     .unwrap();
+//! let date = Utc.with_ymd_and_hms(2017, 2, 28, 12, 0, 0).unwrap();
+//! let next = ctx.call_global("nextDay", &[&date]).unwrap().to_date_time().unwrap();
+//! assert_eq!(Utc.with_ymd_and_hms(2017, 3, 1, 12, 0, 0).unwrap(), next);
+//! # }
+//! ```
+
+use chrono::{DateTime, Utc};
+use duktape_sys;
+
+use {nul_str, Argument, Context, ErrorKind, FromValue, Reference, Result, Value};
+
+/// Converts a number of milliseconds since the epoch into a date, truncating fractions of a
+/// millisecond, or fails with `ErrorKind::Conversion` for `NaN` (an invalid date) and numbers
+/// outside of the range of dates.
+pub fn from_millis(millis: f64) -> Result<DateTime<Utc>> {
+    if millis.is_finite() {
+        if let Some(date) = DateTime::from_timestamp_millis(millis.trunc() as i64) {
+            return Ok(date);
+        }
+    }
+    Err(ErrorKind::Conversion(format!("{} is not a valid date", millis)).into())
+}
+
+/// Converts a date into the number of milliseconds since the epoch, like `Date.prototype.getTime`.
+pub fn to_millis(date: &DateTime<Utc>) -> f64 {
+    date.timestamp_millis() as f64
+}
+
+impl FromValue for DateTime<Utc> {
+    fn from_value(value: Value) -> Result<DateTime<Utc>> {
+        match value {
+            Value::Number(millis) => from_millis(millis),
+            Value::String(ref string) => {
+                DateTime::parse_from_rfc3339(string)
+                    .map(|date| date.with_timezone(&Utc))
+                    .map_err(|e| {
+                        ErrorKind::Conversion(format!("{:?} is not a valid date: {}", string, e))
+                            .into()
+                    })
+            }
+            _ => {
+                Err(ErrorKind::Conversion("expected a number or string for a date".to_owned())
+                    .into())
+            }
+        }
+    }
+}
+
+/// Converts a date into a number of milliseconds since the epoch.  Pass the `DateTime` itself as
+/// an argument to get a `Date` object instead.
+impl From<DateTime<Utc>> for Value {
+    fn from(date: DateTime<Utc>) -> Value {
+        Value::Number(to_millis(&date))
+    }
+}
+
+impl Argument for DateTime<Utc> {
+    unsafe fn push_to_context(&self, context: &Context) {
+        duktape_sys::duk_get_global_string(context.raw, nul_str(b"Date\0"));
+        duktape_sys::duk_push_number(context.raw, to_millis(self));
+        duktape_sys::duk_new(context.raw, 1);
+    }
+}
+
+impl<'a> Reference<'a> {
+    /// Converts a referenced `Date` object, or any other value that `FromValue` accepts for a
+    /// `DateTime`, into a date.  Requires the `chrono` feature.
+    pub fn to_date_time(&self) -> Result<DateTime<Utc>> {
+        let millis = self.with_value(|| unsafe {
+            let raw = self.ctx.raw;
+            duktape_sys::duk_get_global_string(raw, nul_str(b"Date\0"));
+            let is_date = duktape_sys::duk_instanceof(raw, -2, -1) != 0;
+            duktape_sys::duk_pop(raw);
+            if is_date { Some(date_value(raw)) } else { None }
+        });
+        match millis {
+            Some(millis) => from_millis(millis),
+            None => DateTime::from_value(self.to_value()),
+        }
+    }
+}
+
+/// Returns the time value of the `Date` object on top of the stack, or `NaN` if `getTime` throws.
+unsafe fn date_value(raw: *mut duktape_sys::duk_context) -> f64 {
+    duktape_sys::duk_get_prop_string(raw, -1, nul_str(b"getTime\0"));
+    duktape_sys::duk_dup(raw, -2);
+    let millis = if duktape_sys::duk_pcall_method(raw, 0) == 0 {
+        duktape_sys::duk_get_number(raw, -1)
+    } else {
+        f64::NAN
+    };
+    duktape_sys::duk_pop(raw);
+    millis
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use {Context, Value};
+
+    #[test]
+    fn date_conversions() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let date = Utc.with_ymd_and_hms(2016, 12, 31, 23, 59, 58).unwrap();
+        ctx.eval_string("function describe(d) { return [d instanceof Date, d.toISOString()]; }")
+            .unwrap();
+        assert_eq!(Value::Array(vec![Value::Boolean(true),
+                                     Value::String("2016-12-31T23:59:58.000Z".to_owned())]),
+                   ctx.call_global("describe", &[&date]).unwrap().to_value());
+
+        let parsed = ctx.eval_string("new Date(Date.UTC(2016, 11, 31, 23, 59, 58))").unwrap();
+        assert_eq!(date, parsed.to_date_time().unwrap());
+        assert_eq!(date, ctx.eval_as::<DateTime<Utc>>("1483228798000.9").unwrap());
+        let json = "JSON.parse(JSON.stringify(new Date(1483228798000)))";
+        assert_eq!(date, ctx.eval_as::<DateTime<Utc>>(json).unwrap());
+        assert_eq!(Value::Number(1483228798000.0), Value::from(date));
+
+        assert!(ctx.eval_string("new Date(NaN)").unwrap().to_date_time().is_err());
+        assert!(ctx.eval_as::<DateTime<Utc>>("'yesterday'").is_err());
+        assert!(ctx.eval_as::<DateTime<Utc>>("true").is_err());
+        ctx.assert_clean();
+    }
+}
@@ -0,0 +1,425 @@
+//! A `host:db` module for plugins that persist structured data, see
+//! `ContextBuilder::with_database`.
+//!
+//! The context doesn't embed a database engine: statements are handed to a `Connection` that a
+//! host-provided `Driver` opens, typically over SQLite.  Every context is built with a scope (like
+//! the name of the plugin), and the driver opens a separate database for each scope, so plugins
+//! can't see each other's data.  The connection is opened when a script first uses it.
+//!
+//! The module has synchronous functions:
+//!
+//! * `query(sql, params)` returns the rows of a statement as objects keyed by column name.
+//! * `execute(sql, params)` runs a statement and returns the number of rows it changed.
+//! * `transaction(fn)` runs `fn` between `BEGIN` and `COMMIT`, or `ROLLBACK` if it throws.
+//!
+//! Values are always passed as parameters, never spliced into the SQL: `params` is an array with
+//! one value per placeholder, which may be `null`, `undefined` (also `NULL`), a boolean (as 0 or
+//! 1), a number (an integer if it is a safe integer), a string or buffer data.  Integers from the
+//! database are converted to numbers, and blobs to `Uint8Array`s.  Failures throw an `Error` with
+//! the message of the driver.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::io;
+//! use duk::db::{Connection, Rows, SqlValue};
+//!
+//! /// A connection that only knows one query.
+//! struct Clock;
+//!
+//! impl Connection for Clock {
+//!     fn query(&mut self, sql: &str, params: &[SqlValue]) -> io::Result<Rows> {
+//!         assert_eq!("SELECT ? + 1 AS next", sql);
+//!         let next = match params[0] {
+//!             SqlValue::Integer(n) => n + 1,
+//!             _ => 0,
+//!         };
+//!         Ok(Rows {
+//!             columns: vec!["next".to_owned()],
+//!             rows: vec![vec![SqlValue::Integer(next)]],
+//!         })
+//!     }
+//!     fn execute(&mut self, _: &str, _: &[SqlValue]) -> io::Result<u64> {
+//!         Ok(0)
+//!     }
+//! }
+//!
+//! let driver = |_: &str| -> io::Result<Box<dyn Connection>> { Ok(Box::new(Clock)) };
+//! let ctx = duk::Context::builder().with_database(Box::new(driver), "clock").build();
+//! let next = ctx.eval_string("require('host:db').query('SELECT ? + 1 AS next', [41])[0].next")
+//!     .unwrap();
+//! assert_eq!(duk::Value::Number(42.0), next.to_value());
+//! ```
+
+use std::cell;
+use std::io;
+use std::os;
+use std::ptr;
+use std::slice;
+
+use duktape_sys;
+
+use host_modules;
+use strings;
+use HeapData;
+
+/// Builds the exports of the module on top of the native statement function.
+const SETUP: &[u8] = b"(function (run, scope) {
+  function statement(sql, params, write) {
+    if (params !== undefined && !Array.isArray(params)) {
+      throw new TypeError('statement parameters must be an array');
+    }
+    var result = run(String(sql), params || [], write);
+    if (result[0] !== null) {
+      throw new Error('db: ' + result[0]);
+    }
+    return result[1];
+  }
+  var db = {
+    scope: scope,
+    query: function (sql, params) {
+      var result = statement(sql, params, false);
+      return result[1].map(function (values) {
+        var row = {};
+        for (var i = 0; i < result[0].length; i++) {
+          row[result[0][i]] = values[i];
+        }
+        return row;
+      });
+    },
+    execute: function (sql, params) {
+      return statement(sql, params, true);
+    },
+    transaction: function (fn) {
+      db.execute('BEGIN');
+      try {
+        var result = fn(db);
+      } catch (e) {
+        db.execute('ROLLBACK');
+        throw e;
+      }
+      db.execute('COMMIT');
+      return result;
+    }
+  };
+  return Object.freeze(db);
+})";
+
+/// A value that is bound to a statement or read from a row.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SqlValue {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+/// The result of a query.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Rows {
+    /// The column names, in the order of the values of each row.
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<SqlValue>>,
+}
+
+/// A connection to the database of one scope.
+pub trait Connection {
+    /// Runs a statement that returns rows, with one parameter per placeholder.
+    fn query(&mut self, sql: &str, params: &[SqlValue]) -> io::Result<Rows>;
+
+    /// Runs a statement that doesn't return rows, and returns the number of rows it changed.
+    fn execute(&mut self, sql: &str, params: &[SqlValue]) -> io::Result<u64>;
+}
+
+/// Opens the database of a scope on behalf of the context.
+///
+/// Closures taking a scope and returning an `io::Result<Box<dyn Connection>>` are drivers too.
+pub trait Driver {
+    fn open(&self, scope: &str) -> io::Result<Box<dyn Connection>>;
+}
+
+impl<F> Driver for F
+    where F: Fn(&str) -> io::Result<Box<dyn Connection>>
+{
+    fn open(&self, scope: &str) -> io::Result<Box<dyn Connection>> {
+        self(scope)
+    }
+}
+
+/// The driver and scope of a context, and the connection once it has been opened.
+pub(crate) struct Database {
+    driver: Box<dyn Driver>,
+    scope: String,
+    connection: cell::RefCell<Option<Box<dyn Connection>>>,
+}
+
+impl Database {
+    pub(crate) fn new(driver: Box<dyn Driver>, scope: String) -> Database {
+        Database {
+            driver,
+            scope,
+            connection: cell::RefCell::new(None),
+        }
+    }
+
+    /// Runs a statement, opening the connection first if needed.
+    fn run(&self, sql: &str, params: &[SqlValue], write: bool) -> io::Result<Result<u64, Rows>> {
+        let mut connection = self.connection.borrow_mut();
+        if connection.is_none() {
+            #[cfg(feature = "logging")]
+            debug!("db: open scope {}", self.scope);
+            *connection = Some(self.driver.open(&self.scope)?);
+        }
+        let connection = connection.as_mut().unwrap();
+        if write {
+            connection.execute(sql, params).map(Ok)
+        } else {
+            connection.query(sql, params).map(Err)
+        }
+    }
+}
+
+/// Registers the `host:db` module.
+pub(crate) unsafe fn setup(ctx: *mut duktape_sys::duk_context) {
+    use duktape_sys::*;
+
+    let ret = duk_peval_lstring(ctx, SETUP.as_ptr() as *const os::raw::c_char, SETUP.len());
+    assert_eq!(0, ret, "failed to compile host:db");
+    duk_push_c_function(ctx, Some(run), 3);
+    strings::push(ctx, &database(ctx).scope);
+    let ret = duk_pcall(ctx, 2);
+    assert_eq!(0, ret, "failed to set up host:db");
+    host_modules::register(ctx, b"host:db\0");
+}
+
+unsafe fn database<'a>(ctx: *mut duktape_sys::duk_context) -> &'a Database {
+    let mut funcs = duktape_sys::duk_memory_functions::default();
+    duktape_sys::duk_get_memory_functions(ctx, &mut funcs);
+    (*(funcs.udata as *mut HeapData)).database.as_ref().unwrap()
+}
+
+/// Reads the parameter at the top of the stack.
+unsafe fn get_param(ctx: *mut duktape_sys::duk_context) -> Result<SqlValue, String> {
+    use duktape_sys::*;
+
+    const MAX_SAFE_INTEGER: f64 = 9007199254740991.0;
+
+    let mut len = 0;
+    let data = duk_get_buffer_data(ctx, -1, &mut len);
+    Ok(if duk_is_null_or_undefined(ctx, -1) != 0 {
+        SqlValue::Null
+    } else if duk_is_boolean(ctx, -1) != 0 {
+        SqlValue::Integer(duk_get_boolean(ctx, -1) as i64)
+    } else if duk_is_number(ctx, -1) != 0 {
+        let n = duk_get_number(ctx, -1);
+        if n.trunc() == n && n.abs() <= MAX_SAFE_INTEGER {
+            SqlValue::Integer(n as i64)
+        } else {
+            SqlValue::Real(n)
+        }
+    } else if duk_is_string(ctx, -1) != 0 {
+        SqlValue::Text(strings::get(ctx, -1))
+    } else if !data.is_null() {
+        SqlValue::Blob(slice::from_raw_parts(data as *const u8, len).to_vec())
+    } else if duk_is_buffer(ctx, -1) != 0 {
+        SqlValue::Blob(Vec::new())
+    } else {
+        return Err("unsupported parameter type".to_owned());
+    })
+}
+
+unsafe fn push_value(ctx: *mut duktape_sys::duk_context, value: &SqlValue) {
+    use duktape_sys::*;
+
+    match *value {
+        SqlValue::Null => duk_push_null(ctx),
+        SqlValue::Integer(n) => duk_push_number(ctx, n as f64),
+        SqlValue::Real(n) => duk_push_number(ctx, n),
+        SqlValue::Text(ref s) => strings::push(ctx, s),
+        SqlValue::Blob(ref bytes) => {
+            let buf = duk_push_fixed_buffer(ctx, bytes.len());
+            if !bytes.is_empty() {
+                ptr::copy_nonoverlapping(bytes.as_ptr(), buf as *mut u8, bytes.len());
+            }
+            duk_push_buffer_object(ctx, -1, 0, bytes.len(), DUK_BUFOBJ_UINT8ARRAY);
+            duk_remove(ctx, -2);
+        }
+    }
+}
+
+/// `run(sql, params, write)`, returns `[null, changes]` or `[null, [columns, rows]]`, or
+/// `[message]` if the statement failed.
+unsafe extern "C" fn run(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    use duktape_sys::*;
+
+    let sql = strings::get(ctx, 0);
+    let write = duk_to_boolean(ctx, 2) != 0;
+    let mut params = Vec::new();
+    let mut result = Ok(Ok(0));
+    for i in 0..duk_get_length(ctx, 1) as duk_uarridx_t {
+        duk_get_prop_index(ctx, 1, i);
+        let param = get_param(ctx);
+        duk_pop(ctx);
+        match param {
+            Ok(param) => params.push(param),
+            Err(message) => {
+                result = Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                            format!("parameter {}: {}", i + 1, message)));
+                break;
+            }
+        }
+    }
+    if result.is_ok() {
+        result = database(ctx).run(&sql, &params, write);
+    }
+
+    duk_push_array(ctx);
+    match result {
+        Ok(result) => {
+            duk_push_null(ctx);
+            duk_put_prop_index(ctx, -2, 0);
+            match result {
+                Ok(changes) => duk_push_number(ctx, changes as f64),
+                Err(rows) => {
+                    duk_push_array(ctx);
+                    duk_push_array(ctx);
+                    for (i, column) in rows.columns.iter().enumerate() {
+                        strings::push(ctx, column);
+                        duk_put_prop_index(ctx, -2, i as duk_uarridx_t);
+                    }
+                    duk_put_prop_index(ctx, -2, 0);
+                    duk_push_array(ctx);
+                    for (i, row) in rows.rows.iter().enumerate() {
+                        duk_push_array(ctx);
+                        for (j, value) in row.iter().enumerate() {
+                            push_value(ctx, value);
+                            duk_put_prop_index(ctx, -2, j as duk_uarridx_t);
+                        }
+                        duk_put_prop_index(ctx, -2, i as duk_uarridx_t);
+                    }
+                    duk_put_prop_index(ctx, -2, 1);
+                }
+            }
+            duk_put_prop_index(ctx, -2, 1);
+        }
+        Err(e) => {
+            strings::push(ctx, &e.to_string());
+            duk_put_prop_index(ctx, -2, 0);
+        }
+    }
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::cell;
+    use std::io;
+    use std::rc;
+
+    use super::*;
+    use {Context, Value};
+
+    type Log = rc::Rc<cell::RefCell<Vec<(String, String, Vec<SqlValue>)>>>;
+
+    /// A connection that records its statements, and returns one row for every query.
+    struct Recorder {
+        scope: String,
+        log: Log,
+    }
+
+    impl Connection for Recorder {
+        fn query(&mut self, sql: &str, params: &[SqlValue]) -> io::Result<Rows> {
+            self.log.borrow_mut().push((self.scope.clone(), sql.to_owned(), params.to_vec()));
+            Ok(Rows {
+                columns: vec!["id".to_owned(), "name".to_owned(), "data".to_owned()],
+                rows: vec![vec![SqlValue::Integer(1),
+                                SqlValue::Text("one".to_owned()),
+                                SqlValue::Blob(vec![1, 2, 3])]],
+            })
+        }
+
+        fn execute(&mut self, sql: &str, params: &[SqlValue]) -> io::Result<u64> {
+            self.log.borrow_mut().push((self.scope.clone(), sql.to_owned(), params.to_vec()));
+            if sql.starts_with("FAIL") {
+                Err(io::Error::other("syntax error"))
+            } else {
+                Ok(params.len() as u64)
+            }
+        }
+    }
+
+    fn driver(log: &Log) -> Box<dyn Driver> {
+        let log = log.clone();
+        Box::new(move |scope: &str| -> io::Result<Box<dyn Connection>> {
+            Ok(Box::new(Recorder {
+                scope: scope.to_owned(),
+                log: log.clone(),
+            }))
+        })
+    }
+
+    #[test]
+    fn host_db() {
+        let _ = env_logger::init();
+        let log = Log::default();
+        let ctx = Context::builder().with_database(driver(&log), "weather").build();
+        let result = ctx.eval_string(r"
+          var db = require('host:db');
+          var changes = db.execute('INSERT INTO t VALUES (?, ?, ?, ?, ?)',
+                                   [7, 0.5, true, null, new Uint8Array([9])]);
+          var row = db.query('SELECT * FROM t WHERE name = ?', ['x\'; DROP TABLE t; --'])[0];
+          [db.scope, changes, row.id, row.name, row.data instanceof Uint8Array, row.data[2]]
+        ")
+            .unwrap();
+        assert_eq!(Value::Array(vec![Value::String("weather".to_owned()),
+                                     Value::Number(5.0),
+                                     Value::Number(1.0),
+                                     Value::String("one".to_owned()),
+                                     Value::Boolean(true),
+                                     Value::Number(3.0)]),
+                   result.to_value());
+        assert_eq!(vec![("weather".to_owned(),
+                         "INSERT INTO t VALUES (?, ?, ?, ?, ?)".to_owned(),
+                         vec![SqlValue::Integer(7),
+                              SqlValue::Real(0.5),
+                              SqlValue::Integer(1),
+                              SqlValue::Null,
+                              SqlValue::Blob(vec![9])]),
+                        ("weather".to_owned(),
+                         "SELECT * FROM t WHERE name = ?".to_owned(),
+                         vec![SqlValue::Text("x'; DROP TABLE t; --".to_owned())])],
+                   *log.borrow());
+
+        let other = Context::builder().with_database(driver(&log), "news").build();
+        other.eval_string("require('host:db').execute('DELETE FROM t')").unwrap();
+        assert_eq!("news", log.borrow()[2].0);
+        ctx.assert_clean();
+        other.assert_clean();
+    }
+
+    #[test]
+    fn db_errors_and_transactions() {
+        let _ = env_logger::init();
+        let log = Log::default();
+        let ctx = Context::builder().with_database(driver(&log), "p").build();
+        let error = ctx.eval_string(r"
+          var db = require('host:db');
+          try {
+            db.transaction(function () { db.execute('UPDATE t SET n = 1'); db.execute('FAIL'); });
+          } catch (e) {
+            e.message;
+          }
+        ")
+            .unwrap();
+        assert_eq!(Value::String("db: syntax error".to_owned()), error.to_value());
+        let statements = log.borrow().iter().map(|s| s.1.clone()).collect::<Vec<_>>();
+        assert_eq!(vec!["BEGIN", "UPDATE t SET n = 1", "FAIL", "ROLLBACK"], statements);
+        assert!(ctx.eval_string("require('host:db').query('SELECT ?', [{}])").is_err());
+        assert!(ctx.eval_string("require('host:db').query('SELECT ?', 1)").is_err());
+        assert_eq!(4, log.borrow().len());
+        ctx.assert_clean();
+    }
+}
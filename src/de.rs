@@ -0,0 +1,207 @@
+//! Converting values into types that implement `serde::Deserialize`, with the `serde` feature.
+//!
+//! Objects deserialize like maps and structs, arrays like sequences and tuples, and buffers like
+//! byte arrays.  Numbers without a fractional part deserialize as integers too, `null` and
+//! `undefined` are both unit and `None`, and enums are either a string with the name of a unit
+//! variant or an object with a single property named after the variant.
+//!
+//! # Examples
+//!
+//! ```
+//! # extern crate duk;
+//! # extern crate serde;
+//! #[derive(serde::Deserialize)]
+//! struct Point {
+//!     x: i32,
+//!     y: i32,
+//! }
+//!
+//! # fn main() {
+//! let ctx = duk::Context::new();
+//! let duk::de::Deserialized(point): duk::de::Deserialized<Point> =
+//!     ctx.eval_as("({x: 1, y: -2})").unwrap();
+//! assert_eq!((1, -2), (point.x, point.y));
+//! # }
+//! ```
+
+use std::marker;
+
+use serde;
+use serde::de::value::{MapAccessDeserializer, MapDeserializer, SeqDeserializer};
+use serde::de::IntoDeserializer;
+
+use {ErrorKind, FromValue, Result, Value};
+
+/// A value of a type that implements `Deserialize`, which makes it a `FromValue`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Deserialized<T>(pub T);
+
+/// A deserializer that reads a value, and reports errors of type `E`.
+#[derive(Debug)]
+pub struct Deserializer<E> {
+    value: Value,
+    error: marker::PhantomData<E>,
+}
+
+/// Deserializes a value into any type that implements `Deserialize`.
+pub fn from_value<T>(value: Value) -> Result<T>
+    where T: serde::de::DeserializeOwned
+{
+    let deserializer: Deserializer<serde::de::value::Error> = value.into_deserializer();
+    T::deserialize(deserializer).map_err(|e| ErrorKind::Conversion(e.to_string()).into())
+}
+
+impl<T> FromValue for Deserialized<T>
+    where T: serde::de::DeserializeOwned
+{
+    fn from_value(value: Value) -> Result<Deserialized<T>> {
+        from_value(value).map(Deserialized)
+    }
+}
+
+impl<'de, E> IntoDeserializer<'de, E> for Value
+    where E: serde::de::Error
+{
+    type Deserializer = Deserializer<E>;
+
+    fn into_deserializer(self) -> Deserializer<E> {
+        Deserializer {
+            value: self,
+            error: marker::PhantomData,
+        }
+    }
+}
+
+impl<'de, E> serde::Deserializer<'de> for Deserializer<E>
+    where E: serde::de::Error
+{
+    type Error = E;
+
+    fn deserialize_any<V>(self, visitor: V) -> ::std::result::Result<V::Value, E>
+        where V: serde::de::Visitor<'de>
+    {
+        match self.value {
+            Value::Undefined | Value::Null => visitor.visit_unit(),
+            Value::Boolean(b) => visitor.visit_bool(b),
+            Value::Number(n) if n.fract() == 0.0 && n >= 0.0 && n <= u64::MAX as f64 => {
+                visitor.visit_u64(n as u64)
+            }
+            Value::Number(n) if n.fract() == 0.0 && n >= i64::MIN as f64 && n < 0.0 => {
+                visitor.visit_i64(n as i64)
+            }
+            Value::Number(n) => visitor.visit_f64(n),
+            Value::String(s) => visitor.visit_string(s),
+            Value::Array(values) => {
+                let mut seq = SeqDeserializer::new(values.into_iter());
+                let result = visitor.visit_seq(&mut seq)?;
+                seq.end()?;
+                Ok(result)
+            }
+            Value::Object(object) => {
+                let mut map = MapDeserializer::new(object.into_iter());
+                let result = visitor.visit_map(&mut map)?;
+                map.end()?;
+                Ok(result)
+            }
+            Value::Bytes(bytes) => visitor.visit_byte_buf(bytes),
+            Value::Foreign(name) => Err(E::custom(format!("can't deserialize a {}", name))),
+        }
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> ::std::result::Result<V::Value, E>
+        where V: serde::de::Visitor<'de>
+    {
+        match self.value {
+            Value::Undefined | Value::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_enum<V>(self,
+                           _name: &'static str,
+                           _variants: &'static [&'static str],
+                           visitor: V)
+                           -> ::std::result::Result<V::Value, E>
+        where V: serde::de::Visitor<'de>
+    {
+        match self.value {
+            Value::String(variant) => visitor.visit_enum(variant.into_deserializer()),
+            Value::Object(ref object) if object.len() != 1 => {
+                Err(E::custom("expected an object with a single property for an enum"))
+            }
+            Value::Object(object) => {
+                visitor.visit_enum(MapAccessDeserializer::new(MapDeserializer::new(object.into_iter())))
+            }
+            _ => Err(E::custom("expected a string or an object for an enum")),
+        }
+    }
+
+    fn deserialize_newtype_struct<V>(self,
+                                     _name: &'static str,
+                                     visitor: V)
+                                     -> ::std::result::Result<V::Value, E>
+        where V: serde::de::Visitor<'de>
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf unit
+        unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::collections;
+
+    use super::*;
+    use Context;
+
+    #[derive(Debug, PartialEq, serde::Deserialize)]
+    enum Shape {
+        Dot,
+        Circle { radius: f64 },
+        Square(u32),
+    }
+
+    #[derive(Debug, PartialEq, serde::Deserialize)]
+    struct Drawing {
+        name: String,
+        shapes: Vec<Shape>,
+        tags: collections::BTreeMap<String, bool>,
+        origin: (i32, i32),
+        layer: Option<u8>,
+        scale: Option<f32>,
+    }
+
+    #[test]
+    fn deserialize_values() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let Deserialized(drawing): Deserialized<Drawing> = ctx.eval_as(r"({
+          name: 'plan',
+          shapes: ['Dot', {Circle: {radius: 1.5}}, {Square: 3}],
+          tags: {draft: true},
+          origin: [-1, 2],
+          layer: null
+        })")
+            .unwrap();
+        assert_eq!(Drawing {
+                       name: "plan".to_owned(),
+                       shapes: vec![Shape::Dot, Shape::Circle { radius: 1.5 }, Shape::Square(3)],
+                       tags: vec![("draft".to_owned(), true)].into_iter().collect(),
+                       origin: (-1, 2),
+                       layer: None,
+                       scale: None,
+                   },
+                   drawing);
+
+        let error = ctx.eval_as::<Deserialized<Drawing>>("({name: 'plan'})").unwrap_err();
+        assert_eq!("value could not be converted: missing field `shapes`", error.to_string());
+        assert!(ctx.eval_as::<Deserialized<Shape>>("({Square: -3})").is_err());
+        ctx.assert_clean();
+    }
+}
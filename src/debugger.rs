@@ -0,0 +1,1037 @@
+//! Support for the Duktape debug protocol, so that debug clients like `duk_debug.js` can be
+//! connected to a running context.
+//!
+//! The debug protocol can be spoken over any `DebugTransport`, which is implemented for TCP
+//! streams as well as for in-process channels created with `channel`.
+//!
+//! Alternatively, breakpoints can be managed directly from Rust with `Context::add_breakpoint`
+//! and `Context::on_pause`, in which case the wire protocol is spoken by a client living inside
+//! the host.
+
+use std::cell;
+use std::cmp;
+use std::collections;
+use std::fmt;
+use std::io;
+use std::net;
+use std::os;
+use std::rc;
+use std::slice;
+use std::sync::mpsc;
+use std::time;
+
+use duktape_sys;
+
+use Value;
+use coverage;
+use source_map;
+
+use std::io::Read;
+use std::io::Write;
+
+/// A transport that the Duktape debug protocol can be spoken over.
+///
+/// Reads are allowed to block until data is available.
+pub trait DebugTransport: io::Read + io::Write {
+    /// Returns the number of bytes that can currently be read without blocking.
+    ///
+    /// This lets Duktape notice incoming debug messages (like a pause request) while scripts are
+    /// running.  The default implementation always returns 0, in which case messages are only
+    /// processed while the debugger is paused or when the host calls
+    /// `Context::debugger_cooperate`.
+    fn available(&mut self) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl DebugTransport for net::TcpStream {
+    fn available(&mut self) -> io::Result<usize> {
+        let mut buf = [0; 1];
+        self.set_nonblocking(true)?;
+        let result = match self.peek(&mut buf) {
+            Ok(n) => Ok(n),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            Err(e) => Err(e),
+        };
+        self.set_nonblocking(false)?;
+        result
+    }
+}
+
+/// One end of an in-process debug transport, created with `channel`.
+///
+/// This can be used to tunnel the debug protocol over an existing connection of the host, or to
+/// talk to the debugger from tests.
+#[derive(Debug)]
+pub struct ChannelTransport {
+    sender: mpsc::Sender<Vec<u8>>,
+    receiver: mpsc::Receiver<Vec<u8>>,
+    buffer: Vec<u8>,
+}
+
+/// Creates a pair of connected in-process debug transports.
+///
+/// One end should be attached to a context using `Context::attach_debugger_transport`, and the
+/// other end is used by the debug client.
+pub fn channel() -> (ChannelTransport, ChannelTransport) {
+    let (a_sender, b_receiver) = mpsc::channel();
+    let (b_sender, a_receiver) = mpsc::channel();
+
+    let a = ChannelTransport {
+        sender: a_sender,
+        receiver: a_receiver,
+        buffer: Vec::new(),
+    };
+    let b = ChannelTransport {
+        sender: b_sender,
+        receiver: b_receiver,
+        buffer: Vec::new(),
+    };
+
+    (a, b)
+}
+
+impl io::Read for ChannelTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.buffer.is_empty() {
+            match self.receiver.recv() {
+                Ok(data) => self.buffer = data,
+                Err(_) => return Ok(0), // The other end hung up
+            }
+        }
+
+        let n = cmp::min(buf.len(), self.buffer.len());
+        buf[..n].copy_from_slice(&self.buffer[..n]);
+        self.buffer.drain(..n);
+        Ok(n)
+    }
+}
+
+impl io::Write for ChannelTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.sender.send(buf.to_vec()) {
+            Ok(()) => Ok(buf.len()),
+            Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "debug channel closed")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl DebugTransport for ChannelTransport {
+    fn available(&mut self) -> io::Result<usize> {
+        while let Ok(data) = self.receiver.try_recv() {
+            self.buffer.extend(data);
+        }
+        Ok(self.buffer.len())
+    }
+}
+
+/// An attached debugger session.
+pub(crate) struct Session {
+    transport: Box<dyn DebugTransport>,
+    detached: bool,
+}
+
+impl Session {
+    pub fn new(transport: Box<dyn DebugTransport>) -> Session {
+        Session {
+            transport,
+            detached: false,
+        }
+    }
+
+    pub fn is_detached(&self) -> bool {
+        self.detached
+    }
+}
+
+/// Attaches the debugger of the specified context to a session.  The session must stay alive
+/// until the debugger has been detached or the heap has been destroyed.
+pub(crate) unsafe fn attach(ctx: *mut duktape_sys::duk_context, session: *mut Session) {
+    duktape_sys::duk_debugger_attach(ctx,
+                                     Some(read_handler),
+                                     Some(write_handler),
+                                     Some(peek_handler),
+                                     None,
+                                     Some(write_flush_handler),
+                                     None,
+                                     Some(detached_handler),
+                                     session as *mut os::raw::c_void);
+}
+
+// The transport handlers return 0 on errors, which makes Duktape detach the debugger.
+
+unsafe extern "C" fn read_handler(udata: *mut os::raw::c_void,
+                                  buffer: *mut os::raw::c_char,
+                                  length: duktape_sys::duk_size_t)
+                                  -> duktape_sys::duk_size_t {
+    let session = &mut *(udata as *mut Session);
+    let buf = slice::from_raw_parts_mut(buffer as *mut u8, length);
+    session.transport.read(buf).unwrap_or(0)
+}
+
+unsafe extern "C" fn write_handler(udata: *mut os::raw::c_void,
+                                   buffer: *const os::raw::c_char,
+                                   length: duktape_sys::duk_size_t)
+                                   -> duktape_sys::duk_size_t {
+    let session = &mut *(udata as *mut Session);
+    let buf = slice::from_raw_parts(buffer as *const u8, length);
+    session.transport.write(buf).unwrap_or(0)
+}
+
+unsafe extern "C" fn peek_handler(udata: *mut os::raw::c_void) -> duktape_sys::duk_size_t {
+    let session = &mut *(udata as *mut Session);
+    session.transport.available().unwrap_or(0)
+}
+
+unsafe extern "C" fn write_flush_handler(udata: *mut os::raw::c_void) {
+    let session = &mut *(udata as *mut Session);
+    let _ = session.transport.flush();
+}
+
+unsafe extern "C" fn detached_handler(_: *mut duktape_sys::duk_context, udata: *mut os::raw::c_void) {
+    let session = &mut *(udata as *mut Session);
+    session.detached = true;
+}
+
+/// A frame of the call stack of a paused context.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StackFrame {
+    /// The file name of the function, as given when the code was evaluated (or the original file
+    /// name, if a source map is registered for it).
+    pub file_name: String,
+    /// The name of the function, or an empty string for anonymous functions.
+    pub function_name: String,
+    /// The line that is being executed.
+    pub line_number: u32,
+    /// The bytecode offset that is being executed.
+    pub pc: u32,
+}
+
+/// The state of a context that has been paused, for example because a breakpoint was hit.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PausedState {
+    /// The call stack, with the innermost frame first.
+    pub call_stack: Vec<StackFrame>,
+    /// The local variables of the innermost frame.
+    ///
+    /// Objects are not expanded and show up as `Value::Foreign("object")`.
+    pub locals: Vec<(String, Value)>,
+    /// The message of the error that is about to be thrown, if execution was paused because of
+    /// an uncaught error.
+    pub uncaught_error: Option<String>,
+}
+
+impl PausedState {
+    /// The innermost frame of the call stack, i.e. the location where execution is paused.
+    pub fn location(&self) -> Option<&StackFrame> {
+        self.call_stack.first()
+    }
+}
+
+/// What to do after a pause handler returns.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StepAction {
+    /// Continue execution until the next breakpoint.
+    Resume,
+    /// Pause again at the next line, entering any called function.
+    StepInto,
+    /// Pause again at the next line of the current function.
+    StepOver,
+    /// Pause again after the current function returns.
+    StepOut,
+}
+
+pub type PauseHandler = dyn Fn(&PausedState) -> StepAction;
+
+/// A line that was executed while tracing, see `Context::start_trace`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEvent {
+    /// The file name of the function (or the original file name, if a source map is registered
+    /// for it).
+    pub file_name: String,
+    /// The name of the function, or an empty string for anonymous functions.
+    pub function_name: String,
+    /// The line that is about to be executed.
+    pub line_number: u32,
+    /// The local variables of the function, if requested with `TraceOptions::locals`.
+    pub locals: Vec<(String, Value)>,
+    /// The number of executed lines that were not traced since the previous event, because of
+    /// the rate limit.
+    pub skipped: u64,
+}
+
+/// Options that control what is traced.
+#[derive(Clone, Debug, Default)]
+pub struct TraceOptions {
+    /// Whether to include the local variables in every event, which makes tracing much slower.
+    pub locals: bool,
+    /// The maximum number of lines to trace per second; lines beyond the limit are counted in
+    /// `TraceEvent::skipped` instead.  Unlimited if `None`.
+    pub max_lines_per_second: Option<u32>,
+}
+
+pub type TraceHandler = dyn Fn(&TraceEvent);
+
+/// What to do after a statement hook returns, see `Context::set_statement_hook`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StatementAction {
+    /// Execute the statement.
+    Continue,
+    /// Abort the running evaluation or call with a `RangeError`.
+    Abort,
+}
+
+pub type StatementHook = dyn Fn(&str, u32) -> StatementAction;
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.skipped > 0 {
+            writeln!(f, "... {} lines skipped", self.skipped)?;
+        }
+        let function_name = if self.function_name.is_empty() {
+            "(anonymous)"
+        } else {
+            &self.function_name
+        };
+        write!(f, "{}:{} {}", self.file_name, self.line_number, function_name)?;
+        for (i, (name, value)) in self.locals.iter().enumerate() {
+            write!(f, "{}{}=", if i == 0 { " " } else { ", " }, name)?;
+            match *value {
+                Value::Undefined => write!(f, "undefined")?,
+                Value::Null => write!(f, "null")?,
+                Value::Boolean(b) => write!(f, "{}", b)?,
+                Value::Number(n) => write!(f, "{}", n)?,
+                Value::String(ref s) => write!(f, "{:?}", s)?,
+                Value::Foreign(t) => write!(f, "[{}]", t)?,
+                ref other => write!(f, "{:?}", other)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A debug client living inside the host, which lets the host control the debugger without
+/// speaking the wire protocol.
+#[derive(Clone)]
+pub(crate) struct Inspector {
+    state: rc::Rc<cell::RefCell<InspectorState>>,
+}
+
+struct InspectorState {
+    source_maps: rc::Rc<cell::RefCell<source_map::SourceMapRegistry>>,
+    handshake_done: bool,
+    to_duktape: collections::VecDeque<u8>,
+    from_duktape: Vec<u8>,
+    pending: collections::VecDeque<Pending>,
+    paused: Option<PausedState>,
+    breakpoints: Vec<(String, u32)>,
+    pause_handler: Option<rc::Rc<PauseHandler>>,
+    pause_on_uncaught: bool,
+    uncaught_error: Option<String>,
+    pause_requested: bool,
+    awaiting_execution: bool,
+    coverage: Option<coverage::CoverageReport>,
+    trace: Option<Trace>,
+    trace_pending: bool,
+    statement_hook: Option<rc::Rc<StatementHook>>,
+    pause_wanted: bool,
+    last_error: Option<String>,
+}
+
+/// The state of an active trace.
+struct Trace {
+    handler: rc::Rc<TraceHandler>,
+    options: TraceOptions,
+    window_start: time::Instant,
+    window_lines: u32,
+    skipped: u64,
+}
+
+/// A request that has been sent to Duktape, and is awaiting a reply.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Pending {
+    AddBreak,
+    DelBreak,
+    GetCallStack,
+    GetLocals,
+    Pause,
+    Resume,
+    TriggerStatus,
+}
+
+/// The transport end of an `Inspector` that is attached to Duktape.
+struct InspectorTransport {
+    state: rc::Rc<cell::RefCell<InspectorState>>,
+}
+
+/// A value in the debug protocol.
+#[derive(Clone, Debug, PartialEq)]
+enum DValue {
+    Eom,
+    Req,
+    Rep,
+    Err,
+    Nfy,
+    Int(i32),
+    Str(Vec<u8>),
+    Buf(Vec<u8>),
+    Unused,
+    Undefined,
+    Null,
+    Bool(bool),
+    Number(f64),
+    Object,
+    Pointer,
+    LightFunc,
+    HeapPtr,
+}
+
+const CMD_STATUS: i32 = 0x01;
+const CMD_THROW: i32 = 0x05;
+const CMD_TRIGGERSTATUS: i32 = 0x11;
+const CMD_PAUSE: i32 = 0x12;
+const CMD_RESUME: i32 = 0x13;
+const CMD_STEPINTO: i32 = 0x14;
+const CMD_STEPOVER: i32 = 0x15;
+const CMD_STEPOUT: i32 = 0x16;
+const CMD_ADDBREAK: i32 = 0x18;
+const CMD_DELBREAK: i32 = 0x19;
+const CMD_GETCALLSTACK: i32 = 0x1c;
+const CMD_GETLOCALS: i32 = 0x1d;
+
+impl Inspector {
+    pub fn new(source_maps: rc::Rc<cell::RefCell<source_map::SourceMapRegistry>>) -> Inspector {
+        Inspector {
+            state: rc::Rc::new(cell::RefCell::new(InspectorState {
+                source_maps,
+                handshake_done: false,
+                to_duktape: collections::VecDeque::new(),
+                from_duktape: Vec::new(),
+                pending: collections::VecDeque::new(),
+                paused: None,
+                breakpoints: Vec::new(),
+                pause_handler: None,
+                pause_on_uncaught: false,
+                uncaught_error: None,
+                pause_requested: false,
+                awaiting_execution: false,
+                coverage: None,
+                trace: None,
+                trace_pending: false,
+                statement_hook: None,
+                pause_wanted: false,
+                last_error: None,
+            })),
+        }
+    }
+
+    pub fn transport(&self) -> Box<dyn DebugTransport> {
+        Box::new(InspectorTransport { state: self.state.clone() })
+    }
+
+    pub fn set_pause_handler(&self, handler: rc::Rc<PauseHandler>) {
+        self.state.borrow_mut().pause_handler = Some(handler);
+    }
+
+    pub fn set_pause_on_uncaught(&self, pause: bool) {
+        self.state.borrow_mut().pause_on_uncaught = pause;
+    }
+
+    /// Queues a request for pausing at the next executed statement.
+    pub fn pause(&self) {
+        let mut state = self.state.borrow_mut();
+        state.pause_requested = true;
+        state.request(Pending::Pause, &[DValue::Int(CMD_PAUSE)]);
+    }
+
+    /// Queues a request for adding a breakpoint; Duktape handles it the next time it processes
+    /// debug messages.
+    pub fn add_breakpoint(&self, file_name: &str, line: u32) {
+        let mut state = self.state.borrow_mut();
+        state.breakpoints.push((file_name.to_owned(), line));
+        state.request(Pending::AddBreak,
+                      &[DValue::Int(CMD_ADDBREAK),
+                        DValue::Str(file_name.as_bytes().to_vec()),
+                        DValue::Int(line as i32)]);
+    }
+
+    /// Queues a request for removing a breakpoint, and returns whether the breakpoint existed.
+    pub fn remove_breakpoint(&self, file_name: &str, line: u32) -> bool {
+        let mut state = self.state.borrow_mut();
+        let index = state.breakpoints.iter().position(|&(ref f, l)| f == file_name && l == line);
+        match index {
+            Some(index) => {
+                // Duktape shifts the indices of the breakpoints after the removed one, just like
+                // our own list does.
+                state.breakpoints.remove(index);
+                state.request(Pending::DelBreak,
+                              &[DValue::Int(CMD_DELBREAK), DValue::Int(index as i32)]);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Starts recording coverage, by stepping into every line starting with the next executed
+    /// statement.
+    pub fn start_coverage(&self) {
+        let mut state = self.state.borrow_mut();
+        state.coverage = Some(coverage::CoverageReport::default());
+        state.request(Pending::Pause, &[DValue::Int(CMD_PAUSE)]);
+    }
+
+    /// Returns the coverage recorded so far, if coverage is being recorded.
+    pub fn coverage(&self) -> Option<coverage::CoverageReport> {
+        self.state.borrow().coverage.clone()
+    }
+
+    /// Stops recording coverage, and returns the recorded coverage.
+    pub fn stop_coverage(&self) -> Option<coverage::CoverageReport> {
+        let report = self.state.borrow_mut().coverage.take();
+        if report.is_some() {
+            self.stop_stepping();
+        }
+        report
+    }
+
+    /// Starts tracing, by stepping into every line starting with the next executed statement.
+    pub fn start_trace(&self, options: TraceOptions, handler: rc::Rc<TraceHandler>) {
+        let mut state = self.state.borrow_mut();
+        state.trace = Some(Trace {
+            handler,
+            options,
+            window_start: time::Instant::now(),
+            window_lines: 0,
+            skipped: 0,
+        });
+        state.request(Pending::Pause, &[DValue::Int(CMD_PAUSE)]);
+    }
+
+    /// Stops tracing, and returns whether tracing was active.
+    pub fn stop_trace(&self) -> bool {
+        let tracing = self.state.borrow_mut().trace.take().is_some();
+        if tracing {
+            self.stop_stepping();
+        }
+        tracing
+    }
+
+    /// Sets the hook that is called before every executed line, by stepping into every line
+    /// starting with the next executed statement.  The returned `StatementAction` is ignored, acting
+    /// on it is up to the hook since aborting requires the help of the executor interrupt.
+    pub fn set_statement_hook(&self, hook: rc::Rc<StatementHook>) {
+        let mut state = self.state.borrow_mut();
+        state.statement_hook = Some(hook);
+        state.request(Pending::Pause, &[DValue::Int(CMD_PAUSE)]);
+    }
+
+    /// Removes the statement hook, and returns whether there was one.
+    pub fn clear_statement_hook(&self) -> bool {
+        let hooked = self.state.borrow_mut().statement_hook.take().is_some();
+        if hooked {
+            self.stop_stepping();
+        }
+        hooked
+    }
+
+    fn stop_stepping(&self) {
+        if !self.state.borrow().stepping() {
+            // Clears any pending pause left behind by the last step
+            self.step(StepAction::Resume);
+        }
+    }
+
+    /// Takes the last error that Duktape replied with, if any.
+    pub fn take_error(&self) -> Option<String> {
+        self.state.borrow_mut().last_error.take()
+    }
+
+    /// Handles all of the complete messages that Duktape has sent so far.
+    fn process(&self) {
+        loop {
+            let message = {
+                let mut state = self.state.borrow_mut();
+                if !state.handshake_done {
+                    // The version identification line precedes the binary protocol.
+                    match state.from_duktape.iter().position(|&b| b == b'\n') {
+                        Some(n) => {
+                            state.from_duktape.drain(..n + 1);
+                            state.handshake_done = true;
+                        }
+                        None => return,
+                    }
+                }
+
+                match parse_message(&state.from_duktape) {
+                    Some((message, n)) => {
+                        state.from_duktape.drain(..n);
+                        message
+                    }
+                    None => return,
+                }
+            };
+
+            self.handle(message);
+        }
+    }
+
+    fn handle(&self, message: Vec<DValue>) {
+        match message.first() {
+            Some(&DValue::Nfy) if message.get(1) == Some(&DValue::Int(CMD_STATUS)) &&
+                                  message.get(2) == Some(&DValue::Int(1)) => self.paused(),
+            Some(&DValue::Nfy) if message.get(1) == Some(&DValue::Int(CMD_THROW)) &&
+                                  message.get(2) == Some(&DValue::Int(1)) => {
+                // Duktape pauses right after notifying about a fatal throw
+                let error = message.get(3).map(dvalue_to_string).unwrap_or_default();
+                self.state.borrow_mut().uncaught_error = Some(error);
+            }
+            Some(&DValue::Rep) => {
+                let pending = self.state.borrow_mut().pending.pop_front();
+                match pending {
+                    Some(Pending::GetCallStack) => {
+                        self.call_statement_hook(&message);
+                        let mut state = self.state.borrow_mut();
+                        // Breakpoints refer to generated locations, so check before translating
+                        let at_breakpoint = message.len() > 4 && {
+                            let file_name = dvalue_to_string(&message[1]);
+                            let line = dvalue_to_int(&message[3]) as u32;
+                            state.breakpoints.iter().any(|&(ref f, l)| *f == file_name && l == line)
+                        };
+                        let call_stack: Vec<StackFrame> = {
+                            let source_maps = state.source_maps.borrow();
+                            message[1..message.len() - 1]
+                                .chunks(4)
+                                .filter(|c| c.len() == 4)
+                                .map(|c| {
+                                    let mut frame = StackFrame {
+                                        file_name: dvalue_to_string(&c[0]),
+                                        function_name: dvalue_to_string(&c[1]),
+                                        line_number: dvalue_to_int(&c[2]) as u32,
+                                        pc: dvalue_to_int(&c[3]) as u32,
+                                    };
+                                    let original = source_maps.lookup(&frame.file_name,
+                                                                      frame.line_number);
+                                    if let Some(original) = original {
+                                        frame.file_name = original.file_name;
+                                        frame.line_number = original.line_number;
+                                    }
+                                    frame
+                                })
+                                .collect()
+                        };
+                        let stepping = state.stepping();
+                        if let Some(ref mut coverage) = state.coverage {
+                            coverage.record(&call_stack);
+                        }
+                        let traced = match state.trace {
+                            Some(ref mut trace) if !call_stack.is_empty() => trace.admit(),
+                            _ => false,
+                        };
+                        // While stepping through every line, only some pauses are meant for the
+                        // handler.
+                        state.pause_wanted = state.pause_handler.is_some() &&
+                                             (!stepping || state.pause_requested || at_breakpoint ||
+                                              (state.uncaught_error.is_some() &&
+                                               state.pause_on_uncaught));
+                        let trace_locals = traced &&
+                                           state.trace.as_ref().is_some_and(|t| t.options.locals);
+                        if let Some(ref mut paused) = state.paused {
+                            paused.call_stack = call_stack;
+                        }
+                        state.trace_pending = traced;
+                        if stepping && !state.pause_wanted && !trace_locals &&
+                           state.paused.as_ref().is_some_and(|p| !p.call_stack.is_empty()) {
+                            drop(state);
+                            self.emit_trace();
+                            self.step(StepAction::StepInto);
+                        } else {
+                            state.request(Pending::GetLocals, &[DValue::Int(CMD_GETLOCALS)]);
+                        }
+                    }
+                    Some(Pending::GetLocals) => {
+                        let locals = message[1..message.len() - 1]
+                            .chunks(2)
+                            .filter(|c| c.len() == 2)
+                            .map(|c| (dvalue_to_string(&c[0]), dvalue_to_value(&c[1])))
+                            .collect();
+                        let (handler, paused, stepping) = {
+                            let mut state = self.state.borrow_mut();
+                            if let Some(ref mut paused) = state.paused {
+                                paused.locals = locals;
+                            }
+                            let handler = if state.pause_wanted {
+                                state.pause_handler.clone()
+                            } else {
+                                None
+                            };
+                            (handler, state.paused.clone(), state.stepping())
+                        };
+                        self.emit_trace();
+                        let action = match (handler, paused) {
+                            (Some(handler), Some(ref paused)) if !paused.call_stack.is_empty() => {
+                                // The state must not be borrowed while the handler runs
+                                self.state.borrow_mut().pause_requested = false;
+                                match handler(paused) {
+                                    // Keep stepping through every line while recording
+                                    StepAction::Resume if stepping => StepAction::StepInto,
+                                    action => action,
+                                }
+                            }
+                            (_, Some(ref paused)) if stepping && !paused.call_stack.is_empty() => {
+                                StepAction::StepInto
+                            }
+                            (_, Some(_)) if self.state.borrow().pause_requested || stepping => {
+                                // Paused outside of any function, which means that the pause will
+                                // take effect once execution starts.
+                                let mut state = self.state.borrow_mut();
+                                state.paused = None;
+                                state.awaiting_execution = true;
+                                return;
+                            }
+                            // Duktape pauses when the debugger is attached, at which point
+                            // nothing is executing yet.
+                            _ => StepAction::Resume,
+                        };
+                        self.step(action);
+                    }
+                    Some(Pending::Resume) => {
+                        let mut state = self.state.borrow_mut();
+                        state.paused = None;
+                        state.uncaught_error = None;
+                    }
+                    Some(Pending::AddBreak) | Some(Pending::DelBreak) | Some(Pending::Pause) |
+                    Some(Pending::TriggerStatus) | None => {}
+                }
+            }
+            Some(&DValue::Err) => {
+                let pending = {
+                    let mut state = self.state.borrow_mut();
+                    state.last_error = Some(message.get(2).map(dvalue_to_string).unwrap_or_default());
+                    state.pending.pop_front()
+                };
+                match pending {
+                    Some(Pending::GetCallStack) | Some(Pending::GetLocals) => {
+                        self.step(StepAction::Resume)
+                    }
+                    Some(Pending::Resume) => self.state.borrow_mut().paused = None,
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn paused(&self) {
+        let mut state = self.state.borrow_mut();
+        if state.paused.is_some() {
+            // Already handling this pause
+            return;
+        }
+
+        state.paused = Some(PausedState {
+            call_stack: Vec::new(),
+            locals: Vec::new(),
+            uncaught_error: state.uncaught_error.clone(),
+        });
+
+        let ignored = state.uncaught_error.is_some() && !state.pause_on_uncaught;
+        if (state.pause_handler.is_some() && !ignored) || state.stepping() {
+            state.request(Pending::GetCallStack, &[DValue::Int(CMD_GETCALLSTACK)]);
+        } else {
+            state.request(Pending::Resume, &[DValue::Int(CMD_RESUME)]);
+        }
+    }
+
+    /// Calls the statement hook (if any) with the location of the innermost frame of a
+    /// GetCallStack reply.
+    fn call_statement_hook(&self, message: &[DValue]) {
+        let hook = match self.state.borrow().statement_hook {
+            Some(ref hook) if message.len() > 4 => hook.clone(),
+            _ => return,
+        };
+        let mut file_name = dvalue_to_string(&message[1]);
+        let mut line = dvalue_to_int(&message[3]) as u32;
+        let original = self.state.borrow().source_maps.borrow().lookup(&file_name, line);
+        if let Some(original) = original {
+            file_name = original.file_name;
+            line = original.line_number;
+        }
+        // The state must not be borrowed while the hook runs
+        hook(&file_name, line);
+    }
+
+    /// Calls the trace handler with the current location, if it was admitted by the rate limit.
+    fn emit_trace(&self) {
+        let (handler, event) = {
+            let mut state = self.state.borrow_mut();
+            if !state.trace_pending {
+                return;
+            }
+            state.trace_pending = false;
+            let (frame, locals) = match state.paused {
+                Some(ref paused) => (paused.call_stack.first().cloned(), paused.locals.clone()),
+                None => (None, Vec::new()),
+            };
+            let trace = match (frame, state.trace.as_mut()) {
+                (Some(frame), Some(trace)) => {
+                    let event = TraceEvent {
+                        file_name: frame.file_name,
+                        function_name: frame.function_name,
+                        line_number: frame.line_number,
+                        locals: if trace.options.locals { locals } else { Vec::new() },
+                        skipped: trace.skipped,
+                    };
+                    trace.skipped = 0;
+                    Some((trace.handler.clone(), event))
+                }
+                _ => None,
+            };
+            match trace {
+                Some(trace) => trace,
+                None => return,
+            }
+        };
+        // The state must not be borrowed while the handler runs
+        handler(&event);
+    }
+
+    fn step(&self, action: StepAction) {
+        let command = match action {
+            StepAction::Resume => CMD_RESUME,
+            StepAction::StepInto => CMD_STEPINTO,
+            StepAction::StepOver => CMD_STEPOVER,
+            StepAction::StepOut => CMD_STEPOUT,
+        };
+        // Stepping resumes execution just like resuming does
+        self.state.borrow_mut().request(Pending::Resume, &[DValue::Int(command)]);
+    }
+}
+
+impl InspectorState {
+    /// Whether every executed line is being stepped into.
+    fn stepping(&self) -> bool {
+        self.coverage.is_some() || self.trace.is_some() || self.statement_hook.is_some()
+    }
+
+    fn request(&mut self, pending: Pending, values: &[DValue]) {
+        encode(&mut self.to_duktape, &DValue::Req);
+        for value in values {
+            encode(&mut self.to_duktape, value);
+        }
+        encode(&mut self.to_duktape, &DValue::Eom);
+        self.pending.push_back(pending);
+    }
+}
+
+impl Trace {
+    /// Decides whether the current line should be traced, given the rate limit.
+    fn admit(&mut self) -> bool {
+        let limit = match self.options.max_lines_per_second {
+            Some(limit) => limit,
+            None => return true,
+        };
+
+        let now = time::Instant::now();
+        if now.duration_since(self.window_start) >= time::Duration::from_secs(1) {
+            self.window_start = now;
+            self.window_lines = 0;
+        }
+        if self.window_lines < limit {
+            self.window_lines += 1;
+            true
+        } else {
+            self.skipped += 1;
+            false
+        }
+    }
+}
+
+impl io::Read for InspectorTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state.borrow_mut();
+        if state.to_duktape.is_empty() && state.awaiting_execution {
+            // Execution has started while paused, so ask for a status to find out where
+            state.awaiting_execution = false;
+            state.request(Pending::TriggerStatus, &[DValue::Int(CMD_TRIGGERSTATUS)]);
+        }
+        let n = cmp::min(buf.len(), state.to_duktape.len());
+        for (i, b) in state.to_duktape.drain(..n).enumerate() {
+            buf[i] = b;
+        }
+        // Reading nothing makes Duktape detach, which is the right thing to do if we ever end up
+        // without anything to say while Duktape is waiting for us.
+        Ok(n)
+    }
+}
+
+impl io::Write for InspectorTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.state.borrow_mut().from_duktape.extend_from_slice(buf);
+        Inspector { state: self.state.clone() }.process();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl DebugTransport for InspectorTransport {
+    fn available(&mut self) -> io::Result<usize> {
+        Ok(self.state.borrow().to_duktape.len())
+    }
+}
+
+fn encode(out: &mut collections::VecDeque<u8>, value: &DValue) {
+    match *value {
+        DValue::Eom => out.push_back(0x00),
+        DValue::Req => out.push_back(0x01),
+        DValue::Int(i) if (0..0x40).contains(&i) => out.push_back(0x80 + i as u8),
+        DValue::Int(i) if (0..0x4000).contains(&i) => {
+            out.push_back(0xc0 + (i >> 8) as u8);
+            out.push_back(i as u8);
+        }
+        DValue::Int(i) => {
+            out.push_back(0x10);
+            out.extend(&be_bytes(i as u32));
+        }
+        DValue::Str(ref s) if s.len() < 0x20 => {
+            out.push_back(0x60 + s.len() as u8);
+            out.extend(s);
+        }
+        DValue::Str(ref s) => {
+            out.push_back(0x11);
+            out.extend(&be_bytes(s.len() as u32));
+            out.extend(s);
+        }
+        _ => unreachable!("encoding of {:?} is not supported", value),
+    }
+}
+
+fn be_bytes(i: u32) -> [u8; 4] {
+    [(i >> 24) as u8, (i >> 16) as u8, (i >> 8) as u8, i as u8]
+}
+
+/// Parses a complete message (up to and including the EOM marker) from the start of the buffer,
+/// returning the message and the number of consumed bytes.
+fn parse_message(buf: &[u8]) -> Option<(Vec<DValue>, usize)> {
+    let mut message = Vec::new();
+    let mut pos = 0;
+    loop {
+        let (value, n) = parse_value(&buf[pos..])?;
+        pos += n;
+        let eom = value == DValue::Eom;
+        message.push(value);
+        if eom {
+            return Some((message, pos));
+        }
+    }
+}
+
+fn parse_value(buf: &[u8]) -> Option<(DValue, usize)> {
+    fn uint(buf: &[u8], n: usize) -> Option<u64> {
+        if buf.len() < n {
+            None
+        } else {
+            Some(buf[..n].iter().fold(0, |acc, &b| (acc << 8) | b as u64))
+        }
+    }
+
+    fn bytes(buf: &[u8], start: usize, len: usize) -> Option<(Vec<u8>, usize)> {
+        if buf.len() < start + len {
+            None
+        } else {
+            Some((buf[start..start + len].to_vec(), start + len))
+        }
+    }
+
+    let ib = *buf.first()?;
+    let rest = &buf[1..];
+    let result = match ib {
+        0x00 => (DValue::Eom, 1),
+        0x01 => (DValue::Req, 1),
+        0x02 => (DValue::Rep, 1),
+        0x03 => (DValue::Err, 1),
+        0x04 => (DValue::Nfy, 1),
+        0x10 => (DValue::Int(uint(rest, 4)? as u32 as i32), 5),
+        0x11 => {
+            let (s, n) = bytes(rest, 4, uint(rest, 4)? as usize)?;
+            (DValue::Str(s), n + 1)
+        }
+        0x12 => {
+            let (s, n) = bytes(rest, 2, uint(rest, 2)? as usize)?;
+            (DValue::Str(s), n + 1)
+        }
+        0x13 => {
+            let (s, n) = bytes(rest, 4, uint(rest, 4)? as usize)?;
+            (DValue::Buf(s), n + 1)
+        }
+        0x14 => {
+            let (s, n) = bytes(rest, 2, uint(rest, 2)? as usize)?;
+            (DValue::Buf(s), n + 1)
+        }
+        0x15 => (DValue::Unused, 1),
+        0x16 => (DValue::Undefined, 1),
+        0x17 => (DValue::Null, 1),
+        0x18 => (DValue::Bool(true), 1),
+        0x19 => (DValue::Bool(false), 1),
+        0x1a => (DValue::Number(f64::from_bits(uint(rest, 8)?)), 9),
+        0x1b => {
+            // Class number, then a pointer
+            let (_, n) = bytes(rest, 2, uint(&rest[cmp::min(1, rest.len())..], 1)? as usize)?;
+            (DValue::Object, n + 1)
+        }
+        0x1c | 0x1e => {
+            let (_, n) = bytes(rest, 1, uint(rest, 1)? as usize)?;
+            (if ib == 0x1c { DValue::Pointer } else { DValue::HeapPtr }, n + 1)
+        }
+        0x1d => {
+            // Flags, then a pointer
+            let (_, n) = bytes(rest, 3, uint(&rest[cmp::min(2, rest.len())..], 1)? as usize)?;
+            (DValue::LightFunc, n + 1)
+        }
+        0x60..=0x7f => {
+            let (s, n) = bytes(rest, 0, (ib - 0x60) as usize)?;
+            (DValue::Str(s), n + 1)
+        }
+        0x80..=0xbf => (DValue::Int((ib - 0x80) as i32), 1),
+        0xc0..=0xff => (DValue::Int((((ib - 0xc0) as i32) << 8) | *rest.first()? as i32), 2),
+        _ => return None,
+    };
+    Some(result)
+}
+
+fn dvalue_to_string(value: &DValue) -> String {
+    match *value {
+        DValue::Str(ref s) => String::from_utf8_lossy(s).into_owned(),
+        _ => String::new(),
+    }
+}
+
+fn dvalue_to_int(value: &DValue) -> i64 {
+    match *value {
+        DValue::Int(i) => i as i64,
+        DValue::Number(n) => n as i64,
+        _ => 0,
+    }
+}
+
+fn dvalue_to_value(value: &DValue) -> Value {
+    match *value {
+        DValue::Int(i) => Value::Number(i as f64),
+        DValue::Number(n) => Value::Number(n),
+        DValue::Str(ref s) => Value::String(String::from_utf8_lossy(s).into_owned()),
+        DValue::Buf(ref b) => Value::Bytes(b.clone()),
+        DValue::Null => Value::Null,
+        DValue::Bool(b) => Value::Boolean(b),
+        DValue::Object => Value::Foreign("object"),
+        DValue::Pointer => Value::Foreign("pointer"),
+        DValue::LightFunc => Value::Foreign("lightfunc"),
+        DValue::HeapPtr => Value::Foreign("heapptr"),
+        _ => Value::Undefined,
+    }
+}
@@ -0,0 +1,213 @@
+//! `TextEncoder` and `TextDecoder` globals for scripts, converting between strings and bytes.
+//!
+//! The classes follow the WHATWG Encoding Standard for UTF-8: `encode` returns a `Uint8Array`,
+//! and `decode` accepts plain buffers (like a `Value::Bytes` argument), `ArrayBuffer`s, typed
+//! arrays and `DataView`s, and supports the `fatal` and `ignoreBOM` options.  Besides UTF-8, both
+//! classes support `latin1` (also known as `iso-8859-1`), where every byte is one character; as
+//! an extension, `TextEncoder` accepts the encoding as an optional constructor argument, and
+//! encodes characters that latin1 can't represent as `?`.
+
+use std::os;
+use std::ptr;
+use std::slice;
+
+use duktape_sys;
+
+use nul_str;
+use strings;
+
+/// Defines the classes on top of the native encoding and decoding functions.
+const SETUP: &[u8] = b"(function (encode, decode) {
+  var labels = {
+    'utf-8': 'utf-8', 'utf8': 'utf-8', 'unicode-1-1-utf-8': 'utf-8',
+    'latin1': 'latin1', 'iso-8859-1': 'latin1', 'iso8859-1': 'latin1', 'l1': 'latin1',
+    'ascii': 'latin1', 'us-ascii': 'latin1'
+  };
+  function encodingOf(label) {
+    var encoding = labels[label === undefined ? 'utf-8' : String(label).trim().toLowerCase()];
+    if (!encoding) {
+      throw new RangeError('unsupported encoding: ' + label);
+    }
+    return encoding;
+  }
+  function define(obj, name, value) {
+    Object.defineProperty(obj, name, { value: value, enumerable: true });
+  }
+
+  function TextEncoder(label) {
+    if (!(this instanceof TextEncoder)) {
+      throw new TypeError('TextEncoder must be called with new');
+    }
+    define(this, 'encoding', encodingOf(label));
+  }
+  TextEncoder.prototype.encode = function (input) {
+    return encode(this.encoding === 'latin1', input === undefined ? '' : String(input));
+  };
+
+  function TextDecoder(label, options) {
+    if (!(this instanceof TextDecoder)) {
+      throw new TypeError('TextDecoder must be called with new');
+    }
+    define(this, 'encoding', encodingOf(label));
+    define(this, 'fatal', !!(options && options.fatal));
+    define(this, 'ignoreBOM', !!(options && options.ignoreBOM));
+  }
+  TextDecoder.prototype.decode = function (input) {
+    if (input === undefined) {
+      return '';
+    }
+    var result = decode(this.encoding === 'latin1', this.fatal, this.ignoreBOM, input);
+    if (result === undefined) {
+      throw new TypeError('decode argument must be a buffer, an ArrayBuffer or a view');
+    }
+    if (result === null) {
+      throw new TypeError('the encoded data was not valid ' + this.encoding);
+    }
+    return result;
+  };
+
+  TextEncoder.prototype.constructor = TextEncoder;
+  TextDecoder.prototype.constructor = TextDecoder;
+  return [TextEncoder, TextDecoder];
+})";
+
+/// Defines the global `TextEncoder` and `TextDecoder` classes.
+pub unsafe fn setup(ctx: *mut duktape_sys::duk_context) {
+    use duktape_sys::*;
+
+    let ret = duk_peval_lstring(ctx, SETUP.as_ptr() as *const os::raw::c_char, SETUP.len());
+    assert_eq!(0, ret, "failed to compile the encoding classes");
+    duk_push_c_function(ctx, Some(encode), 2);
+    duk_push_c_function(ctx, Some(decode), 4);
+    let ret = duk_pcall(ctx, 2);
+    assert_eq!(0, ret, "failed to set up the encoding classes");
+
+    duk_get_prop_index(ctx, -1, 0);
+    duk_put_global_string(ctx, nul_str(b"TextEncoder\0"));
+    duk_get_prop_index(ctx, -1, 1);
+    duk_put_global_string(ctx, nul_str(b"TextDecoder\0"));
+    duk_pop(ctx);
+}
+
+/// `encode(latin1, string)`, returns a `Uint8Array`.
+unsafe extern "C" fn encode(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    use duktape_sys::*;
+
+    let latin1 = duk_to_boolean(ctx, 0) != 0;
+    let mut len = 0;
+    let data = duk_to_lstring(ctx, 1, &mut len);
+    let chars = strings::decode(slice::from_raw_parts(data as *const u8, len));
+    let bytes = if latin1 {
+        chars.map(|c| if (c as u32) < 0x100 { c as u8 } else { b'?' }).collect::<Vec<_>>()
+    } else {
+        chars.collect::<String>().into_bytes()
+    };
+
+    let buf = duk_push_fixed_buffer(ctx, bytes.len());
+    ptr::copy_nonoverlapping(bytes.as_ptr(), buf as *mut u8, bytes.len());
+    duk_push_buffer_object(ctx, -1, 0, bytes.len(), DUK_BUFOBJ_UINT8ARRAY);
+    1
+}
+
+/// `decode(latin1, fatal, ignoreBOM, input)`, returns the string, `null` if the input is invalid
+/// and `fatal` is set, or `undefined` if the input is not buffer data.
+unsafe extern "C" fn decode(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    use duktape_sys::*;
+
+    let latin1 = duk_to_boolean(ctx, 0) != 0;
+    let fatal = duk_to_boolean(ctx, 1) != 0;
+    let ignore_bom = duk_to_boolean(ctx, 2) != 0;
+    let mut len = 0;
+    let data = duk_get_buffer_data(ctx, 3, &mut len);
+    if data.is_null() && duk_is_buffer(ctx, 3) == 0 {
+        return 0;
+    }
+    let bytes = if data.is_null() {
+        &[][..]
+    } else {
+        slice::from_raw_parts(data as *const u8, len)
+    };
+
+    let string = if latin1 {
+        bytes.iter().map(|&b| b as char).collect::<String>()
+    } else {
+        let bytes = if !ignore_bom && bytes.starts_with(b"\xef\xbb\xbf") {
+            &bytes[3..]
+        } else {
+            bytes
+        };
+        match (String::from_utf8_lossy(bytes), fatal) {
+            (::std::borrow::Cow::Owned(_), true) => {
+                duk_push_null(ctx);
+                return 1;
+            }
+            (string, _) => string.into_owned(),
+        }
+    };
+
+    strings::push(ctx, &string);
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use {Context, Value};
+
+    #[test]
+    fn text_encoder() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let encoded = ctx.eval_string(r"
+          var bytes = new TextEncoder().encode('hé😀');
+          [bytes instanceof Uint8Array, bytes.length, Array.prototype.slice.call(bytes)]
+        ")
+            .unwrap()
+            .to_value();
+        let expected = [0x68, 0xc3, 0xa9, 0xf0, 0x9f, 0x98, 0x80];
+        assert_eq!(Value::Array(vec![Value::Boolean(true),
+                                     Value::Number(7.0),
+                                     Value::Array(expected.iter()
+                                         .map(|&b| Value::Number(b as f64))
+                                         .collect())]),
+                   encoded);
+
+        let latin1 = ctx.eval_string("Array.prototype.slice.call(new TextEncoder('latin1').encode('\
+                                       \u{e9}\u{263a}'))")
+            .unwrap()
+            .to_value();
+        assert_eq!(Value::Array(vec![Value::Number(233.0), Value::Number(63.0)]), latin1);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn text_decoder() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let decode = ctx.eval_string("(function (b, l, o) { return new TextDecoder(l, o).decode(b); \
+                                       })")
+            .unwrap();
+        let decoded = decode.call(&[&Value::Bytes(b"\xef\xbb\xbfh\xc3\xa9".to_vec())]).unwrap();
+        assert_eq!(Value::String("h\u{e9}".to_owned()), decoded.to_value());
+        // Characters outside of the BMP are decoded into surrogate pairs, like in literals
+        assert_eq!(Value::Boolean(true),
+                   ctx.eval_string("new TextDecoder().decode(Duktape.dec('hex', 'f09f9880')) === \
+                                    '\\ud83d\\ude00'")
+                       .unwrap()
+                       .to_value());
+
+        let latin1 = decode.call(&[&Value::Bytes(vec![0x63, 0x61, 0x66, 0xe9]),
+                                   &Value::String("ISO-8859-1".to_owned())])
+            .unwrap();
+        assert_eq!(Value::String("caf\u{e9}".to_owned()), latin1.to_value());
+
+        let lossy = decode.call(&[&Value::Bytes(vec![0x61, 0xff])]).unwrap();
+        assert_eq!(Value::String("a\u{fffd}".to_owned()), lossy.to_value());
+        assert!(ctx.eval_string("new TextDecoder('utf-8', {fatal: true}).decode(Duktape.dec('hex', \
+                                 'ff'))")
+            .is_err());
+        assert!(ctx.eval_string("new TextDecoder('ebcdic')").is_err());
+        ctx.assert_clean();
+    }
+}
@@ -0,0 +1,287 @@
+//! An event loop that the host pumps, and the timer globals that are built on it, see
+//! `ContextBuilder::with_timers` and `Context::pump_event_loop`.
+//!
+//! Scripts get `setTimeout`, `setInterval`, `clearTimeout` and `clearInterval`.  Timer callbacks
+//! never run on their own: they run when the host pumps the loop and the timer is due, so hosts
+//! stay in control of when script code executes.  The callbacks and their arguments are kept in
+//! the heap stash, while the schedule lives in Rust.
+
+use std::cell;
+use std::collections;
+use std::os;
+use std::time;
+
+use duktape_sys;
+
+use hooks;
+use nul_str;
+use HeapData;
+
+/// The key of the heap stash object that holds the callback and arguments of every timer.
+const STASH_KEY: &[u8] = b"timers\0";
+
+/// Defines the timer globals on top of the native scheduling functions.
+const SETUP: &[u8] = b"(function (schedule, cancel) {
+  function timer(repeat) {
+    return function (callback, delay) {
+      if (typeof callback !== 'function') {
+        throw new TypeError('timer callback must be a function');
+      }
+      var args = Array.prototype.slice.call(arguments, 2);
+      var id = schedule(callback, Number(delay) || 0, repeat, args);
+      if (id < 0) {
+        throw new RangeError('too many outstanding timers');
+      }
+      return id;
+    };
+  }
+  function clear(id) {
+    cancel(Number(id) || 0);
+  }
+  return [timer(false), timer(true), clear];
+})";
+
+/// A scheduled timer.
+struct Timer {
+    due: time::Instant,
+    interval: Option<time::Duration>,
+}
+
+/// The schedule of the timers of a context.
+pub(crate) struct EventLoop {
+    max_timers: usize,
+    next_id: u32,
+    timers: collections::BTreeMap<u32, Timer>,
+}
+
+/// A timer that is due, as taken from the schedule.
+pub(crate) struct DueTimer {
+    pub(crate) id: u32,
+    pub(crate) repeat: bool,
+}
+
+impl EventLoop {
+    pub(crate) fn new(max_timers: usize) -> EventLoop {
+        EventLoop {
+            max_timers,
+            next_id: 1,
+            timers: collections::BTreeMap::new(),
+        }
+    }
+
+    /// The number of timers that are scheduled.
+    pub(crate) fn len(&self) -> usize {
+        self.timers.len()
+    }
+
+    /// The time until the next timer is due, which is zero if a timer is overdue.
+    pub(crate) fn next_due(&self, now: time::Instant) -> Option<time::Duration> {
+        self.timers.values().map(|t| t.due.saturating_duration_since(now)).min()
+    }
+
+    /// Returns the ids of the timers that are due at the specified time, in the order in which
+    /// they should run.
+    pub(crate) fn due(&self, now: time::Instant) -> Vec<u32> {
+        let mut due = self.timers
+            .iter()
+            .filter(|&(_, t)| t.due <= now)
+            .map(|(&id, t)| (t.due, id))
+            .collect::<Vec<_>>();
+        due.sort();
+        due.into_iter().map(|(_, id)| id).collect()
+    }
+
+    /// Takes a due timer off the schedule, or reschedules it if it is an interval.  Returns
+    /// `None` if the timer was cleared in the meantime.
+    pub(crate) fn fire(&mut self, id: u32, now: time::Instant) -> Option<DueTimer> {
+        let interval = self.timers.get(&id)?.interval;
+        match interval {
+            Some(interval) => self.timers.get_mut(&id).unwrap().due = now + interval,
+            None => {
+                self.timers.remove(&id);
+            }
+        }
+        Some(DueTimer {
+            id,
+            repeat: interval.is_some(),
+        })
+    }
+
+    fn schedule(&mut self, delay: time::Duration, repeat: bool) -> Option<u32> {
+        if self.timers.len() >= self.max_timers {
+            return None;
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+        self.timers.insert(id,
+                           Timer {
+                               due: time::Instant::now() + delay,
+                               interval: if repeat { Some(delay) } else { None },
+                           });
+        Some(id)
+    }
+
+    fn cancel(&mut self, id: u32) -> bool {
+        self.timers.remove(&id).is_some()
+    }
+}
+
+/// Defines the timer globals.
+pub unsafe fn setup(ctx: *mut duktape_sys::duk_context) {
+    use duktape_sys::*;
+
+    duk_push_heap_stash(ctx);
+    duk_push_object(ctx);
+    duk_put_prop_string(ctx, -2, nul_str(STASH_KEY));
+    duk_pop(ctx);
+
+    let ret = duk_peval_lstring(ctx, SETUP.as_ptr() as *const os::raw::c_char, SETUP.len());
+    assert_eq!(0, ret, "failed to compile the timer functions");
+    duk_push_c_function(ctx, Some(schedule), 4);
+    duk_push_c_function(ctx, Some(cancel), 1);
+    let ret = duk_pcall(ctx, 2);
+    assert_eq!(0, ret, "failed to set up the timer functions");
+
+    let globals: [(&[u8], duk_uarridx_t); 4] = [(b"setTimeout\0", 0),
+                                                (b"setInterval\0", 1),
+                                                (b"clearTimeout\0", 2),
+                                                (b"clearInterval\0", 2)];
+    for &(name, index) in &globals {
+        duk_get_prop_index(ctx, -1, index);
+        duk_put_global_string(ctx, nul_str(name));
+    }
+    duk_pop(ctx);
+}
+
+/// Pushes the callback and arguments of a timer as `[callback, args]`, or `undefined` if the
+/// timer doesn't exist.
+pub(crate) unsafe fn push_timer(ctx: *mut duktape_sys::duk_context, id: u32) {
+    duktape_sys::duk_push_heap_stash(ctx);
+    duktape_sys::duk_get_prop_string(ctx, -1, nul_str(STASH_KEY));
+    duktape_sys::duk_get_prop_index(ctx, -1, id);
+    duktape_sys::duk_remove(ctx, -2);
+    duktape_sys::duk_remove(ctx, -2);
+}
+
+/// Removes the callback and arguments of a timer from the heap stash.
+pub(crate) unsafe fn forget_timer(ctx: *mut duktape_sys::duk_context, id: u32) {
+    duktape_sys::duk_push_heap_stash(ctx);
+    duktape_sys::duk_get_prop_string(ctx, -1, nul_str(STASH_KEY));
+    duktape_sys::duk_del_prop_index(ctx, -1, id);
+    duktape_sys::duk_pop_2(ctx);
+}
+
+unsafe fn event_loop(ctx: *mut duktape_sys::duk_context) -> &'static cell::RefCell<EventLoop> {
+    let mut funcs = duktape_sys::duk_memory_functions::default();
+    duktape_sys::duk_get_memory_functions(ctx, &mut funcs);
+    (*(funcs.udata as *mut HeapData)).event_loop.as_ref().unwrap()
+}
+
+/// `schedule(callback, delay, repeat, args)`, returns the id of the timer, or -1 if there are too
+/// many timers.
+unsafe extern "C" fn schedule(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    use duktape_sys::*;
+
+    let delay = duk_get_number(ctx, 1);
+    let delay = if delay.is_finite() && delay > 0.0 {
+        time::Duration::from_millis(delay as u64)
+    } else {
+        time::Duration::from_millis(0)
+    };
+    let repeat = duk_to_boolean(ctx, 2) != 0;
+    let id = match event_loop(ctx).borrow_mut().schedule(delay, repeat) {
+        Some(id) => id,
+        None => {
+            hooks::notify(ctx, |hooks| hooks.limit_exceeded(hooks::Limit::Timers));
+            duk_push_int(ctx, -1);
+            return 1;
+        }
+    };
+
+    duk_push_heap_stash(ctx);
+    duk_get_prop_string(ctx, -1, nul_str(STASH_KEY));
+    duk_push_array(ctx);
+    duk_dup(ctx, 0);
+    duk_put_prop_index(ctx, -2, 0);
+    duk_dup(ctx, 3);
+    duk_put_prop_index(ctx, -2, 1);
+    duk_put_prop_index(ctx, -2, id);
+    duk_pop_2(ctx);
+
+    duk_push_uint(ctx, id);
+    1
+}
+
+/// `cancel(id)`, clears the timer if it exists.
+unsafe extern "C" fn cancel(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    let id = duktape_sys::duk_get_number(ctx, 0);
+    if id >= 1.0 && id <= u32::MAX as f64 && id.fract() == 0.0 &&
+       event_loop(ctx).borrow_mut().cancel(id as u32) {
+        forget_timer(ctx, id as u32);
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::thread;
+    use std::time;
+
+    use {Context, Value};
+
+    #[test]
+    fn timers() {
+        let _ = env_logger::init();
+        let ctx = Context::builder().with_timers(10).build();
+        ctx.eval_string(r"
+          var log = [];
+          setTimeout(function (a, b) { log.push('later ' + a + b); }, 30, 1, 2);
+          setTimeout(function () { log.push('soon'); }, 0);
+          var cleared = setTimeout(function () { log.push('never'); }, 0);
+          clearTimeout(cleared);
+          var ticks = 0;
+          var interval = setInterval(function () {
+            if (++ticks === 3) {
+              clearInterval(interval);
+            }
+          }, 0);
+        ")
+            .unwrap();
+        assert_eq!(3, ctx.pending_timers());
+
+        let next = ctx.pump_event_loop().unwrap();
+        assert!(next.unwrap() <= time::Duration::from_millis(30));
+        assert_eq!(Value::String("soon".to_owned()), ctx.eval_string("log.join()").unwrap().to_value());
+
+        thread::sleep(time::Duration::from_millis(40));
+        ctx.pump_event_loop().unwrap();
+        ctx.run_event_loop().unwrap();
+        assert_eq!(0, ctx.pending_timers());
+        assert_eq!(None, ctx.pump_event_loop().unwrap());
+        assert_eq!(Value::String("soon,later 12 3".to_owned()),
+                   ctx.eval_string("log.join() + ' ' + ticks").unwrap().to_value());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn timer_limits_and_errors() {
+        let _ = env_logger::init();
+        let ctx = Context::builder().with_timers(2).build();
+        ctx.eval_string("setTimeout(function () { throw new Error('boom'); }, 0);").unwrap();
+        ctx.eval_string("setTimeout(function () {}, 0);").unwrap();
+        assert!(ctx.eval_string("setTimeout(function () {}, 0);").is_err());
+        assert!(ctx.eval_string("setTimeout('code', 0);").is_err());
+
+        assert!(ctx.pump_event_loop().is_err());
+        assert_eq!(1, ctx.pending_timers());
+        ctx.pump_event_loop().unwrap();
+        assert_eq!(0, ctx.pending_timers());
+
+        let plain = Context::new();
+        assert!(plain.eval_string("setTimeout").is_err());
+        assert_eq!(None, plain.pump_event_loop().unwrap());
+        ctx.assert_clean();
+    }
+}
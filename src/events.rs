@@ -0,0 +1,212 @@
+//! Named events between the host and scripts, see `Context::emit` and `Context::events`.
+//!
+//! Scripts subscribe with `host.events.on(name, listener)` (or `once`, and unsubscribe with
+//! `off(name, listener)`), and the host calls the listeners with `Context::emit`.  In the other
+//! direction, `host.events.emit(name, value)` calls the listeners of the script, and delivers an
+//! `Event` with a copy of the value to every receiver that the host got from `Context::events`.
+//! Receivers can be moved to other threads, and receivers that are dropped are forgotten.
+//!
+//! # Examples
+//!
+//! ```
+//! let ctx = duk::Context::new();
+//! let events = ctx.events();
+//! ctx.eval_string("host.events.on('tick', function (n) { host.events.emit('tock', n + 1); })")
+//!     .unwrap();
+//! assert_eq!(1, ctx.emit("tick", &duk::Value::Number(1.0)).unwrap());
+//! let event = events.try_recv().unwrap();
+//! assert_eq!("tock", event.name);
+//! assert_eq!(duk::Value::Number(2.0), event.value);
+//! ```
+
+use std::cell;
+use std::os;
+use std::sync::mpsc;
+
+use duktape_sys;
+
+use nul_str;
+use push_host_object;
+use strings;
+use HeapData;
+use Value;
+
+/// The key of the heap stash entry with the function that calls the listeners of an event.
+const STASH_KEY: &[u8] = b"events\0";
+
+/// Builds `host.events` on top of the native delivery to the host, and returns `[events,
+/// dispatch]`.
+const SETUP: &[u8] = br#"(function (deliver) {
+  var listeners = {};
+
+  function check(name, listener) {
+    if (typeof listener !== 'function') {
+      throw new TypeError('event listener must be a function');
+    }
+    return String(name);
+  }
+  function dispatch(name, value) {
+    var list = listeners.hasOwnProperty(name) ? listeners[name].slice() : [];
+    for (var i = 0; i < list.length; i++) {
+      if (list[i].once) {
+        events.off(name, list[i].listener);
+      }
+      list[i].listener.call(undefined, value);
+    }
+    return list.length;
+  }
+
+  var events = {
+    on: function (name, listener) {
+      name = check(name, listener);
+      (listeners.hasOwnProperty(name) ? listeners[name] : (listeners[name] = []))
+        .push({listener: listener, once: false});
+      return events;
+    },
+    once: function (name, listener) {
+      events.on(name, listener);
+      listeners[String(name)][listeners[String(name)].length - 1].once = true;
+      return events;
+    },
+    off: function (name, listener) {
+      name = String(name);
+      var list = listeners.hasOwnProperty(name) ? listeners[name] : [];
+      for (var i = 0; i < list.length; i++) {
+        if (list[i].listener === listener) {
+          list.splice(i, 1);
+          break;
+        }
+      }
+      if (list.length === 0) {
+        delete listeners[name];
+      }
+      return events;
+    },
+    emit: function (name, value) {
+      name = String(name);
+      var called = dispatch(name, value);
+      return deliver(name, value) + called > 0;
+    },
+    listenerCount: function (name) {
+      name = String(name);
+      return listeners.hasOwnProperty(name) ? listeners[name].length : 0;
+    }
+  };
+  return [events, dispatch];
+})"#;
+
+/// An event that a script emitted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    /// The name of the event.
+    pub name: String,
+    /// A copy of the value that was emitted with the event.
+    pub value: Value,
+}
+
+/// Defines `host.events`.
+pub(crate) unsafe fn setup(ctx: *mut duktape_sys::duk_context) {
+    use duktape_sys::*;
+
+    let ret = duk_peval_lstring(ctx, SETUP.as_ptr() as *const os::raw::c_char, SETUP.len());
+    assert_eq!(0, ret, "failed to compile host.events");
+    duk_push_c_function(ctx, Some(deliver), 2);
+    let ret = duk_pcall(ctx, 1);
+    assert_eq!(0, ret, "failed to set up host.events");
+
+    duk_push_heap_stash(ctx);
+    duk_get_prop_index(ctx, -2, 1);
+    duk_put_prop_string(ctx, -2, nul_str(STASH_KEY));
+    duk_pop(ctx);
+
+    push_host_object(ctx);
+    duk_get_prop_index(ctx, -2, 0);
+    duk_put_prop_string(ctx, -2, nul_str(b"events\0"));
+    duk_pop_2(ctx);
+}
+
+/// Pushes the function that calls the listeners of an event with `(name, value)`.
+pub(crate) unsafe fn push_dispatch(ctx: *mut duktape_sys::duk_context) {
+    duktape_sys::duk_push_heap_stash(ctx);
+    duktape_sys::duk_get_prop_string(ctx, -1, nul_str(STASH_KEY));
+    duktape_sys::duk_remove(ctx, -2);
+}
+
+unsafe fn receivers(ctx: *mut duktape_sys::duk_context) -> &'static cell::RefCell<Vec<mpsc::Sender<Event>>> {
+    let mut funcs = duktape_sys::duk_memory_functions::default();
+    duktape_sys::duk_get_memory_functions(ctx, &mut funcs);
+    &(*(funcs.udata as *mut HeapData)).event_senders
+}
+
+/// `deliver(name, value)`, returns the number of host receivers that got the event.
+unsafe extern "C" fn deliver(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    let mut senders = receivers(ctx).borrow_mut();
+    if !senders.is_empty() {
+        let event = Event {
+            name: strings::get(ctx, 0),
+            value: Value::get(ctx, 1),
+        };
+        senders.retain(|sender| sender.send(event.clone()).is_ok());
+    }
+    duktape_sys::duk_push_uint(ctx, senders.len() as duktape_sys::duk_uint_t);
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::thread;
+
+    use {Context, Value};
+
+    #[test]
+    fn host_events() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.eval_string(r"
+          var seen = [];
+          function record(v) { seen.push(v); }
+          host.events.on('config', record).once('config', function (v) { seen.push('once'); });
+          host.events.on('fail', function () { throw new Error('listener failed'); });
+        ")
+            .unwrap();
+        assert_eq!(2, ctx.emit("config", &Value::String("a".to_owned())).unwrap());
+        assert_eq!(1, ctx.emit("config", &Value::String("b".to_owned())).unwrap());
+        assert_eq!(0, ctx.emit("other", &Value::Null).unwrap());
+        assert!(ctx.emit("fail", &Value::Null).is_err());
+        assert_eq!(Value::String("a,once,b,1".to_owned()),
+                   ctx.eval_string("host.events.off('config', record); \
+                                    seen.concat(host.events.listenerCount('config') + 1).join()")
+                       .unwrap()
+                       .to_value());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    #[cfg_attr(target_os = "wasi", ignore = "needs threads")]
+    fn script_events() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        assert_eq!(Value::Boolean(false),
+                   ctx.eval_string("host.events.emit('lost', 1)").unwrap().to_value());
+
+        let events = ctx.events();
+        let dropped = ctx.events();
+        drop(dropped);
+        ctx.eval_string("host.events.emit('progress', {done: 1, of: 2}); host.events.emit('end')")
+            .unwrap();
+        let received = thread::spawn(move || events.iter().take(2).collect::<Vec<_>>())
+            .join()
+            .unwrap();
+        assert_eq!("progress", received[0].name);
+        assert_eq!(Value::Object(vec![("done".to_owned(), Value::Number(1.0)),
+                                      ("of".to_owned(), Value::Number(2.0))]
+                       .into_iter()
+                       .collect()),
+                   received[0].value);
+        assert_eq!("end", received[1].name);
+        assert_eq!(Value::Undefined, received[1].value);
+        ctx.assert_clean();
+    }
+}
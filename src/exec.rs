@@ -0,0 +1,447 @@
+//! A `host:exec` module for trusted automation plugins that need to run programs, see
+//! `ContextBuilder::with_exec`.
+//!
+//! Contexts can't run anything unless the host builds them with a `Policy`, and then only the
+//! commands that the policy allowlists.  Scripts refer to a command by its name, and the policy
+//! maps the name to the program that actually runs.  Arguments are always passed as an array and
+//! never through a shell, the environment only has the variables that the policy sets, and a
+//! command that runs longer than the timeout of the policy is killed.  On Unix, a command runs in a
+//! process group of its own, and the processes that it starts are killed with it, or when it exits
+//! if it leaves them behind.
+//!
+//! The module has one synchronous function:
+//!
+//! * `run(command, args, options)` runs the command with the array of arguments, and returns
+//!   `{status, stdout, stderr}`, where `status` is the exit code (or `null` if the command was
+//!   killed by a signal) and the output is in `Uint8Array`s.  `options.input` is written to the
+//!   standard input of the command, as UTF-8 if it is a string.
+//!
+//! Failures throw an `Error` with a `code`: `EACCES` if the command isn't allowlisted, `ETIMEDOUT`
+//! if it was killed after the timeout, `EFBIG` if it wrote more output than the policy allows, and
+//! `ENOENT` or `EIO` if it couldn't be started.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! let policy = duk::exec::Policy::new()
+//!     .with_command("git", "/usr/bin/git")
+//!     .with_working_dir("/srv/checkout")
+//!     .with_timeout(Duration::from_secs(30));
+//! let ctx = duk::Context::builder().with_exec(policy).build();
+//! ctx.eval_string("require('host:exec').run('git', ['fetch', '--prune']).status").unwrap();
+//! ```
+
+use std::collections;
+use std::io;
+use std::io::{Read, Write};
+use std::os;
+use std::path;
+use std::process;
+use std::ptr;
+use std::slice;
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+
+use duktape_sys;
+#[cfg(unix)]
+use libc;
+
+use host_modules;
+use nul_str;
+use strings;
+use HeapData;
+
+/// How often a running command is checked for having exited.
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(5);
+
+/// Builds the exports of the module on top of the native function.
+const SETUP: &[u8] = b"(function (run) {
+  function unwrap(result) {
+    if (result[0] !== null) {
+      var error = new Error(result[1]);
+      error.code = result[0];
+      throw error;
+    }
+    return result[1];
+  }
+  return {
+    run: function (command, args, options) {
+      args = args === undefined ? [] : args;
+      if (!Array.isArray(args)) {
+        throw new TypeError('the arguments must be an array');
+      }
+      args = args.map(String);
+      var input = options && options.input;
+      if (input !== undefined && input !== null &&
+          !(typeof input === 'buffer' || input instanceof ArrayBuffer || ArrayBuffer.isView(input))) {
+        input = String(input);
+      }
+      return unwrap(run(String(command), args, input));
+    }
+  };
+})";
+
+/// The commands that a context may run through `host:exec`, and how they run.
+#[derive(Clone, Debug)]
+pub struct Policy {
+    commands: collections::BTreeMap<String, path::PathBuf>,
+    timeout: time::Duration,
+    max_output: usize,
+    working_dir: Option<path::PathBuf>,
+    env: Vec<(String, String)>,
+}
+
+/// What a command that ran to completion produced.
+struct Output {
+    status: Option<i32>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+/// A command that failed to run, as reported to scripts.
+struct ExecError {
+    code: &'static str,
+    message: String,
+}
+
+impl Policy {
+    /// Creates a policy that allows no commands, with a timeout of 10 seconds and at most 1 MiB of
+    /// output on each of stdout and stderr.
+    pub fn new() -> Policy {
+        Policy {
+            commands: collections::BTreeMap::new(),
+            timeout: time::Duration::from_secs(10),
+            max_output: 1024 * 1024,
+            working_dir: None,
+            env: Vec::new(),
+        }
+    }
+
+    /// Allows scripts to run the specified program under the specified command name.
+    pub fn with_command<P>(mut self, name: &str, program: P) -> Self
+        where P: Into<path::PathBuf>
+    {
+        self.commands.insert(name.to_owned(), program.into());
+        self
+    }
+
+    /// Sets how long a command may run before it is killed, together with the processes that it
+    /// started.  Reading its output counts towards the timeout too.
+    pub fn with_timeout(mut self, timeout: time::Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how many bytes a command may write to each of stdout and stderr.
+    pub fn with_max_output(mut self, bytes: usize) -> Self {
+        self.max_output = bytes;
+        self
+    }
+
+    /// Sets the directory that commands run in, instead of the one of the host process.
+    pub fn with_working_dir<P>(mut self, dir: P) -> Self
+        where P: Into<path::PathBuf>
+    {
+        self.working_dir = Some(dir.into());
+        self
+    }
+
+    /// Sets an environment variable for the commands, which otherwise get an empty environment.
+    pub fn with_env(mut self, name: &str, value: &str) -> Self {
+        self.env.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Runs an allowlisted command to completion, or until the timeout.
+    fn run(&self, name: &str, args: &[String], input: Option<Vec<u8>>) -> Result<Output, ExecError> {
+        let program = self.commands.get(name).ok_or_else(|| {
+            ExecError {
+                code: "EACCES",
+                message: format!("{}: command not allowed", name),
+            }
+        })?;
+
+        let mut command = process::Command::new(program);
+        command.args(args)
+            .env_clear()
+            .envs(self.env.iter().map(|(k, v)| (k, v)))
+            .stdin(if input.is_some() {
+                process::Stdio::piped()
+            } else {
+                process::Stdio::null()
+            })
+            .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::piped());
+        if let Some(ref dir) = self.working_dir {
+            command.current_dir(dir);
+        }
+        // A process group of its own, so that the processes that it starts can be killed with it
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            command.process_group(0);
+        }
+        let mut child = command.spawn().map_err(|e| ExecError::io(name, &e))?;
+
+        // Feed and drain the pipes on their own threads, so that the command never blocks on them
+        if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+            thread::spawn(move || stdin.write_all(&input));
+        }
+        let stdout = drain(child.stdout.take(), self.max_output);
+        let stderr = drain(child.stderr.take(), self.max_output);
+
+        let deadline = time::Instant::now() + self.timeout;
+        let timed_out = || {
+            ExecError {
+                code: "ETIMEDOUT",
+                message: format!("{}: killed after {:?}", name, self.timeout),
+            }
+        };
+        let status = loop {
+            if let Some(status) = child.try_wait().map_err(|e| ExecError::io(name, &e))? {
+                break status;
+            }
+            if time::Instant::now() >= deadline {
+                kill(&mut child);
+                let _ = child.wait();
+                return Err(timed_out());
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+        // Processes that the command left behind may still hold on to its output
+        kill(&mut child);
+
+        // Processes that left the group may hold on to the output until the deadline at most
+        let collect = |output: &mpsc::Receiver<Option<Vec<u8>>>| {
+            output.recv_timeout(deadline.saturating_duration_since(time::Instant::now()))
+        };
+        match (collect(&stdout), collect(&stderr)) {
+            (Ok(Some(stdout)), Ok(Some(stderr))) => {
+                Ok(Output {
+                    status: status.code(),
+                    stdout,
+                    stderr,
+                })
+            }
+            (Err(_), _) | (_, Err(_)) => Err(timed_out()),
+            _ => {
+                Err(ExecError {
+                    code: "EFBIG",
+                    message: format!("{}: more than {} bytes of output", name, self.max_output),
+                })
+            }
+        }
+    }
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy::new()
+    }
+}
+
+impl ExecError {
+    fn io(name: &str, error: &io::Error) -> ExecError {
+        let code = match error.kind() {
+            io::ErrorKind::NotFound => "ENOENT",
+            io::ErrorKind::PermissionDenied => "EACCES",
+            _ => "EIO",
+        };
+        ExecError {
+            code,
+            message: format!("{}: {}", name, error),
+        }
+    }
+}
+
+/// Kills the process group of the command, with the command itself if it is still running and the
+/// processes that it started.  Only the command itself is killed on platforms without process
+/// groups.
+fn kill(child: &mut process::Child) {
+    #[cfg(unix)]
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = child.kill();
+    }
+}
+
+/// Reads a pipe to the end on another thread, which sends `None` if there was more output than
+/// the maximum, but keeps reading so that the writer isn't blocked.
+fn drain<R>(pipe: Option<R>, max: usize) -> mpsc::Receiver<Option<Vec<u8>>>
+    where R: Read + Send + 'static
+{
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        if let Some(pipe) = pipe {
+            let mut pipe = pipe.take(max as u64 + 1);
+            let _ = pipe.read_to_end(&mut output);
+            let _ = io::copy(pipe.get_mut(), &mut io::sink());
+        }
+        let _ = sender.send(if output.len() > max { None } else { Some(output) });
+    });
+    receiver
+}
+
+/// Registers the `host:exec` module.
+pub(crate) unsafe fn setup(ctx: *mut duktape_sys::duk_context) {
+    use duktape_sys::*;
+
+    let ret = duk_peval_lstring(ctx, SETUP.as_ptr() as *const os::raw::c_char, SETUP.len());
+    assert_eq!(0, ret, "failed to compile host:exec");
+    duk_push_c_function(ctx, Some(run), 3);
+    let ret = duk_pcall(ctx, 1);
+    assert_eq!(0, ret, "failed to set up host:exec");
+    host_modules::register(ctx, b"host:exec\0");
+}
+
+unsafe fn policy<'a>(ctx: *mut duktape_sys::duk_context) -> &'a Policy {
+    let mut funcs = duktape_sys::duk_memory_functions::default();
+    duktape_sys::duk_get_memory_functions(ctx, &mut funcs);
+    (*(funcs.udata as *mut HeapData)).exec.as_ref().unwrap()
+}
+
+unsafe fn push_bytes(ctx: *mut duktape_sys::duk_context, bytes: &[u8]) {
+    use duktape_sys::*;
+
+    let buf = duk_push_fixed_buffer(ctx, bytes.len());
+    ptr::copy_nonoverlapping(bytes.as_ptr(), buf as *mut u8, bytes.len());
+    duk_push_buffer_object(ctx, -1, 0, bytes.len(), DUK_BUFOBJ_UINT8ARRAY);
+    duk_remove(ctx, -2);
+}
+
+/// `run(command, args, input)`, pushes `[null, {status, stdout, stderr}]` or `[code, message]`.
+unsafe extern "C" fn run(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    use duktape_sys::*;
+
+    let name = strings::get(ctx, 0);
+    let mut args = Vec::new();
+    for i in 0..duk_get_length(ctx, 1) {
+        duk_get_prop_index(ctx, 1, i as duk_uarridx_t);
+        args.push(strings::get(ctx, -1));
+        duk_pop(ctx);
+    }
+    let mut len = 0;
+    let data = duk_get_buffer_data(ctx, 2, &mut len);
+    let input = if !data.is_null() {
+        Some(slice::from_raw_parts(data as *const u8, len).to_vec())
+    } else if duk_is_buffer(ctx, 2) != 0 {
+        Some(Vec::new())
+    } else if duk_is_string(ctx, 2) != 0 {
+        Some(strings::get(ctx, 2).into_bytes())
+    } else {
+        None
+    };
+
+    duk_push_array(ctx);
+    match policy(ctx).run(&name, &args, input) {
+        Ok(output) => {
+            duk_push_null(ctx);
+            duk_put_prop_index(ctx, -2, 0);
+            duk_push_object(ctx);
+            match output.status {
+                Some(status) => duk_push_int(ctx, status),
+                None => duk_push_null(ctx),
+            }
+            duk_put_prop_string(ctx, -2, nul_str(b"status\0"));
+            push_bytes(ctx, &output.stdout);
+            duk_put_prop_string(ctx, -2, nul_str(b"stdout\0"));
+            push_bytes(ctx, &output.stderr);
+            duk_put_prop_string(ctx, -2, nul_str(b"stderr\0"));
+        }
+        Err(e) => {
+            strings::push(ctx, e.code);
+            duk_put_prop_index(ctx, -2, 0);
+            strings::push(ctx, &e.message);
+        }
+    }
+    duk_put_prop_index(ctx, -2, 1);
+    1
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    extern crate env_logger;
+
+    use std::time;
+
+    use super::*;
+    use {Context, Value};
+
+    #[test]
+    fn host_exec() {
+        let _ = env_logger::init();
+        let policy = Policy::new()
+            .with_command("sh", "/bin/sh")
+            .with_command("cat", "/bin/cat")
+            .with_env("GREETING", "hello");
+        let ctx = Context::builder().with_exec(policy).build();
+        let result = ctx.eval_string(r"
+          var exec = require('host:exec');
+          function text(b) {
+            var s = '';
+            for (var i = 0; i < b.length; i++) { s += String.fromCharCode(b[i]); }
+            return s;
+          }
+          var r = exec.run('sh', ['-c', 'echo $GREETING $HOME $0; echo oops >&2; exit 3', 'a b']);
+          var c = exec.run('cat', [], {input: 'piped'});
+          [r.status, text(r.stdout), text(r.stderr), text(c.stdout), c.stdout instanceof Uint8Array]
+        ")
+            .unwrap();
+        assert_eq!(Value::Array(vec![Value::Number(3.0),
+                                     Value::String("hello a b\n".to_owned()),
+                                     Value::String("oops\n".to_owned()),
+                                     Value::String("piped".to_owned()),
+                                     Value::Boolean(true)]),
+                   result.to_value());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn host_exec_limits() {
+        let _ = env_logger::init();
+        let policy = Policy::new()
+            .with_command("sh", "/bin/sh")
+            .with_command("missing", "/nonexistent/program")
+            .with_timeout(time::Duration::from_millis(100))
+            .with_max_output(16);
+        let ctx = Context::builder().with_exec(policy).build();
+        let started = time::Instant::now();
+        let result = ctx.eval_string(r"
+          var exec = require('host:exec');
+          function code(f) {
+            try { f(); return 'ok'; } catch (e) { return e.code; }
+          }
+          [code(function () { exec.run('rm', ['-rf', '/']); }),
+           code(function () { exec.run('sh -c', ['true']); }),
+           code(function () { exec.run('missing'); }),
+           code(function () { exec.run('sh', ['-c', 'exec sleep 5']); }),
+           code(function () { exec.run('sh', ['-c', 'sleep 5; true']); }),
+           code(function () { exec.run('sh', ['-c', 'sleep 5 & exit 0']); }),
+           code(function () { exec.run('sh', ['-c', 'echo 0123456789abcdef']); }),
+           code(function () { exec.run('sh', '-c true'); })]
+        ")
+            .unwrap();
+        assert_eq!(Value::Array(vec![Value::String("EACCES".to_owned()),
+                                     Value::String("EACCES".to_owned()),
+                                     Value::String("ENOENT".to_owned()),
+                                     Value::String("ETIMEDOUT".to_owned()),
+                                     Value::String("ETIMEDOUT".to_owned()),
+                                     Value::String("ok".to_owned()),
+                                     Value::String("EFBIG".to_owned()),
+                                     Value::Undefined]),
+                   result.to_value());
+        // Neither the commands nor the processes that they started ran until the end
+        assert!(started.elapsed() < time::Duration::from_secs(4));
+
+        let ctx = Context::new();
+        assert!(ctx.eval_string("require('host:exec')").is_err());
+        ctx.assert_clean();
+    }
+}
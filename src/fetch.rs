@@ -0,0 +1,365 @@
+//! A blocking, `fetch`-like global for scripts that call external HTTP APIs, see
+//! `ContextBuilder::with_fetch`.
+//!
+//! The context doesn't talk to the network itself: requests are handed to an `HttpClient` that
+//! the host provides, and only after their URL has been normalized according to the URL Standard
+//! and checked against the host's allowlist of destinations.  The client gets the normalized URL,
+//! and URLs with backslashes or control characters, which parsers read differently, are never
+//! allowed.  Scripts call `fetch(url, {method, headers, body})`, which blocks until the
+//! client returns, and returns a response with `ok`, `status`, `url`, `headers.get(name)`,
+//! `headers.has(name)`, `text()` and `json()`.  Requests to destinations that aren't allowed and
+//! failed requests throw a `TypeError`.
+//!
+//! # Examples
+//!
+//! ```
+//! use duk::fetch::{Request, Response};
+//!
+//! let client = |request: &Request| -> std::io::Result<Response> {
+//!     Ok(Response {
+//!         status: 200,
+//!         headers: vec![("Content-Type".to_owned(), "application/json".to_owned())],
+//!         body: format!("{{\"path\": \"{}\"}}", &request.url[23..]).into_bytes(),
+//!     })
+//! };
+//! let ctx = duk::Context::builder()
+//!     .with_fetch(Box::new(client), &["https://api.example.com"])
+//!     .build();
+//! let path = ctx.eval_string("fetch('https://api.example.com/users').json().path").unwrap();
+//! assert_eq!(duk::Value::String("/users".to_owned()), path.to_value());
+//! assert!(ctx.eval_string("fetch('https://evil.example.com/')").is_err());
+//! ```
+
+use std::io;
+use std::os;
+use std::slice;
+
+use duktape_sys;
+use url;
+
+use nul_str;
+use strings;
+use HeapData;
+
+/// Defines `fetch` on top of the native request function.
+const SETUP: &[u8] = b"(function (request) {
+  function Headers(pairs) {
+    this.pairs = pairs;
+  }
+  Headers.prototype.get = function (name) {
+    name = String(name).toLowerCase();
+    var values = this.pairs.filter(function (p) { return p[0].toLowerCase() === name; })
+      .map(function (p) { return p[1]; });
+    return values.length ? values.join(', ') : null;
+  };
+  Headers.prototype.has = function (name) {
+    return this.get(name) !== null;
+  };
+  Headers.prototype.entries = function () {
+    return this.pairs.map(function (p) { return [p[0].toLowerCase(), p[1]]; });
+  };
+
+  function Response(url, status, headers, body) {
+    this.url = url;
+    this.status = status;
+    this.ok = status >= 200 && status < 300;
+    this.headers = new Headers(headers);
+    this.body = body;
+  }
+  Response.prototype.text = function () {
+    return String(this.body);
+  };
+  Response.prototype.json = function () {
+    return JSON.parse(this.text());
+  };
+
+  return function fetch(url, options) {
+    url = String(url);
+    options = options || {};
+    var method = options.method === undefined ? 'GET' : String(options.method).toUpperCase();
+    var headers = options.headers || {};
+    var pairs = Object.keys(headers).map(function (name) {
+      return [name, String(headers[name])];
+    });
+    var body = options.body;
+    if (body !== undefined && body !== null && !(typeof body === 'buffer' ||
+                                                 body instanceof ArrayBuffer ||
+                                                 ArrayBuffer.isView(body))) {
+      body = String(body);
+    }
+    var result = request(url, method, pairs, body === null ? undefined : body);
+    if (result === null) {
+      throw new TypeError('fetch: destination not allowed: ' + url);
+    }
+    if (typeof result === 'string') {
+      throw new TypeError('fetch failed: ' + result);
+    }
+    return new Response(url, result[0], result[1], result[2]);
+  };
+})";
+
+/// An HTTP request made by a script.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Request {
+    /// The request method in upper case, like `GET`.
+    pub method: String,
+    /// The absolute URL, which has been checked against the allowlist.
+    pub url: String,
+    /// The request headers, in the order in which the script specified them.
+    pub headers: Vec<(String, String)>,
+    /// The request body, if the script specified one.  Strings are encoded as UTF-8.
+    pub body: Option<Vec<u8>>,
+}
+
+/// The response to a `Request`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    /// The response body, which `text()` and `json()` decode as UTF-8.
+    pub body: Vec<u8>,
+}
+
+/// Sends the HTTP requests of scripts on behalf of the context.
+///
+/// Closures taking a `&Request` and returning an `io::Result<Response>` are clients too.  An
+/// error makes `fetch` throw a `TypeError` with the error message.
+pub trait HttpClient {
+    fn send(&self, request: &Request) -> io::Result<Response>;
+}
+
+impl<F> HttpClient for F
+    where F: Fn(&Request) -> io::Result<Response>
+{
+    fn send(&self, request: &Request) -> io::Result<Response> {
+        self(request)
+    }
+}
+
+/// The client and allowlist of a context.
+pub(crate) struct Fetcher {
+    client: Box<dyn HttpClient>,
+    allowed: Vec<String>,
+}
+
+impl Fetcher {
+    pub(crate) fn new(client: Box<dyn HttpClient>, allowed: Vec<String>) -> Fetcher {
+        let allowed = allowed.into_iter()
+            .map(|allowed| url::Url::parse(&allowed).map_or(allowed, String::from))
+            .collect();
+        Fetcher { client, allowed }
+    }
+
+    /// Checks whether the URL is one of the allowed destinations, or below one of them, and
+    /// returns it normalized (see `url::Url`), which is the URL that the client gets.  An entry
+    /// only matches at a path, query or fragment boundary, so `https://api.example.com` doesn't
+    /// allow `https://api.example.com.evil.net` or `https://api.example.com@evil.net`.  Dot
+    /// segments are resolved before matching, so they can't climb out of an allowed path.
+    pub(crate) fn check(&self, url: &str) -> Option<String> {
+        // Parsers disagree on these, so the client might not read the URL the same way
+        if url.chars().any(|c| c == '\\' || c.is_control()) {
+            return None;
+        }
+        let url = String::from(url::Url::parse(url).ok()?);
+        let allowed = self.allowed.iter().any(|allowed| {
+            url.len() >= allowed.len() && url.is_char_boundary(allowed.len()) &&
+            url[..allowed.len()].eq_ignore_ascii_case(allowed) &&
+            (allowed.ends_with('/') ||
+             url[allowed.len()..].chars().next().is_none_or(|c| "/?#".contains(c)))
+        });
+        if allowed { Some(url) } else { None }
+    }
+}
+
+/// Defines the global `fetch` function.
+pub(crate) unsafe fn setup(ctx: *mut duktape_sys::duk_context) {
+    use duktape_sys::*;
+
+    let ret = duk_peval_lstring(ctx, SETUP.as_ptr() as *const os::raw::c_char, SETUP.len());
+    assert_eq!(0, ret, "failed to compile fetch");
+    duk_push_c_function(ctx, Some(request), 4);
+    let ret = duk_pcall(ctx, 1);
+    assert_eq!(0, ret, "failed to set up fetch");
+    duk_put_global_string(ctx, nul_str(b"fetch\0"));
+}
+
+/// `request(url, method, headers, body)`, returns `[status, headers, body]`, an error message if
+/// the request failed, or `null` if the destination is not allowed.
+unsafe extern "C" fn request(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    use duktape_sys::*;
+
+    let mut funcs = duk_memory_functions::default();
+    duk_get_memory_functions(ctx, &mut funcs);
+    let fetcher = match (*(funcs.udata as *mut HeapData)).fetch {
+        Some(ref fetcher) => fetcher,
+        None => return 0,
+    };
+
+    let url = match fetcher.check(&strings::get(ctx, 0)) {
+        Some(url) => url,
+        None => {
+            duk_push_null(ctx);
+            return 1;
+        }
+    };
+    let method = strings::get(ctx, 1);
+    let mut headers = Vec::new();
+    for i in 0..duk_get_length(ctx, 2) as duk_uarridx_t {
+        duk_get_prop_index(ctx, 2, i);
+        duk_get_prop_index(ctx, -1, 0);
+        duk_get_prop_index(ctx, -2, 1);
+        headers.push((strings::get(ctx, -2), strings::get(ctx, -1)));
+        duk_pop_3(ctx);
+    }
+    let mut len = 0;
+    let data = duk_get_buffer_data(ctx, 3, &mut len);
+    let body = if !data.is_null() {
+        Some(slice::from_raw_parts(data as *const u8, len).to_vec())
+    } else if duk_is_buffer(ctx, 3) != 0 {
+        Some(Vec::new())
+    } else if duk_is_undefined(ctx, 3) == 0 {
+        Some(strings::get(ctx, 3).into_bytes())
+    } else {
+        None
+    };
+
+    let request = Request {
+        method,
+        url,
+        headers,
+        body,
+    };
+    debug!("fetch: {} {}", request.method, request.url);
+    let response = match fetcher.client.send(&request) {
+        Ok(response) => response,
+        Err(e) => {
+            strings::push(ctx, &e.to_string());
+            return 1;
+        }
+    };
+
+    duk_push_array(ctx);
+    duk_push_uint(ctx, response.status as duk_uint_t);
+    duk_put_prop_index(ctx, -2, 0);
+    duk_push_array(ctx);
+    for (i, (name, value)) in response.headers.iter().enumerate() {
+        duk_push_array(ctx);
+        strings::push(ctx, name);
+        duk_put_prop_index(ctx, -2, 0);
+        strings::push(ctx, value);
+        duk_put_prop_index(ctx, -2, 1);
+        duk_put_prop_index(ctx, -2, i as duk_uarridx_t);
+    }
+    duk_put_prop_index(ctx, -2, 1);
+    strings::push(ctx, &String::from_utf8_lossy(&response.body));
+    duk_put_prop_index(ctx, -2, 2);
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::cell;
+    use std::io;
+    use std::rc;
+
+    use super::*;
+    use {Context, Value};
+
+    #[test]
+    fn fetch_requests() {
+        let _ = env_logger::init();
+        let requests = rc::Rc::new(cell::RefCell::new(Vec::new()));
+        let log = requests.clone();
+        let client = move |request: &Request| -> io::Result<Response> {
+            log.borrow_mut().push(request.clone());
+            if request.url.ends_with("/down") {
+                return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "connection refused"));
+            }
+            Ok(Response {
+                status: if request.method == "POST" { 201 } else { 404 },
+                headers: vec![("X-Id".to_owned(), "7".to_owned()),
+                              ("x-id".to_owned(), "8".to_owned())],
+                body: b"{\"created\": true}".to_vec(),
+            })
+        };
+        let ctx = Context::builder()
+            .with_fetch(Box::new(client), &["https://api.example.com/v1/"])
+            .build();
+
+        let result = ctx.eval_string(r"
+          var r = fetch('https://api.example.com/v1/items', {
+            method: 'post', headers: {'Content-Type': 'text/plain'}, body: 'hé'
+          });
+          [r.ok, r.status, r.headers.get('x-ID'), r.headers.has('etag'), r.json().created]
+        ")
+            .unwrap();
+        assert_eq!(Value::Array(vec![Value::Boolean(true),
+                                     Value::Number(201.0),
+                                     Value::String("7, 8".to_owned()),
+                                     Value::Boolean(false),
+                                     Value::Boolean(true)]),
+                   result.to_value());
+        assert_eq!(Request {
+                       method: "POST".to_owned(),
+                       url: "https://api.example.com/v1/items".to_owned(),
+                       headers: vec![("Content-Type".to_owned(), "text/plain".to_owned())],
+                       body: Some("h\u{e9}".as_bytes().to_vec()),
+                   },
+                   requests.borrow()[0]);
+
+        let status = ctx.eval_string("fetch('https://api.example.com/v1/x?q=1').status").unwrap();
+        assert_eq!(Value::Number(404.0), status.to_value());
+        assert_eq!(None, requests.borrow()[1].body);
+        assert!(ctx.eval_string("fetch('https://api.example.com/v1/down')").is_err());
+        assert_eq!(3, requests.borrow().len());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn allowlist() {
+        let _ = env_logger::init();
+        let client = |_: &Request| -> io::Result<Response> { panic!("the request was not blocked") };
+        let ctx = Context::builder()
+            .with_fetch(Box::new(client), &["https://api.example.com", "http://localhost:8080/"])
+            .build();
+        for url in &["https://api.example.com.evil.net/",
+                     "https://api.example.com@evil.net/",
+                     "https://api.example.comx",
+                     "http://api.example.com/",
+                     "http://localhost:8081/",
+                     "file:///etc/passwd"] {
+            let error = ctx.global_object().call_method("fetch", &[&Value::String(url.to_string())]);
+            assert!(error.unwrap_err().to_string().contains("not allowed"), "{}", url);
+        }
+
+        let fetcher = Fetcher::new(Box::new(client),
+                                   vec!["https://api.example.com".to_owned(),
+                                        "http://localhost:8080/".to_owned()]);
+        let check = |url: &str| fetcher.check(url);
+        assert_eq!(Some("https://api.example.com/".to_owned()), check("https://api.example.com"));
+        assert_eq!(Some("https://api.example.com/a?b#c".to_owned()),
+                   check("HTTPS://API.example.com/a?b#c"));
+        assert_eq!(Some("http://localhost:8080/api".to_owned()),
+                   check("http://localhost:8080/api"));
+        assert_eq!(Some("http://localhost:8080/".to_owned()), check("http://localhost:8080"));
+        assert_eq!(Some("http://localhost:8080/admin".to_owned()),
+                   check("http://localhost:8080/api/../admin"));
+
+        // Dot segments are resolved before matching, however they are spelled
+        let fetcher = Fetcher::new(Box::new(client), vec!["https://api.example.com/v1".to_owned()]);
+        assert!(fetcher.check("https://api.example.com/v1/users").is_some());
+        for url in &["https://api.example.com/v1/../admin",
+                     "https://api.example.com/v1/%2E%2e/admin",
+                     "https://api.example.com/v1/..\\admin",
+                     "https://api.example.com/v1/.\t./admin",
+                     "https://api.example.com/v1/.\n./admin",
+                     "https://api.example.com/v1/users\r",
+                     "https://api.example.com/v1x",
+                     "not a url"] {
+            assert_eq!(None, fetcher.check(url), "{:?}", url);
+        }
+        ctx.assert_clean();
+    }
+}
@@ -0,0 +1,450 @@
+//! A sandboxed `host:fs` module for plugins that need scratch storage, see
+//! `ContextBuilder::with_filesystem`.
+//!
+//! Scripts only see the directory tree below the root of the `Sandbox`: paths are always relative
+//! to the root (a leading `/` is the root itself), `..` can't climb above it, and symbolic links
+//! that point outside of it are refused.  Writes are limited by a maximum file size and,
+//! optionally, by a quota on the total size of all files below the root.
+//!
+//! The module has synchronous functions:
+//!
+//! * `readFile(path, encoding)` returns the contents as a string, or as a `Uint8Array` if the
+//!   encoding is `null` or `'buffer'`.
+//! * `writeFile(path, data)` writes a string as UTF-8, or buffer data as is, creating missing
+//!   parent directories.
+//! * `readDir(path)` returns the sorted names of the entries of a directory.
+//! * `stat(path)` returns `{size, isFile, isDirectory, modified}`, where `modified` is in
+//!   milliseconds since the epoch.
+//!
+//! Failures throw an `Error` with a `code` like `ENOENT`, `EACCES` (outside of the sandbox),
+//! `EFBIG` (file too large) or `EQUOTA` (quota exceeded).  Error messages mention the path as the
+//! script specified it, never the host path of the root.
+//!
+//! Scripts build paths with the `host:path` module, which comes with `host:fs` and follows the
+//! same rules, instead of concatenating strings.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! let sandbox = duk::filesystem::Sandbox::new("/var/lib/host/plugins/weather")
+//!     .with_max_file_size(64 * 1024)
+//!     .with_quota(1024 * 1024);
+//! let ctx = duk::Context::builder().with_filesystem(sandbox).build();
+//! ctx.eval_string("require('host:fs').writeFile('cache/today.json', '{}')").unwrap();
+//! ```
+
+use std::fs;
+use std::io;
+use std::os;
+use std::path;
+use std::ptr;
+use std::slice;
+use std::time;
+
+use duktape_sys;
+
+use host_modules;
+use nul_str;
+use paths;
+use strings;
+use HeapData;
+
+/// Builds the exports of the module on top of the native functions.
+const SETUP: &[u8] = b"(function (read, write, readDir, stat) {
+  function unwrap(result) {
+    if (result[0] !== null) {
+      var error = new Error(result[1]);
+      error.code = result[0];
+      throw error;
+    }
+    return result[1];
+  }
+  return {
+    readFile: function (path, encoding) {
+      var text = encoding === undefined || /^utf-?8$/i.test(encoding);
+      if (!text && encoding !== null && encoding !== 'buffer') {
+        throw new TypeError('unsupported encoding: ' + encoding);
+      }
+      return unwrap(read(String(path), text));
+    },
+    writeFile: function (path, data) {
+      if (!(typeof data === 'buffer' || data instanceof ArrayBuffer || ArrayBuffer.isView(data))) {
+        data = String(data);
+      }
+      unwrap(write(String(path), data));
+    },
+    readDir: function (path) {
+      return unwrap(readDir(path === undefined ? '' : String(path)));
+    },
+    stat: function (path) {
+      return unwrap(stat(String(path)));
+    }
+  };
+})";
+
+/// The directory that a context may access through `host:fs`, with its limits.
+#[derive(Clone, Debug)]
+pub struct Sandbox {
+    root: path::PathBuf,
+    max_file_size: u64,
+    quota: Option<u64>,
+}
+
+/// A failed file system operation, as reported to scripts.
+#[derive(Debug, PartialEq)]
+pub(crate) struct FsError {
+    code: &'static str,
+    message: String,
+}
+
+/// The metadata that `stat` returns.
+pub(crate) struct Stat {
+    size: u64,
+    is_file: bool,
+    is_directory: bool,
+    modified: Option<f64>,
+}
+
+impl Sandbox {
+    /// Creates a sandbox rooted at the specified directory, which must exist, with a maximum file
+    /// size of 1 MiB and no quota.
+    pub fn new<P>(root: P) -> Sandbox
+        where P: Into<path::PathBuf>
+    {
+        Sandbox {
+            root: root.into(),
+            max_file_size: 1024 * 1024,
+            quota: None,
+        }
+    }
+
+    /// Sets the maximum size of the files that scripts can read or write.
+    pub fn with_max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = bytes;
+        self
+    }
+
+    /// Limits the total size of all files below the root, including the ones that were there
+    /// before.  Writes that would exceed the quota fail.
+    pub fn with_quota(mut self, bytes: u64) -> Self {
+        self.quota = Some(bytes);
+        self
+    }
+
+    /// The root directory of the sandbox.
+    pub fn root(&self) -> &path::Path {
+        &self.root
+    }
+
+    /// Maps a script path to a host path below the root, refusing paths that would escape it.
+    fn resolve(&self, path: &str) -> Result<path::PathBuf, FsError> {
+        let parts = paths::components(path)?;
+        let resolved = parts.iter().fold(self.root.clone(), |p, part| p.join(part));
+
+        // Symbolic links may still lead elsewhere, so check where the deepest existing ancestor
+        // really is
+        let root = fs::canonicalize(&self.root).map_err(|e| FsError::io(path, &e))?;
+        let mut existing = resolved.as_path();
+        while fs::symlink_metadata(existing).is_err() {
+            existing = match existing.parent() {
+                Some(parent) => parent,
+                None => break,
+            };
+        }
+        match fs::canonicalize(existing) {
+            Ok(ref real) if real.starts_with(&root) => Ok(resolved),
+            _ => Err(FsError::new("EACCES", path, "outside of the sandbox")),
+        }
+    }
+
+    pub(crate) fn read(&self, path: &str) -> Result<Vec<u8>, FsError> {
+        let resolved = self.resolve(path)?;
+        let size = fs::metadata(&resolved).map_err(|e| FsError::io(path, &e))?.len();
+        if size > self.max_file_size {
+            return Err(FsError::new("EFBIG", path, "file too large"));
+        }
+        fs::read(&resolved).map_err(|e| FsError::io(path, &e))
+    }
+
+    pub(crate) fn write(&self, path: &str, data: &[u8]) -> Result<(), FsError> {
+        let resolved = self.resolve(path)?;
+        if data.len() as u64 > self.max_file_size {
+            return Err(FsError::new("EFBIG", path, "file too large"));
+        }
+        if let Some(quota) = self.quota {
+            let replaced = fs::metadata(&resolved).map(|m| m.len()).unwrap_or(0);
+            let used = usage(&self.root).map_err(|e| FsError::io(path, &e))?;
+            if used - replaced.min(used) + data.len() as u64 > quota {
+                return Err(FsError::new("EQUOTA", path, "quota exceeded"));
+            }
+        }
+        if let Some(parent) = resolved.parent() {
+            fs::create_dir_all(parent).map_err(|e| FsError::io(path, &e))?;
+        }
+        fs::write(&resolved, data).map_err(|e| FsError::io(path, &e))
+    }
+
+    pub(crate) fn read_dir(&self, path: &str) -> Result<Vec<String>, FsError> {
+        let resolved = self.resolve(path)?;
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&resolved).map_err(|e| FsError::io(path, &e))? {
+            let entry = entry.map_err(|e| FsError::io(path, &e))?;
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    pub(crate) fn stat(&self, path: &str) -> Result<Stat, FsError> {
+        let metadata = fs::metadata(self.resolve(path)?).map_err(|e| FsError::io(path, &e))?;
+        let modified = metadata.modified()
+            .ok()
+            .and_then(|m| m.duration_since(time::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as f64 * 1000.0 + d.subsec_millis() as f64);
+        Ok(Stat {
+            size: metadata.len(),
+            is_file: metadata.is_file(),
+            is_directory: metadata.is_dir(),
+            modified,
+        })
+    }
+}
+
+impl FsError {
+    pub(crate) fn new(code: &'static str, path: &str, reason: &str) -> FsError {
+        FsError {
+            code,
+            message: format!("{}: {}", path, reason),
+        }
+    }
+
+    fn io(path: &str, error: &io::Error) -> FsError {
+        let code = match error.kind() {
+            io::ErrorKind::NotFound => "ENOENT",
+            io::ErrorKind::PermissionDenied => "EACCES",
+            io::ErrorKind::AlreadyExists => "EEXIST",
+            _ => "EIO",
+        };
+        FsError::new(code, path, &error.to_string())
+    }
+}
+
+/// The total size of the files below a directory.
+fn usage(dir: &path::Path) -> io::Result<u64> {
+    let mut total = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            total += usage(&entry.path())?;
+        } else if file_type.is_file() {
+            total += entry.metadata()?.len();
+        }
+    }
+    Ok(total)
+}
+
+/// Registers the `host:fs` module.
+pub(crate) unsafe fn setup(ctx: *mut duktape_sys::duk_context) {
+    use duktape_sys::*;
+
+    let ret = duk_peval_lstring(ctx, SETUP.as_ptr() as *const os::raw::c_char, SETUP.len());
+    assert_eq!(0, ret, "failed to compile host:fs");
+    duk_push_c_function(ctx, Some(read), 2);
+    duk_push_c_function(ctx, Some(write), 2);
+    duk_push_c_function(ctx, Some(read_dir), 1);
+    duk_push_c_function(ctx, Some(stat), 1);
+    let ret = duk_pcall(ctx, 4);
+    assert_eq!(0, ret, "failed to set up host:fs");
+    host_modules::register(ctx, b"host:fs\0");
+}
+
+unsafe fn sandbox<'a>(ctx: *mut duktape_sys::duk_context) -> &'a Sandbox {
+    let mut funcs = duktape_sys::duk_memory_functions::default();
+    duktape_sys::duk_get_memory_functions(ctx, &mut funcs);
+    (*(funcs.udata as *mut HeapData)).filesystem.as_ref().unwrap()
+}
+
+/// Pushes `[null, result]` on success, with the result pushed by `push`, or `[code, message]` on
+/// failure.
+pub(crate) unsafe fn respond<T, F>(ctx: *mut duktape_sys::duk_context, result: Result<T, FsError>, push: F)
+    where F: FnOnce(T)
+{
+    use duktape_sys::*;
+
+    duk_push_array(ctx);
+    match result {
+        Ok(value) => {
+            duk_push_null(ctx);
+            duk_put_prop_index(ctx, -2, 0);
+            push(value);
+        }
+        Err(e) => {
+            strings::push(ctx, e.code);
+            duk_put_prop_index(ctx, -2, 0);
+            strings::push(ctx, &e.message);
+        }
+    }
+    duk_put_prop_index(ctx, -2, 1);
+}
+
+/// `read(path, text)`
+unsafe extern "C" fn read(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    use duktape_sys::*;
+
+    let text = duk_to_boolean(ctx, 1) != 0;
+    let result = sandbox(ctx).read(&strings::get(ctx, 0));
+    respond(ctx, result, |bytes| if text {
+        strings::push(ctx, &String::from_utf8_lossy(&bytes));
+    } else {
+        let buf = duk_push_fixed_buffer(ctx, bytes.len());
+        ptr::copy_nonoverlapping(bytes.as_ptr(), buf as *mut u8, bytes.len());
+        duk_push_buffer_object(ctx, -1, 0, bytes.len(), DUK_BUFOBJ_UINT8ARRAY);
+        duk_remove(ctx, -2);
+    });
+    1
+}
+
+/// `write(path, data)`
+unsafe extern "C" fn write(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    use duktape_sys::*;
+
+    let path = strings::get(ctx, 0);
+    let mut len = 0;
+    let data = duk_get_buffer_data(ctx, 1, &mut len);
+    let result = if !data.is_null() {
+        sandbox(ctx).write(&path, slice::from_raw_parts(data as *const u8, len))
+    } else if duk_is_buffer(ctx, 1) != 0 {
+        sandbox(ctx).write(&path, &[])
+    } else {
+        sandbox(ctx).write(&path, strings::get(ctx, 1).as_bytes())
+    };
+    respond(ctx, result, |()| duk_push_undefined(ctx));
+    1
+}
+
+/// `readDir(path)`
+unsafe extern "C" fn read_dir(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    use duktape_sys::*;
+
+    let result = sandbox(ctx).read_dir(&strings::get(ctx, 0));
+    respond(ctx, result, |names| {
+        duk_push_array(ctx);
+        for (i, name) in names.iter().enumerate() {
+            strings::push(ctx, name);
+            duk_put_prop_index(ctx, -2, i as duk_uarridx_t);
+        }
+    });
+    1
+}
+
+/// `stat(path)`
+unsafe extern "C" fn stat(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    use duktape_sys::*;
+
+    let result = sandbox(ctx).stat(&strings::get(ctx, 0));
+    respond(ctx, result, |stat| {
+        duk_push_object(ctx);
+        duk_push_number(ctx, stat.size as f64);
+        duk_put_prop_string(ctx, -2, nul_str(b"size\0"));
+        duk_push_boolean(ctx, stat.is_file as duk_bool_t);
+        duk_put_prop_string(ctx, -2, nul_str(b"isFile\0"));
+        duk_push_boolean(ctx, stat.is_directory as duk_bool_t);
+        duk_put_prop_string(ctx, -2, nul_str(b"isDirectory\0"));
+        match stat.modified {
+            Some(modified) => duk_push_number(ctx, modified),
+            None => duk_push_null(ctx),
+        }
+        duk_put_prop_string(ctx, -2, nul_str(b"modified\0"));
+    });
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::fs;
+
+    use super::*;
+    use testing::ScratchDir;
+    use {Context, Value};
+
+    #[test]
+    #[cfg_attr(target_os = "wasi", ignore = "needs a temporary directory")]
+    fn host_fs() {
+        let _ = env_logger::init();
+        let dir = ScratchDir::new("host-fs");
+        let ctx = Context::builder().with_filesystem(Sandbox::new(dir.path())).build();
+        let result = ctx.eval_string(r"
+          var fs = require('host:fs');
+          fs.writeFile('/notes/today.txt', 'hé');
+          fs.writeFile('notes/raw.bin', Duktape.dec('hex', '00ff'));
+          var bytes = fs.readFile('notes/./raw.bin', null);
+          var stat = fs.stat('notes/today.txt');
+          [fs.readFile('notes/../notes/today.txt'), fs.readDir('notes'), fs.readDir(),
+           bytes instanceof Uint8Array, bytes[1], stat.size, stat.isFile, fs.stat('/').isDirectory,
+           typeof stat.modified]
+        ")
+            .unwrap();
+        assert_eq!(Value::Array(vec![Value::String("h\u{e9}".to_owned()),
+                                     Value::Array(vec![Value::String("raw.bin".to_owned()),
+                                                       Value::String("today.txt".to_owned())]),
+                                     Value::Array(vec![Value::String("notes".to_owned())]),
+                                     Value::Boolean(true),
+                                     Value::Number(255.0),
+                                     Value::Number(3.0),
+                                     Value::Boolean(true),
+                                     Value::Boolean(true),
+                                     Value::String("number".to_owned())]),
+                   result.to_value());
+        assert_eq!(b"h\xc3\xa9".to_vec(), fs::read(dir.join("notes/today.txt")).unwrap());
+
+        let code = ctx.eval_string("try { fs.readFile('missing.txt'); } catch (e) { e.code + ' ' + \
+                                    e.message }")
+            .unwrap();
+        assert_eq!(Value::String("ENOENT missing.txt: No such file or directory (os error 2)"
+                       .to_owned()),
+                   code.to_value());
+        assert!(ctx.eval_string("require('host:nope')").is_err());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    #[cfg_attr(target_os = "wasi", ignore = "needs a temporary directory")]
+    fn sandbox_limits() {
+        let _ = env_logger::init();
+        let dir = ScratchDir::new("sandbox-limits");
+        fs::create_dir(dir.join("root")).unwrap();
+        fs::write(dir.join("secret.txt"), "secret").unwrap();
+        let sandbox = Sandbox::new(dir.join("root")).with_max_file_size(8).with_quota(12);
+
+        for path in &["../secret.txt", "a/../../secret.txt", "/../secret.txt"] {
+            assert_eq!("EACCES", sandbox.read(path).unwrap_err().code, "{}", path);
+        }
+        assert_eq!("EINVAL", sandbox.read("..\\secret.txt").unwrap_err().code);
+        #[cfg(unix)]
+        {
+            ::std::os::unix::fs::symlink(&dir, dir.join("root/link")).unwrap();
+            assert_eq!("EACCES", sandbox.read("link/secret.txt").unwrap_err().code);
+            assert_eq!("EACCES", sandbox.write("link/new.txt", b"x").unwrap_err().code);
+            fs::remove_file(dir.join("root/link")).unwrap();
+        }
+
+        assert_eq!("EFBIG", sandbox.write("big", b"123456789").unwrap_err().code);
+        sandbox.write("a", b"12345678").unwrap();
+        assert_eq!("EQUOTA", sandbox.write("b", b"12345").unwrap_err().code);
+        sandbox.write("b", b"1234").unwrap();
+        // Replacing a file only counts the difference
+        sandbox.write("a", b"1234").unwrap();
+        sandbox.write("c", b"1234").unwrap();
+        assert_eq!(vec!["a", "b", "c"], sandbox.read_dir("").unwrap());
+
+        let ctx = Context::builder().with_filesystem(sandbox).build();
+        let code = ctx.eval_string("try { require('host:fs').writeFile('d', 'x'); } catch (e) { \
+                                    e.code }")
+            .unwrap();
+        assert_eq!(Value::String("EQUOTA".to_owned()), code.to_value());
+        ctx.assert_clean();
+    }
+}
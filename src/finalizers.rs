@@ -0,0 +1,230 @@
+//! Rust code that runs when Duktape collects an object, see `Reference::add_finalizer`.
+//!
+//! An object has at most one Duktape finalizer, so every object with Rust finalizers gets the same
+//! one, which runs all of them in the order they were added.  Native functions and bound objects
+//! release their Rust values through it as well, so adding a finalizer never replaces theirs.
+//!
+//! The Rust finalizers live in a table of the context, by the heap pointers of their objects,
+//! since scripts can read and write any property of an object, hidden ones included.  The Duktape
+//! finalizer is read-only, so scripts can't replace it with `Duktape.fin(object, f)` and leak the
+//! Rust values, which throws a `TypeError` instead.  Scripts can still get it with
+//! `Duktape.fin(object)` and call it or give it to other objects, which only runs the finalizers
+//! of the object that it is called with, once.
+
+use std::cell;
+use std::collections;
+use std::os;
+use std::panic;
+use std::ptr;
+
+use duktape_sys;
+
+use nul_str;
+use HeapData;
+
+/// A finalizer, which gets the context with the object that is being finalized at index 0.
+pub(crate) type Finalizer = Box<dyn FnOnce(*mut duktape_sys::duk_context)>;
+
+/// The Rust finalizers of a context, by the heap pointers of their objects.
+pub(crate) type Registry = cell::RefCell<collections::HashMap<usize, Vec<Finalizer>>>;
+
+/// The internal property where Duktape looks up the finalizer of an object, which
+/// `duk_set_finalizer` sets.
+const FINALIZER_KEY: &[u8] = b"\xffFinalizer";
+
+/// The key of the heap stash entry with the Duktape finalizer that all objects share.
+const STASH_KEY: &[u8] = b"finalize\0";
+
+/// Adds a finalizer to the object at the index, and returns whether it could, which it can't if
+/// the object isn't extensible or a script defined a read-only finalizer on it.
+pub(crate) unsafe fn add(ctx: *mut duktape_sys::duk_context,
+                         index: duktape_sys::duk_idx_t,
+                         finalizer: Finalizer)
+                         -> bool {
+    use duktape_sys::*;
+
+    let object = duk_get_heapptr(ctx, index) as usize;
+    if let Some(finalizers) = registry(ctx).borrow_mut().get_mut(&object) {
+        finalizers.push(finalizer);
+        return true;
+    }
+
+    // Defined rather than set, since a finalizer that the object inherits is read-only too.  The
+    // function is the same for all objects, so defining it again after the finalizers ran (see
+    // `finalize`) succeeds.
+    duk_dup(ctx, index);
+    duk_push_lstring(ctx,
+                     FINALIZER_KEY.as_ptr() as *const os::raw::c_char,
+                     FINALIZER_KEY.len());
+    push_finalize(ctx);
+    let ret = duk_safe_call(ctx, Some(define), ptr::null_mut(), 3, 1);
+    duk_pop(ctx);
+    if ret != 0 {
+        return false;
+    }
+    registry(ctx).borrow_mut().insert(object, vec![finalizer]);
+    true
+}
+
+/// `define(object, key, finalizer)`
+unsafe extern "C" fn define(ctx: *mut duktape_sys::duk_context,
+                            _: *mut os::raw::c_void)
+                            -> duktape_sys::duk_ret_t {
+    use duktape_sys::*;
+
+    // Safe calls share the value stack of the caller, so index 0 may be below the arguments
+    duk_def_prop(ctx,
+                 -3,
+                 DUK_DEFPROP_HAVE_VALUE | DUK_DEFPROP_HAVE_WRITABLE |
+                 DUK_DEFPROP_HAVE_CONFIGURABLE);
+    0
+}
+
+/// Pushes the Duktape finalizer of objects with Rust finalizers.
+unsafe fn push_finalize(ctx: *mut duktape_sys::duk_context) {
+    use duktape_sys::*;
+
+    duk_push_heap_stash(ctx);
+    if duk_get_prop_string(ctx, -1, nul_str(STASH_KEY)) == 0 {
+        duk_pop(ctx);
+        duk_push_c_function(ctx, Some(finalize), 2);
+        duk_dup_top(ctx);
+        duk_put_prop_string(ctx, -3, nul_str(STASH_KEY));
+    }
+    duk_remove(ctx, -2);
+}
+
+unsafe fn registry<'a>(ctx: *mut duktape_sys::duk_context) -> &'a Registry {
+    let mut funcs = duktape_sys::duk_memory_functions::default();
+    duktape_sys::duk_get_memory_functions(ctx, &mut funcs);
+    &(*(funcs.udata as *mut HeapData)).finalizers
+}
+
+/// Runs the finalizers of an object that is being garbage collected.
+unsafe extern "C" fn finalize(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    let object = duktape_sys::duk_get_heapptr(ctx, 0) as usize;
+    // Finalizers can run more than once, if the object is rescued or a script calls the finalizer,
+    // but only the first time runs the Rust ones.  They may add finalizers themselves, so the
+    // table isn't borrowed while they run.
+    let finalizers = registry(ctx).borrow_mut().remove(&object);
+    for finalizer in finalizers.into_iter().flatten() {
+        // There is nobody to report a panic to, and it must not unwind into Duktape
+        let _ = panic::catch_unwind(panic::AssertUnwindSafe(|| finalizer(ctx)));
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::cell;
+    use std::rc;
+
+    use {Context, Value};
+
+    #[test]
+    fn finalizers() {
+        let _ = env_logger::init();
+        let events = rc::Rc::new(cell::RefCell::new(Vec::new()));
+        let record = |event: &'static str| {
+            let events = events.clone();
+            move || events.borrow_mut().push(event)
+        };
+        let ctx = Context::new();
+        let service = rc::Rc::new(cell::RefCell::new(()));
+        ctx.register_object("service", service.clone(), |_| {});
+        ctx.eval_string("var cache = {}; cache.self = cache; var file = {};").unwrap();
+        ctx.eval_string("service").unwrap().add_finalizer(record("service")).unwrap();
+        let cache = ctx.eval_string("cache").unwrap();
+        cache.add_finalizer(|| panic!("finalizer panicked")).unwrap();
+        cache.add_finalizer(record("cache")).unwrap();
+        ctx.eval_string("file").unwrap().add_finalizer(record("file")).unwrap();
+        assert!(ctx.eval_string("1").unwrap().add_finalizer(record("number")).is_err());
+
+        // The reference keeps the cycle alive, which only the garbage collector frees
+        ctx.eval_string("cache = null; file = null;").unwrap();
+        assert_eq!(vec!["file"], *events.borrow());
+        ctx.gc();
+        assert_eq!(vec!["file"], *events.borrow());
+        drop(cache);
+        ctx.gc();
+        assert_eq!(vec!["file", "cache"], *events.borrow());
+
+        // The bound value is released along with the added finalizer
+        assert_eq!(Value::String("function".to_owned()),
+                   ctx.eval_string("typeof Duktape.fin(service)").unwrap().to_value());
+        ctx.assert_clean();
+        drop(ctx);
+        assert_eq!(vec!["file", "cache", "service"], *events.borrow());
+        assert_eq!(1, rc::Rc::strong_count(&service));
+    }
+
+    #[test]
+    fn inherited_finalizers() {
+        let _ = env_logger::init();
+        let events = rc::Rc::new(cell::RefCell::new(Vec::new()));
+        let record = |event: &'static str| {
+            let events = events.clone();
+            move || events.borrow_mut().push(event)
+        };
+        let ctx = Context::new();
+        ctx.eval_string("var parent = {}; var child = Object.create(parent);").unwrap();
+        ctx.eval_string("parent").unwrap().add_finalizer(record("parent")).unwrap();
+        let child = ctx.eval_string("child").unwrap();
+        child.add_finalizer(record("child")).unwrap();
+        drop(child);
+
+        // Scripts can't replace the finalizers, even through inheritance
+        assert_eq!(Value::String("TypeError TypeError".to_owned()),
+                   ctx.eval_string(r"
+                     function error(f) { try { f(); } catch (e) { return e.name; } }
+                     error(function () { Duktape.fin(parent, function () {}); }) + ' ' +
+                     error(function () { Duktape.fin(Object.create(parent), function () {}); })
+                   ")
+                       .unwrap()
+                       .to_value());
+
+        ctx.eval_string("child = null;").unwrap();
+        ctx.gc();
+        assert_eq!(vec!["child"], *events.borrow());
+        ctx.eval_string("parent = null;").unwrap();
+        ctx.gc();
+        assert_eq!(vec!["child", "parent"], *events.borrow());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn copied_finalizers() {
+        let _ = env_logger::init();
+        let events = rc::Rc::new(cell::RefCell::new(Vec::new()));
+        let record = |event: &'static str| {
+            let events = events.clone();
+            move || events.borrow_mut().push(event)
+        };
+        let ctx = Context::new();
+        ctx.eval_string("var a = {}; var b = {};").unwrap();
+        ctx.eval_string("a").unwrap().add_finalizer(record("a")).unwrap();
+
+        // Hidden properties and the finalizer itself only lead back to the finalizers of the
+        // object that the finalizer is called with
+        ctx.eval_string(r"
+          var k = String(Duktape.dec('hex', 'ff66696e616c697a657273'));
+          b[k] = a[k];
+          Duktape.fin(b, Duktape.fin(a));
+          Duktape.fin(b)(b);
+        ")
+            .unwrap();
+        assert!(events.borrow().is_empty());
+        ctx.eval_string("Duktape.fin(a)(a); Duktape.fin(a)(a);").unwrap();
+        assert_eq!(vec!["a"], *events.borrow());
+        ctx.eval_string("a = null; b = null;").unwrap();
+        ctx.gc();
+        assert_eq!(vec!["a"], *events.borrow());
+
+        // Objects that can't take a finalizer
+        let frozen = ctx.eval_string("Object.freeze({})").unwrap();
+        assert!(frozen.add_finalizer(record("frozen")).is_err());
+        ctx.assert_clean();
+    }
+}
@@ -0,0 +1,173 @@
+//! Functions that the host keeps across calls, see `Function`.
+//!
+//! A retained function lives in an object in the heap stash, under an id of its handle, which
+//! keeps it alive for as long as the handle exists.  Handles don't borrow the context, so a
+//! dropped handle can't remove its function right away; it queues its id instead, and the context
+//! removes queued functions the next time it retains or calls one.
+
+use std::cell;
+use std::rc;
+
+use duktape_sys;
+
+use nul_str;
+use Function;
+use HeapData;
+
+/// The key of the heap stash entry with the retained functions, by the ids of their handles.
+const STASH_KEY: &[u8] = b"functions\0";
+
+/// The ids of the handles of a context, which handles share with it.
+#[derive(Debug, Default)]
+pub(crate) struct Handles {
+    next_id: cell::Cell<u32>,
+    /// The ids of dropped handles, whose functions are still in the heap stash.
+    dropped: cell::RefCell<Vec<u32>>,
+}
+
+impl Handles {
+    /// Queues the function of a dropped handle for removal.
+    pub(crate) fn drop_handle(&self, id: u32) {
+        self.dropped.borrow_mut().push(id);
+    }
+}
+
+/// Retains the function at the index, which must be callable.
+pub(crate) unsafe fn retain(ctx: *mut duktape_sys::duk_context,
+                            index: duktape_sys::duk_idx_t)
+                            -> Function {
+    let index = duktape_sys::duk_normalize_index(ctx, index);
+    let handles = handles(ctx);
+    push_functions(ctx);
+    // Ids wrap around, so skip the ones of handles that still exist
+    let mut id = handles.next_id.get();
+    while duktape_sys::duk_has_prop_index(ctx, -1, id) != 0 {
+        id = id.wrapping_add(1);
+    }
+    handles.next_id.set(id.wrapping_add(1));
+    duktape_sys::duk_dup(ctx, index);
+    duktape_sys::duk_put_prop_index(ctx, -2, id);
+    duktape_sys::duk_pop(ctx);
+    Function { id, handles }
+}
+
+/// Pushes the function of a handle of this context.
+pub(crate) unsafe fn push(ctx: *mut duktape_sys::duk_context, id: u32) {
+    push_functions(ctx);
+    duktape_sys::duk_get_prop_index(ctx, -1, id);
+    duktape_sys::duk_remove(ctx, -2);
+}
+
+/// The handles of the context.
+pub(crate) unsafe fn handles(ctx: *mut duktape_sys::duk_context) -> rc::Rc<Handles> {
+    let mut funcs = duktape_sys::duk_memory_functions::default();
+    duktape_sys::duk_get_memory_functions(ctx, &mut funcs);
+    (*(funcs.udata as *mut HeapData)).functions.clone()
+}
+
+/// Pushes the object with the retained functions, after removing the ones of dropped handles.
+unsafe fn push_functions(ctx: *mut duktape_sys::duk_context) {
+    use duktape_sys::*;
+
+    duk_push_heap_stash(ctx);
+    if duk_get_prop_string(ctx, -1, nul_str(STASH_KEY)) == 0 {
+        duk_pop(ctx);
+        duk_push_object(ctx);
+        duk_dup_top(ctx);
+        duk_put_prop_string(ctx, -3, nul_str(STASH_KEY));
+    }
+    duk_remove(ctx, -2);
+    for id in handles(ctx).dropped.borrow_mut().drain(..) {
+        duk_del_prop_index(ctx, -1, id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::cell;
+    use std::rc;
+
+    use {Context, ErrorKind, Function, JsError, JsErrorKind, Value};
+
+    #[test]
+    fn callbacks() {
+        let _ = env_logger::init();
+        let callbacks = rc::Rc::new(cell::RefCell::new(Vec::<Function>::new()));
+        let ctx = Context::new();
+        let registered = callbacks.clone();
+        ctx.register_variadic("onTick", move |args| {
+            let callback = args.function(0)
+                .ok_or_else(|| JsError::new(JsErrorKind::Type, "callback must be a function"))?;
+            registered.borrow_mut().push(callback);
+            Ok(Value::Undefined)
+        });
+        ctx.eval_string(r"
+          var ticks = [];
+          onTick(function (n) { ticks.push('a' + n); return n * 2; });
+          onTick(function (n) { ticks.push('b' + n); });
+        ")
+            .unwrap();
+        assert!(ctx.eval_string("onTick(1)").is_err());
+        for callback in callbacks.borrow().iter() {
+            callback.call(&ctx, &[&1]).unwrap();
+        }
+        assert_eq!(Value::Number(4.0),
+                   callbacks.borrow()[0].call(&ctx, &[&2]).unwrap().to_value());
+        assert_eq!(Value::String("a1,b1,a2".to_owned()),
+                   ctx.eval_string("ticks.join()").unwrap().to_value());
+
+        // Handles of script results outlive the references they came from
+        let double = ctx.eval_string("(function (n) { return n * 2; })")
+            .unwrap()
+            .to_function()
+            .unwrap();
+        assert_eq!(Value::Number(6.0), double.call(&ctx, &[&3]).unwrap().to_value());
+        assert!(ctx.eval_string("1").unwrap().to_function().is_err());
+
+        // A handle only works with its own context
+        let other = Context::new();
+        match *double.call(&other, &[&3]).unwrap_err().kind() {
+            ErrorKind::Js(ref error) => assert_eq!(JsErrorKind::Type, error.kind),
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+
+        // Dropped handles release their functions
+        let collected = rc::Rc::new(cell::Cell::new(false));
+        let finalized = collected.clone();
+        double.function_ref(&ctx)
+            .unwrap()
+            .as_reference()
+            .add_finalizer(move || finalized.set(true))
+            .unwrap();
+        drop(double);
+        callbacks.borrow_mut().clear();
+        ctx.gc();
+        assert!(!collected.get());
+        ctx.eval_string("onTick(function () {});").unwrap();
+        ctx.gc();
+        assert!(collected.get());
+        other.assert_clean();
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn wrapped_ids() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let function = || {
+            ctx.eval_string("(function () { return 1; })").unwrap().to_function().unwrap()
+        };
+        let first = function();
+        unsafe { super::handles(ctx.raw).next_id.set(u32::max_value()) };
+        let last = function();
+        let wrapped = function();
+        assert_eq!(u32::max_value(), last.id);
+        assert_eq!(first.id + 1, wrapped.id);
+        for handle in &[&first, &last, &wrapped] {
+            assert_eq!(Value::Number(1.0), handle.call(&ctx, &[]).unwrap().to_value());
+        }
+        ctx.assert_clean();
+    }
+}
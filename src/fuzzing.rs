@@ -0,0 +1,176 @@
+//! Entry points for fuzz targets, which exercise the conversions between Rust and Javascript
+//! values and the handling of errors from arbitrary scripts.
+//!
+//! With the `arbitrary` feature, `Value` implements `arbitrary::Arbitrary`, so a `cargo fuzz`
+//! target can be as short as:
+//!
+//! ```text
+//! fuzz_target!(|value: duk::Value| {
+//!     let ctx = duk::Context::new();
+//!     duk::fuzzing::push_then_get(&ctx, &value);
+//! });
+//!
+//! fuzz_target!(|source: &[u8]| {
+//!     let _ = duk::fuzzing::eval_arbitrary_source_safely(source);
+//! });
+//! ```
+//!
+//! Both entry points panic when they detect a bug in this crate, like a value stack that isn't
+//! balanced afterwards or a conversion that changes a value, and are otherwise expected to return
+//! normally for any input.
+//!
+//! # Examples
+//!
+//! ```
+//! use duk::fuzzing;
+//!
+//! let ctx = duk::Context::new();
+//! let value = duk::Value::array().element("\u{1f600}").element(1.5).build();
+//! assert_eq!(value, fuzzing::push_then_get(&ctx, &value));
+//! assert!(fuzzing::eval_arbitrary_source_safely(b"throw new Error('\xff')").is_err());
+//! ```
+
+#[cfg(feature = "timeout")]
+use std::time;
+
+#[cfg(feature = "arbitrary")]
+use arbitrary;
+use duktape_sys;
+
+use allocator;
+use testing;
+use {Context, Result, Value};
+
+/// How much memory a script that `eval_arbitrary_source_safely` runs may use, in bytes.
+pub const MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+
+/// How long a script that `eval_arbitrary_source_safely` runs may take, with the `timeout`
+/// feature.  Without it, scripts that never finish are left to the timeout of the fuzzer.
+#[cfg(feature = "timeout")]
+pub const TIME_LIMIT: time::Duration = time::Duration::from_secs(1);
+
+/// Pushes a value to the stack of the context and converts it back, like passing it to a
+/// function and getting it returned.
+///
+/// Panics if the value stack isn't balanced afterwards, or if the value changed on the way even
+/// though `testing::is_lossless` says that it shouldn't have.
+pub fn push_then_get(ctx: &Context, value: &Value) -> Value {
+    let top = ctx.stack_top();
+    let result = unsafe {
+        value.push(ctx.raw);
+        let result = Value::get(ctx.raw, -1);
+        duktape_sys::duk_pop(ctx.raw);
+        result
+    };
+    assert_eq!(top, ctx.stack_top(), "converting {:?} unbalanced the stack", value);
+    if testing::is_lossless(value, &ctx.conversion_options()) {
+        assert_eq!(*value, result, "converting a value changed it");
+    }
+    result
+}
+
+/// Evaluates arbitrary bytes as a script in a fresh context, and converts the result.
+///
+/// The context can use at most `MEMORY_LIMIT` bytes, and with the `timeout` feature the script
+/// is interrupted after `TIME_LIMIT`.  Scripts that throw, don't compile, or aren't even valid
+/// UTF-8 give errors.  Panics if the value stack of the context isn't balanced afterwards.
+pub fn eval_arbitrary_source_safely(source: &[u8]) -> Result<Value> {
+    let builder = Context::builder().with_allocator(Box::new(allocator::Budget::new(MEMORY_LIMIT)));
+    #[cfg(feature = "timeout")]
+    let builder = builder.with_timeout(TIME_LIMIT);
+    let ctx = builder.try_build()?;
+    let result = ctx.eval_named("fuzz",
+                                source,
+                                |ret| unsafe { ctx.pop_reference_or_error(ret) })
+        .map(|reference| reference.to_value());
+    if let Err(imbalance) = ctx.check_stack_balanced() {
+        panic!("evaluating a script unbalanced the stack: {}", imbalance);
+    }
+    result
+}
+
+/// Generates any value except `Foreign` ones, which can't be created from Javascript.
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Value {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Value> {
+        Ok(match u.int_in_range(0..=7)? {
+            0 => Value::Undefined,
+            1 => Value::Null,
+            2 => Value::Boolean(u.arbitrary()?),
+            3 => Value::Number(u.arbitrary()?),
+            4 => Value::String(u.arbitrary()?),
+            5 => Value::Array(u.arbitrary_iter()?.collect::<arbitrary::Result<_>>()?),
+            6 => {
+                let properties = u.arbitrary_iter::<(String, Value)>()?;
+                Value::Object(properties.collect::<arbitrary::Result<_>>()?)
+            }
+            _ => Value::Bytes(u.arbitrary()?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::collections;
+
+    use super::*;
+    use ErrorKind;
+
+    #[test]
+    fn round_trips() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let mut properties = collections::BTreeMap::new();
+        properties.insert("\u{1f600}".to_owned(), Value::Bytes(vec![0, 255]));
+        properties.insert("__proto__".to_owned(), Value::Number(1.0));
+        let values = [Value::Undefined,
+                      Value::Number(::std::f64::NAN),
+                      Value::String("nul\0 and \u{10ffff}".to_owned()),
+                      Value::Array(vec![Value::Null, Value::Foreign("pointer")]),
+                      Value::Object(properties)];
+        for value in &values {
+            push_then_get(&ctx, value);
+        }
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn arbitrary_sources() {
+        let _ = env_logger::init();
+        // Surrogates, holes in arrays and odd things to throw
+        assert_eq!(Value::String("\u{fffd}".to_owned()),
+                   eval_arbitrary_source_safely(b"'\\uD800'").unwrap());
+        assert_eq!(Value::String("\u{1f600}".to_owned()),
+                   eval_arbitrary_source_safely(b"'\\uD83D\\uDE00'").unwrap());
+        assert_eq!(Value::Array(vec![Value::Undefined, Value::Number(1.0)]),
+                   eval_arbitrary_source_safely(b"[, 1]").unwrap());
+        let failing: &[&[u8]] =
+            &[b"throw '\\uDC00'", b"throw Object.create(null)", b"\xff\xfe(", b"}"];
+        for source in failing {
+            assert!(eval_arbitrary_source_safely(source).is_err());
+        }
+        // More than the memory limit
+        match *eval_arbitrary_source_safely(b"new ArrayBuffer(1 << 30)").unwrap_err().kind() {
+            ErrorKind::Js(_) => (),
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn arbitrary_values() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        // Deterministic bytes that look random enough to build all kinds of values
+        let data = (0..4096u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8);
+        let data = data.collect::<Vec<_>>();
+        let mut u = arbitrary::Unstructured::new(&data);
+        while !u.is_empty() {
+            let value = <Value as arbitrary::Arbitrary>::arbitrary(&mut u).unwrap();
+            push_then_get(&ctx, &value);
+        }
+        ctx.assert_clean();
+    }
+}
@@ -0,0 +1,254 @@
+//! Callbacks for the lifecycle events of a context, see `ContextBuilder::with_hooks`.
+//!
+//! Hooks are the extension point for features that cut across everything a context does, like
+//! auditing what plugins load, exporting traces to an observability system, or enforcing a policy
+//! on top of the limits of the context.  Every callback has an empty default implementation, so
+//! hooks only implement the events that they care about.
+//!
+//! The events are:
+//!
+//!   * `eval_start` and `eval_end` around every evaluation, like `Context::eval_string` or
+//!     `Context::eval_file`, and `call_start` and `call_end` around every call, like
+//!     `Context::call_global` or `Reference::call`, including the ones nested within each other.
+//!   * `module_load` when `require` loads a module through the module loader of the context,
+//!     including host modules like `host:fs`.
+//!   * `gc` after every explicit garbage collection with `Context::gc`.  Duktape collects garbage
+//!     on its own as well, which can't be observed.
+//!   * `error_thrown` whenever a script throws, including errors that are caught and errors
+//!     thrown again from a `catch` block.
+//!   * `limit_exceeded` when something runs into a limit of the context, see `Limit`.
+//!
+//! The context invokes hooks synchronously, in the middle of running scripts, so hooks must not
+//! use the context, and should be quick.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::time;
+//!
+//! struct Audit;
+//!
+//! impl duk::hooks::ContextHooks for Audit {
+//!     fn module_load(&self, id: &str) {
+//!         println!("loading {}", id);
+//!     }
+//!
+//!     fn eval_end(&self, file_name: &str, duration: time::Duration, error: Option<&duk::Error>) {
+//!         if let Some(error) = error {
+//!             println!("{} failed after {:?}: {}", file_name, duration, error);
+//!         }
+//!     }
+//! }
+//!
+//! let ctx = duk::Context::builder().with_hooks(Box::new(Audit)).build();
+//! ctx.eval_string("1 + 1").unwrap();
+//! ```
+
+use std::time;
+
+use duktape_sys;
+
+use nul_str;
+use {Error, HeapData, JsError};
+
+/// A limit of a context that a script ran into.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Limit {
+    /// An evaluation or call ran longer than the timeout from `ContextBuilder::with_timeout`.
+    Timeout,
+    /// The allocator from `ContextBuilder::with_allocator` refused an allocation.
+    Memory,
+    /// A script scheduled more timers than `ContextBuilder::with_timers` allows.
+    Timers,
+    /// A value didn't fit into the quota of `ContextBuilder::with_storage`.
+    StorageQuota,
+}
+
+/// Receives the lifecycle events of a context.
+pub trait ContextHooks {
+    /// Called before evaluating code, with its file name.
+    fn eval_start(&self, file_name: &str) {
+        let _ = file_name;
+    }
+
+    /// Called after evaluating code, with its file name, how long it took, and the error if it
+    /// failed.
+    fn eval_end(&self, file_name: &str, duration: time::Duration, error: Option<&Error>) {
+        let _ = (file_name, duration, error);
+    }
+
+    /// Called before calling a function, with its name.  The name is empty for calls of
+    /// functions through a `Reference`, which don't have a name.
+    fn call_start(&self, function: &str) {
+        let _ = function;
+    }
+
+    /// Called after calling a function, with its name, how long the call took, and the error if
+    /// it failed.
+    fn call_end(&self, function: &str, duration: time::Duration, error: Option<&Error>) {
+        let _ = (function, duration, error);
+    }
+
+    /// Called when `require` loads the module with the resolved id.
+    fn module_load(&self, id: &str) {
+        let _ = id;
+    }
+
+    /// Called after an explicit garbage collection, with how long it took.
+    fn gc(&self, duration: time::Duration) {
+        let _ = duration;
+    }
+
+    /// Called whenever a script throws, before the error is caught.
+    fn error_thrown(&self, error: &JsError) {
+        let _ = error;
+    }
+
+    /// Called when a script runs into a limit of the context.  The operation that ran into it
+    /// usually fails, unless the script handles the error.
+    fn limit_exceeded(&self, limit: Limit) {
+        let _ = limit;
+    }
+}
+
+/// Reports thrown errors to the hooks of the context, if it has any.
+pub(crate) unsafe fn setup(ctx: *mut duktape_sys::duk_context) {
+    use duktape_sys::*;
+
+    if heap_hooks(ctx).is_empty() {
+        return;
+    }
+    duk_get_global_string(ctx, nul_str(b"Duktape\0"));
+    duk_push_c_function(ctx, Some(error_thrown), 1);
+    duk_put_prop_string(ctx, -2, nul_str(b"errThrow\0"));
+    duk_pop(ctx);
+}
+
+/// Calls the hooks of the heap of the context.
+pub(crate) unsafe fn notify<F>(ctx: *mut duktape_sys::duk_context, event: F)
+    where F: Fn(&dyn ContextHooks)
+{
+    for hooks in heap_hooks(ctx) {
+        event(&**hooks);
+    }
+}
+
+unsafe fn heap_hooks(ctx: *mut duktape_sys::duk_context) -> &'static [Box<dyn ContextHooks>] {
+    let mut funcs = duktape_sys::duk_memory_functions::default();
+    duktape_sys::duk_get_memory_functions(ctx, &mut funcs);
+    &(*(funcs.udata as *mut HeapData)).hooks
+}
+
+/// `Duktape.errThrow(error)`, returns the error unchanged.
+unsafe extern "C" fn error_thrown(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    let error = JsError::get(ctx, 0);
+    notify(ctx, |hooks| hooks.error_thrown(&error));
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::cell;
+    use std::rc;
+
+    use super::*;
+    use allocator;
+    use {Context, Value};
+
+    struct Recorder {
+        events: rc::Rc<cell::RefCell<Vec<String>>>,
+    }
+
+    impl ContextHooks for Recorder {
+        fn eval_start(&self, file_name: &str) {
+            self.events.borrow_mut().push(format!("eval {}", file_name));
+        }
+
+        fn eval_end(&self, file_name: &str, _: time::Duration, error: Option<&Error>) {
+            self.events.borrow_mut().push(format!("eval end {} {}", file_name, error.is_none()));
+        }
+
+        fn call_start(&self, function: &str) {
+            self.events.borrow_mut().push(format!("call {}", function));
+        }
+
+        fn call_end(&self, function: &str, _: time::Duration, error: Option<&Error>) {
+            self.events.borrow_mut().push(format!("call end {} {}", function, error.is_none()));
+        }
+
+        fn module_load(&self, id: &str) {
+            self.events.borrow_mut().push(format!("module {}", id));
+        }
+
+        fn gc(&self, _: time::Duration) {
+            self.events.borrow_mut().push("gc".to_owned());
+        }
+
+        fn error_thrown(&self, error: &JsError) {
+            self.events.borrow_mut().push(format!("thrown {}", error.message));
+        }
+
+        fn limit_exceeded(&self, limit: Limit) {
+            self.events.borrow_mut().push(format!("limit {:?}", limit));
+        }
+    }
+
+    fn recorder() -> (Box<Recorder>, rc::Rc<cell::RefCell<Vec<String>>>) {
+        let events = rc::Rc::new(cell::RefCell::new(Vec::new()));
+        (Box::new(Recorder { events: events.clone() }), events)
+    }
+
+    #[test]
+    fn lifecycle_events() {
+        let _ = env_logger::init();
+        let (hooks, events) = recorder();
+        let ctx = Context::builder()
+            .with_hooks(hooks)
+            .with_timers(1)
+            .with_module_resolver(Box::new(|id, _| id))
+            .with_module_loader(Box::new(|_| Some("exports.answer = 42;".to_owned())))
+            .build();
+        ctx.eval_string("function answer() { return require('answer').answer; }
+                         function fail() { try { throw new Error('caught'); } catch (e) {}
+                                           throw new TypeError('uncaught'); }")
+            .unwrap();
+        assert_eq!(Value::Number(42.0), ctx.call("answer", ()).unwrap().to_value());
+        assert!(ctx.call("fail", ()).is_err());
+        assert!(ctx.eval_string("setTimeout(String, 0); setTimeout(String, 0);").is_err());
+        ctx.gc();
+        assert_eq!(vec!["eval eval",
+                        "eval end eval true",
+                        "call answer",
+                        "module answer",
+                        "call end answer true",
+                        "call fail",
+                        "thrown caught",
+                        "thrown uncaught",
+                        "call end fail false",
+                        "eval eval",
+                        "limit Timers",
+                        "thrown too many outstanding timers",
+                        "eval end eval false",
+                        "gc"],
+                   *events.borrow());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn memory_limit() {
+        let _ = env_logger::init();
+        let (hooks, events) = recorder();
+        let ctx = Context::builder()
+            .with_allocator(Box::new(allocator::Budget::new(1024 * 1024)))
+            .with_hooks(hooks)
+            .build();
+        assert!(ctx.eval_string("new ArrayBuffer(4 * 1024 * 1024)").is_err());
+        assert!(events.borrow().contains(&"limit Memory".to_owned()));
+        events.borrow_mut().clear();
+        ctx.eval_string("new ArrayBuffer(1024)").unwrap();
+        assert!(!events.borrow().contains(&"limit Memory".to_owned()));
+        ctx.assert_clean();
+    }
+}
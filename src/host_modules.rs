@@ -0,0 +1,85 @@
+//! Native modules that scripts can `require` with a `host:` id, like `host:fs`.
+//!
+//! Host modules bypass the module resolver and loader of the context: their exports are built
+//! natively when the context is set up, and kept in the heap stash until a script requires them.
+
+use std::os;
+
+use duktape_sys;
+
+use nul_str;
+
+/// The key of the heap stash object that holds the exports of every host module.
+const STASH_KEY: &[u8] = b"hostModules\0";
+
+/// The key of the heap stash object that holds the functions that throw in place of loading the
+/// host modules that the context denies.
+const DENIED_KEY: &[u8] = b"deniedHostModules\0";
+
+/// Loaded in place of host modules that the context doesn't have.  The module id can't be spliced
+/// into the source, so it is read from the module object.
+const UNKNOWN: &[u8] = b"throw new Error('unknown host module: ' + module.id);";
+
+/// The prefix of the ids of host modules.
+pub(crate) const PREFIX: &str = "host:";
+
+/// Registers the object on top of the stack as the exports of the host module with the specified
+/// id, and pops it.
+pub(crate) unsafe fn register(ctx: *mut duktape_sys::duk_context, id: &[u8]) {
+    put(ctx, STASH_KEY, id);
+}
+
+/// Denies the host module with the specified id, so that requiring it calls the function on top
+/// of the stack, which is expected to throw, instead.  Pops the function.
+pub(crate) unsafe fn deny(ctx: *mut duktape_sys::duk_context, id: &[u8]) {
+    put(ctx, DENIED_KEY, id);
+}
+
+/// Moves the value on top of the stack into the heap stash object with the specified key.
+unsafe fn put(ctx: *mut duktape_sys::duk_context, key: &[u8], id: &[u8]) {
+    use duktape_sys::*;
+
+    duk_push_heap_stash(ctx);
+    if duk_get_prop_string(ctx, -1, nul_str(key)) == 0 {
+        duk_pop(ctx);
+        duk_push_object(ctx);
+        duk_dup_top(ctx);
+        duk_put_prop_string(ctx, -3, nul_str(key));
+    }
+    duk_dup(ctx, -3);
+    duk_put_prop_string(ctx, -2, nul_str(id));
+    duk_pop_3(ctx);
+}
+
+/// Loads a host module for the module loader, whose arguments `(id, exports, module)` are on the
+/// stack.  Either sets `module.exports` and returns 0, throws if the module is denied, or pushes
+/// source code that throws and returns 1.
+pub(crate) unsafe fn load(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    use duktape_sys::*;
+
+    duk_push_heap_stash(ctx);
+    duk_get_prop_string(ctx, -1, nul_str(STASH_KEY));
+    if duk_is_object(ctx, -1) != 0 {
+        duk_dup(ctx, 0);
+        duk_get_prop(ctx, -2);
+        if duk_is_object(ctx, -1) != 0 {
+            duk_put_prop_string(ctx, 2, nul_str(b"exports\0"));
+            duk_pop_2(ctx);
+            return 0;
+        }
+        duk_pop(ctx);
+    }
+    duk_pop(ctx);
+    duk_get_prop_string(ctx, -1, nul_str(DENIED_KEY));
+    if duk_is_object(ctx, -1) != 0 {
+        duk_dup(ctx, 0);
+        duk_get_prop(ctx, -2);
+        if duk_is_callable(ctx, -1) != 0 {
+            duk_call(ctx, 0);
+        }
+        duk_pop(ctx);
+    }
+    duk_pop_2(ctx);
+    duk_push_lstring(ctx, UNKNOWN.as_ptr() as *const os::raw::c_char, UNKNOWN.len());
+    1
+}
@@ -0,0 +1,456 @@
+//! Minimal `Intl.NumberFormat` and `Intl.DateTimeFormat` classes for scripts, since many libraries
+//! assume that `Intl` exists.
+//!
+//! Every locale resolves to `en-US`.  `NumberFormat` supports the `decimal`, `percent` and
+//! `currency` styles, `currency` (with symbols for a few common currencies), `useGrouping`, and
+//! `minimumFractionDigits` and `maximumFractionDigits`.  `DateTimeFormat` supports the `weekday`,
+//! `year`, `month`, `day`, `hour`, `minute`, `second` and `hour12` options, and the `timeZone`
+//! option only for UTC; without it, dates are formatted in local time.  Both have `format`,
+//! `resolvedOptions` and `supportedLocalesOf`, and `Number.prototype.toLocaleString` uses
+//! `NumberFormat`.  The formatting itself happens in Rust.
+
+use std::os;
+
+use duktape_sys;
+
+use nul_str;
+use strings;
+
+/// Defines `Intl` on top of the native formatting functions.
+const SETUP: &[u8] = br#"(function (formatNumber, formatDate) {
+  var symbols = {USD: '$', EUR: '\u20ac', GBP: '\u00a3', JPY: '\u00a5', INR: '\u20b9'};
+  var utc = {UTC: true, GMT: true, 'Etc/UTC': true, 'Etc/GMT': true};
+
+  function supportedLocalesOf(locales) {
+    return [].concat(locales === undefined ? [] : locales).map(String).filter(function (l) {
+      return /^en(-|$)/i.test(l);
+    });
+  }
+  function digits(options, name, fallback) {
+    var value = options[name] === undefined ? fallback : Number(options[name]);
+    if (!(value >= 0 && value <= 20)) {
+      throw new RangeError(name + ' value is out of range');
+    }
+    return Math.floor(value);
+  }
+  function choice(options, name, allowed) {
+    var value = options[name];
+    if (value !== undefined && allowed.indexOf(String(value)) < 0) {
+      throw new RangeError('invalid value for ' + name + ': ' + value);
+    }
+    return value === undefined ? undefined : String(value);
+  }
+
+  function NumberFormat(locales, options) {
+    if (!(this instanceof NumberFormat)) {
+      return new NumberFormat(locales, options);
+    }
+    options = options || {};
+    var style = choice(options, 'style', ['decimal', 'percent', 'currency']) || 'decimal';
+    var currency;
+    if (style === 'currency') {
+      if (options.currency === undefined) {
+        throw new TypeError('currency code is required with currency style');
+      }
+      currency = String(options.currency).toUpperCase();
+    }
+    var fraction = style === 'currency' ? (currency === 'JPY' ? 0 : 2) : 0;
+    var min = digits(options, 'minimumFractionDigits', fraction);
+    var max = digits(options, 'maximumFractionDigits',
+                     Math.max(min, style === 'decimal' ? 3 : fraction));
+    if (max < min) {
+      throw new RangeError('maximumFractionDigits value is out of range');
+    }
+    this._options = {
+      locale: 'en-US', numberingSystem: 'latn', style: style, currency: currency,
+      minimumFractionDigits: min, maximumFractionDigits: max,
+      useGrouping: options.useGrouping === undefined ? true : !!options.useGrouping
+    };
+    if (currency === undefined) {
+      delete this._options.currency;
+    }
+  }
+  NumberFormat.prototype.format = function (value) {
+    var o = this._options;
+    var n = Number(value);
+    var text = formatNumber(Math.abs(o.style === 'percent' ? n * 100 : n),
+                            o.minimumFractionDigits, o.maximumFractionDigits, o.useGrouping);
+    if (o.style === 'percent') {
+      text += '%';
+    } else if (o.style === 'currency') {
+      text = symbols.hasOwnProperty(o.currency) ? symbols[o.currency] + text
+                                                : o.currency + '\u00a0' + text;
+    }
+    return (n < 0 || (n === 0 && 1 / n < 0) ? '-' : '') + text;
+  };
+  NumberFormat.prototype.resolvedOptions = function () {
+    var copy = {};
+    for (var key in this._options) {
+      copy[key] = this._options[key];
+    }
+    return copy;
+  };
+  NumberFormat.supportedLocalesOf = supportedLocalesOf;
+
+  function DateTimeFormat(locales, options) {
+    if (!(this instanceof DateTimeFormat)) {
+      return new DateTimeFormat(locales, options);
+    }
+    options = options || {};
+    var text = ['narrow', 'short', 'long'];
+    var numeric = ['numeric', '2-digit'];
+    var o = {
+      weekday: choice(options, 'weekday', text),
+      year: choice(options, 'year', numeric),
+      month: choice(options, 'month', numeric.concat(text)),
+      day: choice(options, 'day', numeric),
+      hour: choice(options, 'hour', numeric),
+      minute: choice(options, 'minute', numeric),
+      second: choice(options, 'second', numeric)
+    };
+    if (!o.weekday && !o.year && !o.month && !o.day && !o.hour && !o.minute && !o.second) {
+      o.year = o.month = o.day = 'numeric';
+    }
+    o.hour12 = options.hour12 === undefined ? true : !!options.hour12;
+
+    var zone = options.timeZone;
+    if (zone !== undefined && !utc.hasOwnProperty(String(zone))) {
+      throw new RangeError('unsupported time zone: ' + zone);
+    }
+    this._utc = zone !== undefined;
+    this._format = o;
+    this._options = {locale: 'en-US', calendar: 'gregory', numberingSystem: 'latn'};
+    if (this._utc) {
+      this._options.timeZone = 'UTC';
+    } else {
+      // Without time zone data, name the current offset like the IANA Etc/GMT zones
+      var offset = new Date().getTimezoneOffset();
+      this._options.timeZone = offset === 0 ? 'UTC'
+        : offset % 60 === 0 ? 'Etc/GMT' + (offset > 0 ? '+' : '-') + Math.abs(offset / 60)
+        : undefined;
+    }
+    for (var key in o) {
+      if (o[key] !== undefined && (key !== 'hour12' || o.hour)) {
+        this._options[key] = o[key];
+      }
+    }
+  }
+  DateTimeFormat.prototype.format = function (date) {
+    var time = date === undefined ? Date.now() : Number(date instanceof Date ? date.getTime() : date);
+    if (!isFinite(time)) {
+      throw new RangeError('Invalid time value');
+    }
+    var offset = this._utc ? 0 : -new Date(time).getTimezoneOffset();
+    return formatDate(time, offset, this._format);
+  };
+  DateTimeFormat.prototype.resolvedOptions = NumberFormat.prototype.resolvedOptions;
+  DateTimeFormat.supportedLocalesOf = supportedLocalesOf;
+
+  Number.prototype.toLocaleString = function (locales, options) {
+    return new NumberFormat(locales, options).format(this);
+  };
+
+  return {
+    NumberFormat: NumberFormat,
+    DateTimeFormat: DateTimeFormat,
+    getCanonicalLocales: function (locales) {
+      return [].concat(locales === undefined ? [] : locales).map(String);
+    }
+  };
+})"#;
+
+const MONTHS: [&str; 12] = ["January", "February", "March", "April", "May", "June", "July",
+                            "August", "September", "October", "November", "December"];
+const WEEKDAYS: [&str; 7] = ["Sunday", "Monday", "Tuesday", "Wednesday", "Thursday", "Friday",
+                             "Saturday"];
+
+/// How to format a date, as resolved by `DateTimeFormat`.
+struct DateOptions {
+    weekday: Option<String>,
+    year: Option<String>,
+    month: Option<String>,
+    day: Option<String>,
+    hour: Option<String>,
+    minute: Option<String>,
+    second: Option<String>,
+    hour12: bool,
+}
+
+/// The fields of a point in time.
+struct DateFields {
+    year: i64,
+    /// 1 to 12
+    month: u32,
+    day: u32,
+    /// 0 (Sunday) to 6
+    weekday: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+}
+
+/// Formats a non-negative number with the specified number of fraction digits, and optionally
+/// with thousands separators.
+fn format_number(n: f64, min_fraction: usize, max_fraction: usize, grouping: bool) -> String {
+    if n.is_nan() {
+        return "NaN".to_owned();
+    }
+    if n.is_infinite() {
+        return "\u{221e}".to_owned();
+    }
+    let fixed = format!("{:.*}", max_fraction, n);
+    let (integer, fraction) = match fixed.find('.') {
+        Some(dot) => (&fixed[..dot], &fixed[dot + 1..]),
+        None => (&fixed[..], ""),
+    };
+    let fraction = &fraction[..fraction.trim_end_matches('0').len().max(min_fraction)];
+
+    let mut result = String::new();
+    for (i, c) in integer.chars().enumerate() {
+        if grouping && i > 0 && (integer.len() - i) % 3 == 0 {
+            result.push(',');
+        }
+        result.push(c);
+    }
+    if !fraction.is_empty() {
+        result.push('.');
+        result.push_str(fraction);
+    }
+    result
+}
+
+/// Splits milliseconds since the epoch, shifted into the time zone, into fields.
+fn date_fields(time: f64, offset_minutes: i64) -> DateFields {
+    let ms = time.floor() as i64 + offset_minutes * 60 * 1000;
+    let days = ms.div_euclid(86_400_000);
+    let seconds = ms.rem_euclid(86_400_000) / 1000;
+
+    // The civil calendar from the days since the epoch, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    DateFields {
+        year,
+        month,
+        day,
+        weekday: (days + 4).rem_euclid(7) as u32,
+        hour: (seconds / 3600) as u32,
+        minute: (seconds / 60 % 60) as u32,
+        second: (seconds % 60) as u32,
+    }
+}
+
+fn numeric(value: i64, style: &str) -> String {
+    if style == "2-digit" {
+        format!("{:02}", value.rem_euclid(100))
+    } else {
+        value.to_string()
+    }
+}
+
+fn text(name: &str, style: &str) -> String {
+    match style {
+        "narrow" => name.chars().take(1).collect(),
+        "short" => name.chars().take(3).collect(),
+        _ => name.to_owned(),
+    }
+}
+
+/// Formats a date like `en-US` does.
+fn format_date(time: f64, offset_minutes: i64, options: &DateOptions) -> String {
+    let f = date_fields(time, offset_minutes);
+
+    let mut date = String::new();
+    if let Some(style) = options.month.as_deref().filter(|&m| m != "numeric" && m != "2-digit") {
+        // Like `Tuesday, January 2, 2024`
+        date.push_str(&text(MONTHS[f.month as usize - 1], style));
+        if let Some(ref day) = options.day {
+            date.push(' ');
+            date.push_str(&numeric(f.day as i64, day));
+        }
+        if let Some(ref year) = options.year {
+            date.push_str(if options.day.is_some() { ", " } else { " " });
+            date.push_str(&numeric(f.year, year));
+        }
+    } else {
+        // Like `1/2/2024`
+        let parts = [(&options.month, f.month as i64), (&options.day, f.day as i64), (&options.year, f.year)];
+        date = parts.iter()
+            .filter_map(|(style, value)| style.as_ref().map(|s| numeric(*value, s)))
+            .collect::<Vec<_>>()
+            .join("/");
+    }
+    if let Some(ref weekday) = options.weekday {
+        let name = text(WEEKDAYS[f.weekday as usize], weekday);
+        date = if date.is_empty() { name } else { format!("{}, {}", name, date) };
+    }
+
+    let mut time = String::new();
+    if let Some(ref hour) = options.hour {
+        let h = if options.hour12 { (f.hour + 11) % 12 + 1 } else { f.hour };
+        time.push_str(&numeric(h as i64, hour));
+    }
+    for (style, value) in [(&options.minute, f.minute), (&options.second, f.second)] {
+        if style.is_some() {
+            if !time.is_empty() {
+                time.push(':');
+            }
+            time.push_str(&format!("{:02}", value));
+        }
+    }
+    if options.hour.is_some() && options.hour12 {
+        time.push_str(if f.hour < 12 { " AM" } else { " PM" });
+    }
+
+    match (date.is_empty(), time.is_empty()) {
+        (false, false) => format!("{}, {}", date, time),
+        (true, _) => time,
+        (_, true) => date,
+    }
+}
+
+/// Defines the global `Intl` object, unless there already is one.
+pub(crate) unsafe fn setup(ctx: *mut duktape_sys::duk_context) {
+    use duktape_sys::*;
+
+    if duk_get_global_string(ctx, nul_str(b"Intl\0")) != 0 {
+        duk_pop(ctx);
+        return;
+    }
+    duk_pop(ctx);
+
+    let ret = duk_peval_lstring(ctx, SETUP.as_ptr() as *const os::raw::c_char, SETUP.len());
+    assert_eq!(0, ret, "failed to compile Intl");
+    duk_push_c_function(ctx, Some(number), 4);
+    duk_push_c_function(ctx, Some(date), 3);
+    let ret = duk_pcall(ctx, 2);
+    assert_eq!(0, ret, "failed to set up Intl");
+    duk_put_global_string(ctx, nul_str(b"Intl\0"));
+}
+
+/// `formatNumber(n, minFraction, maxFraction, grouping)`
+unsafe extern "C" fn number(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    use duktape_sys::*;
+
+    let formatted = format_number(duk_get_number(ctx, 0),
+                                  duk_get_uint(ctx, 1) as usize,
+                                  duk_get_uint(ctx, 2) as usize,
+                                  duk_to_boolean(ctx, 3) != 0);
+    strings::push(ctx, &formatted);
+    1
+}
+
+/// `formatDate(time, offsetMinutes, options)`
+unsafe extern "C" fn date(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    use duktape_sys::*;
+
+    let option = |name: &[u8]| {
+        duk_get_prop_string(ctx, 2, nul_str(name));
+        let value = if duk_is_string(ctx, -1) != 0 {
+            Some(strings::get(ctx, -1))
+        } else {
+            None
+        };
+        duk_pop(ctx);
+        value
+    };
+    let options = DateOptions {
+        weekday: option(b"weekday\0"),
+        year: option(b"year\0"),
+        month: option(b"month\0"),
+        day: option(b"day\0"),
+        hour: option(b"hour\0"),
+        minute: option(b"minute\0"),
+        second: option(b"second\0"),
+        hour12: {
+            duk_get_prop_string(ctx, 2, nul_str(b"hour12\0"));
+            let hour12 = duk_to_boolean(ctx, -1) != 0;
+            duk_pop(ctx);
+            hour12
+        },
+    };
+    let formatted = format_date(duk_get_number(ctx, 0), duk_get_int(ctx, 1) as i64, &options);
+    strings::push(ctx, &formatted);
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use super::*;
+    use {Context, Value};
+
+    #[test]
+    fn number_format() {
+        let _ = env_logger::init();
+        assert_eq!("1,234,567.891", format_number(1234567.8912, 0, 3, true));
+        assert_eq!("1234.50", format_number(1234.5, 2, 2, false));
+        assert_eq!("0.1", format_number(0.1, 0, 3, true));
+        assert_eq!("3", format_number(2.5001, 0, 0, true));
+
+        let ctx = Context::builder().build();
+        let result = ctx.eval_string(r"
+          [new Intl.NumberFormat('de-DE').format(-1234.5),
+           new Intl.NumberFormat('en-US', {style: 'currency', currency: 'usd'}).format(-3.5),
+           Intl.NumberFormat(undefined, {style: 'currency', currency: 'CHF'}).format(1000),
+           new Intl.NumberFormat('en', {style: 'percent'}).format(0.256),
+           new Intl.NumberFormat('en', {maximumFractionDigits: 1, useGrouping: false})
+             .format(98765.43),
+           (1e6).toLocaleString(),
+           new Intl.NumberFormat().resolvedOptions().locale,
+           Intl.NumberFormat.supportedLocalesOf(['en-GB', 'fr']).join()]
+        ")
+            .unwrap();
+        assert_eq!(Value::Array(vec![Value::String("-1,234.5".to_owned()),
+                                     Value::String("-$3.50".to_owned()),
+                                     Value::String("CHF\u{a0}1,000.00".to_owned()),
+                                     Value::String("26%".to_owned()),
+                                     Value::String("98765.4".to_owned()),
+                                     Value::String("1,000,000".to_owned()),
+                                     Value::String("en-US".to_owned()),
+                                     Value::String("en-GB".to_owned())]),
+                   result.to_value());
+        assert!(ctx.eval_string("new Intl.NumberFormat('en', {style: 'currency'})").is_err());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn date_time_format() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let result = ctx.eval_string(r"
+          var d = Date.UTC(2024, 0, 2, 15, 4, 5);
+          function f(options) {
+            options.timeZone = 'UTC';
+            return new Intl.DateTimeFormat('en-US', options).format(d);
+          }
+          [f({}), f({year: 'numeric', month: 'long', day: 'numeric', weekday: 'long'}),
+           f({month: 'short', year: 'numeric'}), f({hour: 'numeric', minute: '2-digit'}),
+           f({year: '2-digit', month: '2-digit', day: '2-digit', hour: '2-digit', minute: '2-digit',
+              second: '2-digit', hour12: false}),
+           new Intl.DateTimeFormat('en', {timeZone: 'UTC'}).format(new Date(-86400000)),
+           new Intl.DateTimeFormat('en', {timeZone: 'UTC'}).resolvedOptions().timeZone]
+        ")
+            .unwrap();
+        assert_eq!(Value::Array(vec![Value::String("1/2/2024".to_owned()),
+                                     Value::String("Tuesday, January 2, 2024".to_owned()),
+                                     Value::String("Jan 2024".to_owned()),
+                                     Value::String("3:04 PM".to_owned()),
+                                     Value::String("01/02/24, 15:04:05".to_owned()),
+                                     Value::String("12/31/1969".to_owned()),
+                                     Value::String("UTC".to_owned())]),
+                   result.to_value());
+        assert!(ctx.eval_string("new Intl.DateTimeFormat('en', {timeZone: 'Mars/Base'})").is_err());
+        assert!(ctx.eval_string("new Intl.DateTimeFormat().format(NaN)").is_err());
+        ctx.assert_clean();
+    }
+}
@@ -6,55 +6,316 @@
 //!
 //!   * Loading code.
 //!   * Calling functions and getting their result.
-//!
-//! Other use-cases (like exposing Rust functions to JS) are not yet
-//! implemented.
+//!   * Exposing Rust functions to scripts (see the `native` module).
 //!
 //! [1]: http://duktape.org/
 
+#[cfg(feature = "arbitrary")]
+extern crate arbitrary;
+#[cfg(feature = "chrono")]
+extern crate chrono;
 extern crate duktape_sys;
 #[macro_use]
 extern crate error_chain;
+#[cfg(feature = "crypto")]
+extern crate getrandom;
+#[cfg(feature = "crypto")]
+extern crate hmac;
+#[cfg(feature = "libc")]
+extern crate libc;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "crypto")]
+extern crate sha2;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "url")]
+extern crate url;
 
 #[cfg(feature = "logging")]
 #[macro_use]
 extern crate log;
 
+#[cfg(all(feature = "exec", target_arch = "wasm32"))]
+compile_error!("the exec feature needs child processes and threads, which WebAssembly lacks");
+
+/// The exact version of the `duktape-sys` crate that this crate is built on, for raw code that is
+/// mixed with the safe API (see `Context::with_raw`).  Use this instead of a `duktape-sys`
+/// dependency of your own, which could resolve to a different version.
+///
+/// Requires the `unstable-raw` feature, since it follows the bundled Duktape version, without the
+/// stability guarantees of the rest of this crate.
+#[cfg(feature = "unstable-raw")]
+pub mod sys {
+    pub use duktape_sys::*;
+}
+
+use std::borrow;
+use std::cell;
+use std::cmp;
 use std::collections;
+use std::error;
 use std::ffi;
 use std::fmt;
+use std::hash;
+use std::io;
 use std::mem;
+#[cfg(feature = "debugger")]
+use std::net;
 use std::os;
 use std::path;
 use std::ptr;
+use std::rc;
+use std::result;
 use std::slice;
 use std::str;
 use std::sync::atomic;
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+
+pub mod allocator;
+pub mod bindings;
+mod buffers;
+pub mod build_info;
+pub mod builders;
+pub mod bundle;
+mod call_cache;
+pub mod census;
+#[cfg(feature = "clock")]
+pub mod clock;
+mod codec;
+pub mod conversion;
+#[cfg(feature = "console")]
+pub mod console;
+#[cfg(feature = "debugger")]
+pub mod coverage;
+#[cfg(feature = "crypto")]
+mod crypto;
+#[cfg(feature = "chrono")]
+pub mod dates;
+pub mod db;
+#[cfg(feature = "serde")]
+pub mod de;
+#[cfg(feature = "debugger")]
+pub mod debugger;
+#[cfg(feature = "encoding")]
+mod encoding;
+mod event_loop;
+pub mod events;
+#[cfg(feature = "exec")]
+pub mod exec;
+#[cfg(feature = "fetch")]
+pub mod fetch;
+pub mod filesystem;
+mod finalizers;
+mod functions;
+pub mod fuzzing;
+pub mod hooks;
+mod host_modules;
+#[cfg(feature = "intl")]
+mod intl;
+pub mod loading;
+pub mod metrics;
+pub mod native;
+mod paths;
+mod performance;
+pub mod plugins;
+pub mod polyfills;
+mod pool;
+pub mod prelude;
+pub mod process;
+#[cfg(feature = "profiler")]
+pub mod profiler;
+pub mod project;
+pub mod proxies;
+pub mod raw;
+pub mod recording;
+pub mod repl;
+pub mod report;
+pub mod signatures;
+pub mod snapshot;
+pub mod source_map;
+pub mod sources;
+mod spans;
+pub mod storage;
+mod strings;
+pub mod structured_clone;
+#[macro_use]
+pub mod testing;
+#[cfg(feature = "url")]
+mod urls;
+mod userdata;
+#[cfg(feature = "ws")]
+pub mod ws;
+
+/// Data that is attached to the Duktape heap, and handed to the allocator and executor callbacks.
+struct HeapData {
+    allocator: Option<Box<dyn allocator::Allocator>>,
+    /// Set when the allocator refuses an allocation, until the limit is reported to the hooks.
+    allocation_failed: bool,
+    /// What `Date` reads the current time from, if not the system time.
+    #[cfg(feature = "clock")]
+    clock: Option<Box<dyn clock::Clock>>,
+    #[cfg(feature = "profiler")]
+    sampler: Option<profiler::Sampler>,
+    /// Set by a statement hook to abort execution at the next executor interrupt.
+    #[cfg(feature = "debugger")]
+    abort_requested: bool,
+    /// How long an evaluation or call may run, if the context was built with `with_timeout`.
+    #[cfg(feature = "timeout")]
+    timeout: Option<time::Duration>,
+    /// When the running evaluation or call times out, at the next executor interrupt.
+    #[cfg(feature = "timeout")]
+    deadline: Option<time::Instant>,
+    /// When the context was created, the origin of `performance.now()`.
+    time_origin: time::Instant,
+    /// How Javascript values are converted into `Value`s.
+    conversion: cell::Cell<conversion::ConversionOptions>,
+    /// The timers of the context, if it was built with `with_timers`.
+    event_loop: Option<cell::RefCell<event_loop::EventLoop>>,
+    /// Where `host.events.emit` delivers events, see `Context::events`.
+    event_senders: cell::RefCell<Vec<mpsc::Sender<events::Event>>>,
+    /// The ids of the `Function` handles of the context.
+    functions: rc::Rc<functions::Handles>,
+    /// The Rust finalizers of the objects of the context.
+    finalizers: finalizers::Registry,
+    /// The userdata of the objects of the context.
+    userdata: userdata::Registry,
+    /// The Rust functions of the native functions of the context.
+    native_functions: native::Registry,
+    /// The HTTP client and allowlist of `fetch`, if the context was built with `with_fetch`.
+    #[cfg(feature = "fetch")]
+    fetch: Option<fetch::Fetcher>,
+    /// The sandbox of `host:fs`, if the context was built with `with_filesystem`.
+    filesystem: Option<filesystem::Sandbox>,
+    /// The driver and scope of `host:db`, if the context was built with `with_database`.
+    database: Option<db::Database>,
+    /// The store of `host:storage`, if the context was built with `with_storage`.
+    storage: Option<storage::Storage>,
+    /// The transport and sockets of `host:ws`, if the context was built with `with_websockets`.
+    #[cfg(feature = "ws")]
+    websockets: Option<ws::Sockets>,
+    /// The commands that `host:exec` may run, if the context was built with `with_exec`.
+    #[cfg(feature = "exec")]
+    exec: Option<exec::Policy>,
+    /// Where the console writes to, if not to the `log` crate.
+    #[cfg(feature = "console")]
+    console_sink: Option<Box<dyn console::ConsoleSink>>,
+    /// The hooks that receive the lifecycle events of the context.
+    hooks: Vec<Box<dyn hooks::ContextHooks>>,
+}
 
 pub type ModuleResolver = Fn(String, String) -> String;
 pub type ModuleLoader = Fn(String) -> Option<String>;
 
 /// A context corresponding to a thread of script execution.
+///
+/// All methods take `&self`: the context keeps its own state consistent across evaluations and
+/// calls, including ones that are nested within each other, so there is no need to wrap it in a
+/// `RefCell`.  The Duktape heap is single-threaded, which is why a context is neither `Send` nor
+/// `Sync`, but several owners within one thread can share it with an `Rc`.
+///
+/// # Examples
+///
+/// ```
+/// use std::rc::Rc;
+///
+/// struct Plugin {
+///     ctx: Rc<duk::Context>,
+/// }
+///
+/// let ctx = Rc::new(duk::Context::new());
+/// let plugins = vec![Plugin { ctx: ctx.clone() }, Plugin { ctx: ctx.clone() }];
+/// ctx.eval_string("var loaded = 0;").unwrap();
+/// for plugin in &plugins {
+///     plugin.ctx.eval_string("loaded++;").unwrap();
+/// }
+/// assert_eq!(duk::Value::Number(2.0), ctx.eval_string("loaded").unwrap().to_value());
+/// ```
 pub struct Context {
     raw: *mut duktape_sys::duk_context,
     next_stash_idx: atomic::AtomicUsize,
+    call_cache: call_cache::CallCache,
     module_resolver: Option<*mut Box<ModuleResolver>>,
     module_loader: Option<*mut Box<ModuleLoader>>,
+    heap_data: *mut HeapData,
+    source_maps: rc::Rc<cell::RefCell<source_map::SourceMapRegistry>>,
+    #[cfg(feature = "debugger")]
+    debugger: cell::Cell<Option<*mut debugger::Session>>,
+    #[cfg(feature = "debugger")]
+    inspector: cell::RefCell<Option<debugger::Inspector>>,
+    metrics: Option<Box<dyn metrics::Metrics>>,
+    recorder: cell::RefCell<Option<recording::Recorder>>,
+    reporter: Option<report::Reporter>,
+    verifier: Option<Box<dyn signatures::Verifier>>,
 }
 
 #[derive(Default)]
 pub struct ContextBuilder {
     module_resolver: Option<Box<ModuleResolver>>,
     module_loader: Option<Box<ModuleLoader>>,
+    metrics: Option<Box<dyn metrics::Metrics>>,
+    error_sink: Option<Box<dyn report::ErrorSink>>,
+    verifier: Option<Box<dyn signatures::Verifier>>,
+    hooks: Vec<Box<dyn hooks::ContextHooks>>,
+    allocator: Option<Box<dyn allocator::Allocator>>,
+    #[cfg(feature = "clock")]
+    clock: Option<Box<dyn clock::Clock>>,
+    compact_builtins: bool,
+    max_timers: Option<usize>,
+    #[cfg(feature = "fetch")]
+    fetcher: Option<fetch::Fetcher>,
+    filesystem: Option<filesystem::Sandbox>,
+    process_info: Option<process::ProcessInfo>,
+    polyfills: polyfills::PolyfillSet,
+    database: Option<db::Database>,
+    storage: Option<storage::Storage>,
+    #[cfg(feature = "console")]
+    console_sink: Option<Box<dyn console::ConsoleSink>>,
+    #[cfg(feature = "ws")]
+    ws_transport: Option<Box<dyn ws::Transport>>,
+    #[cfg(feature = "exec")]
+    exec: Option<exec::Policy>,
+    globals: Vec<(String, Value)>,
+    #[cfg(feature = "timeout")]
+    timeout: Option<time::Duration>,
+    conversion_options: conversion::ConversionOptions,
 }
 
 /// Something that can be used as an argument when calling into Javascript code.
+///
+/// Implemented for `Value`, for booleans, numbers, strings and byte vectors, and for references
+/// and `Cow`s of those, which are pushed straight from the borrowed data.  Passing a large string
+/// or buffer as an `Argument` therefore copies it once, into the Javascript heap, unlike wrapping
+/// it in a `Value` first.
 pub trait Argument {
     /// Pushes this argument to the stack of the specified context.  This requires interaction with
     /// the internals of the context, and is therefore an unsafe operation.
     unsafe fn push_to_context(&self, context: &Context);
 }
 
+/// A list of arguments for `Context::call`, whose elements convert into `Value`s.
+///
+/// Implemented for tuples of up to eight elements, which may have different types, and for
+/// vectors, arrays and slices.
+pub trait IntoArgs {
+    /// Converts the arguments into values, in order.
+    fn into_args(self) -> Vec<Value>;
+}
+
+/// A Rust type that values can be converted into, see `Context::eval_as` and
+/// `Context::call_global_as`.
+///
+/// Implemented for `Value` itself, for booleans, numbers and strings, and for `Option`s (where
+/// `null` and `undefined` are `None`), `Vec`s (from arrays and buffers) and maps with string keys
+/// of those.  With the `serde` feature, `de::Deserialized` converts values into any type that
+/// implements `Deserialize`.
+pub trait FromValue: Sized {
+    /// Converts the value, or fails with `ErrorKind::Conversion` if it has the wrong type.
+    fn from_value(value: Value) -> Result<Self>;
+}
+
 /// A reference to a value that lives within a `Context`.
 #[derive(Debug)]
 pub struct Reference<'a> {
@@ -62,6 +323,75 @@ pub struct Reference<'a> {
     stash_idx: duktape_sys::duk_uarridx_t,
 }
 
+/// A reference to a function that has been resolved ahead of time.
+///
+/// A `FunctionRef` keeps calling the function that it was resolved to, even if the global that it
+/// came from is reassigned.  It isn't faster than `call_global`, which caches the functions of
+/// recently called names: in the `call_bench` example (a release build with the default features,
+/// on a single core of a Xeon server), a call of a trivial function takes about 850 ns with
+/// `FunctionRef::call`, 700 ns with `Context::call_global` and 1.6 µs with
+/// `global_object().call_method`.  Most of that is spent on the `Reference` to the result, which
+/// `Context::call_global_void` skips in about 110 ns.
+#[derive(Debug)]
+pub struct FunctionRef<'a> {
+    reference: Reference<'a>,
+}
+
+/// A function that the host keeps, like a callback that a script registered, see
+/// `Reference::to_function` and `native::Arguments::function`.
+///
+/// Unlike a `FunctionRef`, a handle doesn't borrow the context, so it can be stored along with
+/// the context, or within native functions, and called later with the context that it came from.
+/// The function stays alive until the handle is dropped.
+#[derive(Debug)]
+pub struct Function {
+    id: u32,
+    handles: rc::Rc<functions::Handles>,
+}
+
+/// A reference to a Javascript object, whose properties can be read and written one at a time
+/// without converting the whole object, see `Reference::into_object`.
+#[derive(Debug)]
+pub struct ObjectRef<'a> {
+    reference: Reference<'a>,
+}
+
+/// A view of a Javascript object that only converts the properties that are actually accessed.
+///
+/// This is useful when only a few fields are read from a large object (like a plugin
+/// configuration), since `Reference::to_value` would convert the whole object.  Converted
+/// properties are memoized, so reading the same property twice only converts it once.
+#[derive(Debug)]
+pub struct LazyObject<'a> {
+    reference: Reference<'a>,
+    cache: cell::RefCell<collections::BTreeMap<String, Value>>,
+}
+
+/// Records the arguments of every call of a stubbed or spied-on global function, see
+/// `Context::stub_global` and `Context::spy`.
+#[derive(Debug)]
+pub struct Spy<'a> {
+    calls: Reference<'a>,
+}
+
+/// Values that were left on the value stack of a context, see `Context::check_stack_balanced`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct StackImbalance {
+    /// The number of values on the stack.
+    pub leaked: usize,
+}
+
+/// A reusable list of arguments that have already been converted and pushed into a `Context`.
+///
+/// When calling the same function many times in a tight loop (like a user-supplied formula that
+/// is evaluated for every data row), the arguments that don't change between calls only need to
+/// be converted once; only the varying slots need to be updated with `set`.
+#[derive(Debug)]
+pub struct ArgsBuilder<'a> {
+    array: Reference<'a>,
+    len: usize,
+}
+
 /// A Javascript/Ecmascript value that exists in the Rust world.
 ///
 /// Duktape supports values beyond these, but they don't have good Rust semantics, so they cannot be
@@ -97,12 +427,62 @@ error_chain! {
         Error, ErrorKind, ChainErr, Result;
     }
     links {}
-    foreign_links {}
+    foreign_links {
+        io::Error, Io;
+    }
     errors {
         Js(error: JsError) {
             description("Javascript error")
             display("Javascript error: {}", error.message)
         }
+        Debugger(message: String) {
+            description("debugger error")
+            display("debugger error: {}", message)
+        }
+        InvalidSourceMap(message: String) {
+            description("invalid source map")
+            display("invalid source map: {}", message)
+        }
+        InvalidRecording(message: String) {
+            description("invalid recording")
+            display("invalid recording: {}", message)
+        }
+        DataClone(message: String) {
+            description("value could not be cloned")
+            display("value could not be cloned: {}", message)
+        }
+        Conversion(message: String) {
+            description("value could not be converted")
+            display("value could not be converted: {}", message)
+        }
+        Load(path: String) {
+            description("script could not be loaded")
+            display("failed to load {}", path)
+        }
+        InvalidBundle(message: String) {
+            description("invalid bundle")
+            display("invalid bundle: {}", message)
+        }
+        InvalidSnapshot(message: String) {
+            description("invalid snapshot")
+            display("invalid snapshot: {}", message)
+        }
+        Untrusted(path: String, reason: String) {
+            description("script signature could not be verified")
+            display("untrusted script {}: {}", path, reason)
+        }
+        NonExistent(path: String, missing: String) {
+            description("property does not exist")
+            display("{} does not exist (looking up {})", missing, path)
+        }
+        Policy(violation: plugins::Violation) {
+            description("plugin capability policy violated")
+            display("policy violation: {}", violation)
+        }
+        HeapCreation {
+            description("the Duktape heap could not be created")
+            display("the Duktape heap could not be created")
+        }
     }
 }
 
@@ -146,24 +526,179 @@ pub static mut LAST_LOG_LEVELS: &'static mut [Option<log::LogLevel>; 16] = &mut
 
 impl Context {
     /// Creates a new context.
+    ///
+    /// # Panics
+    ///
+    /// Panics if Duktape can't allocate the heap, see `Context::try_new`.
     pub fn new() -> Context {
-        Context::from_builder(Context::builder())
+        Context::builder().build()
+    }
+
+    /// Creates a new context, or fails with `ErrorKind::HeapCreation` if Duktape can't allocate
+    /// the heap, which is worth handling on targets with very little memory.
+    pub fn try_new() -> Result<Context> {
+        Context::builder().try_build()
     }
 
+    /// Returns a builder for contexts that need more than the defaults of `Context::new`, like
+    /// module loading, host modules, globals provided by the host or a timeout.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::builder()
+    ///     .with_timers(16)
+    ///     .with_global("HOST", duk::Value::String("example".to_owned()))
+    ///     .build();
+    /// assert_eq!(duk::Value::String("example".to_owned()),
+    ///            ctx.eval_string("HOST").unwrap().to_value());
+    /// ```
     pub fn builder() -> ContextBuilder {
         ContextBuilder::default()
     }
 
-    fn from_builder(builder: ContextBuilder) -> Context {
+    fn from_builder(builder: ContextBuilder) -> Result<Context> {
+        #[cfg(feature = "fetch")]
+        let has_fetch = builder.fetcher.is_some();
+        #[cfg(feature = "ws")]
+        let has_websockets = builder.ws_transport.is_some();
+        #[cfg(not(feature = "ws"))]
+        let has_websockets = false;
+        #[cfg(feature = "exec")]
+        let has_exec = builder.exec.is_some();
+        #[cfg(not(feature = "exec"))]
+        let has_exec = false;
+        let has_host_modules = cfg!(feature = "crypto") || builder.filesystem.is_some() ||
+                               builder.process_info.is_some() || builder.database.is_some() ||
+                               builder.storage.is_some() || has_websockets || has_exec;
+        let heap_data = Box::into_raw(Box::new(HeapData {
+            allocator: builder.allocator,
+            allocation_failed: false,
+            #[cfg(feature = "clock")]
+            clock: builder.clock,
+            #[cfg(feature = "profiler")]
+            sampler: None,
+            #[cfg(feature = "debugger")]
+            abort_requested: false,
+            #[cfg(feature = "timeout")]
+            timeout: builder.timeout,
+            #[cfg(feature = "timeout")]
+            deadline: None,
+            time_origin: time::Instant::now(),
+            conversion: cell::Cell::new(builder.conversion_options),
+            event_loop: builder.max_timers.map(|max| cell::RefCell::new(event_loop::EventLoop::new(max))),
+            event_senders: cell::RefCell::new(Vec::new()),
+            functions: rc::Rc::default(),
+            finalizers: cell::RefCell::default(),
+            userdata: cell::RefCell::default(),
+            native_functions: cell::RefCell::default(),
+            #[cfg(feature = "fetch")]
+            fetch: builder.fetcher,
+            filesystem: builder.filesystem,
+            database: builder.database,
+            storage: builder.storage,
+            #[cfg(feature = "ws")]
+            websockets: builder.ws_transport.map(ws::Sockets::new),
+            #[cfg(feature = "exec")]
+            exec: builder.exec,
+            #[cfg(feature = "console")]
+            console_sink: builder.console_sink,
+            hooks: builder.hooks,
+        }));
+        let udata = heap_data as *mut os::raw::c_void;
+
+        #[cfg(feature = "clock")]
+        unsafe {
+            if (*heap_data).clock.is_some() {
+                duktape_sys::DATE_GET_NOW = Some(clock::date_get_now);
+            }
+        }
+
         let raw = unsafe {
-            duktape_sys::duk_create_heap(None, None, None, ptr::null_mut(), Some(fatal_handler))
+            if (*heap_data).allocator.is_some() {
+                duktape_sys::duk_create_heap(Some(allocator::alloc_function),
+                                             Some(allocator::realloc_function),
+                                             Some(allocator::free_function),
+                                             udata,
+                                             Some(fatal_handler))
+            } else {
+                duktape_sys::duk_create_heap(None, None, None, udata, Some(fatal_handler))
+            }
         };
+        if raw.is_null() {
+            drop(unsafe { Box::from_raw(heap_data) });
+            return Err(ErrorKind::HeapCreation.into());
+        }
 
         unsafe {
             Context::setup_logging(raw);
+            codec::setup(raw);
+            performance::setup(raw);
+            structured_clone::setup(raw);
+            events::setup(raw);
+            buffers::setup(raw);
+            polyfills::setup(raw, builder.polyfills);
+            #[cfg(feature = "console")]
+            console::setup(raw);
+            #[cfg(feature = "encoding")]
+            encoding::setup(raw);
+            #[cfg(feature = "crypto")]
+            crypto::setup(raw);
+            #[cfg(feature = "intl")]
+            intl::setup(raw);
+            #[cfg(feature = "url")]
+            urls::setup(raw);
+            if builder.max_timers.is_some() {
+                event_loop::setup(raw);
+            }
+            #[cfg(feature = "fetch")]
+            {
+                if has_fetch {
+                    fetch::setup(raw);
+                }
+            }
+            if (*heap_data).filesystem.is_some() {
+                filesystem::setup(raw);
+                paths::setup(raw);
+            }
+            if let Some(ref info) = builder.process_info {
+                process::setup(raw, info);
+            }
+            if (*heap_data).database.is_some() {
+                db::setup(raw);
+            }
+            if (*heap_data).storage.is_some() {
+                storage::setup(raw);
+            }
+            #[cfg(feature = "ws")]
+            {
+                if has_websockets {
+                    ws::setup(raw);
+                }
+            }
+            #[cfg(feature = "exec")]
+            {
+                if has_exec {
+                    exec::setup(raw);
+                }
+            }
+            hooks::setup(raw);
+        }
+
+        if builder.compact_builtins {
+            unsafe {
+                Context::compact_builtins(raw);
+            }
         }
 
-        let (resolver_ptr, loader_ptr) = match (builder.module_resolver, builder.module_loader) {
+        // Host modules need `require`, even if the host doesn't load any other modules
+        let modules = match (builder.module_resolver, builder.module_loader) {
+            (None, None) if has_host_modules => {
+                (Some(Box::new(|id, _| id) as Box<ModuleResolver>), Some(Box::new(|_| None) as Box<ModuleLoader>))
+            }
+            modules => modules,
+        };
+        let (resolver_ptr, loader_ptr) = match modules {
             (Some(module_resolver), Some(module_loader)) =>
                 unsafe {
                     let resolver_ptr = Box::into_raw(Box::new(module_resolver));
@@ -187,12 +722,38 @@ impl Context {
             (_, _) => (None, None),
         };
 
-        Context {
+        #[cfg(feature = "timeout")]
+        {
+            if builder.timeout.is_some() {
+                install_exec_timeout_check();
+            }
+        }
+
+        let ctx = Context {
             raw: raw,
             next_stash_idx: atomic::ATOMIC_USIZE_INIT,
+            call_cache: call_cache::CallCache::default(),
             module_resolver: resolver_ptr,
             module_loader: loader_ptr,
+            heap_data,
+            source_maps: rc::Rc::new(cell::RefCell::new(source_map::SourceMapRegistry::new())),
+            #[cfg(feature = "debugger")]
+            debugger: cell::Cell::new(None),
+            #[cfg(feature = "debugger")]
+            inspector: cell::RefCell::new(None),
+            metrics: builder.metrics,
+            recorder: cell::RefCell::new(None),
+            reporter: builder.error_sink.map(report::Reporter::new),
+            verifier: builder.verifier,
+        };
+
+        for (name, value) in &builder.globals {
+            unsafe {
+                value.push(ctx.raw);
+                ctx.put_global(name);
+            }
         }
+        Ok(ctx)
     }
 
     #[cfg(feature = "logging")]
@@ -239,6 +800,36 @@ impl Context {
         // No-op
     }
 
+    /// Compacts the global object as well as all built-in objects and their prototypes, and then
+    /// runs a full garbage collection, to release the slack of their property tables.
+    unsafe fn compact_builtins(ctx: *mut duktape_sys::duk_context) {
+        use duktape_sys::*;
+
+        duk_push_global_object(ctx);
+        duk_enum(ctx, -1, DUK_ENUM_OWN_PROPERTIES_ONLY | DUK_ENUM_INCLUDE_NONENUMERABLE);
+        // Stack: [ global enum ]
+
+        while 1 == duk_next(ctx, -1, 1) {
+            // Stack: [ global enum key value ]
+            if 1 == duk_is_object(ctx, -1) {
+                duk_compact(ctx, -1);
+                if 1 == duk_get_prop_string(ctx, -1, nul_str(b"prototype\0")) &&
+                   1 == duk_is_object(ctx, -1) {
+                    duk_compact(ctx, -1);
+                }
+                duk_pop(ctx);
+            }
+            duk_pop_2(ctx);
+        }
+
+        duk_pop(ctx);
+        duk_compact(ctx, -1);
+        duk_pop(ctx);
+
+        let _span = spans::Span::gc();
+        duk_gc(ctx, 0);
+    }
+
     /// Evaluates the specified script string within the current
     /// context.
     ///
@@ -273,992 +864,5233 @@ impl Context {
     pub fn eval_string(&self, string: &str) -> Result<Reference> {
         let ptr = string.as_ptr() as *const i8;
         let len = string.len();
-        unsafe {
-            let ret = duktape_sys::duk_peval_lstring(self.raw, ptr, len);
+        self.remember_source("eval", string);
+        self.measure(metrics::Operation::Eval, "eval", || unsafe {
+            let ret = self.recorded(|| recording::eval_input(self.raw, None, string),
+                                    || duktape_sys::duk_peval_lstring(self.raw, ptr, len));
             self.pop_reference_or_error(ret)
-        }
+        })
     }
 
-    /// Like `eval_string`, but sets the file name for all of the evaluated functions to the
-    /// specified string.
-    pub fn eval_string_with_filename(&self, filename: &str, string: &str) -> Result<Reference> {
-        let filename_ptr = filename.as_ptr() as *const i8;
-        let string_ptr = string.as_ptr() as *const i8;
-        unsafe {
-            duktape_sys::duk_push_lstring(self.raw, filename_ptr, filename.len());
-            let flags = duktape_sys::DUK_COMPILE_EVAL | duktape_sys::DUK_COMPILE_NOSOURCE |
-                        duktape_sys::DUK_COMPILE_SAFE;
-            let ret = duktape_sys::duk_eval_raw(self.raw, string_ptr, string.len(), flags);
-            self.pop_reference_or_error(ret)
-        }
+    /// Evaluates a single expression and returns its value.
+    ///
+    /// Unlike `eval_string`, the source is always parsed as an expression, so `{a: 1}` is an
+    /// object literal rather than a block with a label.  Statements like `var` declarations are a
+    /// `SyntaxError`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::new();
+    /// let object = ctx.eval_expression("{a: 1}").unwrap().to_value();
+    /// assert_eq!(ctx.eval_string("({a: 1})").unwrap().to_value(), object);
+    /// assert_eq!(duk::Value::Number(1.0), ctx.eval_program("{a: 1}").unwrap().to_value());
+    /// ```
+    pub fn eval_expression(&self, expression: &str) -> Result<Reference<'_>> {
+        // The line break ends a trailing line comment in the expression
+        self.eval_string(&format!("({}\n)", expression))
     }
 
-    /// Loads and evaluates the specified file within the current
-    /// context.
-    pub fn eval_file(&self, path: &path::Path) -> Result<Reference> {
-        let str_path = path.to_string_lossy();
-        let ffi_str = ffi::CString::new(&*str_path).unwrap();
-        unsafe {
-            let ret = duktape_sys::duk_peval_file(self.raw, ffi_str.as_ptr());
-            self.pop_reference_or_error(ret)
-        }
+    /// Evaluates a program, a sequence of statements, and returns the value of the last statement
+    /// that produced one, or `undefined` if none did.
+    ///
+    /// This is what `eval_string` does; the name makes the intent clear next to
+    /// `eval_expression`.  A leading `{` starts a block, so `{a: 1}` evaluates to `1`.
+    pub fn eval_program(&self, program: &str) -> Result<Reference<'_>> {
+        self.eval_string(program)
     }
 
-    /// Retrieves a reference to the global object.
-    pub fn global_object(&self) -> Reference {
-        unsafe {
-            duktape_sys::duk_push_global_object(self.raw);
-            self.pop_reference()
-        }
+    /// Like `eval_string`, but converts the result into a Rust type.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::new();
+    /// let total: f64 = ctx.eval_as("[1, 2, 3].reduce(function (a, b) { return a + b; })")
+    ///     .unwrap();
+    /// assert_eq!(6.0, total);
+    /// let names: Vec<String> = ctx.eval_as("['a', 'b']").unwrap();
+    /// assert_eq!(vec!["a", "b"], names);
+    /// assert!(ctx.eval_as::<u32>("'six'").is_err());
+    /// ```
+    pub fn eval_as<T>(&self, string: &str) -> Result<T>
+        where T: FromValue
+    {
+        T::from_value(self.eval_string(string)?.to_value())
     }
 
-    /// Calls the specified global script function with the supplied
-    /// arguments.
+    /// Evaluates an expression with temporary bindings of names to values, which are visible to
+    /// the expression like local variables, and shadow globals of the same names.
+    ///
+    /// The global object is left untouched.  Names must be ASCII identifiers, otherwise a
+    /// `SyntaxError` is returned without evaluating anything.
+    ///
+    /// # Examples
     ///
-    /// Behaves like `global_object().call_method(name, args)`.
-    pub fn call_global(&self, name: &str, args: &[&Argument]) -> Result<Reference> {
-        self.global_object().call_method(name, args)
+    /// ```
+    /// let ctx = duk::Context::new();
+    /// let bindings = [("price", duk::Value::Number(2.5)), ("qty", duk::Value::Number(4.0))];
+    /// let total = ctx.eval_with("price * qty", &bindings).unwrap();
+    /// assert_eq!(duk::Value::Number(10.0), total.to_value());
+    /// let leaked = ctx.eval_string("typeof price").unwrap();
+    /// assert_eq!(duk::Value::String("undefined".to_owned()), leaked.to_value());
+    /// ```
+    pub fn eval_with(&self, expression: &str, bindings: &[(&str, Value)]) -> Result<Reference<'_>> {
+        let is_start = |c: char| c.is_ascii_alphabetic() || c == '_' || c == '$';
+        let mut names = Vec::with_capacity(bindings.len());
+        for &(name, _) in bindings {
+            let mut chars = name.chars();
+            let valid = chars.next().is_some_and(is_start) &&
+                        chars.all(|c| is_start(c) || c.is_ascii_digit());
+            if !valid {
+                return Err(self.syntax_error(&format!("invalid binding name: {:?}", name)));
+            }
+            names.push(name);
+        }
+        // The line break ends a trailing line comment in the expression
+        let source = format!("(function ({}) {{ return ({}\n); }})", names.join(", "), expression);
+        self.remember_source("eval", &source);
+        self.measure(metrics::Operation::Eval, "eval", || unsafe {
+            let ptr = source.as_ptr() as *const i8;
+            let ret = duktape_sys::duk_peval_lstring(self.raw, ptr, source.len());
+            if ret != 0 {
+                return Err(self.pop_error());
+            }
+            for (_, value) in bindings {
+                value.push(self.raw);
+            }
+            let ret = duktape_sys::duk_pcall(self.raw, bindings.len() as duktape_sys::duk_idx_t);
+            self.pop_reference_or_error(ret)
+        })
     }
 
-    #[cfg(test)]
-    pub fn assert_clean(&self) {
-        unsafe {
-            assert!(duktape_sys::duk_get_top(self.raw) == 0,
-                    "context stack is not empty");
-        }
+    /// Like `eval_string`, but discards the result of the evaluation instead of returning a
+    /// reference to it.
+    ///
+    /// This is useful for scripts that are only evaluated for their side effects, since no
+    /// reference to the result needs to be stashed.
+    pub fn eval_discard(&self, string: &str) -> Result<()> {
+        let ptr = string.as_ptr() as *const i8;
+        let len = string.len();
+        self.remember_source("eval", string);
+        self.measure(metrics::Operation::Eval, "eval", || unsafe {
+            let ret = self.recorded(|| recording::eval_input(self.raw, None, string),
+                                    || duktape_sys::duk_peval_lstring(self.raw, ptr, len));
+            self.pop_discard_or_error(ret)
+        })
     }
 
-    fn gen_stash_idx(&self) -> duktape_sys::duk_uarridx_t {
-        self.next_stash_idx.fetch_add(1, atomic::Ordering::Relaxed) as duktape_sys::duk_uarridx_t
+    /// Like `eval_string`, but writes the result into the specified writer instead of returning a
+    /// reference to it, and returns the number of bytes written.
+    ///
+    /// Strings are written as UTF-8 and buffers are written as-is, directly from the memory of the
+    /// Duktape heap in chunks, so large results are never copied into Rust-owned memory.  Other
+    /// values are coerced to strings first.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::new();
+    /// let mut out = Vec::new();
+    /// ctx.eval_to_writer("new Array(4).join('ab')", &mut out).unwrap();
+    /// assert_eq!(b"ababab", &out[..]);
+    /// ```
+    pub fn eval_to_writer<W>(&self, string: &str, writer: &mut W) -> Result<usize>
+        where W: io::Write
+    {
+        let ptr = string.as_ptr() as *const i8;
+        let len = string.len();
+        self.remember_source("eval", string);
+        self.measure(metrics::Operation::Eval, "eval", || unsafe {
+            let ret = self.recorded(|| recording::eval_input(self.raw, None, string),
+                                    || duktape_sys::duk_peval_lstring(self.raw, ptr, len));
+            if ret == 0 {
+                let result = write_value(self.raw, -1, writer);
+                duktape_sys::duk_pop(self.raw);
+                if let Ok(n) = result {
+                    self.converted(n);
+                }
+                result
+            } else {
+                Err(self.pop_error())
+            }
+        })
     }
 
-    unsafe fn pop_reference(&self) -> Reference {
-        let idx = self.gen_stash_idx();
-        duktape_sys::duk_push_heap_stash(self.raw);
-        duktape_sys::duk_dup(self.raw, -2);
-        duktape_sys::duk_put_prop_index(self.raw, -2, idx);
-        duktape_sys::duk_pop_2(self.raw);
+    /// Like `eval_string`, but sets the file name for all of the evaluated functions to the
+    /// specified string.
+    pub fn eval_string_with_filename(&self, filename: &str, string: &str) -> Result<Reference> {
+        self.eval_named(filename,
+                        string.as_bytes(),
+                        |ret| unsafe { self.pop_reference_or_error(ret) })
+    }
 
-        Reference {
-            ctx: self,
-            stash_idx: idx,
-        }
+    /// Loads and evaluates the specified file within the current
+    /// context.
+    ///
+    /// The file is read on the Rust side, so paths that aren't valid UTF-8 work too, and errors
+    /// reading it are returned as `ErrorKind::Io`.  Functions defined by the file get the path as
+    /// their file name, as shown by `Path::display`.  Byte order marks, CRLF line endings and
+    /// Windows-1252 text are handled as described in the `sources` module.
+    ///
+    /// With a verifier, the signature of the file is checked first, and files that aren't signed
+    /// by a trusted key fail with `ErrorKind::Untrusted`, see the `signatures` module.
+    pub fn eval_file<P>(&self, path: P) -> Result<Reference<'_>>
+        where P: AsRef<path::Path>
+    {
+        let source = sources::decode(&self.read_script(path.as_ref())?);
+        self.eval_named(&path.as_ref().display().to_string(),
+                        source.as_bytes(),
+                        |ret| unsafe { self.pop_reference_or_error(ret) })
     }
 
-    unsafe fn pop_error(&self) -> Error {
-        let e = Error::get(self.raw, -1);
-        duktape_sys::duk_pop(self.raw);
-        e
+    /// Evaluates a script for its side effects only, like defining functions at startup.  Same as
+    /// `eval_discard`, but makes the intent clear at the call site.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::new();
+    /// ctx.load("function greet(name) { return 'hi ' + name; }").unwrap();
+    /// let greeting = ctx.call("greet", ("bob",)).unwrap().to_value();
+    /// assert_eq!(duk::Value::String("hi bob".to_owned()), greeting);
+    /// ```
+    pub fn load(&self, source: &str) -> Result<()> {
+        self.eval_discard(source)
     }
 
-    unsafe fn pop_reference_or_error(&self, ret: duktape_sys::duk_ret_t) -> Result<Reference> {
-        if ret == 0 {
-            Ok(self.pop_reference())
-        } else {
-            Err(self.pop_error())
-        }
+    /// Like `load`, but reads the script from a file like `eval_file` does.
+    pub fn load_file<P>(&self, path: P) -> Result<()>
+        where P: AsRef<path::Path>
+    {
+        let source = sources::decode(&self.read_script(path.as_ref())?);
+        self.eval_named(&path.as_ref().display().to_string(),
+                        source.as_bytes(),
+                        |ret| unsafe { self.pop_discard_or_error(ret) })
     }
-}
 
-impl fmt::Debug for Context {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Context({:p})", self.raw)
+    /// Reads a script file, checking its signature if the context has a verifier.
+    fn read_script(&self, path: &path::Path) -> Result<Vec<u8>> {
+        signatures::read(self.verifier.as_deref(), path)
     }
-}
 
-impl Drop for Context {
-    fn drop(&mut self) {
-        unsafe { duktape_sys::duk_destroy_heap(self.raw) };
-        if let Some(ptr) = self.module_resolver {
-            drop(unsafe { Box::from_raw(ptr) });
-        }
-        if let Some(ptr) = self.module_loader {
-            drop(unsafe { Box::from_raw(ptr) });
+    /// Loads script files and directories of them in order, like `load_file`, and reports the
+    /// globals they defined.  See the `loading` module for details.
+    pub fn load_all<I>(&self, paths: I) -> Result<loading::LoadReport>
+        where I: IntoIterator,
+              I::Item: AsRef<path::Path>
+    {
+        let before = unsafe { loading::global_names(self.raw) };
+        let mut report = loading::LoadReport::default();
+        for path in paths {
+            for file in loading::files(path.as_ref())? {
+                self.load_file(&file).chain_err(|| ErrorKind::Load(file.display().to_string()))?;
+                report.files.push(file);
+            }
         }
+        let after = unsafe { loading::global_names(self.raw) };
+        report.globals = after.difference(&before).cloned().collect();
+        Ok(report)
     }
-}
 
-impl ContextBuilder {
+    /// Loads the files of a project directory as modules, in dependency order, and reports what
+    /// each module exports.  See the `project` module for details.
+    pub fn load_project(&self, spec: project::ProjectSpec) -> Result<project::Project<'_>> {
+        project::load(self, spec)
+    }
 
-    pub fn with_module_resolver(mut self, module_resolver: Box<ModuleResolver>) -> Self {
-        self.module_resolver = Some(module_resolver);
-        self
+    /// Reads a bundle from a file and runs it like `eval_bundle`.
+    pub fn load_bundle<P>(&self, path: P) -> Result<Reference<'_>>
+        where P: AsRef<path::Path>
+    {
+        let data = self.read_script(path.as_ref())?;
+        self.eval_bundle(&bundle::Bundle::read(&data[..])?)
     }
 
-    pub fn with_module_loader(mut self, module_loader: Box<ModuleLoader>) -> Self {
-        self.module_loader = Some(module_loader);
-        self
+    /// Runs the entry module of a bundle, and returns its exports.  See the `bundle` module for
+    /// details.
+    ///
+    /// Fails with `ErrorKind::InvalidBundle` if the bundle was compiled by a different Duktape
+    /// version or configuration.  Each call runs the modules anew.
+    pub fn eval_bundle(&self, bundle: &bundle::Bundle) -> Result<Reference<'_>> {
+        unsafe { bundle::check_compatible(self.raw, bundle)? };
+        self.measure(metrics::Operation::Eval, bundle.entry(), || unsafe {
+            bundle::push_run(self.raw, bundle);
+            let ret = duktape_sys::duk_pcall(self.raw, 1);
+            self.pop_reference_or_error(ret)
+        })
     }
 
-    pub fn build(self) -> Context {
-        Context::from_builder(self)
+    /// Captures the globals that scripts defined, including compiled functions, so that
+    /// `Context::from_snapshot` can restore them, even in another process.  Globals that can't be
+    /// captured are left out, see the `snapshot` module for details.
+    pub fn snapshot(&self) -> Vec<u8> {
+        unsafe { snapshot::take(self.raw) }
     }
-}
 
-impl<'a> Reference<'a> {
-    /// Converts this reference to a `Value` which can be used for further processing by Rust code.
-    pub fn to_value(&self) -> Value {
-        self.with_value(|| { unsafe { Value::get(self.ctx.raw, -1) } })
+    /// Creates a context with the defaults of `Context::new`, and restores the globals of a
+    /// snapshot in it.
+    ///
+    /// Fails with `ErrorKind::InvalidSnapshot` if the data isn't a snapshot, or if it was taken
+    /// with a different Duktape version or configuration.
+    pub fn from_snapshot(snapshot: &[u8]) -> Result<Context> {
+        Context::builder().build_from_snapshot(snapshot)
     }
 
-    /// Gets the property with the specified key, provided that this reference points to something
-    /// that is object coercible.
-    pub fn get(&self, name: &str) -> Result<Reference<'a>> {
-        let ffi_str = ffi::CString::new(name).unwrap();
-        self.with_value(|| {
-            unsafe {
-                if 0 == duktape_sys::duk_is_object_coercible(self.ctx.raw, -1) {
-                    let msg = ffi::CString::new("value is not object coercible").unwrap();
-                    duktape_sys::duk_push_error_object(self.ctx.raw,
-                                                       duktape_sys::DUK_ERR_TYPE_ERROR,
-                                                       msg.as_ptr());
-                    Err(self.ctx.pop_error())
-                } else {
-                    duktape_sys::duk_get_prop_string(self.ctx.raw, -1, ffi_str.as_ptr());
-                    Ok(self.ctx.pop_reference())
-                }
-            }
+    /// Evaluates source code with the specified file name for all of the evaluated functions, and
+    /// hands the status of the evaluation, with the result or error on the stack, to `finish`.
+    fn eval_named<T, F>(&self, filename: &str, source: &[u8], finish: F) -> Result<T>
+        where F: FnOnce(duktape_sys::duk_ret_t) -> Result<T>
+    {
+        let filename_ptr = filename.as_ptr() as *const i8;
+        let source_ptr = source.as_ptr() as *const i8;
+        let text = String::from_utf8_lossy(source);
+        self.remember_source(filename, &text);
+        self.measure(metrics::Operation::Eval, filename, || unsafe {
+            let ret = self.recorded(|| recording::eval_input(self.raw, Some(filename), &text), || {
+                duktape_sys::duk_push_lstring(self.raw, filename_ptr, filename.len());
+                // The low bits of the flags hold the number of arguments on the stack (the
+                // filename)
+                let flags = 1 | duktape_sys::DUK_COMPILE_EVAL | duktape_sys::DUK_COMPILE_NOSOURCE |
+                            duktape_sys::DUK_COMPILE_SAFE;
+                duktape_sys::duk_eval_raw(self.raw, source_ptr, source.len(), flags)
+            });
+            finish(ret)
         })
     }
 
-    /// Calls the function that this reference points to without a `this` binding, using the
-    /// specified arguments.
+    /// Runs a full garbage collection.
     ///
-    /// When the function executes, the `this` binding is set to `undefined` or the global object,
-    /// depending on if the function is strict or not.  Calling this function is equivalent to doing
-    /// `myfunc.call(undefined, args)` in Javascript.
-    pub fn call(&self, args: &[&Argument]) -> Result<Reference<'a>> {
-        self.with_value(|| {
-            unsafe {
-                duktape_sys::duk_dup_top(self.ctx.raw); // Because pcall consumes the stack
-                for arg in args {
-                    arg.push_to_context(self.ctx);
-                }
-                let ret = duktape_sys::duk_pcall(self.ctx.raw,
-                                                 args.len() as duktape_sys::duk_idx_t);
-                self.ctx.pop_reference_or_error(ret)
-            }
-        })
+    /// Duktape also collects garbage on its own as needed, but only explicit runs like this one
+    /// show up as spans with the `tracing` feature.
+    pub fn gc(&self) {
+        let _span = spans::Span::gc();
+        let start = time::Instant::now();
+        unsafe {
+            duktape_sys::duk_gc(self.raw, 0);
+            hooks::notify(self.raw, |hooks| hooks.gc(start.elapsed()));
+        }
     }
 
-    /// Calls the function that this reference points to with an explicit `this` binding.
-    pub fn call_with_this(&self, this: &Argument, args: &[&Argument]) -> Result<Reference<'a>> {
-        self.with_value(|| {
-            unsafe {
-                duktape_sys::duk_dup_top(self.ctx.raw); // Because pcall consumes the stack
-                this.push_to_context(self.ctx);
-
-                for arg in args {
-                    arg.push_to_context(self.ctx);
-                }
-                let ret = duktape_sys::duk_pcall_method(self.ctx.raw,
-                                                        args.len() as duktape_sys::duk_idx_t);
-                self.ctx.pop_reference_or_error(ret)
-            }
-        })
+    /// Counts the objects, functions, strings and buffers that are reachable from the global
+    /// object, the stashes and the value stack, along with their approximate sizes.
+    ///
+    /// Comparing censuses taken at different times helps to find memory leaks in long-running
+    /// scripts; `HeapCensus::by_class` shows which kind of objects pile up.  The census visits
+    /// every reachable value, so it takes time proportional to the size of the heap.
+    pub fn heap_census(&self) -> census::HeapCensus {
+        unsafe { census::take(self.raw) }
     }
 
-    /// Calls a method on the object that this reference points to.
+    /// Encodes the bytes as base64 with Duktape's codec.
     ///
-    /// The `this` binding will be set to the object during the execution of the function.  Calling
-    /// this function is equivalent to doing `myobj[name](args...)` in Javascript.
-    pub fn call_method(&self, name: &str, args: &[&Argument]) -> Result<Reference<'a>> {
-        self.with_value(|| {
-            unsafe {
-                let obj_idx = duktape_sys::duk_get_top_index(self.ctx.raw);
-                duktape_sys::duk_push_lstring(self.ctx.raw, name.as_ptr() as *const i8, name.len());
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::new();
+    /// assert_eq!("aGk=", ctx.encode_base64(b"hi"));
+    /// assert_eq!(b"hi".to_vec(), ctx.decode_base64("aGk=").unwrap());
+    /// ```
+    pub fn encode_base64(&self, data: &[u8]) -> String {
+        unsafe { codec::encode_base64(self.raw, data) }
+    }
 
-                for arg in args {
-                    arg.push_to_context(self.ctx);
-                }
+    /// Decodes base64, ignoring whitespace.  Fails with a `TypeError` if the input is not valid
+    /// base64.
+    pub fn decode_base64(&self, encoded: &str) -> Result<Vec<u8>> {
+        unsafe {
+            let ret = codec::decode(self.raw, encoded, false);
+            self.pop_bytes_or_error(ret)
+        }
+    }
 
-                let ret = duktape_sys::duk_pcall_prop(self.ctx.raw,
-                                                      obj_idx,
-                                                      args.len() as duktape_sys::duk_idx_t);
+    /// Encodes the bytes as lowercase hex with Duktape's codec.
+    pub fn encode_hex(&self, data: &[u8]) -> String {
+        unsafe { codec::encode_hex(self.raw, data) }
+    }
 
-                self.ctx.pop_reference_or_error(ret)
-            }
-        })
+    /// Decodes hex in upper or lower case.  Fails with a `TypeError` if the input is not valid
+    /// hex.
+    pub fn decode_hex(&self, encoded: &str) -> Result<Vec<u8>> {
+        unsafe {
+            let ret = codec::decode(self.raw, encoded, true);
+            self.pop_bytes_or_error(ret)
+        }
     }
 
-    /// Calls the function that this reference points to as a constructor, with the specified
-    /// arguments.
-    pub fn new(&self, args: &[&Argument]) -> Result<Reference<'a>> {
-        self.with_value(|| {
-            unsafe {
-                duktape_sys::duk_dup_top(self.ctx.raw); // Because pnew consumes the stack
-                for arg in args {
-                    arg.push_to_context(self.ctx);
-                }
-                let ret = duktape_sys::duk_pnew(self.ctx.raw, args.len() as duktape_sys::duk_idx_t);
-                self.ctx.pop_reference_or_error(ret)
+    /// Joins the bytes of buffers into a new plain buffer, and returns the reference to it.
+    ///
+    /// The parts can be plain buffers, `ArrayBuffer`s or typed array views, and are copied
+    /// directly from the memory of the context.  Fails with a `TypeError` if a part isn't a
+    /// buffer.  Scripts have the same helper as `host.buffer.concat`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::new();
+    /// let head = ctx.eval_string("new Uint8Array([1, 2])").unwrap();
+    /// let joined = ctx.concat_buffers(&[&head, &duk::Value::Bytes(vec![3])]).unwrap();
+    /// assert_eq!(duk::Value::Bytes(vec![1, 2, 3]), joined.to_value());
+    /// ```
+    pub fn concat_buffers(&self, parts: &[&dyn Argument]) -> Result<Reference<'_>> {
+        unsafe {
+            duktape_sys::duk_push_array(self.raw);
+            for (i, part) in parts.iter().enumerate() {
+                part.push_to_context(self);
+                duktape_sys::duk_put_prop_index(self.raw, -2, i as duktape_sys::duk_uarridx_t);
             }
-        })
+            let joined = buffers::concat(self.raw, -1, None);
+            if joined {
+                duktape_sys::duk_remove(self.raw, -2);
+                Ok(self.pop_reference())
+            } else {
+                duktape_sys::duk_pop(self.raw);
+                Err(self.buffer_type_error())
+            }
+        }
     }
 
-    #[inline]
-    fn with_value<F, R>(&self, action: F) -> R
-        where F: FnOnce() -> R
-    {
+    /// Orders two buffers by their bytes, like `host.buffer.compare`.  Fails with a `TypeError`
+    /// if either isn't a buffer.
+    pub fn compare_buffers(&self, a: &dyn Argument, b: &dyn Argument) -> Result<cmp::Ordering> {
         unsafe {
-            self.push();
-            let result = action();
-            self.pop();
-            result
+            a.push_to_context(self);
+            b.push_to_context(self);
+            let ordering = buffers::compare(self.raw, -2, -1);
+            duktape_sys::duk_pop_2(self.raw);
+            ordering.ok_or_else(|| self.buffer_type_error())
         }
     }
 
-    unsafe fn push(&self) {
-        duktape_sys::duk_push_heap_stash(self.ctx.raw);
-        duktape_sys::duk_get_prop_index(self.ctx.raw, -1, self.stash_idx);
-        duktape_sys::duk_remove(self.ctx.raw, -2);
+    /// Copies the bytes of the source buffer into the target buffer in place, starting at the
+    /// specified offset, and returns how many bytes fit.  Fails with a `TypeError` if either isn't
+    /// a buffer.
+    pub fn copy_buffer(&self, source: &dyn Argument, target: &dyn Argument, offset: usize) -> Result<usize> {
+        unsafe {
+            source.push_to_context(self);
+            target.push_to_context(self);
+            let copied = buffers::copy(self.raw, -2, -1, offset, 0, usize::MAX);
+            duktape_sys::duk_pop_2(self.raw);
+            copied.ok_or_else(|| self.buffer_type_error())
+        }
     }
 
-    unsafe fn pop(&self) {
-        duktape_sys::duk_pop(self.ctx.raw);
+    /// Runs the callbacks of all timers that are due, and returns the time until the next timer is
+    /// due, or `None` if there are no timers left.
+    ///
+    /// Callbacks run in the order in which they are due, and each one is reported like a call of
+    /// a `setTimeout` or `setInterval` global.  With the `ws` feature, the open sockets of
+    /// `host:ws` are polled first and their handlers are called, and the returned duration is
+    /// capped so that they keep being polled.  Timers that are scheduled by the callbacks run at
+    /// the next pump at the earliest.  If a callback throws, the remaining timers stay scheduled
+    /// and the error is returned.  Contexts without timers (see `ContextBuilder::with_timers`)
+    /// never have any due.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::builder().with_timers(16).build();
+    /// ctx.eval_string("var done = false; setTimeout(function () { done = true; }, 0);").unwrap();
+    /// assert_eq!(None, ctx.pump_event_loop().unwrap());
+    /// assert_eq!(duk::Value::Boolean(true), ctx.eval_string("done").unwrap().to_value());
+    /// ```
+    pub fn pump_event_loop(&self) -> Result<Option<time::Duration>> {
+        self.run_microtasks()?;
+        #[cfg(feature = "ws")]
+        {
+            if let Some(sockets) = unsafe { (*self.heap_data).websockets.as_ref() } {
+                self.pump_websockets(sockets)?;
+                let wait = self.pump_timers()?;
+                if sockets.len() > 0 {
+                    return Ok(Some(wait.map_or(ws::POLL_INTERVAL, |w| w.min(ws::POLL_INTERVAL))));
+                }
+                return Ok(wait);
+            }
+        }
+        self.pump_timers()
     }
-}
 
-impl<'a> Argument for Reference<'a> {
-    unsafe fn push_to_context(&self, context: &Context) {
-        if context.raw != self.ctx.raw {
-            panic!("Tried to mix references coming from different contexts");
+    /// Runs the timer callbacks that are due, see `pump_event_loop`.
+    fn pump_timers(&self) -> Result<Option<time::Duration>> {
+        let event_loop = match unsafe { (*self.heap_data).event_loop.as_ref() } {
+            Some(event_loop) => event_loop,
+            None => return Ok(None),
+        };
+        let now = time::Instant::now();
+        let due = event_loop.borrow().due(now);
+        for id in due {
+            // A previous callback may have cleared this timer
+            let timer = match event_loop.borrow_mut().fire(id, now) {
+                Some(timer) => timer,
+                None => continue,
+            };
+            let name = if timer.repeat { "setInterval" } else { "setTimeout" };
+            self.measure(metrics::Operation::Call, name, || unsafe { self.run_timer(&timer) })?;
+            self.run_microtasks()?;
         }
-
-        self.push();
+        Ok(event_loop.borrow().next_due(time::Instant::now()))
     }
-}
 
-impl<'a> PartialEq for Reference<'a> {
-    fn eq(&self, _: &Self) -> bool {
-        false
+    /// Polls the sockets of `host:ws`, and calls the handlers of their events.
+    ///
+    /// If a handler throws, the remaining events stay queued for the next pump.
+    #[cfg(feature = "ws")]
+    fn pump_websockets(&self, sockets: &ws::Sockets) -> Result<()> {
+        sockets.poll();
+        while let Some((id, event)) = sockets.next_event() {
+            self.measure(metrics::Operation::Call, event.handler(), || unsafe {
+                let ret = ws::dispatch(self.raw, id, &event);
+                self.pop_discard_or_error(ret)
+            })?;
+            self.run_microtasks()?;
+        }
+        Ok(())
     }
-}
 
-impl<'a> Drop for Reference<'a> {
-    fn drop(&mut self) {
-        unsafe {
-            duktape_sys::duk_push_heap_stash(self.ctx.raw);
-            duktape_sys::duk_del_prop_index(self.ctx.raw, -1, self.stash_idx);
-            duktape_sys::duk_pop(self.ctx.raw);
+    /// Runs the pending microtasks, like the reactions of promises, and returns how many ran.
+    ///
+    /// Microtasks that are queued by other microtasks run too.  If one throws, the remaining ones
+    /// stay queued and the error is returned.  Only contexts with the `Promise` polyfill (see
+    /// `ContextBuilder::with_polyfills`) have microtasks; `pump_event_loop` runs them too.  Running
+    /// them is reported like a call of `microtasks`, and is subject to the timeout of the context.
+    pub fn run_microtasks(&self) -> Result<usize> {
+        let queued = unsafe {
+            polyfills::push_microtasks(self.raw);
+            let queued = duktape_sys::duk_is_function(self.raw, -1) != 0;
+            duktape_sys::duk_pop(self.raw);
+            queued
+        };
+        if !queued {
+            return Ok(0);
         }
+        self.measure(metrics::Operation::Call, "microtasks", || unsafe {
+            polyfills::push_microtasks(self.raw);
+            let ret = duktape_sys::duk_pcall(self.raw, 0);
+            if ret == 0 {
+                let count = duktape_sys::duk_get_uint(self.raw, -1);
+                duktape_sys::duk_pop(self.raw);
+                Ok(count as usize)
+            } else {
+                Err(self.pop_error())
+            }
+        })
     }
-}
 
-impl Value {
-    /// Copies this value into a `Context`, and returns the reference to the value within the
-    /// context.
-    pub fn to_reference<'a>(&self, context: &'a Context) -> Reference<'a> {
-        unsafe {
-            self.push(context.raw);
-            context.pop_reference()
+    /// Pumps the event loop until there are no timers (or sockets) left, sleeping while nothing
+    /// is due.
+    ///
+    /// Stops at the first error thrown by a callback.  Note that this never returns if a script
+    /// keeps an interval (or a socket) open.
+    pub fn run_event_loop(&self) -> Result<()> {
+        while let Some(wait) = self.pump_event_loop()? {
+            thread::sleep(wait);
         }
+        Ok(())
     }
 
-    unsafe fn get(ctx: *mut duktape_sys::duk_context, index: duktape_sys::duk_idx_t) -> Value {
-        let t = duktape_sys::duk_get_type(ctx, index);
-        if t == duktape_sys::DUK_TYPE_UNDEFINED {
-            Value::Undefined
-        } else if t == duktape_sys::DUK_TYPE_NULL {
-            Value::Null
-        } else if t == duktape_sys::DUK_TYPE_BOOLEAN {
-            Value::Boolean(duktape_sys::duk_get_boolean(ctx, index) != 0)
-        } else if t == duktape_sys::DUK_TYPE_NUMBER {
-            Value::Number(duktape_sys::duk_get_number(ctx, index))
-        } else if t == duktape_sys::DUK_TYPE_STRING {
-            Value::String(get_string(ctx, index))
-        } else if t == duktape_sys::DUK_TYPE_OBJECT {
-            if 1 == duktape_sys::duk_is_array(ctx, index) {
-                let len = duktape_sys::duk_get_length(ctx, index);
-                let mut array = Vec::with_capacity(len);
+    /// The number of timers that are scheduled and have not run or been cleared yet.
+    pub fn pending_timers(&self) -> usize {
+        unsafe { (*self.heap_data).event_loop.as_ref().map_or(0, |e| e.borrow().len()) }
+    }
 
-                for i in 0..len {
-                    assert!(1 == duktape_sys::duk_get_prop_index(ctx, index, i as u32));
-                    array.push(Value::get(ctx, -1));
-                    duktape_sys::duk_pop(ctx);
-                }
+    /// Calls the callback of a timer with its arguments.
+    unsafe fn run_timer(&self, timer: &event_loop::DueTimer) -> Result<()> {
+        use duktape_sys::*;
 
-                Value::Array(array)
+        event_loop::push_timer(self.raw, timer.id);
+        if !timer.repeat {
+            event_loop::forget_timer(self.raw, timer.id);
+        }
+        duk_get_prop_index(self.raw, -1, 0);
+        duk_get_prop_index(self.raw, -2, 1);
+        duk_remove(self.raw, -3);
+        let argc = duk_get_length(self.raw, -1);
+        for i in 0..argc {
+            duk_get_prop_index(self.raw, -1 - i as duk_idx_t, i as duk_uarridx_t);
+        }
+        duk_remove(self.raw, -1 - argc as duk_idx_t);
+        let ret = duk_pcall(self.raw, argc as duk_idx_t);
+        self.pop_discard_or_error(ret)
+    }
+
+    /// Calls the listeners that scripts subscribed to an event with `host.events.on`, and returns
+    /// how many were called.
+    ///
+    /// The listeners are called in the order in which they subscribed, with the value as their
+    /// argument.  If a listener throws, the remaining listeners aren't called and the error is
+    /// returned.  See the `events` module.
+    pub fn emit(&self, name: &str, value: &Value) -> Result<usize> {
+        self.measure(metrics::Operation::Call, name, || unsafe {
+            events::push_dispatch(self.raw);
+            strings::push(self.raw, name);
+            value.push(self.raw);
+            let ret = duktape_sys::duk_pcall(self.raw, 2);
+            if ret == 0 {
+                let called = duktape_sys::duk_get_uint(self.raw, -1);
+                duktape_sys::duk_pop(self.raw);
+                Ok(called as usize)
             } else {
-                let mut object = collections::BTreeMap::new();
-                duktape_sys::duk_enum(ctx, -1, duktape_sys::DUK_ENUM_OWN_PROPERTIES_ONLY);
+                Err(self.pop_error())
+            }
+        })
+    }
 
-                while 1 == duktape_sys::duk_next(ctx, -1, 1) {
-                    let key = get_string(ctx, -2);
-                    let value = Value::get(ctx, -1);
-                    duktape_sys::duk_pop_2(ctx);
-                    object.insert(key, value);
-                }
+    /// Returns a receiver of the events that scripts emit with `host.events.emit` from now on.
+    ///
+    /// Every receiver gets every event, and can be moved to another thread.  See the `events`
+    /// module.
+    pub fn events(&self) -> mpsc::Receiver<events::Event> {
+        let (sender, receiver) = mpsc::channel();
+        unsafe { (*self.heap_data).event_senders.borrow_mut().push(sender) };
+        receiver
+    }
 
-                duktape_sys::duk_pop(ctx);
+    /// Starts recording all top-level evaluations and calls of global functions, with their
+    /// inputs and outcomes, to the specified writer.  See the `recording` module for the format.
+    ///
+    /// A recording made in the field can be replayed against a fresh context with `replay` to
+    /// reproduce a problem.  Any previous recording is stopped, and errors writing it are lost.
+    pub fn start_recording<W>(&self, writer: W)
+        where W: io::Write + 'static
+    {
+        *self.recorder.borrow_mut() = Some(recording::Recorder::new(Box::new(writer)));
+    }
 
-                Value::Object(object)
-            }
-        } else if t == duktape_sys::DUK_TYPE_BUFFER {
-            let mut size = mem::uninitialized();
-            let data = duktape_sys::duk_get_buffer(ctx, index, &mut size);
-            let slice = slice::from_raw_parts(data as *const u8, size);
-            Value::Bytes(slice.to_vec())
-        } else if t == duktape_sys::DUK_TYPE_POINTER {
-            Value::Foreign("pointer")
-        } else if t == duktape_sys::DUK_TYPE_LIGHTFUNC {
-            Value::Foreign("lightfunc")
-        } else {
-            panic!("Unmapped type {}", t)
+    /// Stops recording, flushes the recording, and returns the first error that occurred while
+    /// writing it.
+    pub fn stop_recording(&self) -> Result<()> {
+        match self.recorder.borrow_mut().take() {
+            Some(recorder) => Ok(recorder.close()?),
+            None => Ok(()),
         }
     }
 
-    unsafe fn push(&self, ctx: *mut duktape_sys::duk_context) {
-        match *self {
-            Value::Undefined => duktape_sys::duk_push_undefined(ctx),
-            Value::Null => duktape_sys::duk_push_null(ctx),
-            Value::Boolean(b) => {
-                let v = if b {
-                    1
-                } else {
-                    0
-                };
-                duktape_sys::duk_push_boolean(ctx, v);
-            }
-            Value::Number(n) => duktape_sys::duk_push_number(ctx, n),
-            Value::String(ref string) => {
-                let data = string.as_ptr() as *const i8;
-                let len = string.len();
-                duktape_sys::duk_push_lstring(ctx, data, len);
-            }
-            Value::Array(ref array) => {
-                duktape_sys::duk_push_array(ctx);
-                for (i, elem) in array.iter().enumerate() {
-                    elem.push(ctx);
-                    assert!(1 == duktape_sys::duk_put_prop_index(ctx, -2, i as u32));
-                }
-            }
-            Value::Object(ref object) => {
-                duktape_sys::duk_push_object(ctx);
+    /// Re-executes all interactions of a recording made with `start_recording` in this context,
+    /// and reports the ones whose outcome differs from the recorded one.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let recording = "{op:\"eval\",filename:null,source:\"1 + 2\",result:3}\n";
+    /// let ctx = duk::Context::new();
+    /// let report = ctx.replay(recording.as_bytes()).unwrap();
+    /// assert_eq!(1, report.interactions);
+    /// assert!(report.is_faithful());
+    /// ```
+    pub fn replay<R>(&self, reader: R) -> Result<recording::ReplayReport>
+        where R: io::BufRead
+    {
+        unsafe { recording::replay(self.raw, reader) }
+    }
 
-                for (k, v) in object {
-                    let k_data = k.as_ptr() as *const i8;
-                    let k_len = k.len();
-                    duktape_sys::duk_push_lstring(ctx, k_data, k_len);
-                    v.push(ctx);
-                    duktape_sys::duk_put_prop(ctx, -3);
-                }
-            }
-            Value::Bytes(ref bytes) => {
-                let len = bytes.len();
-                let data = duktape_sys::duk_push_fixed_buffer(ctx, len);
+    /// Creates a copy of a value that was cloned with `Reference::structured_clone`, possibly
+    /// from another context, and returns the reference to the copy.
+    pub fn import_clone(&self, clone: &structured_clone::StructuredClone) -> Reference<'_> {
+        unsafe {
+            structured_clone::push(self.raw, clone);
+            self.pop_reference()
+        }
+    }
 
-                ptr::copy(bytes.as_ptr(), data as *mut u8, len);
-            }
-            Value::Foreign(_) => duktape_sys::duk_push_undefined(ctx),
+    /// Retrieves a reference to the global object.
+    pub fn global_object(&self) -> Reference {
+        unsafe {
+            duktape_sys::duk_push_global_object(self.raw);
+            self.pop_reference()
         }
     }
-}
 
-impl Argument for Value {
-    unsafe fn push_to_context(&self, context: &Context) {
-        self.push(context.raw);
+    /// Defines a global function that calls the specified Rust function, which gets `nargs`
+    /// arguments (missing ones are `undefined`, and extra ones are ignored).  Errors that the
+    /// function returns are thrown as exceptions.  See the `native` module for details.
+    pub fn register_function<F>(&self, name: &str, nargs: usize, function: F)
+        where F: Fn(&[Value]) -> Result<Value> + 'static
+    {
+        self.register_boxed(name, nargs as duktape_sys::duk_idx_t, native::fixed(function));
     }
-}
 
-impl Error {
-    unsafe fn get(ctx: *mut duktape_sys::duk_context, index: duktape_sys::duk_idx_t) -> Error {
-        let e = duktape_sys::duk_get_error_code(ctx, index);
-        let kind = JsErrorKind::from_raw(e);
-        let message = get_string_property(ctx, index, "message").unwrap_or_else(|| {
-            let mut len = mem::uninitialized();
-            let data = duktape_sys::duk_safe_to_lstring(ctx, index, &mut len);
-            let msg_slice = slice::from_raw_parts(data as *const u8, len);
-            String::from(str::from_utf8(msg_slice).unwrap())
-        });
-        let file_name = get_string_property(ctx, index, "fileName").and_then(|n| if n.is_empty() {
-            None
-        } else {
-            Some(n)
-        });
-        let line_number = get_number_property(ctx, index, "lineNumber")
-            .and_then(|n| if n.is_nan() {
-                None
-            } else {
-                Some(n as usize)
-            });
-        let stack = get_string_property(ctx, index, "stack");
+    /// Like `register_function`, but for Rust functions that mutate the state that they capture,
+    /// like a log that they append to.  A function that calls back into itself through the
+    /// context throws an `Error` instead of running again.
+    pub fn register_function_mut<F>(&self, name: &str, nargs: usize, function: F)
+        where F: FnMut(&[Value]) -> Result<Value> + 'static
+    {
+        self.register_function(name, nargs, native::exclusive(function));
+    }
 
-        ErrorKind::Js(JsError {
-            kind: kind,
-            message: message,
-            file_name: file_name,
-            line_number: line_number,
-            stack: stack,
-        }).into()
+    /// Defines a global function that calls the specified Rust function with all the arguments
+    /// that it was called with, and the value of `this`.  See the `native` module for details.
+    pub fn register_variadic<F>(&self, name: &str, function: F)
+        where F: Fn(&native::Arguments) -> Result<Value> + 'static
+    {
+        let nargs = unsafe { duktape_sys::DUK_VARARGS };
+        self.register_boxed(name, nargs, Box::new(function));
     }
-}
 
-impl JsErrorKind {
-    unsafe fn from_raw(e: duktape_sys::duk_errcode_t) -> JsErrorKind {
-        if e == duktape_sys::DUK_ERR_NONE {
-            JsErrorKind::Generic
-        } else if e == duktape_sys::DUK_ERR_ERROR {
-            JsErrorKind::Error
-        } else if e == duktape_sys::DUK_ERR_EVAL_ERROR {
-            JsErrorKind::Eval
-        } else if e == duktape_sys::DUK_ERR_RANGE_ERROR {
-            JsErrorKind::Range
-        } else if e == duktape_sys::DUK_ERR_REFERENCE_ERROR {
-            JsErrorKind::Reference
-        } else if e == duktape_sys::DUK_ERR_SYNTAX_ERROR {
-            JsErrorKind::Syntax
-        } else if e == duktape_sys::DUK_ERR_TYPE_ERROR {
-            JsErrorKind::Type
-        } else if e == duktape_sys::DUK_ERR_URI_ERROR {
-            JsErrorKind::Uri
-        } else {
-            panic!("Unmapped error code {}", e)
+    /// Registers a native function with a fixed name and number of arguments, usually one
+    /// generated by the `#[duk_function]` attribute of the `duk-macros` crate, which converts the
+    /// arguments and the result of a plain Rust function.
+    pub fn register_native<F>(&self)
+        where F: native::NativeFunction + 'static
+    {
+        self.register_function(F::NAME, F::NARGS, F::call);
+    }
+
+    fn register_boxed(&self,
+                      name: &str,
+                      nargs: duktape_sys::duk_idx_t,
+                      function: Box<native::Function>) {
+        unsafe {
+            native::push(self.raw, nargs, function);
+            self.put_global(name);
         }
     }
-}
 
-unsafe fn get_string(ctx: *mut duktape_sys::duk_context, index: duktape_sys::duk_idx_t) -> String {
-    let mut len = mem::uninitialized();
-    let data = duktape_sys::duk_get_lstring(ctx, index, &mut len);
-    let slice = slice::from_raw_parts(data as *const u8, len);
-    String::from(str::from_utf8(slice).unwrap())
-}
+    /// Defines a global object with the native functions and constants that the closure adds to
+    /// the builder, instead of a global for each of them.  If the global already is an object,
+    /// like the `host` object that holds the host APIs, they are added to it.  See the `native`
+    /// module for details.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::new();
+    /// ctx.register_module("config", |m| {
+    ///     m.function("get", 1, |args| Ok(args[0].clone()));
+    ///     m.constant("VERSION", "1.2.0");
+    /// });
+    /// assert_eq!(duk::Value::String("1.2.0 x".to_owned()),
+    ///            ctx.eval_string("config.VERSION + ' ' + config.get('x')").unwrap().to_value());
+    /// ```
+    pub fn register_module<F>(&self, name: &str, build: F)
+        where F: FnOnce(&mut native::ModuleBuilder)
+    {
+        unsafe {
+            self.get_global(name);
+            if duktape_sys::duk_is_object(self.raw, -1) == 0 {
+                duktape_sys::duk_pop(self.raw);
+                duktape_sys::duk_push_object(self.raw);
+            }
+            native::build_module(self.raw, build);
+            self.put_global(name);
+        }
+    }
 
-unsafe fn get_string_property(ctx: *mut duktape_sys::duk_context,
-                              index: duktape_sys::duk_idx_t,
-                              name: &str)
-                              -> Option<String> {
-    let ffi_name = ffi::CString::new(name).unwrap();
-    if 1 == duktape_sys::duk_get_prop_string(ctx, index, ffi_name.as_ptr()) {
-        let result = get_string(ctx, -1);
-        duktape_sys::duk_pop(ctx);
+    /// Defines a global object that is bound to a shared Rust value, with methods that the
+    /// closure adds, which call into the value.  See the `bindings` module for details.
+    pub fn register_object<T, F>(&self, name: &str, value: rc::Rc<cell::RefCell<T>>, build: F)
+        where T: 'static,
+              F: FnOnce(&mut bindings::Methods<T>)
+    {
+        unsafe {
+            duktape_sys::duk_push_object(self.raw);
+            bindings::bind(self.raw, value, build);
+            self.put_global(name);
+        }
+    }
 
-        Some((*result).to_owned())
-    } else {
-        duktape_sys::duk_pop(ctx);
-        None
+    /// Defines a global class, whose instances are bound to the Rust values that `constructor`
+    /// creates from the `nargs` arguments of `new`, with methods that the closure adds to its
+    /// prototype.  See the `bindings` module for details.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use duk::Value;
+    ///
+    /// struct Database {
+    ///     path: String,
+    ///     rows: Vec<Value>,
+    /// }
+    ///
+    /// let ctx = duk::Context::new();
+    /// ctx.register_class("Database",
+    ///                    1,
+    ///                    |args| {
+    ///                        Ok(Database {
+    ///                            path: duk::native::argument(args, 0, "path")?,
+    ///                            rows: Vec::new(),
+    ///                        })
+    ///                    },
+    ///                    |class| {
+    ///                        class.method_mut("insert", 1, |db, args| {
+    ///                                db.rows.push(args[0].clone());
+    ///                                Ok(Value::Number(db.rows.len() as f64))
+    ///                            })
+    ///                            .method("path", 0, |db, _| Ok(Value::String(db.path.clone())));
+    ///                    });
+    /// let result = ctx.eval_string("var db = new Database('app.db'); db.insert({id: 1});
+    ///                               db.path() + ' ' + (db instanceof Database)")
+    ///     .unwrap();
+    /// assert_eq!(Value::String("app.db true".to_owned()), result.to_value());
+    /// ```
+    pub fn register_class<T, C, F>(&self, name: &str, nargs: usize, constructor: C, build: F)
+        where T: 'static,
+              C: Fn(&[Value]) -> Result<T> + 'static,
+              F: FnOnce(&mut bindings::Methods<T>)
+    {
+        unsafe {
+            bindings::push_class(self.raw, name, nargs, constructor, build);
+            self.put_global(name);
+        }
     }
-}
 
-unsafe fn get_number_property(ctx: *mut duktape_sys::duk_context,
-                              index: duktape_sys::duk_idx_t,
-                              name: &str)
-                              -> Option<f64> {
-    let ffi_name = ffi::CString::new(name).unwrap();
-    if 1 == duktape_sys::duk_get_prop_string(ctx, index, ffi_name.as_ptr()) {
-        let result = duktape_sys::duk_get_number(ctx, -1);
-        duktape_sys::duk_pop(ctx);
-        Some(result)
-    } else {
-        duktape_sys::duk_pop(ctx);
-        None
+    /// Defines a global object whose properties come from the handler, which the context keeps
+    /// until the object is garbage collected.  Fails if the `Proxy` built-in isn't available.  See
+    /// the `proxies` module for details.
+    pub fn register_proxy<H>(&self, name: &str, handler: H) -> Result<()>
+        where H: proxies::ProxyHandler + 'static
+    {
+        unsafe {
+            let ret = proxies::push(self.raw, rc::Rc::new(handler));
+            if ret == 0 {
+                self.put_global(name);
+                Ok(())
+            } else {
+                Err(self.pop_error())
+            }
+        }
     }
-}
 
-unsafe fn nul_str(data: &[u8]) -> *const os::raw::c_char {
-    ffi::CStr::from_bytes_with_nul_unchecked(data).as_ptr()
-}
+    /// Calls the specified global script function with the supplied
+    /// arguments.
+    ///
+    /// Behaves like `global_object().call_method(name, args)`, but operates on the global object
+    /// directly instead of creating a reference to it.  The context caches the functions of
+    /// recently called names, and checks that the global still holds the cached function before
+    /// calling it, which makes repeated calls to the same global function nearly as cheap as
+    /// calling a pre-resolved `FunctionRef`.
+    pub fn call_global(&self, name: &str, args: &[&dyn Argument]) -> Result<Reference<'_>> {
+        self.measure(metrics::Operation::Call, name, || unsafe {
+            let ret = self.pcall_global(name, || {
+                for arg in args {
+                    arg.push_to_context(self);
+                }
+                args.len()
+            });
+            let result = self.pop_reference_or_error(ret);
+            duktape_sys::duk_pop(self.raw); // The global object
+            result
+        })
+    }
 
-unsafe extern "C" fn module_resolve_handler(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
-    let requested_id = get_string(ctx, 0);
-    let parent_id = get_string(ctx, 1);
-    duktape_sys::duk_pop_2(ctx);
+    /// Like `call_global`, but converts the result into a Rust type.
+    pub fn call_global_as<T>(&self, name: &str, args: &[&dyn Argument]) -> Result<T>
+        where T: FromValue
+    {
+        T::from_value(self.call_global(name, args)?.to_value())
+    }
 
-    duktape_sys::duk_push_current_function(ctx);
-    duktape_sys::duk_get_prop_string(ctx, -1, nul_str(b"closure\0"));
-    let ptr = duktape_sys::duk_get_pointer(ctx, -1) as *mut Box<ModuleResolver>;
-    assert!(!ptr.is_null());
-    let resolve = Box::from_raw(ptr);
-    duktape_sys::duk_pop_2(ctx);
+    /// Calls the specified global script function with arguments that convert into values, like
+    /// a tuple of mixed Rust types.  Otherwise behaves like `call_global`.
+    ///
+    /// The arguments are converted into owned `Value`s first, so large strings and buffers are
+    /// cheaper to pass to `call_global` by reference, as `Argument`s.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::new();
+    /// ctx.eval_string("function repeat(n, s, loud) { return (loud ? s.toUpperCase() : s) + n; }")
+    ///     .unwrap();
+    /// let value = ctx.call("repeat", (1, "x", true)).unwrap().to_value();
+    /// assert_eq!(duk::Value::String("X1".to_owned()), value);
+    /// ```
+    pub fn call<A>(&self, name: &str, args: A) -> Result<Reference<'_>>
+        where A: IntoArgs
+    {
+        let values = args.into_args();
+        let args = values.iter().map(|v| v as &dyn Argument).collect::<Vec<_>>();
+        self.call_global(name, &args)
+    }
 
-    // Ensure clear stack before entering the Rust wild west
-    let result = resolve(requested_id, parent_id);
+    /// Calls the specified global script function with an explicit `this` binding, like
+    /// `name.call(this, args...)` in Javascript, for functions that expect to be called as
+    /// methods.  Otherwise behaves like `call_global`, which calls with the global object as
+    /// `this`.  Functions that were resolved ahead of time have `FunctionRef::call_with_this`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::new();
+    /// ctx.eval_string("function onSave(file) { return this.name + ' saved ' + file; }").unwrap();
+    /// let plugin = ctx.eval_string("({name: 'backup'})").unwrap();
+    /// let value = ctx.call_with_this(&plugin, "onSave", &[&"a.txt"]).unwrap().to_value();
+    /// assert_eq!(duk::Value::String("backup saved a.txt".to_owned()), value);
+    /// ```
+    pub fn call_with_this(&self, this: &dyn Argument, name: &str, args: &[&dyn Argument])
+                          -> Result<Reference<'_>> {
+        self.measure(metrics::Operation::Call, name, || unsafe {
+            self.get_global(name);
+            this.push_to_context(self);
+            for arg in args {
+                arg.push_to_context(self);
+            }
+            let ret = duktape_sys::duk_pcall_method(self.raw, args.len() as duktape_sys::duk_idx_t);
+            self.pop_reference_or_error(ret)
+        })
+    }
 
-    mem::forget(resolve);
+    /// Calls the function at a dotted path of properties, starting from the global object, with the
+    /// object that holds it as `this`, like `plugin.hooks.onSave(args...)` in Javascript.
+    ///
+    /// Fails with `ErrorKind::NonExistent` if a property along the path is `undefined` or `null`,
+    /// which tells the part of the path up to the property that is missing, so hosts can tell a
+    /// plugin without an optional hook from a hook that failed.  Errors thrown while looking up
+    /// the properties, like by getters, are returned like the ones of the call.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::new();
+    /// ctx.eval_string("var plugin = {name: 'backup', hooks: {
+    ///                    onSave: function (file) { return plugin.name + ' saved ' + file; }}};")
+    ///     .unwrap();
+    /// let value = ctx.call_method("plugin.hooks.onSave", &[&"a.txt"]).unwrap().to_value();
+    /// assert_eq!(duk::Value::String("backup saved a.txt".to_owned()), value);
+    ///
+    /// match *ctx.call_method("plugin.events.onLoad", &[]).unwrap_err().kind() {
+    ///     duk::ErrorKind::NonExistent(_, ref missing) => assert_eq!("plugin.events", missing),
+    ///     ref kind => panic!("unexpected error {:?}", kind),
+    /// }
+    /// ```
+    pub fn call_method(&self, path: &str, args: &[&dyn Argument]) -> Result<Reference<'_>> {
+        self.measure(metrics::Operation::Call, path, || unsafe {
+            self.push_path(path)?;
+            // The function, and then the object that holds it
+            duktape_sys::duk_swap_top(self.raw, -2);
+            for arg in args {
+                arg.push_to_context(self);
+            }
+            let ret = duktape_sys::duk_pcall_method(self.raw, args.len() as duktape_sys::duk_idx_t);
+            self.pop_reference_or_error(ret)
+        })
+    }
 
-    Value::String(result).push(ctx);
+    /// Calls the constructor at a dotted path of properties, starting from the global object, like
+    /// `new plugin.Widget(args...)` in Javascript, for plugins that export classes instead of
+    /// functions.  Fails like `call_method` if a property along the path doesn't exist, and with
+    /// a `TypeError` if the value isn't a constructor.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::new();
+    /// ctx.eval_string("function Point(x, y) { this.x = x; this.y = y; }
+    ///                  Point.prototype.norm = function () {
+    ///                      return Math.abs(this.x) + Math.abs(this.y);
+    ///                  };")
+    ///     .unwrap();
+    /// let point = ctx.new_instance("Point", &[&1, &-2]).unwrap();
+    /// assert_eq!(duk::Value::Number(3.0), point.call_method("norm", &[]).unwrap().to_value());
+    /// ```
+    pub fn new_instance(&self, path: &str, args: &[&dyn Argument]) -> Result<Reference<'_>> {
+        self.measure(metrics::Operation::Call, path, || unsafe {
+            self.push_path(path)?;
+            duktape_sys::duk_remove(self.raw, -2);
+            for arg in args {
+                arg.push_to_context(self);
+            }
+            let ret = duktape_sys::duk_pnew(self.raw, args.len() as duktape_sys::duk_idx_t);
+            self.pop_reference_or_error(ret)
+        })
+    }
 
-    1
-}
+    /// Pushes the object that holds the property at a dotted path of properties, and then the
+    /// value of the property, or fails with `ErrorKind::NonExistent` and pushes nothing.
+    unsafe fn push_path(&self, path: &str) -> Result<()> {
+        let segments = path.split('.').collect::<Vec<_>>();
+        duktape_sys::duk_push_global_object(self.raw);
+        for (i, segment) in segments.iter().enumerate() {
+            duktape_sys::duk_dup_top(self.raw);
+            strings::push(self.raw, segment);
+            // Getters and proxies can throw
+            let ret =
+                duktape_sys::duk_safe_call(self.raw, Some(get_property), ptr::null_mut(), 2, 1);
+            if ret != 0 {
+                let error = self.pop_error();
+                duktape_sys::duk_pop(self.raw);
+                return Err(error);
+            }
+            if i + 1 < segments.len() {
+                duktape_sys::duk_remove(self.raw, -2);
+            }
+            if 0 != duktape_sys::duk_is_null_or_undefined(self.raw, -1) {
+                duktape_sys::duk_pop_n(self.raw, if i + 1 < segments.len() { 1 } else { 2 });
+                let missing = segments[..i + 1].join(".");
+                return Err(ErrorKind::NonExistent(path.to_owned(), missing).into());
+            }
+        }
+        Ok(())
+    }
 
-unsafe extern "C" fn module_load_handler(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
-    let resolved_id = get_string(ctx, 0);
-    duktape_sys::duk_pop_3(ctx); // Discard 'exports' and 'module'
+    /// Resolves the specified global function once, so that it can be called repeatedly without
+    /// looking it up again.  See `FunctionRef` for how that compares to `call_global`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::new();
+    /// ctx.eval_string("function double(a) { return a * 2; }").unwrap();
+    /// let double = ctx.global_function("double").unwrap();
+    /// let value = double.call(&[&duk::Value::Number(2.0)]).unwrap().to_value();
+    /// assert_eq!(duk::Value::Number(4.0), value);
+    /// ```
+    pub fn global_function(&self, name: &str) -> Result<FunctionRef<'_>> {
+        self.global_object().get(name).and_then(Reference::into_function)
+    }
 
-    duktape_sys::duk_push_current_function(ctx);
-    duktape_sys::duk_get_prop_string(ctx, -1, nul_str(b"closure\0"));
-    let ptr = duktape_sys::duk_get_pointer(ctx, -1) as *mut Box<ModuleLoader>;
-    assert!(!ptr.is_null());
-    let load = Box::from_raw(ptr);
-    duktape_sys::duk_pop_2(ctx);
+    /// Like `call_global`, but writes the result into the specified writer instead of returning a
+    /// reference to it, and returns the number of bytes written.  See `eval_to_writer`.
+    pub fn call_global_to_writer<W>(&self,
+                                    name: &str,
+                                    args: &[&dyn Argument],
+                                    writer: &mut W)
+                                    -> Result<usize>
+        where W: io::Write
+    {
+        self.measure(metrics::Operation::Call, name, || unsafe {
+            let ret = self.pcall_global(name, || {
+                for arg in args {
+                    arg.push_to_context(self);
+                }
+                args.len()
+            });
+            let result = if ret == 0 {
+                let result = write_value(self.raw, -1, writer);
+                duktape_sys::duk_pop(self.raw);
+                if let Ok(n) = result {
+                    self.converted(n);
+                }
+                result
+            } else {
+                Err(self.pop_error())
+            };
+            duktape_sys::duk_pop(self.raw); // The global object
+            result
+        })
+    }
 
-    let result = load(resolved_id);
+    /// Like `call_global`, but uses pre-converted arguments from an `ArgsBuilder`.
+    pub fn call_global_args<'a>(&'a self, name: &str, args: &ArgsBuilder<'a>) -> Result<Reference<'a>> {
+        self.measure(metrics::Operation::Call, name, || unsafe {
+            let ret = self.pcall_global(name, || {
+                args.push_all(self);
+                args.len()
+            });
+            let result = self.pop_reference_or_error(ret);
+            duktape_sys::duk_pop(self.raw); // The global object
+            result
+        })
+    }
 
-    mem::forget(load);
+    /// Creates a new, empty list of reusable arguments.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::new();
+    /// ctx.eval_string("function add(a, b) { return a + b; }").unwrap();
+    /// let mut args = ctx.args_builder().arg(&duk::Value::Number(1.0)).arg(&duk::Value::Number(0.0));
+    /// for i in 0..3 {
+    ///   args.set(1, &duk::Value::Number(i as f64));
+    ///   let value = ctx.call_global_args("add", &args).unwrap().to_value();
+    ///   assert_eq!(duk::Value::Number(1.0 + i as f64), value);
+    /// }
+    /// ```
+    pub fn args_builder(&self) -> ArgsBuilder<'_> {
+        unsafe {
+            duktape_sys::duk_push_array(self.raw);
+            ArgsBuilder {
+                array: self.pop_reference(),
+                len: 0,
+            }
+        }
+    }
 
-    // Ensure clear stack before entering the Rust wild west
-    if let Some(result) = result {
-        Value::String(result).push(ctx);
-        1
-    } else {
-        0
+    /// Like `call_global`, but discards the return value of the function instead of returning a
+    /// reference to it.
+    ///
+    /// Useful for hooks (like `onSave` or `onTick`) whose result is ignored anyway.
+    pub fn call_global_void(&self, name: &str, args: &[&dyn Argument]) -> Result<()> {
+        self.measure(metrics::Operation::Call, name, || unsafe {
+            let ret = self.pcall_global(name, || {
+                for arg in args {
+                    arg.push_to_context(self);
+                }
+                args.len()
+            });
+            let result = self.pop_discard_or_error(ret);
+            duktape_sys::duk_pop(self.raw); // The global object
+            result
+        })
     }
-}
 
-#[cfg(feature = "logging")]
-unsafe extern "C" fn log_handler(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
-    use duktape_sys::*; // Because this function is essentially only calling C stuff
+    /// Replaces the specified global function with a stub that returns the canned results in
+    /// order, and records the arguments it is called with.
+    ///
+    /// Once the results are used up the stub keeps returning the last one, or `undefined` if
+    /// there are none.  Useful for mocking host APIs in unit tests of plugins.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::new();
+    /// let fetch = ctx.stub_global("fetch", &[duk::Value::String("ok".to_owned())]).unwrap();
+    /// ctx.eval_string("fetch('/a'); fetch('/b', 1)").unwrap();
+    /// assert_eq!(vec![vec![duk::Value::String("/a".to_owned())],
+    ///                 vec![duk::Value::String("/b".to_owned()), duk::Value::Number(1.0)]],
+    ///            fetch.calls());
+    /// ```
+    pub fn stub_global(&self, name: &str, results: &[Value]) -> Result<Spy<'_>> {
+        const STUB: &str = "(function (results) {
+          var calls = [];
+          var next = 0;
+          var stub = function () {
+            calls.push(Array.prototype.slice.call(arguments));
+            return next < results.length ? results[next++] : results[results.length - 1];
+          };
+          return [stub, calls];
+        })";
+        self.install_spy(name, STUB, &Value::Array(results.to_vec()))
+    }
 
-    // The function magic is the log level that this handler should handle.
-    let level = duk_get_current_magic(ctx);
-    if level < DUK_LOG_TRACE || level > DUK_LOG_FATAL {
-        warn!("log_handler called with invalid level: {}", level);
-        return 0;
+    /// Wraps the specified global function so that the arguments of all of its calls are
+    /// recorded, while still calling the original function.
+    pub fn spy(&self, name: &str) -> Result<Spy<'_>> {
+        const SPY: &str = "(function (target) {
+          var calls = [];
+          var spy = function () {
+            calls.push(Array.prototype.slice.call(arguments));
+            return target.apply(this, arguments);
+          };
+          return [spy, calls];
+        })";
+        let target = self.global_function(name)?;
+        self.install_spy(name, SPY, &target)
+    }
+
+    /// Waits for a debug client (like the `duk_debug.js` web client shipped with Duktape) to
+    /// connect to the specified listener, and attaches the debugger of this context to it.
+    ///
+    /// Any previously attached debugger is detached first.  Requires the `debugger` feature.
+    #[cfg(feature = "debugger")]
+    pub fn attach_debugger(&self, listener: net::TcpListener) -> Result<()> {
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        self.attach_debugger_transport(stream);
+        Ok(())
+    }
+
+    /// Attaches the debugger of this context to an arbitrary transport, like an in-process
+    /// channel created with `debugger::channel`, or a tunnel over an existing connection of the
+    /// host.
+    ///
+    /// Any previously attached debugger is detached first.  Requires the `debugger` feature.
+    #[cfg(feature = "debugger")]
+    pub fn attach_debugger_transport<T>(&self, transport: T)
+        where T: debugger::DebugTransport + 'static
+    {
+        self.detach_debugger();
+        let session = Box::into_raw(Box::new(debugger::Session::new(Box::new(transport))));
+        self.debugger.set(Some(session));
+        unsafe {
+            debugger::attach(self.raw, session);
+        }
+    }
+
+    /// Detaches the currently attached debugger, if any.
+    #[cfg(feature = "debugger")]
+    pub fn detach_debugger(&self) {
+        self.inspector.borrow_mut().take();
+        if let Some(session) = self.debugger.take() {
+            unsafe {
+                if !(*session).is_detached() {
+                    duktape_sys::duk_debugger_detach(self.raw);
+                }
+                drop(Box::from_raw(session));
+            }
+        }
+    }
+
+    /// Processes pending debug messages.
+    ///
+    /// Debug messages are only processed while scripts are executing, so this should be called
+    /// periodically when the host is idle, so that the debug client stays responsive.
+    #[cfg(feature = "debugger")]
+    pub fn debugger_cooperate(&self) {
+        unsafe {
+            duktape_sys::duk_debugger_cooperate(self.raw);
+        }
+    }
+
+    /// Registers a source map (as JSON) for the specified generated file, so that error locations,
+    /// stack traces and debugger locations refer to the original sources.
+    pub fn register_source_map(&self, file_name: &str, source_map: &str) -> Result<()> {
+        let json = Value::String(source_map.to_owned());
+        let value = self.global_object().get("JSON")?.call_method("parse", &[&json])?.to_value();
+        let map = source_map::SourceMap::from_value(&value)?;
+        self.source_maps.borrow_mut().register(file_name, map);
+        Ok(())
+    }
+
+    /// Gives access to the source maps of this context, for example to unregister a map or to
+    /// translate locations reported by other means.
+    pub fn source_maps(&self) -> cell::RefMut<'_, source_map::SourceMapRegistry> {
+        self.source_maps.borrow_mut()
+    }
+
+    /// Adds a breakpoint at the specified line of the specified file.  The file name is the one
+    /// given to `eval_string_with_filename` and friends.
+    ///
+    /// When a breakpoint is hit, the handler registered with `on_pause` is called, which decides
+    /// how execution continues.  This cannot be combined with an external debug client attached using
+    /// `attach_debugger`.  Requires the `debugger` feature.
+    #[cfg(feature = "debugger")]
+    pub fn add_breakpoint(&self, file_name: &str, line: u32) -> Result<()> {
+        self.inspector()?.add_breakpoint(file_name, line);
+        self.inspector_cooperate()
+    }
+
+    /// Removes a breakpoint that was added with `add_breakpoint`, returning whether the
+    /// breakpoint existed.  Requires the `debugger` feature.
+    #[cfg(feature = "debugger")]
+    pub fn remove_breakpoint(&self, file_name: &str, line: u32) -> Result<bool> {
+        let removed = self.inspector()?.remove_breakpoint(file_name, line);
+        self.inspector_cooperate()?;
+        Ok(removed)
+    }
+
+    /// Registers a handler that is called with the call stack and the local variables every time
+    /// execution is paused, replacing any previously registered handler.  The returned action
+    /// decides whether execution resumes or steps to the next pause.
+    ///
+    /// Requires the `debugger` feature.
+    #[cfg(feature = "debugger")]
+    pub fn on_pause<F>(&self, handler: F) -> Result<()>
+        where F: Fn(&debugger::PausedState) -> debugger::StepAction + 'static
+    {
+        self.inspector()?.set_pause_handler(rc::Rc::new(handler));
+        Ok(())
+    }
+
+    /// Pauses execution at the next statement that is executed, which calls the handler
+    /// registered with `on_pause`.  Requires the `debugger` feature.
+    #[cfg(feature = "debugger")]
+    pub fn pause(&self) -> Result<()> {
+        self.inspector()?.pause();
+        self.inspector_cooperate()
+    }
+
+    /// Sets whether execution should pause before an error is thrown that is not caught by any
+    /// Javascript code.  The paused state contains the error message.  Disabled by default.
+    ///
+    /// Requires the `debugger` feature.
+    #[cfg(feature = "debugger")]
+    pub fn set_pause_on_uncaught(&self, pause: bool) -> Result<()> {
+        self.inspector()?.set_pause_on_uncaught(pause);
+        Ok(())
+    }
+
+    /// Starts recording which lines and functions are executed, discarding any coverage that has
+    /// been recorded so far.  Locations are translated through registered source maps.
+    ///
+    /// Requires the `debugger` feature, and can't be used while an external debugger is attached.
+    #[cfg(feature = "debugger")]
+    pub fn start_coverage(&self) -> Result<()> {
+        self.inspector()?.start_coverage();
+        self.inspector_cooperate()
+    }
+
+    /// Returns the coverage recorded since `start_coverage` was called, while recording
+    /// continues.  Requires the `debugger` feature.
+    #[cfg(feature = "debugger")]
+    pub fn coverage(&self) -> Result<coverage::CoverageReport> {
+        self.inspector()?
+            .coverage()
+            .ok_or_else(|| ErrorKind::Debugger("coverage is not being recorded".to_owned()).into())
+    }
+
+    /// Stops recording coverage, and returns the coverage recorded since `start_coverage` was
+    /// called.  Requires the `debugger` feature.
+    #[cfg(feature = "debugger")]
+    pub fn stop_coverage(&self) -> Result<coverage::CoverageReport> {
+        let report = self.inspector()?
+            .stop_coverage()
+            .ok_or_else(|| -> Error {
+                ErrorKind::Debugger("coverage is not being recorded".to_owned()).into()
+            })?;
+        self.inspector_cooperate()?;
+        Ok(report)
+    }
+
+    /// Starts tracing every executed line, calling the handler with the location (and optionally
+    /// the local variables) before the line is executed.  Replaces any active trace.
+    ///
+    /// Tracing slows execution down considerably, so `TraceOptions::max_lines_per_second` can be
+    /// used to bound the overhead of the handler.  Requires the `debugger` feature, and can't be
+    /// used while an external debugger is attached.
+    #[cfg(feature = "debugger")]
+    pub fn start_trace<F>(&self, options: debugger::TraceOptions, handler: F) -> Result<()>
+        where F: Fn(&debugger::TraceEvent) + 'static
+    {
+        self.inspector()?.start_trace(options, rc::Rc::new(handler));
+        self.inspector_cooperate()
+    }
+
+    /// Like `start_trace`, but writes one line per traced line to the specified writer.  Errors
+    /// while writing are ignored.  Requires the `debugger` feature.
+    #[cfg(feature = "debugger")]
+    pub fn start_trace_to_writer<W>(&self, options: debugger::TraceOptions, writer: W) -> Result<()>
+        where W: io::Write + 'static
+    {
+        let writer = cell::RefCell::new(writer);
+        self.start_trace(options, move |event| {
+            let _ = writeln!(writer.borrow_mut(), "{}", event);
+        })
+    }
+
+    /// Stops tracing.  Requires the `debugger` feature.
+    #[cfg(feature = "debugger")]
+    pub fn stop_trace(&self) -> Result<()> {
+        if !self.inspector()?.stop_trace() {
+            return Err(ErrorKind::Debugger("no trace is active".to_owned()).into());
+        }
+        self.inspector_cooperate()
+    }
+
+    /// Calls the hook before every executed line with its file name and line number (after
+    /// applying source maps), replacing any previous hook.
+    ///
+    /// If the hook returns `StatementAction::Abort`, the running evaluation or call is aborted with
+    /// a `RangeError` before the line is executed, which can't be caught by the script.  Like
+    /// tracing, this slows execution down considerably.  Requires the `debugger` feature, and
+    /// can't be used while an external debugger is attached.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use duk::debugger::StatementAction;
+    ///
+    /// let ctx = duk::Context::new();
+    /// ctx.set_statement_hook(|_, line| if line > 2 {
+    ///         StatementAction::Abort
+    ///     } else {
+    ///         StatementAction::Continue
+    ///     })
+    ///     .unwrap();
+    /// assert!(ctx.eval_string("var a = 1;\nvar b = 2;\nvar c = 3;").is_err());
+    /// assert_eq!(duk::Value::Boolean(true), ctx.eval_string("a + b === 3").unwrap().to_value());
+    /// ```
+    #[cfg(feature = "debugger")]
+    pub fn set_statement_hook<F>(&self, hook: F) -> Result<()>
+        where F: Fn(&str, u32) -> debugger::StatementAction + 'static
+    {
+        let heap_data = self.heap_data;
+        self.inspector()?.set_statement_hook(rc::Rc::new(move |file_name: &str, line| {
+            let action = hook(file_name, line);
+            if action == debugger::StatementAction::Abort {
+                unsafe {
+                    (*heap_data).abort_requested = true;
+                }
+            }
+            action
+        }));
+        install_exec_timeout_check();
+        self.inspector_cooperate()
+    }
+
+    /// Removes the statement hook.  Requires the `debugger` feature.
+    #[cfg(feature = "debugger")]
+    pub fn clear_statement_hook(&self) -> Result<()> {
+        if !self.inspector()?.clear_statement_hook() {
+            return Err(ErrorKind::Debugger("no statement hook is set".to_owned()).into());
+        }
+        self.inspector_cooperate()
+    }
+
+    /// Returns the in-process debug client, attaching it first if necessary.
+    #[cfg(feature = "debugger")]
+    fn inspector(&self) -> Result<debugger::Inspector> {
+        if let Some(ref inspector) = *self.inspector.borrow() {
+            return Ok(inspector.clone());
+        }
+
+        if self.debugger.get().is_some() {
+            return Err(ErrorKind::Debugger("an external debugger is attached".to_owned()).into());
+        }
+
+        let inspector = debugger::Inspector::new(self.source_maps.clone());
+        let session = Box::into_raw(Box::new(debugger::Session::new(inspector.transport())));
+        self.debugger.set(Some(session));
+        unsafe {
+            debugger::attach(self.raw, session);
+        }
+        *self.inspector.borrow_mut() = Some(inspector.clone());
+        // Duktape pauses as soon as a debugger is attached, so resume right away
+        self.debugger_cooperate();
+        Ok(inspector)
+    }
+
+    /// Lets Duktape process the requests queued by the in-process debug client.
+    #[cfg(feature = "debugger")]
+    fn inspector_cooperate(&self) -> Result<()> {
+        self.debugger_cooperate();
+        match self.inspector.borrow().as_ref().and_then(|i| i.take_error()) {
+            Some(message) => Err(ErrorKind::Debugger(message).into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Starts recording a sampling profile of all Javascript code executed by this context
+    /// during the specified duration, replacing any profile that is still being recorded.
+    ///
+    /// Requires the `profiler` feature.
+    #[cfg(feature = "profiler")]
+    pub fn profile(&self, duration: time::Duration) -> profiler::Profile {
+        let (sampler, profile) = profiler::Sampler::new(self.raw, duration, self.source_maps.clone());
+        install_exec_timeout_check();
+        unsafe {
+            (*self.heap_data).sampler = Some(sampler);
+        }
+        profile
+    }
+
+    /// Returns a human-readable description of the value stack of this context, meant for logging
+    /// the interpreter state when something goes wrong.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::new();
+    /// assert_eq!("ctx: top=0, stack=[]", ctx.dump());
+    /// ```
+    pub fn dump(&self) -> String {
+        unsafe {
+            duktape_sys::duk_push_context_dump(self.raw);
+            let mut len = 0;
+            let data = duktape_sys::duk_get_lstring(self.raw, -1, &mut len);
+            let dump = String::from_utf8_lossy(slice::from_raw_parts(data as *const u8, len))
+                .into_owned();
+            duktape_sys::duk_pop(self.raw);
+            dump
+        }
+    }
+
+    /// Changes how this context converts Javascript values into `Value`s from now on.  See the
+    /// `conversion` module for details.
+    pub fn set_conversion_options(&self, options: conversion::ConversionOptions) {
+        unsafe {
+            (*self.heap_data).conversion.set(options);
+        }
+    }
+
+    /// Returns how this context converts Javascript values into `Value`s.
+    pub fn conversion_options(&self) -> conversion::ConversionOptions {
+        unsafe { (*self.heap_data).conversion.get() }
+    }
+
+    /// Returns the version and configuration of the embedded Duktape, and the enabled cargo
+    /// features of this crate.  See the `build_info` module for details.
+    pub fn build_info(&self) -> build_info::BuildInfo {
+        unsafe { build_info::probe(self.raw) }
+    }
+
+    /// Returns the number of values on the value stack of this context.
+    ///
+    /// The stack is empty whenever no Rust code of this crate is running, so a non-zero value
+    /// outside of a call indicates a leak.
+    pub fn stack_top(&self) -> usize {
+        unsafe { duktape_sys::duk_get_top(self.raw) as usize }
+    }
+
+    /// Runs the closure with raw access to the underlying Duktape context, for the operations
+    /// that the safe API doesn't cover yet.  See the `raw` module for details.
+    ///
+    /// The closure must leave the value stack as it found it, which debug builds check.
+    pub fn with_raw<F, R>(&self, action: F) -> R
+        where F: FnOnce(raw::RawScope<'_>) -> R
+    {
+        let top = self.stack_top();
+        let result = action(raw::RawScope::new(self.raw));
+        if cfg!(debug_assertions) {
+            let after = self.stack_top();
+            assert!(after <= top, "raw scope left {} value(s) on the stack", after - top);
+            assert!(after >= top, "raw scope popped {} value(s) that it didn't push", top - after);
+        }
+        result
+    }
+
+    /// Checks that the value stack of this context is empty, as it should be whenever no Rust code
+    /// of this crate is running, like between evaluations and calls.
+    ///
+    /// A leak points at a bug in this crate or at misuse of `Context::with_raw`.  Debug builds also
+    /// check that every evaluation and call leaves the stack as it found it, and panic otherwise.
+    pub fn check_stack_balanced(&self) -> result::Result<(), StackImbalance> {
+        match self.stack_top() {
+            0 => Ok(()),
+            leaked => Err(StackImbalance { leaked }),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn assert_clean(&self) {
+        if let Err(imbalance) = self.check_stack_balanced() {
+            panic!("context stack is not empty: {}", imbalance);
+        }
+    }
+
+    /// Pushes the specified property key, which may contain NUL characters.  Duktape interns
+    /// strings in the string table of the heap, so pushing a key that is in use doesn't allocate.
+    unsafe fn push_key(&self, key: &str) {
+        duktape_sys::duk_push_lstring(self.raw, key.as_ptr() as *const os::raw::c_char, key.len());
+    }
+
+    /// Pushes the specified global, like `duk_get_global_string` but with `push_key`.
+    unsafe fn get_global(&self, name: &str) {
+        duktape_sys::duk_push_global_object(self.raw);
+        self.push_key(name);
+        duktape_sys::duk_get_prop(self.raw, -2);
+        duktape_sys::duk_remove(self.raw, -2);
+    }
+
+    /// Pops a value into the specified global, like `duk_put_global_string` but with `push_key`.
+    unsafe fn put_global(&self, name: &str) {
+        duktape_sys::duk_push_global_object(self.raw);
+        self.push_key(name);
+        // Stack: [ ... value global key ]
+        duktape_sys::duk_dup(self.raw, -3);
+        duktape_sys::duk_put_prop(self.raw, -3);
+        duktape_sys::duk_pop_2(self.raw);
+    }
+
+    /// Calls the specified global function with the arguments pushed by `push_args`, which returns
+    /// the number of pushed arguments.  The function comes from the call cache, if it holds it.
+    ///
+    /// Leaves the global object and the result (or error) on the stack.
+    unsafe fn pcall_global<F>(&self, name: &str, push_args: F) -> duktape_sys::duk_ret_t
+        where F: FnOnce() -> usize
+    {
+        duktape_sys::duk_push_global_object(self.raw);
+        let obj_idx = duktape_sys::duk_get_top_index(self.raw);
+        if self.call_cache.push(self.raw, name) {
+            duktape_sys::duk_dup(self.raw, obj_idx);
+            let nargs = push_args();
+            let call = || duktape_sys::duk_pcall_method(self.raw, nargs as duktape_sys::duk_idx_t);
+            return self.recorded(|| recording::call_input(self.raw, name, nargs), call);
+        }
+
+        self.push_key(name);
+        self.call_cache.insert(self.raw, name, -1);
+        let nargs = push_args();
+        self.recorded(|| recording::call_input(self.raw, name, nargs),
+                      || duktape_sys::duk_pcall_prop(self.raw, obj_idx, nargs as duktape_sys::duk_idx_t))
+    }
+
+    /// Runs a `pcall`-like action that leaves its result or error on the stack, and writes it to
+    /// the recording (if any) along with the input described by `input`, unless it is nested
+    /// within another recorded evaluation or call.
+    unsafe fn recorded<I, F>(&self, input: I, action: F) -> duktape_sys::duk_ret_t
+        where I: FnOnce() -> String,
+              F: FnOnce() -> duktape_sys::duk_ret_t
+    {
+        let recording = self.recorder.borrow_mut().as_mut().is_some_and(|r| r.begin());
+        if !recording {
+            return action();
+        }
+
+        let input = input();
+        let ret = action();
+        let outcome = recording::outcome(self.raw, ret);
+        if let Some(ref mut recorder) = *self.recorder.borrow_mut() {
+            recorder.finish(&input, &outcome);
+        }
+        ret
+    }
+
+    /// Calls the spy factory function in `factory` with the argument, and replaces the specified
+    /// global with the spy function it returns along with the array of calls.
+    fn install_spy(&self, name: &str, factory: &str, arg: &dyn Argument) -> Result<Spy<'_>> {
+        unsafe {
+            let ret = duktape_sys::duk_peval_lstring(self.raw, factory.as_ptr() as *const i8, factory.len());
+            assert_eq!(0, ret, "failed to compile spy factory");
+            arg.push_to_context(self);
+            if duktape_sys::duk_pcall(self.raw, 1) != 0 {
+                return Err(self.pop_error());
+            }
+            // Stack: [ ... [spy calls] ]
+            duktape_sys::duk_get_prop_index(self.raw, -1, 0);
+            self.put_global(name);
+            duktape_sys::duk_get_prop_index(self.raw, -1, 1);
+            let calls = self.pop_reference();
+            duktape_sys::duk_pop(self.raw);
+            Ok(Spy { calls })
+        }
+    }
+
+    /// Runs an evaluation or a call, and reports it to the metrics, error reporter and hooks (if
+    /// any) and as a span.
+    fn measure<T, F>(&self, operation: metrics::Operation, name: &str, action: F) -> Result<T>
+        where F: FnOnce() -> Result<T>
+    {
+        let _span = match operation {
+            metrics::Operation::Eval => spans::Span::eval(name),
+            metrics::Operation::Call => spans::Span::call(name),
+        };
+        let top = if cfg!(debug_assertions) { self.stack_top() } else { 0 };
+        #[cfg(feature = "timeout")]
+        let started = unsafe {
+            let heap_data = &mut *self.heap_data;
+            match (heap_data.timeout, heap_data.deadline) {
+                // Nested evaluations and calls count towards the outermost one
+                (Some(timeout), None) => {
+                    heap_data.deadline = Some(time::Instant::now() + timeout);
+                    true
+                }
+                _ => false,
+            }
+        };
+        let hooked = unsafe { !(*self.heap_data).hooks.is_empty() };
+        if hooked {
+            unsafe {
+                hooks::notify(self.raw, |hooks| match operation {
+                    metrics::Operation::Eval => hooks.eval_start(name),
+                    metrics::Operation::Call => hooks.call_start(name),
+                });
+            }
+        }
+        let measured = self.metrics.is_some() || hooked;
+        let start = if measured { Some(time::Instant::now()) } else { None };
+        let result = action();
+        let duration = start.map(|start| start.elapsed());
+        if let (Some(metrics), Some(duration)) = (&self.metrics, duration) {
+            metrics.operation(operation, name, duration, result.is_ok());
+        }
+        if let (Some(reporter), Err(Error(ErrorKind::Js(ref error), _))) = (&self.reporter, &result) {
+            reporter.report(error);
+        }
+        #[cfg(feature = "debugger")]
+        unsafe {
+            // The abort error has bubbled out of Duktape by now
+            (*self.heap_data).abort_requested = false;
+        }
+        #[cfg(feature = "timeout")]
+        let timed_out = unsafe {
+            let heap_data = &mut *self.heap_data;
+            let now = time::Instant::now();
+            let past_deadline = heap_data.deadline.is_some_and(|deadline| now >= deadline);
+            let timed_out = started && result.is_err() && past_deadline;
+            if started {
+                heap_data.deadline = None;
+            }
+            timed_out
+        };
+        #[cfg(not(feature = "timeout"))]
+        let timed_out = false;
+        if let Some(duration) = duration.filter(|_| hooked) {
+            unsafe { self.end_hooks(operation, name, duration, timed_out, result.as_ref().err()) };
+        }
+        if cfg!(debug_assertions) && self.stack_top() != top {
+            panic!("{} left the stack unbalanced: {} value(s) before, {} after",
+                   name,
+                   top,
+                   self.stack_top());
+        }
+        result
+    }
+
+    /// Reports the end of an evaluation or a call to the hooks, after the limits that it ran into.
+    unsafe fn end_hooks(&self,
+                        operation: metrics::Operation,
+                        name: &str,
+                        duration: time::Duration,
+                        timed_out: bool,
+                        error: Option<&Error>) {
+        let allocation_failed = mem::replace(&mut (*self.heap_data).allocation_failed, false);
+        hooks::notify(self.raw, |hooks| {
+            if timed_out {
+                hooks.limit_exceeded(hooks::Limit::Timeout);
+            }
+            if allocation_failed {
+                hooks.limit_exceeded(hooks::Limit::Memory);
+            }
+            match operation {
+                metrics::Operation::Eval => hooks.eval_end(name, duration, error),
+                metrics::Operation::Call => hooks.call_end(name, duration, error),
+            }
+        });
+    }
+
+    /// Keeps a copy of evaluated code for the error reporter, if any.
+    fn remember_source(&self, file_name: &str, source: &str) {
+        if let Some(ref reporter) = self.reporter {
+            reporter.remember(file_name, source);
+        }
+    }
+
+    /// Reports data that was copied out of the Duktape heap to the metrics, if any.
+    fn converted(&self, bytes: usize) {
+        if let Some(ref metrics) = self.metrics {
+            metrics.bytes_converted(bytes);
+        }
+    }
+
+    fn gen_stash_idx(&self) -> duktape_sys::duk_uarridx_t {
+        self.next_stash_idx.fetch_add(1, atomic::Ordering::Relaxed) as duktape_sys::duk_uarridx_t
+    }
+
+    unsafe fn pop_reference(&self) -> Reference {
+        let idx = self.gen_stash_idx();
+        duktape_sys::duk_push_heap_stash(self.raw);
+        duktape_sys::duk_dup(self.raw, -2);
+        duktape_sys::duk_put_prop_index(self.raw, -2, idx);
+        duktape_sys::duk_pop_2(self.raw);
+
+        Reference {
+            ctx: self,
+            stash_idx: idx,
+        }
+    }
+
+    fn syntax_error(&self, message: &str) -> Error {
+        let msg = ffi::CString::new(message.replace('\0', "")).unwrap();
+        unsafe {
+            duktape_sys::duk_push_error_object(self.raw,
+                                               duktape_sys::DUK_ERR_SYNTAX_ERROR,
+                                               msg.as_ptr());
+            self.pop_error()
+        }
+    }
+
+    unsafe fn buffer_type_error(&self) -> Error {
+        let msg = ffi::CString::new("value is not a buffer").unwrap();
+        duktape_sys::duk_push_error_object(self.raw, duktape_sys::DUK_ERR_TYPE_ERROR, msg.as_ptr());
+        self.pop_error()
+    }
+
+    unsafe fn pop_error(&self) -> Error {
+        if let Some(violation) = plugins::violation(self.raw, -1) {
+            duktape_sys::duk_pop(self.raw);
+            return ErrorKind::Policy(violation).into();
+        }
+        let mut e = JsError::get(self.raw, -1);
+        duktape_sys::duk_pop(self.raw);
+        self.apply_source_maps(&mut e);
+        ErrorKind::Js(e).into()
+    }
+
+    /// Rewrites the locations of an error to refer to the original sources, if there are source
+    /// maps registered for the generated files.
+    fn apply_source_maps(&self, error: &mut JsError) {
+        let source_maps = self.source_maps.borrow();
+        if source_maps.is_empty() {
+            return;
+        }
+
+        let original = match (&error.file_name, error.line_number) {
+            (Some(file_name), Some(line)) => source_maps.lookup(file_name, line as u32),
+            _ => None,
+        };
+        if let Some(original) = original {
+            error.file_name = Some(original.file_name);
+            error.line_number = Some(original.line_number as usize);
+        }
+        if let Some(ref mut stack) = error.stack {
+            *stack = source_maps.rewrite_stack(stack);
+        }
+    }
+
+    unsafe fn pop_discard_or_error(&self, ret: duktape_sys::duk_ret_t) -> Result<()> {
+        if ret == 0 {
+            duktape_sys::duk_pop(self.raw);
+            Ok(())
+        } else {
+            Err(self.pop_error())
+        }
+    }
+
+    unsafe fn pop_reference_or_error(&self, ret: duktape_sys::duk_ret_t) -> Result<Reference> {
+        if ret == 0 {
+            Ok(self.pop_reference())
+        } else {
+            Err(self.pop_error())
+        }
+    }
+
+    unsafe fn pop_bytes_or_error(&self, ret: duktape_sys::duk_ret_t) -> Result<Vec<u8>> {
+        if ret == 0 {
+            let bytes = codec::buffer_bytes(self.raw);
+            duktape_sys::duk_pop(self.raw);
+            Ok(bytes)
+        } else {
+            Err(self.pop_error())
+        }
+    }
+}
+
+impl fmt::Debug for Context {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Context({:p})", self.raw)
+    }
+}
+
+/// Returns the version of the embedded Duktape.
+///
+/// # Examples
+///
+/// ```
+/// println!("running on Duktape {}", duk::version());
+/// ```
+pub fn version() -> build_info::Version {
+    build_info::version()
+}
+
+impl fmt::Display for StackImbalance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} value(s) left on the stack", self.leaked)
+    }
+}
+
+impl error::Error for StackImbalance {
+    fn description(&self) -> &str {
+        "values left on the stack"
+    }
+}
+
+impl Default for Context {
+    fn default() -> Context {
+        Context::new()
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        unsafe { duktape_sys::duk_destroy_heap(self.raw) };
+        if let Some(ptr) = self.module_resolver {
+            drop(unsafe { Box::from_raw(ptr) });
+        }
+        if let Some(ptr) = self.module_loader {
+            drop(unsafe { Box::from_raw(ptr) });
+        }
+        drop(unsafe { Box::from_raw(self.heap_data) });
+        #[cfg(feature = "debugger")]
+        {
+            if let Some(ptr) = self.debugger.take() {
+                drop(unsafe { Box::from_raw(ptr) });
+            }
+        }
+    }
+}
+
+impl ContextBuilder {
+
+    pub fn with_module_resolver(mut self, module_resolver: Box<ModuleResolver>) -> Self {
+        self.module_resolver = Some(module_resolver);
+        self
+    }
+
+    /// Loads the sources of modules by their resolved ids.  Byte order marks and CRLF line
+    /// endings in the sources are handled as described in the `sources` module.
+    pub fn with_module_loader(mut self, module_loader: Box<ModuleLoader>) -> Self {
+        self.module_loader = Some(module_loader);
+        self
+    }
+
+    /// Makes the context use a pool allocator with fixed block sizes tuned for Duktape, instead
+    /// of the system allocator.
+    ///
+    /// The pool serves small allocations from chunks that it frees along with the context, so
+    /// hosts that create and destroy many short-lived contexts make far fewer calls to the system
+    /// allocator: a context that builds a thousand small objects asks for about 11,000 allocations,
+    /// which the pool serves with about 50 allocations from the system allocator.  With glibc's
+    /// `malloc`, that doesn't make such a context measurably faster (about 3.4 ms either way in
+    /// the `pool_bench` example), so it mostly helps where `malloc` is slow or fragments easily.
+    pub fn with_pool_allocator(self) -> Self {
+        self.with_allocator(Box::new(pool::PoolAllocator::new()))
+    }
+
+    /// Makes the context serve all of its memory from the specified allocator, instead of the
+    /// system allocator.  See the `allocator` module for details.
+    pub fn with_allocator(mut self, allocator: Box<dyn allocator::Allocator>) -> Self {
+        self.allocator = Some(allocator);
+        self
+    }
+
+    /// Makes `Date` read the current time from the specified clock, instead of the system time.
+    /// Requires the `clock` feature, see the `clock` module.
+    #[cfg(feature = "clock")]
+    pub fn with_clock(mut self, clock: Box<dyn clock::Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Reports the duration and outcome of every evaluation and call, as well as the amount of data
+    /// converted into Rust values, to the specified metrics.
+    pub fn with_metrics(mut self, metrics: Box<dyn metrics::Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Reports every evaluation or call that fails with a Javascript error to the specified sink,
+    /// including the line of code where the error was thrown.
+    ///
+    /// The context keeps a copy of all evaluated code to look up those lines.  See the `report`
+    /// module for details.
+    pub fn with_error_reporter(mut self, sink: Box<dyn report::ErrorSink>) -> Self {
+        self.error_sink = Some(sink);
+        self
+    }
+
+    /// Sends the lifecycle events of the context, like evaluations, calls and thrown errors, to
+    /// the specified hooks.  Hooks that are added later receive each event after the earlier
+    /// ones.  See the `hooks` module for details.
+    pub fn with_hooks(mut self, hooks: Box<dyn hooks::ContextHooks>) -> Self {
+        self.hooks.push(hooks);
+        self
+    }
+
+    /// Requires script files to be signed, and checks their signatures with the specified
+    /// verifier before they are compiled.  See the `signatures` module for details.
+    pub fn with_verifier(mut self, verifier: Box<dyn signatures::Verifier>) -> Self {
+        self.verifier = Some(verifier);
+        self
+    }
+
+    /// Compacts the built-in objects after the context has been set up, which lowers the memory
+    /// footprint of each context at the cost of a slightly slower context creation.
+    ///
+    /// Combine this with the `low-memory` cargo feature for hosts that run thousands of
+    /// contexts.
+    pub fn with_compacted_builtins(mut self) -> Self {
+        self.compact_builtins = true;
+        self
+    }
+
+    /// Installs the `setTimeout`, `setInterval`, `clearTimeout` and `clearInterval` globals,
+    /// allowing at most `max_timers` outstanding timers at a time.
+    ///
+    /// Timer callbacks only run when the host pumps the event loop with
+    /// `Context::pump_event_loop` or `Context::run_event_loop`.  Scheduling more timers than
+    /// allowed throws a `RangeError`.
+    pub fn with_timers(mut self, max_timers: usize) -> Self {
+        self.max_timers = Some(max_timers);
+        self
+    }
+
+    /// Sends the messages of the `console` object to the specified sink, instead of logging them
+    /// with the `duk::console` target.  See the `console` module for details.
+    #[cfg(feature = "console")]
+    pub fn with_console_sink(mut self, sink: Box<dyn console::ConsoleSink>) -> Self {
+        self.console_sink = Some(sink);
+        self
+    }
+
+    /// Provides the `host:fs` module, which gives scripts access to the files below the root of
+    /// the sandbox, and the `host:path` module, which joins and normalizes paths within it.  See
+    /// the `filesystem` module for details.
+    ///
+    /// Scripts can `require` the module even if the context has no module resolver and loader.
+    pub fn with_filesystem(mut self, sandbox: filesystem::Sandbox) -> Self {
+        self.filesystem = Some(sandbox);
+        self
+    }
+
+    /// Provides the `host:process` module, which tells scripts the platform, the version of the
+    /// host and the allowlisted environment variables.  See the `process` module for details.
+    pub fn with_process_info(mut self, info: process::ProcessInfo) -> Self {
+        self.process_info = Some(info);
+        self
+    }
+
+    /// Provides the `host:db` module, whose statements run on the database that the driver opens
+    /// for the specified scope.  Give every plugin its own scope to keep their data apart.  See
+    /// the `db` module for details.
+    pub fn with_database(mut self, driver: Box<dyn db::Driver>, scope: &str) -> Self {
+        self.database = Some(db::Database::new(driver, scope.to_owned()));
+        self
+    }
+
+    /// Preloads polyfills for the ES2015+ built-ins that Duktape lacks, like `Object.assign`,
+    /// `Array.prototype.find` or `Promise`.  See the `polyfills` module for details.
+    pub fn with_polyfills(mut self, polyfills: polyfills::PolyfillSet) -> Self {
+        self.polyfills = polyfills;
+        self
+    }
+
+    /// Provides the `host:storage` module, a key-value store whose values are kept in the
+    /// specified store, within the quota.  See the `storage` module for details.
+    pub fn with_storage(mut self, store: Box<dyn storage::Store>, quota: storage::Quota) -> Self {
+        self.storage = Some(storage::Storage::new(store, quota));
+        self
+    }
+
+    /// Installs a blocking `fetch` global that sends requests through the specified client, but
+    /// only to the allowed destinations.
+    ///
+    /// A destination like `https://api.example.com` allows every URL on that origin, while
+    /// `https://api.example.com/v1/` only allows URLs below that path.  See the `fetch` module for
+    /// details.
+    #[cfg(feature = "fetch")]
+    pub fn with_fetch(mut self, client: Box<dyn fetch::HttpClient>, allowed: &[&str]) -> Self {
+        self.fetcher = Some(fetch::Fetcher::new(client, allowed.iter().map(|a| a.to_string()).collect()));
+        self
+    }
+
+    /// Registers a `host:ws` module whose sockets are opened by the specified transport.  The
+    /// sockets are polled when the host pumps the event loop.  See the `ws` module for details.
+    #[cfg(feature = "ws")]
+    pub fn with_websockets(mut self, transport: Box<dyn ws::Transport>) -> Self {
+        self.ws_transport = Some(transport);
+        self
+    }
+
+    /// Provides the `host:exec` module, which runs the commands that the policy allowlists.  Only
+    /// give it to contexts of trusted plugins.  See the `exec` module for details.
+    #[cfg(feature = "exec")]
+    pub fn with_exec(mut self, policy: exec::Policy) -> Self {
+        self.exec = Some(policy);
+        self
+    }
+
+    /// Sets how the context converts Javascript values into `Value`s, see
+    /// `Context::set_conversion_options`.
+    pub fn with_conversion_options(mut self, options: conversion::ConversionOptions) -> Self {
+        self.conversion_options = options;
+        self
+    }
+
+    /// Defines a global variable with the specified value, after everything else has been set
+    /// up, so it may replace a built-in global.  Defining the same global twice keeps the last
+    /// value.
+    ///
+    /// Panics when the context is built, if the name contains a NUL character.
+    pub fn with_global(mut self, name: &str, value: Value) -> Self {
+        self.globals.push((name.to_owned(), value));
+        self
+    }
+
+    /// Aborts every evaluation or call that runs for longer than the specified duration with a
+    /// `RangeError`, which scripts can't catch.  Evaluations and calls that are nested within
+    /// another one, like the calls of native functions back into Javascript, share its deadline.
+    ///
+    /// Duktape only checks the deadline every couple of hundred thousand instructions, so it can
+    /// be overrun slightly, and time spent in native functions isn't interrupted at all.  Requires
+    /// the `timeout` feature.
+    #[cfg(feature = "timeout")]
+    pub fn with_timeout(mut self, timeout: time::Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Builds the context.
+    ///
+    /// # Panics
+    ///
+    /// Panics if Duktape can't allocate the heap, see `ContextBuilder::try_build`.
+    pub fn build(self) -> Context {
+        self.try_build().expect("failed to create a context")
+    }
+
+    /// Builds the context, or fails with `ErrorKind::HeapCreation` if Duktape can't allocate the
+    /// heap.
+    pub fn try_build(self) -> Result<Context> {
+        Context::from_builder(self)
+    }
+
+    /// Builds the context, and restores the globals of a snapshot in it, see
+    /// `Context::from_snapshot`.  Globals of the snapshot replace the ones that the builder set
+    /// up.
+    pub fn build_from_snapshot(self, snapshot: &[u8]) -> Result<Context> {
+        let ctx = self.try_build()?;
+        unsafe { snapshot::restore(ctx.raw, snapshot)? };
+        Ok(ctx)
+    }
+}
+
+impl<'a> Reference<'a> {
+    /// Converts this reference to a `Value` which can be used for further processing by Rust code.
+    pub fn to_value(&self) -> Value {
+        let value = self.with_value(|| { unsafe { Value::get(self.ctx.raw, -1) } });
+        if self.ctx.metrics.is_some() {
+            self.ctx.converted(value.data_len());
+        }
+        value
+    }
+
+    /// Copies the value that this reference points to, and everything it references, with the
+    /// structured clone algorithm, so that it can be imported into any context with
+    /// `Context::import_clone`.
+    ///
+    /// Unlike `to_value`, this keeps shared and cyclic references, dates, regular expressions,
+    /// errors and typed arrays.  Values that can't be cloned, like functions, fail with
+    /// `ErrorKind::DataClone`.
+    pub fn structured_clone(&self) -> Result<structured_clone::StructuredClone> {
+        self.with_value(|| unsafe { structured_clone::read(self.ctx.raw, -1) })
+            .map_err(|message| ErrorKind::DataClone(message).into())
+    }
+
+    /// Copies the string that this reference points to directly into the specified buffer as
+    /// UTF-8, and returns the number of bytes copied.
+    ///
+    /// Values that aren't strings are coerced to strings first.  If the buffer is too small,
+    /// nothing is copied and the required buffer length is returned as the error instead.  Duktape
+    /// stores characters outside of the BMP as surrogate pairs, which are converted like in
+    /// `to_value`; only strings with them are converted in a temporary buffer first.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::new();
+    /// let value = ctx.eval_string("'abc' + 'def'").unwrap();
+    /// let mut buf = [0; 4];
+    /// assert_eq!(Err(6), value.copy_string_into(&mut buf));
+    /// let mut buf = [0; 16];
+    /// assert_eq!(Ok(6), value.copy_string_into(&mut buf));
+    /// assert_eq!(b"abcdef", &buf[..6]);
+    /// ```
+    pub fn copy_string_into(&self, buf: &mut [u8]) -> result::Result<usize, usize> {
+        self.with_value(|| {
+            unsafe {
+                let string = strings::to_utf8(coerce_bytes(self.ctx.raw, -1));
+                let bytes = string.as_bytes();
+                if bytes.len() > buf.len() {
+                    Err(bytes.len())
+                } else {
+                    buf[..bytes.len()].copy_from_slice(bytes);
+                    self.ctx.converted(bytes.len());
+                    Ok(bytes.len())
+                }
+            }
+        })
+    }
+
+    /// Appends the string that this reference points to onto the specified `String`, and returns
+    /// the number of bytes appended.
+    ///
+    /// Values that aren't strings are coerced to strings first.  Unlike `to_value`, this copies the
+    /// string data straight from the Duktape heap into the target, so a `String` can be reused
+    /// across many calls without further allocations.  Strings with characters outside of the BMP,
+    /// which Duktape stores as surrogate pairs, are decoded into the target instead.
+    pub fn append_string_to(&self, out: &mut String) -> usize {
+        self.with_value(|| {
+            unsafe {
+                let bytes = coerce_bytes(self.ctx.raw, -1);
+                let len = out.len();
+                match str::from_utf8(bytes) {
+                    Ok(string) => out.push_str(string),
+                    Err(_) => out.extend(strings::decode(bytes)),
+                }
+                self.ctx.converted(out.len() - len);
+                out.len() - len
+            }
+        })
+    }
+
+    /// Gets the property with the specified key, provided that this reference points to something
+    /// that is object coercible.
+    pub fn get(&self, name: &str) -> Result<Reference<'a>> {
+        self.with_value(|| {
+            unsafe {
+                if 0 == duktape_sys::duk_is_object_coercible(self.ctx.raw, -1) {
+                    let msg = ffi::CString::new("value is not object coercible").unwrap();
+                    duktape_sys::duk_push_error_object(self.ctx.raw,
+                                                       duktape_sys::DUK_ERR_TYPE_ERROR,
+                                                       msg.as_ptr());
+                    Err(self.ctx.pop_error())
+                } else {
+                    self.ctx.push_key(name);
+                    duktape_sys::duk_get_prop(self.ctx.raw, -2);
+                    Ok(self.ctx.pop_reference())
+                }
+            }
+        })
+    }
+
+    /// Turns this reference into a `FunctionRef`, provided that it points to something callable.
+    pub fn into_function(self) -> Result<FunctionRef<'a>> {
+        let callable = self.with_value(|| unsafe { duktape_sys::duk_is_callable(self.ctx.raw, -1) });
+        if 1 == callable {
+            Ok(FunctionRef { reference: self })
+        } else {
+            unsafe {
+                let msg = ffi::CString::new("value is not callable").unwrap();
+                duktape_sys::duk_push_error_object(self.ctx.raw,
+                                                   duktape_sys::DUK_ERR_TYPE_ERROR,
+                                                   msg.as_ptr());
+                Err(self.ctx.pop_error())
+            }
+        }
+    }
+
+    /// Retains the function that this reference points to as a `Function`, which outlives the
+    /// reference, provided that it is callable.
+    pub fn to_function(&self) -> Result<Function> {
+        self.with_value(|| unsafe {
+            if 0 == duktape_sys::duk_is_callable(self.ctx.raw, -1) {
+                duktape_sys::duk_push_error_object(self.ctx.raw,
+                                                   duktape_sys::DUK_ERR_TYPE_ERROR,
+                                                   nul_str(b"value is not callable\0"));
+                Err(self.ctx.pop_error())
+            } else {
+                Ok(functions::retain(self.ctx.raw, -1))
+            }
+        })
+    }
+
+    /// Turns this reference into an `ObjectRef`, provided that it points to an object.
+    pub fn into_object(self) -> Result<ObjectRef<'a>> {
+        let object = self.with_value(|| unsafe { duktape_sys::duk_is_object(self.ctx.raw, -1) });
+        if 1 == object {
+            Ok(ObjectRef { reference: self })
+        } else {
+            unsafe {
+                duktape_sys::duk_push_error_object(self.ctx.raw,
+                                                   duktape_sys::DUK_ERR_TYPE_ERROR,
+                                                   nul_str(b"value is not an object\0"));
+                Err(self.ctx.pop_error())
+            }
+        }
+    }
+
+    /// Turns this reference into a `LazyObject`, which converts individual properties on demand.
+    pub fn into_lazy_object(self) -> LazyObject<'a> {
+        LazyObject {
+            reference: self,
+            cache: cell::RefCell::new(collections::BTreeMap::new()),
+        }
+    }
+
+    /// Adds a finalizer to the object that this reference points to, which runs when Duktape
+    /// garbage collects the object, to release host resources that the object owns, like file
+    /// handles or sockets.  Finalizers run in the order they were added, and only once, even if a
+    /// Javascript finalizer of the object rescues it.
+    ///
+    /// The reference itself keeps the object alive, so the finalizer can only run after it is
+    /// dropped, when nothing else refers to the object either: right away for objects that are
+    /// only freed by reference counting, otherwise with the next garbage collection (see
+    /// `Context::gc`).  Objects that are still alive when the context is dropped are finalized
+    /// while the heap is destroyed.  A panic in a finalizer is caught and ignored.  Fails if the
+    /// value isn't an object, or if the object can't be extended, like a frozen one.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::cell;
+    /// use std::rc;
+    ///
+    /// let closed = rc::Rc::new(cell::Cell::new(false));
+    /// let ctx = duk::Context::new();
+    /// {
+    ///     let socket = ctx.eval_string("({fd: 3})").unwrap();
+    ///     let closed = closed.clone();
+    ///     socket.add_finalizer(move || closed.set(true)).unwrap();
+    /// }
+    /// ctx.gc();
+    /// assert!(closed.get());
+    /// ```
+    pub fn add_finalizer<F>(&self, finalizer: F) -> Result<()>
+        where F: FnOnce() + 'static
+    {
+        self.with_value(|| unsafe {
+            if 0 == duktape_sys::duk_is_object(self.ctx.raw, -1) {
+                duktape_sys::duk_push_error_object(self.ctx.raw,
+                                                   duktape_sys::DUK_ERR_TYPE_ERROR,
+                                                   nul_str(b"value is not an object\0"));
+                Err(self.ctx.pop_error())
+            } else if finalizers::add(self.ctx.raw, -1, Box::new(move |_| finalizer())) {
+                Ok(())
+            } else {
+                duktape_sys::duk_push_error_object(self.ctx.raw,
+                                                   duktape_sys::DUK_ERR_TYPE_ERROR,
+                                                   nul_str(b"object can't have a finalizer\0"));
+                Err(self.ctx.pop_error())
+            }
+        })
+    }
+
+    /// Calls the function that this reference points to without a `this` binding, using the
+    /// specified arguments.
+    ///
+    /// When the function executes, the `this` binding is set to `undefined` or the global object,
+    /// depending on if the function is strict or not.  Calling this function is equivalent to doing
+    /// `myfunc.call(undefined, args)` in Javascript.
+    pub fn call(&self, args: &[&Argument]) -> Result<Reference<'a>> {
+        self.ctx.measure(metrics::Operation::Call, "", || {
+            self.with_value(|| {
+                unsafe {
+                    duktape_sys::duk_dup_top(self.ctx.raw); // Because pcall consumes the stack
+                    for arg in args {
+                        arg.push_to_context(self.ctx);
+                    }
+                    let ret = duktape_sys::duk_pcall(self.ctx.raw,
+                                                     args.len() as duktape_sys::duk_idx_t);
+                    self.ctx.pop_reference_or_error(ret)
+                }
+            })
+        })
+    }
+
+    /// Like `call`, but uses pre-converted arguments from an `ArgsBuilder`.
+    pub fn call_args(&self, args: &ArgsBuilder<'a>) -> Result<Reference<'a>> {
+        self.ctx.measure(metrics::Operation::Call, "", || {
+            self.with_value(|| {
+                unsafe {
+                    duktape_sys::duk_dup_top(self.ctx.raw); // Because pcall consumes the stack
+                    args.push_all(self.ctx);
+                    let ret = duktape_sys::duk_pcall(self.ctx.raw, args.len as duktape_sys::duk_idx_t);
+                    self.ctx.pop_reference_or_error(ret)
+                }
+            })
+        })
+    }
+
+    /// Calls the function that this reference points to with an explicit `this` binding.
+    pub fn call_with_this(&self, this: &Argument, args: &[&Argument]) -> Result<Reference<'a>> {
+        self.ctx.measure(metrics::Operation::Call, "", || {
+            self.with_value(|| {
+                unsafe {
+                    duktape_sys::duk_dup_top(self.ctx.raw); // Because pcall consumes the stack
+                    this.push_to_context(self.ctx);
+
+                    for arg in args {
+                        arg.push_to_context(self.ctx);
+                    }
+                    let ret = duktape_sys::duk_pcall_method(self.ctx.raw,
+                                                            args.len() as duktape_sys::duk_idx_t);
+                    self.ctx.pop_reference_or_error(ret)
+                }
+            })
+        })
+    }
+
+    /// Calls a method on the object that this reference points to.
+    ///
+    /// The `this` binding will be set to the object during the execution of the function.  Calling
+    /// this function is equivalent to doing `myobj[name](args...)` in Javascript.
+    pub fn call_method(&self, name: &str, args: &[&Argument]) -> Result<Reference<'a>> {
+        self.ctx.measure(metrics::Operation::Call, name, || {
+            self.with_value(|| {
+                unsafe {
+                    let obj_idx = duktape_sys::duk_get_top_index(self.ctx.raw);
+                    self.ctx.push_key(name);
+
+                    for arg in args {
+                        arg.push_to_context(self.ctx);
+                    }
+
+                    let ret = duktape_sys::duk_pcall_prop(self.ctx.raw,
+                                                          obj_idx,
+                                                          args.len() as duktape_sys::duk_idx_t);
+
+                    self.ctx.pop_reference_or_error(ret)
+                }
+            })
+        })
+    }
+
+    /// Like `call_method`, but uses pre-converted arguments from an `ArgsBuilder`.
+    pub fn call_method_args(&self, name: &str, args: &ArgsBuilder<'a>) -> Result<Reference<'a>> {
+        self.ctx.measure(metrics::Operation::Call, name, || {
+            self.with_value(|| {
+                unsafe {
+                    let obj_idx = duktape_sys::duk_get_top_index(self.ctx.raw);
+                    self.ctx.push_key(name);
+                    args.push_all(self.ctx);
+
+                    let ret = duktape_sys::duk_pcall_prop(self.ctx.raw,
+                                                          obj_idx,
+                                                          args.len as duktape_sys::duk_idx_t);
+
+                    self.ctx.pop_reference_or_error(ret)
+                }
+            })
+        })
+    }
+
+    /// Calls the function that this reference points to as a constructor, with the specified
+    /// arguments.
+    pub fn new(&self, args: &[&Argument]) -> Result<Reference<'a>> {
+        self.ctx.measure(metrics::Operation::Call, "", || {
+            self.with_value(|| {
+                unsafe {
+                    duktape_sys::duk_dup_top(self.ctx.raw); // Because pnew consumes the stack
+                    for arg in args {
+                        arg.push_to_context(self.ctx);
+                    }
+                    let ret = duktape_sys::duk_pnew(self.ctx.raw, args.len() as duktape_sys::duk_idx_t);
+                    self.ctx.pop_reference_or_error(ret)
+                }
+            })
+        })
+    }
+
+    #[inline]
+    fn with_value<F, R>(&self, action: F) -> R
+        where F: FnOnce() -> R
+    {
+        unsafe {
+            self.push();
+            let result = action();
+            self.pop();
+            result
+        }
+    }
+
+    unsafe fn push(&self) {
+        duktape_sys::duk_push_heap_stash(self.ctx.raw);
+        duktape_sys::duk_get_prop_index(self.ctx.raw, -1, self.stash_idx);
+        duktape_sys::duk_remove(self.ctx.raw, -2);
+    }
+
+    unsafe fn pop(&self) {
+        duktape_sys::duk_pop(self.ctx.raw);
+    }
+}
+
+impl<'a> Argument for Reference<'a> {
+    unsafe fn push_to_context(&self, context: &Context) {
+        if context.raw != self.ctx.raw {
+            panic!("Tried to mix references coming from different contexts");
+        }
+
+        self.push();
+    }
+}
+
+impl<'a> PartialEq for Reference<'a> {
+    fn eq(&self, _: &Self) -> bool {
+        false
+    }
+}
+
+impl<'a> Drop for Reference<'a> {
+    fn drop(&mut self) {
+        unsafe {
+            duktape_sys::duk_push_heap_stash(self.ctx.raw);
+            duktape_sys::duk_del_prop_index(self.ctx.raw, -1, self.stash_idx);
+            duktape_sys::duk_pop(self.ctx.raw);
+        }
+    }
+}
+
+impl<'a> FunctionRef<'a> {
+    /// Calls the function, using the specified arguments.  See `Reference::call`.
+    pub fn call(&self, args: &[&dyn Argument]) -> Result<Reference<'a>> {
+        self.reference.call(args)
+    }
+
+    /// Calls the function, using pre-converted arguments.  See `Reference::call_args`.
+    pub fn call_args(&self, args: &ArgsBuilder<'a>) -> Result<Reference<'a>> {
+        self.reference.call_args(args)
+    }
+
+    /// Calls the function with an explicit `this` binding.  See `Reference::call_with_this`.
+    pub fn call_with_this(&self, this: &dyn Argument, args: &[&dyn Argument])
+                          -> Result<Reference<'a>> {
+        self.reference.call_with_this(this, args)
+    }
+
+    /// The underlying reference to the function.
+    pub fn as_reference(&self) -> &Reference<'a> {
+        &self.reference
+    }
+}
+
+impl<'a> Argument for FunctionRef<'a> {
+    unsafe fn push_to_context(&self, context: &Context) {
+        self.reference.push_to_context(context)
+    }
+}
+
+impl Function {
+    /// Calls the function with the specified arguments, in the context that it came from.  Fails
+    /// with a `TypeError` for any other context.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::cell;
+    /// use std::rc;
+    ///
+    /// let listener = rc::Rc::new(cell::RefCell::new(None));
+    /// let ctx = duk::Context::new();
+    /// let registered = listener.clone();
+    /// ctx.register_variadic("onSave", move |args| {
+    ///     *registered.borrow_mut() = args.function(0);
+    ///     Ok(duk::Value::Undefined)
+    /// });
+    /// ctx.eval_string("onSave(function (name) { return 'saved ' + name; })").unwrap();
+    /// let listener = listener.borrow_mut().take().unwrap();
+    /// assert_eq!(duk::Value::String("saved a.txt".to_owned()),
+    ///            listener.call(&ctx, &[&"a.txt"]).unwrap().to_value());
+    /// ```
+    pub fn call<'a>(&self, ctx: &'a Context, args: &[&dyn Argument]) -> Result<Reference<'a>> {
+        self.function_ref(ctx)?.call(args)
+    }
+
+    /// A `FunctionRef` to the function, for the other ways of calling it, provided that this is
+    /// the context that it came from.
+    pub fn function_ref<'a>(&self, ctx: &'a Context) -> Result<FunctionRef<'a>> {
+        unsafe {
+            if !rc::Rc::ptr_eq(&self.handles, &functions::handles(ctx.raw)) {
+                let message = nul_str(b"function belongs to another context\0");
+                duktape_sys::duk_push_error_object(ctx.raw,
+                                                   duktape_sys::DUK_ERR_TYPE_ERROR,
+                                                   message);
+                return Err(ctx.pop_error());
+            }
+            functions::push(ctx.raw, self.id);
+            Ok(FunctionRef { reference: ctx.pop_reference() })
+        }
+    }
+}
+
+impl Drop for Function {
+    fn drop(&mut self) {
+        self.handles.drop_handle(self.id);
+    }
+}
+
+impl<'a> ObjectRef<'a> {
+    /// Stores a Rust value in the object, which native functions can get back later with
+    /// `native::Arguments::userdata` or `this_userdata`.  The value replaces the previous userdata
+    /// of the object, if any, and is kept by the context rather than in a property, out of reach of
+    /// scripts.  It is dropped when the object is finalized or the context is dropped, unless it is
+    /// still borrowed through an `Rc` from `get_userdata`.  Fails if the object can't be extended,
+    /// like a frozen one.
+    ///
+    /// Userdata is shared rather than borrowed, since scripts decide when objects die, so values
+    /// that native functions modify should use interior mutability, like a `RefCell`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::cell;
+    ///
+    /// struct Connection {
+    ///     queries: u32,
+    /// }
+    ///
+    /// let ctx = duk::Context::new();
+    /// ctx.register_variadic("query", |args| {
+    ///     let connection = args.userdata::<cell::RefCell<Connection>>(0).unwrap();
+    ///     connection.borrow_mut().queries += 1;
+    ///     Ok(duk::Value::Undefined)
+    /// });
+    /// let db = ctx.eval_string("var db = {}; db").unwrap().into_object().unwrap();
+    /// db.set_userdata(cell::RefCell::new(Connection { queries: 0 })).unwrap();
+    /// ctx.eval_string("query(db); query(db);").unwrap();
+    /// let connection = db.get_userdata::<cell::RefCell<Connection>>().unwrap();
+    /// assert_eq!(2, connection.borrow().queries);
+    /// ```
+    pub fn set_userdata<T>(&self, value: T) -> Result<()>
+        where T: 'static
+    {
+        let ctx = self.reference.ctx;
+        self.reference.with_value(|| unsafe {
+            if userdata::set(ctx.raw, -1, rc::Rc::new(value)) {
+                Ok(())
+            } else {
+                duktape_sys::duk_push_error_object(ctx.raw,
+                                                   duktape_sys::DUK_ERR_TYPE_ERROR,
+                                                   nul_str(b"object can't have userdata\0"));
+                Err(ctx.pop_error())
+            }
+        })
+    }
+
+    /// The userdata of the object, if it has userdata of type `T`.
+    pub fn get_userdata<T>(&self) -> Option<rc::Rc<T>>
+        where T: 'static
+    {
+        self.reference.with_value(|| unsafe { userdata::get(self.reference.ctx.raw, -1) })
+    }
+
+    /// Defines a read-only property whose value comes from a Rust function whenever it is read,
+    /// see `define_accessor`.
+    pub fn define_getter<G>(&self, name: &str, get: G) -> Result<()>
+        where G: Fn() -> Result<Value> + 'static
+    {
+        self.define_accessor_functions(name, native::getter(get), None)
+    }
+
+    /// Defines an accessor property, whose value comes from a Rust function whenever it is read,
+    /// and goes to another one whenever it is assigned.  Errors of either are thrown into the
+    /// script, so the setter can reject invalid values.  The property is enumerable, but can't be
+    /// redefined or deleted, and defining it fails if the object already has such a property or
+    /// is frozen.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::rc;
+    /// use std::sync::atomic;
+    ///
+    /// let verbose = rc::Rc::new(atomic::AtomicBool::new(false));
+    /// let ctx = duk::Context::new();
+    /// let config = ctx.eval_string("var config = {}; config").unwrap().into_object().unwrap();
+    /// let (get, set) = (verbose.clone(), verbose.clone());
+    /// config.define_accessor("verbose",
+    ///                      move || Ok(duk::Value::Boolean(get.load(atomic::Ordering::SeqCst))),
+    ///                      move |value| {
+    ///                          set.store(value == duk::Value::Boolean(true),
+    ///                                    atomic::Ordering::SeqCst);
+    ///                          Ok(())
+    ///                      })
+    ///     .unwrap();
+    /// ctx.eval_string("config.verbose = !config.verbose;").unwrap();
+    /// assert!(verbose.load(atomic::Ordering::SeqCst));
+    /// ```
+    pub fn define_accessor<G, S>(&self, name: &str, get: G, set: S) -> Result<()>
+        where G: Fn() -> Result<Value> + 'static,
+              S: Fn(Value) -> Result<()> + 'static
+    {
+        self.define_accessor_functions(name, native::getter(get), Some(native::setter(set)))
+    }
+
+    /// Gets the property with the specified key, without converting the rest of the object.
+    /// Fails if a getter or a proxy of the object throws.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::new();
+    /// let state = ctx.eval_string("var state = {count: 1, history: new Array(10000)}; state")
+    ///     .unwrap()
+    ///     .into_object()
+    ///     .unwrap();
+    /// assert_eq!(duk::Value::Number(1.0), state.get("count").unwrap().to_value());
+    /// state.set("count", &2).unwrap();
+    /// assert_eq!(duk::Value::Number(2.0), ctx.eval_string("state.count").unwrap().to_value());
+    /// ```
+    pub fn get(&self, name: &str) -> Result<Reference<'a>> {
+        let ctx = self.reference.ctx;
+        self.reference.with_value(|| unsafe {
+            self.property_operation(name, None, Some(get_property))?;
+            Ok(ctx.pop_reference())
+        })
+    }
+
+    /// Sets the property with the specified key.  Fails if the object doesn't allow it, like a
+    /// frozen object or a read-only property, or if a setter or a proxy of the object throws.
+    pub fn set(&self, name: &str, value: &dyn Argument) -> Result<()> {
+        let ctx = self.reference.ctx;
+        self.reference.with_value(|| unsafe {
+            self.property_operation(name, Some(value), Some(put_property))?;
+            duktape_sys::duk_pop(ctx.raw);
+            Ok(())
+        })
+    }
+
+    /// Whether the object or its prototypes have the property with the specified key, like the
+    /// `in` operator.
+    pub fn has(&self, name: &str) -> Result<bool> {
+        let ctx = self.reference.ctx;
+        self.reference.with_value(|| unsafe {
+            self.property_operation(name, None, Some(has_property))?;
+            let has = duktape_sys::duk_get_boolean(ctx.raw, -1) != 0;
+            duktape_sys::duk_pop(ctx.raw);
+            Ok(has)
+        })
+    }
+
+    /// Deletes the property with the specified key, if the object has it.  Fails if the property
+    /// can't be deleted.
+    pub fn delete(&self, name: &str) -> Result<()> {
+        let ctx = self.reference.ctx;
+        self.reference.with_value(|| unsafe {
+            self.property_operation(name, None, Some(delete_property))?;
+            duktape_sys::duk_pop(ctx.raw);
+            Ok(())
+        })
+    }
+
+    /// Runs a safe call function on the object on top of the stack, the key and the value, if any,
+    /// and leaves its result on the stack, or returns the error that it threw.
+    unsafe fn property_operation(&self,
+                                 name: &str,
+                                 value: Option<&dyn Argument>,
+                                 operation: duktape_sys::duk_safe_call_function)
+                                 -> Result<()> {
+        let ctx = self.reference.ctx;
+        duktape_sys::duk_dup_top(ctx.raw);
+        strings::push(ctx.raw, name);
+        if let Some(value) = value {
+            value.push_to_context(ctx);
+        }
+        let nargs = if value.is_some() { 3 } else { 2 };
+        let ret = duktape_sys::duk_safe_call(ctx.raw, operation, ptr::null_mut(), nargs, 1);
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(ctx.pop_error())
+        }
+    }
+
+    fn define_accessor_functions(&self,
+                                 name: &str,
+                                 get: Box<native::Function>,
+                                 set: Option<Box<native::Function>>)
+                                 -> Result<()> {
+        let ctx = self.reference.ctx;
+        self.reference.with_value(|| unsafe {
+            // Defining the property throws if the object doesn't allow it
+            duktape_sys::duk_dup_top(ctx.raw);
+            strings::push(ctx.raw, name);
+            let nargs = if set.is_some() { 4 } else { 3 };
+            let mut flags = native::push_accessor(ctx.raw, get, set);
+            let ret = duktape_sys::duk_safe_call(ctx.raw,
+                                                 Some(define_property),
+                                                 &mut flags as *mut _ as *mut os::raw::c_void,
+                                                 nargs,
+                                                 1);
+            if ret == 0 {
+                duktape_sys::duk_pop(ctx.raw);
+                Ok(())
+            } else {
+                Err(ctx.pop_error())
+            }
+        })
+    }
+
+    /// The underlying reference to the object.
+    pub fn as_reference(&self) -> &Reference<'a> {
+        &self.reference
+    }
+}
+
+impl<'a> Argument for ObjectRef<'a> {
+    unsafe fn push_to_context(&self, context: &Context) {
+        self.reference.push_to_context(context)
+    }
+}
+
+impl<'a> LazyObject<'a> {
+    /// Gets the value of the property with the specified key, converting it the first time it is
+    /// accessed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let ctx = duk::Context::new();
+    /// let config = ctx.eval_string("({name: 'foo', big: new Array(10000)})").unwrap().into_lazy_object();
+    /// assert_eq!(duk::Value::String("foo".to_owned()), config.get("name").unwrap());
+    /// ```
+    pub fn get(&self, key: &str) -> Result<Value> {
+        if let Some(value) = self.cache.borrow().get(key) {
+            return Ok(value.clone());
+        }
+
+        let value = self.reference.get(key)?.to_value();
+        self.cache.borrow_mut().insert(key.to_owned(), value.clone());
+        Ok(value)
+    }
+
+    /// Forgets all of the memoized property values, so that they are converted again on the next
+    /// access.  Useful if the underlying object might have been mutated.
+    pub fn invalidate(&self) {
+        self.cache.borrow_mut().clear();
+    }
+
+    /// The underlying reference to the object.
+    pub fn as_reference(&self) -> &Reference<'a> {
+        &self.reference
+    }
+}
+
+impl<'a> Spy<'a> {
+    /// The arguments of every call so far, in the order of the calls.
+    pub fn calls(&self) -> Vec<Vec<Value>> {
+        match self.calls.to_value() {
+            Value::Array(calls) => {
+                calls.into_iter()
+                    .map(|args| match args {
+                        Value::Array(args) => args,
+                        _ => Vec::new(),
+                    })
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// The number of calls so far.
+    pub fn call_count(&self) -> usize {
+        self.calls.with_value(|| unsafe { duktape_sys::duk_get_length(self.calls.ctx.raw, -1) })
+    }
+
+    /// Forgets all recorded calls.
+    pub fn reset(&self) -> Result<()> {
+        self.calls.call_method("splice", &[&Value::Number(0.0)]).map(|_| ())
+    }
+}
+
+impl<'a> ArgsBuilder<'a> {
+    /// Appends an argument to the end of the argument list.
+    pub fn arg(mut self, arg: &dyn Argument) -> Self {
+        let len = self.len;
+        self.put(len, arg);
+        self.len += 1;
+        self
+    }
+
+    /// Replaces the argument at the specified position.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn set(&mut self, index: usize, arg: &dyn Argument) {
+        assert!(index < self.len, "argument index {} out of bounds", index);
+        self.put(index, arg);
+    }
+
+    /// The number of arguments in the list.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the argument list is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn put(&mut self, index: usize, arg: &dyn Argument) {
+        let ctx = self.array.ctx;
+        self.array.with_value(|| {
+            unsafe {
+                arg.push_to_context(ctx);
+                duktape_sys::duk_put_prop_index(ctx.raw, -2, index as duktape_sys::duk_uarridx_t);
+            }
+        })
+    }
+
+    /// Pushes all of the arguments onto the stack of the specified context.
+    unsafe fn push_all(&self, context: &Context) {
+        if context.raw != self.array.ctx.raw {
+            panic!("Tried to mix arguments coming from different contexts");
+        }
+
+        self.array.push();
+        let array_idx = duktape_sys::duk_get_top_index(context.raw);
+        for i in 0..self.len {
+            duktape_sys::duk_get_prop_index(context.raw, array_idx, i as duktape_sys::duk_uarridx_t);
+        }
+        duktape_sys::duk_remove(context.raw, array_idx);
+    }
+}
+
+impl Value {
+    /// Copies this value into a `Context`, and returns the reference to the value within the
+    /// context.
+    pub fn to_reference<'a>(&self, context: &'a Context) -> Reference<'a> {
+        unsafe {
+            self.push(context.raw);
+            context.pop_reference()
+        }
+    }
+
+    /// The number of bytes of string and buffer data in this value, including all nested values.
+    fn data_len(&self) -> usize {
+        match *self {
+            Value::String(ref s) => s.len(),
+            Value::Bytes(ref b) => b.len(),
+            Value::Array(ref values) => values.iter().map(Value::data_len).sum(),
+            Value::Object(ref map) => map.iter().map(|(k, v)| k.len() + v.data_len()).sum(),
+            _ => 0,
+        }
+    }
+
+    /// Converts the value at the specified index, with the conversion options of the context.
+    unsafe fn get(ctx: *mut duktape_sys::duk_context, index: duktape_sys::duk_idx_t) -> Value {
+        let mut funcs = duktape_sys::duk_memory_functions::default();
+        duktape_sys::duk_get_memory_functions(ctx, &mut funcs);
+        let options = (*(funcs.udata as *mut HeapData)).conversion.get();
+        Value::get_nested(ctx, duktape_sys::duk_normalize_index(ctx, index), &options, 0)
+    }
+
+    /// Converts the value at the specified normalized index, which is nested in `depth` arrays or
+    /// objects.
+    unsafe fn get_nested(ctx: *mut duktape_sys::duk_context,
+                         index: duktape_sys::duk_idx_t,
+                         options: &conversion::ConversionOptions,
+                         depth: usize)
+                         -> Value {
+        let t = duktape_sys::duk_get_type(ctx, index);
+        if t == duktape_sys::DUK_TYPE_UNDEFINED {
+            Value::Undefined
+        } else if t == duktape_sys::DUK_TYPE_NULL {
+            Value::Null
+        } else if t == duktape_sys::DUK_TYPE_BOOLEAN {
+            Value::Boolean(duktape_sys::duk_get_boolean(ctx, index) != 0)
+        } else if t == duktape_sys::DUK_TYPE_NUMBER {
+            Value::Number(duktape_sys::duk_get_number(ctx, index))
+        } else if t == duktape_sys::DUK_TYPE_STRING {
+            Value::String(get_string(ctx, index))
+        } else if t == duktape_sys::DUK_TYPE_OBJECT {
+            if let Some(date) = Value::get_date(ctx, index, options.dates()) {
+                date
+            } else if options.too_deep(depth) {
+                Value::Foreign("too deep")
+            } else if 1 == duktape_sys::duk_is_array(ctx, index) {
+                let len = duktape_sys::duk_get_length(ctx, index);
+                let mut array = Vec::with_capacity(len);
+
+                for i in 0..len {
+                    // Holes in sparse arrays are read as undefined
+                    duktape_sys::duk_get_prop_index(ctx, index, i as u32);
+                    let elem_idx = duktape_sys::duk_get_top_index(ctx);
+                    array.push(Value::get_nested(ctx, elem_idx, options, depth + 1));
+                    duktape_sys::duk_pop(ctx);
+                }
+
+                Value::Array(array)
+            } else {
+                let mut object = collections::BTreeMap::new();
+                duktape_sys::duk_enum(ctx, index, duktape_sys::DUK_ENUM_OWN_PROPERTIES_ONLY);
+
+                while 1 == duktape_sys::duk_next(ctx, -1, 1) {
+                    let key = get_string(ctx, -2);
+                    let value_idx = duktape_sys::duk_get_top_index(ctx);
+                    let value = Value::get_nested(ctx, value_idx, options, depth + 1);
+                    duktape_sys::duk_pop_2(ctx);
+                    object.insert(key, value);
+                }
+
+                duktape_sys::duk_pop(ctx);
+
+                Value::Object(object)
+            }
+        } else if t == duktape_sys::DUK_TYPE_BUFFER {
+            let mut size = mem::uninitialized();
+            let data = duktape_sys::duk_get_buffer(ctx, index, &mut size);
+            let slice = slice::from_raw_parts(data as *const u8, size);
+            Value::Bytes(slice.to_vec())
+        } else if t == duktape_sys::DUK_TYPE_POINTER {
+            Value::Foreign("pointer")
+        } else if t == duktape_sys::DUK_TYPE_LIGHTFUNC {
+            Value::Foreign("lightfunc")
+        } else {
+            panic!("Unmapped type {}", t)
+        }
+    }
+
+    /// Converts the `Date` object at the specified normalized index as configured, or returns
+    /// `None` if it isn't a `Date` or dates are converted like other objects.
+    unsafe fn get_date(ctx: *mut duktape_sys::duk_context,
+                       index: duktape_sys::duk_idx_t,
+                       dates: conversion::DateConversion)
+                       -> Option<Value> {
+        let method: &[u8] = match dates {
+            conversion::DateConversion::Object => return None,
+            conversion::DateConversion::Millis => b"getTime\0",
+            conversion::DateConversion::IsoString => b"toISOString\0",
+        };
+        if !conversion::push_date_method(ctx, index, method) {
+            return None;
+        }
+        let value = if 1 == duktape_sys::duk_is_string(ctx, -1) {
+            Value::String(get_string(ctx, -1))
+        } else if dates == conversion::DateConversion::Millis {
+            Value::Number(duktape_sys::duk_get_number(ctx, -1))
+        } else {
+            Value::Null
+        };
+        duktape_sys::duk_pop(ctx);
+        Some(value)
+    }
+
+    unsafe fn push(&self, ctx: *mut duktape_sys::duk_context) {
+        match *self {
+            Value::Undefined => duktape_sys::duk_push_undefined(ctx),
+            Value::Null => duktape_sys::duk_push_null(ctx),
+            Value::Boolean(b) => {
+                let v = if b {
+                    1
+                } else {
+                    0
+                };
+                duktape_sys::duk_push_boolean(ctx, v);
+            }
+            Value::Number(n) => duktape_sys::duk_push_number(ctx, n),
+            Value::String(ref string) => push_str(ctx, string),
+            Value::Array(ref array) => {
+                duktape_sys::duk_push_array(ctx);
+                for (i, elem) in array.iter().enumerate() {
+                    elem.push(ctx);
+                    assert!(1 == duktape_sys::duk_put_prop_index(ctx, -2, i as u32));
+                }
+            }
+            Value::Object(ref object) => {
+                duktape_sys::duk_push_object(ctx);
+
+                for (k, v) in object {
+                    let k_data = k.as_ptr() as *const i8;
+                    let k_len = k.len();
+                    duktape_sys::duk_push_lstring(ctx, k_data, k_len);
+                    v.push(ctx);
+                    duktape_sys::duk_put_prop(ctx, -3);
+                }
+            }
+            Value::Bytes(ref bytes) => push_bytes(ctx, bytes),
+            Value::Foreign(_) => duktape_sys::duk_push_undefined(ctx),
+        }
+    }
+}
+
+unsafe fn push_str(ctx: *mut duktape_sys::duk_context, string: &str) {
+    duktape_sys::duk_push_lstring(ctx, string.as_ptr() as *const i8, string.len());
+}
+
+unsafe fn push_bytes(ctx: *mut duktape_sys::duk_context, bytes: &[u8]) {
+    let len = bytes.len();
+    let data = duktape_sys::duk_push_fixed_buffer(ctx, len);
+
+    ptr::copy(bytes.as_ptr(), data as *mut u8, len);
+}
+
+impl Argument for Value {
+    unsafe fn push_to_context(&self, context: &Context) {
+        self.push(context.raw);
+    }
+}
+
+impl Argument for bool {
+    unsafe fn push_to_context(&self, context: &Context) {
+        duktape_sys::duk_push_boolean(context.raw, *self as duktape_sys::duk_bool_t);
+    }
+}
+
+impl Argument for i32 {
+    unsafe fn push_to_context(&self, context: &Context) {
+        duktape_sys::duk_push_number(context.raw, f64::from(*self));
+    }
+}
+
+impl Argument for u32 {
+    unsafe fn push_to_context(&self, context: &Context) {
+        duktape_sys::duk_push_number(context.raw, f64::from(*self));
+    }
+}
+
+impl Argument for f64 {
+    unsafe fn push_to_context(&self, context: &Context) {
+        duktape_sys::duk_push_number(context.raw, *self);
+    }
+}
+
+impl Argument for str {
+    unsafe fn push_to_context(&self, context: &Context) {
+        push_str(context.raw, self);
+    }
+}
+
+impl Argument for String {
+    unsafe fn push_to_context(&self, context: &Context) {
+        push_str(context.raw, self);
+    }
+}
+
+/// Byte slices are pushed as Duktape buffers, like `Value::Bytes`.
+impl Argument for [u8] {
+    unsafe fn push_to_context(&self, context: &Context) {
+        push_bytes(context.raw, self);
+    }
+}
+
+impl Argument for Vec<u8> {
+    unsafe fn push_to_context(&self, context: &Context) {
+        push_bytes(context.raw, self);
+    }
+}
+
+impl<T> Argument for &T
+    where T: Argument + ?Sized
+{
+    unsafe fn push_to_context(&self, context: &Context) {
+        (**self).push_to_context(context);
+    }
+}
+
+impl<'a, T> Argument for borrow::Cow<'a, T>
+    where T: Argument + ToOwned + ?Sized
+{
+    unsafe fn push_to_context(&self, context: &Context) {
+        (**self).push_to_context(context);
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Value {
+        Value::Boolean(value)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(value: i32) -> Value {
+        Value::Number(f64::from(value))
+    }
+}
+
+impl From<u32> for Value {
+    fn from(value: u32) -> Value {
+        Value::Number(f64::from(value))
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Value {
+        Value::Number(value)
+    }
+}
+
+impl<'a> From<&'a str> for Value {
+    fn from(value: &'a str) -> Value {
+        Value::String(value.to_owned())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Value {
+        Value::String(value)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(values: Vec<Value>) -> Value {
+        Value::Array(values)
+    }
+}
+
+impl<T> IntoArgs for Vec<T>
+    where T: Into<Value>
+{
+    fn into_args(self) -> Vec<Value> {
+        self.into_iter().map(Into::into).collect()
+    }
+}
+
+impl<T> IntoArgs for &[T]
+    where T: Clone + Into<Value>
+{
+    fn into_args(self) -> Vec<Value> {
+        self.iter().cloned().map(Into::into).collect()
+    }
+}
+
+impl<T, const N: usize> IntoArgs for [T; N]
+    where T: Into<Value>
+{
+    fn into_args(self) -> Vec<Value> {
+        IntoIterator::into_iter(self).map(Into::into).collect()
+    }
+}
+
+macro_rules! tuple_into_args {
+    ($($name:ident),*) => {
+        impl<$($name),*> IntoArgs for ($($name,)*)
+            where $($name: Into<Value>),*
+        {
+            #[allow(non_snake_case)]
+            fn into_args(self) -> Vec<Value> {
+                let ($($name,)*) = self;
+                vec![$($name.into()),*]
+            }
+        }
+    };
+}
+
+tuple_into_args!();
+tuple_into_args!(A);
+tuple_into_args!(A, B);
+tuple_into_args!(A, B, C);
+tuple_into_args!(A, B, C, D);
+tuple_into_args!(A, B, C, D, E);
+tuple_into_args!(A, B, C, D, E, F);
+tuple_into_args!(A, B, C, D, E, F, G);
+tuple_into_args!(A, B, C, D, E, F, G, H);
+
+impl Value {
+    /// The name of the type of this value, for error messages.
+    fn type_name(&self) -> &'static str {
+        match *self {
+            Value::Undefined => "undefined",
+            Value::Null => "null",
+            Value::Boolean(_) => "a boolean",
+            Value::Number(_) => "a number",
+            Value::String(_) => "a string",
+            Value::Array(_) => "an array",
+            Value::Object(_) => "an object",
+            Value::Bytes(_) => "a buffer",
+            Value::Foreign(name) => name,
+        }
+    }
+
+    fn conversion_error<T>(&self, expected: &str) -> Result<T> {
+        let message = format!("expected {}, got {}", expected, self.type_name());
+        Err(ErrorKind::Conversion(message).into())
+    }
+}
+
+impl FromValue for Value {
+    fn from_value(value: Value) -> Result<Value> {
+        Ok(value)
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: Value) -> Result<bool> {
+        match value {
+            Value::Boolean(b) => Ok(b),
+            value => value.conversion_error("a boolean"),
+        }
+    }
+}
+
+impl FromValue for f64 {
+    fn from_value(value: Value) -> Result<f64> {
+        match value {
+            Value::Number(n) => Ok(n),
+            value => value.conversion_error("a number"),
+        }
+    }
+}
+
+impl FromValue for f32 {
+    fn from_value(value: Value) -> Result<f32> {
+        f64::from_value(value).map(|n| n as f32)
+    }
+}
+
+macro_rules! integer_from_value {
+    ($($t:ty),*) => {
+        $(
+            impl FromValue for $t {
+                fn from_value(value: Value) -> Result<$t> {
+                    match value {
+                        Value::Number(n) if n.fract() == 0.0 && n >= <$t>::MIN as f64 &&
+                                            n <= <$t>::MAX as f64 => Ok(n as $t),
+                        Value::Number(n) => {
+                            Err(ErrorKind::Conversion(format!("{} is not a valid {}",
+                                                              n,
+                                                              stringify!($t)))
+                                .into())
+                        }
+                        value => value.conversion_error("a number"),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+integer_from_value!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl FromValue for String {
+    fn from_value(value: Value) -> Result<String> {
+        match value {
+            Value::String(s) => Ok(s),
+            value => value.conversion_error("a string"),
+        }
+    }
+}
+
+impl<T> FromValue for Option<T>
+    where T: FromValue
+{
+    fn from_value(value: Value) -> Result<Option<T>> {
+        match value {
+            Value::Undefined | Value::Null => Ok(None),
+            value => T::from_value(value).map(Some),
+        }
+    }
+}
+
+impl<T> FromValue for Vec<T>
+    where T: FromValue
+{
+    fn from_value(value: Value) -> Result<Vec<T>> {
+        match value {
+            Value::Array(values) => values.into_iter().map(T::from_value).collect(),
+            Value::Bytes(bytes) => {
+                bytes.into_iter().map(|b| T::from_value(Value::from(u32::from(b)))).collect()
+            }
+            value => value.conversion_error("an array"),
+        }
+    }
+}
+
+impl<T> FromValue for collections::BTreeMap<String, T>
+    where T: FromValue
+{
+    fn from_value(value: Value) -> Result<collections::BTreeMap<String, T>> {
+        match value {
+            Value::Object(object) => {
+                object.into_iter().map(|(k, v)| Ok((k, T::from_value(v)?))).collect()
+            }
+            value => value.conversion_error("an object"),
+        }
+    }
+}
+
+impl<T, S> FromValue for collections::HashMap<String, T, S>
+    where T: FromValue,
+          S: hash::BuildHasher + Default
+{
+    fn from_value(value: Value) -> Result<collections::HashMap<String, T, S>> {
+        match value {
+            Value::Object(object) => {
+                object.into_iter().map(|(k, v)| Ok((k, T::from_value(v)?))).collect()
+            }
+            value => value.conversion_error("an object"),
+        }
+    }
+}
+
+impl JsError {
+    /// Creates an error of the specified kind, without a location.  Native functions return these
+    /// to throw errors of a specific kind, see the `native` module.
+    pub fn new(kind: JsErrorKind, message: &str) -> JsError {
+        JsError {
+            kind,
+            message: message.to_owned(),
+            file_name: None,
+            line_number: None,
+            stack: None,
+        }
+    }
+
+    unsafe fn get(ctx: *mut duktape_sys::duk_context, index: duktape_sys::duk_idx_t) -> JsError {
+        let e = duktape_sys::duk_get_error_code(ctx, index);
+        let kind = JsErrorKind::from_raw(e);
+        let message = get_string_property(ctx, index, b"message\0").unwrap_or_else(|| {
+            let mut len = mem::uninitialized();
+            let data = duktape_sys::duk_safe_to_lstring(ctx, index, &mut len);
+            strings::decode(slice::from_raw_parts(data as *const u8, len)).collect()
+        });
+        let file_name = get_string_property(ctx, index, b"fileName\0").and_then(|n| if n.is_empty() {
+            None
+        } else {
+            Some(n)
+        });
+        let line_number = get_number_property(ctx, index, b"lineNumber\0")
+            .and_then(|n| if n.is_nan() {
+                None
+            } else {
+                Some(n as usize)
+            });
+        let stack = get_string_property(ctx, index, b"stack\0");
+
+        JsError {
+            kind: kind,
+            message: message,
+            file_name: file_name,
+            line_number: line_number,
+            stack: stack,
+        }
+    }
+}
+
+impl From<JsError> for Error {
+    fn from(error: JsError) -> Error {
+        ErrorKind::Js(error).into()
+    }
+}
+
+impl JsErrorKind {
+    /// The name of the constructor of errors of this kind, like `TypeError`.  Thrown values that
+    /// are not errors are called `Error`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            JsErrorKind::Generic | JsErrorKind::Error => "Error",
+            JsErrorKind::Eval => "EvalError",
+            JsErrorKind::Range => "RangeError",
+            JsErrorKind::Reference => "ReferenceError",
+            JsErrorKind::Syntax => "SyntaxError",
+            JsErrorKind::Type => "TypeError",
+            JsErrorKind::Uri => "URIError",
+        }
+    }
+
+    unsafe fn from_raw(e: duktape_sys::duk_errcode_t) -> JsErrorKind {
+        if e == duktape_sys::DUK_ERR_NONE {
+            JsErrorKind::Generic
+        } else if e == duktape_sys::DUK_ERR_ERROR {
+            JsErrorKind::Error
+        } else if e == duktape_sys::DUK_ERR_EVAL_ERROR {
+            JsErrorKind::Eval
+        } else if e == duktape_sys::DUK_ERR_RANGE_ERROR {
+            JsErrorKind::Range
+        } else if e == duktape_sys::DUK_ERR_REFERENCE_ERROR {
+            JsErrorKind::Reference
+        } else if e == duktape_sys::DUK_ERR_SYNTAX_ERROR {
+            JsErrorKind::Syntax
+        } else if e == duktape_sys::DUK_ERR_TYPE_ERROR {
+            JsErrorKind::Type
+        } else if e == duktape_sys::DUK_ERR_URI_ERROR {
+            JsErrorKind::Uri
+        } else {
+            panic!("Unmapped error code {}", e)
+        }
+    }
+
+    unsafe fn to_raw(self) -> duktape_sys::duk_errcode_t {
+        match self {
+            JsErrorKind::Generic | JsErrorKind::Error => duktape_sys::DUK_ERR_ERROR,
+            JsErrorKind::Eval => duktape_sys::DUK_ERR_EVAL_ERROR,
+            JsErrorKind::Range => duktape_sys::DUK_ERR_RANGE_ERROR,
+            JsErrorKind::Reference => duktape_sys::DUK_ERR_REFERENCE_ERROR,
+            JsErrorKind::Syntax => duktape_sys::DUK_ERR_SYNTAX_ERROR,
+            JsErrorKind::Type => duktape_sys::DUK_ERR_TYPE_ERROR,
+            JsErrorKind::Uri => duktape_sys::DUK_ERR_URI_ERROR,
+        }
+    }
+}
+
+unsafe fn get_string(ctx: *mut duktape_sys::duk_context, index: duktape_sys::duk_idx_t) -> String {
+    let mut len = 0;
+    let data = duktape_sys::duk_get_lstring(ctx, index, &mut len);
+    strings::decode(slice::from_raw_parts(data as *const u8, len)).collect()
+}
+
+/// Borrows the bytes of the value at the specified index, coercing it to a string in place if it
+/// isn't one already.  The returned slice is only valid while the value remains on the stack.
+unsafe fn coerce_bytes<'a>(ctx: *mut duktape_sys::duk_context, index: duktape_sys::duk_idx_t) -> &'a [u8] {
+    let mut len = 0;
+    let data = if 1 == duktape_sys::duk_is_string(ctx, index) {
+        duktape_sys::duk_get_lstring(ctx, index, &mut len)
+    } else {
+        duktape_sys::duk_safe_to_lstring(ctx, index, &mut len)
+    };
+    if len == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(data as *const u8, len)
+    }
+}
+
+/// Writes the string or buffer at the specified index into a writer, coercing any other value to
+/// a string in place.  Buffers are written as is and strings as UTF-8, see `strings::to_utf8`.
+unsafe fn write_value<W>(ctx: *mut duktape_sys::duk_context,
+                         index: duktape_sys::duk_idx_t,
+                         writer: &mut W)
+                         -> Result<usize>
+    where W: io::Write
+{
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    let mut len = 0;
+    let data = duktape_sys::duk_get_buffer_data(ctx, index, &mut len) as *const u8;
+    let string;
+    let bytes = if !data.is_null() {
+        slice::from_raw_parts(data, len)
+    } else if 0 != duktape_sys::duk_is_buffer(ctx, index) {
+        &[]
+    } else {
+        string = strings::to_utf8(coerce_bytes(ctx, index));
+        string.as_bytes()
+    };
+
+    for chunk in bytes.chunks(CHUNK_SIZE) {
+        writer.write_all(chunk)?;
+    }
+
+    Ok(bytes.len())
+}
+
+unsafe fn get_string_property(ctx: *mut duktape_sys::duk_context,
+                              index: duktape_sys::duk_idx_t,
+                              name: &[u8])
+                              -> Option<String> {
+    duktape_sys::duk_get_prop_string(ctx, index, nul_str(name));
+    if 1 == duktape_sys::duk_is_string(ctx, -1) {
+        let result = get_string(ctx, -1);
+        duktape_sys::duk_pop(ctx);
+
+        Some((*result).to_owned())
+    } else {
+        duktape_sys::duk_pop(ctx);
+        None
+    }
+}
+
+unsafe fn get_number_property(ctx: *mut duktape_sys::duk_context,
+                              index: duktape_sys::duk_idx_t,
+                              name: &[u8])
+                              -> Option<f64> {
+    if 1 == duktape_sys::duk_get_prop_string(ctx, index, nul_str(name)) {
+        let result = duktape_sys::duk_get_number(ctx, -1);
+        duktape_sys::duk_pop(ctx);
+        Some(result)
+    } else {
+        duktape_sys::duk_pop(ctx);
+        None
+    }
+}
+
+unsafe fn nul_str(data: &[u8]) -> *const os::raw::c_char {
+    ffi::CStr::from_bytes_with_nul_unchecked(data).as_ptr()
+}
+
+/// Pushes the global `host` object that holds the host APIs, creating it first if needed.
+unsafe fn push_host_object(ctx: *mut duktape_sys::duk_context) {
+    duktape_sys::duk_get_global_string(ctx, nul_str(b"host\0"));
+    if 0 == duktape_sys::duk_is_object(ctx, -1) {
+        duktape_sys::duk_pop(ctx);
+        duktape_sys::duk_push_object(ctx);
+        duktape_sys::duk_dup_top(ctx);
+        duktape_sys::duk_put_global_string(ctx, nul_str(b"host\0"));
+    }
+}
+
+unsafe extern "C" fn module_resolve_handler(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    let requested_id = get_string(ctx, 0);
+    let parent_id = get_string(ctx, 1);
+    duktape_sys::duk_pop_2(ctx);
+
+    if requested_id.starts_with(host_modules::PREFIX) {
+        Value::String(requested_id).push(ctx);
+        return 1;
+    }
+
+    duktape_sys::duk_push_current_function(ctx);
+    duktape_sys::duk_get_prop_string(ctx, -1, nul_str(b"closure\0"));
+    let ptr = duktape_sys::duk_get_pointer(ctx, -1) as *mut Box<ModuleResolver>;
+    assert!(!ptr.is_null());
+    let resolve = Box::from_raw(ptr);
+    duktape_sys::duk_pop_2(ctx);
+
+    // Ensure clear stack before entering the Rust wild west
+    let result = resolve(requested_id, parent_id);
+
+    mem::forget(resolve);
+
+    Value::String(result).push(ctx);
+
+    1
+}
+
+unsafe extern "C" fn module_load_handler(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    let resolved_id = get_string(ctx, 0);
+    hooks::notify(ctx, |hooks| hooks.module_load(&resolved_id));
+    if resolved_id.starts_with(host_modules::PREFIX) {
+        return host_modules::load(ctx);
+    }
+    duktape_sys::duk_pop_3(ctx); // Discard 'exports' and 'module'
+
+    duktape_sys::duk_push_current_function(ctx);
+    duktape_sys::duk_get_prop_string(ctx, -1, nul_str(b"closure\0"));
+    let ptr = duktape_sys::duk_get_pointer(ctx, -1) as *mut Box<ModuleLoader>;
+    assert!(!ptr.is_null());
+    let load = Box::from_raw(ptr);
+    duktape_sys::duk_pop_2(ctx);
+
+    let result = {
+        let _span = spans::Span::module_load(&resolved_id);
+        load(resolved_id)
+    };
+
+    mem::forget(load);
+
+    // Ensure clear stack before entering the Rust wild west
+    if let Some(result) = result {
+        strings::push(ctx, &sources::normalize(&result));
+        1
+    } else {
+        0
+    }
+}
+
+/// Defines the property of the object at index 0, with the key and the value or accessor functions
+/// above it, and the flags that `udata` points to.
+unsafe extern "C" fn define_property(ctx: *mut duktape_sys::duk_context,
+                                     udata: *mut os::raw::c_void)
+                                     -> duktape_sys::duk_ret_t {
+    duktape_sys::duk_def_prop(ctx, 0, *(udata as *const duktape_sys::duk_uint_t));
+    0
+}
+
+/// Gets the property of the object at index 0 with the key at index 1.
+unsafe extern "C" fn get_property(ctx: *mut duktape_sys::duk_context,
+                                  _: *mut os::raw::c_void)
+                                  -> duktape_sys::duk_ret_t {
+    duktape_sys::duk_get_prop(ctx, 0);
+    1
+}
+
+/// Sets the property of the object at index 0 with the key at index 1 to the value at index 2.
+unsafe extern "C" fn put_property(ctx: *mut duktape_sys::duk_context,
+                                  _: *mut os::raw::c_void)
+                                  -> duktape_sys::duk_ret_t {
+    duktape_sys::duk_put_prop(ctx, 0);
+    0
+}
+
+/// Whether the object at index 0 has the property with the key at index 1.
+unsafe extern "C" fn has_property(ctx: *mut duktape_sys::duk_context,
+                                  _: *mut os::raw::c_void)
+                                  -> duktape_sys::duk_ret_t {
+    let has = duktape_sys::duk_has_prop(ctx, 0);
+    duktape_sys::duk_push_boolean(ctx, has);
+    1
+}
+
+/// Deletes the property of the object at index 0 with the key at index 1.
+unsafe extern "C" fn delete_property(ctx: *mut duktape_sys::duk_context,
+                                     _: *mut os::raw::c_void)
+                                     -> duktape_sys::duk_ret_t {
+    duktape_sys::duk_del_prop(ctx, 0);
+    0
+}
+
+#[cfg(feature = "logging")]
+unsafe extern "C" fn log_handler(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    use duktape_sys::*; // Because this function is essentially only calling C stuff
+
+    // The function magic is the log level that this handler should handle.
+    let level = duk_get_current_magic(ctx);
+    if level < DUK_LOG_TRACE || level > DUK_LOG_FATAL {
+        warn!("log_handler called with invalid level: {}", level);
+        return 0;
+    }
+
+    // Stack: [ arg0 ... argN ]
+    let nargs = duk_get_top(ctx);
+
+    duk_push_this(ctx);
+    // Stack: [ arg0 ... argN this ]
+
+    duk_get_prop_string(ctx, -1, nul_str(b"l\0"));
+    // Stack: [ arg0 ... argN this loggerLevel ]
+
+    // Check if we should log this level with this logger
+    let logger_level = duk_get_int(ctx, -1);
+    if level < logger_level {
+        return 0;
+    }
+
+    let rust_level = if level == DUK_LOG_TRACE {
+        log::LogLevel::Trace
+    } else if level == DUK_LOG_DEBUG {
+        log::LogLevel::Debug
+    } else if level == DUK_LOG_INFO {
+        log::LogLevel::Info
+    } else if level == DUK_LOG_WARN {
+        log::LogLevel::Warn
+    } else {
+        log::LogLevel::Error
+    };
+
+    duk_get_prop_string(ctx, -2, nul_str(b"n\0"));
+    // Stack: [ arg0 ... argN this loggerLevel loggerName ]
+    duk_to_string(ctx, -1);
+
+    let mut total_len = 0;
+
+    // Replace all args with equivalent strings, and compute their lengths
+    // Stack: [ arg0 ... argN this loggerLevel loggerName ]
+    for i in 0..nargs {
+        if 1 == duk_is_object(ctx, i) {
+            duk_push_string(ctx, nul_str(b"fmt\0"));
+            duk_dup(ctx, i);
+            // Stack: [ arg1 ... argN this loggerLevel loggerName 'fmt' arg ]
+            // Call: this.fmt(arg) so -5 is this
+            duk_pcall_prop(ctx, -5, 1);
+            duk_replace(ctx, i);
+        }
+
+        let mut arg_len = mem::uninitialized();
+
+        duk_to_lstring(ctx, i, &mut arg_len);
+
+        total_len += arg_len as usize;
+    }
+
+    // Stack: [ arg0String ... argNString this loggerLevel loggerName ]
+
+    let mut name_len = mem::uninitialized();
+    let name_data = duk_get_lstring(ctx, -1, &mut name_len);
+    let name_slice = slice::from_raw_parts(name_data as *const u8, name_len);
+    let name_str = str::from_utf8(name_slice).unwrap();
+
+    // Allocate message space; include nargs to allocate spaces
+    let mut msg = String::with_capacity(total_len + name_str.len() + nargs as usize + 1);
+    msg.push_str(name_str);
+    msg.push(':');
+
+    for i in 0..nargs {
+        let mut arg_len = mem::uninitialized();
+        let arg_data = duk_get_lstring(ctx, i, &mut arg_len);
+        let slice = slice::from_raw_parts(arg_data as *const u8, arg_len);
+        let arg_str = str::from_utf8(slice).unwrap();
+
+        msg.push(' ');
+        msg.push_str(arg_str);
+    }
+
+    // For test
+    stash_log(rust_level, &msg);
+
+    log!(target: &format!("{}:{}", module_path!(), name_str), rust_level, "{}",  msg);
+
+    0
+}
+
+#[cfg(all(test, feature = "logging"))]
+fn stash_log(level: log::LogLevel, msg: &str) {
+    println!("Logged: {} {}", level, msg);
+    unsafe {
+        for l in LAST_LOG_LEVELS.iter_mut() {
+            if l.is_none() {
+                *l = Some(level);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(all(not(test), feature = "logging"))]
+fn stash_log(_: log::LogLevel, _: &str) {
+    // No-op
+}
+
+/// Installs `exec_timeout_check` for all heaps, which it tells apart by their heap data.
+#[cfg(any(feature = "profiler", feature = "debugger", feature = "timeout"))]
+fn install_exec_timeout_check() {
+    let installed = duktape_sys::set_exec_timeout_check(exec_timeout_check);
+    debug_assert!(installed, "another executor interrupt hook is installed");
+}
+
+/// The executor interrupt hook, which records profile samples and aborts execution when requested
+/// by a statement hook or past the deadline.  Duktape keeps throwing for as long as this returns
+/// true.
+#[cfg(any(feature = "profiler", feature = "debugger", feature = "timeout"))]
+unsafe fn exec_timeout_check(udata: *mut os::raw::c_void) -> duktape_sys::duk_bool_t {
+    #[cfg(feature = "profiler")]
+    profiler::sample(udata);
+    #[cfg(feature = "debugger")]
+    {
+        if (*(udata as *mut HeapData)).abort_requested {
+            return 1;
+        }
+    }
+    #[cfg(feature = "timeout")]
+    {
+        if (*(udata as *mut HeapData)).deadline.is_some_and(|d| time::Instant::now() >= d) {
+            return 1;
+        }
+    }
+    0
+}
+
+unsafe extern "C" fn fatal_handler(_: *mut os::raw::c_void, msg_raw: *const os::raw::c_char) {
+    let msg = &*ffi::CStr::from_ptr(msg_raw).to_string_lossy();
+    // TODO: No unwind support from C... but this "works" right now
+    panic!("Duktape fatal error: {}", msg)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use super::*;
+    use testing::ScratchDir;
+
+    use std::collections;
+    use std::fmt;
+    use std::fs;
+
+    #[cfg(feature = "logging")]
+    use log;
+
+    fn assert_js_error<A: fmt::Debug>(result: &Result<A>, expected_kind: JsErrorKind, expected_message: &str) {
+        if let &Err(Error(ref k, _)) = result {
+            match k {
+                &ErrorKind::Js(JsError { kind, ref message, ..}) => {
+                    assert_eq!(expected_kind, kind);
+                    assert_eq!(expected_message, message);
+                }
+                _ => panic!("Not a Javascript error: {}", k),
+            }
+        } else {
+            panic!("Not an error: {:?}", result);
+        }
+    }
+
+    #[test]
+    fn eval_string_undefined() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let value = ctx.eval_string("undefined").unwrap().to_value();
+        assert_eq!(Value::Undefined, value);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn eval_string_null() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let value = ctx.eval_string("null").unwrap().to_value();
+        assert_eq!(Value::Null, value);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn eval_string_boolean_true() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let value = ctx.eval_string("true").unwrap().to_value();
+        assert_eq!(Value::Boolean(true), value);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn eval_string_boolean_false() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let value = ctx.eval_string("false").unwrap().to_value();
+        assert_eq!(Value::Boolean(false), value);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn eval_string_number_integral() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let value = ctx.eval_string("4").unwrap().to_value();
+        assert_eq!(Value::Number(4.0), value);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn eval_string_number_fractional() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let value = ctx.eval_string("0.5").unwrap().to_value();
+        assert_eq!(Value::Number(0.5), value);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn eval_string_string() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let value = ctx.eval_string("'ab'").unwrap().to_value();
+        assert_eq!(Value::String("ab".to_owned()), value);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn eval_string_array() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let value = ctx.eval_string("['a', 3, false]").unwrap().to_value();
+        assert_eq!(Value::Array(vec![Value::String("a".to_owned()),
+                                     Value::Number(3.0),
+                                     Value::Boolean(false)]),
+                   value);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn eval_string_object() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let value = ctx.eval_string("({a: 'a', b: 3, c: false})").unwrap().to_value();
+
+        let mut expected = collections::BTreeMap::new();
+        expected.insert("a".to_owned(), Value::String("a".to_owned()));
+        expected.insert("b".to_owned(), Value::Number(3.0));
+        expected.insert("c".to_owned(), Value::Boolean(false));
+
+        assert_eq!(Value::Object(expected), value);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn eval_string_buffer() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let value = ctx.eval_string("Duktape.Buffer('abc')").unwrap().to_value();
+        assert_eq!(Value::Bytes("abc".as_bytes().to_vec()), value);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn eval_string_error_generic() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let value = ctx.eval_string("throw 'foobar';");
+        assert_js_error(&value, JsErrorKind::Generic, "foobar");
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn eval_string_error_error() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let value = ctx.eval_string("throw new Error('xyz')");
+        assert_js_error(&value, JsErrorKind::Error, "xyz");
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn eval_string_eval_error() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let value = ctx.eval_string("throw new EvalError('xyz')");
+        assert_js_error(&value, JsErrorKind::Eval, "xyz");
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn eval_string_range_error() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let value = ctx.eval_string("throw new RangeError('xyz')");
+        assert_js_error(&value, JsErrorKind::Range, "xyz");
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn eval_string_reference_error() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let value = ctx.eval_string("throw new ReferenceError('xyz')");
+        assert_js_error(&value, JsErrorKind::Reference, "xyz");
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn eval_string_syntax_error() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let value = ctx.eval_string("throw new SyntaxError('xyz')");
+        assert_js_error(&value, JsErrorKind::Syntax, "xyz");
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn eval_string_type_error() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let value = ctx.eval_string("throw new TypeError('xyz')");
+        assert_js_error(&value, JsErrorKind::Type, "xyz");
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn eval_string_uri_error() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let value = ctx.eval_string("throw new URIError('xyz')");
+        assert_js_error(&value, JsErrorKind::Uri, "xyz");
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn eval_string_global_object_get_key_call() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.eval_string(r"
+          function foo() {
+            return 'a';
+          }")
+            .unwrap();
+        let global = ctx.global_object();
+        ctx.assert_clean();
+        let foo = global.get("foo").unwrap();
+        ctx.assert_clean();
+        let value = foo.call(&[]).unwrap().to_value();
+        assert_eq!(Value::String("a".to_owned()), value);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn eval_string_global_object_call_method() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.eval_string(r"
+          var bar = 2;
+          function foo() {
+            if (this === undefined || this.bar !== 2) {
+              throw 'b';
+            }
+            return Array.prototype.slice.call(arguments);
+          }")
+            .unwrap();
+        let global = ctx.global_object();
+        ctx.assert_clean();
+        let value = global.call_method("foo", &[&Value::Number(4.25)]).unwrap().to_value();
+        assert_eq!(Value::Array(vec![Value::Number(4.25)]), value);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn eval_string_global_object_get_key_call_with_this() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.eval_string(r"
+          var bar = 2;
+          function foo() {
+            if (this === undefined || this.bar !== 2) {
+              throw 'b';
+            }
+            return Array.prototype.slice.call(arguments);
+          }")
+            .unwrap();
+        let global = ctx.global_object();
+        ctx.assert_clean();
+        let foo = global.get("foo").unwrap();
+        ctx.assert_clean();
+        let value = foo.call_with_this(&global, &[&Value::Number(4.25)]).unwrap().to_value();
+        assert_eq!(Value::Array(vec![Value::Number(4.25)]), value);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn eval_string_global_object_get_key_new() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.eval_string(r"
+          function foo() {
+            if (this.constructor !== foo) {
+              throw 'b';
+            }
+            return Array.prototype.slice.call(arguments);
+          }")
+            .unwrap();
+        let global = ctx.global_object();
+        ctx.assert_clean();
+        let foo = global.get("foo").unwrap();
+        ctx.assert_clean();
+        let value = foo.new(&[&Value::Number(4.25)]).unwrap().to_value();
+        assert_eq!(Value::Array(vec![Value::Number(4.25)]), value);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn eval_string_call_global() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.eval_string(r"
+          function foo() {
+            return 'a';
+          }")
+            .unwrap();
+        let value = ctx.call_global("foo", &[]).unwrap().to_value();
+        assert_eq!(Value::String("a".to_owned()), value);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn eval_string_call_global_args() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.eval_string(r"
+          function foo() {
+            return Array.prototype.slice.call(arguments);
+          }")
+            .unwrap();
+
+        let mut obj = collections::BTreeMap::new();
+        obj.insert("a".to_owned(), Value::String("a".to_owned()));
+        obj.insert("b".to_owned(), Value::Number(3.0));
+        obj.insert("c".to_owned(), Value::Boolean(false));
+
+        let arr = vec![Value::String("a".to_owned()), Value::Number(3.0), Value::Boolean(false)];
+
+        let bytes = vec![0, 1, 2, 3];
+
+        let values = &[Value::Undefined,
+                       Value::Null,
+                       Value::Boolean(true),
+                       Value::Number(1.0),
+                       Value::String("foo".to_owned()),
+                       Value::Array(arr),
+                       Value::Object(obj),
+                       Value::Bytes(bytes)];
+        let args = values.iter().map(|v| v as &Argument).collect::<Vec<_>>();
+        let value = ctx.call_global("foo", &args).unwrap().to_value();
+        assert_eq!(Value::Array(values.to_vec()), value);
+        ctx.assert_clean();
     }
 
-    // Stack: [ arg0 ... argN ]
-    let nargs = duk_get_top(ctx);
-
-    duk_push_this(ctx);
-    // Stack: [ arg0 ... argN this ]
+    #[test]
+    fn eval_string_call_global_error() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.eval_string(r"
+          function foo() {
+            throw 'a';
+          }")
+           .unwrap();
+        let value = ctx.call_global("foo", &[]);
+        assert_js_error(&value, JsErrorKind::Generic, "a");
+        ctx.assert_clean();
+    }
 
-    duk_get_prop_string(ctx, -1, nul_str(b"l\0"));
-    // Stack: [ arg0 ... argN this loggerLevel ]
+    #[test]
+    fn keys_with_nul() {
+        let _ = env_logger::init();
+        let ctx = Context::builder().with_global("b\0c", Value::Number(1.0)).build();
+        ctx.eval_string("this['a\\u0000b'] = function () { return 'ab'; }; var a = 'a';").unwrap();
+        assert_eq!(Value::String("ab".to_owned()),
+                   ctx.call_global("a\0b", &[]).unwrap().to_value());
+        assert_eq!(Value::String("a".to_owned()),
+                   ctx.global_object().get("a").unwrap().to_value());
+        assert_eq!(Value::Undefined, ctx.global_object().get("a\0").unwrap().to_value());
+        assert_eq!(Value::Number(1.0), ctx.eval_string("this['b\\u0000c']").unwrap().to_value());
+        ctx.assert_clean();
+    }
 
-    // Check if we should log this level with this logger
-    let logger_level = duk_get_int(ctx, -1);
-    if level < logger_level {
-        return 0;
+    #[test]
+    fn eval_discard() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.eval_discard("var a = {b: [1, 2, 3]}; a").unwrap();
+        ctx.assert_clean();
+        let value = ctx.eval_discard("throw new TypeError('xyz')");
+        assert_js_error(&value, JsErrorKind::Type, "xyz");
+        ctx.assert_clean();
     }
 
-    let rust_level = if level == DUK_LOG_TRACE {
-        log::LogLevel::Trace
-    } else if level == DUK_LOG_DEBUG {
-        log::LogLevel::Debug
-    } else if level == DUK_LOG_INFO {
-        log::LogLevel::Info
-    } else if level == DUK_LOG_WARN {
-        log::LogLevel::Warn
-    } else {
-        log::LogLevel::Error
-    };
+    #[test]
+    fn call_global_void() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.eval_string(r"
+          var calls = 0;
+          function foo(n) {
+            calls += n;
+            return {a: 'a'};
+          }")
+            .unwrap();
+        ctx.call_global_void("foo", &[&Value::Number(2.0)]).unwrap();
+        ctx.assert_clean();
+        let value = ctx.eval_string("calls").unwrap().to_value();
+        assert_eq!(Value::Number(2.0), value);
+        let value = ctx.call_global_void("bar", &[]);
+        assert_js_error(&value, JsErrorKind::Type, "undefined not callable");
+        ctx.assert_clean();
+    }
 
-    duk_get_prop_string(ctx, -2, nul_str(b"n\0"));
-    // Stack: [ arg0 ... argN this loggerLevel loggerName ]
-    duk_to_string(ctx, -1);
+    #[test]
+    fn eval_as_types() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.eval_string("function pair(a, b) { return [a, b]; }").unwrap();
+        assert_eq!(true, ctx.eval_as::<bool>("1 < 2").unwrap());
+        assert_eq!(-3, ctx.eval_as::<i64>("-3").unwrap());
+        assert_eq!(Some("x".to_owned()), ctx.eval_as::<Option<String>>("'x'").unwrap());
+        assert_eq!(None, ctx.eval_as::<Option<String>>("null").unwrap());
+        assert_eq!(vec![1u8, 255], ctx.eval_as::<Vec<u8>>("Duktape.dec('hex', '01ff')").unwrap());
+        let map = ctx.eval_as::<collections::HashMap<String, Vec<f64>>>("({a: [0.5], b: []})")
+            .unwrap();
+        assert_eq!(Some(&vec![0.5]), map.get("a"));
+        assert_eq!(vec![Some(1), None],
+                   ctx.call_global_as::<Vec<Option<u32>>>("pair", &[&Value::from(1), &Value::Null])
+                       .unwrap());
+
+        let error = ctx.eval_as::<u8>("256").unwrap_err();
+        assert_eq!("value could not be converted: 256 is not a valid u8", error.to_string());
+        let error = ctx.eval_as::<Vec<String>>("['a', 1]").unwrap_err();
+        assert_eq!("value could not be converted: expected a string, got a number",
+                   error.to_string());
+        assert!(ctx.eval_as::<i32>("1.5").is_err());
+        assert!(ctx.eval_as::<f64>("undefined").is_err());
+        ctx.assert_clean();
+    }
 
-    let mut total_len = 0;
+    #[test]
+    #[cfg_attr(target_os = "wasi", ignore = "needs a temporary directory")]
+    fn eval_file() {
+        let _ = env_logger::init();
+        let dir = ScratchDir::new("eval-file");
+        let file = dir.join("script.js");
+        fs::write(&file, "function where() { return new Error().fileName; }\n'h\u{e9}' + 1")
+            .unwrap();
 
-    // Replace all args with equivalent strings, and compute their lengths
-    // Stack: [ arg0 ... argN this loggerLevel loggerName ]
-    for i in 0..nargs {
-        if 1 == duk_is_object(ctx, i) {
-            duk_push_string(ctx, nul_str(b"fmt\0"));
-            duk_dup(ctx, i);
-            // Stack: [ arg1 ... argN this loggerLevel loggerName 'fmt' arg ]
-            // Call: this.fmt(arg) so -5 is this
-            duk_pcall_prop(ctx, -5, 1);
-            duk_replace(ctx, i);
+        let ctx = Context::new();
+        assert_eq!(Value::String("h\u{e9}1".to_owned()), ctx.eval_file(&file).unwrap().to_value());
+        assert_eq!(Value::String(file.display().to_string()),
+                   ctx.call_global("where", &[]).unwrap().to_value());
+        assert_eq!(Value::String("h\u{e9}1".to_owned()),
+                   ctx.eval_file(file.to_str().unwrap()).unwrap().to_value());
+        match ctx.eval_file(dir.join("missing.js")) {
+            Err(Error(ErrorKind::Io(ref e), _)) => assert_eq!(io::ErrorKind::NotFound, e.kind()),
+            result => panic!("unexpected result: {:?}", result),
         }
-
-        let mut arg_len = mem::uninitialized();
-
-        duk_to_lstring(ctx, i, &mut arg_len);
-
-        total_len += arg_len as usize;
+        ctx.assert_clean();
     }
 
-    // Stack: [ arg0String ... argNString this loggerLevel loggerName ]
-
-    let mut name_len = mem::uninitialized();
-    let name_data = duk_get_lstring(ctx, -1, &mut name_len);
-    let name_slice = slice::from_raw_parts(name_data as *const u8, name_len);
-    let name_str = str::from_utf8(name_slice).unwrap();
+    #[test]
+    #[cfg_attr(target_os = "wasi", ignore = "needs a temporary directory")]
+    fn load_for_side_effects() {
+        let _ = env_logger::init();
+        let dir = ScratchDir::new("load-file");
+        let file = dir.join("lib.js");
+        fs::write(&file, "function twice(n) { return helper(n) * 2; }").unwrap();
 
-    // Allocate message space; include nargs to allocate spaces
-    let mut msg = String::with_capacity(total_len + name_str.len() + nargs as usize + 1);
-    msg.push_str(name_str);
-    msg.push(':');
+        let ctx = Context::new();
+        ctx.load("function helper(n) { return n + 1; }").unwrap();
+        ctx.load_file(&file).unwrap();
+        ctx.assert_clean();
+        assert_eq!(Value::Number(8.0), ctx.call("twice", (3,)).unwrap().to_value());
+        assert!(ctx.load("function (").is_err());
+        assert!(ctx.load_file(dir.join("missing.js")).is_err());
+        ctx.assert_clean();
+    }
 
-    for i in 0..nargs {
-        let mut arg_len = mem::uninitialized();
-        let arg_data = duk_get_lstring(ctx, i, &mut arg_len);
-        let slice = slice::from_raw_parts(arg_data as *const u8, arg_len);
-        let arg_str = str::from_utf8(slice).unwrap();
+    #[test]
+    fn check_stack_balanced() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.eval_string("1 + 2").unwrap();
+        assert_eq!(Ok(()), ctx.check_stack_balanced());
+        unsafe {
+            duktape_sys::duk_push_null(ctx.raw);
+            duktape_sys::duk_push_null(ctx.raw);
+        }
+        let imbalance = ctx.check_stack_balanced().unwrap_err();
+        assert_eq!(StackImbalance { leaked: 2 }, imbalance);
+        assert_eq!("2 value(s) left on the stack", imbalance.to_string());
+        unsafe {
+            duktape_sys::duk_pop_2(ctx.raw);
+        }
+        ctx.assert_clean();
+    }
 
-        msg.push(' ');
-        msg.push_str(arg_str);
+    #[test]
+    fn try_new_and_default() {
+        let _ = env_logger::init();
+        let ctx = Context::try_new().unwrap();
+        assert_eq!(Value::Number(3.0), ctx.eval_string("1 + 2").unwrap().to_value());
+        ctx.assert_clean();
+        let ctx = Context::builder().with_pool_allocator().try_build().unwrap();
+        ctx.assert_clean();
+        let ctx = Context::default();
+        assert_eq!(Value::Undefined, ctx.eval_string("undefined").unwrap().to_value());
+        ctx.assert_clean();
     }
 
-    // For test
-    stash_log(rust_level, &msg);
+    #[test]
+    fn shared_context() {
+        let _ = env_logger::init();
+        let ctx = rc::Rc::new(Context::new());
+        let other = ctx.clone();
+        let counter = ctx.eval_string("({count: 0})").unwrap();
+        other.eval_string("var twice = function (o) { o.count += 2; return o.count; };").unwrap();
+        assert_eq!(Value::Number(2.0), other.call_global("twice", &[&counter]).unwrap().to_value());
+        assert_eq!(Value::Number(4.0), ctx.call_global("twice", &[&counter]).unwrap().to_value());
+        drop(counter);
+        ctx.assert_clean();
+    }
 
-    log!(target: &format!("{}:{}", module_path!(), name_str), rust_level, "{}",  msg);
+    #[test]
+    fn builder_globals() {
+        let _ = env_logger::init();
+        let ctx = Context::builder()
+            .with_global("HOST", Value::Array(vec![Value::String("x".to_owned()), Value::Null]))
+            .with_global("limit", Value::Number(1.0))
+            .with_global("limit", Value::Number(2.0))
+            .with_global("Math", Value::Boolean(false))
+            .build();
+        assert_eq!(Value::String("x,,2,false".to_owned()),
+                   ctx.eval_string("[HOST, limit, Math].join()").unwrap().to_value());
+        ctx.assert_clean();
+    }
 
-    0
-}
+    #[cfg(feature = "timeout")]
+    #[test]
+    fn builder_timeout() {
+        let _ = env_logger::init();
+        let ctx = Context::builder().with_timeout(time::Duration::from_millis(50)).build();
+        ctx.eval_string("function spin() { try { for (;;) {} } catch (e) { return 'caught'; } }")
+            .unwrap();
+        match ctx.call("spin", ()) {
+            Err(Error(ErrorKind::Js(ref e), _)) => {
+                assert_eq!((JsErrorKind::Range, "execution timeout"), (e.kind, e.message.as_str()))
+            }
+            result => panic!("expected a timeout, got {:?}", result),
+        }
+        // The next evaluation gets a deadline of its own
+        assert_eq!(Value::Number(3.0), ctx.eval_string("1 + 2").unwrap().to_value());
+        ctx.assert_clean();
+    }
 
-#[cfg(all(test, feature = "logging"))]
-fn stash_log(level: log::LogLevel, msg: &str) {
-    println!("Logged: {} {}", level, msg);
-    unsafe {
-        for l in LAST_LOG_LEVELS.iter_mut() {
-            if l.is_none() {
-                *l = Some(level);
-                break;
+    #[cfg(feature = "timeout")]
+    #[test]
+    fn microtask_timeout() {
+        let _ = env_logger::init();
+        let ctx = Context::builder()
+            .with_polyfills(polyfills::PolyfillSet::all())
+            .with_timeout(time::Duration::from_millis(50))
+            .build();
+        ctx.eval_string("Promise.resolve().then(function () { for (;;) {} });").unwrap();
+        match ctx.run_microtasks() {
+            Err(Error(ErrorKind::Js(ref e), _)) => {
+                assert_eq!((JsErrorKind::Range, "execution timeout"), (e.kind, e.message.as_str()))
             }
+            result => panic!("expected a timeout, got {:?}", result),
         }
+        ctx.assert_clean();
     }
-}
 
-#[cfg(all(not(test), feature = "logging"))]
-fn stash_log(_: log::LogLevel, _: &str) {
-    // No-op
-}
+    #[cfg(feature = "timeout")]
+    #[test]
+    fn timeouts_on_threads() {
+        let _ = env_logger::init();
+        // Every heap has its own deadline, although the interrupt hook is shared
+        let threads = (0..4)
+            .map(|i| {
+                ::std::thread::spawn(move || {
+                    let timeout = time::Duration::from_millis(20 + i * 20);
+                    let ctx = Context::builder().with_timeout(timeout).build();
+                    let start = time::Instant::now();
+                    assert!(ctx.eval_string("for (;;) {}").is_err());
+                    assert!(start.elapsed() >= timeout);
+                    assert_eq!(Value::Number(3.0), ctx.eval_string("1 + 2").unwrap().to_value());
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
 
-unsafe extern "C" fn fatal_handler(_: *mut os::raw::c_void, msg_raw: *const os::raw::c_char) {
-    let msg = &*ffi::CStr::from_ptr(msg_raw).to_string_lossy();
-    // TODO: No unwind support from C... but this "works" right now
-    panic!("Duktape fatal error: {}", msg)
-}
+    #[test]
+    fn eval_expression_and_program() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let mut object = collections::BTreeMap::new();
+        object.insert("a".to_owned(), Value::Number(1.0));
+        assert_eq!(Value::Object(object),
+                   ctx.eval_expression("{a: 1} // object").unwrap().to_value());
+        assert_eq!(Value::Number(1.0), ctx.eval_program("{a: 1}").unwrap().to_value());
+        assert_eq!(Value::Number(3.0), ctx.eval_program("var x = 1; x + 2;").unwrap().to_value());
+        assert_eq!(Value::Undefined, ctx.eval_program("var y = 1;").unwrap().to_value());
+        assert_eq!(Value::Number(2.0), ctx.eval_expression("x + y").unwrap().to_value());
+        let error = ctx.eval_expression("var z = 1");
+        assert!(match error {
+            Err(Error(ErrorKind::Js(JsError { kind: JsErrorKind::Syntax, .. }), _)) => true,
+            _ => false,
+        });
+        assert!(ctx.eval_expression("").is_err());
+        ctx.assert_clean();
+    }
 
-#[cfg(test)]
-mod tests {
-    extern crate env_logger;
+    #[test]
+    fn eval_with_bindings() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.eval_string("var rate = 0.5, price = 'global';").unwrap();
+        let value = ctx.eval_with("price * qty * rate // comment",
+                                  &[("price", Value::Number(3.0)), ("qty", Value::Number(2.0))])
+            .unwrap();
+        assert_eq!(Value::Number(3.0), value.to_value());
+        let value = ctx.eval_with("{a: $tag, b: items.length}",
+                                  &[("$tag", Value::from("x")), ("items", Value::Array(vec![]))])
+            .unwrap();
+        assert_eq!(ctx.eval_string("({a: 'x', b: 0})").unwrap().to_value(), value.to_value());
+        assert_eq!(Value::String("global undefined".to_owned()),
+                   ctx.eval_string("price + ' ' + typeof qty").unwrap().to_value());
+
+        let error = ctx.eval_with("1", &[("a) { evil(); } (function (", Value::Null)]);
+        assert_js_error(&error,
+                        JsErrorKind::Syntax,
+                        "invalid binding name: \"a) { evil(); } (function (\"");
+        assert!(ctx.eval_with("1 +", &[]).is_err());
+        assert!(ctx.eval_with("unknown", &[("known", Value::Null)]).is_err());
+        ctx.assert_clean();
+    }
 
-    use super::*;
+    #[test]
+    fn call_with_into_args() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.eval_string(r"
+          function foo() {
+            return Array.prototype.slice.call(arguments);
+          }")
+            .unwrap();
+        let value = ctx.call("foo", (1, "x", true, 2.5, String::from("y"))).unwrap().to_value();
+        assert_eq!(Value::Array(vec![Value::Number(1.0),
+                                     Value::String("x".to_owned()),
+                                     Value::Boolean(true),
+                                     Value::Number(2.5),
+                                     Value::String("y".to_owned())]),
+                   value);
+        assert_eq!(Value::Array(vec![]), ctx.call("foo", ()).unwrap().to_value());
+        assert_eq!(Value::Array(vec![Value::Number(1.0), Value::Number(2.0)]),
+                   ctx.call("foo", vec![1, 2]).unwrap().to_value());
+        assert_eq!(Value::Array(vec![Value::String("a".to_owned())]),
+                   ctx.call("foo", &["a"][..]).unwrap().to_value());
+        assert_eq!(Value::Array(vec![Value::Boolean(false)]),
+                   ctx.call("foo", [false]).unwrap().to_value());
+        assert!(ctx.call("bar", (1,)).is_err());
+        ctx.assert_clean();
+    }
 
-    use std::collections;
-    use std::fmt;
+    #[test]
+    fn call_with_this() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.eval_string(r"
+          var name = 'global';
+          function describe(suffix) {
+            return (this === undefined ? 'undefined' : this.name) + suffix;
+          }
+          function strict() {
+            'use strict';
+            return this === undefined ? 'undefined' : typeof this;
+          }")
+            .unwrap();
+        let plugin = ctx.eval_string("({name: 'plugin'})").unwrap();
+        assert_eq!(Value::String("plugin!".to_owned()),
+                   ctx.call_with_this(&plugin, "describe", &[&"!"]).unwrap().to_value());
+        assert_eq!(Value::String("global?".to_owned()),
+                   ctx.call_global("describe", &[&"?"]).unwrap().to_value());
+        assert_eq!(Value::String("undefined".to_owned()),
+                   ctx.call_with_this(&Value::Undefined, "strict", &[]).unwrap().to_value());
+        assert_eq!(Value::String("number".to_owned()),
+                   ctx.call_with_this(&1, "strict", &[]).unwrap().to_value());
+        let describe = ctx.global_function("describe").unwrap();
+        assert_eq!(Value::String("plugin.".to_owned()),
+                   describe.call_with_this(&plugin, &[&"."]).unwrap().to_value());
+        assert!(ctx.call_with_this(&plugin, "missing", &[]).is_err());
+        ctx.assert_clean();
+    }
 
-    #[cfg(feature = "logging")]
-    use log;
+    #[test]
+    fn call_method_paths() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.eval_string(r"
+          var plugin = {
+            name: 'backup',
+            hooks: {
+              name: 'hooks',
+              onSave: function (file) { return this.name + ' saved ' + file; },
+              notAFunction: 1
+            },
+            get broken() { throw new Error('broken getter'); }
+          };")
+            .unwrap();
+        assert_eq!(Value::String("hooks saved a.txt".to_owned()),
+                   ctx.call_method("plugin.hooks.onSave", &[&"a.txt"]).unwrap().to_value());
+        assert_eq!(Value::String("BACKUP".to_owned()),
+                   ctx.call_method("plugin.name.toUpperCase", &[]).unwrap().to_value());
+        for &(path, missing) in &[("plugin.events.onLoad", "plugin.events"),
+                                  ("plugin.hooks.onLoad", "plugin.hooks.onLoad"),
+                                  ("missing", "missing")] {
+            match *ctx.call_method(path, &[]).unwrap_err().kind() {
+                ErrorKind::NonExistent(ref p, ref m) => assert_eq!((path, missing), (&**p, &**m)),
+                ref kind => panic!("unexpected error {:?}", kind),
+            }
+        }
+        let error = ctx.call_method("plugin.broken.onSave", &[]);
+        assert_js_error(&error, JsErrorKind::Error, "broken getter");
+        let error = ctx.call_method("plugin.hooks.notAFunction", &[]);
+        assert_js_error(&error, JsErrorKind::Type, "1 not callable");
+        ctx.assert_clean();
+    }
 
-    fn assert_js_error<A: fmt::Debug>(result: &Result<A>, expected_kind: JsErrorKind, expected_message: &str) {
-        if let &Err(Error(ref k, _)) = result {
-            match k {
-                &ErrorKind::Js(JsError { kind, ref message, ..}) => {
-                    assert_eq!(expected_kind, kind);
-                    assert_eq!(expected_message, message);
-                }
-                _ => panic!("Not a Javascript error: {}", k),
+    #[test]
+    fn object_properties() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let state = ctx.eval_string(r"
+          var state = Object.create({inherited: true}, {
+            fixed: {value: 1, enumerable: true},
+            checked: {
+              get: function () { throw new RangeError('unchecked'); },
+              set: function (value) { if (value < 0) { throw new RangeError('negative'); } }
             }
-        } else {
-            panic!("Not an error: {:?}", result);
+          });
+          state.nested = {items: [1, 2, 3]};
+          state")
+            .unwrap()
+            .into_object()
+            .unwrap();
+        let nested = state.get("nested").unwrap().into_object().unwrap();
+        let items = nested.get("items").unwrap();
+        assert_eq!(Value::Number(3.0), items.get("length").unwrap().to_value());
+        assert_eq!(Value::Undefined, state.get("missing").unwrap().to_value());
+        assert_js_error(&state.get("checked"), JsErrorKind::Range, "unchecked");
+
+        state.set("count", &3).unwrap();
+        state.set("copy", &nested).unwrap();
+        state.set("checked", &1).unwrap();
+        assert_js_error(&state.set("checked", &-1), JsErrorKind::Range, "negative");
+        assert!(state.set("fixed", &2).is_err());
+        assert_eq!(Value::Boolean(true),
+                   ctx.eval_string("state.count === 3 && state.copy === state.nested")
+                       .unwrap()
+                       .to_value());
+
+        assert!(state.has("inherited").unwrap());
+        assert!(state.has("count").unwrap());
+        state.delete("count").unwrap();
+        state.delete("missing").unwrap();
+        assert!(!state.has("count").unwrap());
+        assert!(state.delete("fixed").is_err());
+        assert!(state.has("fixed").unwrap());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn new_instances() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.eval_string(r"
+          var plugin = {
+            Widget: function (name) {
+              if (!(this instanceof plugin.Widget)) { throw new Error('not constructed'); }
+              this.name = name;
+            },
+            notAClass: 1
+          };")
+            .unwrap();
+        let widget = ctx.new_instance("plugin.Widget", &[&"button"]).unwrap();
+        assert_eq!(Value::String("button".to_owned()), widget.get("name").unwrap().to_value());
+        let date = ctx.new_instance("Date", &[&0]).unwrap();
+        assert_eq!(Value::Number(0.0), date.call_method("getTime", &[]).unwrap().to_value());
+        match *ctx.new_instance("plugin.Gadget", &[]).unwrap_err().kind() {
+            ErrorKind::NonExistent(_, ref missing) => assert_eq!("plugin.Gadget", missing),
+            ref kind => panic!("unexpected error {:?}", kind),
         }
+        let error = ctx.new_instance("plugin.notAClass", &[]);
+        assert_js_error(&error, JsErrorKind::Type, "1 not constructable");
+        ctx.assert_clean();
     }
 
     #[test]
-    fn eval_string_undefined() {
+    fn call_with_borrowed_arguments() {
         let _ = env_logger::init();
         let ctx = Context::new();
-        let value = ctx.eval_string("undefined").unwrap().to_value();
-        assert_eq!(Value::Undefined, value);
+        ctx.eval_string(r"
+          function describe() {
+            return Array.prototype.map.call(arguments, function(arg) {
+              return Duktape.enc('jx', arg);
+            }).join(' ');
+          }")
+            .unwrap();
+        let text = "x".repeat(3);
+        let bytes = vec![1u8, 2, 255];
+        let cow: borrow::Cow<str> = borrow::Cow::Borrowed("cow");
+        let value = ctx.call_global("describe",
+                         &[&text, &"str", &cow, &bytes, &&bytes[1..], &true, &-1, &7u32, &0.5])
+            .unwrap()
+            .to_value();
+        assert_eq!(Value::String(r#""xxx" "str" "cow" |0102ff| |02ff| true -1 7 0.5"#.to_owned()),
+                   value);
         ctx.assert_clean();
     }
 
     #[test]
-    fn eval_string_null() {
+    fn call_global_args_reused() {
         let _ = env_logger::init();
         let ctx = Context::new();
-        let value = ctx.eval_string("null").unwrap().to_value();
-        assert_eq!(Value::Null, value);
+        ctx.eval_string(r"
+          function foo() {
+            return Array.prototype.slice.call(arguments);
+          }")
+            .unwrap();
+        let mut args = ctx.args_builder()
+            .arg(&Value::String("a".to_owned()))
+            .arg(&Value::Number(1.0));
+        assert_eq!(2, args.len());
         ctx.assert_clean();
+
+        for i in 0..3 {
+            args.set(1, &Value::Number(i as f64));
+            let value = ctx.call_global_args("foo", &args).unwrap().to_value();
+            assert_eq!(Value::Array(vec![Value::String("a".to_owned()), Value::Number(i as f64)]),
+                       value);
+            ctx.assert_clean();
+        }
     }
 
     #[test]
-    fn eval_string_boolean_true() {
+    fn global_function_call() {
         let _ = env_logger::init();
         let ctx = Context::new();
-        let value = ctx.eval_string("true").unwrap().to_value();
-        assert_eq!(Value::Boolean(true), value);
+        ctx.eval_string(r"
+          function foo(a) {
+            return a + 1;
+          }
+          var bar = 3;")
+            .unwrap();
+        let foo = ctx.global_function("foo").unwrap();
+        ctx.assert_clean();
+        for i in 0..3 {
+            let value = foo.call(&[&Value::Number(i as f64)]).unwrap().to_value();
+            assert_eq!(Value::Number(i as f64 + 1.0), value);
+            ctx.assert_clean();
+        }
+        let bar = ctx.global_function("bar");
+        assert_js_error(&bar, JsErrorKind::Type, "value is not callable");
         ctx.assert_clean();
     }
 
     #[test]
-    fn eval_string_boolean_false() {
+    fn pool_allocator() {
         let _ = env_logger::init();
-        let ctx = Context::new();
-        let value = ctx.eval_string("false").unwrap().to_value();
-        assert_eq!(Value::Boolean(false), value);
+        let ctx = Context::builder().with_pool_allocator().build();
+        let value = ctx.eval_string(r"
+          var a = [];
+          for (var i = 0; i < 1000; i++) {
+            a.push({s: 'x' + i, n: i});
+          }
+          a[999].s + ':' + a.length")
+            .unwrap()
+            .to_value();
+        assert_eq!(Value::String("x999:1000".to_owned()), value);
         ctx.assert_clean();
     }
 
     #[test]
-    fn eval_string_number_integral() {
+    fn call_global_after_reassignment() {
         let _ = env_logger::init();
         let ctx = Context::new();
-        let value = ctx.eval_string("4").unwrap().to_value();
-        assert_eq!(Value::Number(4.0), value);
+        ctx.eval_string(r"
+          function tick() {
+            tick = function() { return 'b'; };
+            return 'a';
+          }")
+            .unwrap();
+        let value = ctx.call_global("tick", &[]).unwrap().to_value();
+        assert_eq!(Value::String("a".to_owned()), value);
+        let value = ctx.call_global("tick", &[]).unwrap().to_value();
+        assert_eq!(Value::String("b".to_owned()), value);
         ctx.assert_clean();
     }
 
     #[test]
-    fn eval_string_number_fractional() {
+    fn eval_to_writer() {
         let _ = env_logger::init();
         let ctx = Context::new();
-        let value = ctx.eval_string("0.5").unwrap().to_value();
-        assert_eq!(Value::Number(0.5), value);
+        let mut out = Vec::new();
+        let len = ctx.eval_to_writer("'ab' + 'cd'", &mut out).unwrap();
+        assert_eq!(4, len);
+        assert_eq!(b"abcd", &out[..]);
+        ctx.assert_clean();
+
+        let mut out = Vec::new();
+        ctx.eval_to_writer("Duktape.Buffer('xyz')", &mut out).unwrap();
+        assert_eq!(b"xyz", &out[..]);
+        ctx.assert_clean();
+
+        let mut out = Vec::new();
+        let value = ctx.eval_to_writer("throw new RangeError('xyz')", &mut out);
+        assert_js_error(&value, JsErrorKind::Range, "xyz");
+        assert!(out.is_empty());
         ctx.assert_clean();
     }
 
     #[test]
-    fn eval_string_string() {
+    fn call_global_to_writer() {
         let _ = env_logger::init();
         let ctx = Context::new();
-        let value = ctx.eval_string("'ab'").unwrap().to_value();
-        assert_eq!(Value::String("ab".to_owned()), value);
+        ctx.eval_string(r"
+          function foo(n) {
+            return new Array(n + 1).join('x');
+          }")
+            .unwrap();
+        let mut out = Vec::new();
+        let len = ctx.call_global_to_writer("foo", &[&Value::Number(100000.0)], &mut out).unwrap();
+        assert_eq!(100000, len);
+        assert!(out.iter().all(|&b| b == b'x'));
+
+        let mut out = Vec::new();
+        ctx.eval_string("function emoji() { return '\\ud83d\\ude00'; }").unwrap();
+        assert_eq!(4, ctx.call_global_to_writer("emoji", &[], &mut out).unwrap());
+        assert_eq!("\u{1f600}".as_bytes(), &out[..]);
         ctx.assert_clean();
     }
 
     #[test]
-    fn eval_string_array() {
+    fn copy_string_into() {
         let _ = env_logger::init();
         let ctx = Context::new();
-        let value = ctx.eval_string("['a', 3, false]").unwrap().to_value();
-        assert_eq!(Value::Array(vec![Value::String("a".to_owned()),
-                                     Value::Number(3.0),
-                                     Value::Boolean(false)]),
-                   value);
+        let value = ctx.eval_string("'abc'").unwrap();
+        let mut buf = [0; 2];
+        assert_eq!(Err(3), value.copy_string_into(&mut buf));
+        let mut buf = [0; 3];
+        assert_eq!(Ok(3), value.copy_string_into(&mut buf));
+        assert_eq!(b"abc", &buf);
+
+        let number = ctx.eval_string("12.5").unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(Ok(4), number.copy_string_into(&mut buf));
+        assert_eq!(b"12.5", &buf[..4]);
+        assert_eq!(Value::Number(12.5), number.to_value());
+
+        // Surrogate pairs become UTF-8
+        let emoji = ctx.eval_string("'a\\ud83d\\ude00'").unwrap();
+        assert_eq!(Err(5), emoji.copy_string_into(&mut [0; 4]));
+        assert_eq!(Ok(5), emoji.copy_string_into(&mut buf));
+        assert_eq!("a\u{1f600}".as_bytes(), &buf[..5]);
         ctx.assert_clean();
     }
 
     #[test]
-    fn eval_string_object() {
+    fn append_string_to() {
         let _ = env_logger::init();
         let ctx = Context::new();
-        let value = ctx.eval_string("({a: 'a', b: 3, c: false})").unwrap().to_value();
-
-        let mut expected = collections::BTreeMap::new();
-        expected.insert("a".to_owned(), Value::String("a".to_owned()));
-        expected.insert("b".to_owned(), Value::Number(3.0));
-        expected.insert("c".to_owned(), Value::Boolean(false));
-
-        assert_eq!(Value::Object(expected), value);
+        let mut out = String::from("x");
+        let value = ctx.eval_string("'abc'").unwrap();
+        assert_eq!(3, value.append_string_to(&mut out));
+        assert_eq!(3, value.append_string_to(&mut out));
+        assert_eq!("xabcabc", out);
+        let emoji = ctx.eval_string("'\\ud83d\\ude00'").unwrap();
+        assert_eq!(4, emoji.append_string_to(&mut out));
+        assert_eq!("xabcabc\u{1f600}", out);
         ctx.assert_clean();
     }
 
     #[test]
-    fn eval_string_buffer() {
+    fn lazy_object() {
         let _ = env_logger::init();
         let ctx = Context::new();
-        let value = ctx.eval_string("Duktape.Buffer('abc')").unwrap().to_value();
-        assert_eq!(Value::Bytes("abc".as_bytes().to_vec()), value);
+        let obj = ctx.eval_string("var obj = {a: 'a', b: {c: 3}}; obj").unwrap().into_lazy_object();
+        assert_eq!(Value::String("a".to_owned()), obj.get("a").unwrap());
+        ctx.eval_string("obj.a = 'b'").unwrap();
+        assert_eq!(Value::String("a".to_owned()), obj.get("a").unwrap());
+        obj.invalidate();
+        assert_eq!(Value::String("b".to_owned()), obj.get("a").unwrap());
+        assert_eq!(Value::Undefined, obj.get("x").unwrap());
         ctx.assert_clean();
     }
 
     #[test]
-    fn eval_string_error_generic() {
+    fn compacted_builtins() {
         let _ = env_logger::init();
-        let ctx = Context::new();
-        let value = ctx.eval_string("throw 'foobar';");
-        assert_js_error(&value, JsErrorKind::Generic, "foobar");
+        let ctx = Context::builder().with_compacted_builtins().build();
+        let value = ctx.eval_string("[3, 1, 2].sort().join(Math.floor(1.5))").unwrap().to_value();
+        assert_eq!(Value::String("11213".to_owned()), value);
         ctx.assert_clean();
     }
 
+    #[cfg(feature = "debugger")]
     #[test]
-    fn eval_string_error_error() {
+    #[cfg_attr(target_os = "wasi", ignore = "needs threads and sockets")]
+    fn attach_debugger() {
+        use std::io::BufRead;
+        use std::io;
+        use std::net;
+        use std::thread;
+
         let _ = env_logger::init();
         let ctx = Context::new();
-        let value = ctx.eval_string("throw new Error('xyz')");
-        assert_js_error(&value, JsErrorKind::Error, "xyz");
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let stream = net::TcpStream::connect(addr).unwrap();
+            let mut line = String::new();
+            io::BufReader::new(stream).read_line(&mut line).unwrap();
+            line
+        });
+
+        ctx.attach_debugger(listener).unwrap();
+        let line = client.join().unwrap();
+        assert!(line.starts_with("2 "), "unexpected version identification: {:?}", line);
+
+        ctx.detach_debugger();
+        let value = ctx.eval_string("1 + 1").unwrap().to_value();
+        assert_eq!(Value::Number(2.0), value);
         ctx.assert_clean();
     }
 
+    #[cfg(feature = "debugger")]
     #[test]
-    fn eval_string_eval_error() {
+    fn attach_debugger_channel() {
+        use std::io::Read;
+
         let _ = env_logger::init();
         let ctx = Context::new();
-        let value = ctx.eval_string("throw new EvalError('xyz')");
-        assert_js_error(&value, JsErrorKind::Eval, "xyz");
+        let (server, mut client) = debugger::channel();
+        ctx.attach_debugger_transport(server);
+
+        let mut line = Vec::new();
+        let mut byte = [0; 1];
+        while byte[0] != b'\n' {
+            client.read_exact(&mut byte).unwrap();
+            line.push(byte[0]);
+        }
+        assert!(line.starts_with(b"2 "));
+
+        drop(client);
+        let value = ctx.eval_string("1 + 1").unwrap().to_value();
+        assert_eq!(Value::Number(2.0), value);
+        ctx.detach_debugger();
         ctx.assert_clean();
     }
 
     #[test]
-    fn eval_string_range_error() {
+    fn source_mapped_errors() {
         let _ = env_logger::init();
         let ctx = Context::new();
-        let value = ctx.eval_string("throw new RangeError('xyz')");
-        assert_js_error(&value, JsErrorKind::Range, "xyz");
+        ctx.register_source_map("out.js",
+                                r#"{"version": 3, "sources": ["app.ts"], "names": [],
+                                    "mappings": "AAAA;AAIA"}"#)
+            .unwrap();
+        assert!(ctx.register_source_map("bad.js", "{}").is_err());
+
+        let code = "var a = 1;\nthrow new Error('boom');";
+        match ctx.eval_string_with_filename("out.js", code).unwrap_err().kind() {
+            &ErrorKind::Js(ref e) => {
+                assert_eq!(Some("app.ts".to_owned()), e.file_name);
+                assert_eq!(Some(5), e.line_number);
+                assert!(e.stack.as_ref().unwrap().contains("(app.ts:5:1)"));
+            }
+            k => panic!("unexpected error {:?}", k),
+        }
+
+        assert!(ctx.source_maps().unregister("out.js").is_some());
+        match ctx.eval_string_with_filename("out.js", code).unwrap_err().kind() {
+            &ErrorKind::Js(ref e) => assert_eq!(Some(2), e.line_number),
+            k => panic!("unexpected error {:?}", k),
+        }
         ctx.assert_clean();
     }
 
     #[test]
-    fn eval_string_reference_error() {
+    #[cfg(feature = "debugger")]
+    fn breakpoints() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
         let _ = env_logger::init();
         let ctx = Context::new();
-        let value = ctx.eval_string("throw new ReferenceError('xyz')");
-        assert_js_error(&value, JsErrorKind::Reference, "xyz");
+        let hits = Rc::new(RefCell::new(Vec::new()));
+        let hits_clone = hits.clone();
+        ctx.on_pause(move |state: &debugger::PausedState| {
+                hits_clone.borrow_mut().push(state.clone());
+                debugger::StepAction::Resume
+            })
+            .unwrap();
+        ctx.add_breakpoint("test.js", 3).unwrap();
+
+        let code = "var a = 1;\nfunction f(x) {\n  var y = x + 1;\n  return y;\n}\nf(2) + f(3);";
+        let value = ctx.eval_string_with_filename("test.js", code).unwrap().to_value();
+        assert_eq!(Value::Number(7.0), value);
+
+        {
+            let hits = hits.borrow();
+            assert_eq!(2, hits.len());
+            let frame = hits[0].location().unwrap();
+            assert_eq!("test.js", frame.file_name);
+            assert_eq!("f", frame.function_name);
+            assert_eq!(3, frame.line_number);
+            assert!(hits[0].locals.contains(&("x".to_owned(), Value::Number(2.0))));
+            assert!(hits[1].locals.contains(&("x".to_owned(), Value::Number(3.0))));
+        }
+
+        assert!(ctx.remove_breakpoint("test.js", 3).unwrap());
+        assert!(!ctx.remove_breakpoint("test.js", 3).unwrap());
+        ctx.eval_string_with_filename("test.js", code).unwrap();
+        assert_eq!(2, hits.borrow().len());
+        ctx.detach_debugger();
         ctx.assert_clean();
     }
 
     #[test]
-    fn eval_string_syntax_error() {
+    #[cfg(feature = "debugger")]
+    fn step_over() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
         let _ = env_logger::init();
         let ctx = Context::new();
-        let value = ctx.eval_string("throw new SyntaxError('xyz')");
-        assert_js_error(&value, JsErrorKind::Syntax, "xyz");
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let lines_clone = lines.clone();
+        ctx.on_pause(move |state: &debugger::PausedState| {
+                let mut lines = lines_clone.borrow_mut();
+                lines.push(state.location().unwrap().line_number);
+                if lines.len() < 3 {
+                    debugger::StepAction::StepOver
+                } else {
+                    debugger::StepAction::Resume
+                }
+            })
+            .unwrap();
+        ctx.pause().unwrap();
+
+        let code = "var a = 1;\nfunction f(x) {\n  return x + 1;\n}\nvar b = f(a);\nb = f(b);\nb;";
+        let value = ctx.eval_string_with_filename("test.js", code).unwrap().to_value();
+        assert_eq!(Value::Number(3.0), value);
+        assert_eq!(vec![1, 5, 6], *lines.borrow());
+        ctx.detach_debugger();
         ctx.assert_clean();
     }
 
     #[test]
-    fn eval_string_type_error() {
+    #[cfg(feature = "debugger")]
+    fn pause_on_uncaught() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
         let _ = env_logger::init();
         let ctx = Context::new();
-        let value = ctx.eval_string("throw new TypeError('xyz')");
-        assert_js_error(&value, JsErrorKind::Type, "xyz");
+        let errors = Rc::new(RefCell::new(Vec::new()));
+        let errors_clone = errors.clone();
+        ctx.on_pause(move |state: &debugger::PausedState| {
+                errors_clone.borrow_mut().push(state.uncaught_error.clone());
+                debugger::StepAction::Resume
+            })
+            .unwrap();
+
+        // Caught errors and disabled pausing don't call the handler
+        ctx.eval_string("try { throw new Error('caught'); } catch (e) {}").unwrap();
+        assert!(ctx.eval_string("throw new Error('ignored');").is_err());
+        assert!(errors.borrow().is_empty());
+
+        ctx.set_pause_on_uncaught(true).unwrap();
+        assert!(ctx.eval_string("throw new Error('uncaught');").is_err());
+        assert_eq!(vec![Some("Error: uncaught".to_owned())], *errors.borrow());
+        ctx.detach_debugger();
         ctx.assert_clean();
     }
 
     #[test]
-    fn eval_string_uri_error() {
+    #[cfg(feature = "debugger")]
+    fn coverage() {
         let _ = env_logger::init();
         let ctx = Context::new();
-        let value = ctx.eval_string("throw new URIError('xyz')");
-        assert_js_error(&value, JsErrorKind::Uri, "xyz");
+        ctx.start_coverage().unwrap();
+        let code = "function f(x) {\n  if (x > 1) {\n    return x;\n  }\n  return 0;\n}\n\
+                    var a = f(1);\nvar b = f(2);\n";
+        ctx.eval_string_with_filename("cov.js", code).unwrap();
+        ctx.eval_string_with_filename("cov.js", "f(3);").unwrap();
+        let report = ctx.stop_coverage().unwrap();
+
+        let file = report.file("cov.js").unwrap();
+        assert_eq!(Some(&3), file.lines.get(&2));
+        assert_eq!(Some(&2), file.lines.get(&3));
+        assert_eq!(None, file.lines.get(&4));
+        assert_eq!(Some(&1), file.lines.get(&5));
+        let f = file.functions.iter().find(|f| f.name == "f").unwrap();
+        assert_eq!(3, f.calls);
+
+        let mut lcov = Vec::new();
+        report.write_lcov(&mut lcov).unwrap();
+        let lcov = String::from_utf8(lcov).unwrap();
+        assert!(lcov.starts_with("SF:cov.js\n"), "{}", lcov);
+        assert!(lcov.contains("\nFNDA:3,f\n"), "{}", lcov);
+        assert!(lcov.contains("\nDA:3,2\n"), "{}", lcov);
+
+        // No more pauses once coverage has been stopped
+        ctx.eval_string("f(4);").unwrap();
+        assert!(ctx.coverage().is_err());
+        ctx.detach_debugger();
         ctx.assert_clean();
     }
 
     #[test]
-    fn eval_string_global_object_get_key_call() {
+    #[cfg(feature = "debugger")]
+    fn trace() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
         let _ = env_logger::init();
         let ctx = Context::new();
-        ctx.eval_string(r"
-          function foo() {
-            return 'a';
-          }")
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events_clone = events.clone();
+        let options = debugger::TraceOptions { locals: true, ..Default::default() };
+        ctx.start_trace(options, move |e: &debugger::TraceEvent| {
+                events_clone.borrow_mut().push(e.to_string())
+            })
             .unwrap();
-        let global = ctx.global_object();
-        ctx.assert_clean();
-        let foo = global.get("foo").unwrap();
-        ctx.assert_clean();
-        let value = foo.call(&[]).unwrap().to_value();
-        assert_eq!(Value::String("a".to_owned()), value);
+        let code = "function f(x) {\n  var y = x * 2;\n  return y;\n}\nf(3);";
+        ctx.eval_string_with_filename("trace.js", code).unwrap();
+        ctx.stop_trace().unwrap();
+        ctx.eval_string("f(4);").unwrap();
+
+        // The function declaration is instantiated on line 1
+        assert_eq!(vec!["trace.js:1 eval",
+                        "trace.js:5 eval",
+                        "trace.js:2 f x=3, y=undefined",
+                        "trace.js:3 f x=3, y=6",
+                        "trace.js:5 eval"],
+                   *events.borrow());
+        assert!(ctx.stop_trace().is_err());
+        ctx.detach_debugger();
         ctx.assert_clean();
     }
 
     #[test]
-    fn eval_string_global_object_call_method() {
+    #[cfg(feature = "debugger")]
+    fn trace_rate_limit() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
         let _ = env_logger::init();
         let ctx = Context::new();
-        ctx.eval_string(r"
-          var bar = 2;
-          function foo() {
-            if (this === undefined || this.bar !== 2) {
-              throw 'b';
-            }
-            return Array.prototype.slice.call(arguments);
-          }")
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events_clone = events.clone();
+        let options = debugger::TraceOptions { max_lines_per_second: Some(3), ..Default::default() };
+        ctx.start_trace(options, move |e: &debugger::TraceEvent| {
+                events_clone.borrow_mut().push((e.line_number, e.skipped))
+            })
             .unwrap();
-        let global = ctx.global_object();
-        ctx.assert_clean();
-        let value = global.call_method("foo", &[&Value::Number(4.25)]).unwrap().to_value();
-        assert_eq!(Value::Array(vec![Value::Number(4.25)]), value);
+        ctx.eval_string_with_filename("limit.js", "var a = 1;\na++;\na++;\na++;\na++;").unwrap();
+        ctx.stop_trace().unwrap();
+
+        assert_eq!(vec![(1, 0), (2, 0), (3, 0)], *events.borrow());
+        ctx.detach_debugger();
         ctx.assert_clean();
     }
 
     #[test]
-    fn eval_string_global_object_get_key_call_with_this() {
+    #[cfg(feature = "debugger")]
+    fn statement_hook() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
         let _ = env_logger::init();
         let ctx = Context::new();
-        ctx.eval_string(r"
-          var bar = 2;
-          function foo() {
-            if (this === undefined || this.bar !== 2) {
-              throw 'b';
-            }
-            return Array.prototype.slice.call(arguments);
-          }")
+        ctx.eval_string_with_filename("host.js", "function send(data) {\n  return data;\n}")
             .unwrap();
-        let global = ctx.global_object();
-        ctx.assert_clean();
-        let foo = global.get("foo").unwrap();
-        ctx.assert_clean();
-        let value = foo.call_with_this(&global, &[&Value::Number(4.25)]).unwrap().to_value();
-        assert_eq!(Value::Array(vec![Value::Number(4.25)]), value);
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let lines_clone = lines.clone();
+        ctx.set_statement_hook(move |file_name, line| {
+                lines_clone.borrow_mut().push((file_name.to_owned(), line));
+                if file_name == "host.js" {
+                    debugger::StatementAction::Abort
+                } else {
+                    debugger::StatementAction::Continue
+                }
+            })
+            .unwrap();
+
+        let result = ctx.eval_string_with_filename("plugin.js",
+                                                   "var sent = false;\n\
+                                                    try { send(1); sent = true; } catch (e) {}\n\
+                                                    sent = true;");
+        match result {
+            Err(Error(ErrorKind::Js(ref e), _)) => assert_eq!(JsErrorKind::Range, e.kind),
+            other => panic!("expected an abort, got {:?}", other),
+        }
+        // Neither the catch clause nor the line after it ran
+        assert!(lines.borrow().contains(&("host.js".to_owned(), 2)));
+        assert!(!lines.borrow().contains(&("plugin.js".to_owned(), 3)));
+
+        ctx.clear_statement_hook().unwrap();
+        assert_eq!(Value::Boolean(false), ctx.eval_string("sent").unwrap().to_value());
+        assert_eq!(Value::Number(2.0), ctx.call_global("send", &[&Value::Number(2.0)]).unwrap().to_value());
+        assert!(ctx.clear_statement_hook().is_err());
+        ctx.detach_debugger();
         ctx.assert_clean();
     }
 
     #[test]
-    fn eval_string_global_object_get_key_new() {
+    #[cfg(feature = "tracing")]
+    fn tracing_spans() {
+        use std::sync::{Arc, Mutex};
+        use tracing::{field, span, subscriber, Event, Metadata};
+
+        /// Records the name and fields of every span that is closed.
+        struct Recorder {
+            spans: Arc<Mutex<Vec<(&'static str, String)>>>,
+            open: Mutex<Vec<(&'static str, String)>>,
+        }
+
+        struct Fields<'a>(&'a mut String);
+
+        impl<'a> field::Visit for Fields<'a> {
+            fn record_debug(&mut self, field: &field::Field, value: &dyn fmt::Debug) {
+                if field.name() != "duration_us" {
+                    self.0.push_str(&format!("{}={:?};", field.name(), value));
+                }
+            }
+        }
+
+        impl subscriber::Subscriber for Recorder {
+            fn enabled(&self, _: &Metadata) -> bool {
+                true
+            }
+            fn new_span(&self, attrs: &span::Attributes) -> span::Id {
+                let mut fields = String::new();
+                attrs.record(&mut Fields(&mut fields));
+                let mut open = self.open.lock().unwrap();
+                open.push((attrs.metadata().name(), fields));
+                span::Id::from_u64(open.len() as u64)
+            }
+            fn record(&self, _: &span::Id, _: &span::Record) {}
+            fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+            fn event(&self, _: &Event) {}
+            fn enter(&self, _: &span::Id) {}
+            fn exit(&self, id: &span::Id) {
+                let open = self.open.lock().unwrap();
+                self.spans.lock().unwrap().push(open[id.into_u64() as usize - 1].clone());
+            }
+        }
+
+        let _ = env_logger::init();
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let recorder = Recorder {
+            spans: spans.clone(),
+            open: Mutex::new(Vec::new()),
+        };
+        subscriber::with_default(recorder, || {
+            let ctx = Context::builder()
+                .with_module_resolver(Box::new(|m, _| m))
+                .with_module_loader(Box::new(|_| Some("exports.x = 1;".to_owned())))
+                .build();
+            ctx.eval_string_with_filename("a.js", "function f() { return require('m').x; }")
+                .unwrap();
+            ctx.call_global("f", &[]).unwrap();
+            ctx.gc();
+            ctx.assert_clean();
+        });
+
+        assert_eq!(vec![("eval", "source=\"a.js\";".to_owned()),
+                        ("module_load", "module=\"m\";".to_owned()),
+                        ("call", "function=\"f\";".to_owned()),
+                        ("gc", String::new())],
+                   *spans.lock().unwrap());
+    }
+
+    #[test]
+    fn dump() {
         let _ = env_logger::init();
         let ctx = Context::new();
-        ctx.eval_string(r"
-          function foo() {
-            if (this.constructor !== foo) {
-              throw 'b';
-            }
-            return Array.prototype.slice.call(arguments);
-          }")
-            .unwrap();
-        let global = ctx.global_object();
-        ctx.assert_clean();
-        let foo = global.get("foo").unwrap();
-        ctx.assert_clean();
-        let value = foo.new(&[&Value::Number(4.25)]).unwrap().to_value();
-        assert_eq!(Value::Array(vec![Value::Number(4.25)]), value);
+        assert_eq!(0, ctx.stack_top());
+        unsafe {
+            duktape_sys::duk_push_int(ctx.raw, 42);
+            duktape_sys::duk_push_string(ctx.raw, nul_str(b"foo\0"));
+        }
+        assert_eq!(2, ctx.stack_top());
+        assert_eq!("ctx: top=2, stack=[42,\"foo\"]", ctx.dump());
+        assert_eq!(2, ctx.stack_top());
+        unsafe {
+            duktape_sys::duk_pop_2(ctx.raw);
+        }
         ctx.assert_clean();
     }
 
     #[test]
-    fn eval_string_call_global() {
+    fn stub_global() {
         let _ = env_logger::init();
         let ctx = Context::new();
-        ctx.eval_string(r"
-          function foo() {
-            return 'a';
-          }")
-            .unwrap();
-        let value = ctx.call_global("foo", &[]).unwrap().to_value();
-        assert_eq!(Value::String("a".to_owned()), value);
+        let stub = ctx.stub_global("next", &[Value::Number(1.0), Value::Number(2.0)]).unwrap();
+        let value = ctx.eval_string("[next(), next('a'), next(true, null)]").unwrap().to_value();
+        assert_eq!(Value::Array(vec![Value::Number(1.0), Value::Number(2.0), Value::Number(2.0)]),
+                   value);
+        assert_eq!(vec![vec![],
+                        vec![Value::String("a".to_owned())],
+                        vec![Value::Boolean(true), Value::Null]],
+                   stub.calls());
+        stub.reset().unwrap();
+        assert_eq!(0, stub.call_count());
         ctx.assert_clean();
     }
 
     #[test]
-    fn eval_string_call_global_args() {
+    fn spy() {
         let _ = env_logger::init();
         let ctx = Context::new();
-        ctx.eval_string(r"
-          function foo() {
-            return Array.prototype.slice.call(arguments);
-          }")
-            .unwrap();
+        ctx.eval_string("function double(n) { return n * 2; }").unwrap();
+        let spy = ctx.spy("double").unwrap();
+        let value = ctx.call_global("double", &[&Value::Number(4.0)]).unwrap().to_value();
+        assert_eq!(Value::Number(8.0), value);
+        ctx.eval_string("double(1)").unwrap();
+        assert_eq!(2, spy.call_count());
+        assert_eq!(vec![vec![Value::Number(4.0)], vec![Value::Number(1.0)]], spy.calls());
+        assert!(ctx.spy("missing").is_err());
+        ctx.assert_clean();
+    }
 
-        let mut obj = collections::BTreeMap::new();
-        obj.insert("a".to_owned(), Value::String("a".to_owned()));
-        obj.insert("b".to_owned(), Value::Number(3.0));
-        obj.insert("c".to_owned(), Value::Boolean(false));
+    #[test]
+    fn metrics() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        use std::time::Duration;
 
-        let arr = vec![Value::String("a".to_owned()), Value::Number(3.0), Value::Boolean(false)];
+        struct Recorder(Rc<RefCell<Vec<(metrics::Operation, String, bool)>>>, Rc<RefCell<usize>>);
 
-        let bytes = vec![0, 1, 2, 3];
+        impl metrics::Metrics for Recorder {
+            fn operation(&self, operation: metrics::Operation, name: &str, _: Duration, success: bool) {
+                self.0.borrow_mut().push((operation, name.to_owned(), success));
+            }
 
-        let values = &[Value::Undefined,
-                       Value::Null,
-                       Value::Boolean(true),
-                       Value::Number(1.0),
-                       Value::String("foo".to_owned()),
-                       Value::Array(arr),
-                       Value::Object(obj),
-                       Value::Bytes(bytes)];
-        let args = values.iter().map(|v| v as &Argument).collect::<Vec<_>>();
-        let value = ctx.call_global("foo", &args).unwrap().to_value();
-        assert_eq!(Value::Array(values.to_vec()), value);
+            fn bytes_converted(&self, bytes: usize) {
+                *self.1.borrow_mut() += bytes;
+            }
+        }
+
+        let _ = env_logger::init();
+        let operations = Rc::new(RefCell::new(Vec::new()));
+        let bytes = Rc::new(RefCell::new(0));
+        let ctx = Context::builder()
+            .with_polyfills(polyfills::PolyfillSet::all())
+            .with_metrics(Box::new(Recorder(operations.clone(), bytes.clone())))
+            .build();
+        ctx.eval_string_with_filename("m.js", "function f(s) { return [s, s + s]; }").unwrap();
+        let value = ctx.call_global("f", &[&Value::String("ab".to_owned())]).unwrap().to_value();
+        assert_eq!(Value::Array(vec![Value::String("ab".to_owned()),
+                                     Value::String("abab".to_owned())]),
+                   value);
+        assert!(ctx.call_global("g", &[]).is_err());
+        let f = ctx.global_function("f").unwrap();
+        f.reference.call_method("call", &[]).unwrap();
+        ctx.run_microtasks().unwrap();
+
+        assert_eq!(vec![(metrics::Operation::Eval, "m.js".to_owned(), true),
+                        (metrics::Operation::Call, "f".to_owned(), true),
+                        (metrics::Operation::Call, "g".to_owned(), false),
+                        (metrics::Operation::Call, "call".to_owned(), true),
+                        (metrics::Operation::Call, "microtasks".to_owned(), true)],
+                   *operations.borrow());
+        assert_eq!(6, *bytes.borrow());
         ctx.assert_clean();
     }
 
     #[test]
-    fn eval_string_call_global_error() {
+    #[cfg(feature = "profiler")]
+    fn profile() {
         let _ = env_logger::init();
         let ctx = Context::new();
-        ctx.eval_string(r"
-          function foo() {
-            throw 'a';
-          }")
-           .unwrap();
-        let value = ctx.call_global("foo", &[]);
-        assert_js_error(&value, JsErrorKind::Generic, "a");
+        let profile = ctx.profile(::std::time::Duration::from_secs(60));
+        let code = "function spin(n) {\n  var x = 0;\n  for (var i = 0; i < n; i++) { x += i; }\n  \
+                    return x;\n}\nspin(2000000);";
+        ctx.eval_string_with_filename("spin.js", code).unwrap();
+        assert!(profile.sample_count() > 0);
+
+        let mut folded = Vec::new();
+        profile.write_flamegraph(&mut folded).unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+        assert!(folded.lines().any(|l| l.contains(";spin (spin.js) ")), "{}", folded);
+
+        let mut speedscope = Vec::new();
+        profile.write_speedscope(&mut speedscope).unwrap();
+        let frames = profile.frames().len();
+        let speedscope = ctx.eval_string(&format!("({}).shared.frames.length",
+                                String::from_utf8(speedscope).unwrap()))
+            .unwrap()
+            .to_value();
+        assert_eq!(Value::Number(frames as f64), speedscope);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    #[cfg(feature = "profiler")]
+    fn profile_deep_stacks() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let profile = ctx.profile(::std::time::Duration::from_secs(60));
+        let code = "function spin(n) { var x = 0; for (var i = 0; i < n; i++) { x += i; } }\n\
+                    function deep(n) { return n > 0 ? deep(n - 1) : spin(2000000); }\ndeep(100);";
+        ctx.eval_string_with_filename("deep.js", code).unwrap();
+        assert!(profile.sample_count() > 0);
+
+        let mut folded = Vec::new();
+        profile.write_flamegraph(&mut folded).unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+        let line = folded.lines().find(|l| l.contains(";spin (deep.js) ")).unwrap();
+        assert_eq!(101, line.matches("deep (deep.js)").count(), "{}", line);
         ctx.assert_clean();
     }
 
@@ -0,0 +1,126 @@
+//! Loading several script files at once, see `Context::load_all`.
+//!
+//! Files are loaded in the order they are given.  Directories are expanded into the `.js` files
+//! they contain, including the ones in subdirectories, in the order of their names.  Loading stops
+//! at the first file that can't be read or fails to evaluate, with an `ErrorKind::Load` error that
+//! names the file and has the original error as its cause.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! let ctx = duk::Context::new();
+//! let report = ctx.load_all(&["prelude.js", "lib/", "main.js"]).unwrap();
+//! println!("loaded {} files, defining {}", report.files.len(), report.globals.join(", "));
+//! ```
+
+use std::collections;
+use std::fs;
+use std::io;
+use std::path;
+
+use duktape_sys;
+
+use strings;
+use {ChainErr, ErrorKind, Result};
+
+/// What `Context::load_all` loaded.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct LoadReport {
+    /// The files that were loaded, in order.
+    pub files: Vec<path::PathBuf>,
+    /// The names of the globals that the files defined, in alphabetical order.  Globals that
+    /// existed before are not included, even if the files assigned them.
+    pub globals: Vec<String>,
+}
+
+/// Expands a path into the files to load, which is the path itself unless it is a directory.
+pub(crate) fn files(path: &path::Path) -> Result<Vec<path::PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_owned()]);
+    }
+
+    let mut entries = fs::read_dir(path)
+        .and_then(|entries| entries.map(|e| e.map(|e| e.path())).collect::<io::Result<Vec<_>>>())
+        .chain_err(|| ErrorKind::Load(path.display().to_string()))?;
+    entries.sort();
+    let mut files = Vec::new();
+    for entry in entries {
+        if entry.is_dir() {
+            files.extend(self::files(&entry)?);
+        } else if entry.extension().is_some_and(|e| e == "js") {
+            files.push(entry);
+        }
+    }
+    Ok(files)
+}
+
+/// Returns the names of the own properties of the global object, including non-enumerable ones.
+pub(crate) unsafe fn global_names(ctx: *mut duktape_sys::duk_context)
+                                  -> collections::BTreeSet<String> {
+    use duktape_sys::*;
+
+    let mut names = collections::BTreeSet::new();
+    duk_push_global_object(ctx);
+    duk_enum(ctx, -1, DUK_ENUM_OWN_PROPERTIES_ONLY | DUK_ENUM_INCLUDE_NONENUMERABLE);
+    while duk_next(ctx, -1, 0) != 0 {
+        names.insert(strings::get(ctx, -1));
+        duk_pop(ctx);
+    }
+    duk_pop_2(ctx);
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::fs;
+
+    use testing::ScratchDir;
+    use {Context, ErrorKind, Value};
+
+    #[test]
+    #[cfg_attr(target_os = "wasi", ignore = "needs a temporary directory")]
+    fn load_all() {
+        let _ = env_logger::init();
+        let dir = ScratchDir::new("load-all");
+        fs::create_dir_all(dir.join("lib/util")).unwrap();
+        fs::write(dir.join("prelude.js"), "var parts = [];").unwrap();
+        fs::write(dir.join("lib/b.js"), "parts.push('b');").unwrap();
+        fs::write(dir.join("lib/a.js"), "function a() {} parts.push('a');").unwrap();
+        fs::write(dir.join("lib/util/c.js"), "parts.push('c');").unwrap();
+        fs::write(dir.join("lib/notes.txt"), "not a script").unwrap();
+        fs::write(dir.join("main.js"), "var result = parts.join(); Math.answer = 42;").unwrap();
+
+        let ctx = Context::new();
+        let report = ctx.load_all(&[dir.join("prelude.js"), dir.join("lib"), dir.join("main.js")])
+            .unwrap();
+        assert_eq!(vec![dir.join("prelude.js"),
+                        dir.join("lib/a.js"),
+                        dir.join("lib/b.js"),
+                        dir.join("lib/util/c.js"),
+                        dir.join("main.js")],
+                   report.files);
+        assert_eq!(vec!["a", "parts", "result"], report.globals);
+        assert_eq!(Value::String("a,b,c".to_owned()),
+                   ctx.eval_string("result").unwrap().to_value());
+        ctx.assert_clean();
+
+        fs::write(dir.join("lib/b.js"), "parts.push(;").unwrap();
+        let ctx = Context::new();
+        let error = ctx.load_all(&[dir.join("prelude.js"), dir.join("lib"), dir.join("main.js")])
+            .unwrap_err();
+        assert_eq!(format!("failed to load {}", dir.join("lib/b.js").display()), error.to_string());
+        let cause = (error.1).0.as_ref().unwrap().downcast_ref::<::Error>().unwrap();
+        assert!(match *cause.kind() {
+            ErrorKind::Js(_) => true,
+            _ => false,
+        });
+        assert_eq!(Value::Undefined, ctx.eval_string("this.result").unwrap().to_value());
+
+        let error = ctx.load_all(&[dir.join("missing.js")]).unwrap_err();
+        assert_eq!(format!("failed to load {}", dir.join("missing.js").display()),
+                   error.to_string());
+        ctx.assert_clean();
+    }
+}
@@ -0,0 +1,30 @@
+//! Hooks for collecting operational metrics of a context, see `ContextBuilder::with_metrics`.
+
+use std::time;
+
+/// The kind of an operation that is reported to `Metrics`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Operation {
+    /// Evaluation of a script, like `Context::eval_string` or `Context::eval_file`.
+    Eval,
+    /// A call of a function, like `Context::call_global` or `Reference::call`.
+    Call,
+}
+
+/// Receives measurements of the operations performed by a context.
+///
+/// The context invokes the metrics synchronously, so implementations should only do cheap
+/// bookkeeping like updating counters and histograms.
+pub trait Metrics {
+    /// Called after every evaluation and call, with the file name (for evaluations) or function
+    /// name (for calls), how long the operation took, and whether it succeeded.
+    ///
+    /// The name is empty for calls of functions through a `Reference`, which don't have a name.
+    fn operation(&self, operation: Operation, name: &str, duration: time::Duration, success: bool);
+
+    /// Called every time string or buffer data is copied out of a Javascript value into Rust,
+    /// with the number of bytes that were copied.
+    fn bytes_converted(&self, bytes: usize) {
+        let _ = bytes;
+    }
+}
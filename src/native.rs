@@ -0,0 +1,841 @@
+//! Rust functions that scripts can call, see `Context::register_function`.
+//!
+//! A native function receives its arguments as `Value`s and returns a `Value`, or an error that
+//! is thrown into the script as an exception, so that scripts can handle failures of the host
+//! with `try`/`catch`:
+//!
+//!   * `ErrorKind::Js` errors are thrown as errors of the same kind with the same message, so
+//!     `Err(JsError::new(JsErrorKind::Type, "expected a path").into())` throws a `TypeError`.
+//!   * `ErrorKind::Conversion` errors, like the ones from `FromValue`, are thrown as `TypeError`s.
+//!   * Other errors, like `ErrorKind::Io`, are thrown as `Error`s with the message of the error.
+//!
+//! A panic in a native function is caught too, and thrown as an `Error`.  Exceptions that nothing
+//! catches reach the host like any other error of the script.
+//!
+//! Functions registered with `Context::register_variadic` take any number of arguments, like
+//! Javascript functions with optional or rest parameters.  They get the `Arguments` of the call,
+//! which tell how many arguments were actually passed, their types (which `Value`s don't fully
+//! preserve, since functions convert like other objects), and the value of `this`.
+//!
+//! Instead of defining a global for every function, `Context::register_module` groups functions
+//! and constants in one global object, as the methods and read-only properties of a namespace.
+//!
+//! Functions registered with `Context::register_function_mut` can mutate the state that they
+//! capture, like collecting log lines or sending events to a channel.  The function, and with it
+//! the state it captures, is kept alive for as long as the Javascript function object, and
+//! dropped when the object is garbage collected or the context is dropped.
+//!
+//! Writing the conversions of the arguments and of the result by hand gets noisy for functions
+//! with typed parameters.  The `#[duk_function]` attribute of the `duk-macros` crate generates
+//! them instead, from a plain Rust function, as an implementation of `NativeFunction` that
+//! `Context::register_native` registers:
+//!
+//! ```ignore
+//! #[duk_function]
+//! fn add(a: f64, b: f64) -> f64 {
+//!     a + b
+//! }
+//!
+//! ctx.register_native::<add>();
+//! ```
+//!
+//! # Examples
+//!
+//! ```
+//! use duk::{JsError, JsErrorKind, Value};
+//!
+//! let ctx = duk::Context::new();
+//! ctx.register_function("half", 1, |args| match args[0] {
+//!     Value::Number(n) => Ok(Value::Number(n / 2.0)),
+//!     _ => Err(JsError::new(JsErrorKind::Type, "not a number").into()),
+//! });
+//! let result = ctx.eval_string("try { half('one'); } catch (e) { e.name + ': ' + e.message }")
+//!     .unwrap();
+//! assert_eq!(Value::String("TypeError: not a number".to_owned()), result.to_value());
+//!
+//! ctx.register_variadic("describe", |args| {
+//!     let types = (0..args.len()).map(|i| format!("{:?}", args.type_of(i).unwrap()));
+//!     Ok(Value::String(types.collect::<Vec<_>>().join(" ")))
+//! });
+//! assert_eq!(Value::String("Number Function".to_owned()),
+//!            ctx.eval_string("describe(1, describe)").unwrap().to_value());
+//! ```
+
+use std::any;
+use std::cell;
+use std::collections;
+use std::marker;
+use std::panic;
+use std::rc;
+
+use duktape_sys;
+
+use finalizers;
+use functions;
+use nul_str;
+use strings;
+use userdata;
+use HeapData;
+use {Error, ErrorKind, FromValue, JsError, JsErrorKind, Result, Value};
+
+/// The Rust side of a native function.
+pub(crate) type Function = dyn Fn(&Arguments) -> Result<Value>;
+
+/// The Rust functions of the native function objects of a context, by the heap pointers of the
+/// objects.
+pub(crate) type Registry = cell::RefCell<collections::HashMap<usize, rc::Rc<Function>>>;
+
+/// The type of a Javascript value, see `Arguments::type_of`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Type {
+    /// `undefined`, which is also the type of missing arguments.
+    Undefined,
+    /// `null`.
+    Null,
+    /// A boolean.
+    Boolean,
+    /// A number.
+    Number,
+    /// A string.
+    String,
+    /// An array.
+    Array,
+    /// A function, including native functions.
+    Function,
+    /// Any other object, including buffer objects like `Uint8Array`.
+    Object,
+    /// A plain Duktape buffer.
+    Buffer,
+    /// A Duktape pointer.
+    Pointer,
+}
+
+/// The arguments of a call of a native function, and the value of `this`.
+pub struct Arguments<'a> {
+    pub(crate) ctx: *mut duktape_sys::duk_context,
+    values: Vec<Value>,
+    call: marker::PhantomData<&'a ()>,
+}
+
+/// Adds native functions and constants to the object of a module, see
+/// `Context::register_module`.
+pub struct ModuleBuilder<'a> {
+    ctx: *mut duktape_sys::duk_context,
+    module: marker::PhantomData<&'a ()>,
+}
+
+/// A native function with a fixed name and number of arguments, usually generated by the
+/// `#[duk_function]` attribute of the `duk-macros` crate, see `Context::register_native`.
+pub trait NativeFunction {
+    /// The name of the function in Javascript.
+    const NAME: &'static str;
+    /// The number of arguments that the function gets.
+    const NARGS: usize;
+
+    /// Calls the function with exactly `NARGS` arguments.
+    fn call(args: &[Value]) -> Result<Value>;
+}
+
+/// The result of a native function, which converts into the result of the call.
+///
+/// Implemented for `()`, which returns `undefined`, for everything that converts into a `Value`,
+/// and for `Result`s of those.
+pub trait NativeResult {
+    /// Converts the result of the function into a value, or the error to throw.
+    fn into_result(self) -> Result<Value>;
+}
+
+impl NativeResult for () {
+    fn into_result(self) -> Result<Value> {
+        Ok(Value::Undefined)
+    }
+}
+
+impl<T> NativeResult for T
+    where T: Into<Value>
+{
+    fn into_result(self) -> Result<Value> {
+        Ok(self.into())
+    }
+}
+
+impl<T> NativeResult for Result<T>
+    where T: NativeResult
+{
+    fn into_result(self) -> Result<Value> {
+        self.and_then(NativeResult::into_result)
+    }
+}
+
+/// Converts the argument at `index`, or fails with an `ErrorKind::Conversion` error that names
+/// the argument.  Missing arguments are `undefined`.
+pub fn argument<T>(args: &[Value], index: usize, name: &str) -> Result<T>
+    where T: FromValue
+{
+    let value = args.get(index).cloned().unwrap_or(Value::Undefined);
+    T::from_value(value).map_err(|error| match *error.kind() {
+        ErrorKind::Conversion(ref message) => {
+            let message = format!("argument {} ({}): {}", index + 1, name, message);
+            ErrorKind::Conversion(message).into()
+        }
+        _ => error,
+    })
+}
+
+impl<'a> ModuleBuilder<'a> {
+    /// Adds a function that gets `nargs` arguments, like `Context::register_function`.
+    pub fn function<F>(&mut self, name: &str, nargs: usize, function: F) -> &mut Self
+        where F: Fn(&[Value]) -> Result<Value> + 'static
+    {
+        self.boxed(name, nargs as duktape_sys::duk_idx_t, fixed(function))
+    }
+
+    /// Adds a function that mutates its state, like `Context::register_function_mut`.
+    pub fn function_mut<F>(&mut self, name: &str, nargs: usize, function: F) -> &mut Self
+        where F: FnMut(&[Value]) -> Result<Value> + 'static
+    {
+        self.function(name, nargs, exclusive(function))
+    }
+
+    /// Adds a function that gets all arguments, like `Context::register_variadic`.
+    pub fn variadic<F>(&mut self, name: &str, function: F) -> &mut Self
+        where F: Fn(&Arguments) -> Result<Value> + 'static
+    {
+        let nargs = unsafe { duktape_sys::DUK_VARARGS };
+        self.boxed(name, nargs, Box::new(function))
+    }
+
+    /// Adds a native function under its own name, like `Context::register_native`.
+    pub fn native<F>(&mut self) -> &mut Self
+        where F: NativeFunction + 'static
+    {
+        self.function(F::NAME, F::NARGS, F::call)
+    }
+
+    /// Adds a read-only property.
+    pub fn constant<V>(&mut self, name: &str, value: V) -> &mut Self
+        where V: Into<Value>
+    {
+        use duktape_sys::*;
+
+        unsafe {
+            strings::push(self.ctx, name);
+            value.into().push(self.ctx);
+            duk_def_prop(self.ctx,
+                         -3,
+                         DUK_DEFPROP_HAVE_VALUE | DUK_DEFPROP_HAVE_WRITABLE |
+                         DUK_DEFPROP_HAVE_ENUMERABLE | DUK_DEFPROP_ENUMERABLE |
+                         DUK_DEFPROP_HAVE_CONFIGURABLE);
+        }
+        self
+    }
+
+    /// Adds a read-only property whose value comes from a Rust function whenever it is read.
+    pub fn getter<G>(&mut self, name: &str, get: G) -> &mut Self
+        where G: Fn() -> Result<Value> + 'static
+    {
+        self.define_accessor(name, getter(get), None)
+    }
+
+    /// Adds a property whose value comes from a Rust function whenever it is read, and goes to
+    /// another one whenever it is assigned.  Errors of either are thrown into the script, so the
+    /// setter can reject invalid values.
+    pub fn accessor<G, S>(&mut self, name: &str, get: G, set: S) -> &mut Self
+        where G: Fn() -> Result<Value> + 'static,
+              S: Fn(Value) -> Result<()> + 'static
+    {
+        self.define_accessor(name, getter(get), Some(setter(set)))
+    }
+
+    fn define_accessor(&mut self, name: &str, get: Box<Function>, set: Option<Box<Function>>)
+                       -> &mut Self {
+        unsafe {
+            let module = duktape_sys::duk_get_top_index(self.ctx);
+            strings::push(self.ctx, name);
+            let flags = push_accessor(self.ctx, get, set);
+            duktape_sys::duk_def_prop(self.ctx, module, flags);
+        }
+        self
+    }
+
+    /// Adds a nested module, like `host.fs` within `host`.
+    pub fn module<F>(&mut self, name: &str, build: F) -> &mut Self
+        where F: FnOnce(&mut ModuleBuilder)
+    {
+        unsafe {
+            strings::push(self.ctx, name);
+            duktape_sys::duk_push_object(self.ctx);
+            build_module(self.ctx, build);
+            duktape_sys::duk_put_prop(self.ctx, -3);
+        }
+        self
+    }
+
+    fn boxed(&mut self, name: &str, nargs: duktape_sys::duk_idx_t, function: Box<Function>)
+             -> &mut Self {
+        unsafe {
+            strings::push(self.ctx, name);
+            push(self.ctx, nargs, function);
+            duktape_sys::duk_put_prop(self.ctx, -3);
+        }
+        self
+    }
+}
+
+impl<'a> Arguments<'a> {
+    /// The number of arguments that were passed.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Whether the function was called without arguments.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// The argument at the specified index, or `None` if fewer arguments were passed.
+    pub fn get(&self, index: usize) -> Option<&Value> {
+        self.values.get(index)
+    }
+
+    /// The type of the argument at the specified index, or `None` if fewer arguments were passed.
+    pub fn type_of(&self, index: usize) -> Option<Type> {
+        if index < self.values.len() {
+            Some(unsafe { Type::of(self.ctx, index as duktape_sys::duk_idx_t) })
+        } else {
+            None
+        }
+    }
+
+    /// All arguments, in order.
+    pub fn values(&self) -> &[Value] {
+        &self.values
+    }
+
+    /// The value of `this`, which is `undefined` unless the function was called as a method or a
+    /// receiver was passed with `call` or `apply`.  Converting it copies the whole object, so
+    /// check `this_type` first when only the type matters.
+    pub fn this(&self) -> Value {
+        unsafe {
+            duktape_sys::duk_push_this(self.ctx);
+            let this = Value::get(self.ctx, -1);
+            duktape_sys::duk_pop(self.ctx);
+            this
+        }
+    }
+
+    /// The type of `this`.
+    pub fn this_type(&self) -> Type {
+        unsafe {
+            duktape_sys::duk_push_this(self.ctx);
+            let this_type = Type::of(self.ctx, -1);
+            duktape_sys::duk_pop(self.ctx);
+            this_type
+        }
+    }
+
+    /// The userdata of the argument at the specified index, if it is an object with userdata of
+    /// type `T`, see `ObjectRef::set_userdata`.
+    pub fn userdata<T>(&self, index: usize) -> Option<rc::Rc<T>>
+        where T: 'static
+    {
+        if index < self.values.len() {
+            unsafe { userdata::get(self.ctx, index as duktape_sys::duk_idx_t) }
+        } else {
+            None
+        }
+    }
+
+    /// Retains the argument at the specified index as a `Function`, if it is callable, so that the
+    /// host can call it later, like a callback or an event listener.
+    pub fn function(&self, index: usize) -> Option<::Function> {
+        let index = index as duktape_sys::duk_idx_t;
+        if index < self.values.len() as duktape_sys::duk_idx_t &&
+           unsafe { duktape_sys::duk_is_callable(self.ctx, index) } != 0 {
+            Some(unsafe { functions::retain(self.ctx, index) })
+        } else {
+            None
+        }
+    }
+
+    /// The userdata of `this`, if it is an object with userdata of type `T`.
+    pub fn this_userdata<T>(&self) -> Option<rc::Rc<T>>
+        where T: 'static
+    {
+        unsafe {
+            duktape_sys::duk_push_this(self.ctx);
+            let userdata = userdata::get(self.ctx, -1);
+            duktape_sys::duk_pop(self.ctx);
+            userdata
+        }
+    }
+}
+
+impl Type {
+    unsafe fn of(ctx: *mut duktape_sys::duk_context, index: duktape_sys::duk_idx_t) -> Type {
+        use duktape_sys::*;
+
+        let t = duk_get_type(ctx, index);
+        if t == DUK_TYPE_NULL {
+            Type::Null
+        } else if t == DUK_TYPE_BOOLEAN {
+            Type::Boolean
+        } else if t == DUK_TYPE_NUMBER {
+            Type::Number
+        } else if t == DUK_TYPE_STRING {
+            Type::String
+        } else if t == DUK_TYPE_LIGHTFUNC || duk_is_function(ctx, index) != 0 {
+            Type::Function
+        } else if duk_is_array(ctx, index) != 0 {
+            Type::Array
+        } else if t == DUK_TYPE_OBJECT {
+            Type::Object
+        } else if t == DUK_TYPE_BUFFER {
+            Type::Buffer
+        } else if t == DUK_TYPE_POINTER {
+            Type::Pointer
+        } else {
+            Type::Undefined
+        }
+    }
+}
+
+/// Adapts a function of the values of the arguments.
+pub(crate) fn fixed<F>(function: F) -> Box<Function>
+    where F: Fn(&[Value]) -> Result<Value> + 'static
+{
+    Box::new(move |args: &Arguments| function(args.values()))
+}
+
+/// Adapts a function that mutates its state, which throws if it is called again while it runs.
+pub(crate) fn exclusive<F>(function: F) -> impl Fn(&[Value]) -> Result<Value>
+    where F: FnMut(&[Value]) -> Result<Value>
+{
+    let function = cell::RefCell::new(function);
+    move |args| match function.try_borrow_mut() {
+        Ok(mut function) => (*function)(args),
+        Err(_) => {
+            Err(JsError::new(JsErrorKind::Error, "native function called recursively").into())
+        }
+    }
+}
+
+/// Adapts the getter of an accessor property.
+pub(crate) fn getter<G>(get: G) -> Box<Function>
+    where G: Fn() -> Result<Value> + 'static
+{
+    Box::new(move |_: &Arguments| get())
+}
+
+/// Adapts the setter of an accessor property.
+pub(crate) fn setter<S>(set: S) -> Box<Function>
+    where S: Fn(Value) -> Result<()> + 'static
+{
+    Box::new(move |args: &Arguments| set(args.values()[0].clone()).map(|()| Value::Undefined))
+}
+
+/// Pushes the getter, and the setter if there is one, of an enumerable but not configurable
+/// accessor property, and returns the flags for `duk_def_prop`.
+pub(crate) unsafe fn push_accessor(ctx: *mut duktape_sys::duk_context,
+                                   get: Box<Function>,
+                                   set: Option<Box<Function>>)
+                                   -> duktape_sys::duk_uint_t {
+    use duktape_sys::*;
+
+    let mut flags = DUK_DEFPROP_HAVE_GETTER | DUK_DEFPROP_HAVE_ENUMERABLE |
+                    DUK_DEFPROP_ENUMERABLE | DUK_DEFPROP_HAVE_CONFIGURABLE;
+    push(ctx, 0, get);
+    if let Some(set) = set {
+        push(ctx, 1, set);
+        flags |= DUK_DEFPROP_HAVE_SETTER;
+    }
+    flags
+}
+
+/// Adds the functions and constants of a module to the object on top of the stack.
+pub(crate) unsafe fn build_module<F>(ctx: *mut duktape_sys::duk_context, build: F)
+    where F: FnOnce(&mut ModuleBuilder)
+{
+    build(&mut ModuleBuilder {
+        ctx,
+        module: marker::PhantomData,
+    });
+}
+
+/// Pushes a Javascript function that calls the Rust function with the specified number of
+/// arguments (missing arguments are `undefined`, extra ones are ignored), or with all arguments
+/// if it is `DUK_VARARGS`.
+pub(crate) unsafe fn push(ctx: *mut duktape_sys::duk_context,
+                          nargs: duktape_sys::duk_idx_t,
+                          function: Box<Function>) {
+    use duktape_sys::*;
+
+    duk_push_c_function(ctx, Some(call), nargs);
+    let object = duk_get_heapptr(ctx, -1) as usize;
+    // The function object may be rescued and called again, which must not find the function
+    let added = finalizers::add(ctx, -1, Box::new(move |ctx| {
+        let function = registry(ctx).borrow_mut().remove(&object);
+        drop(function);
+    }));
+    // The function object is a new one, which can always have a finalizer
+    debug_assert!(added, "native function can't have a finalizer");
+    registry(ctx).borrow_mut().insert(object, rc::Rc::from(function));
+}
+
+unsafe fn registry<'a>(ctx: *mut duktape_sys::duk_context) -> &'a Registry {
+    let mut funcs = duktape_sys::duk_memory_functions::default();
+    duktape_sys::duk_get_memory_functions(ctx, &mut funcs);
+    &(*(funcs.udata as *mut HeapData)).native_functions
+}
+
+/// Calls the Rust function of the running function object.
+unsafe extern "C" fn call(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    if invoke(ctx) {
+        1
+    } else {
+        // `duk_throw` doesn't unwind Rust frames, so nothing here may need to be dropped
+        duktape_sys::duk_throw(ctx);
+        0
+    }
+}
+
+/// Calls the Rust function of the running function object with the arguments, and pushes either
+/// its result or the error to throw.  Returns whether the call succeeded.
+unsafe fn invoke(ctx: *mut duktape_sys::duk_context) -> bool {
+    use duktape_sys::*;
+
+    let args = Arguments {
+        ctx,
+        values: (0..duk_get_top(ctx)).map(|i| Value::get(ctx, i)).collect(),
+        call: marker::PhantomData,
+    };
+    duk_push_current_function(ctx);
+    let object = duk_get_heapptr(ctx, -1) as usize;
+    duk_pop(ctx);
+    // Holding on to the function keeps it alive, even if the call finalizes its object
+    let function = match registry(ctx).borrow().get(&object) {
+        Some(function) => function.clone(),
+        None => {
+            // Scripts can run the finalizer themselves with `Duktape.fin(f)(f)`, and call `f` later
+            push_error_object(ctx,
+                              JsErrorKind::Error,
+                              "native function called after it was finalized");
+            return false;
+        }
+    };
+
+    match panic::catch_unwind(panic::AssertUnwindSafe(|| function(&args))) {
+        Ok(Ok(value)) => {
+            value.push(ctx);
+            true
+        }
+        Ok(Err(error)) => {
+            push_error(ctx, &error);
+            false
+        }
+        Err(payload) => {
+            let message = format!("native function panicked: {}", panic_message(&*payload));
+            push_error_object(ctx, JsErrorKind::Error, &message);
+            false
+        }
+    }
+}
+
+/// Pushes the exception that an error of a native function throws.
+pub(crate) unsafe fn push_error(ctx: *mut duktape_sys::duk_context, error: &Error) {
+    match *error.kind() {
+        ErrorKind::Js(ref error) => push_error_object(ctx, error.kind, &error.message),
+        ErrorKind::Conversion(ref message) => push_error_object(ctx, JsErrorKind::Type, message),
+        _ => push_error_object(ctx, JsErrorKind::Error, &error.to_string()),
+    }
+}
+
+unsafe fn push_error_object(ctx: *mut duktape_sys::duk_context, kind: JsErrorKind, message: &str) {
+    use duktape_sys::*;
+
+    // The message is set afterwards, since the format string can't have arguments
+    duk_push_error_object(ctx, kind.to_raw(), nul_str(b"\0"));
+    strings::push(ctx, message);
+    duk_put_prop_string(ctx, -2, nul_str(b"message\0"));
+}
+
+fn panic_message(payload: &(dyn any::Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::io;
+    use std::rc;
+    use std::sync::mpsc;
+
+    use super::*;
+    use Context;
+
+    #[test]
+    fn errors_become_exceptions() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.register_function("fail", 1, |args| {
+            match args[0] {
+                Value::String(ref kind) if kind == "range" => {
+                    Err(JsError::new(JsErrorKind::Range, "out of range").into())
+                }
+                Value::String(ref kind) if kind == "conversion" => {
+                    Err(ErrorKind::Conversion("expected a number".to_owned()).into())
+                }
+                Value::String(ref kind) if kind == "io" => {
+                    Err(io::Error::new(io::ErrorKind::NotFound, "no config").into())
+                }
+                Value::String(ref kind) if kind == "panic" => panic!("oops"),
+                ref value => Ok(value.clone()),
+            }
+        });
+        let result = ctx.eval_string(r"
+          ['range', 'conversion', 'io', 'panic'].map(function (kind) {
+            try { fail(kind); } catch (e) { return e.name + ': ' + e.message; }
+          }).concat(fail(1))
+        ")
+            .unwrap();
+        assert_eq!(Value::Array(vec![Value::String("RangeError: out of range".to_owned()),
+                                     Value::String("TypeError: expected a number".to_owned()),
+                                     Value::String("Error: no config".to_owned()),
+                                     Value::String("Error: native function panicked: oops"
+                                         .to_owned()),
+                                     Value::Number(1.0)]),
+                   result.to_value());
+        // Uncaught exceptions reach the host
+        match *ctx.eval_string("fail('range')").unwrap_err().kind() {
+            ErrorKind::Js(ref error) => {
+                assert_eq!(JsErrorKind::Range, error.kind);
+                assert_eq!("out of range", error.message);
+            }
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn functions_are_dropped() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let captured = rc::Rc::new(());
+        let held = captured.clone();
+        ctx.register_function("held", 0, move |_| {
+            Ok(Value::Number(rc::Rc::strong_count(&held) as f64))
+        });
+        assert_eq!(Value::Number(2.0), ctx.call("held", ()).unwrap().to_value());
+        ctx.eval_string("held = null;").unwrap();
+        ctx.gc();
+        assert_eq!(1, rc::Rc::strong_count(&captured));
+
+        let held = captured.clone();
+        ctx.register_function("held", 0, move |_| {
+            Ok(Value::Number(rc::Rc::strong_count(&held) as f64))
+        });
+        ctx.assert_clean();
+        drop(ctx);
+        assert_eq!(1, rc::Rc::strong_count(&captured));
+    }
+
+    #[test]
+    fn finalized_functions() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let captured = rc::Rc::new(());
+        let held = captured.clone();
+        ctx.register_function("held", 0, move |_| {
+            Ok(Value::Number(rc::Rc::strong_count(&held) as f64))
+        });
+        ctx.register_function("other", 0, |_| Ok(Value::String("other".to_owned())));
+        let result = ctx.eval_string(r"
+          function error(f) { try { f(); } catch (e) { return e.name + ': ' + e.message; } }
+          var before = held();
+          // Copying hidden properties to another native function doesn't copy the Rust function
+          var k = String(Duktape.dec('hex', 'ff66756e6374696f6e'));
+          other[k] = held[k];
+          Duktape.fin(held)(held);
+          [before, error(held), other()]
+        ")
+            .unwrap()
+            .to_value();
+        let message = "Error: native function called after it was finalized";
+        assert_eq!(Value::Array(vec![Value::Number(2.0),
+                                     Value::String(message.to_owned()),
+                                     Value::String("other".to_owned())]),
+                   result);
+        assert_eq!(1, rc::Rc::strong_count(&captured));
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn variadic_arguments() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.register_variadic("inspect", |args| {
+            let mut types = (0..args.len() + 1).map(|i| format!("{:?}", args.type_of(i)))
+                .collect::<Vec<_>>();
+            types.push(format!("{:?}", args.this_type()));
+            Ok(Value::Array(vec![Value::Number(args.len() as f64),
+                                 Value::String(types.join(" ")),
+                                 args.this()]))
+        });
+        let result = ctx.eval_string(r"
+          [inspect(),
+           inspect(undefined, null, true, 1, 's', [], inspect, {}, new Uint8Array(1)),
+           inspect.call({answer: 42}, 'x')]
+        ")
+            .unwrap();
+        assert_eq!(Value::Array(vec![Value::Array(vec![Value::Number(0.0),
+                                                       Value::String("None Undefined".to_owned()),
+                                                       Value::Undefined]),
+                                     Value::Array(vec![Value::Number(9.0),
+                                                       Value::String("Some(Undefined) \
+                                                                      Some(Null) \
+                                                                      Some(Boolean) \
+                                                                      Some(Number) \
+                                                                      Some(String) Some(Array) \
+                                                                      Some(Function) \
+                                                                      Some(Object) \
+                                                                      Some(Object) None \
+                                                                      Undefined"
+                                                           .to_owned()),
+                                                       Value::Undefined]),
+                                     Value::Array(vec![Value::Number(1.0),
+                                                       Value::String("Some(String) None Object"
+                                                           .to_owned()),
+                                                       Value::object()
+                                                           .field("answer", 42.0)
+                                                           .build()])]),
+                   result.to_value());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn stateful_functions() {
+        let _ = env_logger::init();
+        let ctx = rc::Rc::new(Context::new());
+        let mut lines = Vec::new();
+        let (sender, events) = mpsc::channel();
+        ctx.register_function_mut("log", 1, move |args| {
+            lines.push(format!("{:?}", args[0]));
+            sender.send(lines.len()).unwrap();
+            Ok(Value::Number(lines.len() as f64))
+        });
+        assert_eq!(Value::Number(3.0),
+                   ctx.eval_string("log('a'); log(1); log(null)").unwrap().to_value());
+        assert_eq!(vec![1, 2, 3], events.try_iter().collect::<Vec<_>>());
+
+        // Calling back into the same function fails instead of aliasing its state
+        let inner = ctx.clone();
+        ctx.register_function_mut("reenter", 0, move |_| {
+            inner.call("reenter", ()).map(|_| Value::Undefined)
+        });
+        assert!(ctx.call("reenter", ()).unwrap_err().to_string().contains("recursively"));
+        // Dropping the function drops its state, which closes the channel
+        ctx.eval_string("log = null; reenter = null;").unwrap();
+        ctx.gc();
+        assert!(events.recv().is_err());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn modules() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let mut reads = 0;
+        ctx.register_module("host", |m| {
+            m.function_mut("readConfig", 1, move |args| {
+                    reads += 1;
+                    Ok(Value::String(format!("{:?} #{}", args[0], reads)))
+                })
+                .variadic("count", |args| Ok(Value::Number(args.len() as f64)))
+                .constant("VERSION", "1.2.0")
+                .module("limits", |m| {
+                    m.constant("MAX_PLUGINS", 8.0);
+                });
+        });
+        // The host object keeps what was on it before
+        ctx.register_module("host", |m| {
+            m.constant("NAME", "test");
+        });
+        let result = ctx.eval_string(r"
+          'use strict';
+          var readOnly;
+          try { host.VERSION = '2'; } catch (e) { readOnly = e.name; }
+          [host.readConfig('a'), host.readConfig('b'), host.count(1, 2, 3), host.VERSION,
+           host.limits.MAX_PLUGINS, host.NAME, typeof host.events, readOnly,
+           Object.keys(host).sort().join()]
+        ")
+            .unwrap();
+        assert_eq!(Value::Array(vec![Value::String("String(\"a\") #1".to_owned()),
+                                     Value::String("String(\"b\") #2".to_owned()),
+                                     Value::Number(3.0),
+                                     Value::String("1.2.0".to_owned()),
+                                     Value::Number(8.0),
+                                     Value::String("test".to_owned()),
+                                     Value::String("object".to_owned()),
+                                     Value::String("TypeError".to_owned()),
+                                     Value::String("NAME,VERSION,buffer,count,events,limits,\
+                                                    readConfig"
+                                         .to_owned())]),
+                   result.to_value());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn accessors() {
+        let _ = env_logger::init();
+        let level = rc::Rc::new(cell::Cell::new(1.0));
+        let ctx = Context::new();
+        let (get, set) = (level.clone(), level.clone());
+        ctx.register_module("config", |m| {
+            m.getter("pid", || Ok(Value::Number(42.0)))
+                .accessor("level",
+                          move || Ok(Value::Number(get.get())),
+                          move |value| match value {
+                              Value::Number(n) if n >= 0.0 => {
+                                  set.set(n);
+                                  Ok(())
+                              }
+                              _ => Err(JsError::new(JsErrorKind::Range, "invalid level").into()),
+                          });
+        });
+        let result = ctx.eval_string(r"
+          function error(f) { try { f(); } catch (e) { return e.name; } }
+          config.level = config.level + 2;
+          config.pid = 1;
+          [config.level, config.pid, error(function () { config.level = -1; }),
+           error(function () { 'use strict'; config.pid = 1; }), Object.keys(config).join()]
+        ")
+            .unwrap()
+            .to_value();
+        assert_eq!(Value::Array(vec![Value::Number(3.0),
+                                     Value::Number(42.0),
+                                     Value::String("RangeError".to_owned()),
+                                     Value::String("TypeError".to_owned()),
+                                     Value::String("pid,level".to_owned())]),
+                   result);
+        assert_eq!(3.0, level.get());
+
+        let object = ctx.eval_string("var frozen = Object.freeze({}), open = {}; open")
+            .unwrap()
+            .into_object()
+            .unwrap();
+        object.define_getter("size", || Ok(Value::Number(2.0))).unwrap();
+        assert!(object.define_getter("size", || Ok(Value::Number(3.0))).is_err());
+        let frozen = ctx.eval_string("frozen").unwrap().into_object().unwrap();
+        assert!(frozen.define_accessor("size", || Ok(Value::Null), |_| Ok(())).is_err());
+        assert_eq!(Value::String("2 undefined".to_owned()),
+                   ctx.eval_string("open.size + ' ' + frozen.size").unwrap().to_value());
+        ctx.assert_clean();
+    }
+}
@@ -0,0 +1,224 @@
+//! The `host:path` module, which manipulates the paths of the `host:fs` sandbox, see
+//! `ContextBuilder::with_filesystem`.
+//!
+//! Paths use `/` as separator and are relative to the root of the sandbox, like in `host:fs`.  The
+//! functions work on the strings alone, without touching the file system, but they refuse paths
+//! that would climb above the root just like `host:fs` does:
+//!
+//! * `join(...parts)` joins the parts with `/` and normalizes the result.
+//! * `normalize(path)` removes empty and `.` components and resolves `..`, keeping a leading `/`.
+//! * `relative(from, to)` returns the path that leads from one directory to another.
+//! * `extname(path)` returns the extension of the last component, including the dot, or `''`.
+//!
+//! Failures throw an `Error` with a `code` of `EACCES` (outside of the sandbox) or `EINVAL`.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! let sandbox = duk::filesystem::Sandbox::new("/var/lib/host/plugins/weather");
+//! let ctx = duk::Context::builder().with_filesystem(sandbox).build();
+//! ctx.eval_string("var path = require('host:path'); \
+//!                  require('host:fs').readFile(path.join('cache', 'today.json'))")
+//!     .unwrap();
+//! ```
+
+use std::os;
+
+use duktape_sys;
+
+use filesystem::{respond, FsError};
+use host_modules;
+use strings;
+
+/// Builds the exports of the module on top of the native functions.
+const SETUP: &[u8] = b"(function (join, normalize, relative, extname) {
+  function unwrap(result) {
+    if (result[0] !== null) {
+      var error = new Error(result[1]);
+      error.code = result[0];
+      throw error;
+    }
+    return result[1];
+  }
+  return {
+    sep: '/',
+    join: function () {
+      var parts = [];
+      for (var i = 0; i < arguments.length; i++) {
+        parts.push(String(arguments[i]));
+      }
+      return unwrap(join(parts));
+    },
+    normalize: function (path) {
+      return unwrap(normalize(String(path)));
+    },
+    relative: function (from, to) {
+      return unwrap(relative(String(from), String(to)));
+    },
+    extname: function (path) {
+      return extname(String(path));
+    }
+  };
+})";
+
+/// Splits a path into its components, resolving `.` and `..`, and refuses paths that climb above
+/// the root or can't be mapped to a host path.
+pub(crate) fn components(path: &str) -> Result<Vec<&str>, FsError> {
+    if path.contains('\0') || path.contains('\\') {
+        return Err(FsError::new("EINVAL", path, "invalid path"));
+    }
+    let mut parts = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                if parts.pop().is_none() {
+                    return Err(FsError::new("EACCES", path, "outside of the sandbox"));
+                }
+            }
+            part => parts.push(part),
+        }
+    }
+    Ok(parts)
+}
+
+fn normalize(path: &str) -> Result<String, FsError> {
+    let parts = components(path)?;
+    Ok(if path.starts_with('/') {
+        format!("/{}", parts.join("/"))
+    } else if parts.is_empty() {
+        ".".to_owned()
+    } else {
+        parts.join("/")
+    })
+}
+
+fn join(parts: &[String]) -> Result<String, FsError> {
+    let parts = parts.iter().filter(|p| !p.is_empty()).map(|p| p.as_str()).collect::<Vec<_>>();
+    normalize(&parts.join("/"))
+}
+
+fn relative(from: &str, to: &str) -> Result<String, FsError> {
+    let from = components(from)?;
+    let to = components(to)?;
+    let common = from.iter().zip(&to).take_while(|&(a, b)| a == b).count();
+    let mut parts = vec![".."; from.len() - common];
+    parts.extend_from_slice(&to[common..]);
+    Ok(parts.join("/"))
+}
+
+fn extname(path: &str) -> &str {
+    let name = path.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+    match name.rfind('.') {
+        Some(dot) if dot > 0 && name != ".." => &name[dot..],
+        _ => "",
+    }
+}
+
+/// Registers the `host:path` module.
+pub(crate) unsafe fn setup(ctx: *mut duktape_sys::duk_context) {
+    use duktape_sys::*;
+
+    let ret = duk_peval_lstring(ctx, SETUP.as_ptr() as *const os::raw::c_char, SETUP.len());
+    assert_eq!(0, ret, "failed to compile host:path");
+    duk_push_c_function(ctx, Some(join_native), 1);
+    duk_push_c_function(ctx, Some(normalize_native), 1);
+    duk_push_c_function(ctx, Some(relative_native), 2);
+    duk_push_c_function(ctx, Some(extname_native), 1);
+    let ret = duk_pcall(ctx, 4);
+    assert_eq!(0, ret, "failed to set up host:path");
+    host_modules::register(ctx, b"host:path\0");
+}
+
+/// `join(parts)`
+unsafe extern "C" fn join_native(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    use duktape_sys::*;
+
+    let mut parts = Vec::new();
+    for i in 0..duk_get_length(ctx, 0) {
+        duk_get_prop_index(ctx, 0, i as duk_uarridx_t);
+        parts.push(strings::get(ctx, -1));
+        duk_pop(ctx);
+    }
+    respond(ctx, join(&parts), |path| strings::push(ctx, &path));
+    1
+}
+
+/// `normalize(path)`
+unsafe extern "C" fn normalize_native(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    let result = normalize(&strings::get(ctx, 0));
+    respond(ctx, result, |path| strings::push(ctx, &path));
+    1
+}
+
+/// `relative(from, to)`
+unsafe extern "C" fn relative_native(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    let result = relative(&strings::get(ctx, 0), &strings::get(ctx, 1));
+    respond(ctx, result, |path| strings::push(ctx, &path));
+    1
+}
+
+/// `extname(path)`
+unsafe extern "C" fn extname_native(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    strings::push(ctx, extname(&strings::get(ctx, 0)));
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::env;
+
+    use filesystem::Sandbox;
+    use {Context, Value};
+
+    #[test]
+    #[cfg_attr(target_os = "wasi", ignore = "needs a temporary directory")]
+    fn host_path() {
+        let _ = env_logger::init();
+        let ctx = Context::builder().with_filesystem(Sandbox::new(env::temp_dir())).build();
+        ctx.eval_string("var path = require('host:path');").unwrap();
+
+        let check = |script: &str, expected: &str| {
+            assert_eq!(Value::String(expected.to_owned()),
+                       ctx.eval_string(script).unwrap().to_value(),
+                       "{}",
+                       script);
+        };
+        check("path.join('cache', 'a/../b', '', './c.json')", "cache/b/c.json");
+        check("path.join('/', 'x') + ' ' + path.join() + ' ' + path.join('a', '..')", "/x . .");
+        check("path.normalize('//a/./b//c/..')", "/a/b");
+        check("path.relative('a/b/c', 'a/d') + ' ' + path.relative('/a', 'a/x/y')", "../../d x/y");
+        check("path.relative('a', 'a')", "");
+        check("[path.extname('a/b.tar.gz'), path.extname('.profile'), path.extname('x.d/y'), \
+                path.extname('notes.'), path.extname('a.txt/')].join()",
+              ".gz,,,.,.txt");
+        ctx.assert_clean();
+    }
+
+    #[test]
+    #[cfg_attr(target_os = "wasi", ignore = "needs a temporary directory")]
+    fn host_path_escapes() {
+        let _ = env_logger::init();
+        let ctx = Context::builder().with_filesystem(Sandbox::new(env::temp_dir())).build();
+        let result = ctx.eval_string(r"
+          var path = require('host:path');
+          function code(f) {
+            try { f(); } catch (e) { return e.code + ' ' + e.message; }
+          }
+          [code(function () { path.join('a', '../../etc/passwd'); }),
+           code(function () { path.normalize('/..'); }),
+           code(function () { path.relative('a', '../b'); }),
+           code(function () { path.normalize('a\\b'); })]
+        ")
+            .unwrap();
+        let expected = ["EACCES a/../../etc/passwd: outside of the sandbox",
+                        "EACCES /..: outside of the sandbox",
+                        "EACCES ../b: outside of the sandbox",
+                        "EINVAL a\\b: invalid path"];
+        assert_eq!(Value::Array(expected.iter().map(|e| Value::String(e.to_string())).collect()),
+                   result.to_value());
+        ctx.assert_clean();
+    }
+}
@@ -0,0 +1,165 @@
+//! A `performance` global for scripts that profile themselves like they would in a browser.
+//!
+//! `performance.now()` returns the milliseconds since the context was created, with sub-millisecond
+//! resolution, from a monotonic clock that doesn't jump when the system time changes.
+//! `performance.timeOrigin` is the wall clock time of creation, in milliseconds since the epoch.
+//! User timing is supported too: `mark(name)` records a point in time and `measure(name, start,
+//! end)` the duration between two marks (or from the time origin and until now, if they are
+//! omitted), and the entries can be looked up with `getEntries()`, `getEntriesByName(name, type)`
+//! and `getEntriesByType(type)`, and dropped with `clearMarks(name)` and `clearMeasures(name)`.
+
+use std::os;
+use std::time;
+
+use duktape_sys;
+
+use nul_str;
+use HeapData;
+
+/// Builds the `performance` object on top of the native clock.
+const SETUP: &[u8] = br#"(function (now, timeOrigin) {
+  var entries = [];
+
+  function entry(name, entryType, startTime, duration) {
+    var e = {name: String(name), entryType: entryType, startTime: startTime, duration: duration};
+    entries.push(e);
+    return e;
+  }
+  function timeOf(mark) {
+    for (var i = entries.length - 1; i >= 0; i--) {
+      if (entries[i].entryType === 'mark' && entries[i].name === mark) {
+        return entries[i].startTime;
+      }
+    }
+    throw new SyntaxError("the mark '" + mark + "' does not exist");
+  }
+  function clear(entryType, name) {
+    entries = entries.filter(function (e) {
+      return e.entryType !== entryType || (name !== undefined && e.name !== String(name));
+    });
+  }
+  function byStartTime(a, b) {
+    return a.startTime - b.startTime;
+  }
+
+  return {
+    timeOrigin: timeOrigin,
+    now: now,
+    mark: function (name) {
+      return entry(name, 'mark', now(), 0);
+    },
+    measure: function (name, startMark, endMark) {
+      var start = startMark === undefined ? 0 : timeOf(String(startMark));
+      var end = endMark === undefined ? now() : timeOf(String(endMark));
+      return entry(name, 'measure', start, end - start);
+    },
+    getEntries: function () {
+      return entries.slice().sort(byStartTime);
+    },
+    getEntriesByName: function (name, entryType) {
+      return entries.filter(function (e) {
+        return e.name === String(name) && (entryType === undefined || e.entryType === entryType);
+      }).sort(byStartTime);
+    },
+    getEntriesByType: function (entryType) {
+      return entries.filter(function (e) {
+        return e.entryType === entryType;
+      }).sort(byStartTime);
+    },
+    clearMarks: function (name) {
+      clear('mark', name);
+    },
+    clearMeasures: function (name) {
+      clear('measure', name);
+    },
+    toJSON: function () {
+      return {timeOrigin: timeOrigin};
+    }
+  };
+})"#;
+
+/// Defines the global `performance` object.
+pub(crate) unsafe fn setup(ctx: *mut duktape_sys::duk_context) {
+    use duktape_sys::*;
+
+    let time_origin = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as f64 * 1000.0 + d.subsec_nanos() as f64 / 1_000_000.0)
+        .unwrap_or(0.0);
+
+    let ret = duk_peval_lstring(ctx, SETUP.as_ptr() as *const os::raw::c_char, SETUP.len());
+    assert_eq!(0, ret, "failed to compile performance");
+    duk_push_c_function(ctx, Some(now), 0);
+    duk_push_number(ctx, time_origin);
+    let ret = duk_pcall(ctx, 2);
+    assert_eq!(0, ret, "failed to set up performance");
+    duk_put_global_string(ctx, nul_str(b"performance\0"));
+}
+
+/// `now()`
+unsafe extern "C" fn now(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    let mut funcs = duktape_sys::duk_memory_functions::default();
+    duktape_sys::duk_get_memory_functions(ctx, &mut funcs);
+    let elapsed = (*(funcs.udata as *mut HeapData)).time_origin.elapsed();
+    duktape_sys::duk_push_number(ctx,
+                                 elapsed.as_secs() as f64 * 1000.0 +
+                                 elapsed.subsec_nanos() as f64 / 1_000_000.0);
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::thread;
+    use std::time;
+
+    use {Context, Value};
+
+    #[test]
+    fn performance_now() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let first = ctx.eval_string("performance.now()").unwrap().to_value();
+        thread::sleep(time::Duration::from_millis(5));
+        let second = ctx.eval_string("performance.now()").unwrap().to_value();
+        match (first, second) {
+            (Value::Number(first), Value::Number(second)) => {
+                assert!(first >= 0.0 && second - first >= 5.0, "{} {}", first, second)
+            }
+            other => panic!("unexpected values {:?}", other),
+        }
+        assert_eq!(Value::Boolean(true),
+                   ctx.eval_string("Math.abs(performance.timeOrigin + performance.now() - \
+                                    Date.now()) < 1000")
+                       .unwrap()
+                       .to_value());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn user_timing() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let result = ctx.eval_string(r"
+          performance.mark('start');
+          for (var i = 0, s = 0; i < 1000; i++) { s += i; }
+          performance.mark('end');
+          var m = performance.measure('loop', 'start', 'end');
+          var all = performance.measure('all');
+          performance.clearMarks('start');
+          [m.duration >= 0, m.startTime === performance.getEntriesByName('loop')[0].startTime,
+           all.startTime, performance.getEntries().map(function (e) { return e.name; }).join(),
+           performance.getEntriesByType('mark').length]
+        ")
+            .unwrap();
+        assert_eq!(Value::Array(vec![Value::Boolean(true),
+                                     Value::Boolean(true),
+                                     Value::Number(0.0),
+                                     Value::String("all,loop,end".to_owned()),
+                                     Value::Number(1.0)]),
+                   result.to_value());
+        assert!(ctx.eval_string("performance.measure('x', 'start')").is_err());
+        ctx.assert_clean();
+    }
+}
@@ -0,0 +1,515 @@
+//! Loading plugins that declare the capabilities they need, see `PluginLoader`.
+//!
+//! A plugin is a directory with a `plugin.json` manifest, which names the plugin, its main script
+//! and the capabilities that it needs:
+//!
+//! ```text
+//! {"name": "weather", "main": "index.js", "capabilities": ["net", "timers"]}
+//! ```
+//!
+//! `main` defaults to `index.js`, and `capabilities` to none.  The capabilities are:
+//!
+//!   * `fs`: the `host:fs` and `host:path` modules (see `ContextBuilder::with_filesystem`).
+//!   * `net`: the `fetch` global and the `host:ws` module (see `ContextBuilder::with_fetch` and
+//!     `ContextBuilder::with_websockets`).
+//!   * `timers`: the `setTimeout` family of globals (see `ContextBuilder::with_timers`).
+//!   * `exec`: the `host:exec` module (see `ContextBuilder::with_exec`).
+//!
+//! The host grants a capability by giving the loader a function that sets it up on the builder
+//! of a plugin's context.  Every plugin gets a context of its own, which only has the
+//! capabilities that its manifest declares; loading fails with an `ErrorKind::Policy` error if it
+//! declares one that the host doesn't grant.  The globals and modules of the other capabilities
+//! are replaced by functions that throw a `PolicyError` (with the `plugin` and `capability`
+//! properties), so a plugin that uses a capability that it didn't declare fails with a clear
+//! message instead of a `ReferenceError`.  When such an error reaches the host, it is an
+//! `ErrorKind::Policy` error as well, which describes the violation in a way that can be shown to
+//! users.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use duk::plugins::{Capability, PluginLoader};
+//!
+//! let loader = PluginLoader::new()
+//!     .with_grant(Capability::Timers, Box::new(|_, builder| builder.with_timers(16)))
+//!     .with_grant(Capability::Fs,
+//!                 Box::new(|manifest, builder| {
+//!                     let root = format!("data/{}", manifest.name);
+//!                     builder.with_filesystem(duk::filesystem::Sandbox::new(root))
+//!                 }));
+//! match loader.load("plugins/weather") {
+//!     Ok(plugin) => plugin.context.run_event_loop().unwrap(),
+//!     Err(duk::Error(duk::ErrorKind::Policy(violation), _)) => println!("{}", violation),
+//!     Err(e) => panic!("failed to load the plugin: {}", e),
+//! }
+//! ```
+
+use std::collections;
+use std::fmt;
+use std::fs;
+use std::os;
+use std::path;
+use std::rc;
+
+use duktape_sys;
+
+use host_modules;
+use strings;
+use userdata;
+use {ChainErr, Context, ContextBuilder, ErrorKind, Result, Value};
+
+/// Builds a function that throws a `PolicyError` for each undeclared capability, which `record`
+/// remembers.
+const SETUP: &[u8] = b"(function (plugin, record) {
+  return function (capability) {
+    return function () {
+      var error = new Error('plugin ' + plugin + ' did not declare the ' + capability +
+                            ' capability');
+      error.name = 'PolicyError';
+      error.plugin = plugin;
+      error.capability = capability;
+      record(error, plugin, capability);
+      throw error;
+    };
+  };
+})";
+
+/// The name of the manifest file in the directory of a plugin.
+pub const MANIFEST_FILE: &str = "plugin.json";
+
+/// Something that a plugin can only use if its manifest declares it.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Capability {
+    /// The `host:fs` and `host:path` modules.
+    Fs,
+    /// The `fetch` global and the `host:ws` module.
+    Net,
+    /// The `setTimeout`, `setInterval`, `clearTimeout` and `clearInterval` globals.
+    Timers,
+    /// The `host:exec` module.
+    Exec,
+}
+
+/// Every capability.
+pub const CAPABILITIES: [Capability; 4] =
+    [Capability::Fs, Capability::Net, Capability::Timers, Capability::Exec];
+
+/// A function that sets up a capability on the builder of a plugin's context.
+pub type Grant = Box<dyn Fn(&PluginManifest, ContextBuilder) -> ContextBuilder>;
+
+/// The manifest of a plugin, from its `plugin.json`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PluginManifest {
+    /// The name of the plugin.
+    pub name: String,
+    /// The path of the main script, relative to the directory of the plugin.
+    pub main: String,
+    /// The capabilities that the plugin needs.
+    pub capabilities: collections::BTreeSet<Capability>,
+}
+
+/// How a plugin violated the policy.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ViolationKind {
+    /// The plugin used a capability that its manifest doesn't declare.
+    Undeclared,
+    /// The manifest declares a capability that the host doesn't grant.
+    NotGranted,
+}
+
+/// A violation of the capability policy, see `ErrorKind::Policy`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Violation {
+    /// The name of the plugin.
+    pub plugin: String,
+    /// The capability involved.
+    pub capability: Capability,
+    /// How the plugin violated the policy.
+    pub kind: ViolationKind,
+}
+
+/// The userdata of the errors that the policy throws.  Only this module creates it, so scripts
+/// can't forge policy errors, whatever properties they give their errors.
+struct Denied(Violation);
+
+/// Loads plugins into contexts that only have the capabilities that the plugins declare.
+pub struct PluginLoader {
+    builder: Box<dyn Fn(&PluginManifest) -> ContextBuilder>,
+    grants: collections::BTreeMap<Capability, Grant>,
+}
+
+/// A plugin that has been loaded, with its own context.
+pub struct Plugin {
+    /// The manifest of the plugin.
+    pub manifest: PluginManifest,
+    /// The context that the main script of the plugin ran in.
+    pub context: Context,
+}
+
+impl Capability {
+    /// The name of the capability in manifests, like `fs`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Capability::Fs => "fs",
+            Capability::Net => "net",
+            Capability::Timers => "timers",
+            Capability::Exec => "exec",
+        }
+    }
+
+    /// The capability with the specified name, if there is one.
+    pub fn from_name(name: &str) -> Option<Capability> {
+        CAPABILITIES.iter().cloned().find(|capability| capability.name() == name)
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl PluginManifest {
+    /// Parses the JSON of a manifest.  JSON that doesn't parse fails with `ErrorKind::Js`, and
+    /// JSON that isn't a valid manifest with `ErrorKind::Conversion`.
+    pub fn parse(json: &str) -> Result<PluginManifest> {
+        let ctx = Context::new();
+        let json = Value::String(json.to_owned());
+        let mut fields = match ctx.global_object().get("JSON")?.call_method("parse", &[&json])?
+            .to_value() {
+            Value::Object(fields) => fields,
+            _ => return Err(invalid("not an object")),
+        };
+        let name = match fields.remove("name") {
+            Some(Value::String(name)) => name,
+            _ => return Err(invalid("the name is missing")),
+        };
+        let main = match fields.remove("main") {
+            Some(Value::String(main)) => main,
+            None => "index.js".to_owned(),
+            _ => return Err(invalid("the main script isn't a string")),
+        };
+        // The main script must be in the directory of the plugin
+        let inside = path::Path::new(&main).components().all(|component| {
+            matches!(component, path::Component::Normal(_) | path::Component::CurDir)
+        });
+        if !inside {
+            return Err(invalid("the main script isn't a relative path within the plugin"));
+        }
+        let capabilities = match fields.remove("capabilities") {
+            Some(Value::Array(names)) => names,
+            None => Vec::new(),
+            _ => return Err(invalid("the capabilities aren't an array")),
+        };
+        let capabilities = capabilities.iter()
+            .map(|name| match *name {
+                Value::String(ref name) => {
+                    Capability::from_name(name)
+                        .ok_or_else(|| invalid(&format!("unknown capability {}", name)))
+                }
+                _ => Err(invalid("a capability isn't a string")),
+            })
+            .collect::<Result<_>>()?;
+        Ok(PluginManifest {
+            name,
+            main,
+            capabilities,
+        })
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ViolationKind::Undeclared => {
+                write!(f,
+                       "plugin {} used the {} capability without declaring it",
+                       self.plugin,
+                       self.capability)
+            }
+            ViolationKind::NotGranted => {
+                write!(f,
+                       "plugin {} needs the {} capability, which isn't granted",
+                       self.plugin,
+                       self.capability)
+            }
+        }
+    }
+}
+
+impl Default for PluginLoader {
+    fn default() -> PluginLoader {
+        PluginLoader {
+            builder: Box::new(|_| ContextBuilder::default()),
+            grants: collections::BTreeMap::new(),
+        }
+    }
+}
+
+impl PluginLoader {
+    /// Creates a loader that grants no capabilities.
+    pub fn new() -> PluginLoader {
+        PluginLoader::default()
+    }
+
+    /// Uses the function to start the builder of each plugin's context, for the settings that
+    /// every plugin gets, like a verifier or a memory limit.  The capabilities that the builder
+    /// sets up are removed, so that plugins only get the ones that they declare.
+    pub fn with_builder(mut self, builder: Box<dyn Fn(&PluginManifest) -> ContextBuilder>) -> Self {
+        self.builder = builder;
+        self
+    }
+
+    /// Grants a capability to the plugins that declare it, with a function that sets it up on the
+    /// builder of a plugin's context.
+    pub fn with_grant(mut self, capability: Capability, grant: Grant) -> Self {
+        self.grants.insert(capability, grant);
+        self
+    }
+
+    /// Reads the manifest of the plugin in the directory, or fails with `ErrorKind::Load`.
+    pub fn read_manifest<P>(&self, dir: P) -> Result<PluginManifest>
+        where P: AsRef<path::Path>
+    {
+        let path = dir.as_ref().join(MANIFEST_FILE);
+        fs::read_to_string(&path)
+            .map_err(From::from)
+            .and_then(|json| PluginManifest::parse(&json))
+            .chain_err(|| ErrorKind::Load(path.display().to_string()))
+    }
+
+    /// Loads the plugin in the directory, by evaluating its main script (like
+    /// `Context::eval_file`) in a context with the capabilities that its manifest declares.
+    ///
+    /// Fails with `ErrorKind::Policy` if the manifest declares a capability that isn't granted,
+    /// and with `ErrorKind::Load` if the manifest can't be read or is invalid, like one whose
+    /// main script is outside of the directory.
+    pub fn load<P>(&self, dir: P) -> Result<Plugin>
+        where P: AsRef<path::Path>
+    {
+        let manifest = self.read_manifest(dir.as_ref())?;
+        let mut builder = without_capabilities((self.builder)(&manifest));
+        for &capability in &manifest.capabilities {
+            match self.grants.get(&capability) {
+                Some(grant) => builder = grant(&manifest, builder),
+                None => {
+                    return Err(ErrorKind::Policy(Violation {
+                            plugin: manifest.name.clone(),
+                            capability,
+                            kind: ViolationKind::NotGranted,
+                        })
+                        .into())
+                }
+            }
+        }
+        // Denied host modules need `require`, like the host modules themselves
+        if builder.module_resolver.is_none() && builder.module_loader.is_none() {
+            builder = builder.with_module_resolver(Box::new(|id, _| id))
+                .with_module_loader(Box::new(|_| None));
+        }
+        let context = builder.try_build()?;
+        unsafe { deny_undeclared(context.raw, &manifest) };
+        context.load_file(dir.as_ref().join(&manifest.main))?;
+        Ok(Plugin {
+            manifest,
+            context,
+        })
+    }
+}
+
+fn invalid(message: &str) -> ::Error {
+    ErrorKind::Conversion(format!("invalid plugin manifest: {}", message)).into()
+}
+
+/// Removes the capabilities that a builder sets up.
+fn without_capabilities(mut builder: ContextBuilder) -> ContextBuilder {
+    builder.filesystem = None;
+    builder.max_timers = None;
+    #[cfg(feature = "fetch")]
+    {
+        builder.fetcher = None;
+    }
+    #[cfg(feature = "ws")]
+    {
+        builder.ws_transport = None;
+    }
+    #[cfg(feature = "exec")]
+    {
+        builder.exec = None;
+    }
+    builder
+}
+
+/// Replaces the globals and host modules of the capabilities that the plugin didn't declare with
+/// functions that throw a `PolicyError`.
+unsafe fn deny_undeclared(ctx: *mut duktape_sys::duk_context, manifest: &PluginManifest) {
+    use duktape_sys::*;
+
+    let ret = duk_peval_lstring(ctx, SETUP.as_ptr() as *const os::raw::c_char, SETUP.len());
+    assert_eq!(0, ret, "failed to compile the capability policy");
+    strings::push(ctx, &manifest.name);
+    duk_push_c_function(ctx, Some(record), 3);
+    let ret = duk_pcall(ctx, 2);
+    assert_eq!(0, ret, "failed to set up the capability policy");
+    for capability in CAPABILITIES.iter().filter(|c| !manifest.capabilities.contains(c)) {
+        let (globals, modules): (&[&str], &[&[u8]]) = match *capability {
+            Capability::Fs => (&[], &[b"host:fs\0", b"host:path\0"]),
+            Capability::Net => (&["fetch"], &[b"host:ws\0"]),
+            Capability::Timers => {
+                (&["setTimeout", "setInterval", "clearTimeout", "clearInterval"], &[])
+            }
+            Capability::Exec => (&[], &[b"host:exec\0"]),
+        };
+        duk_dup_top(ctx);
+        strings::push(ctx, capability.name());
+        duk_call(ctx, 1);
+        for name in globals {
+            duk_push_global_object(ctx);
+            strings::push(ctx, name);
+            duk_dup(ctx, -3);
+            duk_put_prop(ctx, -3);
+            duk_pop(ctx);
+        }
+        for id in modules {
+            duk_dup_top(ctx);
+            host_modules::deny(ctx, id);
+        }
+        duk_pop(ctx);
+    }
+    duk_pop(ctx);
+}
+
+/// `record(error, plugin, capability)`, remembers that the policy throws the error.
+unsafe extern "C" fn record(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    if let Some(capability) = Capability::from_name(&strings::get(ctx, 2)) {
+        let violation = Violation {
+            plugin: strings::get(ctx, 1),
+            capability,
+            kind: ViolationKind::Undeclared,
+        };
+        // A new error can always have userdata
+        userdata::set(ctx, 0, rc::Rc::new(Denied(violation)));
+    }
+    0
+}
+
+/// The policy violation that the error at the index describes, if it is one that the policy
+/// threw.
+pub(crate) unsafe fn violation(ctx: *mut duktape_sys::duk_context,
+                               index: duktape_sys::duk_idx_t)
+                               -> Option<Violation> {
+    userdata::get::<Denied>(ctx, index).map(|denied| denied.0.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::fs;
+
+    use super::*;
+    use filesystem;
+    use testing::ScratchDir;
+
+    fn write_plugin(dir: &path::Path, manifest: &str, main: &str) {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join(MANIFEST_FILE), manifest).unwrap();
+        fs::write(dir.join("index.js"), main).unwrap();
+    }
+
+    fn assert_violation(result: Result<::Reference>, capability: Capability, kind: ViolationKind) {
+        match *result.unwrap_err().kind() {
+            ErrorKind::Policy(ref violation) => {
+                assert_eq!(Violation {
+                               plugin: "clock".to_owned(),
+                               capability,
+                               kind,
+                           },
+                           *violation)
+            }
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+
+    #[test]
+    #[cfg_attr(target_os = "wasi", ignore = "needs a temporary directory")]
+    fn capabilities() {
+        let _ = env_logger::init();
+        let dir = ScratchDir::new("plugins");
+        let data = dir.join("data");
+        fs::create_dir_all(&data).unwrap();
+        let loader = PluginLoader::new()
+            .with_builder(Box::new(|_| Context::builder().with_timers(1)))
+            .with_grant(Capability::Fs,
+                        Box::new(move |_, builder| {
+                            builder.with_filesystem(filesystem::Sandbox::new(data.clone()))
+                        }));
+
+        let clock = dir.join("clock");
+        write_plugin(&clock,
+                     r#"{"name": "clock", "capabilities": ["fs"]}"#,
+                     "var fs = require('host:fs');
+                      function later() { setTimeout(function () {}, 0); }
+                      function run() { require('host:exec'); }
+                      function caught() {
+                        try { fetch('https://example.com'); } catch (e) {
+                          return [e.name, e.plugin, e.capability].join();
+                        }
+                      }
+                      function forged() {
+                        var e = new Error('forged');
+                        var k = String(Duktape.dec('hex', 'ff706f6c696379'));
+                        e.name = 'PolicyError';
+                        e.plugin = 'clock';
+                        e.capability = 'fs';
+                        e[k] = true;
+                        throw e;
+                      }
+                      function rethrown() {
+                        try { setTimeout(function () {}, 0); } catch (e) { throw e; }
+                      }");
+        let plugin = loader.load(&clock).unwrap();
+        let ctx = &plugin.context;
+        assert_eq!("clock", plugin.manifest.name);
+        // The builder's timers were removed, because the plugin didn't declare them
+        assert_violation(ctx.call("later", ()), Capability::Timers, ViolationKind::Undeclared);
+        assert_violation(ctx.call("run", ()), Capability::Exec, ViolationKind::Undeclared);
+        assert_eq!(Value::String("PolicyError,clock,net".to_owned()),
+                   ctx.call("caught", ()).unwrap().to_value());
+        match *ctx.call("forged", ()).unwrap_err().kind() {
+            ErrorKind::Js(ref error) => assert_eq!("forged", error.message),
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+        assert_violation(ctx.call("rethrown", ()), Capability::Timers, ViolationKind::Undeclared);
+        ctx.assert_clean();
+
+        write_plugin(&clock, r#"{"name": "clock", "capabilities": ["timers"]}"#, "");
+        match *loader.load(&clock).err().unwrap().kind() {
+            ErrorKind::Policy(ref violation) => {
+                assert_eq!("plugin clock needs the timers capability, which isn't granted",
+                           violation.to_string())
+            }
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn manifests() {
+        let _ = env_logger::init();
+        let manifest = PluginManifest::parse(r#"{"name": "clock", "capabilities": ["timers"]}"#)
+            .unwrap();
+        assert_eq!("index.js", manifest.main);
+        assert_eq!(vec![Capability::Timers], manifest.capabilities.into_iter().collect::<Vec<_>>());
+        let invalid = [r#"{"capabilities": []}"#,
+                       r#"{"name": "clock", "capabilities": ["gpu"]}"#,
+                       r#"{"name": "clock", "main": 1}"#,
+                       r#"{"name": "clock", "main": "../index.js"}"#,
+                       r#"{"name": "clock", "main": "lib/../../index.js"}"#,
+                       r#"{"name": "clock", "main": "/etc/passwd"}"#,
+                       "[]",
+                       "{"];
+        for json in &invalid {
+            assert!(PluginManifest::parse(json).is_err(), "{} was accepted", json);
+        }
+    }
+}
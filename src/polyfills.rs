@@ -0,0 +1,802 @@
+//! Polyfills for the ES2015+ built-ins that this version of Duktape lacks, so that typical library
+//! code runs unchanged, see `ContextBuilder::with_polyfills`.
+//!
+//! Each `Polyfill` is a curated group of definitions, which only fills in what is missing:
+//!
+//! * `Object`: `Object.assign`, `entries`, `values`, `fromEntries`, `is` and
+//!   `getOwnPropertyDescriptors`.
+//! * `Array`: `Array.from` (for array-likes and strings), `Array.of`, and `find`, `findIndex`,
+//!   `includes`, `fill`, `flat` and `flatMap` on arrays.
+//! * `String`: `String.fromCodePoint`, and `startsWith`, `endsWith`, `includes`, `repeat`,
+//!   `padStart`, `padEnd`, `trimStart`, `trimEnd` and `codePointAt` on strings.
+//! * `Number`: the `Number` predicates, constants and parse functions, and `Math.trunc`, `sign`,
+//!   `cbrt`, `hypot`, `log2`, `log10`, `log1p`, `expm1`, `clz32`, `imul` and `fround`.
+//! * `Promise`: `Promise` with `then`, `catch` and `finally`, `Promise.resolve`, `reject`,
+//!   `all`, `allSettled` and `race`, and `queueMicrotask`.
+//!
+//! Promise reactions are microtasks: they run when the host calls `Context::run_microtasks`, or
+//! pumps the event loop, which runs them after every callback.
+//!
+//! Each polyfill is compiled once per process, and contexts load the cached bytecode, which keeps
+//! building contexts cheap.
+//!
+//! # Examples
+//!
+//! ```
+//! use duk::polyfills::{Polyfill, PolyfillSet};
+//!
+//! let ctx = duk::Context::builder()
+//!     .with_polyfills(PolyfillSet::all().without(Polyfill::Promise))
+//!     .build();
+//! let found = ctx.eval_string("[1, 2, 3].find(function (n) { return n > 1; })").unwrap();
+//! assert_eq!(duk::Value::Number(2.0), found.to_value());
+//! ```
+
+use std::collections;
+use std::os;
+use std::ptr;
+use std::slice;
+use std::sync;
+
+use duktape_sys;
+
+use nul_str;
+
+/// The key of the heap stash entry with the function that runs the pending microtasks.
+const STASH_KEY: &[u8] = b"microtasks\0";
+
+const OBJECT: &[u8] = br#"(function () {
+  function define(obj, name, value) {
+    if (obj[name] === undefined) {
+      Object.defineProperty(obj, name, {value: value, writable: true, configurable: true});
+    }
+  }
+  function toObject(value) {
+    if (value === null || value === undefined) {
+      throw new TypeError('cannot convert undefined or null to object');
+    }
+    return Object(value);
+  }
+
+  define(Object, 'assign', function assign(target) {
+    var to = toObject(target);
+    for (var i = 1; i < arguments.length; i++) {
+      var source = arguments[i];
+      if (source !== null && source !== undefined) {
+        source = Object(source);
+        Object.keys(source).forEach(function (key) { to[key] = source[key]; });
+      }
+    }
+    return to;
+  });
+  define(Object, 'entries', function entries(obj) {
+    obj = toObject(obj);
+    return Object.keys(obj).map(function (key) { return [key, obj[key]]; });
+  });
+  define(Object, 'values', function values(obj) {
+    obj = toObject(obj);
+    return Object.keys(obj).map(function (key) { return obj[key]; });
+  });
+  define(Object, 'fromEntries', function fromEntries(entries) {
+    var obj = {};
+    Array.prototype.forEach.call(toObject(entries), function (entry) {
+      obj[entry[0]] = entry[1];
+    });
+    return obj;
+  });
+  define(Object, 'is', function is(a, b) {
+    if (a === b) {
+      return a !== 0 || 1 / a === 1 / b;
+    }
+    return a !== a && b !== b;
+  });
+  define(Object, 'getOwnPropertyDescriptors', function getOwnPropertyDescriptors(obj) {
+    obj = toObject(obj);
+    var descriptors = {};
+    Object.getOwnPropertyNames(obj).forEach(function (name) {
+      descriptors[name] = Object.getOwnPropertyDescriptor(obj, name);
+    });
+    return descriptors;
+  });
+})();"#;
+
+const ARRAY: &[u8] = br#"(function () {
+  function define(obj, name, value) {
+    if (obj[name] === undefined) {
+      Object.defineProperty(obj, name, {value: value, writable: true, configurable: true});
+    }
+  }
+  function callable(fn) {
+    if (typeof fn !== 'function') {
+      throw new TypeError(fn + ' is not a function');
+    }
+    return fn;
+  }
+  function relative(index, length, fallback) {
+    var n = index === undefined ? fallback : Math.floor(Number(index)) || 0;
+    return n < 0 ? Math.max(length + n, 0) : Math.min(n, length);
+  }
+
+  define(Array, 'from', function from(items, mapFn, thisArg) {
+    if (items === null || items === undefined) {
+      throw new TypeError('Array.from requires an array-like object');
+    }
+    var source = Object(items);
+    var length = source.length >>> 0;
+    var result = new Array(length);
+    for (var i = 0; i < length; i++) {
+      result[i] = mapFn === undefined ? source[i] : callable(mapFn).call(thisArg, source[i], i);
+    }
+    return result;
+  });
+  define(Array, 'of', function of() {
+    return Array.prototype.slice.call(arguments);
+  });
+  define(Array.prototype, 'find', function find(predicate, thisArg) {
+    callable(predicate);
+    for (var i = 0; i < this.length; i++) {
+      if (predicate.call(thisArg, this[i], i, this)) {
+        return this[i];
+      }
+    }
+    return undefined;
+  });
+  define(Array.prototype, 'findIndex', function findIndex(predicate, thisArg) {
+    callable(predicate);
+    for (var i = 0; i < this.length; i++) {
+      if (predicate.call(thisArg, this[i], i, this)) {
+        return i;
+      }
+    }
+    return -1;
+  });
+  define(Array.prototype, 'includes', function includes(search, fromIndex) {
+    for (var i = relative(fromIndex, this.length >>> 0, 0); i < this.length; i++) {
+      if (this[i] === search || (search !== search && this[i] !== this[i])) {
+        return true;
+      }
+    }
+    return false;
+  });
+  define(Array.prototype, 'fill', function fill(value, start, end) {
+    var length = this.length >>> 0;
+    for (var i = relative(start, length, 0); i < relative(end, length, length); i++) {
+      this[i] = value;
+    }
+    return this;
+  });
+  define(Array.prototype, 'flat', function flat(depth) {
+    depth = depth === undefined ? 1 : Math.floor(Number(depth)) || 0;
+    var result = [];
+    (function flatten(array, depth) {
+      for (var i = 0; i < array.length; i++) {
+        if (!(i in array)) {
+          continue;
+        }
+        if (Array.isArray(array[i]) && depth > 0) {
+          flatten(array[i], depth - 1);
+        } else {
+          result.push(array[i]);
+        }
+      }
+    })(this, depth);
+    return result;
+  });
+  define(Array.prototype, 'flatMap', function flatMap(fn, thisArg) {
+    return Array.prototype.map.call(this, callable(fn), thisArg).flat(1);
+  });
+})();"#;
+
+const STRING: &[u8] = br#"(function () {
+  function define(obj, name, value) {
+    if (obj[name] === undefined) {
+      Object.defineProperty(obj, name, {value: value, writable: true, configurable: true});
+    }
+  }
+  function string(value) {
+    if (value === null || value === undefined) {
+      throw new TypeError('String.prototype method called on null or undefined');
+    }
+    return String(value);
+  }
+  function noRegExp(search) {
+    if (search instanceof RegExp) {
+      throw new TypeError('first argument must not be a regular expression');
+    }
+    return String(search);
+  }
+  function pad(s, length, filler) {
+    length = Math.floor(Number(length)) || 0;
+    filler = filler === undefined ? ' ' : String(filler);
+    if (length <= s.length || filler === '') {
+      return '';
+    }
+    var padding = filler.repeat(Math.ceil((length - s.length) / filler.length));
+    return padding.slice(0, length - s.length);
+  }
+
+  define(String, 'fromCodePoint', function fromCodePoint() {
+    var units = [];
+    for (var i = 0; i < arguments.length; i++) {
+      var cp = Number(arguments[i]);
+      if (cp !== Math.floor(cp) || cp < 0 || cp > 0x10ffff) {
+        throw new RangeError('invalid code point ' + arguments[i]);
+      }
+      if (cp > 0xffff) {
+        cp -= 0x10000;
+        units.push(0xd800 + (cp >> 10), 0xdc00 + (cp & 0x3ff));
+      } else {
+        units.push(cp);
+      }
+    }
+    return String.fromCharCode.apply(null, units);
+  });
+  define(String.prototype, 'startsWith', function startsWith(search, position) {
+    var s = string(this);
+    search = noRegExp(search);
+    var start = Math.min(Math.max(Math.floor(Number(position)) || 0, 0), s.length);
+    return s.substr(start, search.length) === search;
+  });
+  define(String.prototype, 'endsWith', function endsWith(search, position) {
+    var s = string(this);
+    search = noRegExp(search);
+    var end = position === undefined ? s.length
+                                     : Math.min(Math.max(Math.floor(Number(position)) || 0, 0), s.length);
+    var start = end - search.length;
+    return start >= 0 && s.slice(start, end) === search;
+  });
+  define(String.prototype, 'includes', function includes(search, position) {
+    return string(this).indexOf(noRegExp(search), position) !== -1;
+  });
+  define(String.prototype, 'repeat', function repeat(count) {
+    var s = string(this);
+    count = Math.floor(Number(count)) || 0;
+    if (count < 0 || count === Infinity) {
+      throw new RangeError('invalid count value');
+    }
+    var result = '';
+    for (; count > 0; count >>= 1, s += s) {
+      if (count & 1) {
+        result += s;
+      }
+    }
+    return result;
+  });
+  define(String.prototype, 'padStart', function padStart(length, filler) {
+    var s = string(this);
+    return pad(s, length, filler) + s;
+  });
+  define(String.prototype, 'padEnd', function padEnd(length, filler) {
+    var s = string(this);
+    return s + pad(s, length, filler);
+  });
+  define(String.prototype, 'trimStart', function trimStart() {
+    return string(this).replace(/^\s+/, '');
+  });
+  define(String.prototype, 'trimEnd', function trimEnd() {
+    return string(this).replace(/\s+$/, '');
+  });
+  define(String.prototype, 'trimLeft', String.prototype.trimStart);
+  define(String.prototype, 'trimRight', String.prototype.trimEnd);
+  define(String.prototype, 'codePointAt', function codePointAt(position) {
+    var s = string(this);
+    var i = Math.floor(Number(position)) || 0;
+    if (i < 0 || i >= s.length) {
+      return undefined;
+    }
+    var first = s.charCodeAt(i);
+    var second = s.charCodeAt(i + 1);
+    if (first >= 0xd800 && first <= 0xdbff && second >= 0xdc00 && second <= 0xdfff) {
+      return (first - 0xd800) * 0x400 + (second - 0xdc00) + 0x10000;
+    }
+    return first;
+  });
+})();"#;
+
+const NUMBER: &[u8] = br#"(function () {
+  function define(obj, name, value) {
+    if (obj[name] === undefined) {
+      Object.defineProperty(obj, name, {value: value, writable: true, configurable: true});
+    }
+  }
+  function constant(obj, name, value) {
+    if (obj[name] === undefined) {
+      Object.defineProperty(obj, name, {value: value});
+    }
+  }
+
+  constant(Number, 'EPSILON', Math.pow(2, -52));
+  constant(Number, 'MAX_SAFE_INTEGER', 9007199254740991);
+  constant(Number, 'MIN_SAFE_INTEGER', -9007199254740991);
+  define(Number, 'isFinite', function isFinite(value) {
+    return typeof value === 'number' && value === value && value !== Infinity &&
+           value !== -Infinity;
+  });
+  define(Number, 'isNaN', function isNaN(value) {
+    return typeof value === 'number' && value !== value;
+  });
+  define(Number, 'isInteger', function isInteger(value) {
+    return Number.isFinite(value) && Math.floor(value) === value;
+  });
+  define(Number, 'isSafeInteger', function isSafeInteger(value) {
+    return Number.isInteger(value) && Math.abs(value) <= Number.MAX_SAFE_INTEGER;
+  });
+  define(Number, 'parseFloat', parseFloat);
+  define(Number, 'parseInt', parseInt);
+
+  define(Math, 'trunc', function trunc(x) {
+    x = Number(x);
+    return x < 0 ? Math.ceil(x) : Math.floor(x);
+  });
+  define(Math, 'sign', function sign(x) {
+    x = Number(x);
+    return x > 0 ? 1 : x < 0 ? -1 : x;
+  });
+  define(Math, 'cbrt', function cbrt(x) {
+    x = Number(x);
+    var y = Math.pow(Math.abs(x), 1 / 3);
+    var rounded = Math.round(y);
+    y = rounded * rounded * rounded === Math.abs(x) ? rounded : y;
+    return x < 0 ? -y : y;
+  });
+  define(Math, 'hypot', function hypot() {
+    var max = 0;
+    var values = [];
+    for (var i = 0; i < arguments.length; i++) {
+      var v = Math.abs(Number(arguments[i]));
+      if (v === Infinity) {
+        return Infinity;
+      }
+      max = Math.max(max, v);
+      values.push(v);
+    }
+    if (max === 0 || max !== max) {
+      return max;
+    }
+    var sum = 0;
+    values.forEach(function (v) { sum += (v / max) * (v / max); });
+    return max * Math.sqrt(sum);
+  });
+  define(Math, 'log2', function log2(x) {
+    return Math.log(x) / Math.LN2;
+  });
+  define(Math, 'log10', function log10(x) {
+    return Math.log(x) / Math.LN10;
+  });
+  define(Math, 'log1p', function log1p(x) {
+    x = Number(x);
+    return Math.abs(x) < 1e-5 ? x - x * x / 2 + x * x * x / 3 : Math.log(1 + x);
+  });
+  define(Math, 'expm1', function expm1(x) {
+    x = Number(x);
+    return Math.abs(x) < 1e-5 ? x + x * x / 2 + x * x * x / 6 : Math.exp(x) - 1;
+  });
+  define(Math, 'clz32', function clz32(x) {
+    x = Number(x) >>> 0;
+    return x === 0 ? 32 : 31 - Math.floor(Math.log(x) / Math.LN2 + 1e-12);
+  });
+  define(Math, 'imul', function imul(a, b) {
+    a = a | 0;
+    b = b | 0;
+    var low = (a & 0xffff) * b;
+    var high = ((a >>> 16) * b) & 0xffff;
+    return (low + (high << 16)) | 0;
+  });
+  define(Math, 'fround', function fround(x) {
+    var f = new Float32Array(1);
+    f[0] = x;
+    return f[0];
+  });
+})();"#;
+
+/// Evaluates to the function that runs the pending microtasks.
+const PROMISE: &[u8] = br#"(function (global) {
+  var queue = [];
+  function enqueue(job) {
+    queue.push(job);
+  }
+  function drain() {
+    var count = 0;
+    while (queue.length > 0) {
+      count++;
+      queue.shift()();
+    }
+    return count;
+  }
+  if (typeof global.queueMicrotask !== 'function') {
+    global.queueMicrotask = function queueMicrotask(callback) {
+      if (typeof callback !== 'function') {
+        throw new TypeError('microtask callback must be a function');
+      }
+      enqueue(function () { callback(); });
+    };
+  }
+  if (typeof global.Promise === 'function') {
+    return drain;
+  }
+
+  var PENDING = 0, FULFILLED = 1, REJECTED = 2;
+
+  function state(promise) {
+    if (!(promise instanceof Promise)) {
+      throw new TypeError('receiver is not a Promise');
+    }
+    return promise.__promise;
+  }
+  function resolvers(promise) {
+    var done = false;
+    return [function (value) {
+      if (!done) {
+        done = true;
+        resolve(promise, value);
+      }
+    }, function (reason) {
+      if (!done) {
+        done = true;
+        settle(promise, REJECTED, reason);
+      }
+    }];
+  }
+  function resolve(promise, value) {
+    if (value === promise) {
+      return settle(promise, REJECTED, new TypeError('a promise cannot be resolved with itself'));
+    }
+    if (value !== null && (typeof value === 'object' || typeof value === 'function')) {
+      var then;
+      try {
+        then = value.then;
+      } catch (e) {
+        return settle(promise, REJECTED, e);
+      }
+      if (typeof then === 'function') {
+        enqueue(function () {
+          var fns = resolvers(promise);
+          try {
+            then.call(value, fns[0], fns[1]);
+          } catch (e) {
+            fns[1](e);
+          }
+        });
+        return;
+      }
+    }
+    settle(promise, FULFILLED, value);
+  }
+  function settle(promise, to, value) {
+    var s = promise.__promise;
+    if (s.state !== PENDING) {
+      return;
+    }
+    s.state = to;
+    s.value = value;
+    var reactions = s.reactions;
+    s.reactions = null;
+    reactions.forEach(function (reaction) { react(s, reaction); });
+  }
+  function react(s, reaction) {
+    enqueue(function () {
+      var handler = s.state === FULFILLED ? reaction.onFulfilled : reaction.onRejected;
+      if (typeof handler !== 'function') {
+        (s.state === FULFILLED ? reaction.resolve : reaction.reject)(s.value);
+        return;
+      }
+      var result;
+      try {
+        result = handler(s.value);
+      } catch (e) {
+        reaction.reject(e);
+        return;
+      }
+      reaction.resolve(result);
+    });
+  }
+
+  function Promise(executor) {
+    if (!(this instanceof Promise)) {
+      throw new TypeError('Promise must be called with new');
+    }
+    if (typeof executor !== 'function') {
+      throw new TypeError('Promise resolver is not a function');
+    }
+    Object.defineProperty(this, '__promise', {
+      value: {state: PENDING, value: undefined, reactions: []}
+    });
+    var fns = resolvers(this);
+    try {
+      executor(fns[0], fns[1]);
+    } catch (e) {
+      fns[1](e);
+    }
+  }
+  Promise.prototype.then = function (onFulfilled, onRejected) {
+    var s = state(this);
+    var reaction = {onFulfilled: onFulfilled, onRejected: onRejected};
+    var next = new Promise(function (resolve, reject) {
+      reaction.resolve = resolve;
+      reaction.reject = reject;
+    });
+    if (s.state === PENDING) {
+      s.reactions.push(reaction);
+    } else {
+      react(s, reaction);
+    }
+    return next;
+  };
+  Promise.prototype['catch'] = function (onRejected) {
+    return this.then(undefined, onRejected);
+  };
+  Promise.prototype['finally'] = function (onFinally) {
+    if (typeof onFinally !== 'function') {
+      return this.then(onFinally, onFinally);
+    }
+    return this.then(function (value) {
+      return Promise.resolve(onFinally()).then(function () { return value; });
+    }, function (reason) {
+      return Promise.resolve(onFinally()).then(function () { throw reason; });
+    });
+  };
+  Promise.resolve = function (value) {
+    if (value instanceof Promise) {
+      return value;
+    }
+    return new Promise(function (resolve) { resolve(value); });
+  };
+  Promise.reject = function (reason) {
+    return new Promise(function (resolve, reject) { reject(reason); });
+  };
+  function combine(items, onValue, onReason, finish) {
+    return new Promise(function (resolve, reject) {
+      var list = Array.prototype.slice.call(items);
+      var results = new Array(list.length);
+      var remaining = list.length;
+      if (remaining === 0) {
+        resolve(results);
+      }
+      list.forEach(function (item, i) {
+        Promise.resolve(item).then(function (value) {
+          results[i] = onValue(value);
+          if (--remaining === 0) {
+            resolve(results);
+          }
+        }, function (reason) {
+          if (onReason) {
+            results[i] = onReason(reason);
+            if (--remaining === 0) {
+              resolve(results);
+            }
+          } else {
+            reject(reason);
+          }
+        });
+      });
+    });
+  }
+  Promise.all = function (items) {
+    return combine(items, function (value) { return value; });
+  };
+  Promise.allSettled = function (items) {
+    return combine(items, function (value) {
+      return {status: 'fulfilled', value: value};
+    }, function (reason) {
+      return {status: 'rejected', reason: reason};
+    });
+  };
+  Promise.race = function (items) {
+    return new Promise(function (resolve, reject) {
+      Array.prototype.forEach.call(items, function (item) {
+        Promise.resolve(item).then(resolve, reject);
+      });
+    });
+  };
+
+  Object.defineProperty(global, 'Promise', {value: Promise, writable: true, configurable: true});
+  return drain;
+})(this);"#;
+
+/// A group of polyfills, see the module documentation for what each one defines.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Polyfill {
+    Object,
+    Array,
+    String,
+    Number,
+    Promise,
+}
+
+/// The polyfills to preload into a context.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PolyfillSet {
+    bits: u8,
+}
+
+const ALL: [Polyfill; 5] = [Polyfill::Object,
+                            Polyfill::Array,
+                            Polyfill::String,
+                            Polyfill::Number,
+                            Polyfill::Promise];
+
+/// The bytecode of every polyfill that has been compiled in this process.
+static BYTECODE: sync::Mutex<collections::BTreeMap<Polyfill, Vec<u8>>> =
+    sync::Mutex::new(collections::BTreeMap::new());
+
+impl Polyfill {
+    fn source(self) -> &'static [u8] {
+        match self {
+            Polyfill::Object => OBJECT,
+            Polyfill::Array => ARRAY,
+            Polyfill::String => STRING,
+            Polyfill::Number => NUMBER,
+            Polyfill::Promise => PROMISE,
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+impl PolyfillSet {
+    /// No polyfills.
+    pub fn empty() -> PolyfillSet {
+        PolyfillSet::default()
+    }
+
+    /// Every polyfill.
+    pub fn all() -> PolyfillSet {
+        ALL.iter().fold(PolyfillSet::empty(), |set, &p| set.with(p))
+    }
+
+    pub fn with(self, polyfill: Polyfill) -> Self {
+        PolyfillSet { bits: self.bits | polyfill.bit() }
+    }
+
+    pub fn without(self, polyfill: Polyfill) -> Self {
+        PolyfillSet { bits: self.bits & !polyfill.bit() }
+    }
+
+    pub fn contains(&self, polyfill: Polyfill) -> bool {
+        self.bits & polyfill.bit() != 0
+    }
+}
+
+/// Runs the polyfills of the set, in dependency order.
+pub(crate) unsafe fn setup(ctx: *mut duktape_sys::duk_context, set: PolyfillSet) {
+    use duktape_sys::*;
+
+    for &polyfill in ALL.iter().filter(|&&p| set.contains(p)) {
+        push_compiled(ctx, polyfill);
+        let ret = duk_pcall(ctx, 0);
+        assert_eq!(0, ret, "failed to run the {:?} polyfill", polyfill);
+        if polyfill == Polyfill::Promise {
+            duk_push_heap_stash(ctx);
+            duk_swap_top(ctx, -2);
+            duk_put_prop_string(ctx, -2, nul_str(STASH_KEY));
+        }
+        duk_pop(ctx);
+    }
+}
+
+/// Pushes the compiled program of a polyfill, loading it from the cached bytecode if possible.
+unsafe fn push_compiled(ctx: *mut duktape_sys::duk_context, polyfill: Polyfill) {
+    use duktape_sys::*;
+
+    let mut cache = BYTECODE.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(bytecode) = cache.get(&polyfill) {
+        let buf = duk_push_fixed_buffer(ctx, bytecode.len());
+        ptr::copy_nonoverlapping(bytecode.as_ptr(), buf as *mut u8, bytecode.len());
+        duk_load_function(ctx);
+        return;
+    }
+
+    let source = polyfill.source();
+    let ret = duk_pcompile_lstring(ctx, 0, source.as_ptr() as *const os::raw::c_char, source.len());
+    assert_eq!(0, ret, "failed to compile the {:?} polyfill", polyfill);
+    duk_dup_top(ctx);
+    duk_dump_function(ctx);
+    let mut len = 0;
+    let data = duk_get_buffer(ctx, -1, &mut len);
+    cache.insert(polyfill, slice::from_raw_parts(data as *const u8, len).to_vec());
+    duk_pop(ctx);
+}
+
+/// Pushes the function that runs the pending microtasks, or `undefined` if the context doesn't
+/// have the `Promise` polyfill.
+pub(crate) unsafe fn push_microtasks(ctx: *mut duktape_sys::duk_context) {
+    duktape_sys::duk_push_heap_stash(ctx);
+    duktape_sys::duk_get_prop_string(ctx, -1, nul_str(STASH_KEY));
+    duktape_sys::duk_remove(ctx, -2);
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use super::*;
+    use {Context, Value};
+
+    #[test]
+    fn builtins() {
+        let _ = env_logger::init();
+        // The second context loads the cached bytecode
+        for _ in 0..2 {
+            let ctx = Context::builder()
+                .with_polyfills(PolyfillSet::all().without(Polyfill::Promise))
+                .build();
+            let result = ctx.eval_string(r"
+              [Object.assign({a: 1}, null, {b: 2}).b, Object.entries({x: 1})[0].join(),
+               Object.is(NaN, NaN), Object.is(0, -0), Array.from('abc').join('-'),
+               [1, NaN].includes(NaN), [[1, [2]], 3].flat(Infinity).join(),
+               Array(3).fill(0).join(), 'abc'.padStart(6, '12'), 'ab'.repeat(3),
+               'hello'.startsWith('ell', 1), String.fromCodePoint(0x1f600).codePointAt(0),
+               Number.isSafeInteger(Math.pow(2, 53)), Math.trunc(-4.7), Math.sign(-3),
+               Math.cbrt(27), Math.hypot(3, 4), Math.clz32(1), Math.imul(0xffffffff, 5),
+               Object.keys(Array.prototype).length, typeof Promise]
+            ")
+                .unwrap();
+            assert_eq!(Value::Array(vec![Value::Number(2.0),
+                                         Value::String("x,1".to_owned()),
+                                         Value::Boolean(true),
+                                         Value::Boolean(false),
+                                         Value::String("a-b-c".to_owned()),
+                                         Value::Boolean(true),
+                                         Value::String("1,2,3".to_owned()),
+                                         Value::String("0,0,0".to_owned()),
+                                         Value::String("121abc".to_owned()),
+                                         Value::String("ababab".to_owned()),
+                                         Value::Boolean(true),
+                                         Value::Number(128512.0),
+                                         Value::Boolean(false),
+                                         Value::Number(-4.0),
+                                         Value::Number(-1.0),
+                                         Value::Number(3.0),
+                                         Value::Number(5.0),
+                                         Value::Number(31.0),
+                                         Value::Number(-5.0),
+                                         Value::Number(0.0),
+                                         Value::String("undefined".to_owned())]),
+                       result.to_value());
+            ctx.assert_clean();
+        }
+        assert!(!PolyfillSet::empty().contains(Polyfill::Array));
+    }
+
+    #[test]
+    fn promises() {
+        let _ = env_logger::init();
+        let ctx = Context::builder().with_polyfills(PolyfillSet::all()).build();
+        ctx.eval_string(r"
+          var log = [];
+          Promise.resolve(1)
+            .then(function (v) { log.push('then ' + v); throw new Error('boom'); })
+            .catch(function (e) { log.push('catch ' + e.message); return 2; })
+            .finally(function () { log.push('finally'); })
+            .then(function (v) { log.push('after ' + v); });
+          Promise.all([1, Promise.resolve(2), {then: function (f) { f(3); }}])
+            .then(function (v) { log.push('all ' + v.join()); });
+          Promise.race([new Promise(function () {}), Promise.reject('no')])
+            .then(null, function (r) { log.push('race ' + r); });
+          queueMicrotask(function () { log.push('microtask'); });
+          log.push('sync');
+        ")
+            .unwrap();
+        assert_eq!(Value::String("sync".to_owned()),
+                   ctx.eval_string("log.join('|')").unwrap().to_value());
+        assert!(ctx.run_microtasks().unwrap() > 0);
+        // Independent chains may interleave, but run after the synchronous code
+        assert_eq!(Value::String("sync:after 2|all 1,2,3|catch boom|finally|microtask|race no|then 1"
+                       .to_owned()),
+                   ctx.eval_string("log[0] + ':' + log.slice(1).sort().join('|')")
+                       .unwrap()
+                       .to_value());
+        assert_eq!(Value::String("then 1,catch boom,finally,after 2".to_owned()),
+                   ctx.eval_string("log.filter(function (l) { return /^(th|ca|fi|af)/.test(l); })\
+                                    .join()")
+                       .unwrap()
+                       .to_value());
+        assert_eq!(0, ctx.run_microtasks().unwrap());
+
+        ctx.eval_string("queueMicrotask(function () { throw new Error('unhandled'); })").unwrap();
+        assert!(ctx.run_microtasks().is_err());
+        ctx.assert_clean();
+    }
+}
@@ -0,0 +1,246 @@
+//! A simple pool allocator that can be used as the Duktape heap allocator.
+//!
+//! Duktape performs a very large number of small allocations (strings, property tables, hash
+//! parts, activation records, ...).  This allocator serves those from free lists of fixed-size
+//! blocks that are carved out of larger chunks, and only falls back to the system allocator for
+//! allocations that are larger than the largest block size.
+
+use std::alloc;
+use std::cmp;
+use std::ptr;
+
+use allocator::Allocator;
+
+/// The block sizes of the pools, tuned for the typical allocation sizes seen in Duktape.
+const BLOCK_SIZES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// The size of each chunk that blocks are carved out of.
+const CHUNK_SIZE: usize = 32 * 1024;
+
+/// Every block is preceded by a header storing the size class (or the allocation size for large
+/// allocations).  The header size also determines the alignment of returned pointers.
+const HEADER_SIZE: usize = 16;
+const ALIGN: usize = 16;
+
+/// Size class marker for allocations that didn't fit into any pool.
+const LARGE: usize = !0;
+
+pub struct PoolAllocator {
+    free_lists: [*mut u8; 8],
+    /// The chunks, along with the size classes of their blocks.
+    chunks: Vec<(*mut u8, usize)>,
+    /// The number of allocations made from the system allocator, for chunks and large allocations.
+    system_allocations: usize,
+}
+
+impl PoolAllocator {
+    pub fn new() -> PoolAllocator {
+        PoolAllocator {
+            free_lists: [ptr::null_mut(); 8],
+            chunks: Vec::new(),
+            system_allocations: 0,
+        }
+    }
+
+    /// Carves a new chunk into blocks of the specified size class and adds them to the free list.
+    unsafe fn refill(&mut self, class: usize) {
+        let stride = HEADER_SIZE + BLOCK_SIZES[class];
+        let count = cmp::max(CHUNK_SIZE / stride, 1);
+        let chunk = alloc::alloc(chunk_layout(class));
+        if chunk.is_null() {
+            alloc::handle_alloc_error(chunk_layout(class));
+        }
+        self.chunks.push((chunk, class));
+        self.system_allocations += 1;
+
+        for i in 0..count {
+            let block = chunk.add(i * stride);
+            *(block as *mut usize) = class;
+            *(block.add(HEADER_SIZE) as *mut *mut u8) = self.free_lists[class];
+            self.free_lists[class] = block;
+        }
+    }
+}
+
+impl Allocator for PoolAllocator {
+    unsafe fn alloc(&mut self, size: usize) -> *mut u8 {
+        match size_class(size) {
+            Some(class) => {
+                if self.free_lists[class].is_null() {
+                    self.refill(class);
+                }
+
+                let block = self.free_lists[class];
+                self.free_lists[class] = *(block.add(HEADER_SIZE) as *mut *mut u8);
+                *(block as *mut usize) = class;
+                block.add(HEADER_SIZE)
+            }
+            None => {
+                let block = alloc::alloc(large_layout(size));
+                if block.is_null() {
+                    return ptr::null_mut();
+                }
+                self.system_allocations += 1;
+                *(block as *mut usize) = LARGE;
+                *(block.add(8) as *mut usize) = size;
+                block.add(HEADER_SIZE)
+            }
+        }
+    }
+
+    unsafe fn free(&mut self, data: *mut u8) {
+        if data.is_null() {
+            return;
+        }
+
+        let block = data.sub(HEADER_SIZE);
+        let class = *(block as *mut usize);
+        if class == LARGE {
+            let size = *(block.add(8) as *mut usize);
+            alloc::dealloc(block, large_layout(size));
+        } else {
+            *(data as *mut *mut u8) = self.free_lists[class];
+            self.free_lists[class] = block;
+        }
+    }
+
+    unsafe fn realloc(&mut self, data: *mut u8, size: usize) -> *mut u8 {
+        if data.is_null() {
+            return self.alloc(size);
+        }
+
+        if size == 0 {
+            self.free(data);
+            return ptr::null_mut();
+        }
+
+        let old_size = usable_size(data);
+        if size <= old_size && size_class(size) == size_class(old_size) {
+            return data;
+        }
+
+        let new_data = self.alloc(size);
+        if !new_data.is_null() {
+            ptr::copy_nonoverlapping(data, new_data, cmp::min(old_size, size));
+            self.free(data);
+        }
+        new_data
+    }
+}
+
+impl Drop for PoolAllocator {
+    fn drop(&mut self) {
+        for &(chunk, class) in &self.chunks {
+            unsafe { alloc::dealloc(chunk, chunk_layout(class)) };
+        }
+    }
+}
+
+fn size_class(size: usize) -> Option<usize> {
+    BLOCK_SIZES.iter().position(|&s| size <= s)
+}
+
+unsafe fn usable_size(data: *mut u8) -> usize {
+    let block = data.sub(HEADER_SIZE);
+    let class = *(block as *mut usize);
+    if class == LARGE {
+        *(block.add(8) as *mut usize)
+    } else {
+        BLOCK_SIZES[class]
+    }
+}
+
+fn large_layout(size: usize) -> alloc::Layout {
+    alloc::Layout::from_size_align(HEADER_SIZE + size, ALIGN).unwrap()
+}
+
+fn chunk_layout(class: usize) -> alloc::Layout {
+    let stride = HEADER_SIZE + BLOCK_SIZES[class];
+    let count = cmp::max(CHUNK_SIZE / stride, 1);
+    alloc::Layout::from_size_align(stride * count, ALIGN).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell;
+    use std::rc;
+
+    use super::*;
+    use allocator::Allocator;
+    use Context;
+
+    /// Counts the allocations that Duktape asks the pool for, which would each go to the system
+    /// allocator without it, and the ones that the pool makes from the system allocator.
+    struct Counting {
+        pool: PoolAllocator,
+        requests: usize,
+        counts: rc::Rc<cell::Cell<(usize, usize)>>,
+    }
+
+    impl Allocator for Counting {
+        unsafe fn alloc(&mut self, size: usize) -> *mut u8 {
+            self.requests += 1;
+            self.pool.alloc(size)
+        }
+
+        unsafe fn realloc(&mut self, data: *mut u8, size: usize) -> *mut u8 {
+            if size != 0 {
+                self.requests += 1;
+            }
+            self.pool.realloc(data, size)
+        }
+
+        unsafe fn free(&mut self, data: *mut u8) {
+            self.pool.free(data)
+        }
+    }
+
+    impl Drop for Counting {
+        fn drop(&mut self) {
+            self.counts.set((self.requests, self.pool.system_allocations));
+        }
+    }
+
+    #[test]
+    fn alloc_realloc_free() {
+        let mut pool = PoolAllocator::new();
+        unsafe {
+            let a = pool.alloc(10);
+            *a = 42;
+            let b = pool.realloc(a, 12);
+            assert_eq!(a, b);
+            let c = pool.realloc(b, 100);
+            assert_eq!(42, *c);
+            let d = pool.realloc(c, 10000);
+            assert_eq!(42, *d);
+            pool.free(d);
+
+            // Freed blocks are reused
+            let e = pool.alloc(100);
+            assert_eq!(c, e);
+            pool.free(e);
+        }
+        assert_eq!(2, pool.chunks.len());
+        assert_eq!(3, pool.system_allocations);
+    }
+
+    #[test]
+    fn fewer_system_allocations() {
+        let counts = rc::Rc::new(cell::Cell::new((0, 0)));
+        let allocator = Counting {
+            pool: PoolAllocator::new(),
+            requests: 0,
+            counts: counts.clone(),
+        };
+        let ctx = Context::builder().with_allocator(Box::new(allocator)).build();
+        ctx.eval_string("var a = []; for (var i = 0; i < 1000; i++) { a.push({s: 'x' + i}); }")
+            .unwrap();
+        drop(ctx);
+
+        let (requests, system_allocations) = counts.get();
+        assert!(system_allocations * 20 < requests,
+                "{} allocations from Duktape, {} from the system allocator",
+                requests,
+                system_allocations);
+    }
+}
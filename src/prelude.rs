@@ -0,0 +1,21 @@
+//! The types and traits that most users of this crate need, for a glob import.
+//!
+//! `Result` is left out on purpose, since it would shadow the `Result` of the standard prelude;
+//! use `duk::Result` instead.
+//!
+//! # Examples
+//!
+//! ```
+//! use duk::prelude::*;
+//!
+//! fn greet(ctx: &Context, name: &str) -> Result<String, Error> {
+//!     ctx.eval_string("function greet(name) { return 'hi ' + name; }")?;
+//!     String::from_value(ctx.call("greet", (name,))?.to_value())
+//! }
+//!
+//! let ctx = Context::builder().build();
+//! assert_eq!("hi bob", greet(&ctx, "bob").unwrap());
+//! ```
+
+pub use {Argument, Context, ContextBuilder, Error, ErrorKind, FromValue, IntoArgs, JsError,
+         JsErrorKind, Reference, Value};
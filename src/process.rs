@@ -0,0 +1,137 @@
+//! A `host:process` module that tells scripts about the host, see
+//! `ContextBuilder::with_process_info`.
+//!
+//! The module exports frozen data: `platform` and `arch` (like `linux` and `x86_64`, as in
+//! `std::env::consts`), the `version` of the host application (or `null`), and an `env` object
+//! with only the environment variables that the host allowlisted.  Variables are read when the
+//! context is built; allowlisted variables that aren't set are missing from `env`.
+//!
+//! # Examples
+//!
+//! ```
+//! let info = duk::process::ProcessInfo::new()
+//!     .with_version("2.1.0")
+//!     .with_env("PATH")
+//!     .with_env_value("REGION", "eu-west");
+//! let ctx = duk::Context::builder().with_process_info(info).build();
+//! let region = ctx.eval_string("require('host:process').env.REGION").unwrap();
+//! assert_eq!(duk::Value::String("eu-west".to_owned()), region.to_value());
+//! ```
+
+use std::collections;
+use std::env;
+use std::os;
+
+use duktape_sys;
+
+use host_modules;
+use Value;
+
+/// Freezes the exports, so that plugins sharing a context can't fool each other.
+const FREEZE: &[u8] = b"(function (info) {
+  Object.freeze(info.env);
+  return Object.freeze(info);
+})";
+
+/// What `host:process` exposes to scripts.
+#[derive(Clone, Debug, Default)]
+pub struct ProcessInfo {
+    version: Option<String>,
+    env: Vec<(String, Option<String>)>,
+}
+
+impl ProcessInfo {
+    /// Exposes the platform and architecture only.
+    pub fn new() -> ProcessInfo {
+        ProcessInfo::default()
+    }
+
+    /// Sets the version of the host application.
+    pub fn with_version(mut self, version: &str) -> Self {
+        self.version = Some(version.to_owned());
+        self
+    }
+
+    /// Allowlists an environment variable of the host process.
+    pub fn with_env(mut self, name: &str) -> Self {
+        self.env.push((name.to_owned(), None));
+        self
+    }
+
+    /// Exposes a variable in `env` with the specified value, whether or not the host process has
+    /// it.
+    pub fn with_env_value(mut self, name: &str, value: &str) -> Self {
+        self.env.push((name.to_owned(), Some(value.to_owned())));
+        self
+    }
+
+    /// The exports of the module, with the current values of the allowlisted variables.
+    fn to_value(&self) -> Value {
+        let vars = self.env
+            .iter()
+            .filter_map(|(name, value)| {
+                let value = value.clone().or_else(|| env::var(name).ok())?;
+                Some((name.clone(), Value::String(value)))
+            })
+            .collect();
+
+        let mut info = collections::BTreeMap::new();
+        info.insert("platform".to_owned(), Value::String(env::consts::OS.to_owned()));
+        info.insert("arch".to_owned(), Value::String(env::consts::ARCH.to_owned()));
+        info.insert("version".to_owned(),
+                    self.version.clone().map_or(Value::Null, Value::String));
+        info.insert("env".to_owned(), Value::Object(vars));
+        Value::Object(info)
+    }
+}
+
+/// Registers the `host:process` module.
+pub(crate) unsafe fn setup(ctx: *mut duktape_sys::duk_context, info: &ProcessInfo) {
+    use duktape_sys::*;
+
+    let ret = duk_peval_lstring(ctx, FREEZE.as_ptr() as *const os::raw::c_char, FREEZE.len());
+    assert_eq!(0, ret, "failed to compile host:process");
+    info.to_value().push(ctx);
+    let ret = duk_pcall(ctx, 1);
+    assert_eq!(0, ret, "failed to set up host:process");
+    host_modules::register(ctx, b"host:process\0");
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::env;
+
+    use super::*;
+    use Context;
+
+    #[test]
+    fn host_process() {
+        let _ = env_logger::init();
+        env::set_var("DUK_TEST_ALLOWED", "yes");
+        env::set_var("DUK_TEST_SECRET", "hunter2");
+        let info = ProcessInfo::new()
+            .with_env("DUK_TEST_ALLOWED")
+            .with_env("DUK_TEST_UNSET")
+            .with_env_value("MODE", "test");
+        let ctx = Context::builder().with_process_info(info).build();
+        let result = ctx.eval_string(r"
+          var process = require('host:process');
+          process.env.MODE = 'changed';
+          [Object.keys(process.env), process.env.MODE, process.env.DUK_TEST_ALLOWED,
+           process.platform, process.version, Object.isFrozen(process)]
+        ")
+            .unwrap();
+        assert_eq!(Value::Array(vec![Value::Array(vec![Value::String("DUK_TEST_ALLOWED"
+                                                               .to_owned()),
+                                                           Value::String("MODE".to_owned())]),
+                                     Value::String("test".to_owned()),
+                                     Value::String("yes".to_owned()),
+                                     Value::String(env::consts::OS.to_owned()),
+                                     Value::Null,
+                                     Value::Boolean(true)]),
+                   result.to_value());
+        ctx.assert_clean();
+    }
+}
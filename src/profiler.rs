@@ -0,0 +1,254 @@
+//! A sampling profiler for Javascript code, see `Context::profile`.
+//!
+//! Duktape interrupts the bytecode executor roughly every 256k executed instructions, and every
+//! interrupt records the current call stack, weighted by the time since the previous sample.
+//! Short-running code might therefore not show up in a profile at all.
+
+use std::cell;
+use std::collections;
+use std::io;
+use std::os;
+use std::rc;
+use std::slice;
+use std::time;
+
+use duktape_sys;
+
+use source_map;
+use strings;
+use HeapData;
+
+/// A profile of the Javascript code executed by a context, created with `Context::profile`.
+///
+/// Samples keep being added to the profile while it is being recorded, so it can be written out
+/// at any time.
+#[derive(Clone, Debug)]
+pub struct Profile {
+    data: rc::Rc<cell::RefCell<ProfileData>>,
+}
+
+/// A function that shows up in the call stack of a sample.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Frame {
+    /// The name of the function, or `(anonymous)`.
+    pub function_name: String,
+    /// The file name of the function (or the original file name, if a source map is registered
+    /// for it).
+    pub file_name: String,
+}
+
+#[derive(Debug, Default)]
+struct ProfileData {
+    frames: Vec<Frame>,
+    frame_indices: collections::HashMap<Frame, usize>,
+    /// Call stacks as indices into `frames` with the outermost frame first, and their weights.
+    samples: Vec<(Vec<usize>, time::Duration)>,
+}
+
+/// The recording side of a `Profile`, stored in the heap data of a context.
+pub(crate) struct Sampler {
+    ctx: *mut duktape_sys::duk_context,
+    data: rc::Rc<cell::RefCell<ProfileData>>,
+    source_maps: rc::Rc<cell::RefCell<source_map::SourceMapRegistry>>,
+    last_sample: time::Instant,
+    deadline: time::Instant,
+}
+
+impl Profile {
+    /// The number of samples recorded so far.
+    pub fn sample_count(&self) -> usize {
+        self.data.borrow().samples.len()
+    }
+
+    /// The total time covered by the samples recorded so far.
+    pub fn total_time(&self) -> time::Duration {
+        self.data.borrow().samples.iter().map(|&(_, w)| w).sum()
+    }
+
+    /// The distinct functions that have been seen in samples so far.
+    pub fn frames(&self) -> Vec<Frame> {
+        self.data.borrow().frames.clone()
+    }
+
+    /// Writes the profile in the folded stack format understood by `flamegraph.pl` and
+    /// `inferno`, with one line per distinct call stack weighted in microseconds.
+    pub fn write_flamegraph<W>(&self, writer: &mut W) -> io::Result<()>
+        where W: io::Write
+    {
+        let data = self.data.borrow();
+        let mut folded = collections::BTreeMap::new();
+        for (stack, weight) in &data.samples {
+            let line = stack.iter()
+                .map(|&i| data.frames[i].to_string().replace(';', ":"))
+                .collect::<Vec<_>>()
+                .join(";");
+            *folded.entry(line).or_insert(0) += micros(*weight);
+        }
+
+        for (stack, weight) in folded {
+            writeln!(writer, "{} {}", stack, weight)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the profile as a sampled profile in the JSON format of the speedscope viewer.
+    pub fn write_speedscope<W>(&self, writer: &mut W) -> io::Result<()>
+        where W: io::Write
+    {
+        let data = self.data.borrow();
+        let frames = data.frames
+            .iter()
+            .map(|f| format!("{{\"name\":{},\"file\":{}}}",
+                             json_string(&f.function_name),
+                             json_string(&f.file_name)))
+            .collect::<Vec<_>>();
+        let samples = data.samples
+            .iter()
+            .map(|(stack, _)| {
+                format!("[{}]",
+                        stack.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(","))
+            })
+            .collect::<Vec<_>>();
+        let weights = data.samples
+            .iter()
+            .map(|&(_, w)| micros(w).to_string())
+            .collect::<Vec<_>>();
+        let total: u64 = data.samples.iter().map(|&(_, w)| micros(w)).sum();
+
+        write!(writer,
+               "{{\"$schema\":\"https://www.speedscope.app/file-format-schema.json\",\
+                \"shared\":{{\"frames\":[{}]}},\
+                \"profiles\":[{{\"type\":\"sampled\",\"name\":\"duk\",\"unit\":\"microseconds\",\
+                \"startValue\":0,\"endValue\":{},\"samples\":[{}],\"weights\":[{}]}}]}}",
+               frames.join(","),
+               total,
+               samples.join(","),
+               weights.join(","))
+    }
+}
+
+impl ::std::fmt::Display for Frame {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        if self.file_name.is_empty() {
+            write!(f, "{}", self.function_name)
+        } else {
+            write!(f, "{} ({})", self.function_name, self.file_name)
+        }
+    }
+}
+
+impl Sampler {
+    pub fn new(ctx: *mut duktape_sys::duk_context,
+               duration: time::Duration,
+               source_maps: rc::Rc<cell::RefCell<source_map::SourceMapRegistry>>)
+               -> (Sampler, Profile) {
+        let data = rc::Rc::new(cell::RefCell::new(ProfileData::default()));
+        let now = time::Instant::now();
+        let sampler = Sampler {
+            ctx,
+            data: data.clone(),
+            source_maps,
+            last_sample: now,
+            deadline: now + duration,
+        };
+        (sampler, Profile { data })
+    }
+
+    /// Records a sample, returning whether the sampler should keep running.
+    unsafe fn sample(&mut self) -> bool {
+        let now = time::Instant::now();
+        if now >= self.deadline {
+            return false;
+        }
+        let weight = now - self.last_sample;
+        self.last_sample = now;
+
+        let stack = self.capture_stack();
+        let mut data = self.data.borrow_mut();
+        let indices = stack.into_iter()
+            .rev()
+            .map(|frame| {
+                let next = data.frames.len();
+                let index = *data.frame_indices.entry(frame.clone()).or_insert(next);
+                if index == next {
+                    data.frames.push(frame);
+                }
+                index
+            })
+            .collect();
+        data.samples.push((indices, weight));
+        true
+    }
+
+    /// Captures the current call stack, with the innermost frame first.  Runs within the executor
+    /// interrupt hook, so it reads the call stack without calling into Duktape.
+    unsafe fn capture_stack(&self) -> Vec<Frame> {
+        let source_maps = self.source_maps.borrow();
+        let mut frames = Vec::with_capacity(64);
+        let depth = duktape_sys::duktape_sys_capture_callstack(self.ctx,
+                                                               frames.as_mut_ptr(),
+                                                               frames.capacity());
+        if depth > frames.capacity() {
+            frames.reserve_exact(depth);
+            duktape_sys::duktape_sys_capture_callstack(self.ctx, frames.as_mut_ptr(), depth);
+        }
+        frames.set_len(depth);
+
+        frames.into_iter()
+            .map(|frame| {
+                let function_name = Some(string(frame.function_name, frame.function_name_len))
+                    .and_then(|n| if n.is_empty() { None } else { Some(n) })
+                    .unwrap_or_else(|| "(anonymous)".to_owned());
+                let mut file_name = string(frame.file_name, frame.file_name_len);
+                if let Some(original) = source_maps.lookup(&file_name, frame.line) {
+                    file_name = original.file_name;
+                }
+                Frame {
+                    function_name,
+                    file_name,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Decodes a string of a captured frame, which is empty if the function doesn't have it.
+unsafe fn string(data: *const os::raw::c_char, len: usize) -> String {
+    if data.is_null() {
+        String::new()
+    } else {
+        strings::decode(slice::from_raw_parts(data as *const u8, len)).collect()
+    }
+}
+
+/// Records a sample if a profile is being recorded, called from the executor interrupt hook.
+pub(crate) unsafe fn sample(udata: *mut os::raw::c_void) {
+    let heap_data = &mut *(udata as *mut HeapData);
+    let keep_running = match heap_data.sampler {
+        Some(ref mut sampler) => sampler.sample(),
+        None => true,
+    };
+    if !keep_running {
+        heap_data.sampler = None;
+    }
+}
+
+fn micros(duration: time::Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + u64::from(duration.subsec_micros())
+}
+
+fn json_string(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
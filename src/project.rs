@@ -0,0 +1,358 @@
+//! Loading plugins that are whole directories of CommonJS modules, see `Context::load_project`.
+//!
+//! The files of a project are the ones below its root directory whose paths match one of the
+//! `include` patterns and none of the `exclude` patterns.  Patterns use `/` as the separator and
+//! are relative to the root: `*` matches any part of a file or directory name, `?` matches a
+//! single character, and a `**` segment matches any number of directories, so `src/**/*.js`
+//! matches both `src/main.js` and `src/lib/util.js`.
+//!
+//! Every file is loaded as a module, with the path relative to the root as its id (which is also
+//! its file name in stack traces), and `require` resolves like in bundles (see the `bundle`
+//! module): ids starting with `./` or `../` relative to the requiring module, other ids relative
+//! to the root, and `.js` is appended unless the id names an existing file.  Ids that aren't part
+//! of the project are passed on to the `require` of the context.
+//!
+//! Modules load in dependency order: the entry module (if any) and the modules it requires
+//! first, then the remaining modules in the order of their ids, each after the modules it
+//! requires.  Each module runs once, and loading stops at the first module that can't be read,
+//! compiled or run, with an `ErrorKind::Load` error that names the file.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! let ctx = duk::Context::new();
+//! let project = ctx.load_project(duk::project::ProjectSpec {
+//!         root: "plugins/weather".into(),
+//!         include: vec!["src/**/*.js".to_owned()],
+//!         exclude: vec!["src/**/*.test.js".to_owned()],
+//!         entry: Some("src/main.js".to_owned()),
+//!     })
+//!     .unwrap();
+//! for module in &project.manifest.modules {
+//!     println!("{} exports {}", module.id, module.exports.join(", "));
+//! }
+//! let main = project.exports.get("src/main.js").unwrap();
+//! ```
+
+use std::collections;
+use std::fs;
+use std::io;
+use std::path;
+
+use bundle;
+use sources;
+use strings;
+use {ChainErr, Context, ErrorKind, Reference, Result};
+
+/// Which files make up a project, see the module documentation.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ProjectSpec {
+    /// The directory that patterns and module ids are relative to, or an empty path for the
+    /// current directory.
+    pub root: path::PathBuf,
+    /// Patterns of the files to load, like `src/**/*.js`.
+    pub include: Vec<String>,
+    /// Patterns of files not to load, even if they match `include`.
+    pub exclude: Vec<String>,
+    /// The id of the module to load first, like `src/main.js`.
+    pub entry: Option<String>,
+}
+
+/// What a project defined, see `Context::load_project`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ProjectManifest {
+    /// The modules of the project, in the order they were loaded.
+    pub modules: Vec<ProjectModule>,
+}
+
+/// A module of a project.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProjectModule {
+    /// The path of the module relative to the root, with `/` separators.
+    pub id: String,
+    /// The path of the file.
+    pub path: path::PathBuf,
+    /// The ids of the modules of the project that this module requires.
+    pub dependencies: Vec<String>,
+    /// The names of the own enumerable properties of the exports of the module, in alphabetical
+    /// order.
+    pub exports: Vec<String>,
+}
+
+/// A loaded project.
+#[derive(Debug)]
+pub struct Project<'a> {
+    /// What the project defined.
+    pub manifest: ProjectManifest,
+    /// An object with the exports of each module, by id.
+    pub exports: Reference<'a>,
+}
+
+struct Source {
+    id: String,
+    path: path::PathBuf,
+    source: String,
+    dependencies: Vec<(String, String)>,
+}
+
+pub(crate) fn load(ctx: &Context, spec: ProjectSpec) -> Result<Project<'_>> {
+    let mut ids = Vec::new();
+    find(&spec, &spec.root, "", &mut ids)?;
+    if let Some(ref entry) = spec.entry {
+        if !ids.contains(entry) {
+            return Err(ErrorKind::Load(spec.root.join(entry).display().to_string()).into());
+        }
+    }
+
+    let mut modules = collections::BTreeMap::new();
+    for id in &ids {
+        let path = spec.root.join(id);
+        let source = ctx.read_script(&path)
+            .map(|bytes| sources::decode(&bytes))
+            .chain_err(|| ErrorKind::Load(path.display().to_string()))?;
+        let dependencies = bundle::required_ids(&source)
+            .into_iter()
+            .map(|request| {
+                let resolved = bundle::resolve(&spec.root, id, &request);
+                (request, resolved)
+            })
+            .filter(|link| ids.contains(&link.1))
+            .collect();
+        modules.insert(id.clone(), Source { id: id.clone(), path, source, dependencies });
+    }
+
+    let mut order = Vec::new();
+    for id in spec.entry.iter().chain(&ids) {
+        visit(&modules, id, &mut order);
+    }
+    let order = order.into_iter().map(|id| &modules[id]).collect::<Vec<_>>();
+    unsafe { run(ctx, &order) }
+}
+
+/// Finds the files below a directory that the spec includes, by their ids.
+fn find(spec: &ProjectSpec, dir: &path::Path, prefix: &str, ids: &mut Vec<String>) -> Result<()> {
+    let read_dir = if dir.as_os_str().is_empty() {
+        path::Path::new(".")
+    } else {
+        dir
+    };
+    let mut entries = fs::read_dir(read_dir)
+        .and_then(|entries| entries.map(|e| e.map(|e| e.path())).collect::<io::Result<Vec<_>>>())
+        .chain_err(|| ErrorKind::Load(dir.display().to_string()))?;
+    entries.sort();
+    for entry in entries {
+        let name = match entry.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => continue,
+        };
+        let id = format!("{}{}", prefix, name);
+        if entry.is_dir() {
+            find(spec, &dir.join(&name), &format!("{}/", id), ids)?;
+        } else if spec.include.iter().any(|p| matches(p, &id)) &&
+                  !spec.exclude.iter().any(|p| matches(p, &id)) {
+            ids.push(id);
+        }
+    }
+    Ok(())
+}
+
+/// Adds a module to the load order after the modules it requires, unless it's already there.
+fn visit<'s>(modules: &'s collections::BTreeMap<String, Source>,
+             id: &'s str,
+             order: &mut Vec<&'s str>) {
+    if order.contains(&id) {
+        return;
+    }
+    // Modules that require each other are added before they are visited again
+    order.push(id);
+    let position = order.len() - 1;
+    for (_, dependency) in &modules[id].dependencies {
+        visit(modules, dependency, order);
+    }
+    let id = order.remove(position);
+    order.push(id);
+}
+
+/// Compiles and runs the modules in order, and collects what they export.
+unsafe fn run<'a>(ctx: &'a Context, order: &[&Source]) -> Result<Project<'a>> {
+    use duktape_sys::*;
+
+    let raw = ctx.raw;
+    duk_push_object(raw);
+    for module in order {
+        ctx.remember_source(&module.id, &module.source);
+        if let Err(error) = bundle::push_module(ctx, &module.id, &module.source) {
+            duk_pop(raw);
+            return Err(error).chain_err(|| ErrorKind::Load(module.path.display().to_string()));
+        }
+        strings::push(raw, &module.id);
+        duk_swap_top(raw, -2);
+        duk_put_prop(raw, -3);
+    }
+    duk_push_object(raw);
+    for module in order {
+        strings::push(raw, &module.id);
+        duk_push_object(raw);
+        for (request, resolved) in &module.dependencies {
+            strings::push(raw, request);
+            strings::push(raw, resolved);
+            duk_put_prop(raw, -3);
+        }
+        duk_put_prop(raw, -3);
+    }
+    bundle::push_loader(raw);
+
+    let mut manifest = ProjectManifest::default();
+    duk_push_object(raw);
+    for module in order {
+        duk_dup(raw, -2);
+        strings::push(raw, &module.id);
+        if duk_pcall(raw, 1) != 0 {
+            let error = ctx.pop_error();
+            duk_pop_2(raw);
+            return Err(error).chain_err(|| ErrorKind::Load(module.path.display().to_string()));
+        }
+        let mut exports = Vec::new();
+        if duk_is_object(raw, -1) != 0 {
+            duk_enum(raw, -1, DUK_ENUM_OWN_PROPERTIES_ONLY);
+            while duk_next(raw, -1, 0) != 0 {
+                exports.push(strings::get(raw, -1));
+                duk_pop(raw);
+            }
+            duk_pop(raw);
+        }
+        exports.sort();
+        strings::push(raw, &module.id);
+        duk_swap_top(raw, -2);
+        duk_put_prop(raw, -3);
+
+        let mut dependencies = Vec::<String>::new();
+        for (_, dependency) in &module.dependencies {
+            if !dependencies.contains(dependency) {
+                dependencies.push(dependency.clone());
+            }
+        }
+        manifest.modules.push(ProjectModule {
+            id: module.id.clone(),
+            path: module.path.clone(),
+            dependencies,
+            exports,
+        });
+    }
+    duk_remove(raw, -2);
+    Ok(Project { manifest, exports: ctx.pop_reference() })
+}
+
+/// Whether a path relative to the root matches a pattern, see the module documentation.
+fn matches(pattern: &str, id: &str) -> bool {
+    let pattern = pattern.split('/').filter(|s| !s.is_empty() && *s != ".").collect::<Vec<_>>();
+    let id = id.split('/').collect::<Vec<_>>();
+    matches_segments(&pattern, &id)
+}
+
+fn matches_segments(pattern: &[&str], segments: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => segments.is_empty(),
+        Some((&"**", rest)) => (0..=segments.len()).any(|i| matches_segments(rest, &segments[i..])),
+        Some((first, rest)) => {
+            match segments.split_first() {
+                Some((segment, others)) => {
+                    let first = first.chars().collect::<Vec<_>>();
+                    let segment = segment.chars().collect::<Vec<_>>();
+                    matches_name(&first, &segment) && matches_segments(rest, others)
+                }
+                None => false,
+            }
+        }
+    }
+}
+
+fn matches_name(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|i| matches_name(rest, &name[i..])),
+        Some(('?', rest)) => !name.is_empty() && matches_name(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && matches_name(rest, &name[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::fs;
+
+    use super::*;
+    use testing::ScratchDir;
+    use Value;
+
+    #[test]
+    fn patterns() {
+        assert!(matches("src/**/*.js", "src/main.js"));
+        assert!(matches("src/**/*.js", "src/lib/deep/util.js"));
+        assert!(!matches("src/**/*.js", "test/main.js"));
+        assert!(!matches("src/*.js", "src/lib/util.js"));
+        assert!(matches("./src/?.js", "src/a.js"));
+        assert!(!matches("src/?.js", "src/ab.js"));
+        assert!(matches("**", "a/b/c.txt"));
+        assert!(matches("**/*.test.js", "util.test.js"));
+    }
+
+    #[test]
+    #[cfg_attr(target_os = "wasi", ignore = "needs a temporary directory")]
+    fn load_project() {
+        let _ = env_logger::init();
+        let dir = ScratchDir::new("project");
+        fs::create_dir_all(dir.join("src/lib")).unwrap();
+        fs::write(dir.join("src/main.js"),
+                  "var util = require('./lib/util');\n\
+                   exports.run = function () { return util.twice(log.join()); };")
+            .unwrap();
+        fs::write(dir.join("src/lib/util.js"),
+                  "log.push('util');\nexports.twice = function (s) { return s + s; };")
+            .unwrap();
+        fs::write(dir.join("src/a.js"),
+                  "log.push('a');\n\
+                   exports.x = require('src/lib/util') === require('./lib/util.js');")
+            .unwrap();
+        fs::write(dir.join("src/a.test.js"), "throw new Error('not part of the project');")
+            .unwrap();
+        fs::write(dir.join("README.md"), "not a script").unwrap();
+
+        let ctx = Context::new();
+        ctx.eval_string("var log = [];").unwrap();
+        let mut spec = ProjectSpec {
+            root: dir.to_path_buf(),
+            include: vec!["src/**/*.js".to_owned()],
+            exclude: vec!["**/*.test.js".to_owned()],
+            entry: Some("src/main.js".to_owned()),
+        };
+        let project = ctx.load_project(spec.clone()).unwrap();
+        let ids = project.manifest.modules.iter().map(|m| m.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(vec!["src/lib/util.js", "src/main.js", "src/a.js"], ids);
+        assert_eq!(vec!["src/lib/util.js"], project.manifest.modules[1].dependencies);
+        assert_eq!(vec!["run"], project.manifest.modules[1].exports);
+        assert_eq!(dir.join("src/a.js"), project.manifest.modules[2].path);
+        let main = project.exports.get("src/main.js").unwrap();
+        assert_eq!(Value::String("util,autil,a".to_owned()),
+                   main.call_method("run", &[]).unwrap().to_value());
+        assert_eq!(Value::Boolean(true),
+                   project.exports.get("src/a.js").unwrap().get("x").unwrap().to_value());
+        assert_eq!(Value::String("util,a".to_owned()),
+                   ctx.eval_string("log.join()").unwrap().to_value());
+
+        // Errors name the module
+        fs::write(dir.join("src/lib/util.js"), "exports.twice = ;").unwrap();
+        let error = ctx.load_project(spec.clone()).unwrap_err();
+        assert_eq!(format!("failed to load {}", dir.join("src/lib/util.js").display()),
+                   error.to_string());
+        spec.exclude.clear();
+        fs::write(dir.join("src/lib/util.js"), "").unwrap();
+        let error = ctx.load_project(spec.clone()).unwrap_err();
+        assert_eq!(format!("failed to load {}", dir.join("src/a.test.js").display()),
+                   error.to_string());
+        spec.entry = Some("src/missing.js".to_owned());
+        assert!(ctx.load_project(spec).is_err());
+        ctx.assert_clean();
+    }
+}
@@ -0,0 +1,242 @@
+//! Objects whose properties come from Rust, see `Context::register_proxy`.
+//!
+//! A proxy exposes a large or lazy data set of the host to scripts, like environment variables or
+//! a key-value store, without converting all of it into a `Value::Object` up front.  It is an
+//! ES2015 `Proxy`, whose `get`, `set`, `has`, `deleteProperty` and `ownKeys` traps call the
+//! `ProxyHandler`, so every property access of the script reads or writes the data of the host
+//! as it is at that moment.  Errors of the handler are thrown into the script.
+//!
+//! Properties that the handler doesn't have are looked up in an empty object instead, so the
+//! proxy inherits methods like `toString` from `Object.prototype`, and converts to a string like
+//! other objects do.  Only string keys reach the handler; symbol keys can't be set.
+//!
+//! Proxies need the `Proxy` built-in, so they aren't available with the `no-es6-proxy` feature.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::collections;
+//!
+//! struct Environment(collections::HashMap<String, String>);
+//!
+//! impl duk::proxies::ProxyHandler for Environment {
+//!     fn get(&self, key: &str) -> duk::Result<Option<duk::Value>> {
+//!         Ok(self.0.get(key).map(|value| duk::Value::String(value.clone())))
+//!     }
+//!
+//!     fn keys(&self) -> duk::Result<Vec<String>> {
+//!         Ok(self.0.keys().cloned().collect())
+//!     }
+//! }
+//!
+//! let mut vars = collections::HashMap::new();
+//! vars.insert("HOME".to_owned(), "/home/duk".to_owned());
+//! let ctx = duk::Context::new();
+//! ctx.register_proxy("env", Environment(vars)).unwrap();
+//! assert_eq!(duk::Value::String("/home/duk true false".to_owned()),
+//!            ctx.eval_string("env.HOME + ' ' + ('HOME' in env) + ' ' + ('PATH' in env)")
+//!                .unwrap()
+//!                .to_value());
+//! ```
+
+use std::os;
+use std::rc;
+
+use duktape_sys;
+
+use native;
+use {Result, Value};
+
+/// Implements the properties of a proxy.  Every method has a default implementation, which
+/// behaves like an empty object that can't be modified.
+pub trait ProxyHandler {
+    /// The value of the property with the key, or `None` if it doesn't exist, which reads as
+    /// `undefined`.
+    fn get(&self, key: &str) -> Result<Option<Value>> {
+        let _ = key;
+        Ok(None)
+    }
+
+    /// Sets the property with the key, and returns whether it was set.  Assigning a property that
+    /// isn't set throws a `TypeError` in strict mode code, and is ignored otherwise.
+    fn set(&self, key: &str, value: Value) -> Result<bool> {
+        let _ = (key, value);
+        Ok(false)
+    }
+
+    /// Whether the property with the key exists, for the `in` operator.  Defaults to whether
+    /// `get` returns a value.
+    fn has(&self, key: &str) -> Result<bool> {
+        self.get(key).map(|value| value.is_some())
+    }
+
+    /// Deletes the property with the key, and returns whether it is gone.  Like for `set`,
+    /// failing to delete a property throws a `TypeError` in strict mode code.
+    fn delete(&self, key: &str) -> Result<bool> {
+        let _ = key;
+        Ok(false)
+    }
+
+    /// The keys of all properties, for `Object.keys`, `for`-`in` loops and the like.
+    fn keys(&self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Creates the proxy from native functions for the traps.  Properties that the handler doesn't
+/// have come from the target, which inherits things like `toString` from `Object.prototype`.
+const SETUP: &[u8] = b"(function (get, set, has, deleteProperty, ownKeys) {
+  return new Proxy({}, {
+    get: function (target, key) {
+      var found = typeof key === 'string' ? get(key) : undefined;
+      return found ? found[0] : target[key];
+    },
+    set: function (target, key, value) {
+      return typeof key === 'string' && set(key, value);
+    },
+    has: function (target, key) {
+      return typeof key === 'string' && has(key);
+    },
+    deleteProperty: function (target, key) {
+      return typeof key !== 'string' || deleteProperty(key);
+    },
+    ownKeys: function () {
+      return ownKeys();
+    },
+    // for-in loops use the ES2015 draft trap instead of ownKeys
+    enumerate: function () {
+      return ownKeys();
+    }
+  });
+})";
+
+/// Pushes a proxy that calls the handler, or the error of creating it, and returns the result of
+/// the call that created it, like `duk_pcall`.
+pub(crate) unsafe fn push(ctx: *mut duktape_sys::duk_context,
+                          handler: rc::Rc<dyn ProxyHandler>)
+                          -> duktape_sys::duk_int_t {
+    use duktape_sys::*;
+
+    let ret = duk_peval_lstring(ctx, SETUP.as_ptr() as *const os::raw::c_char, SETUP.len());
+    assert_eq!(0, ret, "failed to compile the proxy");
+    let get = handler.clone();
+    trap(ctx, 1, move |args| {
+        // Wrapped in an array, to tell missing properties from ones that are `undefined`
+        get.get(&key(args)).map(|value| value.map_or(Value::Undefined, |v| Value::Array(vec![v])))
+    });
+    let set = handler.clone();
+    trap(ctx, 2, move |args| set.set(&key(args), args.values()[1].clone()).map(Value::Boolean));
+    let has = handler.clone();
+    trap(ctx, 1, move |args| has.has(&key(args)).map(Value::Boolean));
+    let delete = handler.clone();
+    trap(ctx, 1, move |args| delete.delete(&key(args)).map(Value::Boolean));
+    trap(ctx, 0, move |_| {
+        handler.keys().map(|keys| Value::Array(keys.into_iter().map(Value::String).collect()))
+    });
+    duk_pcall(ctx, 5)
+}
+
+unsafe fn trap<F>(ctx: *mut duktape_sys::duk_context, nargs: usize, trap: F)
+    where F: Fn(&native::Arguments) -> Result<Value> + 'static
+{
+    native::push(ctx, nargs as duktape_sys::duk_idx_t, Box::new(trap));
+}
+
+/// The key that a trap gets as its first argument, which is always a string.
+fn key(args: &native::Arguments) -> String {
+    match args.values()[0] {
+        Value::String(ref key) => key.clone(),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::cell;
+    use std::collections;
+
+    use super::*;
+    use {Context, JsError, JsErrorKind};
+
+    struct Store {
+        values: cell::RefCell<collections::BTreeMap<String, Value>>,
+    }
+
+    impl ProxyHandler for Store {
+        fn get(&self, key: &str) -> Result<Option<Value>> {
+            Ok(self.values.borrow().get(key).cloned())
+        }
+
+        fn set(&self, key: &str, value: Value) -> Result<bool> {
+            if key.starts_with('_') {
+                return Err(JsError::new(JsErrorKind::Range, "reserved key").into());
+            }
+            self.values.borrow_mut().insert(key.to_owned(), value);
+            Ok(true)
+        }
+
+        fn delete(&self, key: &str) -> Result<bool> {
+            self.values.borrow_mut().remove(key);
+            Ok(true)
+        }
+
+        fn keys(&self) -> Result<Vec<String>> {
+            Ok(self.values.borrow().keys().cloned().collect())
+        }
+    }
+
+    #[test]
+    fn traps() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let result = ctx.register_proxy("store", Store {
+            values: cell::RefCell::new(collections::BTreeMap::new()),
+        });
+        if cfg!(feature = "no-es6-proxy") {
+            assert!(result.is_err());
+            return;
+        }
+        result.unwrap();
+        let result = ctx.eval_string(r"
+          function error(f) { try { f(); } catch (e) { return e.name; } }
+          store.a = 1;
+          store.b = {nested: true};
+          store.c = 3;
+          delete store.c;
+          var keys = [];
+          for (var key in store) { keys.push(key); }
+          [store.a, store.b.nested, 'a' in store, 'c' in store, store.missing, keys.join(),
+           Object.keys(store).join(), error(function () { store._secret = 1; }),
+           String(store)]
+        ")
+            .unwrap()
+            .to_value();
+        assert_eq!(Value::Array(vec![Value::Number(1.0),
+                                     Value::Boolean(true),
+                                     Value::Boolean(true),
+                                     Value::Boolean(false),
+                                     Value::Undefined,
+                                     Value::String("a,b".to_owned()),
+                                     Value::String("a,b".to_owned()),
+                                     Value::String("RangeError".to_owned()),
+                                     Value::String("[object Object]".to_owned())]),
+                   result);
+
+        // A read-only handler
+        struct Empty;
+        impl ProxyHandler for Empty {}
+        ctx.register_proxy("empty", Empty).unwrap();
+        assert_eq!(Value::String("undefined false TypeError".to_owned()),
+                   ctx.eval_string(r"
+                     'use strict';
+                     var name;
+                     try { empty.x = 1; } catch (e) { name = e.name; }
+                     typeof empty.x + ' ' + ('x' in empty) + ' ' + name
+                   ")
+                       .unwrap()
+                       .to_value());
+        ctx.assert_clean();
+    }
+}
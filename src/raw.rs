@@ -0,0 +1,255 @@
+//! Raw access to the underlying Duktape context, for the operations that the safe API doesn't
+//! cover yet, see `Context::with_raw`.
+//!
+//! The raw pointer can be used with the functions of the `duktape-sys` crate (which the
+//! `unstable-raw` feature re-exports as `duk::sys`), but the usual rules of the Duktape C API
+//! apply: calls that throw must be protected, and the value stack must be left as it was found.
+//! `Context::with_raw` checks the latter in debug builds, and panics if the closure leaves values
+//! behind or pops values that it didn't push.  Within the closure, a `StackFrame` discards the
+//! values that were pushed after it, so that early returns don't have to clean up by hand.
+//!
+//! # Re-exports
+//!
+//! The most commonly needed types and constants of the Duktape C API are re-exported here, so
+//! simple raw code doesn't need a `duktape-sys` dependency of its own.  They are not part of the
+//! stable API of this crate: they follow the bundled Duktape version, and may change along with
+//! it.
+//!
+//! # Examples
+//!
+//! ```
+//! extern crate duk;
+//! extern crate duktape_sys;
+//!
+//! # fn main() {
+//! let ctx = duk::Context::new();
+//! // Defines a read-only global, which the safe API can't do yet
+//! ctx.with_raw(|scope| unsafe {
+//!     use duktape_sys::*;
+//!     duk_push_global_object(scope.as_ptr());
+//!     duk_push_string(scope.as_ptr(), b"VERSION\0".as_ptr() as *const _);
+//!     scope.push(&duk::Value::Number(2.0));
+//!     duk_def_prop(scope.as_ptr(), -3, DUK_DEFPROP_HAVE_VALUE | DUK_DEFPROP_HAVE_WRITABLE);
+//!     duk_pop(scope.as_ptr());
+//! });
+//! assert_eq!(duk::Value::Number(2.0),
+//!            ctx.eval_string("VERSION = 3; VERSION").unwrap().to_value());
+//! # }
+//! ```
+
+use std::marker;
+use std::thread;
+
+use duktape_sys;
+
+pub use duktape_sys::{duk_bool_t, duk_c_function, duk_context, duk_idx_t, duk_int_t, duk_ret_t,
+                      duk_uarridx_t, duk_uint_t};
+pub use duktape_sys::{DUK_DEFPROP_CLEAR_CONFIGURABLE, DUK_DEFPROP_CLEAR_ENUMERABLE,
+                      DUK_DEFPROP_CLEAR_WRITABLE, DUK_DEFPROP_CONFIGURABLE, DUK_DEFPROP_ENUMERABLE,
+                      DUK_DEFPROP_FORCE, DUK_DEFPROP_HAVE_CONFIGURABLE,
+                      DUK_DEFPROP_HAVE_ENUMERABLE, DUK_DEFPROP_HAVE_GETTER,
+                      DUK_DEFPROP_HAVE_SETTER, DUK_DEFPROP_HAVE_VALUE, DUK_DEFPROP_HAVE_WRITABLE,
+                      DUK_DEFPROP_SET_CONFIGURABLE, DUK_DEFPROP_SET_ENUMERABLE,
+                      DUK_DEFPROP_SET_WRITABLE, DUK_DEFPROP_WRITABLE};
+pub use duktape_sys::{DUK_ENUM_ARRAY_INDICES_ONLY, DUK_ENUM_INCLUDE_INTERNAL,
+                      DUK_ENUM_INCLUDE_NONENUMERABLE, DUK_ENUM_NO_PROXY_BEHAVIOR,
+                      DUK_ENUM_OWN_PROPERTIES_ONLY, DUK_ENUM_SORT_ARRAY_INDICES};
+pub use duktape_sys::{DUK_ERR_ERROR, DUK_ERR_EVAL_ERROR, DUK_ERR_RANGE_ERROR,
+                      DUK_ERR_REFERENCE_ERROR, DUK_ERR_SYNTAX_ERROR, DUK_ERR_TYPE_ERROR,
+                      DUK_ERR_URI_ERROR, DUK_EXEC_ERROR, DUK_EXEC_SUCCESS, DUK_INVALID_INDEX,
+                      DUK_VARARGS};
+pub use duktape_sys::{DUK_RET_ERROR, DUK_RET_EVAL_ERROR, DUK_RET_RANGE_ERROR,
+                      DUK_RET_REFERENCE_ERROR, DUK_RET_SYNTAX_ERROR, DUK_RET_TYPE_ERROR,
+                      DUK_RET_URI_ERROR};
+pub use duktape_sys::{DUK_TYPE_BOOLEAN, DUK_TYPE_BUFFER, DUK_TYPE_LIGHTFUNC, DUK_TYPE_NONE,
+                      DUK_TYPE_NULL, DUK_TYPE_NUMBER, DUK_TYPE_OBJECT, DUK_TYPE_POINTER,
+                      DUK_TYPE_STRING, DUK_TYPE_UNDEFINED};
+
+use {Context, Value};
+
+/// The underlying Duktape context of a `Context`, valid for the duration of `Context::with_raw`.
+#[derive(Clone, Copy, Debug)]
+pub struct RawScope<'a> {
+    raw: *mut duktape_sys::duk_context,
+    context: marker::PhantomData<&'a Context>,
+}
+
+impl<'a> RawScope<'a> {
+    pub(crate) fn new(raw: *mut duktape_sys::duk_context) -> RawScope<'a> {
+        RawScope {
+            raw,
+            context: marker::PhantomData,
+        }
+    }
+
+    /// Returns the pointer to the Duktape context, for use with the `duktape-sys` crate.
+    pub fn as_ptr(&self) -> *mut duktape_sys::duk_context {
+        self.raw
+    }
+
+    /// Returns the number of values on the value stack.
+    pub fn top(&self) -> usize {
+        unsafe { duktape_sys::duk_get_top(self.raw) as usize }
+    }
+
+    /// Pushes a copy of the value onto the value stack.
+    pub fn push(&self, value: &Value) {
+        unsafe { value.push(self.raw) }
+    }
+
+    /// Converts the value at the specified stack index, which may be negative to count from the
+    /// top, or returns `None` if there's no value at that index.
+    pub fn get(&self, index: isize) -> Option<Value> {
+        unsafe {
+            let index = index as duktape_sys::duk_idx_t;
+            if duktape_sys::duk_is_valid_index(self.raw, index) == 0 {
+                return None;
+            }
+            Some(Value::get(self.raw, duktape_sys::duk_normalize_index(self.raw, index)))
+        }
+    }
+}
+
+/// A guard that restores the height of the value stack when it is dropped, discarding the values
+/// that were pushed after it was created.
+///
+/// Values below the frame can't be restored, so dropping a frame after popping more values than
+/// were pushed panics, which turns silent stack corruption into an early assertion.
+///
+/// # Examples
+///
+/// ```
+/// let ctx = duk::Context::new();
+/// ctx.with_raw(|scope| {
+///     let frame = duk::raw::StackFrame::new(scope);
+///     scope.push(&duk::Value::Number(1.0));
+///     scope.push(&duk::Value::Number(2.0));
+///     assert_eq!(2, frame.len());
+/// });
+/// ```
+#[derive(Debug)]
+pub struct StackFrame<'a> {
+    scope: RawScope<'a>,
+    base: usize,
+}
+
+impl<'a> StackFrame<'a> {
+    /// Starts a frame at the current height of the value stack.
+    pub fn new(scope: RawScope<'a>) -> StackFrame<'a> {
+        StackFrame {
+            scope,
+            base: scope.top(),
+        }
+    }
+
+    /// Returns the height of the value stack when the frame was started.
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// Returns the number of values that were pushed since the frame was started.
+    ///
+    /// # Panics
+    ///
+    /// Panics if more values were popped than pushed.
+    pub fn len(&self) -> usize {
+        self.scope.top().checked_sub(self.base).expect("stack frame popped below its base")
+    }
+
+    /// Returns whether no values were pushed since the frame was started.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<'a> Drop for StackFrame<'a> {
+    fn drop(&mut self) {
+        let top = self.scope.top();
+        if top < self.base {
+            if !thread::panicking() {
+                panic!("stack frame popped {} value(s) below its base", self.base - top);
+            }
+        } else {
+            unsafe {
+                duktape_sys::duk_set_top(self.scope.as_ptr(), self.base as duktape_sys::duk_idx_t);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use duktape_sys;
+
+    use super::*;
+    use {Context, Value};
+
+    #[test]
+    fn raw_scope() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let value = ctx.with_raw(|scope| {
+            scope.push(&Value::Array(vec![Value::Number(1.0), Value::String("a".to_owned())]));
+            scope.push(&Value::Null);
+            let values = (scope.get(-2), scope.get(1), scope.get(2), scope.get(-3));
+            unsafe {
+                duktape_sys::duk_pop_2(scope.as_ptr());
+            }
+            values
+        });
+        assert_eq!((Some(Value::Array(vec![Value::Number(1.0), Value::String("a".to_owned())])),
+                    Some(Value::Null),
+                    None,
+                    None),
+                   value);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn stack_frame() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let popped = ctx.with_raw(|scope| {
+            scope.push(&Value::Number(1.0));
+            {
+                let frame = StackFrame::new(scope);
+                assert_eq!((1, true), (frame.base(), frame.is_empty()));
+                scope.push(&Value::Null);
+                scope.push(&Value::Null);
+                assert_eq!(2, frame.len());
+            }
+            let value = scope.get(-1);
+            unsafe {
+                duktape_sys::duk_pop(scope.as_ptr());
+            }
+            value
+        });
+        assert_eq!(Some(Value::Number(1.0)), popped);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    #[should_panic(expected = "stack frame popped 1 value(s) below its base")]
+    fn stack_frame_underflow() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.with_raw(|scope| {
+            scope.push(&Value::Number(1.0));
+            let _frame = StackFrame::new(scope);
+            unsafe {
+                duktape_sys::duk_pop(scope.as_ptr());
+            }
+        });
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "raw scope left 1 value(s) on the stack")]
+    fn raw_scope_imbalance() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.with_raw(|scope| scope.push(&Value::Undefined));
+    }
+}
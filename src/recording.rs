@@ -0,0 +1,402 @@
+//! Recording of evaluations and calls, and their deterministic replay, see
+//! `Context::start_recording` and `Context::replay`.
+//!
+//! A recording has one line per top-level evaluation (`eval_string`, `eval_file`, ...) or call of
+//! a global function (`call_global`, ...), holding its input and outcome in Duktape's JX format,
+//! for example:
+//!
+//! ```text
+//! {op:"eval",filename:null,source:"function add(a, b) { return a + b; }",result:undefined}
+//! {op:"call",name:"add",args:[1,2],result:3}
+//! {op:"call",name:"missing",args:[],error:"TypeError: undefined not callable"}
+//! ```
+//!
+//! Evaluations and calls made by Rust code that is itself called from Javascript are part of the
+//! outer interaction and are not recorded separately.  Arguments that can't be represented in JX,
+//! like functions, are replayed as placeholders, and scripts that depend on the outside world (for
+//! example through `Date.now()` or Rust callbacks) only replay faithfully if the world behaves the
+//! same way.
+
+use std::fmt;
+use std::io;
+use std::os;
+
+use duktape_sys;
+
+use nul_str;
+use {ErrorKind, Result};
+
+/// The result of replaying a recording.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ReplayReport {
+    /// The number of replayed evaluations and calls.
+    pub interactions: usize,
+    /// The interactions whose outcome differed from the recorded one.
+    pub mismatches: Vec<Mismatch>,
+}
+
+/// An interaction whose outcome differed between recording and replay.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Mismatch {
+    /// The line of the interaction in the recording, starting at 1.
+    pub line: usize,
+    /// A description of the interaction, like `eval app.js` or `call onSave`.
+    pub interaction: String,
+    /// The recorded outcome: the result in JX format, or `error: ` followed by the error.
+    pub expected: String,
+    /// The outcome of the replay, in the same format.
+    pub actual: String,
+}
+
+impl ReplayReport {
+    /// Whether all interactions had the same outcome as when they were recorded.
+    pub fn is_faithful(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "line {}: {}: expected {} but got {}",
+               self.line,
+               self.interaction,
+               self.expected,
+               self.actual)
+    }
+}
+
+/// Writes interactions to the recording.
+pub(crate) struct Recorder {
+    writer: Box<dyn io::Write>,
+    busy: bool,
+    error: Option<io::Error>,
+}
+
+impl Recorder {
+    pub(crate) fn new(writer: Box<dyn io::Write>) -> Recorder {
+        Recorder {
+            writer,
+            busy: false,
+            error: None,
+        }
+    }
+
+    /// Starts an interaction, and returns `false` if it is nested within another one and should
+    /// not be recorded.
+    pub(crate) fn begin(&mut self) -> bool {
+        !::std::mem::replace(&mut self.busy, true)
+    }
+
+    /// Finishes the current interaction, given its encoded input and outcome.
+    pub(crate) fn finish(&mut self, input: &str, outcome: &str) {
+        self.busy = false;
+        if self.error.is_none() {
+            if let Err(e) = writeln!(self.writer, "{{{},{}}}", input, outcome) {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    /// Flushes the recording, and returns the first error that occurred while writing it.
+    pub(crate) fn close(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.writer.flush(),
+        }
+    }
+}
+
+/// Encodes the input of an evaluation.
+pub(crate) unsafe fn eval_input(ctx: *mut duktape_sys::duk_context,
+                         filename: Option<&str>,
+                         source: &str)
+                         -> String {
+    let filename = match filename {
+        Some(filename) => encode_str(ctx, filename),
+        None => "null".to_owned(),
+    };
+    format!("op:\"eval\",filename:{},source:{}", filename, encode_str(ctx, source))
+}
+
+/// Encodes the input of a call of a global function, whose arguments are the top `nargs` values
+/// on the stack.
+pub(crate) unsafe fn call_input(ctx: *mut duktape_sys::duk_context, name: &str, nargs: usize) -> String {
+    let top = duktape_sys::duk_get_top(ctx);
+    let args = (top - nargs as duktape_sys::duk_idx_t..top)
+        .map(|i| encode(ctx, i))
+        .collect::<Vec<_>>();
+    format!("op:\"call\",name:{},args:[{}]", encode_str(ctx, name), args.join(","))
+}
+
+/// Encodes the outcome of an evaluation or call, given the status returned by `duk_pcall` and the
+/// result or error on top of the stack.
+pub(crate) unsafe fn outcome(ctx: *mut duktape_sys::duk_context, ret: duktape_sys::duk_ret_t) -> String {
+    if ret == 0 {
+        format!("result:{}", encode(ctx, -1))
+    } else {
+        duktape_sys::duk_dup(ctx, -1);
+        duktape_sys::duk_safe_to_string(ctx, -1);
+        let error = encode(ctx, -1);
+        duktape_sys::duk_pop(ctx);
+        format!("error:{}", error)
+    }
+}
+
+/// Replays all interactions of a recording.
+pub(crate) unsafe fn replay<R>(ctx: *mut duktape_sys::duk_context, reader: R) -> Result<ReplayReport>
+    where R: io::BufRead
+{
+    use duktape_sys::*;
+
+    let mut report = ReplayReport::default();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        duk_get_global_string(ctx, nul_str(b"Duktape\0"));
+        duk_get_prop_string(ctx, -1, nul_str(b"dec\0"));
+        duk_push_string(ctx, nul_str(b"jx\0"));
+        duk_push_lstring(ctx, line.as_ptr() as *const os::raw::c_char, line.len());
+        // Stack: [ ... Duktape dec "jx" line ]
+        if duk_pcall(ctx, 2) != 0 || duk_is_object(ctx, -1) == 0 {
+            duk_pop_2(ctx);
+            return Err(invalid(i + 1, "not a JX object"));
+        }
+        duk_remove(ctx, -2);
+
+        let result = replay_interaction(ctx, i + 1);
+        duk_pop(ctx);
+        if let Some(mismatch) = result? {
+            report.mismatches.push(mismatch);
+        }
+        report.interactions += 1;
+    }
+    Ok(report)
+}
+
+/// Replays the decoded interaction on top of the stack.
+unsafe fn replay_interaction(ctx: *mut duktape_sys::duk_context,
+                             line: usize)
+                             -> Result<Option<Mismatch>> {
+    use duktape_sys::*;
+
+    let record_idx = duk_get_top(ctx) - 1;
+    let expected = if duk_has_prop_string(ctx, record_idx, nul_str(b"error\0")) == 1 {
+        duk_get_prop_string(ctx, record_idx, nul_str(b"error\0"));
+        let error = format!("error: {}", lossy_string(ctx, -1));
+        duk_pop(ctx);
+        error
+    } else {
+        duk_get_prop_string(ctx, record_idx, nul_str(b"result\0"));
+        let result = encode(ctx, -1);
+        duk_pop(ctx);
+        result
+    };
+
+    let op = string_property(ctx, record_idx, b"op\0");
+    let (interaction, ret) = match op.as_ref().map(|op| &op[..]) {
+        Some("eval") => {
+            duk_get_prop_string(ctx, record_idx, nul_str(b"source\0"));
+            duk_get_prop_string(ctx, record_idx, nul_str(b"filename\0"));
+            if duk_is_string(ctx, -2) == 0 {
+                duk_pop_2(ctx);
+                return Err(invalid(line, "eval without a source"));
+            }
+            let mut len = 0;
+            let source = duk_get_lstring(ctx, -2, &mut len);
+            if duk_is_string(ctx, -1) == 1 {
+                let interaction = format!("eval {}", lossy_string(ctx, -1));
+                // The low bits of the flags hold the number of arguments on the stack (the
+                // filename)
+                let flags = 1 | DUK_COMPILE_EVAL | DUK_COMPILE_NOSOURCE | DUK_COMPILE_SAFE;
+                let ret = duk_eval_raw(ctx, source, len, flags);
+                duk_remove(ctx, -2);
+                (interaction, ret)
+            } else {
+                duk_pop(ctx);
+                let ret = duk_peval_lstring(ctx, source, len);
+                duk_remove(ctx, -2);
+                ("eval".to_owned(), ret)
+            }
+        }
+        Some("call") => {
+            let name = match string_property(ctx, record_idx, b"name\0") {
+                Some(name) => name,
+                None => return Err(invalid(line, "call without a function name")),
+            };
+            duk_push_global_object(ctx);
+            let obj_idx = duk_get_top_index(ctx);
+            duk_push_lstring(ctx, name.as_ptr() as *const os::raw::c_char, name.len());
+            duk_get_prop_string(ctx, record_idx, nul_str(b"args\0"));
+            let nargs = duk_get_length(ctx, -1);
+            duk_require_stack(ctx, nargs as duk_idx_t);
+            for i in 0..nargs {
+                duk_get_prop_index(ctx, obj_idx + 2, i as duk_uarridx_t);
+            }
+            duk_remove(ctx, obj_idx + 2);
+            // Stack: [ ... record global name args... ]
+            let ret = duk_pcall_prop(ctx, obj_idx, nargs as duk_idx_t);
+            duk_remove(ctx, obj_idx);
+            (format!("call {}", name), ret)
+        }
+        _ => return Err(invalid(line, "unknown operation")),
+    };
+
+    let actual = if ret == 0 {
+        encode(ctx, -1)
+    } else {
+        format!("error: {}", lossy_string(ctx, -1))
+    };
+    duk_pop(ctx);
+
+    if actual == expected {
+        Ok(None)
+    } else {
+        Ok(Some(Mismatch {
+            line,
+            interaction,
+            expected,
+            actual,
+        }))
+    }
+}
+
+fn invalid(line: usize, message: &str) -> ::Error {
+    ErrorKind::InvalidRecording(format!("line {}: {}", line, message)).into()
+}
+
+/// Encodes the value at the specified index in the JX format, or its string coercion if it can't
+/// be encoded (like a cyclic structure).
+pub(crate) unsafe fn encode(ctx: *mut duktape_sys::duk_context, index: duktape_sys::duk_idx_t) -> String {
+    let index = duktape_sys::duk_normalize_index(ctx, index);
+    match jx(ctx, index) {
+        Some(encoded) => encoded,
+        None => {
+            duktape_sys::duk_dup(ctx, index);
+            duktape_sys::duk_safe_to_string(ctx, -1);
+            let encoded = jx(ctx, -1).unwrap_or_else(|| "undefined".to_owned());
+            duktape_sys::duk_pop(ctx);
+            encoded
+        }
+    }
+}
+
+unsafe fn encode_str(ctx: *mut duktape_sys::duk_context, string: &str) -> String {
+    duktape_sys::duk_push_lstring(ctx, string.as_ptr() as *const os::raw::c_char, string.len());
+    let encoded = encode(ctx, -1);
+    duktape_sys::duk_pop(ctx);
+    encoded
+}
+
+unsafe fn jx(ctx: *mut duktape_sys::duk_context, index: duktape_sys::duk_idx_t) -> Option<String> {
+    use duktape_sys::*;
+
+    let index = duk_normalize_index(ctx, index);
+    duk_get_global_string(ctx, nul_str(b"Duktape\0"));
+    duk_get_prop_string(ctx, -1, nul_str(b"enc\0"));
+    duk_push_string(ctx, nul_str(b"jx\0"));
+    duk_dup(ctx, index);
+    // Stack: [ ... Duktape enc "jx" value ]
+    let encoded = if duk_pcall(ctx, 2) == 0 && duk_is_string(ctx, -1) == 1 {
+        Some(lossy_string(ctx, -1))
+    } else {
+        None
+    };
+    duk_pop_2(ctx);
+    encoded
+}
+
+unsafe fn string_property(ctx: *mut duktape_sys::duk_context,
+                          index: duktape_sys::duk_idx_t,
+                          name: &[u8])
+                          -> Option<String> {
+    duktape_sys::duk_get_prop_string(ctx, index, nul_str(name));
+    let value = if duktape_sys::duk_is_string(ctx, -1) == 1 {
+        Some(lossy_string(ctx, -1))
+    } else {
+        None
+    };
+    duktape_sys::duk_pop(ctx);
+    value
+}
+
+unsafe fn lossy_string(ctx: *mut duktape_sys::duk_context, index: duktape_sys::duk_idx_t) -> String {
+    let mut len = 0;
+    let data = duktape_sys::duk_safe_to_lstring(ctx, index, &mut len);
+    String::from_utf8_lossy(::std::slice::from_raw_parts(data as *const u8, len)).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::cell;
+    use std::io;
+    use std::rc;
+
+    use {Context, Value};
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(rc::Rc<cell::RefCell<Vec<u8>>>);
+
+    impl io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let _ = env_logger::init();
+        let buffer = SharedBuffer::default();
+        let ctx = Context::new();
+        ctx.start_recording(buffer.clone());
+        ctx.eval_string_with_filename("app.js", "var total = 0; function add(n) { return total += n; }")
+            .unwrap();
+        ctx.call_global("add", &[&Value::Number(2.0)]).unwrap();
+        ctx.call_global_void("add", &[&Value::Number(3.0)]).unwrap();
+        ctx.eval_discard("({total: total, items: [1, 'a']})").unwrap();
+        assert!(ctx.call_global("missing", &[]).is_err());
+        ctx.stop_recording().unwrap();
+        ctx.eval_string("add(100)").unwrap();
+        ctx.assert_clean();
+
+        let recording = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        let lines = recording.lines().collect::<Vec<_>>();
+        assert_eq!(5, lines.len());
+        assert_eq!("{op:\"call\",name:\"add\",args:[2],result:2}", lines[1]);
+        assert_eq!("{op:\"eval\",filename:null,source:\"({total: total, items: [1, 'a']})\",\
+                    result:{total:5,items:[1,\"a\"]}}",
+                   lines[3]);
+
+        let replay_ctx = Context::new();
+        let report = replay_ctx.replay(recording.as_bytes()).unwrap();
+        assert_eq!(5, report.interactions);
+        assert!(report.is_faithful(), "{:?}", report.mismatches);
+        assert_eq!(Value::Number(5.0), replay_ctx.eval_string("total").unwrap().to_value());
+        replay_ctx.assert_clean();
+    }
+
+    #[test]
+    fn replay_mismatch() {
+        let _ = env_logger::init();
+        let recording = "{op:\"eval\",filename:\"a.js\",source:\"var x = 1; x\",result:1}\n\
+                         {op:\"call\",name:\"String\",args:[2],result:\"3\"}\n";
+        let ctx = Context::new();
+        let report = ctx.replay(recording.as_bytes()).unwrap();
+        assert_eq!(2, report.interactions);
+        assert_eq!(1, report.mismatches.len());
+        assert_eq!("line 2: call String: expected \"3\" but got \"2\"",
+                   report.mismatches[0].to_string());
+
+        assert!(ctx.replay("{op:\"jump\"}".as_bytes()).is_err());
+        ctx.assert_clean();
+    }
+}
@@ -0,0 +1,518 @@
+//! An interactive read-eval-print loop that can be attached to a live context, see `Repl`.
+//!
+//! Input is read line by line, and lines are accumulated for as long as the input has unclosed
+//! brackets, block comments or continued strings, so that functions and object literals can be
+//! entered over several lines.  An empty line forces the evaluation of incomplete input.  Results
+//! are printed in Duktape's JX format with indentation, functions by name and errors with their
+//! stack trace.
+//!
+//! `Repl::run` reads plain lines and writes the prompts itself.  Hosts with a line editor
+//! implement `LineReader` instead and use `Repl::run_with`; the editor gets the `Repl`, whose
+//! `complete` method suggests global and property names by inspecting the live context (or asks a
+//! custom `Completer` first).
+//!
+//! # Examples
+//!
+//! ```
+//! let ctx = duk::Context::new();
+//! let mut out = Vec::new();
+//! duk::repl::Repl::new(&ctx)
+//!     .run("function twice(n) {\n  return 2 * n;\n}\ntwice(21)\n".as_bytes(), &mut out)
+//!     .unwrap();
+//! assert_eq!("> ... ... undefined\n> 42\n> ", String::from_utf8(out).unwrap());
+//!
+//! let completion = duk::repl::Repl::new(&ctx).complete("Math.ma", 7);
+//! assert_eq!((5, vec!["max".to_owned()]), (completion.start, completion.candidates));
+//! ```
+
+use std::io;
+use std::os;
+use std::slice;
+
+use duktape_sys;
+
+use strings;
+use {Argument, Context, ErrorKind, Reference};
+
+/// Formats a value for display.
+const FORMAT: &[u8] = b"(function (v) {
+  if (typeof v === 'function') {
+    // Lightfuncs, like the built-ins of the low-memory profile, are named after their address
+    var name = /^light_[0-9a-f]+_[0-9a-f]{4}$/.test(v.name) ? '' : v.name;
+    return '[Function' + (name ? ': ' + name : '') + ']';
+  }
+  if (v instanceof Error) {
+    return String(v.stack || v);
+  }
+  try {
+    return Duktape.enc('jx', v, null, 2);
+  } catch (e) {
+    return String(v);
+  }
+})";
+
+/// Lists the names of the properties of an object, including inherited and non-enumerable ones,
+/// that start with a prefix.  The object is found by following a path of property names from the
+/// global object.
+const COMPLETE: &[u8] = b"(function (global, path, prefix) {
+  var o = global;
+  for (var i = 0; i < path.length && o !== null && o !== undefined; i++) {
+    o = o[path[i]];
+  }
+  if (o === null || o === undefined) {
+    return [];
+  }
+  var seen = Object.create(null), names = [];
+  for (o = Object(o); o !== null; o = Object.getPrototypeOf(o)) {
+    Object.getOwnPropertyNames(o).forEach(function (name) {
+      if (name.lastIndexOf(prefix, 0) === 0 && seen[name] !== true) {
+        seen[name] = true;
+        names.push(name);
+      }
+    });
+  }
+  return names.sort();
+})";
+
+/// A read-eval-print loop for a context.
+pub struct Repl<'a> {
+    ctx: &'a Context,
+    prompt: String,
+    continuation_prompt: String,
+    filename: String,
+    completer: Option<Box<dyn Completer + 'a>>,
+}
+
+/// Suggestions for completing the word before the cursor, see `Repl::complete`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Completion {
+    /// The byte offset in the line where the completed word starts; a candidate replaces the text
+    /// from there up to the cursor.
+    pub start: usize,
+    /// The possible replacements, in order.
+    pub candidates: Vec<String>,
+}
+
+/// Reads lines of input for `Repl::run_with`, typically with a line editor.
+pub trait LineReader {
+    /// Shows the prompt and reads the next line, without its line terminator, or returns `None` at
+    /// the end of the input.  Editors can offer completions with `Repl::complete`.
+    fn read_line(&mut self, prompt: &str, repl: &Repl) -> io::Result<Option<String>>;
+}
+
+/// Completes input in host-specific ways, like the commands of an admin console, see
+/// `Repl::with_completer`.
+///
+/// Closures taking the line and the cursor position are completers too.
+pub trait Completer {
+    /// Returns the completion for the line with the cursor at the byte offset `pos`, or `None` to
+    /// fall back to completing global and property names.
+    fn complete(&self, line: &str, pos: usize) -> Option<Completion>;
+}
+
+impl<F> Completer for F
+    where F: Fn(&str, usize) -> Option<Completion>
+{
+    fn complete(&self, line: &str, pos: usize) -> Option<Completion> {
+        self(line, pos)
+    }
+}
+
+impl<'a> Repl<'a> {
+    /// Creates a loop that evaluates code in the specified context, with the `> ` prompt.
+    pub fn new(ctx: &'a Context) -> Repl<'a> {
+        Repl {
+            ctx,
+            prompt: "> ".to_owned(),
+            continuation_prompt: "... ".to_owned(),
+            filename: "repl".to_owned(),
+            completer: None,
+        }
+    }
+
+    /// Sets the prompt that is written before reading a new input.
+    pub fn with_prompt(mut self, prompt: &str) -> Self {
+        self.prompt = prompt.to_owned();
+        self
+    }
+
+    /// Sets the prompt that is written before reading the next line of an incomplete input.
+    pub fn with_continuation_prompt(mut self, prompt: &str) -> Self {
+        self.continuation_prompt = prompt.to_owned();
+        self
+    }
+
+    /// Sets the file name of the evaluated code, as it appears in stack traces.
+    pub fn with_filename(mut self, filename: &str) -> Self {
+        self.filename = filename.to_owned();
+        self
+    }
+
+    /// Consults the specified completer before completing global and property names.
+    pub fn with_completer(mut self, completer: Box<dyn Completer + 'a>) -> Self {
+        self.completer = Some(completer);
+        self
+    }
+
+    /// Reads inputs until the end of the reader, and writes the prompts and the formatted results
+    /// to the writer.
+    pub fn run<R, W>(&self, input: R, mut output: W) -> io::Result<()>
+        where R: io::BufRead,
+              W: io::Write
+    {
+        let mut source = String::new();
+        write!(output, "{}", self.prompt)?;
+        output.flush()?;
+        for line in input.lines() {
+            if let Some(result) = self.accept(&mut source, &line?) {
+                writeln!(output, "{}", result)?;
+            }
+            write!(output, "{}", self.current_prompt(&source))?;
+            output.flush()?;
+        }
+        self.finish(&source, &mut output)
+    }
+
+    /// Like `run`, but reads the inputs with the specified reader, which shows the prompts
+    /// itself.
+    pub fn run_with<L, W>(&self, reader: &mut L, mut output: W) -> io::Result<()>
+        where L: LineReader,
+              W: io::Write
+    {
+        let mut source = String::new();
+        while let Some(line) = reader.read_line(self.current_prompt(&source), self)? {
+            if let Some(result) = self.accept(&mut source, &line) {
+                writeln!(output, "{}", result)?;
+                output.flush()?;
+            }
+        }
+        self.finish(&source, &mut output)
+    }
+
+    /// Suggests completions for the word before the cursor, which is at the byte offset `pos` of
+    /// the line.
+    ///
+    /// Unless the completer of the loop has a suggestion, a trailing name like `con` is completed
+    /// with the names of globals, and a trailing path like `config.na` with the names of the
+    /// properties of the object it leads to, including inherited ones.  Following the path reads
+    /// properties, so it runs getters, but nothing is called otherwise.
+    pub fn complete(&self, line: &str, pos: usize) -> Completion {
+        if let Some(completion) = self.completer.as_ref().and_then(|c| c.complete(line, pos)) {
+            return completion;
+        }
+        let (path, prefix) = match trailing_path(&line[..pos]) {
+            Some(path) => path,
+            None => return Completion { start: pos, candidates: Vec::new() },
+        };
+        Completion {
+            start: pos - prefix.len(),
+            candidates: unsafe { property_names(self.ctx.raw, &path, prefix) },
+        }
+    }
+
+    /// Adds a line to the pending source, and evaluates the source once it is complete, returning
+    /// the formatted result.
+    fn accept(&self, source: &mut String, line: &str) -> Option<String> {
+        let force = line.trim().is_empty();
+        source.push_str(line);
+        source.push('\n');
+        if !force && !is_complete(source) {
+            return None;
+        }
+        let result = if source.trim().is_empty() { None } else { Some(self.eval(source)) };
+        source.clear();
+        result
+    }
+
+    /// Evaluates what is left of the source at the end of the input.
+    fn finish<W>(&self, source: &str, output: &mut W) -> io::Result<()>
+        where W: io::Write
+    {
+        if !source.trim().is_empty() {
+            writeln!(output, "{}", self.eval(source))?;
+        }
+        Ok(())
+    }
+
+    fn current_prompt(&self, source: &str) -> &str {
+        if source.is_empty() { &self.prompt } else { &self.continuation_prompt }
+    }
+
+    /// Evaluates the source code, and returns the formatted result or error.
+    pub fn eval(&self, source: &str) -> String {
+        match self.ctx.eval_string_with_filename(&self.filename, source) {
+            Ok(result) => format(&result),
+            Err(e) => {
+                match e.0 {
+                    ErrorKind::Js(ref error) => {
+                        match error.stack {
+                            Some(ref stack) => stack.clone(),
+                            None => format!("{}: {}", error.kind.name(), error.message),
+                        }
+                    }
+                    ref other => other.to_string(),
+                }
+            }
+        }
+    }
+}
+
+/// Formats the referenced value for display.
+fn format(value: &Reference) -> String {
+    use duktape_sys::*;
+
+    let ctx = value.ctx.raw;
+    unsafe {
+        if duk_peval_lstring(ctx, FORMAT.as_ptr() as *const os::raw::c_char, FORMAT.len()) != 0 {
+            duk_pop(ctx);
+            return "[unformattable]".to_owned();
+        }
+        value.push_to_context(value.ctx);
+        duk_pcall(ctx, 1);
+        let mut len = 0;
+        let data = duk_safe_to_lstring(ctx, -1, &mut len);
+        let formatted = String::from_utf8_lossy(slice::from_raw_parts(data as *const u8, len))
+            .into_owned();
+        duk_pop(ctx);
+        formatted
+    }
+}
+
+/// Splits the dotted path of names at the end of the text into the names of the objects and the
+/// unfinished last name, or returns `None` if the text doesn't end with such a path.
+fn trailing_path(text: &str) -> Option<(Vec<&str>, &str)> {
+    let is_name_char = |c: char| c.is_alphanumeric() || c == '_' || c == '$' || c == '.';
+    let start = text.rfind(|c| !is_name_char(c)).map_or(0, |i| i + 1);
+    let mut names = text[start..].split('.').collect::<Vec<_>>();
+    let prefix = names.pop().unwrap_or("");
+    let is_name = |name: &&str| name.chars().next().is_some_and(|c| !c.is_numeric());
+    if names.iter().all(is_name) && (prefix.is_empty() || is_name(&prefix)) {
+        Some((names, prefix))
+    } else {
+        None
+    }
+}
+
+/// Lists the property names for a completion, see `COMPLETE`.
+unsafe fn property_names(ctx: *mut duktape_sys::duk_context,
+                         path: &[&str],
+                         prefix: &str)
+                         -> Vec<String> {
+    use duktape_sys::*;
+
+    if duk_peval_lstring(ctx, COMPLETE.as_ptr() as *const os::raw::c_char, COMPLETE.len()) != 0 {
+        duk_pop(ctx);
+        return Vec::new();
+    }
+    duk_push_global_object(ctx);
+    duk_push_array(ctx);
+    for (i, name) in path.iter().enumerate() {
+        strings::push(ctx, name);
+        duk_put_prop_index(ctx, -2, i as duk_uarridx_t);
+    }
+    strings::push(ctx, prefix);
+    let mut names = Vec::new();
+    if duk_pcall(ctx, 3) == 0 {
+        for i in 0..duk_get_length(ctx, -1) {
+            duk_get_prop_index(ctx, -1, i as duk_uarridx_t);
+            names.push(strings::get(ctx, -1));
+            duk_pop(ctx);
+        }
+    }
+    duk_pop(ctx);
+    names
+}
+
+/// Checks whether the source code could be complete, or whether it has unclosed brackets, block
+/// comments, strings or regular expressions.  Unbalanced closing brackets count as complete,
+/// since more input can't fix them.
+fn is_complete(source: &str) -> bool {
+    #[derive(Clone, Copy)]
+    enum State {
+        Code,
+        Str(char),
+        Regex { class: bool },
+        LineComment,
+        BlockComment,
+    }
+
+    let mut state = State::Code;
+    let mut depth = 0usize;
+    // The last significant character of code, to tell divisions from regular expressions
+    let mut last = None;
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match state {
+            State::Code => {
+                match c {
+                    '\'' | '"' => state = State::Str(c),
+                    '/' if chars.peek() == Some(&'/') => state = State::LineComment,
+                    '/' if chars.peek() == Some(&'*') => {
+                        chars.next();
+                        state = State::BlockComment;
+                    }
+                    '/' if last.is_none_or(|l| "(,=:[!&|?{};+-*%<>~^".contains(l)) => {
+                        state = State::Regex { class: false }
+                    }
+                    '(' | '[' | '{' => depth += 1,
+                    ')' | ']' | '}' => {
+                        if depth == 0 {
+                            return true;
+                        }
+                        depth -= 1;
+                    }
+                    _ => {}
+                }
+                if !c.is_whitespace() {
+                    last = Some(c);
+                }
+            }
+            State::Str(quote) => {
+                if c == '\\' {
+                    chars.next();
+                } else if c == quote || c == '\n' {
+                    state = State::Code;
+                    last = Some(quote);
+                }
+            }
+            State::Regex { class } => {
+                match c {
+                    '\\' => {
+                        chars.next();
+                    }
+                    '[' => state = State::Regex { class: true },
+                    ']' => state = State::Regex { class: false },
+                    '/' if !class => {
+                        state = State::Code;
+                        last = Some('/');
+                    }
+                    '\n' => return true,
+                    _ => {}
+                }
+            }
+            State::LineComment => {
+                if c == '\n' {
+                    state = State::Code;
+                }
+            }
+            State::BlockComment => {
+                if c == '*' && chars.peek() == Some(&'/') {
+                    chars.next();
+                    state = State::Code;
+                }
+            }
+        }
+    }
+    match state {
+        State::Code | State::LineComment => depth == 0,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use super::*;
+
+    #[test]
+    fn multi_line_input() {
+        assert!(is_complete("1 + 1\n"));
+        assert!(!is_complete("function f() {\n"));
+        assert!(!is_complete("var o = {a: [1,\n"));
+        assert!(is_complete("var s = '{('; // [\n"));
+        assert!(!is_complete("/* {} \n"));
+        assert!(!is_complete("var s = 'a\\\n"));
+        assert!(is_complete("var r = /[)}]/g;\n"));
+        assert!(is_complete("var half = (4) / 2; var x = {}\n"));
+        assert!(is_complete("}\n"));
+    }
+
+    #[test]
+    fn repl_session() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.eval_string("var config = {name: 'app', retries: 3};").unwrap();
+        let input = "config\nvar point = {\n  x: 1,\n\n[1, 'a'].map(function (v) {\n  return \
+                     v + v;\n})\nnull.x\nMath.max\n";
+        let mut output = Vec::new();
+        Repl::new(&ctx)
+            .with_prompt("js> ")
+            .with_continuation_prompt("  | ")
+            .run(input.as_bytes(), &mut output)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let expected_start = "js> {\n  name: \"app\",\n  retries: 3\n}\njs>   |   | SyntaxError";
+        assert!(output.starts_with(expected_start), "{}", output);
+        assert!(output.contains("js>   |   | [\n  2,\n  \"aa\"\n]\njs> TypeError"),
+                "{}",
+                output);
+        // Built-in functions are nameless lightfuncs in the low-memory profile
+        let max = if cfg!(feature = "low-memory") { "[Function]" } else { "[Function: max]" };
+        assert!(output.ends_with(&format!("js> {}\njs> ", max)), "{}", output);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn completion() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.eval_string("var config = {name: 'app', retries: 3, nested: {deep: true}};").unwrap();
+        let repl = Repl::new(&ctx);
+        let complete = |line: &str| {
+            let completion = repl.complete(line, line.len());
+            (completion.start, completion.candidates)
+        };
+        assert_eq!((4, vec!["config".to_owned()]), complete("1 + conf"));
+        assert_eq!((7, vec!["name".to_owned(), "nested".to_owned()]), complete("config.n"));
+        assert_eq!((14, vec!["deep".to_owned()]), complete("config.nested.d"));
+        assert_eq!((12, vec!["toUpperCase".to_owned()]), complete("config.name.toUp"));
+        assert_eq!((15, Vec::<String>::new()), complete("config.missing."));
+        assert_eq!((3, Vec::<String>::new()), complete("1.5"));
+        assert_eq!(Completion { start: 2, candidates: vec!["config".to_owned()] },
+                   repl.complete("x(conf)", 6));
+
+        let repl = Repl::new(&ctx).with_completer(Box::new(|line: &str, _: usize| {
+            if line.starts_with(':') {
+                Some(Completion { start: 0, candidates: vec![":quit".to_owned()] })
+            } else {
+                None
+            }
+        }));
+        assert_eq!(vec![":quit".to_owned()], repl.complete(":q", 2).candidates);
+        assert_eq!(vec!["retries".to_owned()], repl.complete("config.r", 8).candidates);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn line_reader() {
+        struct Scripted(Vec<&'static str>, Vec<String>);
+
+        impl LineReader for Scripted {
+            fn read_line(&mut self, prompt: &str, repl: &Repl) -> io::Result<Option<String>> {
+                self.1.push(prompt.to_owned());
+                if self.0.is_empty() {
+                    return Ok(None);
+                }
+                // Complete unambiguous names, like pressing tab would
+                let line = self.0.remove(0);
+                let completion = repl.complete(line, line.len());
+                if completion.candidates.len() == 1 {
+                    Ok(Some(format!("{}{}", &line[..completion.start], completion.candidates[0])))
+                } else {
+                    Ok(Some(line.to_owned()))
+                }
+            }
+        }
+
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.eval_string("var answer = 42;").unwrap();
+        let mut reader = Scripted(vec!["[", "1]", "ans"], Vec::new());
+        let mut output = Vec::new();
+        Repl::new(&ctx).run_with(&mut reader, &mut output).unwrap();
+        assert_eq!(vec!["> ", "... ", "> ", "> "], reader.1);
+        assert_eq!("[\n  1\n]\n42\n", String::from_utf8(output).unwrap());
+        ctx.assert_clean();
+    }
+}
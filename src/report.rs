@@ -0,0 +1,184 @@
+//! Reporting of uncaught Javascript errors, see `ContextBuilder::with_error_reporter`.
+//!
+//! Every evaluation or call that fails with a Javascript error produces an `ErrorReport`, which
+//! combines the error with the line of source code where it was thrown, and is handed to an
+//! `ErrorSink`.  The source line is looked up in the code that was evaluated through the context,
+//! or read from the file system if the error comes from a file (for example the original file of
+//! a source-mapped script).
+
+use std::cell;
+use std::collections;
+use std::fmt;
+use std::fs;
+
+use sources;
+use JsError;
+
+/// A formatted description of an uncaught error.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ErrorReport {
+    /// The name of the error, like `TypeError`.
+    pub name: String,
+    /// The error message.
+    pub message: String,
+    /// The file where the error was thrown, after applying source maps.
+    pub file_name: Option<String>,
+    /// The line where the error was thrown, after applying source maps.
+    pub line_number: Option<usize>,
+    /// The source code of the line where the error was thrown, if it is known.
+    pub source_line: Option<String>,
+    /// The stack trace, after applying source maps.
+    pub stack: Option<String>,
+}
+
+/// Receives reports of uncaught errors.
+pub trait ErrorSink {
+    /// Handles the report of an uncaught error.
+    fn report(&self, report: &ErrorReport);
+}
+
+impl<F> ErrorSink for F
+    where F: Fn(&ErrorReport)
+{
+    fn report(&self, report: &ErrorReport) {
+        self(report)
+    }
+}
+
+/// An `ErrorSink` that logs reports with the `error!` macro and the `duk::errors` target.
+#[cfg(feature = "logging")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LogSink;
+
+#[cfg(feature = "logging")]
+impl ErrorSink for LogSink {
+    fn report(&self, report: &ErrorReport) {
+        error!(target: "duk::errors", "{}", report);
+    }
+}
+
+impl fmt::Display for ErrorReport {
+    /// Formats the report like:
+    ///
+    /// ```text
+    /// TypeError: undefined not callable
+    ///   at app.js:3
+    ///   3 | config.load();
+    /// ```
+    ///
+    /// followed by the stack trace, if any.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.name, self.message)?;
+        if let Some(ref file_name) = self.file_name {
+            match self.line_number {
+                Some(line) => write!(f, "\n  at {}:{}", file_name, line)?,
+                None => write!(f, "\n  at {}", file_name)?,
+            }
+        }
+        if let (Some(line), Some(ref source)) = (self.line_number, &self.source_line) {
+            write!(f, "\n  {} | {}", line, source)?;
+        }
+        if let Some(ref stack) = self.stack {
+            // The first line of the stack repeats the name and message
+            for frame in stack.lines().skip(1) {
+                write!(f, "\n{}", frame)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Builds reports and passes them to the sink, remembering the evaluated sources to look up
+/// source lines.
+pub(crate) struct Reporter {
+    sink: Box<dyn ErrorSink>,
+    sources: cell::RefCell<collections::HashMap<String, String>>,
+}
+
+impl Reporter {
+    pub(crate) fn new(sink: Box<dyn ErrorSink>) -> Reporter {
+        Reporter {
+            sink,
+            sources: cell::RefCell::new(collections::HashMap::new()),
+        }
+    }
+
+    /// Remembers the source code that was evaluated with the specified file name.
+    pub(crate) fn remember(&self, file_name: &str, source: &str) {
+        self.sources.borrow_mut().insert(file_name.to_owned(), source.to_owned());
+    }
+
+    pub(crate) fn report(&self, error: &JsError) {
+        let source_line = match (&error.file_name, error.line_number) {
+            (Some(file_name), Some(line)) if line > 0 => self.source_line(file_name, line),
+            _ => None,
+        };
+        let report = ErrorReport {
+            name: error.kind.name().to_owned(),
+            message: error.message.clone(),
+            file_name: error.file_name.clone(),
+            line_number: error.line_number,
+            source_line,
+            stack: error.stack.clone(),
+        };
+        self.sink.report(&report);
+    }
+
+    fn source_line(&self, file_name: &str, line: usize) -> Option<String> {
+        let nth_line = |source: &str| source.lines().nth(line - 1).map(|l| l.trim_end().to_owned());
+        if let Some(source) = self.sources.borrow().get(file_name) {
+            return nth_line(source);
+        }
+        fs::read(file_name).ok().and_then(|bytes| nth_line(&sources::decode(&bytes)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::cell;
+    use std::rc;
+
+    use super::*;
+
+    use Context;
+
+    #[test]
+    fn report_uncaught() {
+        let _ = env_logger::init();
+        let reports = rc::Rc::new(cell::RefCell::new(Vec::new()));
+        let sink = reports.clone();
+        let ctx = Context::builder()
+            .with_error_reporter(Box::new(move |r: &ErrorReport| sink.borrow_mut().push(r.clone())))
+            .build();
+
+        ctx.eval_string_with_filename("app.js",
+                                       "function load(config) {\n  return config.path;\n}\n")
+            .unwrap();
+        assert!(ctx.call_global("load", &[]).is_err());
+        assert!(ctx.eval_string("var ok = 1;\nthrow new RangeError('too far');").is_err());
+        ctx.eval_string("'no error'").unwrap();
+
+        let reports = reports.borrow();
+        assert_eq!(2, reports.len());
+        let report = &reports[0];
+        assert_eq!("TypeError", report.name);
+        assert_eq!(Some("app.js".to_owned()), report.file_name);
+        assert_eq!(Some(2), report.line_number);
+        assert_eq!(Some("  return config.path;".to_owned()), report.source_line);
+        let formatted = report.to_string();
+        assert!(formatted.starts_with(&format!("TypeError: {}\n  at app.js:2\n  2 |   return \
+                                                 config.path;\n",
+                                                report.message)),
+                "{}",
+                formatted);
+        assert!(formatted.contains("load"), "{}", formatted);
+
+        assert_eq!("RangeError", reports[1].name);
+        assert_eq!("too far", reports[1].message);
+        assert_eq!(Some("throw new RangeError('too far');".to_owned()),
+                   reports[1].source_line);
+        ctx.assert_clean();
+    }
+}
@@ -0,0 +1,138 @@
+//! Checking detached signatures of script files before they run, see
+//! `ContextBuilder::with_verifier`.
+//!
+//! Hosts that run third-party plugins can require every script file to be signed.  With a
+//! verifier, `Context::eval_file`, `load_file`, `load_all`, `load_project` and `load_bundle` read
+//! the signature of each file from the file of the same name with `.sig` appended (so
+//! `plugin.js.sig` for `plugin.js`), and hand the contents and the signature to the verifier
+//! before anything is compiled.  Files without a signature, or whose signature the verifier
+//! rejects, fail with `ErrorKind::Untrusted` (which `load_all` and `load_project` wrap in their
+//! `ErrorKind::Load` error for the file).  Code that the host evaluates from strings isn't
+//! checked.
+//!
+//! The signature scheme is up to the verifier, which usually checks the signature against a set
+//! of public keys that the host trusts.  The signature covers the exact bytes of the file.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::path;
+//!
+//! struct TrustedKeys {
+//!     keys: Vec<Vec<u8>>,
+//! }
+//!
+//! impl duk::signatures::Verifier for TrustedKeys {
+//!     fn verify(&self,
+//!               _path: &path::Path,
+//!               contents: &[u8],
+//!               signature: &[u8])
+//!               -> Result<(), String> {
+//!         if self.keys.iter().any(|key| ed25519_verify(key, contents, signature)) {
+//!             Ok(())
+//!         } else {
+//!             Err("not signed by a trusted key".to_owned())
+//!         }
+//!     }
+//! }
+//! # fn ed25519_verify(_: &[u8], _: &[u8], _: &[u8]) -> bool { true }
+//!
+//! let keys = TrustedKeys { keys: vec![b"public key".to_vec()] };
+//! let ctx = duk::Context::builder().with_verifier(Box::new(keys)).build();
+//! ctx.eval_file("plugins/weather.js").unwrap();
+//! ```
+
+use std::ffi;
+use std::fs;
+use std::path;
+use std::result;
+
+use {ErrorKind, Result};
+
+/// Checks the signatures of script files.
+pub trait Verifier {
+    /// Checks the detached signature of the contents of the file at `path`, or returns why it
+    /// isn't valid.
+    fn verify(&self, path: &path::Path, contents: &[u8], signature: &[u8])
+              -> result::Result<(), String>;
+}
+
+impl<F> Verifier for F
+    where F: Fn(&path::Path, &[u8], &[u8]) -> result::Result<(), String>
+{
+    fn verify(&self, path: &path::Path, contents: &[u8], signature: &[u8])
+              -> result::Result<(), String> {
+        self(path, contents, signature)
+    }
+}
+
+/// The path of the detached signature of a file, which has `.sig` appended to it.
+pub fn signature_path(path: &path::Path) -> path::PathBuf {
+    let mut signature_path = ffi::OsString::from(path.as_os_str());
+    signature_path.push(".sig");
+    path::PathBuf::from(signature_path)
+}
+
+/// Reads a file, and checks its signature if there is a verifier.
+pub(crate) fn read(verifier: Option<&dyn Verifier>, path: &path::Path) -> Result<Vec<u8>> {
+    let contents = fs::read(path)?;
+    if let Some(verifier) = verifier {
+        let untrusted = |reason: String| ErrorKind::Untrusted(path.display().to_string(), reason);
+        let signature = fs::read(signature_path(path))
+            .map_err(|e| untrusted(format!("the signature can't be read: {}", e)))?;
+        verifier.verify(path, &contents, &signature).map_err(untrusted)?;
+    }
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::fs;
+
+    use super::*;
+    use testing::ScratchDir;
+    use {Context, ErrorKind};
+
+    /// Accepts signatures that are the contents reversed, which is good enough for tests.
+    fn reversed(_: &path::Path, contents: &[u8], signature: &[u8]) -> result::Result<(), String> {
+        if contents.iter().rev().eq(signature) {
+            Ok(())
+        } else {
+            Err("bad signature".to_owned())
+        }
+    }
+
+    #[test]
+    #[cfg_attr(target_os = "wasi", ignore = "needs a temporary directory")]
+    fn verified_files() {
+        let _ = env_logger::init();
+        let dir = ScratchDir::new("signatures");
+        let signed = dir.join("signed.js");
+        fs::write(&signed, "var signed = true;").unwrap();
+        fs::write(signature_path(&signed), ";eurt = dengis rav").unwrap();
+        let tampered = dir.join("tampered.js");
+        fs::write(&tampered, "var tampered = true;").unwrap();
+        fs::write(signature_path(&tampered), ";eurt = dengis rav").unwrap();
+        let unsigned = dir.join("unsigned.js");
+        fs::write(&unsigned, "var unsigned = true;").unwrap();
+
+        let ctx = Context::builder().with_verifier(Box::new(reversed)).build();
+        ctx.eval_file(&signed).unwrap();
+        for path in &[&tampered, &unsigned] {
+            match *ctx.load_file(path).unwrap_err().kind() {
+                ErrorKind::Untrusted(ref file, _) => assert_eq!(path.display().to_string(), *file),
+                ref kind => panic!("unexpected error {:?}", kind),
+            }
+        }
+        assert!(ctx.load_all(&[&dir]).is_err());
+        // Nothing from the untrusted files ran
+        assert_eq!(::Value::String("undefined undefined".to_owned()),
+                   ctx.eval_string("typeof tampered + ' ' + typeof unsigned").unwrap().to_value());
+
+        // Without a verifier, files don't need signatures
+        Context::new().load_file(&unsigned).unwrap();
+        ctx.assert_clean();
+    }
+}
@@ -0,0 +1,213 @@
+//! Snapshots of the globals that scripts defined, see `Context::snapshot`.
+//!
+//! Initializing a plugin environment, by loading and compiling its scripts and running their
+//! setup code, can take much longer than starting the process that hosts it.  A snapshot captures
+//! the result, so that a later process can restore it with `Context::from_snapshot` (or
+//! `ContextBuilder::build_from_snapshot`, to install the same host functions first) instead.
+//!
+//! A snapshot holds the globals that a fresh context doesn't have, copied like
+//! `structured_clone` copies values, with these differences:
+//!
+//!   * Compiled functions are kept as Duktape bytecode, along with their own enumerable
+//!     properties and their `prototype`.  A restored function runs in the global scope, so it
+//!     can still use other globals, but it loses the local variables of any function that it was
+//!     defined in.
+//!   * Objects, arrays and functions keep their prototype when it's one of the copied objects
+//!     (like the prototype of a constructor that a script defined) or `null`.  Other prototypes
+//!     are replaced with the default one.
+//!
+//! Globals that can't be copied, like native functions and objects that reference them, are left
+//! out, and so are changes to the built-in globals and to what the `ContextBuilder` set up.
+//! Restoring assigns each captured global in turn.
+//!
+//! Build scripts can take a snapshot at build time with the `duk-build` crate, and the host then
+//! embeds it with `include_snapshot!`.
+//!
+//! Like bundles, snapshots record the version and configuration of Duktape, and restoring
+//! checks that they match.  Bytecode is not validated otherwise, and Duktape may crash on
+//! bytecode that was tampered with, so only restore snapshots from trusted sources.
+//!
+//! # Examples
+//!
+//! ```
+//! let ctx = duk::Context::new();
+//! ctx.eval_string("var greeting = 'hello';
+//!                  function greet(name) { return greeting + ' ' + name; }")
+//!     .unwrap();
+//! let snapshot = ctx.snapshot();
+//!
+//! let restored = duk::Context::from_snapshot(&snapshot).unwrap();
+//! assert_eq!(duk::Value::String("hello world".to_owned()),
+//!            restored.call("greet", ("world",)).unwrap().to_value());
+//! ```
+
+use duktape_sys;
+
+use build_info;
+use loading;
+use strings;
+use structured_clone;
+use {Context, ErrorKind, Result};
+
+/// The first bytes of every snapshot.
+const MAGIC: &[u8; 8] = b"DUKSNAP\0";
+
+/// The version of the snapshot format, which changes whenever the layout does.
+const FORMAT_VERSION: u32 = 1;
+
+/// Embeds a snapshot that the build script wrote to `OUT_DIR`, as a `&'static [u8]`, see the
+/// `duk-build` crate.
+///
+/// # Examples
+///
+/// ```ignore
+/// let ctx = duk::Context::from_snapshot(duk::include_snapshot!("runtime.snap")).unwrap();
+/// ```
+#[macro_export]
+macro_rules! include_snapshot {
+    ($path:expr) => {
+        &include_bytes!(concat!(env!("OUT_DIR"), "/", $path))[..]
+    };
+}
+
+/// Captures the globals of the context that a fresh context doesn't have.
+pub(crate) unsafe fn take(ctx: *mut duktape_sys::duk_context) -> Vec<u8> {
+    use duktape_sys::*;
+
+    let builtins = loading::global_names(Context::new().raw);
+    duk_push_global_object(ctx);
+    duk_push_object(ctx);
+    for name in loading::global_names(ctx).difference(&builtins) {
+        strings::push(ctx, name);
+        duk_get_prop(ctx, -3);
+        if structured_clone::read_with_functions(ctx, -1).is_ok() {
+            strings::push(ctx, name);
+            duk_swap_top(ctx, -2);
+            duk_put_prop(ctx, -3);
+        } else {
+            duk_pop(ctx);
+        }
+    }
+    let globals = structured_clone::read_with_functions(ctx, -1)
+        .expect("failed to copy globals that could be copied one by one");
+    duk_pop_2(ctx);
+
+    let info = build_info::probe(ctx);
+    let mut snapshot = MAGIC.to_vec();
+    snapshot.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    snapshot.extend_from_slice(&info.version.number.to_le_bytes());
+    snapshot.extend_from_slice(&(info.env.len() as u32).to_le_bytes());
+    snapshot.extend_from_slice(info.env.as_bytes());
+    globals.encode(&mut snapshot);
+    snapshot
+}
+
+/// Assigns the globals of a snapshot, or fails with `ErrorKind::InvalidSnapshot`.
+pub(crate) unsafe fn restore(ctx: *mut duktape_sys::duk_context, snapshot: &[u8]) -> Result<()> {
+    use duktape_sys::*;
+
+    let invalid = |message: &str| ErrorKind::InvalidSnapshot(message.to_owned()).into();
+    let rest = match snapshot.strip_prefix(&MAGIC[..]) {
+        Some(rest) if rest.len() >= 12 => rest,
+        _ => return Err(invalid("not a snapshot")),
+    };
+    let u32_at = |i: usize| u32::from_le_bytes([rest[i], rest[i + 1], rest[i + 2], rest[i + 3]]);
+    let format_version = u32_at(0);
+    if format_version != FORMAT_VERSION {
+        return Err(invalid(&format!("unsupported format version {}", format_version)));
+    }
+    let duktape_version = u32_at(4);
+    let env_len = u32_at(8) as usize;
+    let env = match rest.get(12..12 + env_len) {
+        Some(env) => String::from_utf8_lossy(env),
+        None => return Err(invalid("truncated")),
+    };
+    let info = build_info::probe(ctx);
+    if duktape_version != info.version.number || env != info.env {
+        return Err(invalid(&format!("taken with Duktape {} ({}), but the context has {} ({})",
+                                    duktape_version,
+                                    env,
+                                    info.version.number,
+                                    info.env)));
+    }
+    let globals = structured_clone::StructuredClone::decode(&rest[12 + env_len..])
+        .map_err(|message| invalid(&message))?;
+
+    duk_push_global_object(ctx);
+    structured_clone::push(ctx, &globals);
+    duk_enum(ctx, -1, DUK_ENUM_OWN_PROPERTIES_ONLY);
+    while duk_next(ctx, -1, 1) != 0 {
+        duk_put_prop(ctx, -5);
+    }
+    duk_pop_3(ctx);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use {Context, ErrorKind, Value};
+
+    #[test]
+    fn restore_plugin_environment() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.eval_string(r"
+          var config = {name: 'plugin', started: new Date(0), pattern: /^a+$/i};
+          function Counter(start) { this.n = start; }
+          Counter.prototype.next = function () { return ++this.n; };
+          var counter = new Counter(10);
+          counter.next();
+          var handlers = {describe: function () { return config.name + ' ' + counter.n; }};
+          var plain = Object.create(null);
+          var keys = Object.keys;
+        ")
+            .unwrap();
+        let snapshot = ctx.snapshot();
+
+        let restored = Context::from_snapshot(&snapshot).unwrap();
+        // Snapshots of restored contexts capture the same globals
+        assert_eq!(snapshot, restored.snapshot());
+        let result = restored.eval_string(r"
+          [handlers.describe(), counter.next(), counter instanceof Counter,
+           Counter.prototype.constructor === Counter, config.started.getTime(),
+           config.pattern.test('AA'), Object.getPrototypeOf(plain), typeof keys,
+           Object.keys(new Counter(0)).join()]
+        ")
+            .unwrap();
+        assert_eq!(Value::Array(vec![Value::String("plugin 11".to_owned()),
+                                     Value::Number(12.0),
+                                     Value::Boolean(true),
+                                     Value::Boolean(true),
+                                     Value::Number(0.0),
+                                     Value::Boolean(true),
+                                     Value::Null,
+                                     Value::String("undefined".to_owned()),
+                                     Value::String("n".to_owned())]),
+                   result.to_value());
+        ctx.assert_clean();
+        restored.assert_clean();
+    }
+
+    #[test]
+    fn invalid_snapshots() {
+        let _ = env_logger::init();
+        let snapshot = Context::new().snapshot();
+        assert!(Context::from_snapshot(&snapshot).is_ok());
+        let mut other_format = snapshot.clone();
+        other_format[8] = 2;
+        let mut other_env = snapshot.clone();
+        other_env[20] ^= 1;
+        let inputs = [&b"not a snapshot"[..],
+                      &snapshot[..snapshot.len() - 1],
+                      &other_format,
+                      &other_env];
+        for input in &inputs {
+            match *Context::from_snapshot(input).unwrap_err().kind() {
+                ErrorKind::InvalidSnapshot(_) => (),
+                ref kind => panic!("unexpected error {:?}", kind),
+            }
+        }
+    }
+}
@@ -0,0 +1,281 @@
+//! Translation of locations in generated code back to the original sources, using source maps
+//! (revision 3) like the ones emitted by TypeScript or Babel.
+//!
+//! Duktape only tracks line numbers, so a generated line is mapped to the first mapping on that
+//! line.
+
+use std::collections;
+
+use ErrorKind;
+use Result;
+use Value;
+
+/// A parsed source map for a single generated file.
+#[derive(Clone, Debug)]
+pub struct SourceMap {
+    sources: Vec<String>,
+    names: Vec<String>,
+    lines: Vec<Vec<Segment>>,
+}
+
+/// A location in an original source file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OriginalLocation {
+    /// The original file name, including the source root of the map.
+    pub file_name: String,
+    /// The 1-based line number in the original file.
+    pub line_number: u32,
+    /// The 0-based column in the original file.
+    pub column: u32,
+    /// The original name of the symbol at this location, if known.
+    pub name: Option<String>,
+}
+
+/// Source maps registered per generated file name.
+///
+/// A registry is owned by each context (see `Context::register_source_map`), and is used to
+/// rewrite error locations and stack traces as well as debugger locations.
+#[derive(Clone, Debug, Default)]
+pub struct SourceMapRegistry {
+    maps: collections::HashMap<String, SourceMap>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Segment {
+    column: u32,
+    source: u32,
+    line: u32,
+    source_column: u32,
+    name: Option<u32>,
+}
+
+impl SourceMap {
+    /// Creates a source map from its parsed JSON representation.
+    pub fn from_value(value: &Value) -> Result<SourceMap> {
+        let map = match *value {
+            Value::Object(ref map) => map,
+            _ => return Err(invalid("not an object")),
+        };
+
+        match map.get("version") {
+            Some(Value::Number(v)) if *v == 3.0 => (),
+            _ => return Err(invalid("unsupported version")),
+        }
+
+        let root = match map.get("sourceRoot") {
+            Some(Value::String(root)) if !root.is_empty() && !root.ends_with('/') => {
+                format!("{}/", root)
+            }
+            Some(Value::String(root)) => root.clone(),
+            _ => String::new(),
+        };
+        let sources = strings(map.get("sources"))?
+            .into_iter()
+            .map(|s| format!("{}{}", root, s))
+            .collect();
+        let names = strings(map.get("names"))?;
+        let lines = match map.get("mappings") {
+            Some(Value::String(mappings)) => parse_mappings(mappings)?,
+            _ => return Err(invalid("missing mappings")),
+        };
+
+        Ok(SourceMap {
+            sources,
+            names,
+            lines,
+        })
+    }
+
+    /// Looks up the original location of a 1-based line in the generated file, and an optional
+    /// 0-based column (otherwise the first mapping on the line is used).
+    pub fn lookup(&self, line: u32, column: Option<u32>) -> Option<OriginalLocation> {
+        let segments = self.lines.get(line.checked_sub(1)? as usize)?;
+        let segment = match column {
+            Some(column) => segments.iter().rev().find(|s| s.column <= column),
+            None => segments.first(),
+        }?;
+
+        Some(OriginalLocation {
+            file_name: self.sources.get(segment.source as usize)?.clone(),
+            line_number: segment.line + 1,
+            column: segment.source_column,
+            name: segment.name.and_then(|n| self.names.get(n as usize)).cloned(),
+        })
+    }
+}
+
+impl SourceMapRegistry {
+    pub fn new() -> SourceMapRegistry {
+        SourceMapRegistry::default()
+    }
+
+    /// Registers the source map of the specified generated file, replacing any previously
+    /// registered map.
+    pub fn register(&mut self, file_name: &str, map: SourceMap) {
+        self.maps.insert(file_name.to_owned(), map);
+    }
+
+    /// Removes the source map of the specified generated file, returning it if there was one.
+    pub fn unregister(&mut self, file_name: &str) -> Option<SourceMap> {
+        self.maps.remove(file_name)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.maps.is_empty()
+    }
+
+    /// Looks up the original location of a 1-based line in the specified generated file.
+    pub fn lookup(&self, file_name: &str, line: u32) -> Option<OriginalLocation> {
+        self.maps.get(file_name)?.lookup(line, None)
+    }
+
+    /// Rewrites all `(file:line)` locations in a Duktape stack trace that refer to files with a
+    /// registered source map.
+    pub fn rewrite_stack(&self, stack: &str) -> String {
+        stack.lines()
+            .map(|l| self.rewrite_stack_line(l).unwrap_or_else(|| l.to_owned()))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn rewrite_stack_line(&self, line: &str) -> Option<String> {
+        let open = line.rfind('(')?;
+        let close = open + line[open..].find(')')?;
+        let location = &line[open + 1..close];
+        let colon = location.rfind(':')?;
+        let file_name = &location[..colon];
+        let line_number = location[colon + 1..].parse().ok()?;
+        let original = self.lookup(file_name, line_number)?;
+
+        Some(format!("{}({}:{}:{}){}",
+                     &line[..open],
+                     original.file_name,
+                     original.line_number,
+                     original.column + 1,
+                     &line[close + 1..]))
+    }
+}
+
+fn invalid(message: &str) -> ::Error {
+    ErrorKind::InvalidSourceMap(message.to_owned()).into()
+}
+
+fn strings(value: Option<&Value>) -> Result<Vec<String>> {
+    match value {
+        Some(Value::Array(values)) => {
+            Ok(values.iter()
+                .map(|v| match *v {
+                    Value::String(ref s) => s.clone(),
+                    _ => String::new(),
+                })
+                .collect())
+        }
+        None => Ok(Vec::new()),
+        _ => Err(invalid("expected an array of strings")),
+    }
+}
+
+fn parse_mappings(mappings: &str) -> Result<Vec<Vec<Segment>>> {
+    // All fields except the generated column are relative to the previous segment in the file
+    let mut source = 0;
+    let mut line = 0;
+    let mut source_column = 0;
+    let mut name = 0;
+
+    let mut lines = Vec::new();
+    for generated_line in mappings.split(';') {
+        let mut column = 0;
+        let mut segments = Vec::new();
+        for segment in generated_line.split(',').filter(|s| !s.is_empty()) {
+            let fields = decode_vlq(segment)?;
+            column += fields[0];
+            if fields.len() < 4 {
+                // The segment doesn't map to any source location
+                continue;
+            }
+            source += fields[1];
+            line += fields[2];
+            source_column += fields[3];
+            let segment_name = if fields.len() >= 5 {
+                name += fields[4];
+                Some(name as u32)
+            } else {
+                None
+            };
+            segments.push(Segment {
+                column: column as u32,
+                source: source as u32,
+                line: line as u32,
+                source_column: source_column as u32,
+                name: segment_name,
+            });
+        }
+        segments.sort_by_key(|s| s.column);
+        lines.push(segments);
+    }
+    Ok(lines)
+}
+
+fn decode_vlq(segment: &str) -> Result<Vec<i64>> {
+    let mut values = Vec::new();
+    let mut value = 0;
+    let mut shift = 0;
+    for c in segment.bytes() {
+        let digit = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return Err(invalid("invalid base64 in mappings")),
+        } as i64;
+        value += (digit & 0x1f) << shift;
+        if digit & 0x20 == 0 {
+            let magnitude = value >> 1;
+            values.push(if value & 1 == 1 { -magnitude } else { magnitude });
+            value = 0;
+            shift = 0;
+        } else {
+            shift += 5;
+        }
+    }
+    if shift != 0 || values.is_empty() {
+        return Err(invalid("truncated segment in mappings"));
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_vlq_values() {
+        assert_eq!(vec![0, 0, 16, 1], decode_vlq("AAgBC").unwrap());
+        assert_eq!(vec![-1, 123456], decode_vlq("DgkxH").unwrap());
+        assert!(decode_vlq("g").is_err());
+    }
+
+    #[test]
+    fn lookup_columns() {
+        let mut map = collections::BTreeMap::new();
+        map.insert("version".to_owned(), Value::Number(3.0));
+        map.insert("sourceRoot".to_owned(), Value::String("src".to_owned()));
+        map.insert("sources".to_owned(), Value::Array(vec![Value::String("a.ts".to_owned())]));
+        map.insert("names".to_owned(), Value::Array(vec![Value::String("foo".to_owned())]));
+        map.insert("mappings".to_owned(), Value::String(";AAEA,UAAEA;;".to_owned()));
+        let map = SourceMap::from_value(&Value::Object(map)).unwrap();
+
+        assert_eq!(None, map.lookup(1, None));
+        let first = map.lookup(2, None).unwrap();
+        assert_eq!("src/a.ts", first.file_name);
+        assert_eq!(3, first.line_number);
+        assert_eq!(0, first.column);
+        assert_eq!(None, first.name);
+        let second = map.lookup(2, Some(12)).unwrap();
+        assert_eq!(3, second.line_number);
+        assert_eq!(2, second.column);
+        assert_eq!(Some("foo".to_owned()), second.name);
+        assert_eq!(None, map.lookup(3, None));
+    }
+}
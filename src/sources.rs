@@ -0,0 +1,143 @@
+//! Preprocessing of script sources that are read from files, which `Context::eval_file`, the
+//! module loader and bundles apply.
+//!
+//! Editors on Windows often save scripts with a byte order mark, CRLF line endings or in a legacy
+//! code page, none of which should make a script behave differently.  Sources are therefore
+//! decoded as follows:
+//!
+//!   * A UTF-8 byte order mark is removed, and UTF-16 sources (recognized by their byte order
+//!     mark) are converted to UTF-8.
+//!   * Other bytes that aren't valid UTF-8 are taken to be Windows-1252 (a superset of
+//!     Latin-1), one character per byte, so a file that mixes UTF-8 and Windows-1252 keeps all of
+//!     its text.
+//!   * CRLF and lone CR line endings become LF.  Duktape counts all of them as one line break,
+//!     so line numbers in errors stay the same, but the source lines shown in error reports no
+//!     longer end in a stray CR.
+//!
+//! # Examples
+//!
+//! ```
+//! let source = duk::sources::decode(b"\xef\xbb\xbfvar s = 'caf\xe9';\r\n");
+//! assert_eq!("var s = 'caf\u{e9}';\n", source);
+//! ```
+
+use std::borrow;
+use std::char;
+use std::str;
+
+/// The characters of the bytes 0x80 to 0x9f in Windows-1252, where they differ from Latin-1.
+/// The five bytes that Windows-1252 leaves undefined map to the Latin-1 control characters.
+const WINDOWS_1252: [char; 32] = ['\u{20ac}', '\u{81}', '\u{201a}', '\u{192}', '\u{201e}',
+                                  '\u{2026}', '\u{2020}', '\u{2021}', '\u{2c6}', '\u{2030}',
+                                  '\u{160}', '\u{2039}', '\u{152}', '\u{8d}', '\u{17d}', '\u{8f}',
+                                  '\u{90}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}',
+                                  '\u{2022}', '\u{2013}', '\u{2014}', '\u{2dc}', '\u{2122}',
+                                  '\u{161}', '\u{203a}', '\u{153}', '\u{9d}', '\u{17e}', '\u{178}'];
+
+/// Decodes the contents of a script file into normalized text, see the module documentation.
+pub fn decode(bytes: &[u8]) -> String {
+    let text = if let Some(rest) = bytes.strip_prefix(b"\xff\xfe") {
+        decode_utf16(rest, u16::from_le_bytes)
+    } else if let Some(rest) = bytes.strip_prefix(b"\xfe\xff") {
+        decode_utf16(rest, u16::from_be_bytes)
+    } else {
+        decode_mixed(bytes)
+    };
+    match normalize(&text) {
+        borrow::Cow::Borrowed(_) => text,
+        borrow::Cow::Owned(normalized) => normalized,
+    }
+}
+
+/// Normalizes text that is already decoded, like the sources returned by a module loader, by
+/// removing a byte order mark and converting line endings to LF.
+pub fn normalize(source: &str) -> borrow::Cow<'_, str> {
+    let source = source.strip_prefix('\u{feff}').unwrap_or(source);
+    if !source.contains('\r') {
+        return borrow::Cow::Borrowed(source);
+    }
+    borrow::Cow::Owned(source.replace("\r\n", "\n").replace('\r', "\n"))
+}
+
+fn decode_utf16(bytes: &[u8], unit: fn([u8; 2]) -> u16) -> String {
+    let units = bytes.chunks(2).map(|pair| unit([pair[0], *pair.get(1).unwrap_or(&0)]));
+    char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect()
+}
+
+/// Decodes UTF-8, taking the bytes that aren't part of valid UTF-8 to be Windows-1252.
+fn decode_mixed(mut bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len());
+    loop {
+        match str::from_utf8(bytes) {
+            Ok(valid) => {
+                text.push_str(valid);
+                return text;
+            }
+            Err(error) => {
+                let (valid, rest) = bytes.split_at(error.valid_up_to());
+                text.push_str(unsafe { str::from_utf8_unchecked(valid) });
+                let invalid = error.error_len().unwrap_or(rest.len());
+                text.extend(rest[..invalid].iter().map(|&b| windows_1252(b)));
+                bytes = &rest[invalid..];
+            }
+        }
+    }
+}
+
+fn windows_1252(byte: u8) -> char {
+    match byte {
+        0x80..=0x9f => WINDOWS_1252[(byte - 0x80) as usize],
+        _ => byte as char,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::fs;
+
+    use super::*;
+    use testing::ScratchDir;
+    use {Context, ErrorKind, Value};
+
+    #[test]
+    fn decoding() {
+        let _ = env_logger::init();
+        assert_eq!("a\nb\nc\n", decode(b"\xef\xbb\xbfa\r\nb\rc\n"));
+        assert_eq!("\u{e9}t\u{e9} \u{201c}caf\u{e9}\u{201d}",
+                   decode(b"\xe9t\xc3\xa9 \x93caf\xe9\x94"));
+        assert_eq!("h\u{e9}\n", decode(b"\xff\xfeh\x00\xe9\x00\r\x00\n\x00"));
+        assert_eq!("h\u{e9}", decode(b"\xfe\xff\x00h\x00\xe9"));
+        assert!(matches!(normalize("a\nb"), borrow::Cow::Borrowed("a\nb")));
+    }
+
+    #[test]
+    fn windows_files_and_modules() {
+        let _ = env_logger::init();
+        let dir = ScratchDir::new("sources");
+        let path = dir.join("plugin.js");
+        fs::write(&path,
+                  b"\xef\xbb\xbfvar name = 'caf\xe9';\r\n\r\nfunction fail() {\r\n\
+                    throw new Error('failed');\r\n}\r\n")
+            .unwrap();
+
+        let module = "\u{feff}exports.x = 1;\r\nexports.y = ;";
+        let ctx = Context::builder()
+            .with_module_resolver(Box::new(|id, _| id))
+            .with_module_loader(Box::new(move |_| Some(module.to_owned())))
+            .build();
+        ctx.eval_file(&path).unwrap();
+        assert_eq!(Value::String("caf\u{e9}".to_owned()),
+                   ctx.eval_string("name").unwrap().to_value());
+        match *ctx.call("fail", ()).unwrap_err().kind() {
+            ErrorKind::Js(ref error) => assert_eq!(Some(4), error.line_number),
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+        match *ctx.eval_string("require('m')").unwrap_err().kind() {
+            ErrorKind::Js(ref error) => assert_eq!(Some(2), error.line_number),
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+        ctx.assert_clean();
+    }
+}
@@ -0,0 +1,89 @@
+//! Spans for the `tracing` crate, so that script execution shows up in the traces of the host.
+//!
+//! All spans have the `duk` target, and record their duration in microseconds in the
+//! `duration_us` field when they end.  Without the `tracing` feature, spans do nothing.
+
+#[cfg(feature = "tracing")]
+use std::time;
+
+#[cfg(feature = "tracing")]
+use tracing;
+
+/// An entered span that is exited when dropped.
+#[cfg(feature = "tracing")]
+pub struct Span {
+    span: tracing::span::EnteredSpan,
+    start: time::Instant,
+}
+
+#[cfg(not(feature = "tracing"))]
+pub struct Span;
+
+#[cfg(feature = "tracing")]
+impl Span {
+    /// A span for evaluating the code from the specified source.
+    pub fn eval(source: &str) -> Span {
+        Span::enter(tracing::info_span!(target: "duk",
+                                        "eval",
+                                        source = source,
+                                        duration_us = tracing::field::Empty))
+    }
+
+    /// A span for calling the specified function.
+    pub fn call(function: &str) -> Span {
+        Span::enter(tracing::info_span!(target: "duk",
+                                        "call",
+                                        function = function,
+                                        duration_us = tracing::field::Empty))
+    }
+
+    /// A span for loading the source of the specified module.
+    pub fn module_load(module: &str) -> Span {
+        Span::enter(tracing::info_span!(target: "duk",
+                                        "module_load",
+                                        module = module,
+                                        duration_us = tracing::field::Empty))
+    }
+
+    /// A span for a garbage collection run.
+    pub fn gc() -> Span {
+        Span::enter(tracing::info_span!(target: "duk",
+                                        "gc",
+                                        duration_us = tracing::field::Empty))
+    }
+
+    fn enter(span: tracing::Span) -> Span {
+        Span {
+            span: span.entered(),
+            start: time::Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl Drop for Span {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        let micros = elapsed.as_secs() * 1_000_000 + u64::from(elapsed.subsec_micros());
+        self.span.record("duration_us", micros);
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    pub fn eval(_: &str) -> Span {
+        Span
+    }
+
+    pub fn call(_: &str) -> Span {
+        Span
+    }
+
+    pub fn module_load(_: &str) -> Span {
+        Span
+    }
+
+    pub fn gc() -> Span {
+        Span
+    }
+}
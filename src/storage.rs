@@ -0,0 +1,575 @@
+//! A `host:storage` module with a persistent, `localStorage`-like key-value store for plugins, see
+//! `ContextBuilder::with_storage`.
+//!
+//! Keys and values are strings.  The store is either a `DirectoryStore` that keeps one file per
+//! key, a `MemoryStore` that lives as long as the context, or any other `Store` that the host
+//! implements.  A `Quota` limits the size of every key and value and, optionally, the total size of
+//! all keys and values.
+//!
+//! The module has synchronous functions:
+//!
+//! * `get(key)` returns the value, or `null` if the key isn't set.
+//! * `set(key, value)` converts the value to a string and stores it.
+//! * `delete(key)` removes a key, and returns whether it was set.
+//! * `list(prefix)` returns the sorted keys, optionally only the ones starting with `prefix`.
+//!
+//! Failures throw an `Error` with a `code`: `ENAMETOOLONG` if a key is too long, `EFBIG` if a value
+//! is too large, `EQUOTA` if the store would grow beyond its quota, and `EIO` if the store failed.
+//!
+//! # Examples
+//!
+//! ```
+//! use duk::storage::{MemoryStore, Quota};
+//!
+//! let ctx = duk::Context::builder()
+//!     .with_storage(Box::new(MemoryStore::new()), Quota::new().with_max_value_size(16))
+//!     .build();
+//! let value = ctx.eval_string(r"
+//!   var storage = require('host:storage');
+//!   storage.set('theme', 'dark');
+//!   storage.get('theme')
+//! ").unwrap();
+//! assert_eq!(duk::Value::String("dark".to_owned()), value.to_value());
+//! assert!(ctx.eval_string("storage.set('notes', 'far too long for the quota')").is_err());
+//! ```
+
+use std::cell;
+use std::collections;
+use std::fs;
+use std::io;
+use std::os;
+use std::path;
+
+use duktape_sys;
+
+use hooks;
+use host_modules;
+use strings;
+use HeapData;
+
+/// Builds the exports of the module on top of the native functions.
+const SETUP: &[u8] = b"(function (get, set, remove, list) {
+  function unwrap(result) {
+    if (result[0] !== null) {
+      var error = new Error(result[1]);
+      error.code = result[0];
+      throw error;
+    }
+    return result[1];
+  }
+  return {
+    get: function (key) {
+      return unwrap(get(String(key)));
+    },
+    set: function (key, value) {
+      unwrap(set(String(key), String(value)));
+    },
+    delete: function (key) {
+      return unwrap(remove(String(key)));
+    },
+    list: function (prefix) {
+      return unwrap(list(prefix === undefined ? '' : String(prefix)));
+    }
+  };
+})";
+
+/// Where the values of `host:storage` are kept.
+pub trait Store {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>>;
+
+    fn set(&mut self, key: &str, value: &str) -> io::Result<()>;
+
+    /// Removes a key, and returns whether it was set.
+    fn delete(&mut self, key: &str) -> io::Result<bool>;
+
+    /// Returns all keys, in any order.
+    fn keys(&mut self) -> io::Result<Vec<String>>;
+
+    /// The total size of all keys and values in bytes, which the quota applies to.  It is only
+    /// asked for once, and then kept up to date as values are set and deleted.
+    fn total_size(&mut self) -> io::Result<u64> {
+        let mut size = 0;
+        for key in self.keys()? {
+            size += (key.len() + self.get(&key)?.map_or(0, |v| v.len())) as u64;
+        }
+        Ok(size)
+    }
+}
+
+/// A store that keeps every key in a file of a directory.
+///
+/// File names are the hex encoded keys, so keys can't refer to other files.  Encoded keys are
+/// twice as long, and file systems usually limit names to 255 bytes, so keys must not be longer
+/// than about 120 bytes, which the default `Quota` ensures.  The directory is created when the
+/// first value is set.
+#[derive(Clone, Debug)]
+pub struct DirectoryStore {
+    dir: path::PathBuf,
+}
+
+/// A store that keeps the values in memory.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStore {
+    values: collections::BTreeMap<String, String>,
+}
+
+/// The limits of a store.
+#[derive(Clone, Copy, Debug)]
+pub struct Quota {
+    max_key_size: usize,
+    max_value_size: usize,
+    max_total_size: Option<u64>,
+}
+
+/// A failed store operation, as reported to scripts.
+struct StorageError {
+    code: &'static str,
+    message: String,
+}
+
+/// The store of a context with its quota.
+pub(crate) struct Storage {
+    store: cell::RefCell<Box<dyn Store>>,
+    quota: Quota,
+    /// The total size of the store, once the quota needed it.
+    total_size: cell::Cell<Option<u64>>,
+}
+
+impl DirectoryStore {
+    pub fn new<P>(dir: P) -> DirectoryStore
+        where P: Into<path::PathBuf>
+    {
+        DirectoryStore { dir: dir.into() }
+    }
+
+    fn path(&self, key: &str) -> path::PathBuf {
+        let name = key.bytes().map(|b| format!("{:02x}", b)).collect::<String>();
+        self.dir.join(format!("{}.value", name))
+    }
+}
+
+impl Store for DirectoryStore {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        match fs::read_to_string(self.path(key)) {
+            Ok(value) => Ok(Some(value)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        // Write a temporary file first, so that a crash doesn't leave a truncated value behind
+        let path = self.path(key);
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, value)?;
+        fs::rename(&temp, &path)
+    }
+
+    fn delete(&mut self, key: &str) -> io::Result<bool> {
+        match fs::remove_file(self.path(key)) {
+            Ok(()) => Ok(true),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn keys(&mut self) -> io::Result<Vec<String>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut keys = Vec::new();
+        for entry in entries {
+            let name = entry?.file_name();
+            let name = match name.to_str().and_then(|n| n.strip_suffix(".value")) {
+                Some(name) => name.to_owned(),
+                None => continue,
+            };
+            let bytes = (0..name.len() / 2)
+                .map(|i| u8::from_str_radix(name.get(i * 2..i * 2 + 2).unwrap_or(""), 16))
+                .collect::<Result<Vec<_>, _>>();
+            if let Ok(key) = bytes.map(String::from_utf8) {
+                keys.extend(key.ok());
+            }
+        }
+        Ok(keys)
+    }
+
+    fn total_size(&mut self) -> io::Result<u64> {
+        let mut size = 0;
+        for key in self.keys()? {
+            size += key.len() as u64 + fs::metadata(self.path(&key))?.len();
+        }
+        Ok(size)
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl Store for MemoryStore {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        Ok(self.values.get(key).cloned())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> io::Result<()> {
+        self.values.insert(key.to_owned(), value.to_owned());
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> io::Result<bool> {
+        Ok(self.values.remove(key).is_some())
+    }
+
+    fn keys(&mut self) -> io::Result<Vec<String>> {
+        Ok(self.values.keys().cloned().collect())
+    }
+}
+
+impl Quota {
+    /// Limits keys to 100 bytes and values to 64 KiB, without a limit on the total size.
+    pub fn new() -> Quota {
+        Quota {
+            max_key_size: 100,
+            max_value_size: 64 * 1024,
+            max_total_size: None,
+        }
+    }
+
+    /// Sets the maximum size of a key in bytes, as UTF-8.
+    pub fn with_max_key_size(mut self, bytes: usize) -> Self {
+        self.max_key_size = bytes;
+        self
+    }
+
+    /// Sets the maximum size of a value in bytes, as UTF-8.
+    pub fn with_max_value_size(mut self, bytes: usize) -> Self {
+        self.max_value_size = bytes;
+        self
+    }
+
+    /// Sets the maximum total size of all keys and values in bytes.
+    pub fn with_max_total_size(mut self, bytes: u64) -> Self {
+        self.max_total_size = Some(bytes);
+        self
+    }
+}
+
+impl Default for Quota {
+    fn default() -> Quota {
+        Quota::new()
+    }
+}
+
+impl From<io::Error> for StorageError {
+    fn from(e: io::Error) -> StorageError {
+        StorageError {
+            code: "EIO",
+            message: e.to_string(),
+        }
+    }
+}
+
+impl Storage {
+    pub(crate) fn new(store: Box<dyn Store>, quota: Quota) -> Storage {
+        Storage {
+            store: cell::RefCell::new(store),
+            quota,
+            total_size: cell::Cell::new(None),
+        }
+    }
+
+    fn check_key(&self, key: &str) -> Result<(), StorageError> {
+        if key.len() > self.quota.max_key_size {
+            return Err(StorageError {
+                code: "ENAMETOOLONG",
+                message: format!("the key is longer than {} bytes", self.quota.max_key_size),
+            });
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<String>, StorageError> {
+        self.check_key(key)?;
+        Ok(self.store.borrow_mut().get(key)?)
+    }
+
+    fn set(&self, key: &str, value: &str) -> Result<(), StorageError> {
+        self.check_key(key)?;
+        if value.len() > self.quota.max_value_size {
+            return Err(StorageError {
+                code: "EFBIG",
+                message: format!("the value of '{}' is larger than {} bytes",
+                                 key,
+                                 self.quota.max_value_size),
+            });
+        }
+        let mut store = self.store.borrow_mut();
+        let max = match self.quota.max_total_size {
+            Some(max) => max,
+            None => return Ok(store.set(key, value)?),
+        };
+        // Forgotten until the store succeeds, so that a failure has it asked for again
+        let current = match self.total_size.take() {
+            Some(current) => current,
+            None => store.total_size()?,
+        };
+        let old = store.get(key)?.map_or(0, |v| key.len() + v.len()) as u64;
+        let total = current.saturating_sub(old) + (key.len() + value.len()) as u64;
+        if total > max {
+            self.total_size.set(Some(current));
+            return Err(StorageError {
+                code: "EQUOTA",
+                message: format!("setting '{}' exceeds the storage quota of {} bytes", key, max),
+            });
+        }
+        store.set(key, value)?;
+        self.total_size.set(Some(total));
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<bool, StorageError> {
+        self.check_key(key)?;
+        let mut store = self.store.borrow_mut();
+        let current = match self.total_size.take() {
+            Some(current) => current,
+            None => return Ok(store.delete(key)?),
+        };
+        let old = store.get(key)?.map_or(0, |v| key.len() + v.len()) as u64;
+        let deleted = store.delete(key)?;
+        self.total_size.set(Some(current.saturating_sub(old)));
+        Ok(deleted)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        let mut keys = self.store.borrow_mut().keys()?;
+        keys.retain(|k| k.starts_with(prefix));
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+/// Registers the `host:storage` module.
+pub(crate) unsafe fn setup(ctx: *mut duktape_sys::duk_context) {
+    use duktape_sys::*;
+
+    let ret = duk_peval_lstring(ctx, SETUP.as_ptr() as *const os::raw::c_char, SETUP.len());
+    assert_eq!(0, ret, "failed to compile host:storage");
+    duk_push_c_function(ctx, Some(get), 1);
+    duk_push_c_function(ctx, Some(set), 2);
+    duk_push_c_function(ctx, Some(delete), 1);
+    duk_push_c_function(ctx, Some(list), 1);
+    let ret = duk_pcall(ctx, 4);
+    assert_eq!(0, ret, "failed to set up host:storage");
+    host_modules::register(ctx, b"host:storage\0");
+}
+
+unsafe fn storage<'a>(ctx: *mut duktape_sys::duk_context) -> &'a Storage {
+    let mut funcs = duktape_sys::duk_memory_functions::default();
+    duktape_sys::duk_get_memory_functions(ctx, &mut funcs);
+    (*(funcs.udata as *mut HeapData)).storage.as_ref().unwrap()
+}
+
+/// Pushes `[null, result]` on success, with the result pushed by `push`, or `[code, message]` on
+/// failure.
+unsafe fn respond<T, F>(ctx: *mut duktape_sys::duk_context, result: Result<T, StorageError>, push: F)
+    where F: FnOnce(T)
+{
+    use duktape_sys::*;
+
+    duk_push_array(ctx);
+    match result {
+        Ok(value) => {
+            duk_push_null(ctx);
+            duk_put_prop_index(ctx, -2, 0);
+            push(value);
+        }
+        Err(e) => {
+            strings::push(ctx, e.code);
+            duk_put_prop_index(ctx, -2, 0);
+            strings::push(ctx, &e.message);
+        }
+    }
+    duk_put_prop_index(ctx, -2, 1);
+}
+
+/// `get(key)`
+unsafe extern "C" fn get(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    let result = storage(ctx).get(&strings::get(ctx, 0));
+    respond(ctx, result, |value| match value {
+        Some(value) => strings::push(ctx, &value),
+        None => duktape_sys::duk_push_null(ctx),
+    });
+    1
+}
+
+/// `set(key, value)`
+unsafe extern "C" fn set(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    let result = storage(ctx).set(&strings::get(ctx, 0), &strings::get(ctx, 1));
+    let codes = ["ENAMETOOLONG", "EFBIG", "EQUOTA"];
+    if result.as_ref().err().is_some_and(|e| codes.contains(&e.code)) {
+        hooks::notify(ctx, |hooks| hooks.limit_exceeded(hooks::Limit::StorageQuota));
+    }
+    respond(ctx, result, |()| duktape_sys::duk_push_undefined(ctx));
+    1
+}
+
+/// `remove(key)`
+unsafe extern "C" fn delete(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    let result = storage(ctx).delete(&strings::get(ctx, 0));
+    respond(ctx, result, |deleted| {
+        duktape_sys::duk_push_boolean(ctx, deleted as duktape_sys::duk_bool_t)
+    });
+    1
+}
+
+/// `list(prefix)`
+unsafe extern "C" fn list(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    use duktape_sys::*;
+
+    let result = storage(ctx).list(&strings::get(ctx, 0));
+    respond(ctx, result, |keys| {
+        duk_push_array(ctx);
+        for (i, key) in keys.iter().enumerate() {
+            strings::push(ctx, key);
+            duk_put_prop_index(ctx, -2, i as duk_uarridx_t);
+        }
+    });
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::fs;
+    use std::rc;
+
+    use super::*;
+    use testing::ScratchDir;
+    use {Context, Value};
+
+    #[test]
+    #[cfg_attr(target_os = "wasi", ignore = "needs a temporary directory")]
+    fn directory_storage() {
+        let _ = env_logger::init();
+        let dir = ScratchDir::new("storage");
+        let build = || {
+            Context::builder()
+                .with_storage(Box::new(DirectoryStore::new(dir.path())), Quota::new())
+                .build()
+        };
+
+        let ctx = build();
+        let result = ctx.eval_string(r"
+          var storage = require('host:storage');
+          var empty = storage.list();
+          storage.set('user/name', 'Ada');
+          storage.set('user/../id', 42);
+          storage.set('theme', 'dark');
+          storage.set(new Array(101).join('k'), 'long');
+          [empty.length, storage.get('missing'), storage.delete('theme'), storage.delete('theme')]
+        ")
+            .unwrap();
+        assert_eq!(Value::Array(vec![Value::Number(0.0),
+                                     Value::Null,
+                                     Value::Boolean(true),
+                                     Value::Boolean(false)]),
+                   result.to_value());
+        ctx.assert_clean();
+
+        // The values survive the context
+        let ctx = build();
+        let result = ctx.eval_string(r"
+          var storage = require('host:storage');
+          [storage.list('user/').join(), storage.get('user/../id')]
+        ")
+            .unwrap();
+        assert_eq!(Value::Array(vec![Value::String("user/../id,user/name".to_owned()),
+                                     Value::String("42".to_owned())]),
+                   result.to_value());
+        assert_eq!(3, fs::read_dir(&dir).unwrap().count());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn storage_quota() {
+        let _ = env_logger::init();
+        let quota = Quota::new().with_max_value_size(8).with_max_total_size(16);
+        let ctx = Context::builder().with_storage(Box::new(MemoryStore::new()), quota).build();
+        let result = ctx.eval_string(r"
+          var storage = require('host:storage');
+          function code(f) { try { f(); return 'ok'; } catch (e) { return e.code; } }
+          [code(function () { storage.set('a', '123456789'); }),
+           code(function () { storage.set('a', '12345678'); }),
+           code(function () { storage.set('b', '12345678'); }),
+           code(function () { storage.set('a', '1234'); }),
+           code(function () { storage.set('b', '12345'); }),
+           code(function () { storage.set('c', '12345'); }),
+           code(function () { storage.delete('b'); }),
+           code(function () { storage.set('c', '12345'); }),
+           code(function () { storage.get(new Array(102).join('k')); })]
+        ")
+            .unwrap();
+        assert_eq!(Value::Array(vec![Value::String("EFBIG".to_owned()),
+                                     Value::String("ok".to_owned()),
+                                     Value::String("EQUOTA".to_owned()),
+                                     Value::String("ok".to_owned()),
+                                     Value::String("ok".to_owned()),
+                                     Value::String("EQUOTA".to_owned()),
+                                     Value::String("ok".to_owned()),
+                                     Value::String("ok".to_owned()),
+                                     Value::String("ENAMETOOLONG".to_owned())]),
+                   result.to_value());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn running_total_size() {
+        struct Counted(MemoryStore, rc::Rc<cell::Cell<usize>>);
+
+        impl Store for Counted {
+            fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+                self.0.get(key)
+            }
+
+            fn set(&mut self, key: &str, value: &str) -> io::Result<()> {
+                self.0.set(key, value)
+            }
+
+            fn delete(&mut self, key: &str) -> io::Result<bool> {
+                self.0.delete(key)
+            }
+
+            fn keys(&mut self) -> io::Result<Vec<String>> {
+                self.1.set(self.1.get() + 1);
+                self.0.keys()
+            }
+        }
+
+        let _ = env_logger::init();
+        let mut store = MemoryStore::new();
+        store.set("old", "12345").unwrap();
+        let listed = rc::Rc::new(cell::Cell::new(0));
+        let quota = Quota::new().with_max_total_size(32);
+        let ctx = Context::builder()
+            .with_storage(Box::new(Counted(store, listed.clone())), quota)
+            .build();
+        let result = ctx.eval_string(r"
+          var storage = require('host:storage');
+          for (var i = 0; i < 10; i++) { storage.set('k' + i % 3, '123'); }
+          storage.delete('old');
+          storage.set('k3', '1234567890');
+          try { storage.set('k4', '123456'); } catch (e) { e.code }
+        ")
+            .unwrap();
+        assert_eq!(Value::String("EQUOTA".to_owned()), result.to_value());
+        assert_eq!(1, listed.get());
+        ctx.assert_clean();
+    }
+}
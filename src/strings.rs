@@ -0,0 +1,105 @@
+//! Conversions between Rust strings and Duktape's internal string representation.
+//!
+//! Duktape stores strings in an extended UTF-8, where characters outside of the BMP are usually
+//! stored as two encoded surrogates (like CESU-8), so that they behave like in other engines.
+
+use std::borrow;
+use std::char;
+use std::os;
+use std::slice;
+use std::str;
+
+use duktape_sys;
+
+/// Coerces the value at the specified index to a string in place, and converts it into a Rust
+/// string.
+pub(crate) unsafe fn get(ctx: *mut duktape_sys::duk_context, index: duktape_sys::duk_idx_t) -> String {
+    let mut len = 0;
+    let data = duktape_sys::duk_safe_to_lstring(ctx, index, &mut len);
+    decode(slice::from_raw_parts(data as *const u8, len)).collect()
+}
+
+/// Pushes a Rust string.
+pub(crate) unsafe fn push(ctx: *mut duktape_sys::duk_context, string: &str) {
+    let internal = encode(string);
+    duktape_sys::duk_push_lstring(ctx, internal.as_ptr() as *const os::raw::c_char, internal.len());
+}
+
+/// Decodes Duktape's internal string representation, where characters outside of the BMP are
+/// usually stored as surrogate pairs, into characters.  Unpaired surrogates become U+FFFD.
+pub(crate) fn decode(bytes: &[u8]) -> Decode<'_> {
+    Decode { bytes, pos: 0 }
+}
+
+/// Converts Duktape's internal string representation into UTF-8, without copying it if it is valid
+/// UTF-8 already, like when it has no surrogates.
+pub(crate) fn to_utf8(bytes: &[u8]) -> borrow::Cow<'_, str> {
+    match str::from_utf8(bytes) {
+        Ok(string) => borrow::Cow::Borrowed(string),
+        Err(_) => borrow::Cow::Owned(decode(bytes).collect()),
+    }
+}
+
+pub(crate) struct Decode<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decode<'a> {
+    fn next_code_point(&mut self) -> Option<u32> {
+        let lead = *self.bytes.get(self.pos)?;
+        let (len, init) = match lead {
+            0x00..=0x7f => (1, lead as u32),
+            0xc0..=0xdf => (2, (lead & 0x1f) as u32),
+            0xe0..=0xef => (3, (lead & 0x0f) as u32),
+            0xf0..=0xf7 => (4, (lead & 0x07) as u32),
+            _ => (1, 0xfffd),
+        };
+        let end = (self.pos + len).min(self.bytes.len());
+        let cp = self.bytes[self.pos + 1..end]
+            .iter()
+            .fold(init, |cp, &b| (cp << 6) | (b & 0x3f) as u32);
+        self.pos = end;
+        Some(cp)
+    }
+}
+
+impl<'a> Iterator for Decode<'a> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let cp = self.next_code_point()?;
+        if (0xd800..0xdc00).contains(&cp) {
+            let pos = self.pos;
+            match self.next_code_point() {
+                Some(low) if (0xdc00..0xe000).contains(&low) => {
+                    let cp = 0x10000 + ((cp - 0xd800) << 10) + (low - 0xdc00);
+                    return Some(char::from_u32(cp).unwrap_or(char::REPLACEMENT_CHARACTER));
+                }
+                _ => self.pos = pos,
+            }
+        }
+        Some(char::from_u32(cp).unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+}
+
+/// Encodes a string into Duktape's internal representation, with characters outside of the BMP
+/// as surrogate pairs so that scripts see the same string as for the equivalent literal.
+pub(crate) fn encode(string: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(string.len());
+    let mut units = [0; 2];
+    for c in string.chars() {
+        if (c as u32) < 0x10000 {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+        } else {
+            for &unit in c.encode_utf16(&mut units).iter() {
+                let unit = unit as u32;
+                bytes.push(0xe0 | (unit >> 12) as u8);
+                bytes.push(0x80 | ((unit >> 6) & 0x3f) as u8);
+                bytes.push(0x80 | (unit & 0x3f) as u8);
+            }
+        }
+    }
+    bytes
+}
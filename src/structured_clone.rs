@@ -0,0 +1,814 @@
+//! Copies of Javascript values that can be moved between contexts, see
+//! `Reference::structured_clone` and `Context::import_clone`.
+//!
+//! Unlike a `Value`, a `StructuredClone` keeps the object graph intact: objects that are
+//! referenced more than once (including cycles) are still shared in the copy, dates, regular
+//! expressions and errors keep their type, and `ArrayBuffer`s, typed arrays, `DataView`s and plain
+//! buffers keep their bytes.  Like in the HTML structured clone algorithm, only own enumerable
+//! properties are copied, objects lose their prototype (apart from the built-in types above),
+//! errors only keep their name and message, and functions can't be cloned at all.  Each view is
+//! copied with its own buffer, so views that shared one don't share it in the copy.  This
+//! version of Duktape has no `Map` or `Set`.
+//!
+//! Scripts get the same algorithm as the global `structuredClone(value)`, which throws a
+//! `DataCloneError` for values that can't be cloned.
+//!
+//! # Examples
+//!
+//! ```
+//! let source = duk::Context::new();
+//! let target = duk::Context::new();
+//! let value = source.eval_string("var o = {when: new Date(0)}; o.self = o; o").unwrap();
+//! let clone = value.structured_clone().unwrap();
+//! let copy = target.import_clone(&clone);
+//! let check = target.eval_string("(function (o) { return o.self === o && o.when instanceof Date; })")
+//!     .unwrap();
+//! assert_eq!(duk::Value::Boolean(true), check.call(&[&copy]).unwrap().to_value());
+//! ```
+
+use std::os;
+use std::slice;
+
+use duktape_sys;
+
+use nul_str;
+
+/// Describes an object as `[class, ...details]`.
+const DESCRIBE: &[u8] = br#"(function (v) {
+  var cls = Object.prototype.toString.call(v).slice(8, -1);
+  switch (cls) {
+  case 'Date':
+    return [cls, v.getTime()];
+  case 'RegExp':
+    return [cls, v.source, (v.global ? 'g' : '') + (v.ignoreCase ? 'i' : '') +
+                           (v.multiline ? 'm' : '')];
+  case 'Error':
+    return [cls, String(v.name), String(v.message)];
+  case 'Array':
+    return [cls, v.length];
+  case 'Object':
+  case 'Arguments':
+    return typeof v === 'function' ? ['Function'] : ['Object'];
+  default:
+    return [cls];
+  }
+})"#;
+
+/// Creates the shell of an object in the target context.
+const CREATE: &[u8] = br#"(function (cls, a, b) {
+  var errors = {
+    Error: Error, EvalError: EvalError, RangeError: RangeError, ReferenceError: ReferenceError,
+    SyntaxError: SyntaxError, TypeError: TypeError, URIError: URIError
+  };
+  switch (cls) {
+  case 'Object':
+    return {};
+  case 'Array':
+    return [];
+  case 'Date':
+    return new Date(a);
+  case 'RegExp':
+    return new RegExp(a, b);
+  case 'Error':
+    var e = new (errors.hasOwnProperty(a) ? errors[a] : Error)(b);
+    if (e.name !== a) {
+      e.name = a;
+    }
+    return e;
+  case 'ArrayBuffer':
+    return new Uint8Array(a).buffer;
+  default:
+    return new this[cls](new Uint8Array(a).buffer);
+  }
+})"#;
+
+/// The classes of views whose bytes are copied.
+const VIEWS: &[&str] = &["Int8Array", "Uint8Array", "Uint8ClampedArray", "Int16Array",
+                         "Uint16Array", "Int32Array", "Uint32Array", "Float32Array",
+                         "Float64Array", "DataView"];
+
+/// A copy of a Javascript value and everything it references.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StructuredClone {
+    root: Slot,
+    nodes: Vec<Node>,
+    /// The prototypes of objects that don't have the default one, only for snapshots.
+    prototypes: Vec<(usize, Slot)>,
+}
+
+/// A value, or a reference to an object of the clone.  Strings are kept in Duktape's internal
+/// representation, so that they are copied exactly.
+#[derive(Clone, Debug, PartialEq)]
+enum Slot {
+    Undefined,
+    Null,
+    Boolean(bool),
+    Number(f64),
+    String(Vec<u8>),
+    Node(usize),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Node {
+    Object(Vec<(Vec<u8>, Slot)>),
+    Array(u32, Vec<(Vec<u8>, Slot)>),
+    Date(f64),
+    RegExp(Vec<u8>, Vec<u8>),
+    Error(Vec<u8>, Vec<u8>),
+    Buffer(Vec<u8>),
+    View(&'static str, Vec<u8>),
+    /// The bytecode of a compiled function, its properties including `prototype`, and whether
+    /// the `constructor` of the prototype is the function, only for snapshots.
+    Function(Vec<u8>, Vec<(Vec<u8>, Slot)>, bool),
+}
+
+/// Copies the value at the specified index, or returns why it can't be cloned.
+pub(crate) unsafe fn read(ctx: *mut duktape_sys::duk_context,
+                          index: duktape_sys::duk_idx_t)
+                          -> Result<StructuredClone, String> {
+    read_with(ctx, index, false)
+}
+
+/// Copies the value at the specified index like `read`, but also copies compiled functions as
+/// bytecode, and the prototypes of objects, for `Context::snapshot`.
+pub(crate) unsafe fn read_with_functions(ctx: *mut duktape_sys::duk_context,
+                                         index: duktape_sys::duk_idx_t)
+                                         -> Result<StructuredClone, String> {
+    read_with(ctx, index, true)
+}
+
+unsafe fn read_with(ctx: *mut duktape_sys::duk_context,
+                    index: duktape_sys::duk_idx_t,
+                    functions: bool)
+                    -> Result<StructuredClone, String> {
+    use duktape_sys::*;
+
+    let index = duk_normalize_index(ctx, index);
+    let ret = duk_peval_lstring(ctx, DESCRIBE.as_ptr() as *const os::raw::c_char, DESCRIBE.len());
+    assert_eq!(0, ret, "failed to compile the clone helper");
+    let mut reader = Reader {
+        ctx,
+        describe: duk_get_top(ctx) - 1,
+        objects: Vec::new(),
+        functions,
+        prototypes: Vec::new(),
+    };
+
+    let result = reader.slot(index).and_then(|root| {
+        // Objects are visited breadth first, so that deep structures don't exhaust the stack
+        let mut nodes = Vec::new();
+        while nodes.len() < reader.objects.len() {
+            duk_push_heapptr(ctx, reader.objects[nodes.len()]);
+            let node = reader.node();
+            if let Ok(ref node) = node {
+                if reader.functions {
+                    reader.prototype(nodes.len(), node);
+                }
+            }
+            duk_pop(ctx);
+            nodes.push(node?);
+        }
+        Ok((root, nodes))
+    });
+    duk_pop(ctx);
+    result.map(|(root, nodes)| StructuredClone { root, nodes, prototypes: reader.prototypes })
+}
+
+struct Reader {
+    ctx: *mut duktape_sys::duk_context,
+    describe: duktape_sys::duk_idx_t,
+    objects: Vec<*mut os::raw::c_void>,
+    functions: bool,
+    prototypes: Vec<(usize, Slot)>,
+}
+
+impl Reader {
+    /// Reads the value at the specified index, and schedules objects to be read.
+    unsafe fn slot(&mut self, index: duktape_sys::duk_idx_t) -> Result<Slot, String> {
+        use duktape_sys::*;
+
+        let t = duk_get_type(self.ctx, index);
+        Ok(if t == DUK_TYPE_UNDEFINED || t == DUK_TYPE_NONE {
+            Slot::Undefined
+        } else if t == DUK_TYPE_NULL {
+            Slot::Null
+        } else if t == DUK_TYPE_BOOLEAN {
+            Slot::Boolean(duk_get_boolean(self.ctx, index) != 0)
+        } else if t == DUK_TYPE_NUMBER {
+            Slot::Number(duk_get_number(self.ctx, index))
+        } else if t == DUK_TYPE_STRING {
+            Slot::String(raw_string(self.ctx, index))
+        } else if t == DUK_TYPE_OBJECT || t == DUK_TYPE_BUFFER {
+            let ptr = duk_get_heapptr(self.ctx, index);
+            let id = match self.objects.iter().position(|&p| p == ptr) {
+                Some(id) => id,
+                None => {
+                    self.objects.push(ptr);
+                    self.objects.len() - 1
+                }
+            };
+            Slot::Node(id)
+        } else {
+            return Err("pointers and lightweight functions can't be cloned".to_owned());
+        })
+    }
+
+    /// Reads the object on top of the stack.
+    unsafe fn node(&mut self) -> Result<Node, String> {
+        use duktape_sys::*;
+
+        let ctx = self.ctx;
+        if duk_is_buffer(ctx, -1) != 0 {
+            return Ok(Node::Buffer(buffer_bytes(ctx, -1)));
+        }
+
+        duk_dup(ctx, self.describe);
+        duk_dup(ctx, -2);
+        if duk_pcall(ctx, 1) != 0 {
+            duk_pop(ctx);
+            return Err("the object could not be inspected".to_owned());
+        }
+        duk_get_prop_index(ctx, -1, 0);
+        let class = String::from_utf8_lossy(&raw_string(ctx, -1)).into_owned();
+        duk_pop(ctx);
+        duk_get_prop_index(ctx, -1, 1);
+        duk_get_prop_index(ctx, -2, 2);
+        // Stack: [ ... object description detail1 detail2 ]
+        let node = match &*class {
+            "Object" => Ok(Node::Object(self.properties(-4)?)),
+            "Array" => Ok(Node::Array(duk_get_uint(ctx, -2), self.properties(-4)?)),
+            "Date" => Ok(Node::Date(duk_get_number(ctx, -2))),
+            "RegExp" => Ok(Node::RegExp(raw_string(ctx, -2), raw_string(ctx, -1))),
+            "Error" => Ok(Node::Error(raw_string(ctx, -2), raw_string(ctx, -1))),
+            "ArrayBuffer" => Ok(Node::View("ArrayBuffer", buffer_bytes(ctx, -4))),
+            "Function" if self.functions => self.function(),
+            class => {
+                match VIEWS.iter().find(|&&v| v == class) {
+                    Some(view) => Ok(Node::View(view, buffer_bytes(ctx, -4))),
+                    None => Err(format!("{} objects can't be cloned", class)),
+                }
+            }
+        };
+        duk_pop_3(ctx);
+        node
+    }
+
+    /// Reads the function below the three values on top of the stack.
+    unsafe fn function(&mut self) -> Result<Node, String> {
+        use duktape_sys::*;
+
+        let ctx = self.ctx;
+        if duk_is_ecmascript_function(ctx, -4) == 0 || duk_is_bound_function(ctx, -4) != 0 {
+            return Err("native and bound functions can't be cloned".to_owned());
+        }
+        duk_dup(ctx, -4);
+        duk_dump_function(ctx);
+        let bytecode = buffer_bytes(ctx, -1);
+        duk_pop(ctx);
+
+        // The prototype isn't enumerable, and its constructor usually isn't either
+        let mut properties = self.properties(-4)?;
+        let mut constructor = false;
+        duk_get_prop_string(ctx, -4, nul_str(b"prototype\0"));
+        if duk_is_object(ctx, -1) != 0 {
+            properties.push((b"prototype".to_vec(), self.slot(-1).unwrap()));
+            duk_get_prop_string(ctx, -1, nul_str(b"constructor\0"));
+            constructor = duk_strict_equals(ctx, -1, -6) != 0;
+            duk_pop(ctx);
+        }
+        duk_pop(ctx);
+        Ok(Node::Function(bytecode, properties, constructor))
+    }
+
+    /// Records the prototype of the object on top of the stack, unless it's the default one for
+    /// its type.
+    unsafe fn prototype(&mut self, id: usize, node: &Node) {
+        use duktape_sys::*;
+
+        let ctx = self.ctx;
+        let class: &[u8] = match *node {
+            Node::Object(_) => b"Object\0",
+            Node::Array(..) => b"Array\0",
+            Node::Function(..) => b"Function\0",
+            _ => return,
+        };
+        duk_get_prototype(ctx, -1);
+        duk_get_global_string(ctx, nul_str(class));
+        duk_get_prop_string(ctx, -1, nul_str(b"prototype\0"));
+        if duk_strict_equals(ctx, -1, -3) == 0 {
+            // Objects and `undefined`, for no prototype, can always be read
+            let prototype = self.slot(-3).unwrap();
+            self.prototypes.push((id, prototype));
+        }
+        duk_pop_3(ctx);
+    }
+
+    /// Reads the own enumerable properties of the object at the specified index.
+    unsafe fn properties(&mut self,
+                         index: duktape_sys::duk_idx_t)
+                         -> Result<Vec<(Vec<u8>, Slot)>, String> {
+        use duktape_sys::*;
+
+        let mut properties = Vec::new();
+        duk_enum(self.ctx, index, DUK_ENUM_OWN_PROPERTIES_ONLY);
+        while duk_next(self.ctx, -1, 1) != 0 {
+            let property = self.slot(-1).map(|slot| (raw_string(self.ctx, -2), slot));
+            duk_pop_2(self.ctx);
+            match property {
+                Ok(property) => properties.push(property),
+                Err(e) => {
+                    duk_pop(self.ctx);
+                    return Err(e);
+                }
+            }
+        }
+        duk_pop(self.ctx);
+        Ok(properties)
+    }
+}
+
+/// Pushes a copy of the cloned value.
+pub(crate) unsafe fn push(ctx: *mut duktape_sys::duk_context, clone: &StructuredClone) {
+    use duktape_sys::*;
+
+    let ret = duk_peval_lstring(ctx, CREATE.as_ptr() as *const os::raw::c_char, CREATE.len());
+    assert_eq!(0, ret, "failed to compile the clone helper");
+
+    // Create all objects first, so that properties can refer to any of them
+    duk_push_array(ctx);
+    let objects = duk_get_top(ctx) - 1;
+    for (i, node) in clone.nodes.iter().enumerate() {
+        if let Node::Buffer(ref bytes) = *node {
+            push_bytes(ctx, bytes);
+        } else if let Node::Function(ref bytecode, ..) = *node {
+            push_bytes(ctx, bytecode);
+            duk_load_function(ctx);
+        } else {
+            let base = duk_get_top(ctx);
+            duk_dup(ctx, -2);
+            duk_push_global_object(ctx);
+            match *node {
+                Node::Object(_) => push_str(ctx, "Object"),
+                Node::Array(..) => push_str(ctx, "Array"),
+                Node::Date(time) => {
+                    push_str(ctx, "Date");
+                    duk_push_number(ctx, time);
+                }
+                Node::RegExp(ref source, ref flags) => {
+                    push_str(ctx, "RegExp");
+                    push_raw(ctx, source);
+                    push_raw(ctx, flags);
+                }
+                Node::Error(ref name, ref message) => {
+                    push_str(ctx, "Error");
+                    push_raw(ctx, name);
+                    push_raw(ctx, message);
+                }
+                Node::View(class, ref bytes) => {
+                    push_str(ctx, class);
+                    push_bytes(ctx, bytes);
+                }
+                Node::Buffer(_) | Node::Function(..) => unreachable!(),
+            }
+            let ret = duk_pcall_method(ctx, duk_get_top(ctx) - base - 2);
+            assert_eq!(0, ret, "failed to create a cloned object");
+        }
+        duk_put_prop_index(ctx, -2, i as duk_uarridx_t);
+    }
+
+    for (i, node) in clone.nodes.iter().enumerate() {
+        let (length, properties) = match *node {
+            Node::Object(ref properties) | Node::Function(_, ref properties, _) => {
+                (None, properties)
+            }
+            Node::Array(length, ref properties) => (Some(length), properties),
+            _ => continue,
+        };
+        duk_get_prop_index(ctx, -1, i as duk_uarridx_t);
+        for (key, value) in properties {
+            push_raw(ctx, key);
+            push_slot(ctx, value, objects);
+            duk_put_prop(ctx, -3);
+        }
+        if let Some(length) = length {
+            duk_push_uint(ctx, length);
+            duk_put_prop_string(ctx, -2, nul_str(b"length\0"));
+        }
+        if let Node::Function(_, _, true) = *node {
+            duk_get_prop_string(ctx, -1, nul_str(b"prototype\0"));
+            push_str(ctx, "constructor");
+            duk_dup(ctx, -3);
+            duk_def_prop(ctx,
+                         -3,
+                         DUK_DEFPROP_HAVE_VALUE | DUK_DEFPROP_HAVE_WRITABLE |
+                         DUK_DEFPROP_WRITABLE | DUK_DEFPROP_HAVE_ENUMERABLE |
+                         DUK_DEFPROP_HAVE_CONFIGURABLE |
+                         DUK_DEFPROP_CONFIGURABLE);
+            duk_pop(ctx);
+        }
+        duk_pop(ctx);
+    }
+
+    for &(id, ref prototype) in &clone.prototypes {
+        duk_get_prop_index(ctx, -1, id as duk_uarridx_t);
+        push_slot(ctx, prototype, objects);
+        duk_set_prototype(ctx, -2);
+        duk_pop(ctx);
+    }
+
+    push_slot(ctx, &clone.root, objects);
+    // Stack: [ ... create objects root ]
+    duk_remove(ctx, -2);
+    duk_remove(ctx, -2);
+}
+
+/// Pushes a slot, looking objects up in the array at the specified index.
+unsafe fn push_slot(ctx: *mut duktape_sys::duk_context, slot: &Slot, objects: duktape_sys::duk_idx_t) {
+    use duktape_sys::*;
+
+    match *slot {
+        Slot::Undefined => duk_push_undefined(ctx),
+        Slot::Null => duk_push_null(ctx),
+        Slot::Boolean(b) => duk_push_boolean(ctx, b as duk_bool_t),
+        Slot::Number(n) => duk_push_number(ctx, n),
+        Slot::String(ref s) => push_raw(ctx, s),
+        Slot::Node(id) => {
+            duk_get_prop_index(ctx, objects, id as duk_uarridx_t);
+        }
+    }
+}
+
+unsafe fn raw_string(ctx: *mut duktape_sys::duk_context, index: duktape_sys::duk_idx_t) -> Vec<u8> {
+    let mut len = 0;
+    let data = duktape_sys::duk_safe_to_lstring(ctx, index, &mut len);
+    slice::from_raw_parts(data as *const u8, len).to_vec()
+}
+
+unsafe fn buffer_bytes(ctx: *mut duktape_sys::duk_context, index: duktape_sys::duk_idx_t) -> Vec<u8> {
+    let mut len = 0;
+    let data = duktape_sys::duk_get_buffer_data(ctx, index, &mut len);
+    if data.is_null() {
+        Vec::new()
+    } else {
+        slice::from_raw_parts(data as *const u8, len).to_vec()
+    }
+}
+
+unsafe fn push_raw(ctx: *mut duktape_sys::duk_context, s: &[u8]) {
+    duktape_sys::duk_push_lstring(ctx, s.as_ptr() as *const os::raw::c_char, s.len());
+}
+
+unsafe fn push_str(ctx: *mut duktape_sys::duk_context, s: &str) {
+    push_raw(ctx, s.as_bytes());
+}
+
+unsafe fn push_bytes(ctx: *mut duktape_sys::duk_context, bytes: &[u8]) {
+    let buf = duktape_sys::duk_push_fixed_buffer(ctx, bytes.len());
+    if !bytes.is_empty() {
+        ::std::ptr::copy_nonoverlapping(bytes.as_ptr(), buf as *mut u8, bytes.len());
+    }
+}
+
+impl StructuredClone {
+    /// Appends the clone in a binary format to `out`, for snapshots.  Numbers and lengths are
+    /// little-endian and 32 bits long, apart from `f64`s.
+    pub(crate) fn encode(&self, out: &mut Vec<u8>) {
+        put_u32(out, self.nodes.len() as u32);
+        for node in &self.nodes {
+            match *node {
+                Node::Object(ref properties) => {
+                    out.push(0);
+                    put_properties(out, properties);
+                }
+                Node::Array(length, ref properties) => {
+                    out.push(1);
+                    put_u32(out, length);
+                    put_properties(out, properties);
+                }
+                Node::Date(time) => {
+                    out.push(2);
+                    out.extend_from_slice(&time.to_bits().to_le_bytes());
+                }
+                Node::RegExp(ref source, ref flags) => {
+                    out.push(3);
+                    put_bytes(out, source);
+                    put_bytes(out, flags);
+                }
+                Node::Error(ref name, ref message) => {
+                    out.push(4);
+                    put_bytes(out, name);
+                    put_bytes(out, message);
+                }
+                Node::Buffer(ref bytes) => {
+                    out.push(5);
+                    put_bytes(out, bytes);
+                }
+                Node::View(class, ref bytes) => {
+                    out.push(6);
+                    put_bytes(out, class.as_bytes());
+                    put_bytes(out, bytes);
+                }
+                Node::Function(ref bytecode, ref properties, constructor) => {
+                    out.push(7);
+                    put_bytes(out, bytecode);
+                    put_properties(out, properties);
+                    out.push(constructor as u8);
+                }
+            }
+        }
+        put_u32(out, self.prototypes.len() as u32);
+        for &(id, ref prototype) in &self.prototypes {
+            put_u32(out, id as u32);
+            put_slot(out, prototype);
+        }
+        put_slot(out, &self.root);
+    }
+
+    /// Reads a clone that `encode` wrote, or returns why it's invalid.  The bytecode of
+    /// functions isn't checked.
+    pub(crate) fn decode(data: &[u8]) -> Result<StructuredClone, String> {
+        let mut input = Input(data);
+        let count = input.u32()? as usize;
+        let mut nodes = Vec::new();
+        for _ in 0..count {
+            nodes.push(match input.byte()? {
+                0 => Node::Object(input.properties()?),
+                1 => Node::Array(input.u32()?, input.properties()?),
+                2 => Node::Date(input.f64()?),
+                3 => Node::RegExp(input.bytes()?, input.bytes()?),
+                4 => Node::Error(input.bytes()?, input.bytes()?),
+                5 => Node::Buffer(input.bytes()?),
+                6 => {
+                    let class = input.bytes()?;
+                    let class = match VIEWS.iter().chain(&["ArrayBuffer"]).find(|v| {
+                        v.as_bytes() == &class[..]
+                    }) {
+                        Some(class) => *class,
+                        None => return Err("unknown view class".to_owned()),
+                    };
+                    Node::View(class, input.bytes()?)
+                }
+                7 => Node::Function(input.bytes()?, input.properties()?, input.byte()? != 0),
+                tag => return Err(format!("unknown node type {}", tag)),
+            });
+        }
+        let mut prototypes = Vec::new();
+        for _ in 0..input.u32()? {
+            prototypes.push((input.u32()? as usize, input.slot()?));
+        }
+        let root = input.slot()?;
+        if !input.0.is_empty() {
+            return Err("trailing data".to_owned());
+        }
+
+        let clone = StructuredClone { root, nodes, prototypes };
+        let properties = clone.nodes.iter().flat_map(|node| match *node {
+            Node::Object(ref properties) |
+            Node::Array(_, ref properties) |
+            Node::Function(_, ref properties, _) => &properties[..],
+            _ => &[],
+        });
+        let mut slots = properties.map(|(_, slot)| slot)
+            .chain(clone.prototypes.iter().map(|(_, slot)| slot))
+            .chain(Some(&clone.root));
+        let missing = |slot: &Slot| match *slot {
+            Slot::Node(id) => id >= clone.nodes.len(),
+            _ => false,
+        };
+        if slots.any(missing) || clone.prototypes.iter().any(|&(id, _)| id >= clone.nodes.len()) {
+            return Err("reference to a missing object".to_owned());
+        }
+        Ok(clone)
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, n: u32) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_u32(out, bytes.len() as u32);
+    out.extend_from_slice(bytes);
+}
+
+fn put_slot(out: &mut Vec<u8>, slot: &Slot) {
+    match *slot {
+        Slot::Undefined => out.push(0),
+        Slot::Null => out.push(1),
+        Slot::Boolean(b) => out.push(2 + b as u8),
+        Slot::Number(n) => {
+            out.push(4);
+            out.extend_from_slice(&n.to_bits().to_le_bytes());
+        }
+        Slot::String(ref s) => {
+            out.push(5);
+            put_bytes(out, s);
+        }
+        Slot::Node(id) => {
+            out.push(6);
+            put_u32(out, id as u32);
+        }
+    }
+}
+
+fn put_properties(out: &mut Vec<u8>, properties: &[(Vec<u8>, Slot)]) {
+    put_u32(out, properties.len() as u32);
+    for (key, value) in properties {
+        put_bytes(out, key);
+        put_slot(out, value);
+    }
+}
+
+/// The unread rest of an encoded clone.
+struct Input<'a>(&'a [u8]);
+
+impl<'a> Input<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.0.len() < len {
+            return Err("truncated".to_owned());
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn f64(&mut self) -> Result<f64, String> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(f64::from_bits(u64::from_le_bytes(bytes)))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, String> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn slot(&mut self) -> Result<Slot, String> {
+        Ok(match self.byte()? {
+            0 => Slot::Undefined,
+            1 => Slot::Null,
+            2 => Slot::Boolean(false),
+            3 => Slot::Boolean(true),
+            4 => Slot::Number(self.f64()?),
+            5 => Slot::String(self.bytes()?),
+            6 => Slot::Node(self.u32()? as usize),
+            tag => return Err(format!("unknown value type {}", tag)),
+        })
+    }
+
+    fn properties(&mut self) -> Result<Vec<(Vec<u8>, Slot)>, String> {
+        let mut properties = Vec::new();
+        for _ in 0..self.u32()? {
+            properties.push((self.bytes()?, self.slot()?));
+        }
+        Ok(properties)
+    }
+}
+
+/// Defines the global `structuredClone` function.
+pub(crate) unsafe fn setup(ctx: *mut duktape_sys::duk_context) {
+    use duktape_sys::*;
+
+    const SETUP: &[u8] = b"(function (clone) {
+  return function structuredClone(value) {
+    var result = clone(value);
+    if (result[0]) {
+      var error = new Error(result[1]);
+      error.name = 'DataCloneError';
+      throw error;
+    }
+    return result[1];
+  };
+})";
+    let ret = duk_peval_lstring(ctx, SETUP.as_ptr() as *const os::raw::c_char, SETUP.len());
+    assert_eq!(0, ret, "failed to compile structuredClone");
+    duk_push_c_function(ctx, Some(structured_clone), 1);
+    let ret = duk_pcall(ctx, 1);
+    assert_eq!(0, ret, "failed to set up structuredClone");
+    duk_put_global_string(ctx, nul_str(b"structuredClone\0"));
+}
+
+/// `clone(value)`, returns `[false, copy]` or `[true, message]`.
+unsafe extern "C" fn structured_clone(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    use duktape_sys::*;
+
+    let result = read(ctx, 0);
+    duk_push_array(ctx);
+    match result {
+        Ok(clone) => {
+            duk_push_false(ctx);
+            duk_put_prop_index(ctx, -2, 0);
+            push(ctx, &clone);
+        }
+        Err(message) => {
+            duk_push_true(ctx);
+            duk_put_prop_index(ctx, -2, 0);
+            push_str(ctx, &message);
+        }
+    }
+    duk_put_prop_index(ctx, -2, 1);
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use {Context, ErrorKind, Value};
+
+    #[test]
+    fn clone_between_contexts() {
+        let _ = env_logger::init();
+        let source = Context::new();
+        let target = Context::new();
+        let value = source.eval_string(r"
+          var shared = {n: 1};
+          var bytes = new Int16Array([1, -2, 300]);
+          var o = {
+            a: shared, b: shared, list: [1, , 'three'], when: new Date(86400000),
+            re: /a+b/gi, err: new RangeError('too far'), bytes: bytes,
+            raw: Duktape.dec('hex', '00ff'), text: 'hé 😀'
+          };
+          o.list.extra = true;
+          o.self = o;
+          o
+        ")
+            .unwrap();
+        let clone = value.structured_clone().unwrap();
+        let copy = target.import_clone(&clone);
+        let check = target.eval_string(r"(function (o) {
+          return [o.self === o, o.a === o.b, o.a.n, o.list.length, 1 in o.list, o.list[2],
+                  o.list.extra, o.when instanceof Date && o.when.getTime(), String(o.re),
+                  o.err instanceof RangeError && o.err.message, o.bytes instanceof Int16Array,
+                  Array.prototype.join.call(o.bytes), typeof o.raw, o.raw[1],
+                  o.text === 'hé 😀'];
+        })")
+            .unwrap();
+        assert_eq!(Value::Array(vec![Value::Boolean(true),
+                                     Value::Boolean(true),
+                                     Value::Number(1.0),
+                                     Value::Number(3.0),
+                                     Value::Boolean(false),
+                                     Value::String("three".to_owned()),
+                                     Value::Boolean(true),
+                                     Value::Number(86400000.0),
+                                     Value::String("/a+b/gi".to_owned()),
+                                     Value::String("too far".to_owned()),
+                                     Value::Boolean(true),
+                                     Value::String("1,-2,300".to_owned()),
+                                     Value::String("buffer".to_owned()),
+                                     Value::Number(255.0),
+                                     Value::Boolean(true)]),
+                   check.call(&[&copy]).unwrap().to_value());
+
+        // The copy is independent of the original
+        source.eval_string("shared.n = 2").unwrap();
+        assert_eq!(Value::Number(1.0),
+                   target.eval_string("(function (o) { return o.a.n; })")
+                       .unwrap()
+                       .call(&[&copy])
+                       .unwrap()
+                       .to_value());
+
+        let function = source.eval_string("({f: function () {}})").unwrap();
+        match function.structured_clone().unwrap_err().0 {
+            ErrorKind::DataClone(ref message) => assert_eq!("Function objects can't be cloned", message),
+            ref other => panic!("unexpected error {:?}", other),
+        }
+        source.assert_clean();
+        target.assert_clean();
+    }
+
+    #[test]
+    fn structured_clone_global() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let result = ctx.eval_string(r"
+          var o = {d: new Date(5), nested: {list: [new Uint8Array([7])]}};
+          var c = structuredClone(o);
+          var error;
+          try { structuredClone({f: Math.max}); } catch (e) { error = e.name; }
+          [c !== o, c.nested !== o.nested, c.d.getTime(), c.nested.list[0][0], error,
+           structuredClone('plain'), structuredClone(undefined)]
+        ")
+            .unwrap();
+        assert_eq!(Value::Array(vec![Value::Boolean(true),
+                                     Value::Boolean(true),
+                                     Value::Number(5.0),
+                                     Value::Number(7.0),
+                                     Value::String("DataCloneError".to_owned()),
+                                     Value::String("plain".to_owned()),
+                                     Value::Undefined]),
+                   result.to_value());
+        ctx.assert_clean();
+    }
+}
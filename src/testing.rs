@@ -0,0 +1,350 @@
+//! Helpers for unit tests of scripts and of the hosts that embed them.
+//!
+//! The `assert_eval_eq!` and `assert_throws!` macros evaluate a snippet of code and check its
+//! outcome, with a descriptive panic message when the check fails, while `Fixture` builds contexts
+//! that are pre-populated with globals and scripts.  Tests that need files, like scripts to
+//! load, can keep them in a `ScratchDir`.
+//!
+//! For property tests of conversions, `roundtrip` and `roundtrip_with` push a `Value` into
+//! Javascript and read it back, and `is_lossless` tells whether that gives an equal value.  These
+//! are the invariants that hold for round trips:
+//!
+//!   * `undefined`, `null`, booleans, strings and byte buffers come back unchanged.
+//!   * Numbers come back unchanged, including `-0` and infinities.  `NaN` stays `NaN`, but isn't
+//!     equal to itself.
+//!   * Arrays and objects come back with the same elements and properties, unless they are
+//!     nested deeper than the `max_depth` of the conversion options, which turns them into
+//!     `Value::Foreign("too deep")`.
+//!   * A `__proto__` property sets the prototype of the object instead, so it is lost.
+//!   * `Foreign` values become `undefined`.
+//!   * Date conversion options don't matter, since no `Value` turns into a `Date`.
+//!
+//! # Examples
+//!
+//! ```
+//! #[macro_use]
+//! extern crate duk;
+//!
+//! fn main() {
+//!     let ctx = duk::testing::Fixture::new()
+//!         .global("limit", 3)
+//!         .script("lib.js", "function clamp(n) { return Math.min(n, limit); }")
+//!         .build();
+//!     assert_eval_eq!(ctx, "clamp(5)", 3);
+//!     assert_throws!(ctx, "clamp.call.call(1)", duk::JsErrorKind::Type);
+//! }
+//! ```
+
+use std::env;
+use std::fs;
+use std::ops;
+use std::path;
+use std::process;
+
+use duktape_sys;
+
+use conversion;
+use {Argument, Context, ContextBuilder, ErrorKind, JsError, JsErrorKind, Result, Value};
+
+/// Asserts that evaluating the code in the context gives the expected value, which can be
+/// anything that converts into a `Value`.
+#[macro_export]
+macro_rules! assert_eval_eq {
+    ($ctx:expr, $code:expr, $expected:expr) => {
+        $crate::testing::assert_eval_eq(&$ctx, $code, $crate::Value::from($expected))
+    };
+}
+
+/// Asserts that evaluating the code in the context throws an error of the specified
+/// `JsErrorKind`, and returns the error for further checks.
+#[macro_export]
+macro_rules! assert_throws {
+    ($ctx:expr, $code:expr, $kind:expr) => {
+        $crate::testing::assert_throws(&$ctx, $code, $kind)
+    };
+}
+
+/// Builds contexts with predefined globals and scripts, for tests that share the same setup.
+pub struct Fixture {
+    builder: ContextBuilder,
+    globals: Vec<(String, Value)>,
+    scripts: Vec<(String, String)>,
+}
+
+impl Fixture {
+    /// Creates a fixture for contexts with the default configuration.
+    pub fn new() -> Fixture {
+        Fixture::with_builder(ContextBuilder::default())
+    }
+
+    /// Creates a fixture for contexts that are configured by the specified builder, for example
+    /// with a module resolver and loader.
+    pub fn with_builder(builder: ContextBuilder) -> Fixture {
+        Fixture {
+            builder,
+            globals: Vec::new(),
+            scripts: Vec::new(),
+        }
+    }
+
+    /// Defines a global variable with the specified value.  Globals are defined before any of the
+    /// scripts are evaluated.
+    pub fn global<V>(mut self, name: &str, value: V) -> Self
+        where V: Into<Value>
+    {
+        self.globals.push((name.to_owned(), value.into()));
+        self
+    }
+
+    /// Adds a script that is evaluated with the specified file name, after the scripts that were
+    /// added before it.
+    pub fn script(mut self, filename: &str, source: &str) -> Self {
+        self.scripts.push((filename.to_owned(), source.to_owned()));
+        self
+    }
+
+    /// Builds the context.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any of the scripts fails to evaluate.
+    pub fn build(self) -> Context {
+        let ctx = self.builder.build();
+        for (name, value) in &self.globals {
+            unsafe {
+                value.push_to_context(&ctx);
+                ctx.put_global(name);
+            }
+        }
+        for (filename, source) in &self.scripts {
+            if let Err(e) = ctx.eval_string_with_filename(filename, source) {
+                panic!("fixture script {} failed: {}", filename, e);
+            }
+        }
+        ctx
+    }
+}
+
+impl Default for Fixture {
+    fn default() -> Fixture {
+        Fixture::new()
+    }
+}
+
+/// A directory for the files of a test, which is empty when it is created, and removed with its
+/// contents when it is dropped.
+pub struct ScratchDir {
+    path: path::PathBuf,
+}
+
+impl ScratchDir {
+    /// Creates the directory `duk-<name>-<process id>` in the temporary directory, removing what a
+    /// previous run left behind.  The name must be unique among the tests of a process.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the directory can't be created.
+    pub fn new(name: &str) -> ScratchDir {
+        let path = env::temp_dir().join(format!("duk-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&path);
+        if let Err(e) = fs::create_dir_all(&path) {
+            panic!("can't create the scratch directory {}: {}", path.display(), e);
+        }
+        ScratchDir { path }
+    }
+
+    /// The path of the directory.
+    pub fn path(&self) -> &path::Path {
+        &self.path
+    }
+}
+
+impl ops::Deref for ScratchDir {
+    type Target = path::Path;
+
+    fn deref(&self) -> &path::Path {
+        &self.path
+    }
+}
+
+impl AsRef<path::Path> for ScratchDir {
+    fn as_ref(&self) -> &path::Path {
+        &self.path
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Evaluates the code and converts its result into a `Value`.
+///
+/// # Panics
+///
+/// Panics if the evaluation fails.
+pub fn eval_value(ctx: &Context, code: &str) -> Value {
+    match ctx.eval_string(code) {
+        Ok(result) => result.to_value(),
+        Err(e) => panic!("evaluation of `{}` failed: {}", code, e),
+    }
+}
+
+/// The implementation of `assert_eval_eq!`.
+pub fn assert_eval_eq(ctx: &Context, code: &str, expected: Value) {
+    let actual = eval_value(ctx, code);
+    assert!(actual == expected,
+            "evaluation of `{}` gave {:?}, expected {:?}",
+            code,
+            actual,
+            expected);
+}
+
+/// The implementation of `assert_throws!`.
+pub fn assert_throws(ctx: &Context, code: &str, kind: JsErrorKind) -> JsError {
+    match ctx.eval_string(code) {
+        Ok(result) => {
+            panic!("evaluation of `{}` gave {:?}, expected a {:?} error",
+                   code,
+                   result.to_value(),
+                   kind)
+        }
+        Err(e) => {
+            match e.0 {
+                ErrorKind::Js(ref error) if error.kind == kind => error.clone(),
+                _ => panic!("evaluation of `{}` failed with {}, expected a {:?} error", code, e, kind),
+            }
+        }
+    }
+}
+
+/// Pushes a value into Javascript and reads it back with the default conversion options.  See the
+/// module documentation for what changes on the way.
+///
+/// # Examples
+///
+/// ```
+/// use duk::Value;
+/// use duk::testing::{is_lossless, roundtrip};
+///
+/// let value = Value::array().element("a").element(Value::object().field("b", 2.5)).build();
+/// assert!(is_lossless(&value, &Default::default()));
+/// assert_eq!(value, roundtrip(&value).unwrap());
+/// ```
+pub fn roundtrip(value: &Value) -> Result<Value> {
+    roundtrip_with(conversion::ConversionOptions::new(), value)
+}
+
+/// Like `roundtrip`, but reads the value back with the specified conversion options.
+pub fn roundtrip_with(options: conversion::ConversionOptions, value: &Value) -> Result<Value> {
+    let ctx = Context::try_new()?;
+    ctx.set_conversion_options(options);
+    let result = unsafe {
+        value.push(ctx.raw);
+        let result = Value::get(ctx.raw, -1);
+        duktape_sys::duk_pop(ctx.raw);
+        result
+    };
+    Ok(result)
+}
+
+/// Whether a round trip with the specified conversion options gives a value that is equal to the
+/// original, according to the invariants in the module documentation.
+pub fn is_lossless(value: &Value, options: &conversion::ConversionOptions) -> bool {
+    is_lossless_at(value, options, 0)
+}
+
+fn is_lossless_at(value: &Value, options: &conversion::ConversionOptions, depth: usize) -> bool {
+    match *value {
+        Value::Number(n) => !n.is_nan(),
+        Value::Foreign(_) => false,
+        Value::Array(_) | Value::Object(_) if options.too_deep(depth) => false,
+        Value::Array(ref elements) => {
+            elements.iter().all(|element| is_lossless_at(element, options, depth + 1))
+        }
+        Value::Object(ref properties) => {
+            properties.iter()
+                .all(|(key, v)| key != "__proto__" && is_lossless_at(v, options, depth + 1))
+        }
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::collections;
+    use std::f64;
+
+    use super::*;
+
+    #[test]
+    fn fixture() {
+        let _ = env_logger::init();
+        let ctx = Fixture::new()
+            .global("greeting", "hello")
+            .global("items", vec![Value::from(1), Value::from(true)])
+            .script("a.js", "var shout = function (s) { return s.toUpperCase() + '!'; };")
+            .script("b.js", "var message = shout(greeting);")
+            .build();
+
+        assert_eval_eq!(ctx, "message", "HELLO!");
+        assert_eval_eq!(ctx, "items.length", 2);
+        assert_eval_eq!(ctx, "items[1]", true);
+        assert_eval_eq!(ctx, "0.5 + 0.25", 0.75);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn throws() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        let error = assert_throws!(ctx, "null.foo", JsErrorKind::Type);
+        assert!(error.message.contains("null"));
+        assert_throws!(ctx, "throw new RangeError('far')", JsErrorKind::Range);
+        ctx.assert_clean();
+    }
+
+    #[test]
+    #[should_panic(expected = "evaluation of `1 + 1` gave Number(2.0), expected Number(3.0)")]
+    fn eval_mismatch() {
+        let ctx = Context::new();
+        assert_eval_eq!(ctx, "1 + 1", 3);
+    }
+
+    #[test]
+    fn roundtrips() {
+        let _ = env_logger::init();
+        let nested = Value::array().element(Value::array().element(1)).build();
+        let options = conversion::ConversionOptions::new().with_max_depth(1);
+        assert!(is_lossless(&nested, &Default::default()));
+        assert_eq!(nested, roundtrip(&nested).unwrap());
+        assert!(!is_lossless(&nested, &options));
+        assert_eq!(Value::Array(vec![Value::Foreign("too deep")]),
+                   roundtrip_with(options, &nested).unwrap());
+
+        let mut properties = collections::BTreeMap::new();
+        properties.insert("__proto__".to_owned(), Value::Object(collections::BTreeMap::new()));
+        properties.insert("bytes".to_owned(), Value::Bytes(vec![0, 1]));
+        let object = Value::Object(properties);
+        assert!(!is_lossless(&object, &Default::default()));
+        let bytes = Value::object().field("bytes", Value::Bytes(vec![0, 1])).build();
+        assert_eq!(bytes, roundtrip(&object).unwrap());
+
+        let exact = [Value::Number(-0.0),
+                     Value::Number(f64::INFINITY),
+                     Value::from("\u{0}\u{10ffff}")];
+        for value in &exact {
+            assert!(is_lossless(value, &Default::default()));
+            assert_eq!(*value, roundtrip(value).unwrap());
+        }
+        assert!(!is_lossless(&Value::Number(f64::NAN), &Default::default()));
+        match roundtrip(&Value::Number(f64::NAN)).unwrap() {
+            Value::Number(n) => assert!(n.is_nan()),
+            value => panic!("unexpected value {:?}", value),
+        }
+        assert_eq!(Value::Undefined, roundtrip(&Value::Foreign("pointer")).unwrap());
+    }
+}
@@ -0,0 +1,366 @@
+//! `URL` and `URLSearchParams` globals for scripts, backed by the `url` crate.
+//!
+//! URLs are parsed and serialized according to the WHATWG URL Standard, including relative URLs
+//! with a base, and all components can be read and assigned.  The `searchParams` of a URL stay in
+//! sync with its `search` component.  Iterators are not available in ES5, so `URLSearchParams`
+//! offers `forEach`, and `keys`, `values` and `entries` return arrays.
+
+use std::os;
+
+use duktape_sys;
+use url;
+use url::quirks;
+
+use nul_str;
+use strings;
+
+/// The components of a URL, in the order of the properties of `URL` instances.
+const COMPONENTS: [&[u8]; 11] = [b"href\0", b"origin\0", b"protocol\0", b"username\0",
+                                 b"password\0", b"host\0", b"hostname\0", b"port\0",
+                                 b"pathname\0", b"search\0", b"hash\0"];
+
+/// Defines the classes on top of the native parsing and serialization functions, and the hidden
+/// key that holds the state of instances.
+const SETUP: &[u8] = b"(function (parse, update, parseQuery, serializeQuery, key) {
+  function define(obj, name, value) {
+    Object.defineProperty(obj, name, { value: value, writable: true, configurable: true });
+  }
+
+  function URL(url, base) {
+    if (!(this instanceof URL)) {
+      throw new TypeError('URL must be called with new');
+    }
+    var parts = parse(String(url), base === undefined ? undefined : String(base));
+    if (!parts) {
+      throw new TypeError('invalid URL: ' + url);
+    }
+    Object.defineProperty(this, key, { value: { parts: parts, params: null } });
+  }
+  ['href', 'origin', 'protocol', 'username', 'password', 'host', 'hostname', 'port', 'pathname',
+   'search', 'hash'].forEach(function (name) {
+    var desc = {
+      get: function () { return this[key].parts[name]; },
+      enumerable: true,
+      configurable: true
+    };
+    if (name !== 'origin') {
+      desc.set = function (value) {
+        var state = this[key];
+        var parts = update(state.parts.href, name, String(value));
+        if (!parts) {
+          if (name === 'href') {
+            throw new TypeError('invalid URL: ' + value);
+          }
+          return;
+        }
+        state.parts = parts;
+        if (state.params) {
+          state.params[key].list = parseQuery(parts.search.slice(1));
+        }
+      };
+    }
+    Object.defineProperty(URL.prototype, name, desc);
+  });
+  Object.defineProperty(URL.prototype, 'searchParams', {
+    get: function () {
+      var state = this[key];
+      if (!state.params) {
+        state.params = new URLSearchParams(state.parts.search);
+        state.params[key].url = this;
+      }
+      return state.params;
+    },
+    enumerable: true,
+    configurable: true
+  });
+  define(URL.prototype, 'toString', function () { return this.href; });
+  define(URL.prototype, 'toJSON', function () { return this.href; });
+
+  function URLSearchParams(init) {
+    if (!(this instanceof URLSearchParams)) {
+      throw new TypeError('URLSearchParams must be called with new');
+    }
+    var list = [];
+    if (init instanceof URLSearchParams) {
+      list = init[key].list.map(function (pair) { return [pair[0], pair[1]]; });
+    } else if (Array.isArray(init)) {
+      init.forEach(function (pair) {
+        if (!pair || pair.length !== 2) {
+          throw new TypeError('URLSearchParams pairs must have a name and a value');
+        }
+        list.push([String(pair[0]), String(pair[1])]);
+      });
+    } else if (init !== null && typeof init === 'object') {
+      Object.keys(init).forEach(function (name) { list.push([name, String(init[name])]); });
+    } else if (init !== undefined) {
+      var query = String(init);
+      list = parseQuery(query.charAt(0) === '?' ? query.slice(1) : query);
+    }
+    Object.defineProperty(this, key, { value: { list: list, url: null } });
+  }
+  function changed(params) {
+    var state = params[key];
+    if (state.url) {
+      var url = state.url[key];
+      url.parts = update(url.parts.href, 'search', serializeQuery(state.list)) || url.parts;
+    }
+  }
+  function column(params, i) {
+    return params[key].list.map(function (pair) { return pair[i]; });
+  }
+  var methods = {
+    append: function (name, value) {
+      this[key].list.push([String(name), String(value)]);
+      changed(this);
+    },
+    'delete': function (name) {
+      name = String(name);
+      this[key].list = this[key].list.filter(function (pair) { return pair[0] !== name; });
+      changed(this);
+    },
+    get: function (name) {
+      var values = this.getAll(name);
+      return values.length > 0 ? values[0] : null;
+    },
+    getAll: function (name) {
+      name = String(name);
+      return this[key].list.filter(function (pair) { return pair[0] === name; })
+        .map(function (pair) { return pair[1]; });
+    },
+    has: function (name) {
+      return this.getAll(name).length > 0;
+    },
+    set: function (name, value) {
+      name = String(name);
+      var found = false;
+      this[key].list = this[key].list.filter(function (pair) {
+        if (pair[0] !== name) {
+          return true;
+        }
+        if (!found) {
+          found = true;
+          pair[1] = String(value);
+          return true;
+        }
+        return false;
+      });
+      if (!found) {
+        this[key].list.push([name, String(value)]);
+      }
+      changed(this);
+    },
+    sort: function () {
+      // Array.prototype.sort is not guaranteed to be stable
+      this[key].list = this[key].list
+        .map(function (pair, i) { return [pair, i]; })
+        .sort(function (a, b) {
+          return a[0][0] < b[0][0] ? -1 : a[0][0] > b[0][0] ? 1 : a[1] - b[1];
+        })
+        .map(function (entry) { return entry[0]; });
+      changed(this);
+    },
+    forEach: function (callback, thisArg) {
+      var params = this;
+      this[key].list.slice().forEach(function (pair) {
+        callback.call(thisArg, pair[1], pair[0], params);
+      });
+    },
+    keys: function () { return column(this, 0); },
+    values: function () { return column(this, 1); },
+    entries: function () { return column(this, 0).map(function (name, i) {
+      return [name, this[key].list[i][1]];
+    }, this); },
+    toString: function () { return serializeQuery(this[key].list); }
+  };
+  Object.keys(methods).forEach(function (name) {
+    define(URLSearchParams.prototype, name, methods[name]);
+  });
+
+  return [URL, URLSearchParams];
+})";
+
+/// Defines the global `URL` and `URLSearchParams` classes.
+pub unsafe fn setup(ctx: *mut duktape_sys::duk_context) {
+    use duktape_sys::*;
+
+    let ret = duk_peval_lstring(ctx, SETUP.as_ptr() as *const os::raw::c_char, SETUP.len());
+    assert_eq!(0, ret, "failed to compile the URL classes");
+    duk_push_c_function(ctx, Some(parse), 2);
+    duk_push_c_function(ctx, Some(update), 3);
+    duk_push_c_function(ctx, Some(parse_query), 1);
+    duk_push_c_function(ctx, Some(serialize_query), 1);
+    // A key that doesn't clash with the properties of scripts.  Scripts that dig it up with
+    // `Duktape.dec` can only break their own instances, since the state is plain strings that the
+    // native functions parse again.
+    let key = b"\xffurl";
+    duk_push_lstring(ctx, key.as_ptr() as *const os::raw::c_char, key.len());
+    let ret = duk_pcall(ctx, 5);
+    assert_eq!(0, ret, "failed to set up the URL classes");
+
+    duk_get_prop_index(ctx, -1, 0);
+    duk_put_global_string(ctx, nul_str(b"URL\0"));
+    duk_get_prop_index(ctx, -1, 1);
+    duk_put_global_string(ctx, nul_str(b"URLSearchParams\0"));
+    duk_pop(ctx);
+}
+
+/// `parse(input, base)`, returns the components of the URL, or `null` if it is invalid.
+unsafe extern "C" fn parse(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    let input = strings::get(ctx, 0);
+    let url = if duktape_sys::duk_is_undefined(ctx, 1) == 1 {
+        url::Url::parse(&input)
+    } else {
+        url::Url::parse(&strings::get(ctx, 1)).and_then(|base| base.join(&input))
+    };
+    match url {
+        Ok(url) => push_components(ctx, &url),
+        Err(_) => duktape_sys::duk_push_null(ctx),
+    }
+    1
+}
+
+/// `update(href, component, value)`, returns the components of the URL after assigning the
+/// component, or `null` if the assignment failed.
+unsafe extern "C" fn update(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    let mut url = match url::Url::parse(&strings::get(ctx, 0)) {
+        Ok(url) => url,
+        Err(_) => {
+            duktape_sys::duk_push_null(ctx);
+            return 1;
+        }
+    };
+    let value = strings::get(ctx, 2);
+    // Invalid values for components other than `href` are ignored, as required by the standard
+    match &strings::get(ctx, 1)[..] {
+        "href" if quirks::set_href(&mut url, &value).is_err() => {
+            duktape_sys::duk_push_null(ctx);
+            return 1;
+        }
+        "protocol" => drop(quirks::set_protocol(&mut url, &value)),
+        "username" => drop(quirks::set_username(&mut url, &value)),
+        "password" => drop(quirks::set_password(&mut url, &value)),
+        "host" => drop(quirks::set_host(&mut url, &value)),
+        "hostname" => drop(quirks::set_hostname(&mut url, &value)),
+        "port" => drop(quirks::set_port(&mut url, &value)),
+        "pathname" => quirks::set_pathname(&mut url, &value),
+        "search" => quirks::set_search(&mut url, &value),
+        "hash" => quirks::set_hash(&mut url, &value),
+        _ => {}
+    }
+    push_components(ctx, &url);
+    1
+}
+
+/// `parseQuery(query)`, returns the name-value pairs of an `application/x-www-form-urlencoded`
+/// string as an array of arrays.
+unsafe extern "C" fn parse_query(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    use duktape_sys::*;
+
+    let query = strings::get(ctx, 0);
+    duk_push_array(ctx);
+    for (i, (name, value)) in url::form_urlencoded::parse(query.as_bytes()).enumerate() {
+        duk_push_array(ctx);
+        strings::push(ctx, &name);
+        duk_put_prop_index(ctx, -2, 0);
+        strings::push(ctx, &value);
+        duk_put_prop_index(ctx, -2, 1);
+        duk_put_prop_index(ctx, -2, i as duk_uarridx_t);
+    }
+    1
+}
+
+/// `serializeQuery(pairs)`, returns the `application/x-www-form-urlencoded` string of an array of
+/// name-value arrays.
+unsafe extern "C" fn serialize_query(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    use duktape_sys::*;
+
+    let mut serializer = url::form_urlencoded::Serializer::new(String::new());
+    for i in 0..duk_get_length(ctx, 0) {
+        duk_get_prop_index(ctx, 0, i as duk_uarridx_t);
+        duk_get_prop_index(ctx, -1, 0);
+        duk_get_prop_index(ctx, -2, 1);
+        serializer.append_pair(&strings::get(ctx, -2), &strings::get(ctx, -1));
+        duk_pop_3(ctx);
+    }
+    strings::push(ctx, &serializer.finish());
+    1
+}
+
+/// Pushes an object with the components of the URL.
+unsafe fn push_components(ctx: *mut duktape_sys::duk_context, url: &url::Url) {
+    let values = [quirks::href(url).to_owned(),
+                  quirks::origin(url),
+                  quirks::protocol(url).to_owned(),
+                  quirks::username(url).to_owned(),
+                  quirks::password(url).to_owned(),
+                  quirks::host(url).to_owned(),
+                  quirks::hostname(url).to_owned(),
+                  quirks::port(url).to_owned(),
+                  quirks::pathname(url).to_owned(),
+                  quirks::search(url).to_owned(),
+                  quirks::hash(url).to_owned()];
+    duktape_sys::duk_push_object(ctx);
+    for (name, value) in COMPONENTS.iter().zip(values.iter()) {
+        strings::push(ctx, value);
+        duktape_sys::duk_put_prop_string(ctx, -2, nul_str(name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use {Context, Value};
+
+    fn eval(ctx: &Context, code: &str) -> Value {
+        ctx.eval_string(code).unwrap().to_value()
+    }
+
+    #[test]
+    fn url() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.eval_string("var u = new URL('../v2/items?id=7&tag=a%20b#top', \
+                         'https://user:pw@api.example.com:8443/v1/users');")
+            .unwrap();
+        assert_eq!(Value::String("https://user:pw@api.example.com:8443/v2/items?id=7&tag=a%20b#top"
+                       .to_owned()),
+                   eval(&ctx, "u.href"));
+        assert_eq!(Value::String("https://api.example.com:8443".to_owned()),
+                   eval(&ctx, "u.origin"));
+        assert_eq!(Value::String("api.example.com:8443/v2/items?id=7#top".to_owned()),
+                   eval(&ctx, "[u.host, u.pathname, u.search.slice(0, 5), u.hash].join('')"));
+        assert_eq!(Value::String("a b".to_owned()), eval(&ctx, "u.searchParams.get('tag')"));
+
+        ctx.eval_string("u.port = '443'; u.hostname = 'EXAMPLE.org'; u.port = 'bad';").unwrap();
+        assert_eq!(Value::String("https://user:pw@example.org/v2/items?id=7&tag=a%20b#top"
+                       .to_owned()),
+                   eval(&ctx, "String(u)"));
+
+        assert!(ctx.eval_string("new URL('/relative')").is_err());
+        assert!(ctx.eval_string("u.href = 'not a url'").is_err());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn search_params() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.eval_string("var u = new URL('http://h/p?b=2&a=1&b=3'); var p = u.searchParams;")
+            .unwrap();
+        assert_eq!(Value::Array(vec![Value::String("2".to_owned()),
+                                     Value::String("3".to_owned())]),
+                   eval(&ctx, "p.getAll('b')"));
+
+        ctx.eval_string("p.append('q', 'x y&z'); p.set('b', '4'); p.sort();").unwrap();
+        assert_eq!(Value::String("?a=1&b=4&q=x+y%26z".to_owned()), eval(&ctx, "u.search"));
+
+        ctx.eval_string("u.search = '?only=1';").unwrap();
+        assert_eq!(Value::Boolean(false), eval(&ctx, "p.has('a')"));
+        assert_eq!(Value::String("only=1".to_owned()), eval(&ctx, "p.toString()"));
+
+        assert_eq!(Value::String("k=v&n=%E2%98%BA".to_owned()),
+                   eval(&ctx, "new URLSearchParams({k: 'v', n: '\\u263a'}).toString()"));
+        ctx.assert_clean();
+    }
+}
@@ -0,0 +1,187 @@
+//! Rust values stored in Javascript objects, see `ObjectRef::set_userdata`.
+//!
+//! The values live in a table of the context, by the heap pointers of their objects, and are
+//! dropped by a finalizer of the object (see `finalizers`), or when they are replaced.  Scripts
+//! can't reach them, and a value belongs to the object itself, so objects that inherit from it
+//! have none of their own.
+
+use std::any;
+use std::cell;
+use std::collections;
+use std::rc;
+
+use duktape_sys;
+
+use finalizers;
+use HeapData;
+
+type Userdata = rc::Rc<dyn any::Any>;
+
+/// The userdata of a context, by the heap pointers of their objects.
+pub(crate) type Registry = cell::RefCell<collections::HashMap<usize, Userdata>>;
+
+/// Stores the userdata of the object at the index, replacing what it had before, and returns
+/// whether it could, which it can't if the object can't have a finalizer (see `finalizers::add`).
+pub(crate) unsafe fn set(ctx: *mut duktape_sys::duk_context,
+                         index: duktape_sys::duk_idx_t,
+                         value: Userdata)
+                         -> bool {
+    let object = duktape_sys::duk_get_heapptr(ctx, index) as usize;
+    // The previous value is dropped after the table is released, since its `Drop` may use it
+    let previous = registry(ctx).borrow_mut().get_mut(&object).map(|userdata| {
+        ::std::mem::replace(userdata, value.clone())
+    });
+    if previous.is_some() {
+        return true;
+    }
+
+    let added = finalizers::add(ctx, index, Box::new(move |ctx| {
+        let userdata = registry(ctx).borrow_mut().remove(&object);
+        drop(userdata);
+    }));
+    if added {
+        registry(ctx).borrow_mut().insert(object, value);
+    }
+    added
+}
+
+/// The userdata of the value at the index, if it is an object with userdata of type `T`.
+pub(crate) unsafe fn get<T>(ctx: *mut duktape_sys::duk_context,
+                            index: duktape_sys::duk_idx_t)
+                            -> Option<rc::Rc<T>>
+    where T: 'static
+{
+    if duktape_sys::duk_is_object(ctx, index) == 0 {
+        return None;
+    }
+    let object = duktape_sys::duk_get_heapptr(ctx, index) as usize;
+    let userdata = registry(ctx).borrow().get(&object).cloned();
+    userdata.and_then(|userdata| userdata.downcast().ok())
+}
+
+unsafe fn registry<'a>(ctx: *mut duktape_sys::duk_context) -> &'a Registry {
+    let mut funcs = duktape_sys::duk_memory_functions::default();
+    duktape_sys::duk_get_memory_functions(ctx, &mut funcs);
+    &(*(funcs.udata as *mut HeapData)).userdata
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::cell;
+
+    use super::*;
+    use {Context, Value};
+
+    struct Handle {
+        name: String,
+        closed: rc::Rc<cell::Cell<bool>>,
+    }
+
+    impl Drop for Handle {
+        fn drop(&mut self) {
+            self.closed.set(true);
+        }
+    }
+
+    #[test]
+    fn userdata() {
+        let _ = env_logger::init();
+        let closed = rc::Rc::new(cell::Cell::new(false));
+        let ctx = Context::new();
+        ctx.register_variadic("handleName", |args| {
+            Ok(match args.userdata::<Handle>(0) {
+                Some(handle) => Value::String(handle.name.clone()),
+                None => Value::Null,
+            })
+        });
+        let file = ctx.eval_string("var file = {}; file").unwrap().into_object().unwrap();
+        assert!(file.get_userdata::<Handle>().is_none());
+        file.set_userdata(Handle {
+                name: "a.txt".to_owned(),
+                closed: closed.clone(),
+            })
+            .unwrap();
+        assert!(file.get_userdata::<String>().is_none());
+        assert_eq!("a.txt", file.get_userdata::<Handle>().unwrap().name);
+        assert_eq!(Value::Array(vec![Value::String("a.txt".to_owned()), Value::Null, Value::Null]),
+                   ctx.eval_string("[handleName(file), handleName({}), handleName(1)]")
+                       .unwrap()
+                       .to_value());
+
+        // Replacing the userdata drops the previous value, unless the host still shares it
+        let handle = file.get_userdata::<Handle>().unwrap();
+        file.set_userdata(42).unwrap();
+        assert!(!closed.get());
+        drop(handle);
+        assert!(closed.get());
+
+        assert_eq!(Some(42), file.get_userdata::<i32>().map(|n| *n));
+        assert!(ctx.eval_string("1").unwrap().into_object().is_err());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn inherited_userdata() {
+        let _ = env_logger::init();
+        let ctx = Context::new();
+        ctx.register_variadic("userdata", |args| {
+            Ok(args.userdata::<String>(0)
+                .map_or(Value::Null, |data| Value::String((*data).clone())))
+        });
+        let parent = ctx.eval_string("var parent = {}; parent").unwrap().into_object().unwrap();
+        parent.set_userdata("parent".to_owned()).unwrap();
+        let child = ctx.eval_string("var child = Object.create(parent); child")
+            .unwrap()
+            .into_object()
+            .unwrap();
+        assert!(child.get_userdata::<String>().is_none());
+        assert_eq!(Value::Array(vec![Value::String("parent".to_owned()), Value::Null]),
+                   ctx.eval_string("[userdata(parent), userdata(child)]").unwrap().to_value());
+
+        // The child gets its own userdata, instead of replacing the one of the parent
+        child.set_userdata("child".to_owned()).unwrap();
+        assert_eq!("parent", *parent.get_userdata::<String>().unwrap());
+        assert_eq!("child", *child.get_userdata::<String>().unwrap());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn finalized_userdata() {
+        let _ = env_logger::init();
+        let closed = rc::Rc::new(cell::Cell::new(false));
+        let ctx = Context::new();
+        ctx.register_variadic("handleName", |args| {
+            Ok(match args.userdata::<Handle>(0) {
+                Some(handle) => Value::String(handle.name.clone()),
+                None => Value::Null,
+            })
+        });
+        let file = ctx.eval_string("var file = {}; file").unwrap().into_object().unwrap();
+        file.set_userdata(Handle {
+                name: "a.txt".to_owned(),
+                closed: closed.clone(),
+            })
+            .unwrap();
+        drop(file);
+
+        // Copying hidden properties doesn't copy the userdata, and finalizing the object early
+        // drops it
+        let names = ctx.eval_string(r"
+          var k = String(Duktape.dec('hex', 'ff7573657264617461'));
+          var copy = {};
+          copy[k] = file[k];
+          Duktape.fin(file)(file);
+          [handleName(file), handleName(copy)]
+        ")
+            .unwrap();
+        assert_eq!(Value::Array(vec![Value::Null, Value::Null]), names.to_value());
+        assert!(closed.get());
+
+        let frozen = ctx.eval_string("Object.freeze({})").unwrap().into_object().unwrap();
+        assert!(frozen.set_userdata(1).is_err());
+        assert!(frozen.get_userdata::<i32>().is_none());
+        ctx.assert_clean();
+    }
+}
@@ -0,0 +1,508 @@
+//! A `host:ws` module for scripts that consume live data feeds over WebSockets, see
+//! `ContextBuilder::with_websockets`.
+//!
+//! Like `fetch`, the context doesn't talk to the network itself: connections are opened by a
+//! `Transport` that the host provides, which is also the place to restrict where scripts may
+//! connect to.  Scripts call `require('host:ws').connect(url)`, which returns an open socket with
+//! `send(data)`, `close()`, `url`, `readyState`, and `onopen`, `onmessage`, `onerror` and `onclose`
+//! handlers that are called with an event object.  Text messages have a string as the `data` of
+//! the event, and binary messages a `Uint8Array`.
+//!
+//! Handlers never run on their own: like timers, they run when the host pumps the event loop
+//! with `Context::pump_event_loop`, which polls every open connection for messages.  `onopen` is
+//! called at the first pump after connecting, and `onclose` at the first pump after the socket
+//! was closed by either side.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::io;
+//! use duk::ws::{Connection, Message};
+//!
+//! /// A connection that echoes every message.
+//! struct Echo(Vec<Message>);
+//!
+//! impl Connection for Echo {
+//!     fn send(&mut self, message: Message) -> io::Result<()> {
+//!         self.0.push(message);
+//!         Ok(())
+//!     }
+//!     fn receive(&mut self) -> io::Result<Option<Message>> {
+//!         Ok(self.0.pop())
+//!     }
+//!     fn is_closed(&self) -> bool {
+//!         false
+//!     }
+//!     fn close(&mut self) {}
+//! }
+//!
+//! let transport = |_: &str| -> io::Result<Box<dyn Connection>> { Ok(Box::new(Echo(Vec::new()))) };
+//! let ctx = duk::Context::builder().with_websockets(Box::new(transport)).build();
+//! ctx.eval_string(r"
+//!   var reply;
+//!   var socket = require('host:ws').connect('wss://feed.example.com');
+//!   socket.onmessage = function (e) { reply = e.data; socket.close(); };
+//!   socket.send('ping');
+//! ").unwrap();
+//! ctx.run_event_loop().unwrap();
+//! assert_eq!(duk::Value::String("ping".to_owned()), ctx.eval_string("reply").unwrap().to_value());
+//! ```
+
+use std::cell;
+use std::collections;
+use std::io;
+use std::os;
+use std::slice;
+use std::time;
+
+use duktape_sys;
+
+use host_modules;
+use nul_str;
+use strings;
+use HeapData;
+
+/// The key of the heap stash entry with the function that dispatches socket events.
+const STASH_KEY: &[u8] = b"websockets\0";
+
+/// How long the host may wait before pumping the event loop again while sockets are open.
+pub(crate) const POLL_INTERVAL: time::Duration = time::Duration::from_millis(10);
+
+/// Builds the exports of `host:ws` on top of the native functions, and returns `[exports,
+/// dispatch]`.
+const SETUP: &[u8] = br#"(function (connect, send, close) {
+  var sockets = {};
+
+  function Socket(url) {
+    var id = connect(url);
+    if (typeof id === 'string') {
+      throw new Error('websocket connection failed: ' + id);
+    }
+    this.url = url;
+    this.readyState = 1;
+    this.onopen = null;
+    this.onmessage = null;
+    this.onerror = null;
+    this.onclose = null;
+    Object.defineProperty(this, 'id', {value: id});
+    sockets[id] = this;
+  }
+  Socket.CONNECTING = 0;
+  Socket.OPEN = 1;
+  Socket.CLOSING = 2;
+  Socket.CLOSED = 3;
+  Socket.prototype.send = function (data) {
+    if (this.readyState !== 1) {
+      throw new Error('websocket is not open');
+    }
+    if (!(typeof data === 'buffer' || data instanceof ArrayBuffer || ArrayBuffer.isView(data))) {
+      data = String(data);
+    }
+    var error = send(this.id, data);
+    if (error !== null) {
+      throw new Error('websocket send failed: ' + error);
+    }
+  };
+  Socket.prototype.close = function () {
+    if (this.readyState < 2) {
+      this.readyState = 2;
+      close(this.id);
+    }
+  };
+
+  function dispatch(id, type, data) {
+    var socket = sockets[id];
+    if (!socket) {
+      return;
+    }
+    if (type === 'close') {
+      socket.readyState = 3;
+      delete sockets[id];
+    }
+    if (data !== undefined && typeof data !== 'string') {
+      data = new Uint8Array(data);
+    }
+    var handler = socket['on' + type];
+    if (typeof handler === 'function') {
+      handler.call(socket, {type: type, data: data, target: socket});
+    }
+  }
+
+  return [{
+    connect: function (url) {
+      return new Socket(String(url));
+    },
+    WebSocket: Socket
+  }, dispatch];
+})"#;
+
+/// A WebSocket message.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Opens the connections of scripts on behalf of the context.
+///
+/// Closures taking a URL and returning an `io::Result<Box<dyn Connection>>` are transports too.
+/// An error makes `connect` throw an `Error` with the error message.
+pub trait Transport {
+    fn connect(&self, url: &str) -> io::Result<Box<dyn Connection>>;
+}
+
+impl<F> Transport for F
+    where F: Fn(&str) -> io::Result<Box<dyn Connection>>
+{
+    fn connect(&self, url: &str) -> io::Result<Box<dyn Connection>> {
+        self(url)
+    }
+}
+
+/// An open WebSocket connection.
+pub trait Connection {
+    /// Sends a message on behalf of the script.
+    fn send(&mut self, message: Message) -> io::Result<()>;
+
+    /// Returns the next message that has arrived, or `None` if there is none yet.  This must not
+    /// block, since the context polls connections while pumping the event loop.  An error is
+    /// reported to the `onerror` handler, and closes the socket.
+    fn receive(&mut self) -> io::Result<Option<Message>>;
+
+    /// Whether the peer closed the connection.
+    fn is_closed(&self) -> bool;
+
+    /// Closes the connection on behalf of the script.
+    fn close(&mut self);
+}
+
+/// The transport and open sockets of a context.
+pub(crate) struct Sockets {
+    transport: Box<dyn Transport>,
+    next_id: cell::Cell<u32>,
+    open: cell::RefCell<collections::BTreeMap<u32, Socket>>,
+    pending: cell::RefCell<collections::VecDeque<(u32, SocketEvent)>>,
+}
+
+struct Socket {
+    connection: Box<dyn Connection>,
+    opened: bool,
+    closed: bool,
+}
+
+/// Something that happened to a socket, which is reported to one of its handlers.
+pub(crate) enum SocketEvent {
+    Open,
+    Message(Message),
+    Error(String),
+    Close,
+}
+
+impl SocketEvent {
+    /// The name of the handler, as reported to the metrics.
+    pub(crate) fn handler(&self) -> &'static str {
+        match *self {
+            SocketEvent::Open => "onopen",
+            SocketEvent::Message(_) => "onmessage",
+            SocketEvent::Error(_) => "onerror",
+            SocketEvent::Close => "onclose",
+        }
+    }
+}
+
+impl Sockets {
+    pub(crate) fn new(transport: Box<dyn Transport>) -> Sockets {
+        Sockets {
+            transport,
+            next_id: cell::Cell::new(1),
+            open: cell::RefCell::new(collections::BTreeMap::new()),
+            pending: cell::RefCell::new(collections::VecDeque::new()),
+        }
+    }
+
+    /// The number of sockets that are open, or whose events haven't all been dispatched.
+    pub(crate) fn len(&self) -> usize {
+        self.open.borrow().len()
+    }
+
+    /// Polls every socket, and queues the events that need to be dispatched.
+    pub(crate) fn poll(&self) {
+        let mut open = self.open.borrow_mut();
+        let mut pending = self.pending.borrow_mut();
+        for (&id, socket) in open.iter_mut() {
+            if socket.closed {
+                continue;
+            }
+            if !socket.opened {
+                socket.opened = true;
+                pending.push_back((id, SocketEvent::Open));
+            }
+            loop {
+                match socket.connection.receive() {
+                    Ok(Some(message)) => pending.push_back((id, SocketEvent::Message(message))),
+                    Ok(None) => break,
+                    Err(e) => {
+                        pending.push_back((id, SocketEvent::Error(e.to_string())));
+                        socket.connection.close();
+                        socket.closed = true;
+                        break;
+                    }
+                }
+            }
+            if !socket.closed && socket.connection.is_closed() {
+                socket.closed = true;
+            }
+            if socket.closed {
+                pending.push_back((id, SocketEvent::Close));
+            }
+        }
+    }
+
+    /// Takes the next event to dispatch off the queue.
+    pub(crate) fn next_event(&self) -> Option<(u32, SocketEvent)> {
+        let event = self.pending.borrow_mut().pop_front();
+        if let Some((id, SocketEvent::Close)) = event {
+            self.open.borrow_mut().remove(&id);
+        }
+        event
+    }
+}
+
+/// Registers the `host:ws` module.
+pub(crate) unsafe fn setup(ctx: *mut duktape_sys::duk_context) {
+    use duktape_sys::*;
+
+    let ret = duk_peval_lstring(ctx, SETUP.as_ptr() as *const os::raw::c_char, SETUP.len());
+    assert_eq!(0, ret, "failed to compile host:ws");
+    duk_push_c_function(ctx, Some(connect), 1);
+    duk_push_c_function(ctx, Some(send), 2);
+    duk_push_c_function(ctx, Some(close), 1);
+    let ret = duk_pcall(ctx, 3);
+    assert_eq!(0, ret, "failed to set up host:ws");
+
+    duk_push_heap_stash(ctx);
+    duk_get_prop_index(ctx, -2, 1);
+    duk_put_prop_string(ctx, -2, nul_str(STASH_KEY));
+    duk_pop(ctx);
+    duk_get_prop_index(ctx, -1, 0);
+    duk_remove(ctx, -2);
+    host_modules::register(ctx, b"host:ws\0");
+}
+
+/// Calls the handler of a socket event, and leaves the result or error on the stack.
+pub(crate) unsafe fn dispatch(ctx: *mut duktape_sys::duk_context,
+                              id: u32,
+                              event: &SocketEvent)
+                              -> duktape_sys::duk_ret_t {
+    use duktape_sys::*;
+
+    duk_push_heap_stash(ctx);
+    duk_get_prop_string(ctx, -1, nul_str(STASH_KEY));
+    duk_remove(ctx, -2);
+    duk_push_uint(ctx, id);
+    match *event {
+        SocketEvent::Open => strings::push(ctx, "open"),
+        SocketEvent::Message(_) => strings::push(ctx, "message"),
+        SocketEvent::Error(_) => strings::push(ctx, "error"),
+        SocketEvent::Close => strings::push(ctx, "close"),
+    }
+    match *event {
+        SocketEvent::Message(Message::Text(ref text)) => strings::push(ctx, text),
+        SocketEvent::Message(Message::Binary(ref bytes)) => {
+            let buf = duk_push_fixed_buffer(ctx, bytes.len());
+            if !bytes.is_empty() {
+                ::std::ptr::copy_nonoverlapping(bytes.as_ptr(), buf as *mut u8, bytes.len());
+            }
+        }
+        SocketEvent::Error(ref message) => strings::push(ctx, message),
+        _ => duk_push_undefined(ctx),
+    }
+    duk_pcall(ctx, 3)
+}
+
+unsafe fn sockets(ctx: *mut duktape_sys::duk_context) -> &'static Sockets {
+    let mut funcs = duktape_sys::duk_memory_functions::default();
+    duktape_sys::duk_get_memory_functions(ctx, &mut funcs);
+    (*(funcs.udata as *mut HeapData)).websockets.as_ref().unwrap()
+}
+
+/// `connect(url)`, returns the id of the socket, or an error message.
+unsafe extern "C" fn connect(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    let sockets = sockets(ctx);
+    let url = strings::get(ctx, 0);
+    debug!("websocket: connect to {}", url);
+    match sockets.transport.connect(&url) {
+        Ok(connection) => {
+            let id = sockets.next_id.get();
+            sockets.next_id.set(id.wrapping_add(1).max(1));
+            sockets.open.borrow_mut().insert(id,
+                                             Socket {
+                                                 connection,
+                                                 opened: false,
+                                                 closed: false,
+                                             });
+            duktape_sys::duk_push_uint(ctx, id);
+        }
+        Err(e) => strings::push(ctx, &e.to_string()),
+    }
+    1
+}
+
+/// `send(id, data)`, returns `null`, or an error message.
+unsafe extern "C" fn send(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    use duktape_sys::*;
+
+    let id = duk_get_uint(ctx, 0);
+    let message = if duk_is_string(ctx, 1) != 0 {
+        Message::Text(strings::get(ctx, 1))
+    } else {
+        let mut len = 0;
+        let data = duk_get_buffer_data(ctx, 1, &mut len);
+        if data.is_null() {
+            Message::Binary(Vec::new())
+        } else {
+            Message::Binary(slice::from_raw_parts(data as *const u8, len).to_vec())
+        }
+    };
+    let result = match sockets(ctx).open.borrow_mut().get_mut(&id) {
+        Some(ref mut socket) if !socket.closed => socket.connection.send(message),
+        _ => Err(io::Error::new(io::ErrorKind::NotConnected, "the socket is closed")),
+    };
+    match result {
+        Ok(()) => duk_push_null(ctx),
+        Err(e) => strings::push(ctx, &e.to_string()),
+    }
+    1
+}
+
+/// `close(id)`
+unsafe extern "C" fn close(ctx: *mut duktape_sys::duk_context) -> duktape_sys::duk_ret_t {
+    let id = duktape_sys::duk_get_uint(ctx, 0);
+    let sockets = sockets(ctx);
+    let mut open = sockets.open.borrow_mut();
+    if let Some(socket) = open.get_mut(&id) {
+        if !socket.closed {
+            socket.connection.close();
+            socket.closed = true;
+            sockets.pending.borrow_mut().push_back((id, SocketEvent::Close));
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::cell;
+    use std::io;
+    use std::rc;
+
+    use super::*;
+    use {Context, Value};
+
+    /// A connection that the test feeds, and that records what the script sends.
+    struct Feed {
+        incoming: rc::Rc<cell::RefCell<Vec<io::Result<Message>>>>,
+        sent: rc::Rc<cell::RefCell<Vec<Message>>>,
+        closed: rc::Rc<cell::Cell<bool>>,
+    }
+
+    impl Connection for Feed {
+        fn send(&mut self, message: Message) -> io::Result<()> {
+            self.sent.borrow_mut().push(message);
+            Ok(())
+        }
+
+        fn receive(&mut self) -> io::Result<Option<Message>> {
+            let mut incoming = self.incoming.borrow_mut();
+            if incoming.is_empty() {
+                Ok(None)
+            } else {
+                incoming.remove(0).map(Some)
+            }
+        }
+
+        fn is_closed(&self) -> bool {
+            self.closed.get()
+        }
+
+        fn close(&mut self) {
+            self.closed.set(true);
+        }
+    }
+
+    #[test]
+    fn websocket_feed() {
+        let _ = env_logger::init();
+        let incoming = rc::Rc::new(cell::RefCell::new(Vec::new()));
+        let sent = rc::Rc::new(cell::RefCell::new(Vec::new()));
+        let closed = rc::Rc::new(cell::Cell::new(false));
+        let (i, s, c) = (incoming.clone(), sent.clone(), closed.clone());
+        let transport = move |url: &str| -> io::Result<Box<dyn Connection>> {
+            if !url.starts_with("wss://feed.example.com/") {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "not allowed"));
+            }
+            Ok(Box::new(Feed {
+                incoming: i.clone(),
+                sent: s.clone(),
+                closed: c.clone(),
+            }))
+        };
+        let ctx = Context::builder().with_websockets(Box::new(transport)).build();
+        ctx.eval_string(r"
+          var log = [];
+          var socket = require('host:ws').connect('wss://feed.example.com/prices');
+          socket.onopen = function () { log.push('open'); socket.send('subscribe'); };
+          socket.onmessage = function (e) {
+            log.push(typeof e.data === 'string' ? e.data : 'bytes:' + e.data.length);
+            socket.send(new Uint8Array([1, 2]));
+          };
+          socket.onclose = function () { log.push('close ' + socket.readyState); };
+        ")
+            .unwrap();
+        assert!(ctx.eval_string("require('host:ws').connect('wss://evil.example.com/')").is_err());
+
+        assert_eq!(Some(POLL_INTERVAL), ctx.pump_event_loop().unwrap());
+        incoming.borrow_mut().push(Ok(Message::Text("42.5".to_owned())));
+        incoming.borrow_mut().push(Ok(Message::Binary(vec![0; 3])));
+        ctx.pump_event_loop().unwrap();
+        closed.set(true);
+        assert_eq!(None, ctx.pump_event_loop().unwrap());
+
+        assert_eq!(Value::String("open,42.5,bytes:3,close 3".to_owned()),
+                   ctx.eval_string("log.join()").unwrap().to_value());
+        assert_eq!(vec![Message::Text("subscribe".to_owned()),
+                        Message::Binary(vec![1, 2]),
+                        Message::Binary(vec![1, 2])],
+                   *sent.borrow());
+        assert!(ctx.eval_string("socket.send('late')").is_err());
+        ctx.assert_clean();
+    }
+
+    #[test]
+    fn websocket_errors() {
+        let _ = env_logger::init();
+        let transport = |_: &str| -> io::Result<Box<dyn Connection>> {
+            Ok(Box::new(Feed {
+                incoming: rc::Rc::new(cell::RefCell::new(vec![Err(io::Error::new(io::ErrorKind::ConnectionReset,
+                                                                                  "reset"))])),
+                sent: rc::Rc::new(cell::RefCell::new(Vec::new())),
+                closed: rc::Rc::new(cell::Cell::new(false)),
+            }))
+        };
+        let ctx = Context::builder().with_websockets(Box::new(transport)).build();
+        ctx.eval_string(r"
+          var log = [];
+          var socket = require('host:ws').connect('wss://feed.example.com/');
+          socket.onerror = function (e) { log.push('error ' + e.data); };
+          socket.onclose = function () { throw new Error('handler failed'); };
+        ")
+            .unwrap();
+        assert!(ctx.pump_event_loop().is_err());
+        assert_eq!(None, ctx.pump_event_loop().unwrap());
+        assert_eq!(Value::String("error reset".to_owned()),
+                   ctx.eval_string("log.join()").unwrap().to_value());
+        ctx.assert_clean();
+    }
+}